@@ -0,0 +1,43 @@
+//! Time colourising a full -z 8 map with the colour LUT and with exact evaluation.
+//!
+//! Run with `cargo bench --bench colourise`.
+
+use ip_heatmap::{DomainType, Heatmap, RenderOptions, ValueMode};
+use std::time::{Duration, Instant};
+
+fn time(heatmap: &Heatmap, options: &RenderOptions) -> Duration {
+    // The fastest of a few runs, so other load does not count
+    (0..5)
+        .map(|_| {
+            let started = Instant::now();
+            std::hint::black_box(heatmap.render(options).unwrap());
+            started.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let mut heatmap = Heatmap::new(DomainType::Logarithmic, None, None, true, 8, &colorous::MAGMA, ValueMode::Raw, None);
+    // Every pixel painted, with values spread over several orders of magnitude
+    let mut input = String::new();
+    for block in 0..=u16::MAX as u32 {
+        let address = block.wrapping_mul(2_654_435_761) & 0xffff_0000;
+        input.push_str(&format!("{}/16 {}\n", std::net::Ipv4Addr::from(address), block % 1000 + 1));
+    }
+    heatmap.process_input_from_string(&input).unwrap();
+
+    for (curve, gamma) in [(DomainType::Linear, 1.0), (DomainType::Logarithmic, 1.0), (DomainType::Logarithmic, 0.5)] {
+        let base = RenderOptions { curve, gamma, ..heatmap.render_options() };
+        let exact = time(&heatmap, &RenderOptions { colour_lut: None, ..base.clone() });
+        let lut = time(&heatmap, &base);
+        println!(
+            "{:>12} gamma {:.1}: exact {:>7.1} ms, lut {:>7.1} ms, {:.2}x",
+            curve.to_string(),
+            gamma,
+            exact.as_secs_f64() * 1e3,
+            lut.as_secs_f64() * 1e3,
+            exact.as_secs_f64() / lut.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Time painting a generated input serially and on several threads.
+//!
+//! Run with `cargo bench --bench parallel`; set `LINES` to change the input size.
+
+use ip_heatmap::testdata::{self, GenerateConfig};
+use ip_heatmap::{DomainType, Heatmap, ValueMode};
+use std::time::Instant;
+
+/// The standard fixture: hotspots with Zipf-distributed shares over background noise.
+fn input(lines: usize) -> String {
+    let config = GenerateConfig { lines: lines as u64, ..GenerateConfig::default() };
+    testdata::generate(config).map(|line| line + "\n").collect()
+}
+
+fn main() {
+    let lines = std::env::var("LINES").ok().and_then(|lines| lines.parse().ok()).unwrap_or(2_000_000);
+    let input = input(lines);
+    let available = std::thread::available_parallelism().map_or(4, |threads| threads.get());
+    let mut serial = None;
+    let mut counts = vec![1, 2, 4, 8, available];
+    counts.retain(|&threads| threads <= available.max(2));
+    counts.sort();
+    counts.dedup();
+    for threads in counts {
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 12, &colorous::MAGMA, ValueMode::Raw, None);
+        let started = Instant::now();
+        heatmap.process_input_parallel(input.as_bytes(), threads).unwrap();
+        let elapsed = started.elapsed();
+        let serial = *serial.get_or_insert(elapsed);
+        println!(
+            "{:>3} threads: {:>8.1} ms, {:>6.2}x serial, {:.1}M lines/s",
+            threads,
+            elapsed.as_secs_f64() * 1e3,
+            serial.as_secs_f64() / elapsed.as_secs_f64(),
+            lines as f64 / elapsed.as_secs_f64() / 1e6
+        );
+    }
+}
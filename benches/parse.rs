@@ -0,0 +1,55 @@
+//! Time reading and parsing a generated input serially, the parse phase on its own
+//! and the whole run.
+//!
+//! Run with `cargo bench --bench parse`; set `LINES` to change the input size.
+
+use ip_heatmap::{DomainType, Heatmap, Phase, ValueMode};
+use std::time::{Duration, Instant};
+
+/// Mostly `address value` lines, with some prefixes, comma separated fields,
+/// integer addresses and blank lines.
+fn input(lines: usize) -> String {
+    let mut input = String::with_capacity(lines * 20);
+    for line in 0..lines {
+        let address = std::net::Ipv4Addr::from((line as u32).wrapping_mul(2_654_435_761));
+        let line = match line % 100 {
+            0 => format!("{}/24 {}\n", address, line % 7),
+            1..=9 => format!("{},{}\n", address, line % 13),
+            10 => format!("{}\t{}\n", u32::from(address), line % 5),
+            11 => "\n".to_string(),
+            _ => format!("{} {}\n", address, line % 11),
+        };
+        input.push_str(&line);
+    }
+    input
+}
+
+/// The fastest of a few runs, with the parse phase timed when `timing` is set.
+fn run(input: &str, timing: bool) -> (Duration, Duration) {
+    (0..3)
+        .map(|_| {
+            // A small map, so painting one pixel per line costs little next to parsing
+            let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 8, &colorous::MAGMA, ValueMode::Raw, None);
+            heatmap.set_timing(timing);
+            let started = Instant::now();
+            heatmap.process_input_from_reader(input.as_bytes()).unwrap();
+            (started.elapsed(), heatmap.timer().total(Phase::Parse))
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let lines = std::env::var("LINES").ok().and_then(|lines| lines.parse().ok()).unwrap_or(10_000_000);
+    let input = input(lines);
+    let (elapsed, _) = run(&input, false);
+    let (_, parse) = run(&input, true);
+    println!(
+        "{} lines: {:>8.1} ms, {:.1}M lines/s; parsing {:>8.1} ms, {:.1}M lines/s",
+        lines,
+        elapsed.as_secs_f64() * 1e3,
+        lines as f64 / elapsed.as_secs_f64() / 1e6,
+        parse.as_secs_f64() * 1e3,
+        lines as f64 / parse.as_secs_f64() / 1e6
+    );
+}
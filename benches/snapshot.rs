@@ -0,0 +1,37 @@
+//! Time taking snapshots of a full -z 8 map after painting little or all of it,
+//! against copying the whole buffer.
+//!
+//! Run with `cargo bench --bench snapshot`.
+
+use ip_heatmap::{DomainType, Heatmap, ValueMode};
+use std::time::{Duration, Instant};
+
+/// The fastest of a few runs of `paint` then `measured`, timing only `measured`.
+fn time<T>(heatmap: &mut Heatmap, paint: impl Fn(&mut Heatmap), measured: impl Fn(&mut Heatmap) -> T) -> Duration {
+    (0..5)
+        .map(|_| {
+            paint(heatmap);
+            let started = Instant::now();
+            std::hint::black_box(measured(heatmap));
+            started.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 8, &colorous::MAGMA, ValueMode::Raw, None);
+    heatmap.process_input_from_string("0.0.0.0/0 1\n").unwrap();
+    let addresses = |count: u32| -> String {
+        (0..count).map(|n| format!("{} 1\n", std::net::Ipv4Addr::from(n.wrapping_mul(2_654_435_761)))).collect()
+    };
+    let snapshot = heatmap.snapshot();
+    let copy = time(&mut heatmap, |_| {}, |_| snapshot.to_heatmap());
+    println!("{:>26}: {:>8.3} ms", "full copy", copy.as_secs_f64() * 1e3);
+    for (name, input) in [("nothing painted", String::new()), ("1 address", addresses(1)), ("1000 addresses", addresses(1000))] {
+        let snapshot = time(&mut heatmap, |heatmap| heatmap.process_input_from_string(&input).unwrap(), Heatmap::snapshot);
+        println!("{:>26}: {:>8.3} ms", format!("snapshot, {}", name), snapshot.as_secs_f64() * 1e3);
+    }
+    let everything = time(&mut heatmap, |heatmap| heatmap.process_input_from_string("0.0.0.0/0 1\n").unwrap(), Heatmap::snapshot);
+    println!("{:>26}: {:>8.3} ms", "snapshot, every pixel", everything.as_secs_f64() * 1e3);
+}
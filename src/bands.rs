@@ -0,0 +1,217 @@
+use crate::Heatmap;
+use crate::json::JsonValue;
+use crate::render::RenderOptions;
+use crate::scale::ScaleDomain;
+use std::str::FromStr;
+
+/// A named range of values: everything below `below` that is not in an earlier band.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Band {
+    pub name: String,
+    /// Exclusive upper threshold; `None` for the last, open-ended band.
+    pub below: Option<f64>,
+}
+
+/// Named thresholds such as `quiet:10,elevated:1000,hot:`, labelling the legend and
+/// optionally snapping the colouring to one colour per band.
+///
+/// Thresholds ascend and every band but the last, open-ended one has one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bands {
+    bands: Vec<Band>,
+}
+
+impl Bands {
+    pub fn bands(&self) -> &[Band] {
+        &self.bands
+    }
+
+    /// Index of the band `value` falls in.
+    pub fn band_of(&self, value: f64) -> usize {
+        self.bands
+            .iter()
+            .position(|band| band.below.is_none_or(|below| value < below))
+            .unwrap_or(self.bands.len() - 1)
+    }
+
+    /// The spec as a JSON array of `{"name": ..., "below": ...}` objects, `below`
+    /// being null for the last band.
+    pub fn to_json(&self) -> JsonValue {
+        let bands: Vec<JsonValue> = self
+            .bands
+            .iter()
+            .map(|band| {
+                let mut json = JsonValue::object();
+                json.insert("name", band.name.as_str());
+                json.insert("below", band.below);
+                json
+            })
+            .collect();
+        bands.into()
+    }
+}
+
+impl FromStr for Bands {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bands = Vec::new();
+        for item in s.split(',').map(str::trim) {
+            let (name, below) = item
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid band: {}. Use name:threshold, or name: for the last band", item))?;
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(format!("Band without a name: {}", item));
+            }
+            let below = match below.trim() {
+                "" => None,
+                threshold => match threshold.parse::<f64>() {
+                    Ok(threshold) if threshold.is_finite() => Some(threshold),
+                    _ => return Err(format!("Invalid threshold for band {}: {}", name, threshold)),
+                },
+            };
+            bands.push(Band {
+                name: name.to_string(),
+                below,
+            });
+        }
+        let (last, rest) = bands.split_last().expect("split yields at least one item");
+        if rest.is_empty() {
+            return Err("Bands need at least two names".to_string());
+        }
+        if last.below.is_some() {
+            return Err(format!("The last band, {}, must be open-ended: {}:", last.name, last.name));
+        }
+        let mut previous = f64::NEG_INFINITY;
+        for band in rest {
+            let Some(below) = band.below else {
+                return Err(format!("Only the last band can be open-ended, not {}", band.name));
+            };
+            if below <= previous {
+                return Err(format!("Band thresholds must ascend, but {} is not above {}", below, previous));
+            }
+            previous = below;
+        }
+        Ok(Self { bands })
+    }
+}
+
+impl std::fmt::Display for Bands {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, band) in self.bands.iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}:", band.name)?;
+            if let Some(below) = band.below {
+                write!(f, "{}", below)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a band falls along the colour scale, as fractions of the legend width.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LegendBand {
+    pub name: String,
+    pub start: f64,
+    pub end: f64,
+    /// The single colour of the band when the colouring is snapped to bands.
+    pub colour: Option<[u8; 3]>,
+}
+
+/// Place `bands` on `domain`; snapped bands are coloured at their midpoint.
+pub(crate) fn band_spans(bands: &Bands, domain: &ScaleDomain, options: &RenderOptions) -> Vec<LegendBand> {
+    let position = |value: f64| domain.scale(value).unwrap_or(0.0);
+    let mut start = 0.0;
+    bands
+        .bands
+        .iter()
+        .map(|band| {
+            let end = band.below.map_or(1.0, |below| position(below).max(start));
+            let middle = (start + end) / 2.0;
+            let colour = options
+                .snap_to_bands
+                .then(|| options.palette.eval(if options.gamma == 1.0 { middle } else { middle.powf(options.gamma) }));
+            let span = LegendBand {
+                name: band.name.clone(),
+                start,
+                end,
+                colour,
+            };
+            start = end;
+            span
+        })
+        .collect()
+}
+
+impl Heatmap {
+    /// Where each of `options.bands` falls on the colour scale `options` resolve to;
+    /// empty without bands.
+    pub fn legend_bands(&self, options: &RenderOptions) -> Result<Vec<LegendBand>, &'static str> {
+        match &options.bands {
+            Some(bands) => Ok(band_spans(bands, &self.calculate_domain(options)?, options)),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    #[test]
+    fn test_parse_bands() {
+        let bands: Bands = "quiet:10, elevated:1000, hot:".parse().unwrap();
+        assert_eq!(bands.bands().len(), 3);
+        assert_eq!(bands.bands()[1], Band { name: "elevated".to_string(), below: Some(1000.0) });
+        assert_eq!(bands.to_string(), "quiet:10,elevated:1000,hot:");
+        assert_eq!(
+            bands.to_json().to_string(),
+            r#"[{"name":"quiet","below":10},{"name":"elevated","below":1000},{"name":"hot","below":null}]"#
+        );
+        assert_eq!((bands.band_of(9.9), bands.band_of(10.0), bands.band_of(1e9)), (0, 1, 2));
+
+        for invalid in ["hot:", "quiet:10,hot:5", "quiet:10,elevated:10,hot:", "quiet:,hot:", "quiet", ":10,hot:", "quiet:x,hot:"] {
+            assert!(invalid.parse::<Bands>().is_err(), "{} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn test_snapped_colouring() {
+        let mut hm = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::VIRIDIS,
+            ValueMode::Raw,
+            None,
+        );
+        hm.process_input_from_string("10.0.0.1 5\n11.0.0.1 8\n12.0.0.1 50\n13.0.0.1 100\n").unwrap();
+        let options = RenderOptions {
+            bands: Some("low:10,high:".parse().unwrap()),
+            snap_to_bands: true,
+            ..hm.render_options()
+        };
+        let spans = hm.legend_bands(&options).unwrap();
+        assert_eq!((spans[0].start, spans[0].end, spans[1].end), (0.0, 0.1, 1.0));
+
+        let image = hm.render(&options).unwrap();
+        let colour = |addr: [u8; 4]| {
+            let (x, y) = hm.ip_to_xy(u32::from(std::net::Ipv4Addr::from(addr))).unwrap();
+            let [r, g, b, _] = image.get_pixel(x, y).0;
+            [r, g, b]
+        };
+        // 5 and 8 share the low band's colour, 50 and 100 the high band's
+        assert_eq!(colour([10, 0, 0, 1]), colour([11, 0, 0, 1]));
+        assert_eq!(colour([12, 0, 0, 1]), colour([13, 0, 0, 1]));
+        assert_eq!(Some(colour([10, 0, 0, 1])), spans[0].colour);
+        assert_eq!(Some(colour([13, 0, 0, 1])), spans[1].colour);
+        assert_ne!(spans[0].colour, spans[1].colour);
+    }
+}
@@ -0,0 +1,98 @@
+//! Painting checks every pixel against the buffer, so coordinates that do not fit
+//! it are counted and skipped rather than panicking deep in processing.
+
+use crate::Heatmap;
+
+impl Heatmap {
+    /// Pixels skipped because they fell outside the buffer. Coordinates come from
+    /// the map's geometry, so this stays 0 unless the buffer does not match it.
+    pub fn out_of_bounds(&self) -> u64 {
+        self.out_of_bounds
+    }
+
+    /// The row-major index of pixel (`x`, `y`) in the buffer and touched mask, or
+    /// `None`, counted in [`Heatmap::out_of_bounds`], when either does not hold it.
+    pub(crate) fn cell_index(&mut self, x: u32, y: u32) -> Option<usize> {
+        let size = self.image_size() as usize;
+        let (x, y) = (x as usize, y as usize);
+        debug_assert!(x < size && y < size, "pixel ({}, {}) is outside a {}x{} map", x, y, size, size);
+        let index = y * size + x;
+        let inside = x < size && y < size && y < self.buffer.len() && x < self.buffer.len();
+        if !inside || index / 64 >= self.touched.len() {
+            self.out_of_bounds += 1;
+            return None;
+        }
+        Some(index)
+    }
+
+    /// Clear touched bits past the last pixel, which only a damaged state file sets,
+    /// returning how many there were.
+    pub(crate) fn clear_stray_touched(&mut self) -> u64 {
+        let pixels = self.image_size() as usize * self.image_size() as usize;
+        let mut stray = 0;
+        for (word_index, word) in self.touched.iter_mut().enumerate() {
+            let first = word_index * 64;
+            let mask = match pixels.saturating_sub(first) {
+                0 => 0,
+                valid if valid >= 64 => continue,
+                valid => (1u64 << valid) - 1,
+            };
+            stray += (*word & !mask).count_ones() as u64;
+            *word &= mask;
+        }
+        stray
+    }
+
+    /// Warn once about the pixels skipped since `before`.
+    pub(crate) fn warn_out_of_bounds(&self, before: u64) {
+        let skipped = self.out_of_bounds - before;
+        if skipped > 0 {
+            let size = self.image_size();
+            log::warn!(
+                count = skipped;
+                "{} pixels fell outside the {}x{} buffer and were not painted; the buffer does not match bits_per_pixel {}",
+                skipped,
+                size,
+                size,
+                self.bits_per_pixel
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cells::{Grid, Slab};
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8) -> Heatmap {
+        Heatmap::new(DomainType::Linear, None, None, true, bits_per_pixel, &colorous::MAGMA, ValueMode::Raw, None)
+    }
+
+    #[test]
+    fn test_mis_sized_buffer_is_not_indexed() {
+        let mut heatmap = heatmap(16);
+        // A buffer for a quarter of the map, as a mismatched state would leave
+        heatmap.buffer = Grid::new(128, 0);
+        heatmap.touched = Slab::heap(vec![0u64; 128 * 128 / 64]);
+        heatmap.process_input_from_string("0.0.0.1 5\n128.0.0.1 3\n255.255.0.0/16 2\n").unwrap();
+        assert_eq!(heatmap.lines_processed(), 3);
+        assert_eq!(heatmap.out_of_bounds(), 2);
+        assert_eq!(heatmap.stats(&[]).out_of_bounds, 2);
+        assert!(heatmap.stats(&[]).to_text(crate::TextStyle::ASCII).contains("| out of bounds  | 2     |"));
+    }
+
+    #[test]
+    fn test_stray_touched_bits_are_cleared() {
+        // 2x2 pixels share one word of the mask
+        let mut small = heatmap(30);
+        small.touched[0] = u64::MAX;
+        assert_eq!(small.clear_stray_touched(), 60);
+        assert_eq!(small.touched_pixels(), 4);
+        assert_eq!(small.clear_stray_touched(), 0);
+        let mut full = heatmap(16);
+        full.touched[5] = u64::MAX;
+        assert_eq!(full.clear_stray_touched(), 0);
+    }
+}
@@ -0,0 +1,106 @@
+//! Brace groups in address tokens, as written by IPAM exports: `10.20.{0-255}.0/24`
+//! and `192.0.2.{1,5,9}` stand for one address or prefix per item of the group.
+
+/// Most tokens a single address token may expand to.
+pub const MAX_BRACE_EXPANSIONS: usize = 65536;
+
+/// The tokens `token` stands for: itself if it has no brace group, else one per item
+/// of its only group. Items are separated by commas, and each is text or a decimal
+/// range `a-b`.
+pub(crate) fn expand_braces(token: &str) -> Result<Vec<String>, String> {
+    let Some(open) = token.find('{') else {
+        if token.contains('}') {
+            return Err("'}' without '{'".to_string());
+        }
+        return Ok(vec![token.to_string()]);
+    };
+    let (prefix, rest) = (&token[..open], &token[open + 1..]);
+    if prefix.contains('}') {
+        return Err("'}' without '{'".to_string());
+    }
+    let close = rest.find('}').ok_or_else(|| "'{' without '}'".to_string())?;
+    let (group, suffix) = (&rest[..close], &rest[close + 1..]);
+    if group.contains('{') {
+        return Err("nested brace groups".to_string());
+    }
+    if suffix.contains(['{', '}']) {
+        return Err("more than one brace group".to_string());
+    }
+
+    let too_many = || format!("brace group expands to more than {} addresses", MAX_BRACE_EXPANSIONS);
+    let mut items = Vec::new();
+    for item in group.split(',').map(str::trim) {
+        if item.is_empty() {
+            return Err(format!("empty item in {{{}}}", group));
+        }
+        let Some((low, high)) = item.split_once('-') else {
+            if items.len() == MAX_BRACE_EXPANSIONS {
+                return Err(too_many());
+            }
+            items.push(item.to_string());
+            continue;
+        };
+        let (low, high) = (parse_bound(low, item)?, parse_bound(high, item)?);
+        if low > high {
+            return Err(format!("range {} runs backwards", item));
+        }
+        if items.len() as u64 + (high - low) as u64 + 1 > MAX_BRACE_EXPANSIONS as u64 {
+            return Err(too_many());
+        }
+        items.extend((low..=high).map(|number| number.to_string()));
+    }
+    Ok(items.into_iter().map(|item| format!("{}{}{}", prefix, item, suffix)).collect())
+}
+
+fn parse_bound(bound: &str, item: &str) -> Result<u32, String> {
+    match bound.bytes().all(|byte| byte.is_ascii_digit()) {
+        true => bound.parse().map_err(|_| format!("invalid range {}", item)),
+        false => Err(format!("invalid range {}", item)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges_and_lists() {
+        assert_eq!(expand_braces("10.0.0.1").unwrap(), ["10.0.0.1"]);
+        assert_eq!(expand_braces("192.0.2.{1,5,9}").unwrap(), ["192.0.2.1", "192.0.2.5", "192.0.2.9"]);
+        let expanded = expand_braces("10.20.{0-255}.0/24").unwrap();
+        assert_eq!(expanded.len(), 256);
+        assert_eq!((expanded[0].as_str(), expanded[255].as_str()), ("10.20.0.0/24", "10.20.255.0/24"));
+        assert_eq!(expand_braces("{1-2,7}.0.0.0/8").unwrap(), ["1.0.0.0/8", "2.0.0.0/8", "7.0.0.0/8"]);
+        assert_eq!(expand_braces("10.0.0.0/{8-9}").unwrap(), ["10.0.0.0/8", "10.0.0.0/9"]);
+        assert_eq!(expand_braces("10.0.0.{3-3}").unwrap(), ["10.0.0.3"]);
+        assert_eq!(expand_braces("10.0.0.{1, 2}").unwrap(), ["10.0.0.1", "10.0.0.2"]);
+    }
+
+    #[test]
+    fn test_expansion_cap() {
+        assert_eq!(expand_braces("{0-65535}").unwrap().len(), MAX_BRACE_EXPANSIONS);
+        assert!(expand_braces("{0-65536}").unwrap_err().contains("more than 65536"));
+        assert!(expand_braces("{0-4294967295}").unwrap_err().contains("more than 65536"));
+        assert!(expand_braces("{0-65535,1}").unwrap_err().contains("more than 65536"));
+    }
+
+    #[test]
+    fn test_malformed_groups() {
+        for (token, error) in [
+            ("10.{0-{1-2}}.0.0", "nested"),
+            ("10.{1,2}.{3,4}.0", "more than one"),
+            ("10.{1,2.0.0", "without '}'"),
+            ("10.1}.0.0", "without '{'"),
+            ("10.}{1}.0.0", "without '{'"),
+            ("10.{}.0.0", "empty item"),
+            ("10.{1,,2}.0.0", "empty item"),
+            ("10.{5-1}.0.0", "runs backwards"),
+            ("10.{a-b}.0.0", "invalid range a-b"),
+            ("10.{1-}.0.0", "invalid range 1-"),
+            ("10.{-1}.0.0", "invalid range -1"),
+        ] {
+            let message = expand_braces(token).unwrap_err();
+            assert!(message.contains(error), "{}: {}", token, message);
+        }
+    }
+}
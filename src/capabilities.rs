@@ -0,0 +1,151 @@
+//! What the wasm module accepts, read from the tables its parsers consult, so a
+//! page can build its controls at runtime without a list that drifts from the code.
+
+use crate::input::{InputFormat, MAX_LINE_LENGTH};
+use crate::json::JsonValue;
+use crate::palette::Palette;
+use crate::scale::DomainType;
+use crate::ValueMode;
+
+/// The smallest `bits_per_pixel` the wasm module renders, a 4096x4096 map.
+const WASM_MIN_BITS_PER_PIXEL: u8 = 8;
+/// The largest `bits_per_pixel` the wasm module renders, a 256x256 map.
+const WASM_MAX_BITS_PER_PIXEL: u8 = 24;
+
+/// The names and limits the wasm module accepts. Names include aliases, in the order
+/// of their tables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    /// The crate version, e.g. `0.1.0`.
+    pub version: &'static str,
+    /// Curve names, see [`DomainType::NAMES`]; `symlog` also takes `:threshold`.
+    pub curves: Vec<&'static str>,
+    /// Built-in palette names, see [`Palette::builtin_names`].
+    pub palettes: Vec<&'static str>,
+    /// Input formats of text, see [`InputFormat::NAMES`]; binary records cannot be
+    /// passed as a string.
+    pub input_formats: Vec<&'static str>,
+    /// Value mode names, see [`ValueMode::NAMES`].
+    pub value_modes: Vec<&'static str>,
+    pub min_bits_per_pixel: u8,
+    pub max_bits_per_pixel: u8,
+    /// Only even `bits_per_pixel` map to a square.
+    pub even_bits_per_pixel_only: bool,
+    /// Longer lines are rejected.
+    pub max_line_bytes: usize,
+    /// The largest input accepted, if there is a limit; only memory bounds it.
+    pub max_input_bytes: Option<u64>,
+}
+
+impl Capabilities {
+    /// The capabilities of this build.
+    pub fn current() -> Self {
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            curves: DomainType::NAMES.iter().map(|(name, _)| *name).collect(),
+            palettes: Palette::builtin_names().collect(),
+            input_formats: InputFormat::NAMES
+                .iter()
+                .filter(|(_, format)| format.is_text())
+                .map(|(name, _)| *name)
+                .collect(),
+            value_modes: ValueMode::NAMES.iter().map(|(name, _)| *name).collect(),
+            min_bits_per_pixel: WASM_MIN_BITS_PER_PIXEL,
+            max_bits_per_pixel: WASM_MAX_BITS_PER_PIXEL,
+            even_bits_per_pixel_only: true,
+            max_line_bytes: MAX_LINE_LENGTH,
+            max_input_bytes: None,
+        }
+    }
+
+    /// The capabilities as a JSON object with camelCase keys, as the page sees them.
+    pub fn to_json(&self) -> JsonValue {
+        let mut limits = JsonValue::object();
+        limits.insert("minBitsPerPixel", self.min_bits_per_pixel);
+        limits.insert("maxBitsPerPixel", self.max_bits_per_pixel);
+        limits.insert("evenBitsPerPixelOnly", self.even_bits_per_pixel_only);
+        limits.insert("maxLineBytes", self.max_line_bytes);
+        limits.insert("maxInputBytes", self.max_input_bytes);
+        let mut json = JsonValue::object();
+        json.insert("version", self.version);
+        json.insert("curves", self.curves.clone());
+        json.insert("palettes", self.palettes.clone());
+        json.insert("inputFormats", self.input_formats.clone());
+        json.insert("valueModes", self.value_modes.clone());
+        json.insert("limits", limits);
+        json
+    }
+
+    /// Check `bits_per_pixel` against the limits.
+    pub fn check_bits_per_pixel(&self, bits_per_pixel: u8) -> Result<(), String> {
+        if bits_per_pixel < self.min_bits_per_pixel {
+            return Err(format!(
+                "bits_per_pixel must be at least {} (got {}). Each pixel represents 2^bits_per_pixel IPs.",
+                self.min_bits_per_pixel, bits_per_pixel
+            ));
+        }
+        if bits_per_pixel > self.max_bits_per_pixel {
+            return Err(format!("bits_per_pixel cannot exceed {} (got {})", self.max_bits_per_pixel, bits_per_pixel));
+        }
+        if self.even_bits_per_pixel_only && !bits_per_pixel.is_multiple_of(2) {
+            return Err(format!("bits_per_pixel must be even (got {})", bits_per_pixel));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    /// Every name of every table, and some that no parser takes.
+    fn candidates(capabilities: &Capabilities) -> Vec<&'static str> {
+        let mut names = vec!["", "nope", "logx", "symlog:", "raw-u32", "magmas", "text,csv", "Scaled"];
+        for list in
+            [&capabilities.curves, &capabilities.palettes, &capabilities.input_formats, &capabilities.value_modes]
+        {
+            names.extend(list.iter().copied());
+        }
+        names
+    }
+
+    fn accepts<T: FromStr>(name: &str) -> bool {
+        name.parse::<T>().is_ok()
+    }
+
+    #[test]
+    fn test_every_accepted_name_is_listed() {
+        let capabilities = Capabilities::current();
+        for name in candidates(&capabilities) {
+            // Parsers ignore case; the lists hold the lowercase names
+            let listed = |list: &[&str]| list.contains(&name.to_lowercase().as_str());
+            assert_eq!(accepts::<DomainType>(name), listed(&capabilities.curves), "curve {:?}", name);
+            assert_eq!(Palette::builtin(name).is_some(), listed(&capabilities.palettes), "palette {:?}", name);
+            assert_eq!(accepts::<ValueMode>(name), listed(&capabilities.value_modes), "value mode {:?}", name);
+            let text = name.parse::<InputFormat>().is_ok_and(InputFormat::is_text);
+            assert_eq!(text, listed(&capabilities.input_formats), "input format {:?}", name);
+        }
+        assert!(!capabilities.input_formats.contains(&"raw-u32v"));
+        assert!(capabilities.palettes.contains(&"accessible"));
+    }
+
+    #[test]
+    fn test_bits_per_pixel_limits() {
+        let capabilities = Capabilities::current();
+        for bits_per_pixel in 0..=32 {
+            let listed = (capabilities.min_bits_per_pixel..=capabilities.max_bits_per_pixel).contains(&bits_per_pixel)
+                && bits_per_pixel.is_multiple_of(2);
+            assert_eq!(capabilities.check_bits_per_pixel(bits_per_pixel).is_ok(), listed, "{}", bits_per_pixel);
+        }
+    }
+
+    #[test]
+    fn test_capabilities_json() {
+        let json = Capabilities::current().to_json().to_string();
+        let start = format!("{{\"version\":\"{}\",\"curves\":[\"linear\",", env!("CARGO_PKG_VERSION"));
+        assert!(json.starts_with(&start), "{}", json);
+        assert!(json.contains("\"inputFormats\":[\"text\",\"csv\",\"cells\"]"), "{}", json);
+        assert!(json.contains("\"maxInputBytes\":null"), "{}", json);
+    }
+}
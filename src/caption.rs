@@ -0,0 +1,84 @@
+use crate::Heatmap;
+use crate::strings::Strings;
+use ipnet::Ipv4Net;
+
+/// `value` with commas between groups of three digits.
+pub(crate) fn group_digits(value: u64) -> String {
+    let digits = value.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+impl Heatmap {
+    /// A caption stating what one pixel stands for, e.g. `1 pixel = /24 (256 addresses)`,
+    /// in the words of `strings`.
+    ///
+    /// A downsampled map states its factor, a map cropped to `crop` the prefix shown,
+    /// and one with an odd `bits_per_pixel`, whose square only fits half the curve,
+    /// the part of the address space it covers.
+    pub fn pixel_caption(&self, crop: Option<&Ipv4Net>, strings: &Strings) -> String {
+        let geometry = self.geometry();
+        let addresses = geometry.ips_per_pixel;
+        let key = if addresses == 1 { "caption.pixel-one-address" } else { "caption.pixel" };
+        let prefix = geometry.prefix_len_per_pixel;
+        let mut caption = strings.format(key, &[("prefix", &prefix), ("addresses", &group_digits(addresses))]);
+        if let Some(source) = self.downsampled_from {
+            let factor = 1u32 << ((self.bits_per_pixel - source) / 2);
+            caption = strings.format("caption.downsampled", &[("caption", &caption), ("factor", &factor)]);
+        }
+        match crop {
+            Some(net) => caption = strings.format("caption.crop", &[("caption", &caption), ("prefix", net)]),
+            None if self.bits_per_pixel % 2 == 1 => caption = strings.format("caption.half-map", &[("caption", &caption)]),
+            None => {}
+        }
+        caption
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Aggregation, DomainType, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8) -> Heatmap {
+        Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_group_digits() {
+        assert_eq!(group_digits(1), "1");
+        assert_eq!(group_digits(256), "256");
+        assert_eq!(group_digits(4096), "4,096");
+        assert_eq!(group_digits(4_294_967_296), "4,294,967,296");
+    }
+
+    #[test]
+    fn test_pixel_caption() {
+        assert_eq!(heatmap(8).pixel_caption(None, &Strings::default()), "1 pixel = /24 (256 addresses)");
+        assert_eq!(heatmap(32).pixel_caption(None, &Strings::default()), "1 pixel = /0 (4,294,967,296 addresses)");
+        assert_eq!(heatmap(15).pixel_caption(None, &Strings::default()), "1 pixel = /17 (32,768 addresses), map covers 0.0.0.0/1");
+        let crop = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(heatmap(16).pixel_caption(Some(&crop), &Strings::default()), "1 pixel = /16 (65,536 addresses) in 10.0.0.0/8");
+
+        // 256 pixels a side downsampled to 64 is 4x, and again to 16 is 16x overall
+        let reduced = heatmap(16).downsample(64, Aggregation::Max).unwrap();
+        assert_eq!(reduced.pixel_caption(None, &Strings::default()), "1 pixel = /12 (1,048,576 addresses) after 4x downsampling");
+        let reduced = reduced.downsample(16, Aggregation::Max).unwrap();
+        assert_eq!(reduced.pixel_caption(None, &Strings::default()), "1 pixel = /8 (16,777,216 addresses) after 16x downsampling");
+    }
+}
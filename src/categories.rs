@@ -0,0 +1,262 @@
+//! Colours of categorical values that stay the same from one run to the next.
+//!
+//! A mapping file holds one JSON object from category to colour, keys in
+//! ascending order so that it diffs well:
+//!
+//! ```json
+//! {
+//!   "3": "#b8cb4d",
+//!   "13335": "#cb4f4d"
+//! }
+//! ```
+
+use crate::palette::parse_hex_colour;
+use crate::{Heatmap, JsonValue, ValueMode};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Saturation and lightness of hashed colours.
+const SATURATION: f64 = 0.55;
+const LIGHTNESS: f64 = 0.55;
+
+/// Hashed hues closer than this many degrees to a colour already in use are moved.
+const MIN_HUE_GAP: f64 = 12.0;
+
+/// Hues tried for a new category before settling for the one farthest from the rest.
+const HUE_ATTEMPTS: u32 = 16;
+
+/// A colour for each category, read from and saved to a mapping file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CategoryColours {
+    colours: BTreeMap<i32, [u8; 3]>,
+    /// Categories assigned since the mapping was read.
+    added: usize,
+}
+
+impl CategoryColours {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the JSON of a mapping file.
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        let JsonValue::Object(members) = text.parse::<JsonValue>()? else {
+            return Err("Category colours must be a JSON object".to_string());
+        };
+        let mut colours = BTreeMap::new();
+        for (key, value) in members {
+            let category = key.parse::<i32>().map_err(|_| format!("Invalid category: {}. Use an integer", key))?;
+            let JsonValue::String(colour) = value else {
+                return Err(format!("The colour of category {} must be a string such as \"#rrggbb\"", key));
+            };
+            colours.insert(category, parse_hex_colour(&colour)?);
+        }
+        Ok(Self { colours, added: 0 })
+    }
+
+    /// Read a mapping file, or start an empty mapping if it does not exist.
+    pub fn load(path: &str) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => {
+                Self::from_json(&text).map_err(anyhow::Error::msg).with_context(|| format!("Failed to read {}", path))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", path)),
+        }
+    }
+
+    /// The mapping as a file's JSON, one category per line.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\n");
+        for (i, (category, [r, g, b])) in self.colours.iter().enumerate() {
+            let separator = if i + 1 < self.colours.len() { "," } else { "" };
+            let _ = writeln!(json, "  \"{}\": \"#{:02x}{:02x}{:02x}\"{}", category, r, g, b, separator);
+        }
+        json.push_str("}\n");
+        json
+    }
+
+    /// Write the mapping to `path`, replacing it atomically.
+    pub fn save(&self, path: &str) -> Result<()> {
+        crate::write_atomic(path, |writer| Ok(std::io::Write::write_all(writer, self.to_json().as_bytes())?))
+            .with_context(|| format!("Failed to write category colours to {}", path))
+    }
+
+    pub fn get(&self, category: i32) -> Option<[u8; 3]> {
+        self.colours.get(&category).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.colours.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colours.is_empty()
+    }
+
+    /// Number of categories assigned since the mapping was read.
+    pub fn added(&self) -> usize {
+        self.added
+    }
+
+    /// The colour of `category`, giving it one if it has none yet.
+    ///
+    /// New categories get a hue hashed from their label, so the same category
+    /// tends to get the same colour even in separate mappings. A hue too close to
+    /// one already in use is hashed again, up to [`HUE_ATTEMPTS`] times, after
+    /// which the hue farthest from the others is taken.
+    pub fn assign(&mut self, category: i32) -> [u8; 3] {
+        if let Some(colour) = self.get(category) {
+            return colour;
+        }
+        let taken: Vec<f64> = self.colours.values().map(|&colour| hue_of(colour)).collect();
+        let gap = |hue: f64| taken.iter().map(|&other| hue_distance(hue, other)).fold(f64::INFINITY, f64::min);
+        let mut best = hashed_hue(category, 0);
+        for attempt in 0..HUE_ATTEMPTS {
+            let hue = hashed_hue(category, attempt);
+            if gap(hue) >= MIN_HUE_GAP {
+                best = hue;
+                break;
+            }
+            if gap(hue) > gap(best) {
+                best = hue;
+            }
+        }
+        let colour = hsl_to_rgb(best, SATURATION, LIGHTNESS);
+        self.colours.insert(category, colour);
+        self.added += 1;
+        colour
+    }
+}
+
+/// The hue of `category` hashed with `attempt`, in degrees.
+fn hashed_hue(category: i32, attempt: u32) -> f64 {
+    let label = match attempt {
+        0 => category.to_string(),
+        attempt => format!("{}#{}", category, attempt),
+    };
+    // FNV-1a, which is stable across platforms and releases
+    let hash = label.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3));
+    (hash % 3600) as f64 / 10.0
+}
+
+fn hue_distance(a: f64, b: f64) -> f64 {
+    let distance = (a - b).rem_euclid(360.0);
+    distance.min(360.0 - distance)
+}
+
+fn hue_of([r, g, b]: [u8; 3]) -> f64 {
+    let (r, g, b) = (r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0);
+    let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+    let chroma = max - min;
+    if chroma == 0.0 {
+        return 0.0;
+    }
+    let sector = match max {
+        max if max == r => ((g - b) / chroma).rem_euclid(6.0),
+        max if max == g => (b - r) / chroma + 2.0,
+        _ => (r - g) / chroma + 4.0,
+    };
+    sector * 60.0
+}
+
+fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> [u8; 3] {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let sector = hue / 60.0;
+    let x = chroma * (1.0 - (sector.rem_euclid(2.0) - 1.0).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |value: f64| ((value + m) * 255.0).round() as u8;
+    [channel(r), channel(g), channel(b)]
+}
+
+impl Heatmap {
+    /// Give every category painted on the map a colour in `colours`, in ascending
+    /// order so that the new colours do not depend on the order of the input.
+    pub fn assign_category_colours(&self, colours: &mut CategoryColours) {
+        if self.value_mode != ValueMode::Categorical {
+            return;
+        }
+        let categories: BTreeSet<i32> = self.buffer.cells().iter().copied().filter(|&value| value >= 0).collect();
+        for category in categories {
+            colours.assign(category);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+
+    fn heatmap(input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            false,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Categorical,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_shared_categories_keep_their_colours() {
+        let mut first = CategoryColours::new();
+        heatmap("10.0.0.1 3\n10.1.0.1 13335\n10.2.0.1 15169\n").assign_category_colours(&mut first);
+        assert_eq!(first.added(), 3);
+
+        // A second run over other categories reads the first run's mapping
+        let mut second = CategoryColours::from_json(&first.to_json()).unwrap();
+        assert_eq!(second, CategoryColours { added: 0, ..first.clone() });
+        heatmap("10.0.0.1 15169\n10.1.0.1 7\n10.2.0.1 3\n").assign_category_colours(&mut second);
+        assert_eq!(second.added(), 1);
+        for category in [3, 13335, 15169] {
+            assert_eq!(second.get(category), first.get(category), "{}", category);
+        }
+        assert!(first.get(7).is_none() && second.get(7).is_some());
+    }
+
+    #[test]
+    fn test_new_hues_keep_away_from_taken_ones() {
+        let mut colours = CategoryColours::new();
+        for category in 0..12 {
+            colours.assign(category);
+        }
+        let hues: Vec<f64> = (0..12).map(|category| hue_of(colours.get(category).unwrap())).collect();
+        for (i, &hue) in hues.iter().enumerate() {
+            for &other in &hues[i + 1..] {
+                assert!(hue_distance(hue, other) >= MIN_HUE_GAP - 1.0, "{} and {}", hue, other);
+            }
+        }
+        // The same label hashes to the same hue in a mapping of its own
+        let mut alone = CategoryColours::new();
+        assert_eq!(alone.assign(0), colours.get(0).unwrap());
+        assert_eq!(alone.assign(0), alone.get(0).unwrap());
+        assert_eq!(alone.added(), 1);
+    }
+
+    #[test]
+    fn test_mapping_files() {
+        let colours = CategoryColours::from_json("{\"5\": \"#ff0000\", \"-2\": \"00ff00\"}").unwrap();
+        assert_eq!(colours.get(5), Some([255, 0, 0]));
+        assert_eq!(colours.to_json(), "{\n  \"-2\": \"#00ff00\",\n  \"5\": \"#ff0000\"\n}\n");
+        assert_eq!(CategoryColours::from_json("{}").unwrap().to_json(), "{\n}\n");
+        for invalid in ["[]", "{\"x\": \"#ff0000\"}", "{\"1\": 5}", "{\"1\": \"red\"}", "{"] {
+            assert!(CategoryColours::from_json(invalid).is_err(), "{} was accepted", invalid);
+        }
+        assert_eq!(hue_of(hsl_to_rgb(200.0, SATURATION, LIGHTNESS)).round(), 200.0);
+    }
+}
@@ -0,0 +1,202 @@
+//! Storage for the cell buffer and touched mask: a heap allocation, or a region of a
+//! memory-mapped state file (see [`crate::Heatmap::map_state`]).
+
+use crate::mapped::Mapping;
+use crate::memory::{MemoryEstimate, MemoryPlan};
+use std::ops::{Deref, DerefMut, Index, IndexMut};
+use std::rc::Rc;
+use std::slice::{ChunksExact, ChunksExactMut};
+
+/// A fixed-length run of values, dereferencing to a slice.
+pub(crate) struct Slab<T> {
+    storage: Storage<T>,
+}
+
+enum Storage<T> {
+    Heap(Vec<T>),
+    /// `len` values at `ptr`, inside `mapping` (which keeps the memory mapped).
+    Mapped {
+        _mapping: Rc<Mapping>,
+        ptr: *mut T,
+        len: usize,
+    },
+}
+
+impl<T> Slab<T> {
+    pub(crate) fn heap(values: Vec<T>) -> Self {
+        Slab {
+            storage: Storage::Heap(values),
+        }
+    }
+
+    /// `len` values starting `offset` bytes into `mapping`.
+    ///
+    /// The region must lie inside the mapping, be aligned for `T`, and not overlap any
+    /// other slab of the same mapping.
+    pub(crate) fn mapped(mapping: &Rc<Mapping>, offset: usize, len: usize) -> Self {
+        assert!(offset + len * size_of::<T>() <= mapping.len());
+        let ptr = mapping.as_ptr().wrapping_add(offset).cast::<T>();
+        assert!(ptr.is_aligned());
+        Slab {
+            storage: Storage::Mapped {
+                _mapping: Rc::clone(mapping),
+                ptr,
+                len,
+            },
+        }
+    }
+}
+
+impl<T> Deref for Slab<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.storage {
+            Storage::Heap(values) => values,
+            // Safety: `mapped` checked bounds and alignment, and the mapping outlives us
+            Storage::Mapped { ptr, len, .. } => unsafe { std::slice::from_raw_parts(*ptr, *len) },
+        }
+    }
+}
+
+impl<T> DerefMut for Slab<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match &mut self.storage {
+            Storage::Heap(values) => values,
+            // Safety: as for `deref`; slabs of one mapping never overlap
+            Storage::Mapped { ptr, len, .. } => unsafe { std::slice::from_raw_parts_mut(*ptr, *len) },
+        }
+    }
+}
+
+impl<T: PartialEq> PartialEq for Slab<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Slab<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// Cells in a chunk of the buffer shared by snapshots, see [`Grid::take_dirty`].
+pub(crate) const CHUNK_CELLS: usize = 1 << 16;
+
+/// The square cell buffer, indexed `[y][x]`.
+///
+/// The rows are grouped into chunks of whole rows, [`CHUNK_CELLS`] cells or one row
+/// if that is larger, and every mutable access marks the chunks it may change, so
+/// snapshots only copy the chunks written since the last one.
+pub(crate) struct Grid {
+    size: usize,
+    cells: Slab<i32>,
+    chunk_rows: usize,
+    /// Chunks written since the last [`Grid::take_dirty`].
+    dirty: Vec<bool>,
+}
+
+impl Grid {
+    pub(crate) fn new(size: usize, init_value: i32) -> Self {
+        Self::from_slab(size, Slab::heap(vec![init_value; size * size]))
+    }
+
+    pub(crate) fn from_slab(size: usize, cells: Slab<i32>) -> Self {
+        assert_eq!(cells.len(), size * size);
+        let chunk_rows = (CHUNK_CELLS / size.max(1)).clamp(1, size.max(1));
+        let dirty = vec![true; size.div_ceil(chunk_rows)];
+        Grid { size, cells, chunk_rows, dirty }
+    }
+
+    /// Number of rows.
+    pub(crate) fn len(&self) -> usize {
+        self.size
+    }
+
+    /// The rows, top to bottom.
+    pub(crate) fn iter(&self) -> ChunksExact<'_, i32> {
+        self.cells.chunks_exact(self.size)
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> ChunksExactMut<'_, i32> {
+        self.dirty.fill(true);
+        self.cells.chunks_exact_mut(self.size)
+    }
+
+    /// All cells, row-major.
+    pub(crate) fn cells(&self) -> &[i32] {
+        &self.cells
+    }
+
+    pub(crate) fn cells_mut(&mut self) -> &mut [i32] {
+        self.dirty.fill(true);
+        &mut self.cells
+    }
+
+    /// Rows in each chunk; the last chunk may have fewer.
+    pub(crate) fn chunk_rows(&self) -> usize {
+        self.chunk_rows
+    }
+
+    /// Which chunks were written since the last call, or since the grid was made.
+    pub(crate) fn take_dirty(&mut self) -> Vec<bool> {
+        let clean = vec![false; self.dirty.len()];
+        std::mem::replace(&mut self.dirty, clean)
+    }
+}
+
+impl Index<usize> for Grid {
+    type Output = [i32];
+
+    fn index(&self, y: usize) -> &[i32] {
+        &self.cells[y * self.size..(y + 1) * self.size]
+    }
+}
+
+impl IndexMut<usize> for Grid {
+    fn index_mut(&mut self, y: usize) -> &mut [i32] {
+        self.dirty[y / self.chunk_rows] = true;
+        &mut self.cells[y * self.size..(y + 1) * self.size]
+    }
+}
+
+impl<'a> IntoIterator for &'a Grid {
+    type Item = &'a [i32];
+    type IntoIter = ChunksExact<'a, i32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl PartialEq for Grid {
+    fn eq(&self, other: &Self) -> bool {
+        self.size == other.size && self.cells == other.cells
+    }
+}
+
+impl PartialEq<Vec<Vec<i32>>> for Grid {
+    fn eq(&self, rows: &Vec<Vec<i32>>) -> bool {
+        self.len() == rows.len() && self.iter().zip(rows).all(|(a, b)| a == b.as_slice())
+    }
+}
+
+impl std::fmt::Debug for Grid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+/// Bytes of the cells and of the touched mask of a `size` by `size` map.
+pub(crate) fn buffer_bytes(size: u32) -> (u64, u64) {
+    let pixels = size as u64 * size as u64;
+    (pixels * 4, pixels.div_ceil(64) * 8)
+}
+
+/// Add the map's cell buffer and touched mask.
+pub(crate) fn add_memory(plan: &MemoryPlan, estimate: &mut MemoryEstimate) {
+    let (cells, touched) = buffer_bytes(plan.image_size());
+    estimate.add("cells", cells);
+    estimate.add("touched mask", touched);
+}
+
@@ -0,0 +1,234 @@
+//! Prefixes whose value changed between two heatmaps.
+
+use crate::hilbert::hilbert_d2xy;
+use crate::json::JsonValue;
+use crate::{Geometry, Heatmap, ValueMode};
+use anyhow::{Result, bail};
+use ipnet::Ipv4Net;
+use std::io::Write;
+use std::net::Ipv4Addr;
+
+/// How a region changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    /// Nothing was painted there before.
+    Appeared,
+    Increased,
+    Decreased,
+    /// Nothing is painted there now.
+    WentDark,
+}
+
+impl std::fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Appeared => "appeared",
+            Self::Increased => "increased",
+            Self::Decreased => "decreased",
+            Self::WentDark => "went-dark",
+        })
+    }
+}
+
+/// A prefix whose pixels all changed the same way, with its total value before and after.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub net: Ipv4Net,
+    pub old: i64,
+    pub new: i64,
+    pub kind: ChangeKind,
+}
+
+impl Change {
+    pub fn delta(&self) -> i64 {
+        self.new - self.old
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::object();
+        json.insert("prefix", self.net.to_string());
+        json.insert("old", self.old);
+        json.insert("new", self.new);
+        json.insert("delta", self.delta());
+        json.insert("change", self.kind.to_string());
+        json
+    }
+}
+
+/// Write `changes` as a table with a header row.
+pub fn write_changes<W: Write>(mut writer: W, changes: &[Change]) -> std::io::Result<()> {
+    writeln!(writer, "{:<18} {:>12} {:>12} {:>12}  change", "prefix", "old", "new", "delta")?;
+    for change in changes {
+        writeln!(
+            writer,
+            "{:<18} {:>12} {:>12} {:>+12}  {}",
+            change.net.to_string(),
+            change.old,
+            change.new,
+            change.delta(),
+            change.kind
+        )?;
+    }
+    Ok(())
+}
+
+/// `changes` and the threshold they were found with as a JSON object.
+pub fn changes_to_json(changes: &[Change], threshold: i64) -> JsonValue {
+    let mut json = JsonValue::object();
+    json.insert("threshold", threshold);
+    let changes: Vec<JsonValue> = changes.iter().map(Change::to_json).collect();
+    json.insert("changes", changes);
+    json
+}
+
+impl Heatmap {
+    /// The regions whose value changed by more than `threshold` from this heatmap to
+    /// `newer`, sorted by the size of the change, largest first.
+    ///
+    /// Pixels that changed are aggregated into the fewest prefixes whose pixels all
+    /// changed the same way; the threshold applies to each pixel, and a region's
+    /// values are the totals of its pixels.
+    pub fn changes(&self, newer: &Heatmap, threshold: i64) -> Result<Vec<Change>> {
+        if self.bits_per_pixel != newer.bits_per_pixel {
+            bail!(
+                "Cannot compare heatmaps with different bits_per_pixel ({} and {})",
+                self.bits_per_pixel,
+                newer.bits_per_pixel
+            );
+        }
+        if self.value_mode == ValueMode::Categorical || newer.value_mode == ValueMode::Categorical {
+            bail!("Categorical values are labels, so they have no changes to measure");
+        }
+        if threshold < 0 {
+            bail!("The change threshold cannot be negative, got {}", threshold);
+        }
+
+        let Geometry { order, prefix_len_per_pixel: prefix_len, .. } = self.geometry();
+        let value = |heatmap: &Heatmap, x: usize, y: usize| match heatmap.is_touched(x, y) {
+            true => Some(heatmap.buffer[y][x] as i64),
+            false => None,
+        };
+        // Changed pixels in address order, grouped by kind
+        let mut pixels: [Vec<(Ipv4Net, i64, i64)>; 4] = Default::default();
+        for d in 0..1u64 << (2 * order) {
+            let Some((x, y)) = hilbert_d2xy(d, order) else {
+                continue;
+            };
+            let (x, y) = (x as usize, y as usize);
+            let (old, new) = (value(self, x, y), value(newer, x, y));
+            let delta = new.unwrap_or(0) - old.unwrap_or(0);
+            if delta.abs() <= threshold || (old.is_none() && new.is_none()) {
+                continue;
+            }
+            let kind = match (old, new) {
+                (None, _) => ChangeKind::Appeared,
+                (_, None) => ChangeKind::WentDark,
+                _ if delta > 0 => ChangeKind::Increased,
+                _ => ChangeKind::Decreased,
+            };
+            let network = Ipv4Addr::from(((d << self.bits_per_pixel) & 0xffff_ffff) as u32);
+            let net = Ipv4Net::new(network, prefix_len).expect("prefix length is at most 32");
+            pixels[kind as usize].push((net, old.unwrap_or(0), new.unwrap_or(0)));
+        }
+
+        let kinds = [ChangeKind::Appeared, ChangeKind::Increased, ChangeKind::Decreased, ChangeKind::WentDark];
+        let mut changes = Vec::new();
+        for (kind, pixels) in kinds.into_iter().zip(pixels) {
+            let nets: Vec<Ipv4Net> = pixels.iter().map(|(net, _, _)| *net).collect();
+            // Both are in address order, so each region takes the next run of pixels
+            let mut pixels = pixels.into_iter().peekable();
+            for net in Ipv4Net::aggregate(&nets) {
+                let (mut old, mut new) = (0, 0);
+                while let Some((_, pixel_old, pixel_new)) = pixels.next_if(|(pixel, _, _)| net.contains(pixel)) {
+                    old += pixel_old;
+                    new += pixel_new;
+                }
+                changes.push(Change { net, old, new, kind });
+            }
+        }
+        changes.sort_by(|a, b| b.delta().abs().cmp(&a.delta().abs()).then(a.net.cmp(&b.net)));
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+
+    fn heatmap(input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    fn change(net: &str, old: i64, new: i64, kind: ChangeKind) -> Change {
+        Change {
+            net: net.parse().unwrap(),
+            old,
+            new,
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_changes_are_aggregated_and_sorted() {
+        let before = heatmap("10.0.0.0/15 5\n20.0.0.0/16 100\n30.0.0.0/16 40\n40.0.0.0/16 7\n");
+        let after = heatmap("10.0.0.0/15 8\n30.0.0.0/16 20\n40.0.0.0/16 7\n50.0.0.0/14 3\n");
+        let changes = before.changes(&after, 0).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                change("20.0.0.0/16", 100, 0, ChangeKind::WentDark),
+                change("30.0.0.0/16", 40, 20, ChangeKind::Decreased),
+                change("50.0.0.0/14", 0, 12, ChangeKind::Appeared),
+                change("10.0.0.0/15", 10, 16, ChangeKind::Increased),
+            ]
+        );
+        // The threshold applies per pixel: +3 per /16 is not more than 3
+        let large = before.changes(&after, 3).unwrap();
+        assert_eq!(large.len(), 2);
+        assert!(before.changes(&before, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_report_formats() {
+        let changes = vec![change("10.0.0.0/8", 0, 500, ChangeKind::Appeared)];
+        let mut text = Vec::new();
+        write_changes(&mut text, &changes).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.lines().nth(1).unwrap().starts_with("10.0.0.0/8"));
+        assert!(text.lines().nth(1).unwrap().ends_with("+500  appeared"));
+        assert_eq!(
+            changes_to_json(&changes, 10).to_string(),
+            r#"{"threshold":10,"changes":[{"prefix":"10.0.0.0/8","old":0,"new":500,"delta":500,"change":"appeared"}]}"#
+        );
+    }
+
+    #[test]
+    fn test_mismatched_maps() {
+        let mut other = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            24,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        assert!(heatmap("").changes(&other, 0).is_err());
+        other = heatmap("");
+        assert!(heatmap("").changes(&other, -1).is_err());
+    }
+}
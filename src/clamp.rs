@@ -0,0 +1,105 @@
+use crate::{Heatmap, ValueMode};
+use anyhow::{Result, bail};
+
+/// Cells changed by [`Heatmap::clamp_cells`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClampCounts {
+    /// Painted cells below the floor, now cleared.
+    pub floored: u64,
+    /// Cells above the ceiling, now equal to it.
+    pub capped: u64,
+}
+
+impl Heatmap {
+    /// Clear painted cells below `floor` and cap cells above `ceiling`.
+    ///
+    /// This changes the buffer itself, so unlike the colour domain's bounds it also
+    /// affects state files, stats and everything derived from the cells. Run it after
+    /// all input is processed: values are aggregated first, then clamped, and the
+    /// colour domain is computed from the clamped cells. Cleared cells count as
+    /// untouched.
+    pub fn clamp_cells(&mut self, floor: Option<i32>, ceiling: Option<i32>) -> Result<ClampCounts> {
+        if self.value_mode == ValueMode::Categorical {
+            bail!("Floor and ceiling do not apply to categorical values");
+        }
+        if let (Some(floor), Some(ceiling)) = (floor, ceiling)
+            && floor > ceiling
+        {
+            bail!("Floor {} is above ceiling {}", floor, ceiling);
+        }
+        let mut counts = ClampCounts::default();
+        for (index, cell) in self.buffer.cells_mut().iter_mut().enumerate() {
+            let bit = 1 << (index % 64);
+            if self.touched[index / 64] & bit == 0 {
+                continue;
+            }
+            if floor.is_some_and(|floor| *cell < floor) {
+                *cell = 0;
+                self.touched[index / 64] &= !bit;
+                counts.floored += 1;
+            } else if let Some(ceiling) = ceiling
+                && *cell > ceiling
+            {
+                *cell = ceiling;
+                counts.capped += 1;
+            }
+        }
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, RenderOptions};
+
+    fn heatmap(input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_values_are_aggregated_before_clamping() {
+        // Two lines of 3 in one pixel sum to 6, which survives a floor of 5
+        let mut hm = heatmap("10.0.0.1 3\n10.0.0.2 3\n11.0.0.1 4\n12.0.0.1 900\n13.0.0.1 50\n");
+        let counts = hm.clamp_cells(Some(5), Some(100)).unwrap();
+        assert_eq!(counts, ClampCounts { floored: 1, capped: 1 });
+        assert_eq!(hm.touched_pixels(), 3);
+        assert_eq!(hm.value_range(), (0, 100));
+        assert_eq!(hm.sorted_values().values(), [6, 50, 100]);
+    }
+
+    #[test]
+    fn test_domain_follows_clamped_cells() {
+        let mut hm = heatmap("10.0.0.1 1\n11.0.0.1 20\n12.0.0.1 40\n13.0.0.1 5000\n");
+        // Take the minimum from the smallest painted value rather than the background
+        let options = RenderOptions {
+            min_percentile: Some(0.0),
+            ..hm.render_options()
+        };
+        assert_eq!(hm.domain_bounds(&options), (1.0, 5000.0));
+        hm.clamp_cells(Some(10), Some(100)).unwrap();
+        // The floored cell no longer drags the minimum down, and the ceiling is the maximum
+        assert_eq!(hm.domain_bounds(&options), (20.0, 100.0));
+        let image = hm.render(&options).unwrap();
+        let (x, y) = hm.ip_to_xy(u32::from(std::net::Ipv4Addr::new(10, 0, 0, 1))).unwrap();
+        assert_eq!(image.get_pixel(x, y).0[3], 0, "floored cells are background");
+    }
+
+    #[test]
+    fn test_invalid_clamps() {
+        let mut hm = heatmap("10.0.0.1 1\n");
+        assert!(hm.clamp_cells(Some(10), Some(5)).is_err());
+        assert_eq!(hm.clamp_cells(None, None).unwrap(), ClampCounts::default());
+    }
+}
@@ -0,0 +1,100 @@
+//! Records that fall outside the part of the address space a map shows.
+
+use crate::Heatmap;
+use crate::input::Record;
+use ipnet::Ipv4Net;
+
+/// Share of the input, in percent, outside the view above which a run warns and
+/// notes it in the title.
+pub const DEFAULT_CLIP_WARNING_PERCENT: f64 = 25.0;
+
+impl Heatmap {
+    /// Count records outside `view`, e.g. the prefix a map will be cropped to, as
+    /// clipped. Records are counted as they are read, so set this before processing
+    /// input; a map with an odd `bits_per_pixel` always clips records outside the
+    /// 0.0.0.0/1 its square covers.
+    pub fn set_view(&mut self, view: Option<Ipv4Net>) {
+        self.view = view.map(|net| net.trunc());
+    }
+
+    pub fn view(&self) -> Option<Ipv4Net> {
+        self.view
+    }
+
+    /// Number of records read, including those outside the view.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Number of records without any address in the view.
+    pub fn clipped_records(&self) -> u64 {
+        self.clipped_records
+    }
+
+    /// Sum of the values of the clipped records, after weighting.
+    pub fn clipped_value(&self) -> i64 {
+        self.clipped_value
+    }
+
+    /// Whether any address of `net` is both in the view and on the map.
+    fn in_view(&self, net: &Ipv4Net) -> bool {
+        let overlaps = |a: &Ipv4Net, b: &Ipv4Net| a.contains(&b.network()) || b.contains(&a.network());
+        // Odd maps cover 0.0.0.0/1, which every prefix starting there overlaps
+        let on_map = self.bits_per_pixel.is_multiple_of(2) || net.network().octets()[0] < 128;
+        on_map && self.view.is_none_or(|view| overlaps(&view, net))
+    }
+
+    /// Count `record`, painted with `value`, in the record and clipped totals.
+    pub(crate) fn count_clipped(&mut self, record: &Record, value: i32) {
+        self.records += 1;
+        if !self.in_view(&record.net) {
+            self.clipped_records += 1;
+            self.clipped_value += value as i64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8, view: Option<&str>, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.set_view(view.map(|net| net.parse().unwrap()));
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_records_outside_the_view() {
+        // Half the records and a third of the value are outside 10.0.0.0/8
+        let input = "10.0.0.1 1\n10.200.0.0/16 2\n11.0.0.1 1\n192.168.0.0/16 2\n";
+        let hm = heatmap(16, Some("10.0.0.0/8"), input);
+        assert_eq!((hm.records(), hm.clipped_records(), hm.clipped_value()), (4, 2, 3));
+        // Prefixes overlapping the view count as inside
+        let hm = heatmap(16, Some("10.128.0.0/9"), "10.0.0.0/8 4\n0.0.0.0/0 1\n10.0.0.1 2\n");
+        assert_eq!((hm.records(), hm.clipped_records(), hm.clipped_value()), (3, 1, 2));
+        // Without a view nothing is clipped
+        let hm = heatmap(16, None, input);
+        assert_eq!(hm.clipped_records(), 0);
+    }
+
+    #[test]
+    fn test_odd_maps_clip_the_upper_half() {
+        let hm = heatmap(17, None, "10.0.0.1 1\n128.0.0.1 5\n200.0.0.0/8 1\n0.0.0.0/0 1\n");
+        assert_eq!((hm.records(), hm.clipped_records(), hm.clipped_value()), (4, 2, 6));
+        // The upper half is past the end of the curve, so it is not painted over the lower
+        let hm = heatmap(17, None, "128.0.0.1 5\n200.0.0.0/8 1\n");
+        assert_eq!(hm.touched_pixels(), 0);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::json::JsonValue;
+use crate::table::{Align, Table, TextStyle};
+use crate::{Heatmap, ValueMode};
+use anyhow::{Result, bail};
+
+/// How similar two heatmaps are. Metrics that are undefined (e.g. the cosine of an
+/// empty map) are NaN.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimilarityReport {
+    /// Cosine similarity of the cell values.
+    pub cosine: f64,
+    /// Pearson correlation over cells that are non-zero in either map.
+    pub pearson: f64,
+    /// Touched pixels in both maps divided by touched pixels in either.
+    pub jaccard: f64,
+    /// Number of cells that are non-zero in either map.
+    pub union_cells: u64,
+}
+
+impl SimilarityReport {
+    pub fn to_json(&self) -> JsonValue {
+        let mut report = JsonValue::object();
+        report.insert("cosine", self.cosine);
+        report.insert("pearson", self.pearson);
+        report.insert("jaccard", self.jaccard);
+        report.insert("union_cells", self.union_cells);
+        report
+    }
+
+    /// The metrics as printed by `compare`.
+    pub fn to_text(&self, style: TextStyle) -> String {
+        let mut table = Table::new(&[("metric", Align::Left), ("value", Align::Right), ("over", Align::Left)]);
+        table.push(vec!["cosine".to_string(), format!("{:.6}", self.cosine)]);
+        table.push(vec![
+            "pearson".to_string(),
+            format!("{:.6}", self.pearson),
+            format!("{} non-zero cells", self.union_cells),
+        ]);
+        table.push(vec!["jaccard".to_string(), format!("{:.6}", self.jaccard), "touched pixels".to_string()]);
+        table.render(style)
+    }
+}
+
+impl Heatmap {
+    /// Compare the cell values and touched pixels of two heatmaps.
+    pub fn similarity(&self, other: &Heatmap) -> Result<SimilarityReport> {
+        if self.bits_per_pixel != other.bits_per_pixel {
+            bail!(
+                "Cannot compare heatmaps with different bits_per_pixel ({} and {})",
+                self.bits_per_pixel,
+                other.bits_per_pixel
+            );
+        }
+
+        // Single pass accumulating the sums both metrics need
+        let (mut n, mut sum_a, mut sum_b) = (0u64, 0.0f64, 0.0f64);
+        let (mut sum_aa, mut sum_bb, mut sum_ab) = (0.0f64, 0.0f64, 0.0f64);
+        let cells = self.buffer.iter().flatten().zip(other.buffer.iter().flatten());
+        for (&a, &b) in cells {
+            let a = self.cell_value(a);
+            let b = other.cell_value(b);
+            if a == 0.0 && b == 0.0 {
+                continue;
+            }
+            n += 1;
+            sum_a += a;
+            sum_b += b;
+            sum_aa += a * a;
+            sum_bb += b * b;
+            sum_ab += a * b;
+        }
+
+        let cosine = sum_ab / (sum_aa.sqrt() * sum_bb.sqrt());
+        let count = n as f64;
+        let covariance = sum_ab - sum_a * sum_b / count;
+        let variance_a = sum_aa - sum_a * sum_a / count;
+        let variance_b = sum_bb - sum_b * sum_b / count;
+        let pearson = covariance / (variance_a.sqrt() * variance_b.sqrt());
+
+        let (mut both, mut either) = (0u64, 0u64);
+        for (a, b) in self.touched.iter().zip(other.touched.iter()) {
+            both += (a & b).count_ones() as u64;
+            either += (a | b).count_ones() as u64;
+        }
+
+        Ok(SimilarityReport {
+            cosine,
+            pearson,
+            jaccard: both as f64 / either as f64,
+            union_cells: n,
+        })
+    }
+
+    /// A cell value as a number, mapping the categorical "no data" sentinel to zero.
+    fn cell_value(&self, value: i32) -> f64 {
+        if self.value_mode == ValueMode::Categorical && value < 0 {
+            0.0
+        } else {
+            value as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::table::TextStyle;
+    use crate::{DomainType, Heatmap, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_identical_maps_are_fully_similar() {
+        let a = heatmap(16, "10.0.0.0 5\n11.0.0.0 7\n12.0.0.0 1\n");
+        let report = a.similarity(&a).unwrap();
+        assert!((report.cosine - 1.0).abs() < 1e-12);
+        assert!((report.pearson - 1.0).abs() < 1e-12);
+        assert_eq!(report.jaccard, 1.0);
+        assert_eq!(report.union_cells, 3);
+    }
+
+    #[test]
+    fn test_disjoint_maps() {
+        let a = heatmap(16, "10.0.0.0 5\n");
+        let b = heatmap(16, "11.0.0.0 5\n");
+        let report = a.similarity(&b).unwrap();
+        assert_eq!(report.cosine, 0.0);
+        assert_eq!(report.jaccard, 0.0);
+        assert!((report.pearson + 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_partial_overlap() {
+        let a = heatmap(16, "10.0.0.0 1\n11.0.0.0 2\n12.0.0.0 3\n");
+        let b = heatmap(16, "10.0.0.0 2\n11.0.0.0 4\n13.0.0.0 0\n");
+        let report = a.similarity(&b).unwrap();
+        // a = (1, 2, 3), b = (2, 4, 0): a.b = 10, |a| = sqrt(14), |b| = sqrt(20)
+        assert!((report.cosine - 10.0 / (14.0f64.sqrt() * 20.0f64.sqrt())).abs() < 1e-12);
+        // Touched: a = {10, 11, 12}, b = {10, 11, 13}
+        assert_eq!(report.jaccard, 0.5);
+        assert_eq!(report.union_cells, 3);
+    }
+
+    #[test]
+    fn test_empty_maps_are_undefined() {
+        let report = heatmap(16, "").similarity(&heatmap(16, "")).unwrap();
+        assert!(report.cosine.is_nan());
+        assert!(report.jaccard.is_nan());
+        assert_eq!(report.to_json().to_string(), r#"{"cosine":null,"pearson":null,"jaccard":null,"union_cells":0}"#);
+    }
+
+    #[test]
+    fn test_report_text() {
+        let report = heatmap(16, "10.0.0.0 1\n11.0.0.0 2\n").similarity(&heatmap(16, "10.0.0.0 1\n")).unwrap();
+        assert_eq!(
+            report.to_text(TextStyle::ASCII),
+            "+---------+-----------+------------------+\n\
+             | metric  |     value | over             |\n\
+             +---------+-----------+------------------+\n\
+             | cosine  |  0.447214 |                  |\n\
+             | pearson | -1.000000 | 2 non-zero cells |\n\
+             | jaccard |  0.500000 | touched pixels   |\n\
+             +---------+-----------+------------------+\n"
+        );
+        assert_eq!(
+            report.to_text(TextStyle { unicode: true, colour: false }),
+            "┌─────────┬───────────┬──────────────────┐\n\
+             │ metric  │     value │ over             │\n\
+             ├─────────┼───────────┼──────────────────┤\n\
+             │ cosine  │  0.447214 │                  │\n\
+             │ pearson │ -1.000000 │ 2 non-zero cells │\n\
+             │ jaccard │  0.500000 │ touched pixels   │\n\
+             └─────────┴───────────┴──────────────────┘\n"
+        );
+    }
+
+    #[test]
+    fn test_mismatched_bits_per_pixel() {
+        assert!(heatmap(16, "").similarity(&heatmap(14, "")).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! Pixels painted again with a different value when values do not accumulate.
+
+use crate::{Heatmap, ValueSource};
+use anyhow::{Result, bail};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Default number of conflicting lines kept as samples.
+pub const DEFAULT_CONFLICT_SAMPLES: usize = 5;
+
+/// Conflicting line content is truncated to this many characters.
+const MAX_CONTENT_CHARS: usize = 120;
+
+/// What happens when a pixel painted before is painted with a different value and
+/// values do not accumulate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ConflictPolicy {
+    /// Keep the later value and warn with a count at the end of the input.
+    #[default]
+    Warn,
+    /// Abort processing at the first conflicting line.
+    Error,
+    /// Add the values, as `-C` does, for conflicting pixels.
+    Accumulate,
+    /// Keep the larger value.
+    KeepMax,
+}
+
+impl ConflictPolicy {
+    pub const ALL: [ConflictPolicy; 4] =
+        [ConflictPolicy::Warn, ConflictPolicy::Error, ConflictPolicy::Accumulate, ConflictPolicy::KeepMax];
+
+    /// The new value of a `cell` holding `previous` that is painted with a different
+    /// `value`.
+    pub(crate) fn resolve(self, previous: i32, value: i32) -> i32 {
+        match self {
+            ConflictPolicy::Warn | ConflictPolicy::Error => value,
+            ConflictPolicy::Accumulate => previous.saturating_add(value),
+            ConflictPolicy::KeepMax => previous.max(value),
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(ConflictPolicy::Warn),
+            "error" => Ok(ConflictPolicy::Error),
+            "accumulate" => Ok(ConflictPolicy::Accumulate),
+            "keep-max" => Ok(ConflictPolicy::KeepMax),
+            _ => Err(format!(
+                "Invalid conflict policy: {}. Use 'warn', 'error', 'accumulate' or 'keep-max'",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConflictPolicy::Warn => write!(f, "warn"),
+            ConflictPolicy::Error => write!(f, "error"),
+            ConflictPolicy::Accumulate => write!(f, "accumulate"),
+            ConflictPolicy::KeepMax => write!(f, "keep-max"),
+        }
+    }
+}
+
+/// A line that painted a pixel over a different value, with the first such pixel's
+/// values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConflictSample {
+    pub line_number: usize,
+    /// The line, truncated to a bounded length.
+    pub content: String,
+    pub previous: i32,
+    pub value: i32,
+}
+
+impl Display for ConflictSample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {} (painted {} over {})", self.line_number, self.content, self.value, self.previous)
+    }
+}
+
+impl Heatmap {
+    /// How pixels painted again with a different value are resolved when values do
+    /// not accumulate. Prefix lengths, which keep the largest by design, never
+    /// conflict. Defaults to [`ConflictPolicy::Warn`].
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.conflict_policy = policy;
+    }
+
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.conflict_policy
+    }
+
+    /// Number of paints that overwrote a different value. A pixel painted over
+    /// several times counts each time.
+    pub fn conflicts(&self) -> u64 {
+        self.conflicts
+    }
+
+    /// The first conflicting lines, up to [`DEFAULT_CONFLICT_SAMPLES`]. Lines are
+    /// only sampled when reading on one thread.
+    pub fn conflict_samples(&self) -> &[ConflictSample] {
+        &self.conflict_samples
+    }
+
+    /// Whether painting a pixel can conflict with what it holds.
+    pub(crate) fn detects_conflicts(&self) -> bool {
+        !self.accumulate && self.parse_options.value_source != ValueSource::PrefixLen
+    }
+
+    /// Count a pixel painted with `value` over a different `previous` one, returning
+    /// the value it keeps.
+    pub(crate) fn conflict(&mut self, previous: i32, value: i32) -> i32 {
+        self.conflicts += 1;
+        self.line_conflict.get_or_insert((previous, value));
+        self.conflict_policy.resolve(previous, value)
+    }
+
+    /// Sample the line just painted if it conflicted, and fail under
+    /// [`ConflictPolicy::Error`].
+    pub(crate) fn finish_line_conflicts(&mut self, line_number: usize, line: &str) -> Result<()> {
+        let Some((previous, value)) = self.line_conflict.take() else {
+            return Ok(());
+        };
+        if self.conflict_policy == ConflictPolicy::Error {
+            bail!(
+                "Line {} paints {} over {} in a pixel painted before: {}",
+                line_number,
+                value,
+                previous,
+                line.trim_end()
+            );
+        }
+        if self.conflict_samples.len() < DEFAULT_CONFLICT_SAMPLES {
+            self.conflict_samples.push(ConflictSample {
+                line_number,
+                content: line.trim_end().chars().take(MAX_CONTENT_CHARS).collect(),
+                previous,
+                value,
+            });
+        }
+        Ok(())
+    }
+
+    /// Warn once about the conflicts counted since `before`, with the samples.
+    pub(crate) fn warn_conflicts(&self, before: u64) {
+        let conflicts = self.conflicts - before;
+        if conflicts == 0 || self.conflict_policy != ConflictPolicy::Warn {
+            return;
+        }
+        let samples: Vec<String> = self.conflict_samples.iter().map(ConflictSample::to_string).collect();
+        log::warn!(
+            count = conflicts;
+            "{} paints overwrote a different value and kept the later one (see --on-conflict){}{}",
+            conflicts,
+            if samples.is_empty() { "" } else { ", first at " },
+            samples.join("; ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    const INPUT: &str = "10.0.0.1 5\n10.0.0.2 3\n10.0.0.3 3\n10.1.0.1 4\n10.1.0.2 9\n11.0.0.0/15 2\n11.0.0.1 7\n";
+
+    fn heatmap(policy: ConflictPolicy) -> (Heatmap, Result<()>) {
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, false, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.set_conflict_policy(policy);
+        let result = heatmap.process_input_from_string(INPUT);
+        (heatmap, result)
+    }
+
+    fn values(heatmap: &Heatmap) -> Vec<i64> {
+        let totals = heatmap.prefix_totals(16).unwrap();
+        ["10.0.0.0/16", "10.1.0.0/16", "11.0.0.0/16", "11.1.0.0/16"]
+            .iter()
+            .map(|prefix| totals.iter().find(|(net, _)| net.to_string() == *prefix).unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn test_policies() {
+        // 10.0.0.3 repeats the later value, so only conflicts where that was not kept
+        let (warn, result) = heatmap(ConflictPolicy::Warn);
+        assert!(result.is_ok());
+        assert_eq!((warn.conflicts(), values(&warn)), (3, vec![3, 9, 7, 2]));
+        let lines: Vec<usize> = warn.conflict_samples().iter().map(|sample| sample.line_number).collect();
+        assert_eq!(lines, [2, 5, 7]);
+        assert_eq!(warn.conflict_samples()[0].to_string(), "line 2: 10.0.0.2 3 (painted 3 over 5)");
+
+        let (accumulate, _) = heatmap(ConflictPolicy::Accumulate);
+        assert_eq!((accumulate.conflicts(), values(&accumulate)), (4, vec![11, 13, 9, 2]));
+        let (keep_max, _) = heatmap(ConflictPolicy::KeepMax);
+        assert_eq!((keep_max.conflicts(), values(&keep_max)), (4, vec![5, 9, 7, 2]));
+
+        let (error, result) = heatmap(ConflictPolicy::Error);
+        let message = result.unwrap_err().to_string();
+        assert!(message.contains("Line 2 paints 3 over 5"), "{}", message);
+        assert_eq!((error.conflicts(), error.lines_processed()), (1, 2));
+    }
+
+    #[test]
+    fn test_accumulated_and_prefix_len_maps_never_conflict() {
+        let mut accumulated = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        accumulated.process_input_from_string(INPUT).unwrap();
+        assert_eq!(accumulated.conflicts(), 0);
+        let mut prefix_len = Heatmap::new(DomainType::Linear, None, None, false, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        prefix_len.set_parse_options(crate::ParseOptions::default().value_source(ValueSource::PrefixLen));
+        prefix_len.process_input_from_string(INPUT).unwrap();
+        assert_eq!(prefix_len.conflicts(), 0);
+        assert_eq!(prefix_len.stats(&[]).conflicts, 0);
+    }
+}
@@ -0,0 +1,361 @@
+//! Converting input between representations, so it is parsed and cleaned once and
+//! re-rendered quickly many times.
+
+use crate::input::{self, ParseOptions, ParsedLine};
+use crate::raw::RawWriter;
+use crate::rejects::RejectLog;
+use crate::timing::PhaseTimer;
+use anyhow::{Context, Result};
+use ipnet::Ipv4Net;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+/// What [`Converter::convert`] writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    /// `address value` lines, e.g. `10.0.0.1 5` or `10.0.0.0/16 3`.
+    Text,
+    /// `address,value` lines.
+    Csv,
+    /// Binary records, see [`crate::RAW_MAGIC`].
+    RawU32v,
+    /// Text sorted by address, summing the values of repeated prefixes and, see
+    /// [`Converter::set_merge_siblings`], merging sibling prefixes of equal value.
+    Aggregated,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 4] =
+        [OutputFormat::Text, OutputFormat::Csv, OutputFormat::RawU32v, OutputFormat::Aggregated];
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "csv" => Ok(OutputFormat::Csv),
+            "raw-u32v" => Ok(OutputFormat::RawU32v),
+            "aggregated" => Ok(OutputFormat::Aggregated),
+            _ => Err(format!(
+                "Invalid output format: {}. Use 'text', 'csv', 'raw-u32v' or 'aggregated'",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::RawU32v => write!(f, "raw-u32v"),
+            OutputFormat::Aggregated => write!(f, "aggregated"),
+        }
+    }
+}
+
+/// Reads input with the same parser and filters as [`crate::Heatmap`] and writes the
+/// records that would be painted in another format.
+pub struct Converter {
+    parse_options: ParseOptions,
+    rejects: RejectLog,
+    merge_siblings: Option<u8>,
+}
+
+/// The outcome of a [`Converter`] run.
+#[derive(Clone, Debug)]
+pub struct Conversion {
+    pub lines: u64,
+    /// Lines that parsed into an address or prefix.
+    pub records: u64,
+    /// Records written, fewer than `records` when aggregated.
+    pub written: u64,
+    /// Prefixes that were accepted with host bits set; they are written as their network.
+    pub cidr_host_bits: u64,
+    /// Records dropped for their value.
+    pub ignored_values: u64,
+    /// Records dropped for their time.
+    pub outside_window: u64,
+    pub rejects: RejectLog,
+}
+
+impl Converter {
+    /// A converter reading input with `options`, in the format they give.
+    pub fn new(options: ParseOptions) -> Self {
+        Self {
+            rejects: RejectLog::new(options.max_rejects),
+            parse_options: options,
+            merge_siblings: None,
+        }
+    }
+
+    /// When aggregating, also merge pairs of sibling prefixes with equal values into
+    /// their parent if they cover whole pixels at `bits_per_pixel`, which keeps the
+    /// cells of accumulating renders at that resolution or coarser the same.
+    pub fn set_merge_siblings(&mut self, bits_per_pixel: Option<u8>) {
+        self.merge_siblings = bits_per_pixel;
+    }
+
+    /// Convert `reader` into `writer`. Records are written as they are read, except
+    /// for [`OutputFormat::Aggregated`], which has to hold them all to sort them.
+    pub fn convert<R: BufRead, W: Write>(mut self, reader: R, writer: W, to: OutputFormat) -> Result<Conversion> {
+        let mut sink = Sink::new(writer, to, self.merge_siblings).context("Failed to write converted output")?;
+        let (mut lines, mut records, mut cidr_host_bits, mut ignored_values, mut outside_window) = (0, 0, 0, 0, 0);
+        let options = self.parse_options.clone();
+        input::for_each_record(reader, 0, &options, &PhaseTimer::default(), |line_number, line, parsed| {
+            lines += 1;
+            match parsed {
+                ParsedLine::Blank | ParsedLine::Unsampled => {}
+                ParsedLine::Ignored => ignored_values += 1,
+                ParsedLine::OutsideWindow => outside_window += 1,
+                ParsedLine::Record(record) => {
+                    records += 1;
+                    if record.has_host_bits() {
+                        cidr_host_bits += 1;
+                    }
+                    sink.push(record.net.trunc(), record.value)
+                        .context("Failed to write converted output")?;
+                }
+                ParsedLine::Records(expanded) => {
+                    for record in expanded {
+                        records += 1;
+                        if record.has_host_bits() {
+                            cidr_host_bits += 1;
+                        }
+                        sink.push(record.net.trunc(), record.value)
+                            .context("Failed to write converted output")?;
+                    }
+                }
+                ParsedLine::Rejected(reason, message) => self.rejects.record(line_number, line, reason, message),
+            }
+            Ok(())
+        })?;
+        let written = sink.finish().context("Failed to write converted output")?;
+        Ok(Conversion {
+            lines,
+            records,
+            written,
+            cidr_host_bits,
+            ignored_values,
+            outside_window,
+            rejects: self.rejects,
+        })
+    }
+}
+
+impl Conversion {
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "lines: {}, records: {}, written: {}, rejected: {}",
+            self.lines,
+            self.records,
+            self.written,
+            self.rejects.total()
+        );
+        if self.cidr_host_bits > 0 {
+            text.push_str(&format!(", cidr host bits: {}", self.cidr_host_bits));
+        }
+        if self.ignored_values > 0 {
+            text.push_str(&format!(", ignored values: {}", self.ignored_values));
+        }
+        if self.outside_window > 0 {
+            text.push_str(&format!(", outside window: {}", self.outside_window));
+        }
+        text
+    }
+}
+
+/// Where converted records go.
+enum Sink<W: Write> {
+    Lines { writer: W, separator: char, written: u64 },
+    Raw { writer: RawWriter<W>, written: u64 },
+    Aggregated {
+        writer: W,
+        records: Vec<(Ipv4Net, i32)>,
+        merge_siblings: Option<u8>,
+    },
+}
+
+impl<W: Write> Sink<W> {
+    fn new(writer: W, format: OutputFormat, merge_siblings: Option<u8>) -> std::io::Result<Self> {
+        Ok(match format {
+            OutputFormat::Text => Sink::Lines { writer, separator: ' ', written: 0 },
+            OutputFormat::Csv => Sink::Lines { writer, separator: ',', written: 0 },
+            OutputFormat::RawU32v => Sink::Raw {
+                writer: RawWriter::new(writer)?,
+                written: 0,
+            },
+            OutputFormat::Aggregated => Sink::Aggregated {
+                writer,
+                records: Vec::new(),
+                merge_siblings,
+            },
+        })
+    }
+
+    fn push(&mut self, net: Ipv4Net, value: i32) -> std::io::Result<()> {
+        match self {
+            Sink::Lines {
+                writer,
+                separator,
+                written,
+            } => {
+                *written += 1;
+                write_line(writer, &net, *separator, value)
+            }
+            Sink::Raw { writer, written } => {
+                *written += 1;
+                writer.write(&net, value)
+            }
+            Sink::Aggregated { records, .. } => {
+                records.push((net, value));
+                Ok(())
+            }
+        }
+    }
+
+    /// Flush the output, returning the number of records written.
+    fn finish(self) -> std::io::Result<u64> {
+        match self {
+            Sink::Lines {
+                mut writer, written, ..
+            } => writer.flush().map(|()| written),
+            Sink::Raw { writer, written } => writer.into_inner().flush().map(|()| written),
+            Sink::Aggregated {
+                mut writer,
+                records,
+                merge_siblings,
+            } => {
+                let records = aggregate(records, merge_siblings);
+                for (net, value) in &records {
+                    write_line(&mut writer, net, ' ', *value)?;
+                }
+                writer.flush().map(|()| records.len() as u64)
+            }
+        }
+    }
+}
+
+/// One text record; single addresses are written without a prefix length.
+fn write_line<W: Write>(writer: &mut W, net: &Ipv4Net, separator: char, value: i32) -> std::io::Result<()> {
+    match net.prefix_len() {
+        32 => writeln!(writer, "{}{}{}", net.addr(), separator, value),
+        _ => writeln!(writer, "{}{}{}", net, separator, value),
+    }
+}
+
+/// Sort `records` and sum the values of repeated prefixes. With `merge_siblings`,
+/// pairs of sibling prefixes with equal values that cover whole pixels at that
+/// `bits_per_pixel` are merged into their parent too.
+///
+/// Accumulating raw values paint the same cells from the result as from `records`.
+fn aggregate(mut records: Vec<(Ipv4Net, i32)>, merge_siblings: Option<u8>) -> Vec<(Ipv4Net, i32)> {
+    let longest = merge_siblings.map(|bits_per_pixel| 32 - bits_per_pixel);
+    records.sort_by_key(|(net, _)| (net.network(), net.prefix_len()));
+    let mut merged: Vec<(Ipv4Net, i32)> = Vec::with_capacity(records.len());
+    for (net, value) in records {
+        if let Some((last, total)) = merged.last_mut()
+            && *last == net
+        {
+            *total = total.saturating_add(value);
+            continue;
+        }
+        merged.push((net, value));
+        // A new entry can complete a sibling pair, and the parent another one
+        while let [.., (lower, lower_value), (upper, upper_value)] = merged[..]
+            && lower.prefix_len() == upper.prefix_len()
+            && lower.prefix_len() > 0
+            && longest.is_some_and(|longest| lower.prefix_len() <= longest)
+            && lower_value == upper_value
+            && lower.supernet() == upper.supernet()
+            && lower != upper
+        {
+            merged.truncate(merged.len() - 2);
+            let parent = lower.supernet().expect("prefix length is above 0");
+            match merged.last_mut() {
+                Some((last, total)) if *last == parent => *total = total.saturating_add(lower_value),
+                _ => merged.push((parent, lower_value)),
+            }
+        }
+    }
+    merged.sort_by_key(|(net, _)| (net.network(), net.prefix_len()));
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputFormat;
+
+    fn convert(input: &[u8], from: InputFormat, to: OutputFormat) -> (Vec<u8>, Conversion) {
+        let mut output = Vec::new();
+        let conversion = Converter::new(ParseOptions::default().format(from)).convert(input, &mut output, to).unwrap();
+        (output, conversion)
+    }
+
+    fn records(records: &[(&str, i32)]) -> Vec<(Ipv4Net, i32)> {
+        records.iter().map(|(net, value)| (net.parse().unwrap(), *value)).collect()
+    }
+
+    #[test]
+    fn test_text_and_csv() {
+        let input = b"10.0.0.1\n10.1.2.3/16 7\nbad\n\n167772162,3\n";
+        let (text, conversion) = convert(input, InputFormat::Text, OutputFormat::Text);
+        assert_eq!(String::from_utf8(text).unwrap(), "10.0.0.1 1\n10.1.0.0/16 7\n10.0.0.2 3\n");
+        assert_eq!((conversion.lines, conversion.records, conversion.written), (5, 3, 3));
+        assert_eq!((conversion.rejects.total(), conversion.cidr_host_bits), (1, 1));
+
+        let (csv, _) = convert(input, InputFormat::Text, OutputFormat::Csv);
+        assert_eq!(String::from_utf8(csv).unwrap(), "10.0.0.1,1\n10.1.0.0/16,7\n10.0.0.2,3\n");
+    }
+
+    #[test]
+    fn test_raw_round_trip() {
+        let input = b"10.0.0.1 5\n192.168.0.0/16 -2\n";
+        let (raw, conversion) = convert(input, InputFormat::Text, OutputFormat::RawU32v);
+        assert_eq!(conversion.written, 2);
+        let (text, conversion) = convert(&raw, InputFormat::RawU32v, OutputFormat::Text);
+        assert_eq!(String::from_utf8(text).unwrap(), "10.0.0.1 5\n192.168.0.0/16 -2\n");
+        assert_eq!(conversion.to_text(), "lines: 2, records: 2, written: 2, rejected: 0");
+    }
+
+    #[test]
+    fn test_aggregation() {
+        // Repeats are summed, then 10.0.0.0/25 and 10.0.0.128/25 (both 4) merge, and
+        // the resulting /24 merges with 10.0.1.0/24 into a /23
+        let input = records(&[
+            ("10.0.1.0/24", 4),
+            ("10.0.0.128/25", 4),
+            ("192.168.0.1/32", 1),
+            ("10.0.0.0/25", 3),
+            ("10.0.0.0/25", 1),
+            ("8.8.8.8/32", 2),
+            ("192.168.0.0/32", 2),
+        ]);
+        assert_eq!(aggregate(input.clone(), None).len(), 6);
+        assert_eq!(
+            aggregate(input.clone(), Some(0)),
+            records(&[("8.8.8.8/32", 2), ("10.0.0.0/23", 4), ("192.168.0.0/32", 2), ("192.168.0.1/32", 1)])
+        );
+        // A merged parent that already exists is summed into it
+        assert_eq!(
+            aggregate(records(&[("10.0.0.0/24", 1), ("10.0.0.0/25", 2), ("10.0.0.128/25", 2)]), Some(0)),
+            records(&[("10.0.0.0/24", 3)])
+        );
+        // /25s are smaller than a pixel at 8 bits per pixel, so only the /24s merge
+        assert_eq!(
+            aggregate(input, Some(8)),
+            records(&[
+                ("8.8.8.8/32", 2),
+                ("10.0.0.0/25", 4),
+                ("10.0.0.128/25", 4),
+                ("10.0.1.0/24", 4),
+                ("192.168.0.0/32", 2),
+                ("192.168.0.1/32", 1),
+            ])
+        );
+    }
+}
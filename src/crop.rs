@@ -0,0 +1,82 @@
+//! The bounding box of the painted pixels, to crop sparse maps to their data.
+
+use crate::Heatmap;
+
+impl Heatmap {
+    /// The smallest rectangle `(x, y, width, height)` holding every painted pixel,
+    /// grown by `padding` pixels on each side within the map, or `None` if nothing
+    /// was painted.
+    pub fn painted_bounds(&self, padding: u32) -> Option<(u32, u32, u32, u32)> {
+        let size = self.image_size() as usize;
+        let pixels = size * size;
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+        let mut include = |index: usize| {
+            let (x, y) = (index % size, index / size);
+            (min_x, min_y, max_x, max_y) = (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y));
+        };
+        for (word_index, &word) in self.touched.iter().enumerate() {
+            if word == 0 {
+                continue;
+            }
+            let first = word_index * 64;
+            if size >= 64 {
+                // A word lies within one row, so its lowest and highest bits bound it
+                include(first + word.trailing_zeros() as usize);
+                include(first + 63 - word.leading_zeros() as usize);
+                continue;
+            }
+            let mut bits = word;
+            while bits != 0 {
+                let index = first + bits.trailing_zeros() as usize;
+                if index < pixels {
+                    include(index);
+                }
+                bits &= bits - 1;
+            }
+        }
+        if min_x == usize::MAX {
+            return None;
+        }
+        let padding = padding as usize;
+        let (left, top) = (min_x.saturating_sub(padding), min_y.saturating_sub(padding));
+        let (right, bottom) = ((max_x + padding).min(size - 1), (max_y + padding).min(size - 1));
+        Some((left as u32, top as u32, (right - left + 1) as u32, (bottom - top + 1) as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DomainType, Heatmap, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, bits_per_pixel, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_bounds_of_corner_prefixes() {
+        // 0.0.0.0/8 is the top left 16x16 of a -z 16 map and 255.0.0.0/8 the top right
+        let hm = heatmap(16, "0.0.0.1 1\n");
+        assert_eq!(hm.painted_bounds(0), Some((0, 0, 1, 1)));
+        let hm = heatmap(16, "0.0.0.0/8 1\n255.0.0.0/8 1\n");
+        assert_eq!(hm.prefix_rect(&"255.0.0.0/8".parse().unwrap()), (240, 0, 16, 16));
+        assert_eq!(hm.painted_bounds(0), Some((0, 0, 256, 16)));
+        // Padding stops at the edges of the map
+        assert_eq!(hm.painted_bounds(4), Some((0, 0, 256, 20)));
+        let hm = heatmap(16, "128.0.0.0/8 1\n");
+        assert_eq!(hm.prefix_rect(&"128.0.0.0/8".parse().unwrap()), (128, 128, 16, 16));
+        assert_eq!(hm.painted_bounds(0), Some((128, 128, 16, 16)));
+        assert_eq!(hm.painted_bounds(2), Some((126, 126, 20, 20)));
+    }
+
+    #[test]
+    fn test_bounds_of_small_maps() {
+        // Rows of a 4x4 map share one word of the mask
+        let hm = heatmap(28, "192.0.0.0 1\n");
+        let (x, y, _, _) = hm.prefix_rect(&"192.0.0.0/4".parse().unwrap());
+        assert_eq!(hm.painted_bounds(0), Some((x, y, 1, 1)));
+        assert_eq!(hm.painted_bounds(9), Some((0, 0, 4, 4)));
+        assert_eq!(heatmap(28, "").painted_bounds(1), None);
+    }
+}
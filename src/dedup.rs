@@ -0,0 +1,122 @@
+//! Suppressing records repeated within a few lines of each other.
+
+use crate::Heatmap;
+use crate::input::Record;
+use ipnet::Ipv4Net;
+use std::collections::{HashMap, VecDeque};
+
+/// The last `capacity` records read, with how often each occurs among them.
+pub(crate) struct DedupWindow {
+    capacity: usize,
+    recent: VecDeque<(Ipv4Net, i32)>,
+    counts: HashMap<(Ipv4Net, i32), u32>,
+}
+
+impl DedupWindow {
+    fn new(capacity: usize) -> Self {
+        DedupWindow {
+            capacity,
+            recent: VecDeque::with_capacity(capacity),
+            counts: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Add `record` to the window, returning whether an identical record was
+    /// already in it.
+    fn seen(&mut self, record: &Record) -> bool {
+        let key = (record.net, record.value);
+        let seen = self.counts.contains_key(&key);
+        if self.recent.len() == self.capacity
+            && let Some(oldest) = self.recent.pop_front()
+            && let Some(count) = self.counts.get_mut(&oldest)
+        {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&oldest);
+            }
+        }
+        self.recent.push_back(key);
+        *self.counts.entry(key).or_insert(0) += 1;
+        seen
+    }
+}
+
+impl Heatmap {
+    /// Drop a record when an identical one, the same address or prefix as written
+    /// and the same value, is among the `window` records read before it. Dropped
+    /// records still enter the window, so a burst of one line is dropped for as
+    /// long as it lasts. Memory is proportional to `window`; `None` or 0 keeps
+    /// every record.
+    pub fn set_dedup_window(&mut self, window: Option<usize>) {
+        self.dedup = window.filter(|&window| window > 0).map(DedupWindow::new);
+    }
+
+    pub fn dedup_window(&self) -> Option<usize> {
+        self.dedup.as_ref().map(|dedup| dedup.capacity)
+    }
+
+    /// Number of records dropped as repeats, see [`Heatmap::set_dedup_window`].
+    pub fn deduplicated(&self) -> u64 {
+        self.deduplicated
+    }
+
+    /// Whether `record` is painted rather than dropped as a repeat.
+    pub(crate) fn admit(&mut self, record: &Record) -> bool {
+        let Some(dedup) = &mut self.dedup else {
+            return true;
+        };
+        let repeated = dedup.seen(record);
+        if repeated {
+            self.deduplicated += 1;
+        }
+        !repeated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap(window: usize, input: &str) -> Heatmap {
+        let mut heatmap =
+            Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.set_dedup_window(Some(window));
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    fn value_at(heatmap: &Heatmap, address: &str) -> i64 {
+        heatmap.prefix_totals(16).unwrap().into_iter().find(|(net, _)| net.to_string() == address).unwrap().1
+    }
+
+    #[test]
+    fn test_window_boundaries() {
+        // The repeat is exactly 3 records after the first: within a window of 3
+        let input = "10.0.0.1 5\n11.0.0.1 1\n12.0.0.1 1\n10.0.0.1 5\n";
+        let hm = heatmap(3, input);
+        assert_eq!((hm.deduplicated(), value_at(&hm, "10.0.0.0/16")), (1, 5));
+        // 4 records apart it has left the window
+        let hm = heatmap(3, "10.0.0.1 5\n11.0.0.1 1\n12.0.0.1 1\n13.0.0.1 1\n10.0.0.1 5\n");
+        assert_eq!((hm.deduplicated(), value_at(&hm, "10.0.0.0/16")), (0, 10));
+        let hm = heatmap(2, input);
+        assert_eq!((hm.deduplicated(), value_at(&hm, "10.0.0.0/16")), (0, 10));
+    }
+
+    #[test]
+    fn test_bursts_and_other_values() {
+        // A burst stays suppressed however long it is, and another value or prefix is no
+        // repeat
+        let mut input = "10.0.0.1 5\n".repeat(1000);
+        input.push_str("10.0.0.1 7\n10.0.0.0/31 5\n");
+        let hm = heatmap(1, &input);
+        assert_eq!(hm.deduplicated(), 999);
+        assert_eq!(value_at(&hm, "10.0.0.0/16"), 5 + 7 + 5);
+        assert_eq!(hm.stats(&[]).deduplicated, 999);
+        assert_eq!(hm.records(), 3);
+
+        // A window of 0 keeps every record
+        let hm = heatmap(0, "10.0.0.1 5\n10.0.0.1 5\n");
+        assert_eq!((hm.dedup_window(), hm.deduplicated(), value_at(&hm, "10.0.0.0/16")), (None, 0, 10));
+    }
+}
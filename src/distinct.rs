@@ -0,0 +1,193 @@
+//! Approximate counts of distinct addresses, overall or per pixel.
+
+use crate::Heatmap;
+use crate::hilbert::hilbert_d2xy;
+use crate::hll::HyperLogLog;
+use crate::input::{self, Record};
+use crate::ValueMode;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// What `--distinct-approx` estimates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DistinctApprox {
+    /// Only the number of distinct addresses in the whole input, from one sketch.
+    Total,
+    /// Also each pixel's, from one sketch per painted pixel, painted as its value.
+    Pixels,
+}
+
+impl DistinctApprox {
+    pub const ALL: [DistinctApprox; 2] = [DistinctApprox::Total, DistinctApprox::Pixels];
+}
+
+impl FromStr for DistinctApprox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "total" => Ok(DistinctApprox::Total),
+            "pixels" => Ok(DistinctApprox::Pixels),
+            _ => Err(format!("Invalid distinct estimate: {}. Use 'total' or 'pixels'", s)),
+        }
+    }
+}
+
+impl Display for DistinctApprox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DistinctApprox::Total => write!(f, "total"),
+            DistinctApprox::Pixels => write!(f, "pixels"),
+        }
+    }
+}
+
+/// An estimated number of distinct addresses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DistinctEstimate {
+    pub count: f64,
+    /// Relative standard error of `count`.
+    pub relative_error: f64,
+}
+
+/// The sketches of [`Heatmap::set_distinct_approx`].
+pub(crate) struct DistinctCounter {
+    mode: DistinctApprox,
+    total: HyperLogLog,
+    /// Sketches of the painted pixels by buffer index, created as they are painted.
+    pixels: HashMap<usize, HyperLogLog>,
+}
+
+/// The key a record is counted under: its network address and prefix length.
+pub(crate) fn key(record: &Record) -> u64 {
+    ((u32::from(record.net.network()) as u64) << 8) | record.net.prefix_len() as u64
+}
+
+impl Heatmap {
+    /// Estimate the number of distinct addresses read with HyperLogLog sketches of
+    /// 2^`precision` bytes each, instead of keeping every address. A prefix counts
+    /// as one entry, keyed by its network address and length. With
+    /// [`DistinctApprox::Pixels`] every painted pixel gets a sketch of its own and
+    /// its cell becomes its estimate, whatever the value column or `-C` say.
+    pub fn set_distinct_approx(&mut self, mode: Option<DistinctApprox>, precision: u8) -> Result<(), String> {
+        if mode == Some(DistinctApprox::Pixels) && self.value_mode == ValueMode::Categorical {
+            return Err("Distinct counts per pixel cannot be coloured as categories".to_string());
+        }
+        self.distinct = match mode {
+            Some(mode) => Some(DistinctCounter {
+                mode,
+                total: HyperLogLog::new(precision)?,
+                pixels: HashMap::new(),
+            }),
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn distinct_approx(&self) -> Option<DistinctApprox> {
+        self.distinct.as_ref().map(|counter| counter.mode)
+    }
+
+    /// The estimated number of distinct addresses read, if estimated.
+    pub fn distinct_estimate(&self) -> Option<DistinctEstimate> {
+        self.distinct.as_ref().map(|counter| DistinctEstimate {
+            count: counter.total.estimate(),
+            relative_error: counter.total.relative_error(),
+        })
+    }
+
+    /// Bytes held by the per-pixel sketches.
+    pub fn distinct_sketch_bytes(&self) -> usize {
+        self.distinct.as_ref().map_or(0, |counter| counter.pixels.values().map(HyperLogLog::size).sum())
+    }
+
+    /// Whether records are painted with [`Heatmap::paint_distinct`].
+    pub(crate) fn paints_distinct(&self) -> bool {
+        self.distinct_approx() == Some(DistinctApprox::Pixels)
+    }
+
+    /// Count `record` in the sketch of the whole input.
+    pub(crate) fn count_distinct(&mut self, record: &Record) {
+        if let Some(counter) = &mut self.distinct {
+            counter.total.insert(key(record));
+        }
+    }
+
+    /// Count `record` in the sketch of every pixel it covers, and set each cell whose
+    /// estimate changed to the estimate.
+    pub(crate) fn paint_distinct(&mut self, record: &Record) {
+        let order = self.geometry().order;
+        let key = key(record);
+        // Taken out so the buffer can be checked while a sketch is borrowed
+        let Some(mut counter) = self.distinct.take() else {
+            return;
+        };
+        let precision = counter.total.precision();
+        input::for_each_pixel(self.bits_per_pixel, ValueMode::Raw, record, |d, _| {
+            let Some((x, y)) = hilbert_d2xy(d, order) else {
+                return;
+            };
+            let Some(index) = self.cell_index(x, y) else {
+                return;
+            };
+            let sketch = counter
+                .pixels
+                .entry(index)
+                .or_insert_with(|| HyperLogLog::new(precision).expect("precision was checked"));
+            if sketch.insert(key) {
+                self.touched[index / 64] |= 1 << (index % 64);
+                self.buffer[y as usize][x as usize] = sketch.estimate().round() as i32;
+            }
+        });
+        self.distinct = Some(counter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+
+    fn heatmap(mode: DistinctApprox, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.set_distinct_approx(Some(mode), 10).unwrap();
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_pixels_hold_their_estimates() {
+        // 10.0.0.0/16 sees 300 addresses, each ten times, and 10.1.0.0/16 one
+        let mut input = String::new();
+        for repeat in 0..10 {
+            for host in 0..300 {
+                input.push_str(&format!("10.0.{}.{} {}\n", host / 256, host % 256, repeat));
+            }
+        }
+        input.push_str("10.1.0.1 50\n");
+        let hm = heatmap(DistinctApprox::Pixels, &input);
+        let totals = hm.prefix_totals(16).unwrap();
+        let value = |prefix: &str| totals.iter().find(|(net, _)| net.to_string() == prefix).unwrap().1;
+        assert!((value("10.0.0.0/16") - 300).abs() <= 10, "{}", value("10.0.0.0/16"));
+        assert_eq!(value("10.1.0.0/16"), 1);
+        assert_eq!(hm.touched_pixels(), 2);
+        assert_eq!(hm.distinct_sketch_bytes(), 2 * 1024);
+        let estimate = hm.distinct_estimate().unwrap();
+        assert!((estimate.count - 301.0).abs() <= 10.0, "{}", estimate.count);
+    }
+
+    #[test]
+    fn test_total_only_leaves_values_alone() {
+        let hm = heatmap(DistinctApprox::Total, "10.0.0.1 5\n10.0.0.1 5\n10.0.0.2 5\n10.0.0.0/24 1\n");
+        let totals = hm.prefix_totals(16).unwrap();
+        assert_eq!(totals.iter().find(|(net, _)| net.to_string() == "10.0.0.0/16").unwrap().1, 16);
+        assert_eq!(hm.distinct_estimate().unwrap().count.round(), 3.0);
+        assert_eq!(hm.distinct_sketch_bytes(), 0);
+        assert_eq!("pixels".parse::<DistinctApprox>(), Ok(DistinctApprox::Pixels));
+        let mut categorical =
+            Heatmap::new(DomainType::Linear, None, None, false, 16, &colorous::MAGMA, ValueMode::Categorical, None);
+        assert!(categorical.set_distinct_approx(Some(DistinctApprox::Pixels), 10).is_err());
+        assert!(categorical.set_distinct_approx(Some(DistinctApprox::Total), 20).is_err());
+    }
+}
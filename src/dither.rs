@@ -0,0 +1,252 @@
+//! Ordered dithering of scaled values before the palette lookup, which breaks up the
+//! bands that large areas of slowly varying values show at 8 bits per channel.
+//!
+//! Each pixel's scaled value moves by up to half of [`Dither::strength`] colour levels
+//! either way, a level being 1/256 of the scale, by a threshold that depends only on
+//! the pixel's coordinates. Renders are reproducible, and the thresholds of every
+//! tile average out, so the mean colour of an area stays what it was.
+
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Strength of `--dither` when not given: offsets spanning one colour level.
+pub const DEFAULT_DITHER_STRENGTH: f64 = 1.0;
+
+/// Strengths `--dither-strength` takes, in colour levels.
+pub const DITHER_STRENGTHS: std::ops::RangeInclusive<f64> = 0.0..=64.0;
+
+/// Side of the blue-noise tile.
+const BLUE_NOISE_SIDE: usize = 64;
+
+/// The threshold pattern of a [`Dither`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DitherPattern {
+    /// The 8x8 Bayer matrix: cheap and regular, with a faint cross-hatch.
+    Bayer,
+    /// A 64x64 void-and-cluster tile, whose grain has no visible structure.
+    BlueNoise,
+}
+
+impl DitherPattern {
+    pub const ALL: [DitherPattern; 2] = [DitherPattern::Bayer, DitherPattern::BlueNoise];
+
+    /// The threshold of pixel (`x`, `y`), in (0, 1).
+    fn threshold(self, x: u32, y: u32) -> f64 {
+        match self {
+            DitherPattern::Bayer => (bayer(x, y) as f64 + 0.5) / 64.0,
+            DitherPattern::BlueNoise => {
+                let index = (y as usize % BLUE_NOISE_SIDE) * BLUE_NOISE_SIDE + x as usize % BLUE_NOISE_SIDE;
+                (blue_noise()[index] as f64 + 0.5) / (BLUE_NOISE_SIDE * BLUE_NOISE_SIDE) as f64
+            }
+        }
+    }
+}
+
+impl FromStr for DitherPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bayer" | "ordered" => Ok(DitherPattern::Bayer),
+            "blue-noise" | "bluenoise" => Ok(DitherPattern::BlueNoise),
+            _ => Err(format!("Invalid dither pattern: {}. Use 'bayer' or 'blue-noise'", s)),
+        }
+    }
+}
+
+impl Display for DitherPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DitherPattern::Bayer => write!(f, "bayer"),
+            DitherPattern::BlueNoise => write!(f, "blue-noise"),
+        }
+    }
+}
+
+/// How scaled values are dithered, see the [module docs](self).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dither {
+    pub pattern: DitherPattern,
+    /// Colour levels between the largest offsets down and up.
+    pub strength: f64,
+}
+
+impl Dither {
+    pub fn new(pattern: DitherPattern) -> Self {
+        Dither { pattern, strength: DEFAULT_DITHER_STRENGTH }
+    }
+
+    /// The amount added to the scaled value of pixel (`x`, `y`).
+    pub fn offset(&self, x: u32, y: u32) -> f64 {
+        (self.pattern.threshold(x, y) - 0.5) * self.strength / 256.0
+    }
+
+    /// `scaled` dithered at pixel (`x`, `y`), still within [0, 1].
+    pub fn apply(&self, scaled: f64, x: u32, y: u32) -> f64 {
+        (scaled + self.offset(x, y)).clamp(0.0, 1.0)
+    }
+}
+
+/// Parse a dither strength in colour levels, see [`DITHER_STRENGTHS`].
+pub fn parse_dither_strength(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(strength) if DITHER_STRENGTHS.contains(&strength) => Ok(strength),
+        _ => Err(format!("Dither strength must be a number of colour levels from 0 to 64: {}", value)),
+    }
+}
+
+/// The rank of pixel (`x`, `y`) in the 8x8 Bayer matrix, from 0 to 63.
+fn bayer(x: u32, y: u32) -> u32 {
+    let (x, y) = (x & 7, y & 7);
+    // The lowest bits of the coordinates pick the most significant bits of the rank
+    (0..3).fold(0, |rank, bit| (rank << 2) | (((x ^ y) >> bit & 1) << 1) | (y >> bit & 1))
+}
+
+/// The ranks of a blue-noise tile, made once per process.
+fn blue_noise() -> &'static [u16] {
+    static RANKS: OnceLock<Vec<u16>> = OnceLock::new();
+    RANKS.get_or_init(void_and_cluster)
+}
+
+/// Ulichney's void-and-cluster method: rank the pixels of a tile so that those
+/// below any rank are spread as evenly as can be, with the energy of each pixel
+/// the sum of a Gaussian around every chosen pixel, wrapping at the edges.
+fn void_and_cluster() -> Vec<u16> {
+    const SIDE: usize = BLUE_NOISE_SIDE;
+    const PIXELS: usize = SIDE * SIDE;
+    let wrapped = |d: usize| d.min(SIDE - d) as f64;
+    let kernel: Vec<f64> = (0..PIXELS)
+        .map(|offset| {
+            let (dx, dy) = (wrapped(offset % SIDE), wrapped(offset / SIDE));
+            (-(dx * dx + dy * dy) / (2.0 * 1.5 * 1.5)).exp()
+        })
+        .collect();
+    struct Pattern<'a> {
+        kernel: &'a [f64],
+        chosen: Vec<bool>,
+        energy: Vec<f64>,
+    }
+    impl Pattern<'_> {
+        fn set(&mut self, pixel: usize, chosen: bool) {
+            self.chosen[pixel] = chosen;
+            let sign = if chosen { 1.0 } else { -1.0 };
+            let (px, py) = (pixel % SIDE, pixel / SIDE);
+            for (other, energy) in self.energy.iter_mut().enumerate() {
+                let (dx, dy) = ((other % SIDE + SIDE - px) % SIDE, (other / SIDE + SIDE - py) % SIDE);
+                *energy += sign * self.kernel[dy * SIDE + dx];
+            }
+        }
+
+        /// The chosen pixel with the most energy, or the other with the least.
+        fn extreme(&self, chosen: bool) -> usize {
+            let candidates = (0..PIXELS).filter(|&pixel| self.chosen[pixel] == chosen);
+            let energy = |pixel: &usize| self.energy[*pixel];
+            match chosen {
+                true => candidates.max_by(|a, b| energy(a).total_cmp(&energy(b)).then(b.cmp(a))),
+                false => candidates.min_by(|a, b| energy(a).total_cmp(&energy(b)).then(a.cmp(b))),
+            }
+            .expect("the tile has pixels of both kinds")
+        }
+    }
+
+    // A tenth of the pixels, picked by a fixed seed and then spread out by moving the
+    // most crowded one to the emptiest spot until that is where it was
+    let mut pattern = Pattern { kernel: &kernel, chosen: vec![false; PIXELS], energy: vec![0.0; PIXELS] };
+    let (initial, mut seed) = (PIXELS / 10, 0u64);
+    let mut placed = 0;
+    while placed < initial {
+        seed = seed.wrapping_add(1);
+        let pixel = (crate::input::splitmix64(seed) % PIXELS as u64) as usize;
+        if !pattern.chosen[pixel] {
+            pattern.set(pixel, true);
+            placed += 1;
+        }
+    }
+    loop {
+        let cluster = pattern.extreme(true);
+        pattern.set(cluster, false);
+        let void = pattern.extreme(false);
+        pattern.set(void, true);
+        if void == cluster {
+            break;
+        }
+    }
+    let mut ranks = vec![0u16; PIXELS];
+    let mut removing = Pattern { kernel: &kernel, chosen: pattern.chosen.clone(), energy: pattern.energy.clone() };
+    for rank in (0..initial).rev() {
+        let cluster = removing.extreme(true);
+        removing.set(cluster, false);
+        ranks[cluster] = rank as u16;
+    }
+    for rank in initial..PIXELS - 1 {
+        let void = pattern.extreme(false);
+        pattern.set(void, true);
+        ranks[void] = rank as u16;
+    }
+    let last = pattern.chosen.iter().position(|&chosen| !chosen).expect("one pixel is left");
+    ranks[last] = (PIXELS - 1) as u16;
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `ranks` hold every rank below their count once.
+    fn is_permutation(ranks: impl Iterator<Item = usize>, count: usize) -> bool {
+        let mut seen = vec![false; count];
+        ranks.into_iter().all(|rank| rank < count && !std::mem::replace(&mut seen[rank], true)) && seen.iter().all(|&s| s)
+    }
+
+    #[test]
+    fn test_bayer_matrix() {
+        assert_eq!([bayer(0, 0), bayer(1, 0), bayer(0, 1), bayer(1, 1)], [0, 32, 48, 16]);
+        assert!(is_permutation((0..64).map(|i| bayer(i % 8, i / 8) as usize), 64));
+        // The matrix repeats every 8 pixels
+        assert_eq!(bayer(3, 5), bayer(3 + 8, 5 + 16));
+    }
+
+    #[test]
+    fn test_blue_noise_tile() {
+        let ranks = blue_noise();
+        assert!(is_permutation(ranks.iter().map(|&rank| rank as usize), BLUE_NOISE_SIDE * BLUE_NOISE_SIDE));
+        // Made the same way every time
+        assert_eq!(void_and_cluster(), ranks);
+        // The pixels below any rank are spread out: each 8x8 window of the tile holds
+        // close to its share of the lowest quarter, much closer than chance would
+        for window in 0..64 {
+            let (left, top) = (window % 8 * 8, window / 8 * 8);
+            let low = (0..64)
+                .filter(|i| ranks[(top + i / 8) * BLUE_NOISE_SIDE + left + i % 8] < 1024)
+                .count();
+            assert!((12..=20).contains(&low), "window at ({}, {}) has {} of 16", left, top, low);
+        }
+    }
+
+    #[test]
+    fn test_offsets_average_out() {
+        for pattern in DitherPattern::ALL {
+            let dither = Dither { pattern, strength: 4.0 };
+            let offsets: Vec<f64> = (0..64 * 64).map(|i| dither.offset(i % 64, i / 64)).collect();
+            let mean = offsets.iter().sum::<f64>() / offsets.len() as f64;
+            assert!(mean.abs() < 1e-12, "{}: {}", pattern, mean);
+            // Within two colour levels either way
+            assert!(offsets.iter().all(|offset| offset.abs() < 2.0 / 256.0), "{}", pattern);
+            // The ends of the scale stay on it
+            let (ends, tile) = ([0.0, 1.0], 0..64 * 64);
+            let dithered: Vec<f64> = ends.iter().flat_map(|&end| tile.clone().map(move |i| dither.apply(end, i % 64, i / 64))).collect();
+            assert!(dithered.iter().all(|scaled| (0.0..=1.0).contains(scaled)));
+            assert!(dithered.contains(&0.0) && dithered.contains(&1.0));
+        }
+    }
+
+    #[test]
+    fn test_parse_dither_strength() {
+        assert_eq!(parse_dither_strength("2.5"), Ok(2.5));
+        assert_eq!(parse_dither_strength("0"), Ok(0.0));
+        for invalid in ["-1", "65", "NaN", "strong"] {
+            assert!(parse_dither_strength(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
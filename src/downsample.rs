@@ -0,0 +1,248 @@
+use crate::memory::{MemoryEstimate, MemoryPlan};
+use crate::streamed::{self, STREAMED_ENCODE_MIN_SIZE};
+use crate::snapshot::Settings;
+use crate::{Heatmap, ValueMode, cells, image_size_for_bpp};
+use anyhow::{Result, bail};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// How the painted cells of a block are combined when downsampling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Aggregation {
+    /// The largest value, so isolated hotspots stay visible.
+    #[default]
+    Max,
+    Sum,
+    Mean,
+}
+
+impl Aggregation {
+    pub const ALL: [Aggregation; 3] = [Aggregation::Max, Aggregation::Sum, Aggregation::Mean];
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "max" => Ok(Aggregation::Max),
+            "sum" => Ok(Aggregation::Sum),
+            "mean" => Ok(Aggregation::Mean),
+            _ => Err(format!("Invalid aggregation: {}. Use 'max', 'sum', or 'mean'", s)),
+        }
+    }
+}
+
+impl Display for Aggregation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Aggregation::Max => write!(f, "max"),
+            Aggregation::Sum => write!(f, "sum"),
+            Aggregation::Mean => write!(f, "mean"),
+        }
+    }
+}
+
+/// A smaller image to write alongside the main output: `path:size`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThumbnailSpec {
+    pub output: String,
+    pub size: u32,
+}
+
+impl FromStr for ThumbnailSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (output, size) = spec
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Invalid thumbnail spec: {}. Use path:size, e.g. thumb.png:256", spec))?;
+        let size = size
+            .parse()
+            .map_err(|_| format!("Invalid thumbnail size: {}", size))?;
+        if output.is_empty() {
+            return Err(format!("Thumbnail spec has no output path: {}", spec));
+        }
+        Ok(ThumbnailSpec {
+            output: output.to_string(),
+            size,
+        })
+    }
+}
+
+impl Heatmap {
+    /// A copy reduced to `size` pixels per side by combining square blocks of cells.
+    ///
+    /// Aligned blocks of the Hilbert curve are cells of a coarser curve, so the result
+    /// is the heatmap at a larger bits_per_pixel. `size` must be a power of two no
+    /// larger than the image. Untouched cells are ignored; a block without painted
+    /// cells stays untouched.
+    pub fn downsample(&self, size: u32, aggregation: Aggregation) -> Result<Heatmap> {
+        let cell = |x: usize, y: usize| self.is_touched(x, y).then(|| self.buffer[y][x]);
+        downsample_cells(&self.settings(), self.image_size(), size, aggregation, cell)
+    }
+}
+
+/// Check `size` is one a map at `bits_per_pixel` can be downsampled to, so a bad
+/// size fails before any input is read.
+pub fn check_downsample_size(bits_per_pixel: u8, size: u32) -> Result<()> {
+    let image_size = image_size_for_bpp(bits_per_pixel);
+    if !size.is_power_of_two() || size > image_size {
+        bail!(
+            "Downsampled size must be a power of two up to {} at bits_per_pixel {}, got {}",
+            image_size,
+            bits_per_pixel,
+            size
+        );
+    }
+    Ok(())
+}
+
+/// [`Heatmap::downsample`] of a map of `image_size` with `settings`, whose painted
+/// cells `cell` returns.
+pub(crate) fn downsample_cells(
+    settings: &Settings,
+    image_size: u32,
+    size: u32,
+    aggregation: Aggregation,
+    cell: impl Fn(usize, usize) -> Option<i32>,
+) -> Result<Heatmap> {
+    check_downsample_size(settings.bits_per_pixel, size)?;
+    if settings.value_mode == ValueMode::Categorical && aggregation != Aggregation::Max {
+        log::warn!("Categorical values are labels; {} aggregation mixes them", aggregation);
+    }
+    let levels = (image_size / size).trailing_zeros() as u8;
+    let bits_per_pixel = settings.bits_per_pixel + 2 * levels;
+    debug_assert_eq!(image_size_for_bpp(bits_per_pixel), size);
+
+    let mut reduced = settings.heatmap(bits_per_pixel);
+    reduced.downsampled_from = settings.downsampled_from.or(Some(settings.bits_per_pixel));
+
+    let factor = (image_size / size) as usize;
+    for y in 0..size as usize {
+        for x in 0..size as usize {
+            let mut count = 0i64;
+            let mut total = 0i64;
+            let mut largest = i32::MIN;
+            for cy in y * factor..(y + 1) * factor {
+                for cx in x * factor..(x + 1) * factor {
+                    if let Some(value) = cell(cx, cy) {
+                        count += 1;
+                        total += value as i64;
+                        largest = largest.max(value);
+                    }
+                }
+            }
+            if count == 0 {
+                continue;
+            }
+            let value = match aggregation {
+                Aggregation::Max => largest,
+                Aggregation::Sum => total.clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+                Aggregation::Mean => (total as f64 / count as f64).round() as i32,
+            };
+            reduced.buffer[y][x] = value;
+            let index = y * size as usize + x;
+            reduced.touched[index / 64] |= 1 << (index % 64);
+        }
+    }
+    Ok(reduced)
+}
+
+/// Add the downsampled map and the largest thumbnail with its image. Thumbnails are
+/// written one at a time, and mean aggregation sums block by block, so neither needs
+/// more than one reduced map.
+pub(crate) fn add_memory(plan: &MemoryPlan, estimate: &mut MemoryEstimate) {
+    if let Some(size) = plan.output_size {
+        let (cells, touched) = cells::buffer_bytes(size);
+        estimate.add("downsampled map", cells + touched);
+    }
+    let thumbnail = plan.thumbnails.iter().map(|&size| {
+        let (cells, touched) = cells::buffer_bytes(size);
+        let streamed = plan.low_memory || size >= STREAMED_ENCODE_MIN_SIZE;
+        cells + touched + streamed::image_bytes(size, streamed)
+    });
+    if let Some(bytes) = thumbnail.max() {
+        estimate.add("thumbnail", bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+    use std::net::Ipv4Addr;
+
+    fn heatmap(bits_per_pixel: u8, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_two_by_two_reduction() {
+        // At bits_per_pixel 30 the image is 2x2: one pixel per /2
+        let hm = heatmap(30, "0.0.0.1 4\n64.0.0.1 -1\n128.0.0.1 7\n");
+        let pixel = |a: u8| hm.ip_to_xy(u32::from(Ipv4Addr::new(a, 0, 0, 1))).unwrap();
+        let mut expected = [[0; 2]; 2];
+        for (a, value) in [(0, 4), (64, -1), (128, 7)] {
+            let (x, y) = pixel(a);
+            expected[y as usize][x as usize] = value;
+        }
+        assert_eq!(hm.buffer, expected.map(Vec::from).to_vec());
+
+        // The fourth cell is untouched, so it takes no part in any aggregate
+        for (aggregation, value) in [(Aggregation::Max, 7), (Aggregation::Sum, 10), (Aggregation::Mean, 3)] {
+            let reduced = hm.downsample(1, aggregation).unwrap();
+            assert_eq!(reduced.bits_per_pixel, 32);
+            assert_eq!(reduced.buffer, vec![vec![value]], "{}", aggregation);
+            assert_eq!(reduced.touched_pixels(), 1);
+        }
+    }
+
+    #[test]
+    fn test_downsample_matches_coarser_bits_per_pixel() {
+        let input = "10.0.0.1 5\n10.200.0.0/16 3\n192.168.0.0/16 9\n8.8.8.8 1\n";
+        let fine = heatmap(16, input);
+        let coarse = heatmap(20, input);
+        let reduced = fine.downsample(64, Aggregation::Sum).unwrap();
+        assert_eq!(reduced.buffer, coarse.buffer);
+        assert_eq!(reduced.touched_pixels(), coarse.touched_pixels());
+    }
+
+    #[test]
+    fn test_untouched_blocks_stay_empty() {
+        let hm = heatmap(16, "10.0.0.1 5\n");
+        let reduced = hm.downsample(16, Aggregation::Max).unwrap();
+        assert_eq!(reduced.touched_pixels(), 1);
+        assert_eq!(reduced.buffer.iter().flatten().filter(|&&v| v != 0).count(), 1);
+    }
+
+    #[test]
+    fn test_invalid_sizes() {
+        let hm = heatmap(16, "");
+        assert!(hm.downsample(100, Aggregation::Max).is_err());
+        assert!(hm.downsample(512, Aggregation::Max).is_err());
+        assert!(hm.downsample(0, Aggregation::Max).is_err());
+        assert!(hm.downsample(256, Aggregation::Max).is_ok());
+    }
+
+    #[test]
+    fn test_thumbnail_spec() {
+        let spec: ThumbnailSpec = "maps/thumb.png:256".parse().unwrap();
+        assert_eq!(spec.output, "maps/thumb.png");
+        assert_eq!(spec.size, 256);
+        assert!("thumb.png".parse::<ThumbnailSpec>().is_err());
+        assert!("thumb.png:big".parse::<ThumbnailSpec>().is_err());
+        assert!(":256".parse::<ThumbnailSpec>().is_err());
+    }
+}
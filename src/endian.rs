@@ -0,0 +1,86 @@
+//! The little-endian integers of the binary formats (`raw-u32v` records, `.npy`
+//! profiles and state files), read and written the same on any host.
+
+/// The `u32` at `offset` in `bytes`.
+pub(crate) fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+/// The `i32` at `offset` in `bytes`.
+pub(crate) fn i32_at(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+/// The `u64` at `offset` in `bytes`.
+pub(crate) fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+/// Append `values` to `bytes`, four bytes each.
+pub(crate) fn extend_i32s(bytes: &mut Vec<u8>, values: &[i32]) {
+    bytes.reserve(values.len() * 4);
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Fill `values` from `bytes`, which holds exactly four bytes for each.
+pub(crate) fn read_i32s(bytes: &[u8], values: &mut [i32]) {
+    assert_eq!(bytes.len(), values.len() * 4);
+    for (value, chunk) in values.iter_mut().zip(bytes.chunks_exact(4)) {
+        *value = i32_at(chunk, 0);
+    }
+}
+
+/// Append `words` to `bytes`, eight bytes each.
+pub(crate) fn extend_u64s(bytes: &mut Vec<u8>, words: &[u64]) {
+    bytes.reserve(words.len() * 8);
+    for word in words {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+}
+
+/// Fill `words` from `bytes`, which holds exactly eight bytes for each.
+pub(crate) fn read_u64s(bytes: &[u8], words: &mut [u64]) {
+    assert_eq!(bytes.len(), words.len() * 8);
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(8)) {
+        *word = u64_at(chunk, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Byte fixtures rather than round trips, which would pass in either byte order
+    const I32S: [i32; 4] = [1, -2, 0x0102_0304, i32::MIN];
+    const I32_BYTES: [u8; 16] = [1, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff, 4, 3, 2, 1, 0, 0, 0, 0x80];
+    const U64S: [u64; 2] = [0x0102_0304_0506_0708, 1 << 63];
+    const U64_BYTES: [u8; 16] = [8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0x80];
+
+    #[test]
+    fn test_integers_are_little_endian() {
+        let mut bytes = Vec::new();
+        extend_i32s(&mut bytes, &I32S);
+        assert_eq!(bytes, I32_BYTES);
+        let mut values = [0; 4];
+        read_i32s(&I32_BYTES, &mut values);
+        assert_eq!(values, I32S);
+
+        bytes.clear();
+        extend_u64s(&mut bytes, &U64S);
+        assert_eq!(bytes, U64_BYTES);
+        let mut words = [0; 2];
+        read_u64s(&U64_BYTES, &mut words);
+        assert_eq!(words, U64S);
+    }
+
+    #[test]
+    fn test_values_at_offsets() {
+        assert_eq!(u32_at(&I32_BYTES, 8), 0x0102_0304);
+        assert_eq!(u32_at(&I32_BYTES, 4), 0xffff_fffe);
+        assert_eq!(i32_at(&I32_BYTES, 12), i32::MIN);
+        assert_eq!(u64_at(&U64_BYTES, 0), 0x0102_0304_0506_0708);
+        assert_eq!(u64_at(&I32_BYTES, 0), 0xffff_fffe_0000_0001);
+    }
+}
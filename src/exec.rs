@@ -0,0 +1,81 @@
+//! Input read from the stdout of a child command, for `--exec`.
+//!
+//! The child's stderr is passed through and its stdin is closed. A child that
+//! fails fails the run with its own exit status, so that a capture tool's error
+//! is not mistaken for an empty capture.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+
+/// Start `command` through the shell with its stdout piped to us.
+pub fn spawn(command: &str) -> Result<Child> {
+    #[cfg(unix)]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    #[cfg(not(unix))]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    shell
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", command))
+}
+
+/// Stop a child whose output is no longer read, and reap it.
+pub fn kill(child: &mut Child, command: &str) {
+    log::debug!("Stopping `{}`", command);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Wait for a child that closed its stdout, failing if it did not succeed.
+pub fn wait(child: &mut Child, command: &str) -> Result<()> {
+    let status = child.wait().with_context(|| format!("Failed to wait for {}", command))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(CommandFailed { command: command.to_string(), status }.into()),
+    }
+}
+
+/// A child command of `--exec` that exited with a failure.
+#[derive(Debug)]
+pub struct CommandFailed {
+    command: String,
+    status: ExitStatus,
+}
+
+impl CommandFailed {
+    /// The exit code to pass on: the child's own, or 128 plus the signal that
+    /// killed it as shells report it.
+    pub fn exit_code(&self) -> u8 {
+        if let Some(code) = self.status.code() {
+            return code.clamp(1, 255) as u8;
+        }
+        #[cfg(unix)]
+        if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&self.status) {
+            return (128 + signal).clamp(1, 255) as u8;
+        }
+        1
+    }
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status.code() {
+            Some(code) => write!(f, "Command `{}` exited with status {}", self.command, code),
+            None => write!(f, "Command `{}` was killed by signal {}", self.command, self.exit_code() as i32 - 128),
+        }
+    }
+}
+
+impl std::error::Error for CommandFailed {}
@@ -0,0 +1,242 @@
+//! Prefixes that are expected to show traffic, checked against the painted map.
+
+use crate::hilbert::hilbert_d2xy;
+use crate::{Heatmap, ValueMode};
+use anyhow::{Result, bail};
+use ipnet::Ipv4Net;
+use std::fmt::Display;
+use std::io::Write;
+use std::net::Ipv4Addr;
+
+/// A prefix from an expectation file, with its own threshold if the line gives one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expectation {
+    pub net: Ipv4Net,
+    pub threshold: Option<i64>,
+    pub line_number: usize,
+}
+
+/// Parse an expectation file: one prefix or address per line, optionally followed
+/// by the least total value it must show. Blank lines and `#` comments are skipped.
+pub fn parse_expectations(text: &str) -> Result<Vec<Expectation>, String> {
+    let mut expectations = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(prefix) = fields.next() else {
+            continue;
+        };
+        let net = match prefix.parse::<Ipv4Net>() {
+            Ok(net) => net.trunc(),
+            Err(_) => match prefix.parse::<Ipv4Addr>() {
+                Ok(addr) => Ipv4Net::from(addr),
+                Err(_) => return Err(format!("Line {}: invalid expected prefix: {}", line_number, prefix)),
+            },
+        };
+        let threshold = match fields.next() {
+            Some(threshold) => Some(
+                threshold
+                    .parse::<i64>()
+                    .map_err(|_| format!("Line {}: invalid threshold: {}", line_number, threshold))?,
+            ),
+            None => None,
+        };
+        if let Some(extra) = fields.next() {
+            return Err(format!("Line {}: unexpected field {}. Use prefix [threshold]", line_number, extra));
+        }
+        expectations.push(Expectation { net, threshold, line_number });
+    }
+    Ok(expectations)
+}
+
+/// The painted value of a prefix, see [`Heatmap::value_for_cidr`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CidrValue {
+    /// Sum of the painted pixels covering the prefix.
+    pub total: i64,
+    pub touched_pixels: u64,
+    pub pixels: u64,
+    /// False for prefixes smaller than a pixel, whose pixel's value includes its
+    /// neighbours'.
+    pub exact: bool,
+}
+
+/// How an expected prefix fared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExpectationStatus {
+    /// The prefix shows at least its threshold.
+    Met,
+    /// Some of its pixels were painted, but for less than the threshold.
+    Low,
+    /// None of its pixels were painted.
+    Absent,
+}
+
+impl Display for ExpectationStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExpectationStatus::Met => write!(f, "met"),
+            ExpectationStatus::Low => write!(f, "low"),
+            ExpectationStatus::Absent => write!(f, "absent"),
+        }
+    }
+}
+
+/// An expected prefix checked against the map.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpectationCheck {
+    pub expectation: Expectation,
+    /// The expectation's threshold, or the default it was checked with.
+    pub threshold: i64,
+    pub observed: CidrValue,
+}
+
+impl ExpectationCheck {
+    pub fn status(&self) -> ExpectationStatus {
+        if self.observed.touched_pixels == 0 {
+            ExpectationStatus::Absent
+        } else if self.observed.total < self.threshold {
+            ExpectationStatus::Low
+        } else {
+            ExpectationStatus::Met
+        }
+    }
+}
+
+impl Heatmap {
+    /// The total value and painted pixels of the pixels covering `net`. Parts of the
+    /// prefix beyond the square of an odd `bits_per_pixel` count as unpainted.
+    pub fn value_for_cidr(&self, net: &Ipv4Net) -> CidrValue {
+        let geometry = self.geometry();
+        let first_d = (u32::from(net.network()) as u64) >> self.bits_per_pixel;
+        let last_d = ((u32::from(net.broadcast()) as u64) >> self.bits_per_pixel).min(geometry.pixels() - 1);
+        let mut value = CidrValue {
+            exact: net.prefix_len() <= geometry.prefix_len_per_pixel,
+            ..CidrValue::default()
+        };
+        let skip_sentinel = self.value_mode == ValueMode::Categorical;
+        for d in first_d..=last_d {
+            let (x, y) = hilbert_d2xy(d, geometry.order).expect("d is on the curve");
+            value.pixels += 1;
+            if self.is_touched(x as usize, y as usize) {
+                let cell = self.buffer[y as usize][x as usize];
+                value.touched_pixels += 1;
+                if !skip_sentinel || cell > 0 {
+                    value.total += cell as i64;
+                }
+            }
+        }
+        value
+    }
+
+    /// Check every expectation, those without a threshold of their own against
+    /// `default_threshold`.
+    pub fn check_expectations(&self, expectations: &[Expectation], default_threshold: i64) -> Result<Vec<ExpectationCheck>> {
+        if self.value_mode == ValueMode::Categorical {
+            bail!("Categorical values are labels, so they cannot meet a threshold");
+        }
+        Ok(expectations
+            .iter()
+            .map(|expectation| ExpectationCheck {
+                expectation: expectation.clone(),
+                threshold: expectation.threshold.unwrap_or(default_threshold),
+                observed: self.value_for_cidr(&expectation.net),
+            })
+            .collect())
+    }
+}
+
+/// Write the expectations that were not met, one per line with their status, the
+/// observed total, the threshold and the painted share of their pixels, tab-separated.
+/// Totals of prefixes smaller than a pixel are marked with a `~`.
+pub fn write_expectation_report<W: Write>(checks: &[ExpectationCheck], mut writer: W) -> std::io::Result<()> {
+    let unmet: Vec<&ExpectationCheck> = checks.iter().filter(|check| check.status() != ExpectationStatus::Met).collect();
+    writeln!(writer, "# {} of {} expected prefixes are below their threshold", unmet.len(), checks.len())?;
+    writeln!(writer, "# prefix\tstatus\tobserved\tthreshold\tpainted pixels")?;
+    for check in unmet {
+        writeln!(
+            writer,
+            "{}\t{}\t{}{}\t{}\t{}/{}",
+            check.expectation.net,
+            check.status(),
+            if check.observed.exact { "" } else { "~" },
+            check.observed.total,
+            check.threshold,
+            check.observed.touched_pixels,
+            check.observed.pixels
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+
+    fn heatmap(value_mode: ValueMode, input: &str) -> Heatmap {
+        let accumulate = value_mode != ValueMode::Categorical;
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, accumulate, 16, &colorous::MAGMA, value_mode, None);
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_parse_expectations() {
+        let text = "# anycast\n192.0.2.0/24 100\n\n198.51.100.7   # office egress\n10.1.2.3/16\n";
+        let expectations = parse_expectations(text).unwrap();
+        let parsed: Vec<(String, Option<i64>, usize)> = expectations
+            .iter()
+            .map(|expectation| (expectation.net.to_string(), expectation.threshold, expectation.line_number))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                ("192.0.2.0/24".to_string(), Some(100), 2),
+                ("198.51.100.7/32".to_string(), None, 4),
+                ("10.1.0.0/16".to_string(), None, 5)
+            ]
+        );
+        assert_eq!(parse_expectations("10.0.0.0/8 many").unwrap_err(), "Line 1: invalid threshold: many");
+        assert!(parse_expectations("\n10.0.0.0/33\n").unwrap_err().starts_with("Line 2:"));
+        assert!(parse_expectations("10.0.0.0/8 1 2").is_err());
+    }
+
+    #[test]
+    fn test_present_absent_and_partial() {
+        // 10.0.0.0/15 covers two pixels, only one of which is painted
+        let hm = heatmap(ValueMode::Raw, "10.0.0.1 5\n10.0.0.2 5\n11.0.0.0/16 2\n10.2.0.0 1\n");
+        let expectations =
+            parse_expectations("10.0.0.0/16\n10.0.0.0/15\n11.0.0.0/16 3\n12.0.0.0/8\n10.2.0.0/24 1\n").unwrap();
+        let checks = hm.check_expectations(&expectations, 1).unwrap();
+        let statuses: Vec<ExpectationStatus> = checks.iter().map(ExpectationCheck::status).collect();
+        use ExpectationStatus::*;
+        assert_eq!(statuses, [Met, Met, Low, Absent, Met]);
+        assert_eq!(checks[1].observed, CidrValue { total: 10, touched_pixels: 1, pixels: 2, exact: true });
+        assert_eq!(checks[3].observed.pixels, 256);
+        assert!(!checks[4].observed.exact);
+
+        // A higher default only applies to the prefixes without a threshold of their own
+        let strict = hm.check_expectations(&expectations, 20).unwrap();
+        let statuses: Vec<ExpectationStatus> = strict.iter().map(ExpectationCheck::status).collect();
+        assert_eq!(statuses, [Low, Low, Low, Absent, Met]);
+
+        let mut report = Vec::new();
+        write_expectation_report(&checks, &mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "# 2 of 5 expected prefixes are below their threshold\n\
+             # prefix\tstatus\tobserved\tthreshold\tpainted pixels\n\
+             11.0.0.0/16\tlow\t2\t3\t1/1\n\
+             12.0.0.0/8\tabsent\t0\t1\t0/256\n"
+        );
+    }
+
+    #[test]
+    fn test_categorical_maps_have_no_thresholds() {
+        let hm = heatmap(ValueMode::Categorical, "10.0.0.1 2\n");
+        assert!(hm.check_expectations(&parse_expectations("10.0.0.0/8").unwrap(), 1).is_err());
+        assert_eq!(hm.value_for_cidr(&"10.0.0.0/16".parse().unwrap()).touched_pixels, 1);
+    }
+}
@@ -0,0 +1,171 @@
+//! Combinations of render flags that cannot work together, checked right after the
+//! command line is parsed.
+//!
+//! Clap refuses pairs of flags that conflict whatever their values; the tables here
+//! hold the combinations that depend on values, such as `--category-colours` without
+//! `--value-mode categorical`. Every combination given is reported at once, before
+//! any output is written or input read. A new combination belongs in [`CONFLICTS`],
+//! or in [`SINGLE_RESOLUTION`] when a flag only works with one `-z`.
+
+use crate::RenderArgs;
+use ip_heatmap::{Aggregation, ConflictPolicy, DistinctApprox, DomainType, InputFormat, RenderOptions, RenderSpec, ValueMode, ValueSource};
+
+/// A combination of flags that is refused.
+pub struct Conflict {
+    /// Whether `args` hold the combination.
+    pub given: fn(&RenderArgs) -> bool,
+    /// What is wrong and what to use instead.
+    pub message: &'static str,
+}
+
+fn categorical(args: &RenderArgs) -> bool {
+    args.value_mode == ValueMode::Categorical
+}
+
+/// Whether the main output or a `--render` output is coloured on a log curve.
+fn log_curve(args: &RenderArgs) -> bool {
+    let log = |curve| curve == DomainType::Logarithmic;
+    log(args.curve)
+        || args.log_min.is_some()
+        || args.log_max.is_some()
+        || args.render.iter().any(|spec| {
+            RenderSpec::parse(spec, &RenderOptions::default()).is_ok_and(|render| log(render.options.curve))
+        })
+}
+
+fn downsampled(args: &RenderArgs) -> bool {
+    args.output_size.is_some() || !args.thumbnail.is_empty()
+}
+
+pub const CONFLICTS: [Conflict; 15] = [
+    Conflict {
+        given: |args| args.category_colours.is_some() && !categorical(args),
+        message: "--category-colours colours categories, so it needs --value-mode categorical",
+    },
+    Conflict {
+        given: |args| args.accumulate && categorical(args),
+        message: "Categorical values are labels, so they cannot accumulate; drop -C or use --value-mode raw",
+    },
+    Conflict {
+        given: |args| args.on_conflict == ConflictPolicy::Accumulate && categorical(args),
+        message: "Categorical values cannot be accumulated, use --on-conflict warn, error or keep-max",
+    },
+    Conflict {
+        given: |args| args.expect.is_some() && categorical(args),
+        message: "Categorical values are labels, so --expect thresholds do not apply",
+    },
+    Conflict {
+        given: |args| (args.preaggregate || args.distinct) && categorical(args),
+        message: "Categorical values are labels, so --preaggregate and --distinct cannot sum or count them",
+    },
+    Conflict {
+        given: |args| args.distinct_approx == Some(DistinctApprox::Pixels) && categorical(args),
+        message: "Distinct counts per pixel cannot be coloured as categories, use --distinct-approx total",
+    },
+    Conflict {
+        given: |args| args.parse.value_from == ValueSource::Count && categorical(args),
+        message: "Counts are not category labels; use --value-mode raw to colour --value-from count",
+    },
+    Conflict {
+        given: |args| args.parse.value_from == ValueSource::Count && !args.parse.ignore_value.is_empty(),
+        message: "--ignore-value matches the value column, which --value-from count does not read",
+    },
+    Conflict {
+        given: |args| args.parse.value_from == ValueSource::Count && args.downsample == Aggregation::Mean && downsampled(args),
+        message: "The mean of pixel counts is not a count; downsample --value-from count with --downsample sum or max",
+    },
+    Conflict {
+        given: |args| {
+            let weighted = args.weight != 1.0 || args.inputs.iter().any(|input| input.weight != 1.0);
+            weighted && (categorical(args) || args.parse.value_from == ValueSource::PrefixLen)
+        },
+        message: "Weights do not apply to categorical values or prefix lengths",
+    },
+    Conflict {
+        given: |args| args.format == InputFormat::Cells && args.parse.value_from != ValueSource::Column,
+        message: "Cell rows are painted with their value, so --value-from does not apply to --format cells",
+    },
+    Conflict {
+        given: |args| args.exec.is_some() && args.format == InputFormat::RawU32v,
+        message: "--exec reads lines of text, use --format text or cells",
+    },
+    Conflict {
+        given: |args| (args.parse.since.is_some() || args.parse.until.is_some()) && args.format != InputFormat::Text,
+        message: "--since and --until need timestamps, which only --format text input has",
+    },
+    Conflict {
+        given: |args| (args.log_base.is_some() || args.log_offset.is_some()) && !log_curve(args),
+        message: "--log-base and --log-offset shape the log curve, so they need --curve log",
+    },
+    Conflict {
+        given: |args| args.validate && args.bits_per_pixel.len() > 1,
+        message: "--validate takes a single -z",
+    },
+];
+
+/// Flags that only work with a single `-z`, with whether `args` give them.
+#[allow(clippy::type_complexity)]
+pub const SINGLE_RESOLUTION: [(&str, fn(&RenderArgs) -> bool); 28] = [
+    ("--render", |args| !args.render.is_empty()),
+    ("--thumbnail", |args| !args.thumbnail.is_empty()),
+    ("--output-size", |args| args.output_size.is_some()),
+    ("--multiples", |args| args.multiples.is_some()),
+    ("--annotation-layer", |args| args.annotation_layer.is_some()),
+    ("--save-state", |args| args.save_state.is_some()),
+    ("--state-mmap", |args| args.state_mmap.is_some()),
+    ("--histogram", |args| args.histogram.is_some() || args.histogram_text),
+    ("--slash8-chart", |args| args.slash8_chart.is_some()),
+    ("--export-prefixes", |args| args.export_prefixes.is_some()),
+    ("--export-cells", |args| args.export_cells.is_some()),
+    ("--stats-json", |args| args.stats_json.is_some()),
+    ("--rejects", |args| args.rejects.is_some()),
+    ("--coverage-report", |args| args.coverage_report),
+    ("--sanity-check", |args| args.sanity_check),
+    ("--timing", |args| args.timing),
+    ("--out-dir", |args| args.out_dir.is_some()),
+    ("--expect", |args| args.expect.is_some()),
+    ("--export-profile", |args| args.export_profile.is_some() || args.export_profile_strip.is_some()),
+    ("--floor/--ceiling", |args| args.floor.is_some() || args.ceiling.is_some()),
+    ("--invert", |args| args.invert),
+    ("--rank", |args| !args.rank.is_empty()),
+    ("--category-colours", |args| args.category_colours.is_some()),
+    ("--exec", |args| args.exec.is_some()),
+    ("--preview", |args| args.preview.is_some()),
+    ("--preaggregate/--distinct", |args| args.preaggregate || args.distinct),
+    ("--threads", |args| args.threads > 1),
+    ("--metrics-listen", metrics_listen),
+];
+
+#[cfg(feature = "serve")]
+fn metrics_listen(args: &RenderArgs) -> bool {
+    args.metrics_listen.is_some()
+}
+
+#[cfg(not(feature = "serve"))]
+fn metrics_listen(_: &RenderArgs) -> bool {
+    false
+}
+
+/// The message of every combination in the tables that `args` give, in table order.
+pub fn find(args: &RenderArgs) -> Vec<String> {
+    let mut messages: Vec<String> =
+        CONFLICTS.iter().filter(|conflict| (conflict.given)(args)).map(|conflict| conflict.message.to_string()).collect();
+    if args.bits_per_pixel.len() > 1 {
+        messages.extend(
+            SINGLE_RESOLUTION
+                .iter()
+                .filter(|(_, given)| given(args))
+                .map(|(flag, _)| format!("{} cannot be combined with more than one -z", flag)),
+        );
+    }
+    messages
+}
+
+/// Fail with every combination `args` give that cannot work, one per line.
+pub fn check(args: &RenderArgs) -> anyhow::Result<()> {
+    match &find(args)[..] {
+        [] => Ok(()),
+        [message] => anyhow::bail!("{}", message),
+        messages => anyhow::bail!("{} conflicting options:\n  {}", messages.len(), messages.join("\n  ")),
+    }
+}
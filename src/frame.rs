@@ -0,0 +1,256 @@
+use crate::Heatmap;
+use crate::label::Label;
+use crate::outline::Outline;
+use crate::output;
+use crate::pipeline::RenderPipeline;
+use crate::shade::Shade;
+use crate::render::RenderOptions;
+use crate::theme::Theme;
+use crate::timing::Phase;
+use anyhow::{Result, anyhow};
+use image::RgbaImage;
+use ipnet::Ipv4Net;
+
+/// Decorations around a single rendered heatmap: a title above it, a legend below
+/// it and an optional crop to one prefix.
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+    pub title: Option<String>,
+    /// Draw a legend with this text between the end labels.
+    pub legend_label: Option<String>,
+    /// Only show the pixels covering this prefix.
+    pub crop: Option<Ipv4Net>,
+    /// Only show the painted pixels and this many pixels around them, see
+    /// [`Heatmap::painted_bounds`]. Ignored with `crop`, and when nothing was painted.
+    pub crop_to_data: Option<u32>,
+    /// Height in pixels of the title and legend text, sized to the image by default.
+    pub font_size: Option<u32>,
+    /// Height in pixels of the title, overriding `font_size`.
+    pub title_size: Option<u32>,
+    /// Prefixes shaded in a colour or pattern, before cropping.
+    pub shades: Vec<Shade>,
+    /// Borders drawn around prefixes, over any shades.
+    pub outlines: Vec<Outline>,
+    /// Text drawn over prefixes, over any outlines.
+    pub labels: Vec<Label>,
+    /// State below the map what one pixel stands for.
+    pub pixel_caption: bool,
+    /// Colours of the canvas, text and outlines without a colour. Themes other than
+    /// [`Theme::DARK`] also fill unpainted pixels with their background.
+    pub theme: Theme,
+}
+
+impl Frame {
+    /// Whether the frame leaves the rendered image unchanged.
+    pub fn is_plain(&self) -> bool {
+        self.title.is_none()
+            && self.legend_label.is_none()
+            && self.crop.is_none()
+            && self.crop_to_data.is_none()
+            && self.shades.is_empty()
+            && self.outlines.is_empty()
+            && self.labels.is_empty()
+            && !self.pixel_caption
+            && self.theme == Theme::DARK
+    }
+}
+
+impl Heatmap {
+    /// Render with `options`, then crop and decorate the image as described by `frame`.
+    /// This is [`RenderPipeline::framed`], which embedders can extend with layers.
+    pub fn render_framed(&self, options: &RenderOptions, frame: &Frame) -> Result<RgbaImage, &'static str> {
+        RenderPipeline::framed(options, frame).render(self)
+    }
+
+    /// [`Heatmap::render_framed`] as the data and the annotations drawn over it,
+    /// see [`RenderPipeline::render_layered`].
+    pub fn render_framed_layers(
+        &self,
+        options: &RenderOptions,
+        frame: &Frame,
+    ) -> Result<(RgbaImage, RgbaImage), &'static str> {
+        RenderPipeline::framed(options, frame).render_layered(self)
+    }
+
+    /// Like [`Heatmap::save_with_options`], applying `frame` before encoding.
+    pub fn save_framed(&self, filename: &str, options: &RenderOptions, frame: &Frame) -> Result<()> {
+        let metadata = self.frame_metadata(options, frame);
+        // Decorations are drawn on the whole image, so only plain renders stream
+        if frame.is_plain() && options.bands.is_none() && self.streams_encode() {
+            return self.save_streamed(filename, options, &metadata);
+        }
+        let image = self.render_framed(options, frame).map_err(|err| anyhow!(err))?;
+        self.timer
+            .time(Phase::Encode, || output::save_png(filename, &image, &metadata, &options.png))
+    }
+
+    /// Save the layers of [`Heatmap::render_framed_layers`] from one render: the
+    /// data to `data` unless it is `None`, and the annotations to `annotations`.
+    pub fn save_framed_layers(
+        &self,
+        data: Option<&str>,
+        annotations: &str,
+        options: &RenderOptions,
+        frame: &Frame,
+    ) -> Result<()> {
+        let metadata = self.frame_metadata(options, frame);
+        let (image, overlay) = self.render_framed_layers(options, frame).map_err(|err| anyhow!(err))?;
+        self.timer.time(Phase::Encode, || {
+            if let Some(data) = data {
+                output::save_png(data, &image, &metadata, &options.png)?;
+            }
+            output::save_png(annotations, &overlay, &metadata, &options.png)
+        })
+    }
+
+    fn frame_metadata(&self, options: &RenderOptions, frame: &Frame) -> Vec<(String, String)> {
+        let mut metadata = self.png_metadata(options);
+        if let Some(title) = &frame.title {
+            metadata.push(("Title".to_string(), title.clone()));
+        }
+        if let Some(net) = &frame.crop {
+            metadata.push(("crop".to_string(), net.to_string()));
+        } else if let Some((x, y, width, height)) = frame.crop_to_data.and_then(|padding| self.painted_bounds(padding)) {
+            // Where the first pixel sits on the whole map, and how much of it is shown
+            metadata.push(("crop-origin".to_string(), format!("{},{}", x, y)));
+            metadata.push(("crop-size".to_string(), format!("{}x{}", width, height)));
+        }
+        metadata
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Layout;
+    use crate::legend::Legend;
+    use crate::text::text_height;
+    use crate::{DomainType, ValueMode};
+    use image::Rgba;
+
+    fn heatmap() -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string("10.0.0.1 5\n192.168.0.0/16 3\n").unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_plain_frame_keeps_image() {
+        let hm = heatmap();
+        let options = hm.render_options();
+        assert!(Frame::default().is_plain());
+        assert_eq!(hm.render_framed(&options, &Frame::default()).unwrap(), hm.render(&options).unwrap());
+    }
+
+    #[test]
+    fn test_crop_to_prefix() {
+        let hm = heatmap();
+        let frame = Frame {
+            crop: Some("10.0.0.0/8".parse().unwrap()),
+            ..Frame::default()
+        };
+        let image = hm.render_framed(&hm.render_options(), &frame).unwrap();
+        // A /8 is 256 pixels at 16 bits per pixel
+        assert_eq!(image.dimensions(), (16, 16));
+        assert!(image.pixels().any(|pixel| pixel.0[3] == 255));
+    }
+
+    #[test]
+    fn test_crop_to_data() {
+        let hm = heatmap();
+        let frame = Frame {
+            crop_to_data: Some(1),
+            outlines: vec!["192.168.0.0/16:ff0000".parse().unwrap()],
+            ..Frame::default()
+        };
+        let (x, y, width, height) = hm.painted_bounds(1).unwrap();
+        let image = hm.render_framed(&hm.render_options(), &frame).unwrap();
+        assert_eq!(image.dimensions(), (width, height));
+        // The outline keeps its place on the data
+        let (outline_x, outline_y, _, _) = hm.prefix_rect(&"192.168.0.0/16".parse().unwrap());
+        assert_eq!(*image.get_pixel(outline_x - x, outline_y - y), Rgba([255, 0, 0, 255]));
+        let metadata = hm.frame_metadata(&hm.render_options(), &frame);
+        assert!(metadata.contains(&("crop-origin".to_string(), format!("{},{}", x, y))));
+        assert!(metadata.contains(&("crop-size".to_string(), format!("{}x{}", width, height))));
+
+        // Nothing painted keeps the whole map
+        let empty = Heatmap::new(DomainType::Linear, Some(0.0), Some(1.0), true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        assert_eq!(empty.render_framed(&empty.render_options(), &frame).unwrap().dimensions(), (256, 256));
+        assert!(!empty.frame_metadata(&empty.render_options(), &frame).iter().any(|(key, _)| key == "crop-origin"));
+    }
+
+    #[test]
+    fn test_title_and_legend_extend_canvas() {
+        let hm = heatmap();
+        let frame = Frame {
+            title: Some("scan".to_string()),
+            legend_label: Some("hosts".to_string()),
+            ..Frame::default()
+        };
+        let image = hm.render_framed(&hm.render_options(), &frame).unwrap();
+        let layout = Layout::for_panel_size(256);
+        let title_height = text_height(layout.text_scale) + layout.gap / 2;
+        let legend_height = Legend::height(layout.text_scale) + layout.gap;
+        assert_eq!(image.width(), 256 + 2 * layout.gap);
+        assert_eq!(image.height(), 256 + 2 * layout.gap + title_height + legend_height);
+    }
+
+    #[test]
+    fn test_pixel_caption_row() {
+        let hm = heatmap();
+        let frame = Frame {
+            pixel_caption: true,
+            ..Frame::default()
+        };
+        assert!(!frame.is_plain());
+        let image = hm.render_framed(&hm.render_options(), &frame).unwrap();
+        let layout = Layout::for_panel_size(256);
+        assert_eq!(image.height(), 256 + 2 * layout.gap + text_height(1) + layout.gap / 2);
+        // The caption is drawn in the row below the map, in the theme's muted colour
+        let below = 256 + 2 * layout.gap;
+        let caption = |image: &RgbaImage, colour: Rgba<u8>| {
+            image.enumerate_pixels().any(|(_, y, pixel)| y >= below && *pixel == colour)
+        };
+        assert!(caption(&image, Theme::DARK.muted));
+
+        // A light theme also fills unpainted pixels of the map
+        let light = Frame { theme: Theme::LIGHT, ..frame };
+        assert!(!Frame { theme: Theme::LIGHT, ..Frame::default() }.is_plain());
+        let image = hm.render_framed(&hm.render_options(), &light).unwrap();
+        assert!(caption(&image, Theme::LIGHT.muted));
+        assert_eq!(image.get_pixel(0, 0), &Theme::LIGHT.background);
+        assert_eq!(image.get_pixel(layout.gap + 128, layout.gap + 128), &Theme::LIGHT.background);
+    }
+
+    #[test]
+    fn test_font_sizes() {
+        let hm = heatmap();
+        let framed = |font_size, title_size| {
+            let frame = Frame {
+                title: Some("a long title for a small map".to_string()),
+                legend_label: Some("hosts".to_string()),
+                font_size,
+                title_size,
+                ..Frame::default()
+            };
+            hm.render_framed(&hm.render_options(), &frame).unwrap()
+        };
+        let layout = Layout::for_panel_size(256);
+        let default_height = framed(None, None).height();
+        // Legend and title text both grow from scale 2 to 4
+        let larger = framed(Some(32), None).height();
+        assert_eq!(larger - default_height, text_height(4) - text_height(2) + Legend::height(4) - Legend::height(2));
+        // An explicit title size only changes the title row
+        let title_only = framed(None, Some(48)).height();
+        assert_eq!(title_only - default_height, text_height(6) - text_height(layout.text_scale));
+    }
+}
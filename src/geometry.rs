@@ -0,0 +1,360 @@
+//! The size of a map, how much of the address space each of its pixels covers and
+//! which pixels a prefix covers.
+
+use crate::hilbert::hilbert_d2xy;
+use crate::{Heatmap, format_bytes};
+use ipnet::Ipv4Net;
+use std::ops::Deref;
+
+/// Dimensions of a map at a resolution of `bits_per_pixel`.
+///
+/// Pixel `d` along the Hilbert curve of order `order` covers the addresses from
+/// `d * ips_per_pixel`, so it is the `/prefix_len_per_pixel` prefix starting there.
+/// `x` grows to the right and `y` downwards from the top left corner, which holds
+/// `0.0.0.0`. An odd `bits_per_pixel` leaves a square of the next smaller even
+/// resolution's side, covering the lower half of the address space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Geometry {
+    pub width: u32,
+    pub height: u32,
+    /// Order of the Hilbert curve through the pixels, log2 of the side.
+    pub order: u32,
+    pub ips_per_pixel: u64,
+    pub prefix_len_per_pixel: u8,
+}
+
+impl Geometry {
+    /// The geometry of a map whose pixels each cover 2^`bits_per_pixel` addresses.
+    ///
+    /// Panics if `bits_per_pixel` is over 32.
+    pub const fn for_bits_per_pixel(bits_per_pixel: u8) -> Geometry {
+        assert!(bits_per_pixel <= 32, "bits_per_pixel is at most 32");
+        let prefix_len_per_pixel = 32 - bits_per_pixel;
+        let order = prefix_len_per_pixel as u32 / 2;
+        Geometry {
+            width: 1 << order,
+            height: 1 << order,
+            order,
+            ips_per_pixel: 1 << bits_per_pixel,
+            prefix_len_per_pixel,
+        }
+    }
+
+    /// Number of pixels in the map.
+    pub const fn pixels(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    /// Bytes of the cell buffer, and of an RGBA image of the map.
+    pub const fn buffer_bytes(&self) -> u64 {
+        self.pixels() * 4
+    }
+
+    /// Check that the map's buffers can be allocated and indexed on this platform. On
+    /// a 32-bit one, maps at a `bits_per_pixel` of 2 or finer are too large.
+    pub fn check_platform(&self) -> Result<(), String> {
+        self.check_pointer_width(usize::BITS)
+    }
+
+    fn check_pointer_width(&self, bits: u32) -> Result<(), String> {
+        // Allocations are limited to isize::MAX bytes
+        let limit = (1u64 << (bits - 1)) - 1;
+        if self.buffer_bytes() <= limit {
+            return Ok(());
+        }
+        Err(format!(
+            "Image too large for this platform: a {}x{} map needs {} per buffer, more than a {}-bit platform can \
+             allocate; use a coarser -z",
+            self.width,
+            self.height,
+            format_bytes(self.buffer_bytes()),
+            bits
+        ))
+    }
+}
+
+/// A rectangle of whole pixels, `x` and `y` being its top left corner on the map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PixelRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PixelRect {
+    pub const fn area(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    pub const fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && y >= self.y && x - self.x < self.width && y - self.y < self.height
+    }
+
+    /// The pixels in both rectangles, or `None` if they do not overlap.
+    pub fn intersect(&self, other: &PixelRect) -> Option<PixelRect> {
+        let (left, top) = (self.x.max(other.x), self.y.max(other.y));
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        (left < right && top < bottom).then(|| PixelRect { x: left, y: top, width: right - left, height: bottom - top })
+    }
+
+    /// The smallest rectangle holding both.
+    pub fn union(&self, other: &PixelRect) -> PixelRect {
+        let (left, top) = (self.x.min(other.x), self.y.min(other.y));
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        PixelRect { x: left, y: top, width: right - left, height: bottom - top }
+    }
+}
+
+/// The pixels covering a prefix, from [`blocks_for_prefix`]: none, one square, or
+/// the two squares of an odd number of pixels, which share a whole side.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrefixBlocks {
+    rects: [PixelRect; 2],
+    len: usize,
+    /// The share of each pixel's addresses that are in the prefix: 1 unless the
+    /// prefix is smaller than a pixel.
+    pub coverage: f64,
+}
+
+impl PrefixBlocks {
+    fn push(&mut self, rect: PixelRect) {
+        self.rects[self.len] = rect;
+        self.len += 1;
+    }
+
+    /// The number of pixels covered.
+    pub fn area(&self) -> u64 {
+        self.iter().map(PixelRect::area).sum()
+    }
+
+    /// The rectangle around every block, or `None` if the prefix is off the map.
+    pub fn bounds(&self) -> Option<PixelRect> {
+        self.iter().copied().reduce(|bounds, rect| bounds.union(&rect))
+    }
+}
+
+impl Deref for PrefixBlocks {
+    type Target = [PixelRect];
+
+    fn deref(&self) -> &[PixelRect] {
+        &self.rects[..self.len]
+    }
+}
+
+/// The pixels of a map with `geometry` that cover `net`, as disjoint rectangles.
+///
+/// A prefix of a power of four pixels fills one aligned square of the curve and one
+/// of twice that fills two, side by side. A prefix smaller than a pixel gives its
+/// single pixel with the share of it that the prefix covers. Pixels off the map,
+/// the upper half of the address space at an odd resolution, are left out, and with
+/// a `view` so is everything outside the rectangle around the view's own blocks.
+pub fn blocks_for_prefix(net: &Ipv4Net, geometry: &Geometry, view: Option<&Ipv4Net>) -> PrefixBlocks {
+    let mut blocks = PrefixBlocks { rects: [PixelRect::default(); 2], len: 0, coverage: 1.0 };
+    let pixel_len = geometry.prefix_len_per_pixel;
+    let first = (u32::from(net.network()) as u64) >> (32 - pixel_len as u32);
+    let pixels = geometry.pixels();
+    if first >= pixels {
+        return blocks;
+    }
+    if net.prefix_len() >= pixel_len {
+        blocks.coverage = 1.0 / (1u64 << (net.prefix_len() - pixel_len)) as f64;
+    }
+    // The whole space on an odd map is twice its pixels, so it is clipped to them
+    let count = (1u64 << pixel_len.saturating_sub(net.prefix_len())).min(pixels - first);
+    let (square, squares) = match count.trailing_zeros().is_multiple_of(2) {
+        true => (count, 1),
+        false => (count / 2, 2),
+    };
+    let side = 1u32 << (square.trailing_zeros() / 2);
+    for start in (0..squares).map(|index| first + index * square) {
+        let (x, y) = hilbert_d2xy(start, geometry.order).expect("the block starts on the map");
+        blocks.push(PixelRect { x: x / side * side, y: y / side * side, width: side, height: side });
+    }
+    if let Some(view) = view {
+        let window = blocks_for_prefix(view, geometry, None).bounds();
+        let clipped = blocks;
+        blocks.len = 0;
+        for rect in clipped.iter().filter_map(|rect| window?.intersect(rect)) {
+            blocks.push(rect);
+        }
+    }
+    blocks
+}
+
+impl Heatmap {
+    /// The geometry of the map, see [`Geometry::for_bits_per_pixel`].
+    pub fn geometry(&self) -> Geometry {
+        Geometry::for_bits_per_pixel(self.bits_per_pixel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hilbert::{hilbert_d2xy, hilbert_xy2d};
+    use std::collections::HashSet;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_every_resolution() {
+        for bits_per_pixel in 0..=32u8 {
+            let geometry = Geometry::for_bits_per_pixel(bits_per_pixel);
+            assert_eq!(geometry.width, geometry.height);
+            assert_eq!(geometry.width, 1 << geometry.order);
+            assert_eq!(geometry.prefix_len_per_pixel + bits_per_pixel, 32);
+            assert_eq!(geometry.ips_per_pixel, 1 << bits_per_pixel);
+            // Even resolutions cover the address space exactly, odd ones half of it
+            let covered = geometry.pixels() * geometry.ips_per_pixel;
+            let expected = if bits_per_pixel % 2 == 0 { 1u64 << 32 } else { 1u64 << 31 };
+            assert_eq!(covered, expected, "bits_per_pixel={}", bits_per_pixel);
+            // The last pixel of the curve is in the map
+            let last = geometry.pixels() - 1;
+            let (x, y) = hilbert_d2xy(last, geometry.order).unwrap();
+            assert!(x < geometry.width && y < geometry.height);
+            assert_eq!(hilbert_xy2d(x, y, geometry.order), Some(last));
+            assert_eq!(hilbert_d2xy(last + 1, geometry.order), None);
+        }
+    }
+
+    #[test]
+    fn test_known_sizes() {
+        let sizes: Vec<(u32, u32, u64, u8)> = [0, 8, 12, 16, 24, 31, 32]
+            .iter()
+            .map(|&bits| Geometry::for_bits_per_pixel(bits))
+            .map(|geometry| (geometry.width, geometry.order, geometry.ips_per_pixel, geometry.prefix_len_per_pixel))
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                (65536, 16, 1, 32),
+                (4096, 12, 256, 24),
+                (1024, 10, 4096, 20),
+                (256, 8, 65536, 16),
+                (16, 4, 1 << 24, 8),
+                (1, 0, 1 << 31, 1),
+                (1, 0, 1 << 32, 0),
+            ]
+        );
+        let odd = Geometry { width: 2048, height: 2048, order: 11, ips_per_pixel: 512, prefix_len_per_pixel: 23 };
+        assert_eq!(Geometry::for_bits_per_pixel(9), odd);
+    }
+
+    #[test]
+    fn test_platform_limits() {
+        for bits_per_pixel in 0..=32u8 {
+            let geometry = Geometry::for_bits_per_pixel(bits_per_pixel);
+            assert!(geometry.check_pointer_width(64).is_ok());
+            // From 2^30 pixels, 4 GiB per buffer, maps are too large for 32 bits
+            assert_eq!(geometry.check_pointer_width(32).is_ok(), bits_per_pixel > 2, "bits_per_pixel={}", bits_per_pixel);
+        }
+        assert_eq!(
+            Geometry::for_bits_per_pixel(2).check_pointer_width(32).unwrap_err(),
+            "Image too large for this platform: a 32768x32768 map needs 4.0 GiB per buffer, more than a 32-bit \
+             platform can allocate; use a coarser -z"
+        );
+    }
+
+    /// The pixels of `net` found address block by address block, in view.
+    fn brute_force(net: &Ipv4Net, geometry: &Geometry, view: Option<&Ipv4Net>) -> HashSet<(u32, u32)> {
+        let bits = 32 - geometry.prefix_len_per_pixel as u32;
+        let pixels = |net: &Ipv4Net| -> HashSet<(u32, u32)> {
+            let (first, last) = (u32::from(net.network()) as u64 >> bits, u32::from(net.broadcast()) as u64 >> bits);
+            (first..=last).filter_map(|d| hilbert_d2xy(d, geometry.order)).collect()
+        };
+        let mut covered = pixels(net);
+        if let Some(view) = view {
+            let window = pixels(view);
+            let (min_x, max_x) = (window.iter().map(|p| p.0).min(), window.iter().map(|p| p.0).max());
+            let (min_y, max_y) = (window.iter().map(|p| p.1).min(), window.iter().map(|p| p.1).max());
+            covered.retain(|&(x, y)| {
+                min_x.is_some_and(|min| x >= min)
+                    && max_x.is_some_and(|max| x <= max)
+                    && min_y.is_some_and(|min| y >= min)
+                    && max_y.is_some_and(|max| y <= max)
+            });
+        }
+        covered
+    }
+
+    /// The blocks are disjoint and hold exactly the pixels found by brute force.
+    fn check(net: &Ipv4Net, geometry: &Geometry, view: Option<&Ipv4Net>) {
+        let blocks = blocks_for_prefix(net, geometry, view);
+        let expected = brute_force(net, geometry, view);
+        let mut found = HashSet::new();
+        for rect in blocks.iter() {
+            for (x, y) in (rect.x..rect.x + rect.width).flat_map(|x| (rect.y..rect.y + rect.height).map(move |y| (x, y))) {
+                assert!(found.insert((x, y)), "{} in view {:?} covers ({}, {}) twice", net, view, x, y);
+            }
+        }
+        assert_eq!(blocks.area(), found.len() as u64);
+        assert_eq!(found, expected, "{} in view {:?} at /{}", net, view, geometry.prefix_len_per_pixel);
+        assert!(blocks.len() <= 2);
+    }
+
+    #[test]
+    fn test_blocks_match_brute_force() {
+        for bits_per_pixel in 22..=32u8 {
+            let geometry = Geometry::for_bits_per_pixel(bits_per_pixel);
+            for prefix_len in 0..=(geometry.prefix_len_per_pixel + 2).min(32) {
+                let step = 1u64 << (32 - prefix_len as u32);
+                for network in (0..1u64 << 32).step_by(step as usize).take(1 << 12) {
+                    let net = Ipv4Net::new(Ipv4Addr::from(network as u32), prefix_len).unwrap();
+                    check(&net, &geometry, None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_blocks_in_a_view() {
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = move || {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            state >> 32
+        };
+        for bits_per_pixel in [20, 21, 24, 25] {
+            let geometry = Geometry::for_bits_per_pixel(bits_per_pixel);
+            for _ in 0..200 {
+                let net = Ipv4Net::new(Ipv4Addr::from(next() as u32), (next() % 33) as u8).unwrap().trunc();
+                let view = Ipv4Net::new(Ipv4Addr::from(next() as u32), (next() % 17) as u8).unwrap().trunc();
+                check(&net, &geometry, None);
+                check(&net, &geometry, Some(&view));
+            }
+        }
+    }
+
+    #[test]
+    fn test_blocks_of_small_large_and_odd_prefixes() {
+        let geometry = Geometry::for_bits_per_pixel(16);
+        let net = |s: &str| s.parse::<Ipv4Net>().unwrap();
+        let blocks = blocks_for_prefix(&net("10.0.0.0/8"), &geometry, None);
+        assert_eq!((blocks.len(), blocks.area(), blocks.coverage), (1, 256, 1.0));
+        // An odd length gives two squares sharing a side
+        let blocks = blocks_for_prefix(&net("10.0.0.0/7"), &geometry, None);
+        assert_eq!((blocks.len(), blocks.area()), (2, 512));
+        assert_eq!(blocks.bounds().unwrap().area(), 512);
+        // Smaller than a pixel: that pixel, a quarter covered
+        let blocks = blocks_for_prefix(&net("10.0.64.0/18"), &geometry, None);
+        assert_eq!((blocks.len(), blocks.area(), blocks.coverage), (1, 1, 0.25));
+        let host = blocks_for_prefix(&net("10.0.0.1/32"), &geometry, None);
+        assert_eq!((host[0], host.coverage), (blocks_for_prefix(&net("10.0.0.0/16"), &geometry, None)[0], 1.0 / 65536.0));
+        // The whole space is clipped to the square of an odd map and a view
+        let odd = Geometry::for_bits_per_pixel(17);
+        let whole = blocks_for_prefix(&net("0.0.0.0/0"), &odd, None);
+        assert_eq!(&whole[..], &[PixelRect { x: 0, y: 0, width: 128, height: 128 }]);
+        assert!(blocks_for_prefix(&net("128.0.0.0/1"), &odd, None).is_empty());
+        let view = net("0.0.0.0/2");
+        let focused = blocks_for_prefix(&net("0.0.0.0/0"), &geometry, Some(&view));
+        assert_eq!(focused.bounds(), blocks_for_prefix(&view, &geometry, None).bounds());
+        assert!(blocks_for_prefix(&net("10.0.0.0/8"), &geometry, Some(&net("192.0.0.0/8"))).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 32")]
+    fn test_too_coarse() {
+        Geometry::for_bits_per_pixel(33);
+    }
+}
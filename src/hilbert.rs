@@ -1,6 +1,13 @@
+/// Largest order the curve functions accept: a 2^31-pixel square, whose distances
+/// still fit in a `u64`. IPv4 maps need at most order 16.
+pub const MAX_HILBERT_ORDER: u32 = 31;
+
+/// The pixel at distance `d` along the curve filling a 2^`order`-pixel square, or
+/// `None` when `d` is past the end of the curve or `order` is above
+/// [`MAX_HILBERT_ORDER`].
 pub fn hilbert_d2xy(d: u64, order: u32) -> Option<(u32, u32)> {
-    if order == 0 {
-        return Some((0, 0));
+    if order > MAX_HILBERT_ORDER || d >> (2 * order) != 0 {
+        return None;
     }
 
     let n = 1u32 << order;
@@ -20,9 +27,7 @@ pub fn hilbert_d2xy(d: u64, order: u32) -> Option<(u32, u32)> {
             }
 
             // Swap x and y
-            let temp = x;
-            x = y;
-            y = temp;
+            std::mem::swap(&mut x, &mut y);
         }
 
         x += s * rx as u32;
@@ -34,10 +39,136 @@ pub fn hilbert_d2xy(d: u64, order: u32) -> Option<(u32, u32)> {
     Some((x, y))
 }
 
+/// Inverse of [`hilbert_d2xy`]: the curve distance of pixel (`x`, `y`), or `None`
+/// when the pixel is outside the square or `order` is above [`MAX_HILBERT_ORDER`].
+pub fn hilbert_xy2d(x: u32, y: u32, order: u32) -> Option<u64> {
+    if order > MAX_HILBERT_ORDER || (x | y) >> order != 0 {
+        return None;
+    }
+    let n = 1u64 << order;
+    let mut x = x as u64;
+    let mut y = y as u64;
+    let mut d = 0u64;
+
+    let mut s = n >> 1;
+    while s > 0 {
+        let rx = u64::from(x & s > 0);
+        let ry = u64::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+
+            // Swap x and y
+            std::mem::swap(&mut x, &mut y);
+        }
+        s >>= 1;
+    }
+
+    Some(d)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Distances spread over the curve of `order`: both ends and `count` more from a
+    /// fixed linear congruential sequence.
+    fn sampled_distances(order: u32, count: usize) -> Vec<u64> {
+        let length = 1u64 << (2 * order);
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15 ^ order as u64;
+        let mut distances = vec![0, length - 1];
+        distances.extend((0..count).map(|_| {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (state >> 1) % length
+        }));
+        distances
+    }
+
+    fn are_neighbours((x0, y0): (u32, u32), (x1, y1): (u32, u32)) -> bool {
+        x0.abs_diff(x1) + y0.abs_diff(y1) == 1
+    }
+
+    #[test]
+    fn test_xy2d_inverts_d2xy() {
+        for order in 1..=5 {
+            for d in 0..(1u64 << (2 * order)) {
+                let (x, y) = hilbert_d2xy(d, order).unwrap();
+                assert_eq!(hilbert_xy2d(x, y, order), Some(d), "order {} d {}", order, d);
+            }
+        }
+    }
+
+    #[test]
+    fn test_small_orders_are_exhaustive_bijections() {
+        for order in 0..=6 {
+            let side = 1u32 << order;
+            let mut seen = vec![false; (side * side) as usize];
+            let mut previous = None;
+            for d in 0..(1u64 << (2 * order)) {
+                let pixel = hilbert_d2xy(d, order).unwrap();
+                assert!(pixel.0 < side && pixel.1 < side, "order {} d {} -> {:?}", order, d, pixel);
+                let index = (pixel.1 * side + pixel.0) as usize;
+                assert!(!seen[index], "order {}: {:?} is visited twice", order, pixel);
+                seen[index] = true;
+                if let Some(previous) = previous
+                    && !are_neighbours(previous, pixel)
+                {
+                    panic!("order {}: d {} jumps from {:?} to {:?}", order, d, previous, pixel);
+                }
+                previous = Some(pixel);
+            }
+            assert!(seen.iter().all(|&seen| seen), "order {} misses pixels", order);
+        }
+    }
+
+    #[test]
+    fn test_consecutive_distances_are_neighbours() {
+        let order = 12;
+        for start in sampled_distances(order, 200) {
+            let start = start.min((1 << (2 * order)) - 1000);
+            let mut previous = hilbert_d2xy(start, order).unwrap();
+            for d in start + 1..start + 1000 {
+                let pixel = hilbert_d2xy(d, order).unwrap();
+                assert!(are_neighbours(previous, pixel), "d {} jumps from {:?} to {:?}", d, previous, pixel);
+                previous = pixel;
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trips_at_every_order() {
+        for order in 1..=MAX_HILBERT_ORDER {
+            let side = 1u64 << order;
+            for d in sampled_distances(order, 2000) {
+                let (x, y) = hilbert_d2xy(d, order).unwrap();
+                assert!((x as u64) < side && (y as u64) < side, "order {} d {}", order, d);
+                assert_eq!(hilbert_xy2d(x, y, order), Some(d), "order {} d {}", order, d);
+            }
+        }
+    }
+
+    #[test]
+    fn test_out_of_range_is_rejected() {
+        assert_eq!(hilbert_d2xy(0, 0), Some((0, 0)));
+        assert_eq!(hilbert_d2xy(1, 0), None);
+        for order in [1, 8, 16, MAX_HILBERT_ORDER] {
+            let length = 1u64 << (2 * order);
+            assert!(hilbert_d2xy(length - 1, order).is_some());
+            assert_eq!(hilbert_d2xy(length, order), None, "order {}", order);
+            assert_eq!(hilbert_d2xy(u64::MAX, order), None);
+            let side = 1u32 << order;
+            assert_eq!(hilbert_xy2d(side, 0, order), None);
+            assert_eq!(hilbert_xy2d(0, side, order), None);
+        }
+        assert_eq!(hilbert_d2xy(0, MAX_HILBERT_ORDER + 1), None);
+        assert_eq!(hilbert_xy2d(0, 0, MAX_HILBERT_ORDER + 1), None);
+        assert_eq!(hilbert_xy2d(0, 0, 64), None);
+    }
+
     #[test]
     fn test_hilbert_quadrant_mapping() {
         // Test the quadrant mapping for IPv4 space
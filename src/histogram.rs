@@ -0,0 +1,230 @@
+use crate::Heatmap;
+use crate::legend::format_value;
+use crate::percentile::SortedValues;
+use crate::scale::DomainType;
+use crate::strings::Strings;
+use crate::text::{draw_text, fill_rect, text_height, text_width};
+use image::{Rgba, RgbaImage};
+use std::fmt::Write;
+
+/// Percentiles reported alongside the histogram.
+pub const HISTOGRAM_PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+pub(crate) const CHART_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+pub(crate) const CHART_FOREGROUND: Rgba<u8> = Rgba([0, 0, 0, 255]);
+pub(crate) const CHART_BAR: Rgba<u8> = Rgba([60, 80, 160, 255]);
+pub(crate) const CHART_PADDING: u32 = 8;
+pub(crate) const CHART_TEXT_SCALE: u32 = 2;
+/// Width of the longest bar in the text histogram.
+const TEXT_BAR_WIDTH: u64 = 50;
+
+/// One histogram bin covering `lower..upper` (the last bin includes `upper`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramBin {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: u64,
+}
+
+/// Distribution of the non-zero cell values of a heatmap.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    pub bins: Vec<HistogramBin>,
+    pub log_scale: bool,
+    /// `(p, value)` for each of [`HISTOGRAM_PERCENTILES`].
+    pub percentiles: Vec<(f64, i32)>,
+    pub max: Option<i32>,
+    /// Non-zero cells that did not fit the bins (non-positive values on a log axis).
+    pub skipped: u64,
+}
+
+impl Histogram {
+    /// Bin `values` into `bins` bins, log-spaced when `log_scale` is set.
+    pub fn new(values: &SortedValues, bins: usize, log_scale: bool) -> Self {
+        let bins = bins.max(1);
+        let candidates = match log_scale {
+            true => &values.values()[values.values().partition_point(|&v| v <= 0)..],
+            false => values.values(),
+        };
+        let skipped = (values.len() - candidates.len()) as u64;
+        let percentiles = HISTOGRAM_PERCENTILES
+            .iter()
+            .filter_map(|&p| values.percentile(p).map(|value| (p, value)))
+            .collect();
+
+        let (Some(&low), Some(&high)) = (candidates.first(), candidates.last()) else {
+            return Self {
+                bins: Vec::new(),
+                log_scale,
+                percentiles,
+                max: None,
+                skipped,
+            };
+        };
+        let (low, high) = (low as f64, high as f64);
+        let position = |value: f64| match log_scale {
+            true if high > low => (value / low).ln() / (high / low).ln(),
+            false if high > low => (value - low) / (high - low),
+            _ => 0.0,
+        };
+        let edge = |i: usize| {
+            let t = i as f64 / bins as f64;
+            match log_scale {
+                true => low * (high / low).powf(t),
+                false => low + (high - low) * t,
+            }
+        };
+
+        let mut histogram: Vec<HistogramBin> = (0..bins)
+            .map(|i| HistogramBin {
+                lower: edge(i),
+                upper: edge(i + 1),
+                count: 0,
+            })
+            .collect();
+        for &value in candidates {
+            let index = ((position(value as f64) * bins as f64) as usize).min(bins - 1);
+            histogram[index].count += 1;
+        }
+
+        Self {
+            bins: histogram,
+            log_scale,
+            percentiles,
+            max: values.values().last().copied(),
+            skipped,
+        }
+    }
+
+    /// Fixed-width ASCII histogram followed by the percentiles.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let largest = self.bins.iter().map(|bin| bin.count).max().unwrap_or(0).max(1);
+        for bin in &self.bins {
+            let bar = "#".repeat((bin.count * TEXT_BAR_WIDTH).div_ceil(largest) as usize);
+            let _ = writeln!(
+                text,
+                "{:>8} - {:<8} {:>10} {}",
+                format_value(bin.lower),
+                format_value(bin.upper),
+                bin.count,
+                bar
+            );
+        }
+        if self.skipped > 0 {
+            let _ = writeln!(text, "{} non-positive cells not shown on the log axis", self.skipped);
+        }
+        for (p, value) in &self.percentiles {
+            let _ = writeln!(text, "p{:<5} {}", p, value);
+        }
+        if let Some(max) = self.max {
+            let _ = writeln!(text, "max    {}", max);
+        }
+        text
+    }
+
+    /// A bar chart of the bins with labelled axis ends, titled in the words of `strings`.
+    pub fn render(&self, width: u32, height: u32, strings: &Strings) -> RgbaImage {
+        let mut image = RgbaImage::from_pixel(width, height, CHART_BACKGROUND);
+        let label_height = text_height(CHART_TEXT_SCALE);
+        let plot_left = CHART_PADDING;
+        let plot_top = CHART_PADDING * 2 + label_height;
+        let plot_width = width.saturating_sub(CHART_PADDING * 2);
+        let plot_height = height.saturating_sub(plot_top + CHART_PADDING * 2 + label_height);
+
+        let largest = self.bins.iter().map(|bin| bin.count).max().unwrap_or(0);
+        let curve = strings.curve(if self.log_scale { DomainType::Logarithmic } else { DomainType::Linear });
+        let title = strings.format("chart.histogram", &[("curve", &curve), ("max", &largest)]);
+        draw_text(&mut image, CHART_PADDING as i64, CHART_PADDING as i64, &title, CHART_TEXT_SCALE, CHART_FOREGROUND);
+
+        if largest > 0 && plot_width > 0 {
+            let bins = self.bins.len() as u32;
+            for (i, bin) in self.bins.iter().enumerate() {
+                let left = plot_left + i as u32 * plot_width / bins;
+                let right = plot_left + (i as u32 + 1) * plot_width / bins;
+                let bar_height = (bin.count as f64 / largest as f64 * plot_height as f64).round() as u32;
+                // Leave a one pixel gap between bars when there is room
+                let bar_width = if right - left > 2 { right - left - 1 } else { right - left };
+                fill_rect(
+                    &mut image,
+                    left as i64,
+                    (plot_top + plot_height - bar_height) as i64,
+                    bar_width,
+                    bar_height,
+                    CHART_BAR,
+                );
+            }
+        }
+
+        // Axis line and labels for both ends of the value range
+        fill_rect(&mut image, plot_left as i64, (plot_top + plot_height) as i64, plot_width, 1, CHART_FOREGROUND);
+        if let (Some(first), Some(last)) = (self.bins.first(), self.bins.last()) {
+            let label_top = (plot_top + plot_height + CHART_PADDING) as i64;
+            let low = format_value(first.lower);
+            let high = format_value(last.upper);
+            draw_text(&mut image, plot_left as i64, label_top, &low, CHART_TEXT_SCALE, CHART_FOREGROUND);
+            let high_left = (plot_left + plot_width) as i64 - text_width(&high, CHART_TEXT_SCALE) as i64;
+            draw_text(&mut image, high_left, label_top, &high, CHART_TEXT_SCALE, CHART_FOREGROUND);
+        }
+        image
+    }
+}
+
+impl Heatmap {
+    /// Histogram of the non-zero cell values.
+    pub fn histogram(&self, bins: usize, log_scale: bool) -> Histogram {
+        Histogram::new(&self.sorted_values(), bins, log_scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_bins_are_geometric() {
+        let values = SortedValues::new(vec![1, 20, 20, 200, 1000]);
+        let histogram = Histogram::new(&values, 3, true);
+        let edges: Vec<f64> = histogram.bins.iter().map(|bin| bin.lower.round()).collect();
+        assert_eq!(edges, vec![1.0, 10.0, 100.0]);
+        let counts: Vec<u64> = histogram.bins.iter().map(|bin| bin.count).collect();
+        assert_eq!(counts, vec![1, 2, 2]);
+        assert_eq!(histogram.max, Some(1000));
+    }
+
+    #[test]
+    fn test_linear_bins_and_negative_values() {
+        let values = SortedValues::new(vec![-5, 5, 15]);
+        let linear = Histogram::new(&values, 2, false);
+        let counts: Vec<u64> = linear.bins.iter().map(|bin| bin.count).collect();
+        assert_eq!(counts, vec![1, 2]);
+
+        let log = Histogram::new(&values, 2, true);
+        assert_eq!(log.skipped, 1);
+        assert_eq!(log.bins.iter().map(|bin| bin.count).sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn test_single_value_and_empty_input() {
+        let single = Histogram::new(&SortedValues::new(vec![7, 7]), 4, true);
+        assert_eq!(single.bins[0].count, 2);
+        assert!(Histogram::new(&SortedValues::new(vec![]), 4, true).bins.is_empty());
+    }
+
+    #[test]
+    fn test_text_lists_percentiles() {
+        let values = SortedValues::new((1..=1000).collect());
+        let text = Histogram::new(&values, 10, true).to_text();
+        assert!(text.contains("p50    500"));
+        assert!(text.contains("p99.9  999"));
+        assert!(text.contains("max    1000"));
+    }
+
+    #[test]
+    fn test_render_draws_bars() {
+        let values = SortedValues::new(vec![1, 2, 2, 3]);
+        let image = Histogram::new(&values, 2, false).render(200, 120, &Strings::default());
+        assert_eq!(image.dimensions(), (200, 120));
+        assert!(image.pixels().any(|pixel| *pixel == CHART_BAR));
+    }
+}
@@ -0,0 +1,447 @@
+//! `http://` inputs: a small HTTP/1.1 client streaming a response body into the
+//! parser, following redirects within a time and size budget.
+//!
+//! This build has no TLS, so `https://` URLs are refused with a hint rather than
+//! fetched in the clear.
+
+use crate::limits::{Deadline, Limited};
+use anyhow::{Context, Result, bail};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Longest status line plus headers accepted.
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+
+/// Limits on fetching one URL. No request is retried.
+#[derive(Clone, Debug)]
+pub struct FetchOptions {
+    /// Time from connecting until the body has been read, across redirects.
+    pub timeout: Duration,
+    /// Largest response body, in bytes as sent and again after any gzip decoding.
+    pub max_bytes: u64,
+    pub max_redirects: usize,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(300),
+            max_bytes: 4 * 1024 * 1024 * 1024,
+            max_redirects: 5,
+        }
+    }
+}
+
+/// An `http://` URL split into what a request needs.
+#[derive(Debug, PartialEq)]
+struct Url {
+    /// Host and port as written, for the `Host` header.
+    authority: String,
+    host: String,
+    port: u16,
+    /// Path and query, at least `/`.
+    target: String,
+}
+
+fn parse_url(url: &str) -> Result<Url, String> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return Err(format!("Not a URL: {}", url));
+    };
+    match scheme.to_ascii_lowercase().as_str() {
+        "http" => {}
+        "https" => {
+            return Err(format!(
+                "Cannot fetch {}: https needs TLS, which this build does not include. \
+                 Download it first, e.g. curl -L {} | ip-heatmap",
+                url, url
+            ));
+        }
+        _ => return Err(format!("Unsupported URL scheme {}: {}. Use http://", scheme, url)),
+    }
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (authority, target) = match rest.find(['/', '?']) {
+        Some(start) if rest[start..].starts_with('?') => (&rest[..start], format!("/{}", &rest[start..])),
+        Some(start) => (&rest[..start], rest[start..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, after) = bracketed.split_once(']').ok_or_else(|| format!("Invalid host in URL: {}", url))?;
+            (host, after.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() || host.contains('@') {
+        return Err(format!("Invalid host in URL: {}", url));
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| format!("Invalid port in URL: {}", url))?,
+        None => 80,
+    };
+    Ok(Url { authority: authority.to_string(), host: host.to_string(), port, target })
+}
+
+/// The URL a `Location` header points to, relative to the URL that answered with it.
+fn resolve_location(base: &Url, location: &str) -> String {
+    if location.contains("://") {
+        location.to_string()
+    } else if let Some(rest) = location.strip_prefix("//") {
+        format!("http://{}", rest)
+    } else if location.starts_with('/') {
+        format!("http://{}{}", base.authority, location)
+    } else {
+        let path = base.target.split('?').next().unwrap_or_default();
+        let directory = &path[..path.rfind('/').map_or(0, |slash| slash + 1)];
+        format!("http://{}{}{}", base.authority, directory, location)
+    }
+}
+
+/// The status line and headers of a response.
+#[derive(Debug, PartialEq)]
+struct ResponseHead {
+    status: u16,
+    reason: String,
+    /// Header names are lower case.
+    headers: HashMap<String, String>,
+}
+
+fn read_head<R: BufRead>(reader: &mut R) -> Result<ResponseHead> {
+    let mut limited = reader.take(MAX_HEAD_BYTES);
+    let mut next_line = || -> Result<String> {
+        let mut line = String::new();
+        limited.read_line(&mut line).context("Failed to read the response")?;
+        if !line.ends_with('\n') {
+            bail!("Response head is incomplete or too large");
+        }
+        Ok(line.trim_end().to_string())
+    };
+    let status_line = next_line()?;
+    let mut parts = status_line.splitn(3, ' ');
+    let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+        bail!("Invalid status line: {}", status_line);
+    };
+    let status: u16 = match status.parse() {
+        Ok(status) if version.starts_with("HTTP/1.") => status,
+        _ => bail!("Invalid status line: {}", status_line),
+    };
+    let mut headers = HashMap::new();
+    loop {
+        let line = next_line()?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').with_context(|| format!("Invalid header: {}", line))?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+    Ok(ResponseHead { status, reason: parts.next().unwrap_or_default().to_string(), headers })
+}
+
+/// Connect to `url` and send a GET request, giving up at `deadline`.
+fn request(url: &Url, deadline: Instant) -> Result<BufReader<Deadline<TcpStream>>> {
+    let remaining = || deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1));
+    let addresses = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {}", url.host))?;
+    let mut last_error = None;
+    let mut connected = None;
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, remaining()) {
+            Ok(stream) => {
+                connected = Some(stream);
+                break;
+            }
+            Err(err) => last_error = Some(err),
+        }
+    }
+    let stream = match (connected, last_error) {
+        (Some(stream), _) => stream,
+        (None, Some(err)) => return Err(err).with_context(|| format!("Failed to connect to {}", url.authority)),
+        (None, None) => bail!("{} has no addresses", url.host),
+    };
+    stream.set_read_timeout(Some(remaining()))?;
+    stream.set_write_timeout(Some(remaining()))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: ip-heatmap/{}\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+        url.target,
+        url.authority,
+        env!("CARGO_PKG_VERSION")
+    );
+    (&stream)
+        .write_all(request.as_bytes())
+        .with_context(|| format!("Failed to send the request to {}", url.authority))?;
+    Ok(BufReader::new(Deadline { inner: stream, deadline }))
+}
+
+/// Fetch `url` and return its body, gunzipped when it is sent with
+/// `Content-Encoding: gzip` or its path ends in `.gz`.
+///
+/// The body is streamed: errors after the response head, such as a timeout or a
+/// body beyond [`FetchOptions::max_bytes`], surface from the reader.
+pub fn fetch(url: &str, options: &FetchOptions) -> Result<Box<dyn BufRead>> {
+    let deadline = Instant::now() + options.timeout;
+    let mut current = url.to_string();
+    let mut redirects = 0;
+    let (parsed, reader, head) = loop {
+        let parsed = parse_url(&current).map_err(anyhow::Error::msg)?;
+        let mut reader = request(&parsed, deadline)?;
+        let head = read_head(&mut reader).with_context(|| format!("Failed to fetch {}", current))?;
+        match (head.status, head.headers.get("location")) {
+            (301 | 302 | 303 | 307 | 308, Some(location)) => {
+                redirects += 1;
+                if redirects > options.max_redirects {
+                    bail!("Failed to fetch {}: more than {} redirects", url, options.max_redirects);
+                }
+                let next = resolve_location(&parsed, location);
+                log::debug!("{} redirects to {}", current, next);
+                current = next;
+            }
+            (200..=299, _) => break (parsed, reader, head),
+            (status, _) => bail!("Failed to fetch {}: HTTP {} {}", current, status, head.reason),
+        }
+    };
+    let chunked = head.headers.get("transfer-encoding").is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    let body: Box<dyn Read> = if chunked {
+        Box::new(Chunked { inner: reader, remaining: 0, done: false })
+    } else if let Some(length) = head.headers.get("content-length") {
+        let Ok(length) = length.parse::<u64>() else {
+            bail!("Failed to fetch {}: invalid Content-Length {}", current, length);
+        };
+        if length > options.max_bytes {
+            bail!("Failed to fetch {}: its {} bytes are more than the limit of {}", current, length, options.max_bytes);
+        }
+        Box::new(Exact { inner: reader, remaining: length })
+    } else {
+        Box::new(reader)
+    };
+    let body = Limited { inner: body, remaining: options.max_bytes };
+    let path = parsed.target.split('?').next().unwrap_or_default();
+    let body: Box<dyn Read> = match head.headers.get("content-encoding").map(|value| value.to_ascii_lowercase()) {
+        Some(encoding) if encoding == "gzip" || encoding == "x-gzip" => {
+            Box::new(Limited { inner: flate2::read::MultiGzDecoder::new(body), remaining: options.max_bytes })
+        }
+        Some(encoding) if encoding != "identity" => {
+            bail!("Failed to fetch {}: unsupported Content-Encoding {}", current, encoding)
+        }
+        _ if path.ends_with(".gz") => {
+            Box::new(Limited { inner: flate2::read::MultiGzDecoder::new(body), remaining: options.max_bytes })
+        }
+        _ => Box::new(body),
+    };
+    Ok(Box::new(BufReader::new(Labelled { inner: body, url: current, options: options.clone() })))
+}
+
+/// A body of `Content-Length` bytes, which fails if the connection closes early.
+struct Exact<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for Exact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let wanted = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..wanted])?;
+        if read == 0 {
+            let message = format!("the response ended {} bytes early", self.remaining);
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// A `Transfer-Encoding: chunked` body.
+struct Chunked<R> {
+    inner: R,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+    fn read_line(&mut self) -> std::io::Result<String> {
+        let mut line = String::new();
+        (&mut self.inner).take(1024).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the response ended inside a chunk"));
+        }
+        Ok(line.trim_end().to_string())
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid chunk size: {}", size))
+            })?;
+            if self.remaining == 0 {
+                // Skip any trailers
+                while !self.read_line()?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let wanted = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..wanted])?;
+        if read == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the response ended inside a chunk"));
+        }
+        self.remaining -= read as u64;
+        if self.remaining == 0 && !self.read_line()?.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "a chunk is longer than its size"));
+        }
+        Ok(read)
+    }
+}
+
+/// Names the URL in errors reading its body.
+struct Labelled<R> {
+    inner: R,
+    url: String,
+    options: FetchOptions,
+}
+
+impl<R: Read> Read for Labelled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf).map_err(|err| {
+            let message = match err.kind() {
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
+                    format!("{} timed out after {}s", self.url, self.options.timeout.as_secs_f64())
+                }
+                std::io::ErrorKind::FileTooLarge => {
+                    format!("{} is larger than the limit of {} bytes", self.url, self.options.max_bytes)
+                }
+                _ => format!("Failed to read {}: {}", self.url, err),
+            };
+            std::io::Error::new(err.kind(), message)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Answer each of `responses` to one connection, in order, and return the base URL.
+    fn server(responses: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut head = Vec::new();
+                let mut reader = BufReader::new(&stream);
+                while !head.ends_with(b"\r\n\r\n") {
+                    if reader.read_until(b'\n', &mut head).unwrap() == 0 {
+                        break;
+                    }
+                }
+                let _ = stream.write_all(&response);
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    fn response(head: &str, body: &[u8]) -> Vec<u8> {
+        [format!("HTTP/1.1 {}\r\n\r\n", head).as_bytes(), body].concat()
+    }
+
+    fn body(url: &str, options: &FetchOptions) -> Result<String> {
+        let mut text = String::new();
+        fetch(url, options)?.read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url = |url: &str| parse_url(url).map(|url| (url.host, url.port, url.target));
+        assert_eq!(url("http://example.org/a.txt"), Ok(("example.org".to_string(), 80, "/a.txt".to_string())));
+        assert_eq!(url("HTTP://example.org:8080"), Ok(("example.org".to_string(), 8080, "/".to_string())));
+        assert_eq!(url("http://[::1]:81/x?y#z"), Ok(("::1".to_string(), 81, "/x?y".to_string())));
+        assert_eq!(url("http://example.org?list=1"), Ok(("example.org".to_string(), 80, "/?list=1".to_string())));
+        assert!(url("https://example.org/").unwrap_err().contains("needs TLS"));
+        assert!(url("ftp://example.org/").unwrap_err().starts_with("Unsupported URL scheme"));
+        assert!(url("http://example.org:http/").is_err());
+        assert!(url("http:///a.txt").is_err());
+
+        let base = parse_url("http://example.org:81/lists/a.txt?v=1").unwrap();
+        assert_eq!(resolve_location(&base, "b.txt"), "http://example.org:81/lists/b.txt");
+        assert_eq!(resolve_location(&base, "/b.txt"), "http://example.org:81/b.txt");
+        assert_eq!(resolve_location(&base, "//cdn.example.org/b.txt"), "http://cdn.example.org/b.txt");
+        assert_eq!(resolve_location(&base, "https://example.org/b.txt"), "https://example.org/b.txt");
+    }
+
+    #[test]
+    fn test_bodies_and_redirects() {
+        let options = FetchOptions::default();
+        let plain = server(vec![response("200 OK\r\nContent-Length: 11", b"10.0.0.1 5\n")]);
+        assert_eq!(body(&format!("{}/a.txt", plain), &options).unwrap(), "10.0.0.1 5\n");
+
+        let chunks = b"4\r\n10.0\r\n7;x=y\r\n.0.1 5\n\r\n0\r\n\r\n";
+        let chunked = server(vec![response("200 OK\r\nTransfer-Encoding: chunked", chunks)]);
+        assert_eq!(body(&chunked, &options).unwrap(), "10.0.0.1 5\n");
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"10.0.0.1 5\n").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let redirected = server(vec![
+            response("301 Moved Permanently\r\nLocation: /lists/new.txt.gz\r\nContent-Length: 0", b""),
+            response("302 Found\r\nLocation: newer.txt.gz", b""),
+            response("200 OK", &gzipped),
+        ]);
+        assert_eq!(body(&format!("{}/old.txt", redirected), &options).unwrap(), "10.0.0.1 5\n");
+
+        let head = format!("200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}", gzipped.len());
+        let encoded = server(vec![response(&head, &gzipped)]);
+        assert_eq!(body(&encoded, &options).unwrap(), "10.0.0.1 5\n");
+    }
+
+    #[test]
+    fn test_errors() {
+        let options = FetchOptions { max_redirects: 1, max_bytes: 16, ..FetchOptions::default() };
+        let missing = server(vec![response("404 Not Found\r\nContent-Length: 0", b"")]);
+        let err = body(&format!("{}/a.txt", missing), &options).unwrap_err().to_string();
+        assert_eq!(err, format!("Failed to fetch {}/a.txt: HTTP 404 Not Found", missing));
+
+        let looping = server(vec![response("302 Found\r\nLocation: /a", b""), response("302 Found\r\nLocation: /b", b"")]);
+        assert!(body(&looping, &options).unwrap_err().to_string().ends_with("more than 1 redirects"));
+
+        let large = server(vec![response("200 OK\r\nContent-Length: 17", &[b'1'; 17])]);
+        assert!(body(&large, &options).unwrap_err().to_string().contains("more than the limit of 16"));
+        let unsized_body = server(vec![response("200 OK", &[b'1'; 17])]);
+        let err = body(&unsized_body, &options).unwrap_err();
+        assert!(err.to_string().ends_with("is larger than the limit of 16 bytes"), "{}", err);
+
+        let truncated = server(vec![response("200 OK\r\nContent-Length: 10", b"10.0")]);
+        assert!(body(&truncated, &options).unwrap_err().to_string().contains("ended 6 bytes early"));
+        let redirect_to_https = server(vec![response("301 Moved\r\nLocation: https://example.org/", b"")]);
+        assert!(body(&redirect_to_https, &options).unwrap_err().to_string().contains("needs TLS"));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        // Accept without answering
+        let handle = std::thread::spawn(move || listener.accept().map(|(stream, _)| stream));
+        let options = FetchOptions { timeout: Duration::from_millis(200), ..FetchOptions::default() };
+        let started = Instant::now();
+        assert!(fetch(&url, &options).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(handle.join());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::Heatmap;
+use crate::json::JsonValue;
+use crate::render::RenderOptions;
+use anyhow::{Result, anyhow, bail};
+use image::RgbaImage;
+
+/// How two images of the same size differ.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageDiffReport {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels with a channel differing by more than `tolerance`.
+    pub differing_pixels: u64,
+    /// Largest difference of any channel of any pixel.
+    pub max_channel_delta: u8,
+    pub tolerance: u8,
+}
+
+impl ImageDiffReport {
+    /// Whether every channel is within the tolerance.
+    pub fn matches(&self) -> bool {
+        self.differing_pixels == 0
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut report = JsonValue::object();
+        report.insert("width", self.width as u64);
+        report.insert("height", self.height as u64);
+        report.insert("differing_pixels", self.differing_pixels);
+        report.insert("max_channel_delta", self.max_channel_delta as u64);
+        report.insert("tolerance", self.tolerance as u64);
+        report
+    }
+}
+
+impl std::fmt::Display for ImageDiffReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "differing pixels:  {} of {} (tolerance {})",
+            self.differing_pixels,
+            self.width as u64 * self.height as u64,
+            self.tolerance
+        )?;
+        write!(f, "max channel delta: {}", self.max_channel_delta)
+    }
+}
+
+/// Count the pixels of `a` and `b` with any channel differing by more than `tolerance`.
+pub fn compare_images(a: &RgbaImage, b: &RgbaImage, tolerance: u8) -> Result<ImageDiffReport> {
+    if a.dimensions() != b.dimensions() {
+        bail!(
+            "Cannot compare images of different sizes ({}x{} and {}x{})",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        );
+    }
+    let mut differing_pixels = 0;
+    let mut max_channel_delta = 0;
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        let delta = pa.0.iter().zip(pb.0).map(|(&ca, cb)| ca.abs_diff(cb)).max().unwrap_or(0);
+        if delta > tolerance {
+            differing_pixels += 1;
+        }
+        max_channel_delta = max_channel_delta.max(delta);
+    }
+    Ok(ImageDiffReport {
+        width: a.width(),
+        height: a.height(),
+        differing_pixels,
+        max_channel_delta,
+        tolerance,
+    })
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is the same in every build.
+fn fnv1a(bytes: impl IntoIterator<Item = u8>, mut hash: u64) -> u64 {
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// A hash of the image's dimensions and RGBA pixels that is stable across builds and
+/// platforms.
+pub fn image_hash(image: &RgbaImage) -> u64 {
+    let dimensions = image.width().to_le_bytes().into_iter().chain(image.height().to_le_bytes());
+    let hash = fnv1a(dimensions, 0xcbf29ce484222325);
+    fnv1a(image.as_raw().iter().copied(), hash)
+}
+
+impl Heatmap {
+    /// A stable hash of the image rendered with `options`, see [`image_hash`].
+    pub fn render_hash(&self, options: &RenderOptions) -> Result<u64> {
+        let image = self.render(options).map_err(|err| anyhow!(err))?;
+        Ok(image_hash(&image))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+    use image::Rgba;
+
+    #[test]
+    fn test_tolerance() {
+        let a = RgbaImage::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+        let mut b = a.clone();
+        b.put_pixel(0, 0, Rgba([12, 20, 30, 255]));
+        b.put_pixel(1, 0, Rgba([10, 25, 30, 255]));
+
+        let exact = compare_images(&a, &b, 0).unwrap();
+        assert_eq!((exact.differing_pixels, exact.max_channel_delta), (2, 5));
+        assert!(!exact.matches());
+        let loose = compare_images(&a, &b, 2).unwrap();
+        assert_eq!(loose.differing_pixels, 1);
+        assert!(compare_images(&a, &b, 5).unwrap().matches());
+        assert!(compare_images(&a, &RgbaImage::new(4, 5), 0).is_err());
+    }
+
+    #[test]
+    fn test_render_hash_is_stable() {
+        let mut hm = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            24,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        hm.process_input_from_string("10.0.0.1 5\n192.168.0.0/16 3\n").unwrap();
+        let options = hm.render_options();
+        // Pinned so that any change to the rendered pixels shows up here
+        assert_eq!(hm.render_hash(&options).unwrap(), 0xd7a3_d069_d3db_1ae3);
+        assert_eq!(image_hash(&RgbaImage::new(1, 1)), image_hash(&RgbaImage::new(1, 1)));
+        assert_ne!(image_hash(&RgbaImage::new(1, 2)), image_hash(&RgbaImage::new(2, 1)));
+    }
+}
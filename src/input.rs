@@ -0,0 +1,1515 @@
+use crate::{Geometry, ValueMode};
+use crate::braces;
+use crate::rejects::{DEFAULT_MAX_REJECT_SAMPLES, RejectReason};
+use crate::timestamps::{TimeWindow, parse_timestamp};
+use crate::timing::{Phase, PhaseTimer};
+use anyhow::{Context, Result};
+use ipnet::{Ipv4Net, Ipv6Net};
+use std::borrow::Cow;
+use std::io::BufRead;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// How IPv6 addresses in the input are treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum MapV6 {
+    /// Reject every IPv6 address.
+    #[default]
+    Off,
+    /// Plot IPv4-mapped addresses (`::ffff:a.b.c.d`) as their IPv4 address.
+    Mapped,
+}
+
+impl MapV6 {
+    pub const ALL: [MapV6; 2] = [MapV6::Off, MapV6::Mapped];
+}
+
+impl FromStr for MapV6 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(MapV6::Off),
+            "mapped" => Ok(MapV6::Mapped),
+            _ => Err(format!("Invalid IPv6 mapping: {}. Use 'off' or 'mapped'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for MapV6 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapV6::Off => write!(f, "off"),
+            MapV6::Mapped => write!(f, "mapped"),
+        }
+    }
+}
+
+/// How CIDR prefixes with host bits set (e.g. `10.1.2.3/16`) are treated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CidrHostBits {
+    /// Paint from the network address and warn once with a count.
+    #[default]
+    Warn,
+    /// Paint from the network address silently.
+    Allow,
+    /// Reject the line.
+    Reject,
+}
+
+impl CidrHostBits {
+    pub const ALL: [CidrHostBits; 3] = [CidrHostBits::Warn, CidrHostBits::Allow, CidrHostBits::Reject];
+}
+
+impl FromStr for CidrHostBits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(CidrHostBits::Warn),
+            "allow" => Ok(CidrHostBits::Allow),
+            "reject" => Ok(CidrHostBits::Reject),
+            _ => Err(format!("Invalid CIDR host bits handling: {}. Use 'warn', 'allow', or 'reject'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for CidrHostBits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CidrHostBits::Warn => write!(f, "warn"),
+            CidrHostBits::Allow => write!(f, "allow"),
+            CidrHostBits::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// Where a record's value comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ValueSource {
+    /// The value column, or 1 when there is none.
+    #[default]
+    Column,
+    /// The prefix length, ignoring any value column; plain addresses count as /32.
+    PrefixLen,
+    /// 1 for every record, ignoring any value column, so cells count the records
+    /// covering them.
+    Count,
+}
+
+impl ValueSource {
+    pub const ALL: [ValueSource; 3] = [ValueSource::Column, ValueSource::PrefixLen, ValueSource::Count];
+
+    /// The value mode to paint with: prefix lengths and counts are never divided over a
+    /// pixel.
+    pub(crate) fn paint_mode(self, value_mode: ValueMode) -> ValueMode {
+        match (self, value_mode) {
+            (ValueSource::PrefixLen | ValueSource::Count, ValueMode::Scaled) => ValueMode::Raw,
+            _ => value_mode,
+        }
+    }
+
+    /// The new value of a `cell` painted with `value` when values do not accumulate.
+    /// Prefix lengths keep the largest, so the most specific prefix wins in any order.
+    pub(crate) fn overwrite(self, cell: i32, value: i32) -> i32 {
+        match self {
+            ValueSource::Column | ValueSource::Count => value,
+            ValueSource::PrefixLen => cell.max(value),
+        }
+    }
+}
+
+impl FromStr for ValueSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "column" | "value" | "weight" | "bytes" => Ok(ValueSource::Column),
+            "prefix-len" => Ok(ValueSource::PrefixLen),
+            "count" => Ok(ValueSource::Count),
+            _ => Err(format!("Invalid value source: {}. Use 'column', 'prefix-len', or 'count'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueSource::Column => write!(f, "column"),
+            ValueSource::PrefixLen => write!(f, "prefix-len"),
+            ValueSource::Count => write!(f, "count"),
+        }
+    }
+}
+
+/// How input is encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum InputFormat {
+    /// Lines of an address or prefix and an optional value, separated by whitespace
+    /// or commas, so this also reads CSV.
+    #[default]
+    Text,
+    /// Binary records written by `convert --to raw-u32v`, see [`crate::RAW_MAGIC`].
+    RawU32v,
+    /// Rows of `cidr,value` as written by `--export-cells`, any further columns
+    /// ignored. Each value is the total of its prefix, see [`for_each_cell_share`].
+    Cells,
+}
+
+impl InputFormat {
+    pub const ALL: [InputFormat; 3] = [InputFormat::Text, InputFormat::RawU32v, InputFormat::Cells];
+
+    /// Every name the parser accepts, with the format it stands for.
+    pub const NAMES: [(&'static str, InputFormat); 4] = [
+        ("text", InputFormat::Text),
+        ("csv", InputFormat::Text),
+        ("raw-u32v", InputFormat::RawU32v),
+        ("cells", InputFormat::Cells),
+    ];
+
+    /// Whether input in this format is lines of text rather than binary records.
+    pub fn is_text(self) -> bool {
+        self != InputFormat::RawU32v
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_lowercase();
+        match InputFormat::NAMES.iter().find(|(known, _)| *known == name) {
+            Some(&(_, format)) => Ok(format),
+            None => Err(format!("Invalid input format: {}. Use 'text', 'csv', 'raw-u32v' or 'cells'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputFormat::Text => write!(f, "text"),
+            InputFormat::RawU32v => write!(f, "raw-u32v"),
+            InputFormat::Cells => write!(f, "cells"),
+        }
+    }
+}
+
+/// Parse a value weight, which must be a positive number.
+pub fn parse_weight(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(weight) if weight.is_finite() && weight > 0.0 => Ok(weight),
+        _ => Err(format!("Weight must be a number greater than 0: {}", value)),
+    }
+}
+
+/// Whether an input names an `http://` or `https://` URL rather than a file.
+pub fn is_url(input: &str) -> bool {
+    let lower = input.get(..8).unwrap_or(input).to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// An input file or URL with the weight of its values, written `path[:weight]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeightedInput {
+    pub path: String,
+    pub weight: f64,
+}
+
+impl FromStr for WeightedInput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // A suffix that is not a number is part of the path, as is the port of a URL
+        // without one
+        match s.rsplit_once(':') {
+            Some((path, weight)) if !path.is_empty() && weight.parse::<f64>().is_ok() && !is_bare_host(path) => {
+                Ok(Self {
+                    path: path.to_string(),
+                    weight: parse_weight(weight)?,
+                })
+            }
+            _ => Ok(Self {
+                path: s.to_string(),
+                weight: 1.0,
+            }),
+        }
+    }
+}
+
+/// Whether `path` is a URL up to its host, so that a following `:number` is its port.
+fn is_bare_host(path: &str) -> bool {
+    is_url(path) && !path.split_once("://").is_some_and(|(_, rest)| rest.contains('/'))
+}
+
+/// How input lines are tokenized and parsed and which records are kept, given by
+/// value to whatever reads them: [`crate::Heatmap::set_parse_options`],
+/// [`crate::Validator::new`], [`crate::Converter::new`] and [`crate::Summarizer::new`].
+///
+/// Start from the default and set what differs, e.g.
+/// `ParseOptions::default().strict_ip(true).ignore_values(vec!["-1".into()])`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseOptions {
+    pub(crate) separator: Option<char>,
+    pub(crate) map_v6: MapV6,
+    pub(crate) strict_ip: bool,
+    pub(crate) cidr_host_bits: CidrHostBits,
+    pub(crate) sampling: Option<Sampling>,
+    pub(crate) value_source: ValueSource,
+    pub(crate) ignore_values: Vec<String>,
+    pub(crate) format: InputFormat,
+    pub(crate) expand_braces: bool,
+    pub(crate) time_window: Option<TimeWindow>,
+    pub(crate) default_value: i32,
+    pub(crate) max_rejects: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            separator: None,
+            map_v6: MapV6::default(),
+            strict_ip: false,
+            cidr_host_bits: CidrHostBits::default(),
+            sampling: None,
+            value_source: ValueSource::default(),
+            ignore_values: Vec::new(),
+            format: InputFormat::default(),
+            expand_braces: false,
+            time_window: None,
+            default_value: 1,
+            max_rejects: DEFAULT_MAX_REJECT_SAMPLES,
+        }
+    }
+}
+
+impl ParseOptions {
+    /// Split fields at `separator` rather than at commas and whitespace.
+    pub fn separator(mut self, separator: Option<char>) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// How IPv6 addresses in the input are treated. Defaults to [`MapV6::Off`].
+    pub fn map_v6(mut self, map_v6: MapV6) -> Self {
+        self.map_v6 = map_v6;
+        self
+    }
+
+    /// Only accept canonical dotted-quad addresses (no integers or leading zeros).
+    pub fn strict_ip(mut self, strict: bool) -> Self {
+        self.strict_ip = strict;
+        self
+    }
+
+    /// How CIDR prefixes with host bits set are treated. Defaults to [`CidrHostBits::Warn`].
+    pub fn cidr_host_bits(mut self, cidr_host_bits: CidrHostBits) -> Self {
+        self.cidr_host_bits = cidr_host_bits;
+        self
+    }
+
+    /// Only process the lines picked by `sampling`, see [`Sampling`]. When values
+    /// accumulate on a heatmap they are scaled by 1/rate, so totals estimate those of
+    /// the full input.
+    pub fn sampling(mut self, sampling: Option<Sampling>) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Where record values come from. Defaults to [`ValueSource::Column`].
+    ///
+    /// With [`ValueSource::PrefixLen`] values are painted unscaled, and when they do
+    /// not accumulate each pixel keeps its most specific prefix whatever the input order.
+    pub fn value_source(mut self, value_source: ValueSource) -> Self {
+        self.value_source = value_source;
+        self
+    }
+
+    /// Drop records whose value column is exactly one of `values`, e.g. sentinels such
+    /// as `-1` for unknown. Tokens are compared as written, so `-1.0` does not match
+    /// `-1`; lines without a value column are never dropped.
+    pub fn ignore_values(mut self, values: Vec<String>) -> Self {
+        self.ignore_values = values;
+        self
+    }
+
+    /// How input is encoded. Defaults to [`InputFormat::Text`].
+    pub fn format(mut self, format: InputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Expand a brace group in the address, `10.20.{0-255}.0/24` or `192.0.2.{1,5,9}`,
+    /// into one record per item sharing the line's value. A line expands to at most
+    /// [`crate::MAX_BRACE_EXPANSIONS`] records; malformed groups are rejected.
+    pub fn expand_braces(mut self, expand: bool) -> Self {
+        self.expand_braces = expand;
+        self
+    }
+
+    /// Only keep records whose timestamp, the third field of a line as in
+    /// `10.0.0.1 5 2024-06-01T12:00:00Z`, is in `window`. Lines without a parsable
+    /// timestamp are rejected as [`RejectReason::InvalidTimestamp`].
+    pub fn time_window(mut self, window: Option<TimeWindow>) -> Self {
+        self.time_window = window;
+        self
+    }
+
+    /// The value of lines without a value column. Defaults to 1. Value columns that
+    /// are not integers, or are beyond the range of cells, are rejected instead.
+    pub fn default_value(mut self, value: i32) -> Self {
+        self.default_value = value;
+        self
+    }
+
+    /// Limit the number of rejected lines kept as samples (all are still counted).
+    /// Defaults to [`DEFAULT_MAX_REJECT_SAMPLES`].
+    pub fn max_rejects(mut self, max_samples: usize) -> Self {
+        self.max_rejects = max_samples;
+        self
+    }
+}
+
+/// Process roughly `rate` of the input lines, chosen by a hash of the line number so
+/// the same `seed` picks the same lines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sampling {
+    rate: f64,
+    seed: u64,
+    /// Lines whose hash is below this are kept.
+    threshold: u64,
+}
+
+impl Sampling {
+    /// `rate` must be in (0, 1].
+    pub fn new(rate: f64, seed: u64) -> Result<Self, String> {
+        if !(rate > 0.0 && rate <= 1.0) {
+            return Err(format!("Sample rate must be greater than 0 and at most 1, got {}", rate));
+        }
+        // Rates close to 1 round to u64::MAX, which still rejects one hash in 2^64
+        let threshold = if rate == 1.0 { u64::MAX } else { (rate * u64::MAX as f64) as u64 };
+        Ok(Self { rate, seed, threshold })
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Whether the line numbered `line_number` (from 1) is part of the sample.
+    pub fn keeps(&self, line_number: usize) -> bool {
+        self.rate == 1.0 || splitmix64(self.seed ^ line_number as u64) < self.threshold
+    }
+}
+
+/// The SplitMix64 finaliser: a cheap, well-mixed hash of one word.
+pub(crate) fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// One successfully parsed input line: the addresses it covers and its value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    /// Single addresses are represented as /32 networks. The address may have host
+    /// bits set; painting starts at the network address.
+    pub net: Ipv4Net,
+    pub value: i32,
+}
+
+impl Record {
+    /// Whether the prefix was written with host bits set, e.g. `10.1.2.3/16`.
+    pub fn has_host_bits(&self) -> bool {
+        self.net.addr() != self.net.network()
+    }
+}
+
+/// The outcome of parsing one input line.
+#[derive(Clone)]
+pub(crate) enum ParsedLine {
+    Blank,
+    /// Left out by [`ParseOptions::sampling`] without being parsed.
+    Unsampled,
+    Record(Record),
+    /// The records of an address with a brace group, see
+    /// [`ParseOptions::expand_braces`].
+    Records(Vec<Record>),
+    /// A record whose value is one of [`ParseOptions::ignore_values`].
+    Ignored,
+    /// A record whose timestamp is outside [`ParseOptions::time_window`].
+    OutsideWindow,
+    Rejected(RejectReason, String),
+}
+
+/// Parse one line of `address[,value]`, `a.b.c.d/len[,value]` or `integer[,value]`.
+pub(crate) fn parse_line(line: &str, options: &ParseOptions) -> ParsedLine {
+    let [ip_str, value_str, timestamp] = if options.expand_braces {
+        leading_fields(split_outside_braces(line, options.separator).into_iter())
+    } else {
+        leading_fields(split_fields(line, options.separator))
+    };
+    let Some(ip_str) = ip_str else {
+        return ParsedLine::Blank;
+    };
+
+    let record = |net: Ipv4Net| {
+        if let Some(value) = value_str
+            && options.ignore_values.iter().any(|ignored| ignored == value)
+        {
+            return ParsedLine::Ignored;
+        }
+        if let Some(window) = &options.time_window {
+            match timestamp.map(parse_timestamp) {
+                Some(Ok(millis)) if window.contains(millis) => {}
+                Some(Ok(_)) => return ParsedLine::OutsideWindow,
+                Some(Err(message)) => return ParsedLine::Rejected(RejectReason::InvalidTimestamp, message),
+                None => return ParsedLine::Rejected(RejectReason::InvalidTimestamp, "no timestamp field".to_string()),
+            }
+        }
+        let value = match (options.value_source, value_str) {
+            (ValueSource::Column, Some(value)) => match parse_value(value) {
+                Ok(value) => value,
+                Err((reason, message)) => return ParsedLine::Rejected(reason, message),
+            },
+            (ValueSource::Column, None) => options.default_value,
+            (ValueSource::PrefixLen, _) => net.prefix_len() as i32,
+            (ValueSource::Count, _) => 1,
+        };
+        ParsedLine::Record(Record { net, value })
+    };
+
+    if options.expand_braces && ip_str.contains(['{', '}']) {
+        let tokens = match braces::expand_braces(ip_str) {
+            Ok(tokens) => tokens,
+            Err(message) => return ParsedLine::Rejected(RejectReason::InvalidBraces, message),
+        };
+        let mut records = Vec::with_capacity(tokens.len());
+        for token in &tokens {
+            match parse_address(token, options, &record) {
+                ParsedLine::Record(record) => records.push(record),
+                ParsedLine::Rejected(reason, message) => {
+                    return ParsedLine::Rejected(reason, format!("{}: {}", token, message));
+                }
+                // Every item shares the value, so all are ignored alike
+                other => return other,
+            }
+        }
+        return ParsedLine::Records(records);
+    }
+    parse_address(ip_str, options, &record)
+}
+
+/// The cell value of a value column: an integer, written as digits or as a number
+/// without a fraction such as `1e6`. Values beyond the range of cells, including
+/// numbers too large for a float, are rejected rather than saturated.
+fn parse_value(value: &str) -> Result<i32, (RejectReason, String)> {
+    if let Ok(value) = value.parse::<i32>() {
+        return Ok(value);
+    }
+    match value.parse::<f64>() {
+        Ok(number) if number.is_nan() => Err((RejectReason::InvalidValue, format!("value {} is not a number", value))),
+        Ok(number) if number < i32::MIN as f64 || number > i32::MAX as f64 => Err((
+            RejectReason::ValueOutOfRange,
+            format!("value {} is outside {} to {}", value, i32::MIN, i32::MAX),
+        )),
+        Ok(number) if number.fract() == 0.0 => Ok(number as i32),
+        Ok(_) => Err((RejectReason::InvalidValue, format!("value {} is not an integer", value))),
+        Err(_) => Err((RejectReason::InvalidValue, format!("value {} is not a number", value))),
+    }
+}
+
+/// The address, value and timestamp fields, the only ones a line is parsed for.
+fn leading_fields<'a>(mut fields: impl Iterator<Item = &'a str>) -> [Option<&'a str>; 3] {
+    [fields.next(), fields.next(), fields.next()]
+}
+
+/// Fields of `line` split at `separator`, or at commas and whitespace, trimmed and
+/// without empty ones.
+fn split_fields(line: &str, separator: Option<char>) -> Fields<'_> {
+    Fields { rest: line, separator, ascii: line.is_ascii() }
+}
+
+/// The iterator of [`split_fields`], which borrows its fields from the line. Lines
+/// of ASCII, nearly all of them, are split on bytes rather than decoded chars.
+struct Fields<'a> {
+    rest: &'a str,
+    separator: Option<char>,
+    ascii: bool,
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        while !self.rest.is_empty() {
+            let (field, rest) = match self.separator {
+                Some(separator) => self.rest.split_once(separator).unwrap_or((self.rest, "")),
+                // Fields split at whitespace have none to trim
+                None if self.ascii => match self.rest.bytes().position(is_ascii_field_separator) {
+                    Some(end) => (&self.rest[..end], &self.rest[end + 1..]),
+                    None => (self.rest, ""),
+                },
+                None => match self.rest.char_indices().find(|&(_, c)| c == ',' || c.is_whitespace()) {
+                    Some((end, c)) => (&self.rest[..end], &self.rest[end + c.len_utf8()..]),
+                    None => (self.rest, ""),
+                },
+            };
+            self.rest = rest;
+            let field = if self.separator.is_some() { field.trim() } else { field };
+            if !field.is_empty() {
+                return Some(field);
+            }
+        }
+        None
+    }
+}
+
+/// A comma or a byte that [`char::is_whitespace`] holds as whitespace, which
+/// unlike [`u8::is_ascii_whitespace`] includes the vertical tab.
+fn is_ascii_field_separator(byte: u8) -> bool {
+    matches!(byte, b',' | b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r')
+}
+
+/// Parse one row of [`InputFormat::Cells`]: a prefix, its value and any further
+/// columns, such as the exporter's `x,y`. The exporter's header reads as blank.
+pub(crate) fn parse_cell_line(line: &str, options: &ParseOptions) -> ParsedLine {
+    let mut fields = split_fields(line, options.separator);
+    let Some(cidr) = fields.next() else {
+        return ParsedLine::Blank;
+    };
+    if cidr.eq_ignore_ascii_case("cidr") {
+        return ParsedLine::Blank;
+    }
+    let value_str = fields.next().unwrap_or_default();
+    let value = match parse_value(value_str) {
+        Ok(value) => value,
+        Err((RejectReason::ValueOutOfRange, message)) => return ParsedLine::Rejected(RejectReason::ValueOutOfRange, message),
+        Err(_) => return ParsedLine::Rejected(RejectReason::InvalidCell, "expected cidr,value".to_string()),
+    };
+    if options.ignore_values.iter().any(|ignored| ignored == value_str) {
+        return ParsedLine::Ignored;
+    }
+    match parse_ipv4_token(cidr, options.strict_ip) {
+        Ok(net) => ParsedLine::Record(Record { net, value }),
+        Err((reason, message)) => ParsedLine::Rejected(reason, message),
+    }
+}
+
+/// Fields of `line` split at `separator` (or commas and whitespace), except inside
+/// brace groups.
+fn split_outside_braces(line: &str, separator: Option<char>) -> Vec<&str> {
+    let is_separator = |c: char| match separator {
+        Some(separator) => c == separator,
+        None => c == ',' || c.is_whitespace(),
+    };
+    let mut fields = Vec::new();
+    let (mut depth, mut start) = (0usize, 0);
+    for (index, c) in line.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            c if depth == 0 && is_separator(c) => {
+                fields.push(line[start..index].trim());
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    fields.push(line[start..].trim());
+    fields.retain(|field| !field.is_empty());
+    fields
+}
+
+/// The suffix of reverse-DNS names, matched without regard to case and with or
+/// without the root's trailing dot.
+const IN_ADDR_SUFFIX: &str = ".in-addr.arpa";
+
+/// Whether `token` is a reverse-DNS name such as `4.3.2.1.in-addr.arpa`.
+fn is_in_addr(token: &str) -> bool {
+    let name = token.strip_suffix('.').unwrap_or(token);
+    name.len() >= IN_ADDR_SUFFIX.len()
+        && name.is_char_boundary(name.len() - IN_ADDR_SUFFIX.len())
+        && name[name.len() - IN_ADDR_SUFFIX.len()..].eq_ignore_ascii_case(IN_ADDR_SUFFIX)
+}
+
+/// Parse a reverse-DNS name: `4.3.2.1.in-addr.arpa` is 1.2.3.4, and the zone
+/// names with fewer labels are prefixes, `2.1.in-addr.arpa` being 1.2.0.0/16.
+pub fn parse_in_addr(name: &str) -> Result<Ipv4Net, String> {
+    if !is_in_addr(name) {
+        return Err(format!("{} does not end in in-addr.arpa", name));
+    }
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let labels: Vec<&str> = name[..name.len() - IN_ADDR_SUFFIX.len()].split('.').collect();
+    if labels.len() > 4 {
+        return Err(format!("{} labels before in-addr.arpa, more than the four octets of an address", labels.len()));
+    }
+    let mut octets = [0u8; 4];
+    for (octet, label) in octets.iter_mut().zip(labels.iter().rev()) {
+        *octet = parse_decimal(label, 255).map_err(|e| format!("label {}", e))? as u8;
+    }
+    Ok(Ipv4Net::new(Ipv4Addr::from(octets), 8 * labels.len() as u8).expect("at most 32 bits"))
+}
+
+/// Parse one address or prefix token, making a record of it with `record`.
+fn parse_address(ip_str: &str, options: &ParseOptions, record: &dyn Fn(Ipv4Net) -> ParsedLine) -> ParsedLine {
+    if is_in_addr(ip_str) {
+        return match parse_in_addr(ip_str) {
+            Ok(net) => record(net),
+            Err(message) => ParsedLine::Rejected(RejectReason::InvalidInAddr, message),
+        };
+    }
+    if ip_str.contains(':')
+        && let Some(v6) = parse_ipv6_token(ip_str)
+    {
+        return match (options.map_v6, ipv6_to_ipv4(v6)) {
+            (MapV6::Mapped, Some(net)) => record(net),
+            (MapV6::Mapped, None) => ParsedLine::Rejected(RejectReason::Ipv6, "not IPv4-mapped".to_string()),
+            (MapV6::Off, _) => ParsedLine::Rejected(RejectReason::Ipv6, "IPv6 is not plotted".to_string()),
+        };
+    }
+
+    // Check if this is a CIDR prefix
+    match parse_ipv4_token(ip_str, options.strict_ip) {
+        Ok(net) if options.cidr_host_bits == CidrHostBits::Reject && net.addr() != net.network() => {
+            ParsedLine::Rejected(RejectReason::CidrHostBits, format!("network address is {}", net.trunc()))
+        }
+        Ok(net) => record(net),
+        Err((reason, message)) => ParsedLine::Rejected(reason, message),
+    }
+}
+
+/// Parse an IPv4 address token: `a.b.c.d`, `a.b.c.d/len` or a decimal integer.
+/// Single addresses are returned as /32 networks.
+///
+/// In `strict` mode the address must be exactly four decimal octets 0-255 without
+/// leading zeros, the prefix length a plain decimal 0-32, and integers are refused.
+/// Either way, tokens with more than four parts are refused.
+pub(crate) fn parse_ipv4_token(token: &str, strict: bool) -> Result<Ipv4Net, (RejectReason, String)> {
+    // Check if this is a CIDR prefix
+    if let Some((address, prefix_len)) = token.split_once('/') {
+        let invalid = |message: String| (RejectReason::InvalidCidr, message);
+        if !strict {
+            return token.parse::<Ipv4Net>().map_err(|e| invalid(e.to_string()));
+        }
+        let address = parse_dotted_quad(address).map_err(invalid)?;
+        let prefix_len = parse_decimal(prefix_len, 32).map_err(|e| invalid(format!("prefix length {}", e)))?;
+        return Ipv4Net::new(address, prefix_len as u8).map_err(|e| invalid(e.to_string()));
+    }
+
+    // Process as individual IP
+    let addr = if token.bytes().all(|byte| byte.is_ascii_digit()) && !strict {
+        let ip = token
+            .parse::<u32>()
+            .map_err(|e| (RejectReason::InvalidIntegerIp, e.to_string()))?;
+        Ipv4Addr::from(ip)
+    } else if strict && !token.contains('.') {
+        return Err((RejectReason::InvalidIp, "not a dotted-quad address".to_string()));
+    } else if strict {
+        parse_dotted_quad(token).map_err(|message| (RejectReason::InvalidIp, message))?
+    } else {
+        if token.bytes().filter(|&byte| byte == b'.').count() > 3 {
+            return Err((RejectReason::InvalidIp, "more than four octets".to_string()));
+        }
+        Ipv4Addr::from_str(token).map_err(|e| (RejectReason::InvalidIp, e.to_string()))?
+    };
+    Ok(Ipv4Net::from(addr))
+}
+
+/// Parse exactly four dot-separated decimal octets.
+fn parse_dotted_quad(token: &str) -> Result<Ipv4Addr, String> {
+    let mut octets = [0u8; 4];
+    let mut parts = token.split('.');
+    for (i, octet) in octets.iter_mut().enumerate() {
+        let part = parts
+            .next()
+            .ok_or_else(|| format!("expected four octets, found {}", i))?;
+        *octet = parse_decimal(part, 255).map_err(|e| format!("octet {} {}", i + 1, e))? as u8;
+    }
+    if parts.next().is_some() {
+        return Err("more than four octets".to_string());
+    }
+    Ok(Ipv4Addr::from(octets))
+}
+
+/// Parse a plain decimal number no larger than `max`, without sign or leading zeros.
+fn parse_decimal(text: &str, max: u32) -> Result<u32, String> {
+    if text.is_empty() {
+        return Err("is empty".to_string());
+    }
+    if !text.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("'{}' is not a decimal number", text));
+    }
+    if text.len() > 1 && text.starts_with('0') {
+        return Err(format!("'{}' has a leading zero", text));
+    }
+    match text.parse::<u32>() {
+        Ok(value) if value <= max => Ok(value),
+        _ => Err(format!("'{}' is larger than {}", text, max)),
+    }
+}
+
+/// Parse an IPv6 address or prefix token, also accepting the bracketed form with a
+/// port (`[::1]:443`) and zone identifiers (`fe80::1%eth0`). Single addresses are
+/// returned as /128 networks.
+fn parse_ipv6_token(token: &str) -> Option<Ipv6Net> {
+    let token = match token.strip_prefix('[') {
+        Some(rest) => &rest[..rest.find(']')?],
+        None => token,
+    };
+    if token.contains('/') {
+        return token.parse::<Ipv6Net>().ok();
+    }
+    let address = token.split('%').next().unwrap_or(token);
+    address.parse::<Ipv6Addr>().ok().map(Ipv6Net::from)
+}
+
+/// The IPv4 network embedded in an IPv4-mapped IPv6 network, if any.
+fn ipv6_to_ipv4(net: Ipv6Net) -> Option<Ipv4Net> {
+    let addr = net.network().to_ipv4_mapped()?;
+    let prefix_len = net.prefix_len().checked_sub(96)?;
+    Ipv4Net::new(addr, prefix_len).ok()
+}
+
+/// Lines longer than this many bytes are skipped without being buffered in full.
+pub(crate) const MAX_LINE_LENGTH: usize = 1 << 20;
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// A line of text input before parsing.
+pub(crate) enum TextLine<'a> {
+    Text(Cow<'a, str>),
+    /// Left out by [`ParseOptions::sampling`].
+    Unsampled,
+    /// Longer than [`MAX_LINE_LENGTH`]; holds the first bytes.
+    Overlong(Cow<'a, str>),
+}
+
+impl TextLine<'_> {
+    /// Parse the line, giving the outcome and the text rejects are reported with.
+    pub(crate) fn parse(&self, options: &ParseOptions) -> ParsedLine {
+        match self {
+            TextLine::Text(line) if options.format == InputFormat::Cells => parse_cell_line(line, options),
+            TextLine::Text(line) => parse_line(line, options),
+            TextLine::Unsampled => ParsedLine::Unsampled,
+            TextLine::Overlong(_) => {
+                ParsedLine::Rejected(RejectReason::LineTooLong, format!("line exceeds {} bytes", MAX_LINE_LENGTH))
+            }
+        }
+    }
+
+    pub(crate) fn text(&self) -> &str {
+        match self {
+            TextLine::Text(line) | TextLine::Overlong(line) => line,
+            TextLine::Unsampled => "",
+        }
+    }
+}
+
+/// Parse every line of `reader`, passing the 1-based line number (counting on from
+/// `first_line` earlier lines), the line and the outcome to `on_line`. Reading and
+/// parsing are timed with `timer`.
+///
+/// Lines end at LF, CRLF or a lone CR. Invalid UTF-8 is replaced rather than failing
+/// the run, a leading byte order mark is ignored and overlong lines are rejected.
+pub(crate) fn for_each_line<R: BufRead>(
+    reader: R,
+    first_line: usize,
+    options: &ParseOptions,
+    timer: &PhaseTimer,
+    mut on_line: impl FnMut(usize, &str, ParsedLine) -> Result<()>,
+) -> Result<()> {
+    for_each_text_line(reader, first_line, options, timer, |line_number, line| {
+        let parsed = timer.time(Phase::Parse, || line.parse(options));
+        on_line(line_number, line.text(), parsed)
+    })
+}
+
+/// Split `reader` into lines as [`for_each_line`] does, without parsing them.
+pub(crate) fn for_each_text_line<R: BufRead>(
+    mut reader: R,
+    first_line: usize,
+    options: &ParseOptions,
+    timer: &PhaseTimer,
+    mut on_line: impl FnMut(usize, TextLine) -> Result<()>,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+    let mut line_number = first_line;
+    loop {
+        let started = timer.start();
+        buffer.clear();
+        let (read, overlong) =
+            read_line_capped(&mut reader, &mut buffer, MAX_LINE_LENGTH).context("Failed to read line")?;
+        if read == 0 {
+            timer.stop(Phase::Read, started);
+            break;
+        }
+        let mut bytes = buffer.as_slice();
+        if line_number == 0 {
+            bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes);
+        }
+        bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+        bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+        timer.stop(Phase::Read, started);
+
+        if overlong {
+            line_number += 1;
+            on_line(line_number, TextLine::Overlong(String::from_utf8_lossy(bytes)))?;
+            continue;
+        }
+        for segment in bytes.split(|&byte| byte == b'\r') {
+            line_number += 1;
+            if let Some(sampling) = &options.sampling
+                && !sampling.keeps(line_number)
+            {
+                on_line(line_number, TextLine::Unsampled)?;
+                continue;
+            }
+            // Only allocates when the line is not valid UTF-8
+            on_line(line_number, TextLine::Text(String::from_utf8_lossy(segment)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Like [`for_each_line`], for input in `options.format`. Binary records have no
+/// text, so `on_line` sees them as empty lines numbered by record.
+pub(crate) fn for_each_record<R: BufRead>(
+    reader: R,
+    first_line: usize,
+    options: &ParseOptions,
+    timer: &PhaseTimer,
+    mut on_line: impl FnMut(usize, &str, ParsedLine) -> Result<()>,
+) -> Result<()> {
+    match options.format {
+        InputFormat::Text | InputFormat::Cells => for_each_line(reader, first_line, options, timer, on_line),
+        InputFormat::RawU32v => crate::raw::for_each_raw_record(reader, options, |number, parsed| {
+            on_line(first_line + number, "", parsed)
+        }),
+    }
+}
+
+/// Read up to and including the next LF into `line`, keeping at most `max_length`
+/// bytes. Returns the number of bytes consumed and whether the line was cut short.
+fn read_line_capped<R: BufRead>(reader: &mut R, line: &mut Vec<u8>, max_length: usize) -> std::io::Result<(usize, bool)> {
+    let mut read = 0;
+    let mut overlong = false;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if available.is_empty() {
+            break;
+        }
+        let (chunk, done) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(end) => (&available[..=end], true),
+            None => (available, false),
+        };
+        // The terminating LF does not count towards the limit
+        let room = (max_length + 1).saturating_sub(line.len());
+        if chunk.len() > room {
+            overlong = true;
+        }
+        line.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        let used = chunk.len();
+        read += used;
+        reader.consume(used);
+        if done {
+            break;
+        }
+    }
+    if overlong {
+        line.truncate(max_length);
+    }
+    Ok((read, overlong))
+}
+
+/// Call `paint` with the curve distance and value of every pixel a record covers.
+///
+/// In scaled mode the value is spread over the pixel's addresses, so a pixel only
+/// partially covered by the network receives a proportional share.
+pub(crate) fn for_each_pixel(
+    bits_per_pixel: u8,
+    value_mode: ValueMode,
+    record: &Record,
+    mut paint: impl FnMut(u64, i32),
+) {
+    let ips_per_pixel = Geometry::for_bits_per_pixel(bits_per_pixel).ips_per_pixel;
+
+    // Calculate the range of pixels that this CIDR block covers
+    let first_ip = u32::from(record.net.network()) as u64;
+    let last_ip = u32::from(record.net.broadcast()) as u64;
+    let first_pixel_d = first_ip >> bits_per_pixel;
+    let last_pixel_d = last_ip >> bits_per_pixel;
+
+    // Iterate through the affected pixels
+    for pixel_d in first_pixel_d..=last_pixel_d {
+        // Calculate the IP range this pixel represents
+        let pixel_first_ip = pixel_d << bits_per_pixel;
+        let pixel_last_ip = pixel_first_ip + ips_per_pixel - 1;
+
+        // Calculate overlap between CIDR block and this pixel's IP range
+        let overlap_first = first_ip.max(pixel_first_ip);
+        let overlap_last = last_ip.min(pixel_last_ip);
+
+        if overlap_first <= overlap_last {
+            let paint_value = match value_mode {
+                ValueMode::Categorical | ValueMode::Raw => record.value,
+                ValueMode::Scaled => {
+                    let overlap_count = overlap_last - overlap_first + 1;
+                    (record.value as f64 * overlap_count as f64 / ips_per_pixel as f64) as i32
+                }
+            };
+            paint(pixel_d, paint_value);
+        }
+    }
+}
+
+/// Call `paint` with every pixel a row of [`InputFormat::Cells`] covers and its
+/// share of the row's value, which is the total of the row's addresses: a row
+/// covering several pixels is divided evenly between them, the remainder going to
+/// the first, so the shares add up to the value. Several rows in one pixel are
+/// added, or for categories the last one kept, by the caller.
+pub(crate) fn for_each_cell_share(bits_per_pixel: u8, record: &Record, mut paint: impl FnMut(u64, i32)) {
+    let first_pixel_d = u32::from(record.net.network()) as u64 >> bits_per_pixel;
+    let last_pixel_d = u32::from(record.net.broadcast()) as u64 >> bits_per_pixel;
+    let pixels = (last_pixel_d - first_pixel_d + 1) as i64;
+    let (share, remainder) = (record.value as i64 / pixels, record.value as i64 % pixels);
+    for (index, pixel_d) in (first_pixel_d..=last_pixel_d).enumerate() {
+        let extra = if (index as i64) < remainder.abs() { remainder.signum() } else { 0 };
+        paint(pixel_d, (share + extra) as i32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each line as passed to `on_line`, with its number and whether it parsed.
+    fn lines(input: &[u8]) -> Vec<(usize, String, bool)> {
+        let mut lines = Vec::new();
+        for_each_line(input, 0, &ParseOptions::default(), &PhaseTimer::default(), |number, line, parsed| {
+            lines.push((number, line.to_string(), matches!(parsed, ParsedLine::Record(_))));
+            Ok(())
+        })
+        .unwrap();
+        lines
+    }
+
+    #[test]
+    fn test_byte_order_mark_is_stripped() {
+        let lines = lines(b"\xef\xbb\xbf10.0.0.1\n10.0.0.2\n");
+        assert_eq!(lines[0], (1, "10.0.0.1".to_string(), true));
+        assert!(lines[1].2);
+    }
+
+    #[test]
+    fn test_crlf_and_lone_cr_line_endings() {
+        assert_eq!(
+            lines(b"10.0.0.1 5\r\n10.0.0.2\r\n"),
+            vec![(1, "10.0.0.1 5".to_string(), true), (2, "10.0.0.2".to_string(), true)]
+        );
+        let lone_cr = lines(b"10.0.0.1\r10.0.0.2\r10.0.0.3");
+        assert_eq!(lone_cr.len(), 3);
+        assert!(lone_cr.iter().all(|line| line.2));
+        assert_eq!(lone_cr[2].0, 3);
+    }
+
+    #[test]
+    fn test_invalid_utf8_in_trailing_fields_is_tolerated() {
+        let lines = lines(b"10.0.0.1 5 caf\xe9\n10.0.0.2 3\n\xff\xfe\n");
+        assert!(lines[0].2);
+        assert!(lines[1].2);
+        // A line that is only garbage is rejected, not fatal
+        assert!(!lines[2].2);
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn test_overlong_lines_are_rejected() {
+        let mut input = vec![b'x'; MAX_LINE_LENGTH * 2];
+        input.extend_from_slice(b"\n10.0.0.1\n");
+        let mut reasons = Vec::new();
+        for_each_line(input.as_slice(), 0, &ParseOptions::default(), &PhaseTimer::default(), |number, line, parsed| {
+            let reason = match parsed {
+                ParsedLine::Rejected(reason, _) => Some(reason),
+                _ => None,
+            };
+            reasons.push((number, line.len(), reason));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(reasons, vec![(1, MAX_LINE_LENGTH, Some(RejectReason::LineTooLong)), (2, 8, None)]);
+    }
+
+    #[test]
+    fn test_line_of_exactly_max_length_is_kept() {
+        let mut buffer = Vec::new();
+        let mut input = vec![b'x'; 10];
+        input.push(b'\n');
+        let (read, overlong) = read_line_capped(&mut input.as_slice(), &mut buffer, 10).unwrap();
+        assert_eq!((read, overlong, buffer.len()), (11, false, 11));
+    }
+
+    fn record(line: &str) -> Record {
+        match parse_line(line, &ParseOptions::default()) {
+            ParsedLine::Record(record) => record,
+            _ => panic!("{} did not parse", line),
+        }
+    }
+
+    #[test]
+    fn test_parse_line_forms() {
+        assert_eq!(record("10.0.0.1").net, "10.0.0.1/32".parse().unwrap());
+        assert_eq!(record("10.0.0.1,5").value, 5);
+        assert_eq!(record("167772161 7"), Record { net: "10.0.0.1/32".parse().unwrap(), value: 7 });
+        assert_eq!(record("10.0.0.0/8\t3").net, "10.0.0.0/8".parse().unwrap());
+        assert!(matches!(parse_line("   ", &ParseOptions::default()), ParsedLine::Blank));
+        assert!(matches!(
+            parse_line("10.0.0.0/40", &ParseOptions::default()),
+            ParsedLine::Rejected(RejectReason::InvalidCidr, _)
+        ));
+        assert!(matches!(
+            parse_line("99999999999", &ParseOptions::default()),
+            ParsedLine::Rejected(RejectReason::InvalidIntegerIp, _)
+        ));
+        assert!(matches!(parse_line("host", &ParseOptions::default()), ParsedLine::Rejected(RejectReason::InvalidIp, _)));
+    }
+
+    #[test]
+    fn test_parse_options_builder() {
+        let options = ParseOptions::default().separator(Some(';')).strict_ip(true).default_value(0).max_rejects(3);
+        let expected =
+            ParseOptions { separator: Some(';'), strict_ip: true, default_value: 0, max_rejects: 3, ..ParseOptions::default() };
+        assert_eq!(options, expected);
+        // Later settings win
+        assert!(!options.clone().strict_ip(false).strict_ip);
+    }
+
+    #[test]
+    fn test_value_columns() {
+        let value = |line: &str, options: &ParseOptions| match parse_line(line, options) {
+            ParsedLine::Record(record) => Ok(record.value),
+            ParsedLine::Rejected(reason, message) => Err((reason, message)),
+            _ => panic!("{} was neither", line),
+        };
+        let defaults = ParseOptions::default();
+        assert_eq!(value("10.0.0.1 -2147483648", &defaults), Ok(i32::MIN));
+        assert_eq!(value("10.0.0.1 +7", &defaults), Ok(7));
+        // Numbers without a fraction are integers however they are written
+        assert_eq!(value("10.0.0.1 1e6", &defaults), Ok(1_000_000));
+        assert_eq!(value("10.0.0.1 5.0", &defaults), Ok(5));
+        let out_of_range = (
+            RejectReason::ValueOutOfRange,
+            "value 99999999999999999999 is outside -2147483648 to 2147483647".to_string(),
+        );
+        assert_eq!(value("10.0.0.1 99999999999999999999", &defaults), Err(out_of_range));
+        for line in ["10.0.0.1 2147483648", "10.0.0.1 -2147483649", "10.0.0.1 1e400", "10.0.0.1 -inf"] {
+            assert_eq!(value(line, &defaults).unwrap_err().0, RejectReason::ValueOutOfRange, "{}", line);
+        }
+        assert_eq!(value("10.0.0.1 2.5", &defaults), Err((RejectReason::InvalidValue, "value 2.5 is not an integer".to_string())));
+        for line in ["10.0.0.1 n/a", "10.0.0.1 NaN", "10.0.0.1 0x10"] {
+            assert_eq!(value(line, &defaults).unwrap_err().0, RejectReason::InvalidValue, "{}", line);
+        }
+        // Only a missing value column takes the default
+        let options = ParseOptions { default_value: 0, ..ParseOptions::default() };
+        assert_eq!((value("10.0.0.1", &defaults), value("10.0.0.1", &options)), (Ok(1), Ok(0)));
+        assert_eq!(value("10.0.0.1 n/a", &options).unwrap_err().0, RejectReason::InvalidValue);
+        // An ignored token, or a value source that does not read the column, is not checked
+        let ignored = ParseOptions { ignore_values: vec!["n/a".to_string()], ..ParseOptions::default() };
+        assert!(matches!(parse_line("10.0.0.1 n/a", &ignored), ParsedLine::Ignored));
+        let counted = ParseOptions { value_source: ValueSource::Count, ..ParseOptions::default() };
+        assert_eq!(value("10.0.0.1 99999999999999999999", &counted), Ok(1));
+        // The address is checked first
+        assert_eq!(value("host n/a", &defaults).unwrap_err().0, RejectReason::InvalidIp);
+    }
+
+    #[test]
+    fn test_prefix_len_ignores_the_value_column() {
+        let options = ParseOptions {
+            value_source: ValueSource::PrefixLen,
+            ..ParseOptions::default()
+        };
+        let value = |line: &str| match parse_line(line, &options) {
+            ParsedLine::Record(record) => record.value,
+            _ => panic!("{} did not parse", line),
+        };
+        assert_eq!(value("10.0.0.0/8 500"), 8);
+        assert_eq!(value("10.1.2.0/24"), 24);
+        assert_eq!(value("10.1.2.3,9"), 32);
+        assert_eq!("prefix-len".parse::<ValueSource>().unwrap(), ValueSource::PrefixLen);
+        assert!("length".parse::<ValueSource>().is_err());
+    }
+
+    #[test]
+    fn test_ignored_values_in_every_format() {
+        let options = ParseOptions {
+            ignore_values: vec!["-1".to_string(), "0".to_string()],
+            map_v6: MapV6::Mapped,
+            ..ParseOptions::default()
+        };
+        let ignored = |line: &str| matches!(parse_line(line, &options), ParsedLine::Ignored);
+        for line in ["10.0.0.1 -1", "10.0.0.0/8,0", "167772161 0", "::ffff:10.0.0.1 -1"] {
+            assert!(ignored(line), "{} was not ignored", line);
+        }
+        // Tokens are compared as written, and lines without a value are kept
+        for line in ["10.0.0.1 -1.0", "10.0.0.1 00", "10.0.0.1 5", "10.0.0.1"] {
+            assert!(matches!(parse_line(line, &options), ParsedLine::Record(_)), "{} was dropped", line);
+        }
+        // An unparsable address is still rejected
+        assert!(matches!(parse_line("host 0", &options), ParsedLine::Rejected(..)));
+    }
+
+    #[test]
+    fn test_time_window_keeps_its_start_and_drops_its_end() {
+        let june_1 = 1_717_200_000;
+        let options = ParseOptions {
+            time_window: Some(TimeWindow::new(Some(june_1 * 1000), Some((june_1 + 3600) * 1000)).unwrap()),
+            ..ParseOptions::default()
+        };
+        let parsed = |line: &str| parse_line(line, &options);
+        for line in ["10.0.0.1 5 2024-06-01T00:00:00Z", "10.0.0.1,5,1717203599", "10.0.0.1 5 1717203599999"] {
+            assert!(matches!(parsed(line), ParsedLine::Record(_)), "{} was dropped", line);
+        }
+        for line in ["10.0.0.1 5 2024-06-01T01:00:00Z", "10.0.0.1 5 1717199999", "10.0.0.1 5 2024-06-01T01:59:59+02:00"] {
+            assert!(matches!(parsed(line), ParsedLine::OutsideWindow), "{} was kept", line);
+        }
+        for line in ["10.0.0.1 5", "10.0.0.1 5 noon"] {
+            assert!(matches!(parsed(line), ParsedLine::Rejected(RejectReason::InvalidTimestamp, _)), "{}", line);
+        }
+        // The address is checked first
+        assert!(matches!(parsed("host 5 1717200000"), ParsedLine::Rejected(RejectReason::InvalidIp, _)));
+    }
+
+    /// The splitting [`split_fields`] replaced, which collected every field.
+    fn reference_split_fields(line: &str, separator: Option<char>) -> Vec<&str> {
+        if let Some(sep) = separator {
+            line.split(sep).map(|s| s.trim()).filter(|s| !s.is_empty()).collect()
+        } else {
+            line.split(|c: char| c == ',' || c.is_whitespace()).map(|s| s.trim()).filter(|s| !s.is_empty()).collect()
+        }
+    }
+
+    #[test]
+    fn test_split_fields_matches_reference_on_fuzz_corpus() {
+        // Short lines of the characters that matter to splitting, ASCII and not
+        let alphabet = ['1', '0', '.', '/', ',', ';', ' ', '\t', '\u{b}', '\u{c}', '\r', 'a', 'é', '\u{a0}', '\u{3000}', '{'];
+        for seed in 0..20_000u64 {
+            let hash = splitmix64(seed);
+            let pick = |i: u64| alphabet[(splitmix64(hash ^ i) % alphabet.len() as u64) as usize];
+            let line: String = (0..hash % 24).map(pick).collect();
+            for separator in [None, Some(','), Some(';'), Some('\t'), Some('é')] {
+                let fields: Vec<&str> = split_fields(&line, separator).collect();
+                assert_eq!(fields, reference_split_fields(&line, separator), "{:?} split at {:?}", line, separator);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cell_rows() {
+        let options = ParseOptions { format: InputFormat::Cells, ..ParseOptions::default() };
+        let parsed = |line: &str| match parse_cell_line(line, &options) {
+            ParsedLine::Record(record) => Ok((record.net.to_string(), record.value)),
+            ParsedLine::Blank => Err(None),
+            ParsedLine::Rejected(reason, _) => Err(Some(reason)),
+            _ => panic!("{} was neither", line),
+        };
+        assert_eq!(parsed("cidr,value,x,y"), Err(None));
+        assert_eq!(parsed("CIDR,Value"), Err(None));
+        assert_eq!(parsed("10.0.0.0/16,8,48,48"), Ok(("10.0.0.0/16".to_string(), 8)));
+        assert_eq!(parsed("10.1.0.0/16 -3"), Ok(("10.1.0.0/16".to_string(), -3)));
+        assert_eq!(parsed("10.0.0.0/16"), Err(Some(RejectReason::InvalidCell)));
+        assert_eq!(parsed("10.0.0.0/16,lots"), Err(Some(RejectReason::InvalidCell)));
+        assert_eq!(parsed("10.0.0.0/16,3000000000"), Err(Some(RejectReason::ValueOutOfRange)));
+        assert_eq!(parsed("cider,1"), Err(Some(RejectReason::InvalidIp)));
+    }
+
+    #[test]
+    fn test_cell_shares_add_up_to_the_value() {
+        let shares = |net: &str, value: i32, bits_per_pixel: u8| {
+            let mut shares = Vec::new();
+            let record = Record { net: net.parse().unwrap(), value };
+            for_each_cell_share(bits_per_pixel, &record, |d, share| shares.push((d, share)));
+            shares
+        };
+        assert_eq!(shares("10.0.0.0/16", 7, 16), [(0x0a00, 7)]);
+        assert_eq!(shares("10.0.0.0/16", 7, 20), [(0xa0, 7)]);
+        let divided = shares("10.0.0.0/16", 7, 14);
+        assert_eq!(divided.iter().map(|&(_, share)| share).collect::<Vec<_>>(), [2, 2, 2, 1]);
+        assert_eq!(divided[0].0, 0x2800);
+        let negative: Vec<i32> = shares("10.0.0.0/16", -7, 14).iter().map(|&(_, share)| share).collect();
+        assert_eq!(negative, [-2, -2, -2, -1]);
+    }
+
+    #[test]
+    fn test_weighted_input() {
+        let input = |s: &str| s.parse::<WeightedInput>().map(|input| (input.path, input.weight));
+        assert_eq!(input("a.txt"), Ok(("a.txt".to_string(), 1.0)));
+        assert_eq!(input("b.txt:1000"), Ok(("b.txt".to_string(), 1000.0)));
+        assert_eq!(input("c:d.txt:0.5"), Ok(("c:d.txt".to_string(), 0.5)));
+        assert_eq!(input("scan:latest"), Ok(("scan:latest".to_string(), 1.0)));
+        assert_eq!(input("http://example.org:8080"), Ok(("http://example.org:8080".to_string(), 1.0)));
+        assert_eq!(input("http://example.org:8080/a.txt:2"), Ok(("http://example.org:8080/a.txt".to_string(), 2.0)));
+        assert!(is_url("HTTPS://example.org/a.txt") && !is_url("http.txt") && !is_url("scan:latest"));
+        assert!(input("b.txt:0").is_err());
+        assert!(input("b.txt:-2").is_err());
+    }
+
+    fn ipv4(token: &str, strict: bool) -> Result<String, RejectReason> {
+        parse_ipv4_token(token, strict)
+            .map(|net| net.to_string())
+            .map_err(|(reason, _)| reason)
+    }
+
+    #[test]
+    fn test_tokens_accepted_in_both_modes() {
+        for strict in [false, true] {
+            assert_eq!(ipv4("10.0.0.1", strict), Ok("10.0.0.1/32".to_string()));
+            assert_eq!(ipv4("0.0.0.0", strict), Ok("0.0.0.0/32".to_string()));
+            assert_eq!(ipv4("255.255.255.255", strict), Ok("255.255.255.255/32".to_string()));
+            assert_eq!(ipv4("10.0.0.0/8", strict), Ok("10.0.0.0/8".to_string()));
+            assert_eq!(ipv4("0.0.0.0/0", strict), Ok("0.0.0.0/0".to_string()));
+            assert_eq!(ipv4("1.2.3.4/32", strict), Ok("1.2.3.4/32".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_tokens_rejected_in_both_modes() {
+        for strict in [false, true] {
+            for token in ["1.2.3.4.5", "10.2.3", "1.2.3", "256.0.0.1", "1.2.3.-4", "a.b.c.d", "1..2.3", "1.2.3.4.", ".1.2.3"] {
+                assert_eq!(ipv4(token, strict), Err(RejectReason::InvalidIp), "{} strict={}", token, strict);
+            }
+            for token in ["1.2.3.4/33", "1.2.3.4/", "1.2.3/8", "1.2.3.4.5/8", "1.2.3.4/x"] {
+                assert_eq!(ipv4(token, strict), Err(RejectReason::InvalidCidr), "{} strict={}", token, strict);
+            }
+            assert!(ipv4("4294967296", strict).is_err());
+        }
+    }
+
+    #[test]
+    fn test_strict_only_rejections() {
+        assert_eq!(ipv4("167772161", false), Ok("10.0.0.1/32".to_string()));
+        assert_eq!(ipv4("167772161", true), Err(RejectReason::InvalidIp));
+        assert_eq!(ipv4("1.2.3.4/08", false), Ok("1.2.3.4/8".to_string()));
+        assert_eq!(ipv4("1.2.3.4/08", true), Err(RejectReason::InvalidCidr));
+    }
+
+    #[test]
+    fn test_strict_rejects_leading_zeros_and_signs() {
+        for token in ["01.2.3.4", "1.02.3.4", "1.2.003.4", "1.2.3.00", "+1.2.3.4", "1.2.3.4 "] {
+            assert_eq!(ipv4(token, true), Err(RejectReason::InvalidIp), "{}", token);
+        }
+        assert!(parse_ipv4_token("1.2.3.0400", true).unwrap_err().1.contains("leading zero"));
+        assert!(parse_ipv4_token("1.2.3.256", true).unwrap_err().1.contains("larger than 255"));
+        assert!(parse_ipv4_token("1.2.3", true).unwrap_err().1.contains("expected four octets"));
+        assert!(parse_ipv4_token("1.2.3.4.5", true).unwrap_err().1.contains("more than four"));
+        assert!(parse_ipv4_token("1.2.3.4.5", false).unwrap_err().1.contains("more than four"));
+    }
+
+    fn parse_v6(line: &str, map_v6: MapV6) -> ParsedLine {
+        let options = ParseOptions {
+            map_v6,
+            ..ParseOptions::default()
+        };
+        parse_line(line, &options)
+    }
+
+    fn is_ipv6_reject(parsed: &ParsedLine) -> bool {
+        matches!(parsed, ParsedLine::Rejected(RejectReason::Ipv6, _))
+    }
+
+    #[test]
+    fn test_ipv6_addresses_are_rejected_by_default() {
+        for token in ["2001:db8::1", "::1", "[::1]:443", "[2001:db8::1]", "fe80::1%eth0", "2001:db8::/32", "::ffff:10.0.0.1"] {
+            assert!(is_ipv6_reject(&parse_v6(token, MapV6::Off)), "{}", token);
+        }
+        // An IPv4 address with a port is not mistaken for IPv6
+        assert!(matches!(
+            parse_v6("10.0.0.1:80", MapV6::Off),
+            ParsedLine::Rejected(RejectReason::InvalidIp, _)
+        ));
+    }
+
+    #[test]
+    fn test_mapped_ipv6_addresses_are_plotted() {
+        let mapped = |token: &str| match parse_v6(token, MapV6::Mapped) {
+            ParsedLine::Record(record) => Some(record),
+            _ => None,
+        };
+        assert_eq!(mapped("::ffff:10.0.0.1 5").unwrap().net, "10.0.0.1/32".parse().unwrap());
+        assert_eq!(mapped("::FFFF:a00:1").unwrap().net, "10.0.0.1/32".parse().unwrap());
+        assert_eq!(mapped("[::ffff:10.0.0.1]:443").unwrap().net, "10.0.0.1/32".parse().unwrap());
+        assert_eq!(mapped("::ffff:10.0.0.0/104").unwrap().net, "10.0.0.0/8".parse().unwrap());
+        // Pure IPv6 is still rejected
+        assert!(is_ipv6_reject(&parse_v6("2001:db8::1", MapV6::Mapped)));
+        assert!(is_ipv6_reject(&parse_v6("[::1]:443", MapV6::Mapped)));
+    }
+
+    #[test]
+    fn test_in_addr_names_are_reversed() {
+        let net = |name: &str| parse_in_addr(name).map(|net| net.to_string());
+        assert_eq!(net("4.3.2.1.in-addr.arpa"), Ok("1.2.3.4/32".to_string()));
+        assert_eq!(net("4.3.2.1.in-addr.arpa."), Ok("1.2.3.4/32".to_string()));
+        assert_eq!(net("0.2.0.192.IN-ADDR.ARPA"), Ok("192.0.2.0/32".to_string()));
+        assert_eq!(net("2.0.192.in-addr.arpa"), Ok("192.0.2.0/24".to_string()));
+        assert_eq!(net("2.1.in-addr.arpa"), Ok("1.2.0.0/16".to_string()));
+        assert_eq!(net("10.in-addr.arpa."), Ok("10.0.0.0/8".to_string()));
+        for nonsense in [
+            "in-addr.arpa",
+            "5.4.3.2.1.in-addr.arpa",
+            "256.2.0.192.in-addr.arpa",
+            "0/26.2.0.192.in-addr.arpa",
+            "01.2.0.192.in-addr.arpa",
+            "x.2.0.192.in-addr.arpa",
+            ".2.0.192.in-addr.arpa",
+            "1..192.in-addr.arpa",
+            "4.3.2.1.ip6.arpa",
+        ] {
+            assert!(net(nonsense).is_err(), "{} was accepted", nonsense);
+        }
+    }
+
+    #[test]
+    fn test_in_addr_names_are_plotted() {
+        let options = ParseOptions::default();
+        match parse_line("4.3.2.1.in-addr.arpa. 7 PTR host.example.com.", &options) {
+            ParsedLine::Record(record) => assert_eq!((record.net, record.value), ("1.2.3.4/32".parse().unwrap(), 7)),
+            _ => panic!("the name was not plotted"),
+        }
+        match parse_line("2.1.in-addr.arpa", &options) {
+            ParsedLine::Record(record) => assert_eq!(record.net, "1.2.0.0/16".parse().unwrap()),
+            _ => panic!("the zone was not plotted"),
+        }
+        assert!(matches!(
+            parse_line("0/26.2.0.192.in-addr.arpa 3", &options),
+            ParsedLine::Rejected(RejectReason::InvalidInAddr, _)
+        ));
+        assert!(matches!(parse_line("in-addr.arpa 3", &options), ParsedLine::Rejected(RejectReason::InvalidIp, _)));
+    }
+
+    #[test]
+    fn test_cidr_host_bits() {
+        let parse = |token: &str, cidr_host_bits| {
+            let options = ParseOptions {
+                cidr_host_bits,
+                ..ParseOptions::default()
+            };
+            parse_line(token, &options)
+        };
+        for policy in [CidrHostBits::Warn, CidrHostBits::Allow] {
+            match parse("10.1.2.3/16", policy) {
+                ParsedLine::Record(record) => {
+                    assert!(record.has_host_bits());
+                    assert_eq!(record.net.network(), Ipv4Addr::new(10, 1, 0, 0));
+                }
+                _ => panic!("host bits are accepted with {}", policy),
+            }
+        }
+        assert!(matches!(
+            parse("10.1.2.3/16", CidrHostBits::Reject),
+            ParsedLine::Rejected(RejectReason::CidrHostBits, message) if message.contains("10.1.0.0/16")
+        ));
+        // Prefixes without host bits and single addresses are never affected
+        for token in ["10.1.0.0/16", "10.1.2.3", "10.1.2.3/32"] {
+            match parse(token, CidrHostBits::Reject) {
+                ParsedLine::Record(record) => assert!(!record.has_host_bits()),
+                _ => panic!("{} is accepted", token),
+            }
+        }
+    }
+
+    #[test]
+    fn test_brace_groups_expand_to_records() {
+        let options = ParseOptions {
+            expand_braces: true,
+            ..ParseOptions::default()
+        };
+        let nets = |line: &str| match parse_line(line, &options) {
+            ParsedLine::Records(records) => {
+                records.iter().map(|record| (record.net.to_string(), record.value)).collect::<Vec<_>>()
+            }
+            _ => panic!("{} did not expand", line),
+        };
+        // The list's commas are not field separators, and every item shares the value
+        assert_eq!(
+            nets("192.0.2.{1,5,9},7"),
+            [("192.0.2.1/32".to_string(), 7), ("192.0.2.5/32".to_string(), 7), ("192.0.2.9/32".to_string(), 7)]
+        );
+        let expanded = nets("10.20.{0-255}.0/24 3");
+        assert_eq!(expanded.len(), 256);
+        assert_eq!(expanded[255], ("10.20.255.0/24".to_string(), 3));
+        let tab_separated = ParseOptions { separator: Some('\t'), ..options.clone() };
+        assert!(matches!(parse_line("10.0.{1, 2}.0/24\t4", &tab_separated), ParsedLine::Records(r) if r.len() == 2));
+        // Lines without a group parse as usual
+        assert!(matches!(parse_line("10.0.0.1,5", &options), ParsedLine::Record(_)));
+
+        for (line, reason, error) in [
+            ("10.{0-{1-2}}.0.0", RejectReason::InvalidBraces, "nested"),
+            ("10.{1,2.0.0", RejectReason::InvalidBraces, "without '}'"),
+            ("10.0.0.{0-65536}", RejectReason::InvalidBraces, "more than 65536"),
+            ("10.0.0.{1,256}", RejectReason::InvalidIp, "10.0.0.256"),
+            ("10.0.0.0/{8,33}", RejectReason::InvalidCidr, "10.0.0.0/33"),
+        ] {
+            match parse_line(line, &options) {
+                ParsedLine::Rejected(rejected, message) => {
+                    assert_eq!(rejected, reason, "{}", line);
+                    assert!(message.contains(error), "{}: {}", line, message);
+                }
+                _ => panic!("{} was not rejected", line),
+            }
+        }
+        // Without the option braces are not special
+        assert!(matches!(
+            parse_line("192.0.2.{1,5}", &ParseOptions::default()),
+            ParsedLine::Rejected(RejectReason::InvalidIp, _)
+        ));
+    }
+
+    #[test]
+    fn test_scaled_values_are_spread_over_partial_pixels() {
+        let mut painted = Vec::new();
+        let half_pixel = record("10.0.0.0/25 256");
+        for_each_pixel(8, ValueMode::Scaled, &half_pixel, |d, value| painted.push((d, value)));
+        assert_eq!(painted, vec![(0x0a0000, 128)]);
+    }
+
+    #[test]
+    fn test_sampling_is_deterministic() {
+        let sampling = Sampling::new(0.25, 42).unwrap();
+        let kept: Vec<usize> = (1..=10_000).filter(|&n| sampling.keeps(n)).collect();
+        assert!((2300..2700).contains(&kept.len()), "{}", kept.len());
+        assert_eq!(kept, (1..=10_000).filter(|&n| Sampling::new(0.25, 42).unwrap().keeps(n)).collect::<Vec<_>>());
+        let other_seed = Sampling::new(0.25, 43).unwrap();
+        assert_ne!(kept, (1..=10_000).filter(|&n| other_seed.keeps(n)).collect::<Vec<_>>());
+
+        assert!((1..=1000).all(|n| Sampling::new(1.0, 0).unwrap().keeps(n)));
+        for rate in [0.0, -0.5, 1.5, f64::NAN] {
+            assert!(Sampling::new(rate, 0).is_err(), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn test_unsampled_lines_keep_their_numbers() {
+        let options = ParseOptions {
+            sampling: Some(Sampling::new(0.5, 7).unwrap()),
+            ..ParseOptions::default()
+        };
+        let input = "10.0.0.1\n".repeat(100);
+        let mut numbers = Vec::new();
+        for_each_line(input.as_bytes(), 0, &options, &PhaseTimer::default(), |number, _, parsed| {
+            if !matches!(parsed, ParsedLine::Unsampled) {
+                numbers.push(number);
+            }
+            Ok(())
+        })
+        .unwrap();
+        let sampling = options.sampling.unwrap();
+        assert_eq!(numbers, (1..=100).filter(|&n| sampling.keeps(n)).collect::<Vec<_>>());
+    }
+}
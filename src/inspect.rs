@@ -0,0 +1,368 @@
+//! Identifying files ip-heatmap wrote: PNG images with their parameters, state files
+//! and raw-u32v records.
+
+use crate::json::JsonValue;
+use crate::raw::RAW_MAGIC;
+use crate::state::{StateHeader, state_file_len};
+use anyhow::{Context, Result, bail};
+use std::io::{Read, Seek, SeekFrom};
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+const STATE_MAGIC: &[u8; 8] = b"IPHMSTAT";
+const RAW_RECORD_LEN: u64 = 9;
+
+/// What a file turned out to be.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inspection {
+    Png(PngInfo),
+    State(StateHeader),
+    /// A raw-u32v file with this many records.
+    Raw { records: u64 },
+}
+
+/// The header and text chunks of a PNG file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PngInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub colour_type: u8,
+    /// Keywords and texts of the tEXt and uncompressed iTXt chunks, in file order.
+    pub text: Vec<(String, String)>,
+    /// Keywords of compressed text chunks, which are not decoded.
+    pub compressed_text: Vec<String>,
+}
+
+impl PngInfo {
+    fn colour_type_name(&self) -> &'static str {
+        match self.colour_type {
+            0 => "grayscale",
+            2 => "rgb",
+            3 => "indexed",
+            4 => "grayscale-alpha",
+            6 => "rgba",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Identify the file at `path`, see [`inspect`].
+pub fn inspect_file(path: &str) -> Result<Inspection> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    inspect(std::io::BufReader::new(file)).with_context(|| format!("Failed to inspect {}", path))
+}
+
+/// Identify `reader` from its first bytes and describe it, failing with what is wrong
+/// for corrupt or truncated files. State files are checked against the length their
+/// header implies without reading the buffer.
+pub fn inspect<R: Read + Seek>(mut reader: R) -> Result<Inspection> {
+    let mut magic = Vec::with_capacity(8);
+    (&mut reader).take(8).read_to_end(&mut magic).context("Failed to read the file")?;
+    if magic.is_empty() {
+        bail!("The file is empty");
+    }
+    if magic[..] == PNG_SIGNATURE[..] {
+        return inspect_png(reader).map(Inspection::Png);
+    }
+    if magic.len() < 8 {
+        for (start, kind) in [(&PNG_SIGNATURE[..], "PNG"), (&STATE_MAGIC[..], "state file"), (&RAW_MAGIC[..], "raw-u32v file")] {
+            if start.starts_with(&magic) {
+                bail!("Truncated {}: the file ends inside its first 8 bytes", kind);
+            }
+        }
+    }
+    let len = reader.seek(SeekFrom::End(0)).context("Failed to find the file length")?;
+    if magic[..] == STATE_MAGIC[..] {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = StateHeader::read(&mut reader)?;
+        let expected = state_file_len(header.bits_per_pixel);
+        if len < expected {
+            bail!(
+                "Truncated state file: {} of {} bytes, the buffer for bits_per_pixel {} is incomplete",
+                len,
+                expected,
+                header.bits_per_pixel
+            );
+        }
+        if len > expected {
+            bail!("State file has {} bytes after the buffer", len - expected);
+        }
+        return Ok(Inspection::State(header));
+    }
+    if magic[..] == RAW_MAGIC[..] {
+        let body = len - RAW_MAGIC.len() as u64;
+        if !body.is_multiple_of(RAW_RECORD_LEN) {
+            bail!(
+                "Truncated raw-u32v file: {} bytes after the last whole record",
+                body % RAW_RECORD_LEN
+            );
+        }
+        return Ok(Inspection::Raw { records: body / RAW_RECORD_LEN });
+    }
+    let start: Vec<String> = magic.iter().map(|byte| format!("{:02x}", byte)).collect();
+    bail!("Not a PNG image, state file or raw-u32v file (starts with {})", start.join(" "))
+}
+
+/// Walk the chunks after the signature, checking their CRCs, up to IEND.
+fn inspect_png<R: Read>(mut reader: R) -> Result<PngInfo> {
+    let mut info: Option<PngInfo> = None;
+    let mut offset = PNG_SIGNATURE.len() as u64;
+    loop {
+        let mut head = [0u8; 8];
+        let read = read_up_to(&mut reader, &mut head)?;
+        if read == 0 {
+            bail!("Truncated PNG: no IEND chunk after {} bytes", offset);
+        }
+        if read < head.len() {
+            bail!("Truncated PNG: the chunk header at offset {} is incomplete", offset);
+        }
+        let length = u32::from_be_bytes(head[..4].try_into().expect("4 bytes")) as usize;
+        let kind = String::from_utf8_lossy(&head[4..]).into_owned();
+        if !head[4..].iter().all(u8::is_ascii_alphabetic) {
+            bail!("Corrupt PNG: invalid chunk type {:?} at offset {}", kind, offset);
+        }
+        // Read through `take` so a corrupt length only allocates what the file holds
+        let mut data = Vec::new();
+        (&mut reader)
+            .take(length as u64 + 4)
+            .read_to_end(&mut data)
+            .context("Failed to read the file")?;
+        if data.len() < length + 4 {
+            bail!(
+                "Truncated PNG: chunk {} at offset {} needs {} bytes, {} remain",
+                kind,
+                offset,
+                length + 4,
+                data.len()
+            );
+        }
+        let stored = u32::from_be_bytes(data[length..].try_into().expect("4 bytes"));
+        let data = &data[..length];
+        if crc32(&head[4..], data) != stored {
+            bail!("Corrupt PNG: CRC mismatch in chunk {} at offset {}", kind, offset);
+        }
+        offset += 12 + length as u64;
+
+        match (kind.as_str(), &mut info) {
+            ("IHDR", None) => {
+                if length != 13 {
+                    bail!("Corrupt PNG: IHDR is {} bytes, expected 13", length);
+                }
+                info = Some(PngInfo {
+                    width: u32::from_be_bytes(data[..4].try_into().expect("4 bytes")),
+                    height: u32::from_be_bytes(data[4..8].try_into().expect("4 bytes")),
+                    bit_depth: data[8],
+                    colour_type: data[9],
+                    text: Vec::new(),
+                    compressed_text: Vec::new(),
+                });
+            }
+            (_, None) => bail!("Corrupt PNG: the first chunk is {}, not IHDR", kind),
+            ("IHDR", Some(_)) => bail!("Corrupt PNG: a second IHDR chunk at offset {}", offset - 12 - length as u64),
+            ("tEXt", Some(info)) => {
+                let (keyword, text) = split_keyword(data, &kind)?;
+                info.text.push((keyword, latin1(text)));
+            }
+            ("zTXt", Some(info)) => info.compressed_text.push(split_keyword(data, &kind)?.0),
+            ("iTXt", Some(info)) => {
+                let (keyword, rest) = split_keyword(data, &kind)?;
+                // Compression flag and method, then the language tag and translated keyword
+                let [compressed, _, rest @ ..] = rest else {
+                    bail!("Corrupt PNG: iTXt chunk {} is truncated", keyword);
+                };
+                if *compressed != 0 {
+                    info.compressed_text.push(keyword);
+                    continue;
+                }
+                let mut fields = rest.splitn(3, |&byte| byte == 0);
+                let (Some(_), Some(_), Some(text)) = (fields.next(), fields.next(), fields.next()) else {
+                    bail!("Corrupt PNG: iTXt chunk {} is truncated", keyword);
+                };
+                info.text.push((keyword, String::from_utf8_lossy(text).into_owned()));
+            }
+            ("IEND", Some(_)) => return Ok(info.expect("IHDR was read")),
+            _ => {}
+        }
+    }
+}
+
+/// Split a text chunk at the NUL after its keyword.
+fn split_keyword<'a>(data: &'a [u8], kind: &str) -> Result<(String, &'a [u8])> {
+    match data.iter().position(|&byte| byte == 0) {
+        Some(end) => Ok((latin1(&data[..end]), &data[end + 1..])),
+        None => bail!("Corrupt PNG: {} chunk without a keyword separator", kind),
+    }
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+/// Fill `buffer` unless the input ends first, returning the bytes read.
+fn read_up_to<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(length) => read += length,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).context("Failed to read the file"),
+        }
+    }
+    Ok(read)
+}
+
+/// The CRC-32 of a chunk's type and data, as PNG defines it.
+fn crc32(kind: &[u8], data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in kind.iter().chain(data) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+impl Inspection {
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::object();
+        match self {
+            Inspection::Png(info) => {
+                json.insert("type", "png");
+                json.insert("width", info.width);
+                json.insert("height", info.height);
+                json.insert("bit_depth", info.bit_depth);
+                json.insert("colour_type", info.colour_type_name());
+                let mut text = JsonValue::object();
+                for (keyword, value) in &info.text {
+                    text.insert(keyword, value.as_str());
+                }
+                json.insert("text", text);
+                let compressed: Vec<JsonValue> = info.compressed_text.iter().map(|k| k.as_str().into()).collect();
+                json.insert("compressed_text", compressed);
+            }
+            Inspection::State(header) => {
+                json.insert("type", "state");
+                json.insert("version", header.version);
+                json.insert("bits_per_pixel", header.bits_per_pixel);
+                json.insert("value_mode", header.value_mode.to_string());
+                json.insert("accumulate", header.accumulate);
+                json.insert("lines", header.lines);
+                json.insert("nonzero_cells", header.nonzero_cells);
+                json.insert("min_value", header.min_value as i64);
+                json.insert("max_value", header.max_value as i64);
+                json.insert("total", header.total);
+                json.insert("touched_pixels", header.touched_pixels);
+            }
+            Inspection::Raw { records } => {
+                json.insert("type", "raw-u32v");
+                json.insert("records", *records);
+            }
+        }
+        json
+    }
+}
+
+impl std::fmt::Display for Inspection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inspection::Png(info) => {
+                writeln!(f, "type:           png")?;
+                writeln!(f, "dimensions:     {}x{}", info.width, info.height)?;
+                write!(f, "colour:         {} {}-bit", info.colour_type_name(), info.bit_depth)?;
+                if info.text.is_empty() && info.compressed_text.is_empty() {
+                    write!(f, "\nno parameters (not written by ip-heatmap, or stripped)")?;
+                }
+                for (keyword, text) in &info.text {
+                    write!(f, "\n{:<15} {}", format!("{}:", keyword), text)?;
+                }
+                for keyword in &info.compressed_text {
+                    write!(f, "\n{:<15} (compressed, not shown)", format!("{}:", keyword))?;
+                }
+                Ok(())
+            }
+            Inspection::State(header) => {
+                writeln!(f, "type:           state (version {})", header.version)?;
+                writeln!(f, "bits_per_pixel: {}", header.bits_per_pixel)?;
+                writeln!(f, "value_mode:     {}", header.value_mode)?;
+                writeln!(f, "accumulate:     {}", header.accumulate)?;
+                writeln!(f, "lines:          {}", header.lines)?;
+                writeln!(f, "nonzero cells:  {}", header.nonzero_cells)?;
+                writeln!(f, "touched pixels: {}", header.touched_pixels)?;
+                writeln!(f, "value range:    {} to {}", header.min_value, header.max_value)?;
+                write!(f, "total:          {}", header.total)
+            }
+            Inspection::Raw { records } => {
+                writeln!(f, "type:           raw-u32v")?;
+                write!(f, "records:        {}", records)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, Heatmap, ValueMode};
+    use std::io::Cursor;
+
+    fn png_with_text() -> Vec<u8> {
+        let image = image::RgbaImage::new(4, 2);
+        let metadata = vec![("curve".to_string(), "log".to_string()), ("bits_per_pixel".to_string(), "16".to_string())];
+        let mut bytes = Vec::new();
+        crate::output::write_png(&mut bytes, &image, &metadata, &Default::default()).unwrap();
+        bytes
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        format!("{:#}", inspect(Cursor::new(bytes)).unwrap_err())
+    }
+
+    #[test]
+    fn test_png() {
+        let bytes = png_with_text();
+        let Inspection::Png(info) = inspect(Cursor::new(&bytes)).unwrap() else {
+            panic!("not a PNG");
+        };
+        assert_eq!((info.width, info.height, info.colour_type_name()), (4, 2, "rgba"));
+        assert_eq!(info.text[0], ("curve".to_string(), "log".to_string()));
+        assert_eq!(info.text.len(), 2);
+
+        assert!(error(&bytes[..bytes.len() - 12]).contains("no IEND chunk"));
+        assert!(error(&bytes[..45]).contains("chunk tEXt at offset 33 needs"));
+        assert!(error(&bytes[..5]).contains("Truncated PNG: the file ends inside"));
+        let mut corrupt = bytes.clone();
+        corrupt[20] ^= 1;
+        assert!(error(&corrupt).contains("CRC mismatch in chunk IHDR"));
+    }
+
+    #[test]
+    fn test_state() {
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.process_input_from_string("10.0.0.1 5\n10.1.0.0/16 3\n").unwrap();
+        let mut bytes = Vec::new();
+        heatmap.write_state(&mut bytes).unwrap();
+        let Inspection::State(header) = inspect(Cursor::new(&bytes)).unwrap() else {
+            panic!("not a state file");
+        };
+        assert_eq!((header.bits_per_pixel, header.min_value, header.max_value, header.total), (16, 0, 5, 8));
+
+        assert!(error(&bytes[..bytes.len() - 1]).contains("Truncated state file"));
+        assert!(error(&bytes[..20]).contains("header"));
+        assert!(error(&bytes[..4]).contains("Truncated state file"));
+        bytes.push(0);
+        assert!(error(&bytes).contains("1 bytes after the buffer"));
+    }
+
+    #[test]
+    fn test_other_files() {
+        let mut raw = RAW_MAGIC.to_vec();
+        raw.extend([0; 18]);
+        assert_eq!(inspect(Cursor::new(&raw)).unwrap(), Inspection::Raw { records: 2 });
+        raw.pop();
+        assert!(error(&raw).contains("Truncated raw-u32v"));
+        assert_eq!(error(b""), "The file is empty");
+        assert!(error(b"10.0.0.1 5\n").contains("starts with 31 30 2e 30"));
+    }
+}
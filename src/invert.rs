@@ -0,0 +1,111 @@
+use crate::{Heatmap, ValueMode};
+use anyhow::{Result, bail};
+
+impl Heatmap {
+    /// Turn the map inside out so unpainted space stands out: each cell becomes
+    /// `max - value`, where `max` is the largest painted value (at least 1) and
+    /// unpainted cells count as 0.
+    ///
+    /// Unpainted cells end up at `max`, the top of the automatic colour domain, and
+    /// the hottest painted cells at 0, which is background. The touched mask follows
+    /// the new values: a cell counts as painted when it is non-zero. Returns the
+    /// number of cells that were unpainted before.
+    pub fn invert(&mut self) -> Result<u64> {
+        if self.value_mode == ValueMode::Categorical {
+            bail!("Categorical values cannot be inverted");
+        }
+        let max = self
+            .buffer
+            .cells()
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| self.touched[index / 64] & (1 << (index % 64)) != 0)
+            .map(|(_, &value)| value)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let mut unpainted = 0;
+        for (index, cell) in self.buffer.cells_mut().iter_mut().enumerate() {
+            let bit = 1 << (index % 64);
+            if self.touched[index / 64] & bit == 0 {
+                unpainted += 1;
+                *cell = 0;
+            }
+            *cell = max.saturating_sub(*cell);
+            match *cell {
+                0 => self.touched[index / 64] &= !bit,
+                _ => self.touched[index / 64] |= bit,
+            }
+        }
+        Ok(unpainted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+    use std::net::Ipv4Addr;
+
+    fn heatmap(input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    fn cell(hm: &Heatmap, addr: [u8; 4]) -> i32 {
+        let (x, y) = hm.ip_to_xy(u32::from(Ipv4Addr::from(addr))).unwrap();
+        hm.buffer[y as usize][x as usize]
+    }
+
+    #[test]
+    fn test_half_painted_space() {
+        // The lower half of the address space is painted, hottest in 10.0.0.0/8
+        let mut hm = heatmap("0.0.0.0/1 2\n10.0.0.0/8 6\n");
+        assert_eq!(hm.touched_pixels(), 32768);
+        assert_eq!(hm.invert().unwrap(), 32768);
+        assert_eq!(cell(&hm, [200, 0, 0, 0]), 8);
+        assert_eq!(cell(&hm, [1, 0, 0, 0]), 6);
+        assert_eq!(cell(&hm, [10, 0, 0, 0]), 0);
+        // Everything but the hottest prefix is painted now
+        assert_eq!(hm.touched_pixels(), 65536 - 256);
+
+        let options = hm.render_options();
+        assert_eq!(hm.domain_bounds(&options), (0.0, 8.0));
+        let image = hm.render(&options).unwrap();
+        let pixel = |addr: [u8; 4]| {
+            let (x, y) = hm.ip_to_xy(u32::from(Ipv4Addr::from(addr))).unwrap();
+            *image.get_pixel(x, y)
+        };
+        assert_eq!(pixel([200, 0, 0, 0]).0[3], 255, "unpainted space glows");
+        assert_eq!(pixel([10, 0, 0, 0]).0[3], 0, "the hottest cells are background");
+    }
+
+    #[test]
+    fn test_empty_and_categorical() {
+        let mut empty = heatmap("");
+        assert_eq!(empty.invert().unwrap(), 65536);
+        assert_eq!(empty.value_range(), (1, 1));
+
+        let mut categorical = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            false,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Categorical,
+            None,
+        );
+        assert!(categorical.invert().is_err());
+    }
+}
@@ -0,0 +1,417 @@
+use std::fmt::{Display, Write};
+
+/// A minimal JSON value, used for machine-readable reports.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    /// Object members, serialised in insertion order.
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    /// An empty object, to be filled with [`JsonValue::insert`].
+    pub fn object() -> Self {
+        JsonValue::Object(Vec::new())
+    }
+
+    /// Add a member to an object. Has no effect on other values.
+    pub fn insert(&mut self, key: &str, value: impl Into<JsonValue>) {
+        if let JsonValue::Object(members) = self {
+            members.push((key.to_string(), value.into()));
+        }
+    }
+
+    /// Look up a member of an object.
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for JsonValue {
+    fn from(value: bool) -> Self {
+        JsonValue::Bool(value)
+    }
+}
+
+impl From<i64> for JsonValue {
+    fn from(value: i64) -> Self {
+        JsonValue::Int(value)
+    }
+}
+
+impl From<u64> for JsonValue {
+    fn from(value: u64) -> Self {
+        JsonValue::Int(value as i64)
+    }
+}
+
+impl From<u32> for JsonValue {
+    fn from(value: u32) -> Self {
+        JsonValue::Int(value as i64)
+    }
+}
+
+impl From<u8> for JsonValue {
+    fn from(value: u8) -> Self {
+        JsonValue::Int(value as i64)
+    }
+}
+
+impl From<usize> for JsonValue {
+    fn from(value: usize) -> Self {
+        JsonValue::Int(value as i64)
+    }
+}
+
+impl From<f64> for JsonValue {
+    fn from(value: f64) -> Self {
+        JsonValue::Float(value)
+    }
+}
+
+impl From<&str> for JsonValue {
+    fn from(value: &str) -> Self {
+        JsonValue::String(value.to_string())
+    }
+}
+
+impl From<String> for JsonValue {
+    fn from(value: String) -> Self {
+        JsonValue::String(value)
+    }
+}
+
+impl<T: Into<JsonValue>> From<Option<T>> for JsonValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(JsonValue::Null, Into::into)
+    }
+}
+
+impl<T: Into<JsonValue>> From<Vec<T>> for JsonValue {
+    fn from(values: Vec<T>) -> Self {
+        JsonValue::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl Display for JsonValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(value) => write!(f, "{}", value),
+            JsonValue::Int(value) => write!(f, "{}", value),
+            // JSON has no representation for NaN or infinities
+            JsonValue::Float(value) if !value.is_finite() => write!(f, "null"),
+            JsonValue::Float(value) => write!(f, "{}", value),
+            JsonValue::String(value) => write_string(f, value),
+            JsonValue::Array(values) => {
+                f.write_char('[')?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_char(']')
+            }
+            JsonValue::Object(members) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for JsonValue {
+    type Err = String;
+
+    /// Parse one JSON document. Numbers without a fraction or exponent that fit an
+    /// i64 are [`JsonValue::Int`], others [`JsonValue::Float`].
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        match parser.pos == text.len() {
+            true => Ok(value),
+            false => Err(parser.error("trailing characters")),
+        }
+    }
+}
+
+/// Nesting deeper than this is refused rather than overflowing the stack.
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, what: &str) -> String {
+        format!("Invalid JSON at byte {}: {}", self.pos, what)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.peek() == Some(byte) {
+            true => {
+                self.pos += 1;
+                Ok(())
+            }
+            false => Err(self.error(&format!("expected '{}'", byte as char))),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<JsonValue, String> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value(depth + 1)?));
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(JsonValue::Object(members));
+                        }
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Array(values));
+                }
+                loop {
+                    values.push(self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(JsonValue::Array(values));
+                        }
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some(b'"') => Ok(JsonValue::String(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => {
+                for (word, value) in [("null", JsonValue::Null), ("true", JsonValue::Bool(true)), ("false", JsonValue::Bool(false))] {
+                    if self.text[self.pos..].starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+        let mut string = String::new();
+        loop {
+            let rest = &self.text[self.pos..];
+            let Some(end) = rest.find(['"', '\\']) else {
+                return Err(self.error("unterminated string"));
+            };
+            if rest[..end].chars().any(|c| (c as u32) < 0x20) {
+                return Err(self.error("control character in string"));
+            }
+            string.push_str(&rest[..end]);
+            self.pos += end + 1;
+            if rest.as_bytes()[end] == b'"' {
+                return Ok(string);
+            }
+            let escaped = match self.peek() {
+                Some(b'"') => '"',
+                Some(b'\\') => '\\',
+                Some(b'/') => '/',
+                Some(b'b') => '\u{8}',
+                Some(b'f') => '\u{c}',
+                Some(b'n') => '\n',
+                Some(b'r') => '\r',
+                Some(b't') => '\t',
+                Some(b'u') => {
+                    self.pos += 1;
+                    let high = self.hex4()?;
+                    let code = match high {
+                        0xd800..=0xdbff if self.text[self.pos..].starts_with("\\u") => {
+                            self.pos += 2;
+                            let low = self.hex4()?;
+                            if !(0xdc00..=0xdfff).contains(&low) {
+                                return Err(self.error("unpaired surrogate"));
+                            }
+                            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+                        }
+                        code => code,
+                    };
+                    string.push(char::from_u32(code).ok_or_else(|| self.error("unpaired surrogate"))?);
+                    continue;
+                }
+                _ => return Err(self.error("invalid escape")),
+            };
+            string.push(escaped);
+            self.pos += 1;
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.pos..self.pos + 4).ok_or_else(|| self.error("short \\u escape"))?;
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(self.error("invalid \\u escape"));
+        }
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).expect("hex digits"))
+    }
+
+    fn number(&mut self) -> Result<JsonValue, String> {
+        let start = self.pos;
+        let bytes = self.text.as_bytes();
+        let digits = |pos: &mut usize| {
+            let from = *pos;
+            while bytes.get(*pos).is_some_and(u8::is_ascii_digit) {
+                *pos += 1;
+            }
+            *pos > from
+        };
+        let mut pos = start + usize::from(bytes[start] == b'-');
+        let leading_zero = bytes.get(pos) == Some(&b'0');
+        let mut valid = digits(&mut pos) && !(leading_zero && pos - start > 1 + usize::from(bytes[start] == b'-'));
+        let mut integer = true;
+        if valid && bytes.get(pos) == Some(&b'.') {
+            pos += 1;
+            valid = digits(&mut pos);
+            integer = false;
+        }
+        if valid && matches!(bytes.get(pos), Some(b'e' | b'E')) {
+            pos += 1;
+            if matches!(bytes.get(pos), Some(b'+' | b'-')) {
+                pos += 1;
+            }
+            valid = digits(&mut pos);
+            integer = false;
+        }
+        if !valid {
+            return Err(self.error("invalid number"));
+        }
+        let token = &self.text[start..pos];
+        self.pos = pos;
+        match token.parse::<i64>() {
+            Ok(int) if integer => Ok(JsonValue::Int(int)),
+            _ => Ok(JsonValue::Float(token.parse().expect("a JSON number is a float"))),
+        }
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, value: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialise_nested_values() {
+        let mut object = JsonValue::object();
+        object.insert("lines", 10u64);
+        object.insert("ratio", 0.5);
+        object.insert("name", "a \"quoted\"\ttab");
+        object.insert("missing", None::<u64>);
+        object.insert("list", vec![1u64, 2, 3]);
+        assert_eq!(
+            object.to_string(),
+            r#"{"lines":10,"ratio":0.5,"name":"a \"quoted\"\ttab","missing":null,"list":[1,2,3]}"#
+        );
+        assert_eq!(object.get("lines"), Some(&JsonValue::Int(10)));
+    }
+
+    #[test]
+    fn test_non_finite_floats_become_null() {
+        assert_eq!(JsonValue::Float(f64::NAN).to_string(), "null");
+        assert_eq!(JsonValue::Float(f64::INFINITY).to_string(), "null");
+    }
+
+    #[test]
+    fn test_parse_round_trips() {
+        let text = r#"{"lines":10,"ratio":-2.5e-1,"name":"a \"quoted\"\ttab \u00e9\ud83d\ude00","missing":null,"ok":true,"list":[1,[],{}]}"#;
+        let value: JsonValue = text.parse().unwrap();
+        assert_eq!(value.get("ratio"), Some(&JsonValue::Float(-0.25)));
+        assert_eq!(value.get("name"), Some(&JsonValue::String("a \"quoted\"\ttab \u{e9}\u{1f600}".to_string())));
+        assert_eq!(value.to_string().parse::<JsonValue>().unwrap(), value);
+        assert_eq!(" [ 1 , 2 ]\n".parse::<JsonValue>(), Ok(JsonValue::Array(vec![JsonValue::Int(1), JsonValue::Int(2)])));
+        assert_eq!("18446744073709551616".parse::<JsonValue>(), Ok(JsonValue::Float(18446744073709551616.0)));
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_documents() {
+        let deep = "[".repeat(MAX_DEPTH + 2) + &"]".repeat(MAX_DEPTH + 2);
+        for invalid in ["", "{", "{\"a\"}", "{\"a\":1,}", "[1 2]", "01", "1.", "-", "+1", "\"\\x\"", "\"\n\"", "\"\\ud800\"", "nul", "1 2", &deep] {
+            assert!(invalid.parse::<JsonValue>().is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn test_control_characters_are_escaped() {
+        assert_eq!(JsonValue::from("\u{1}").to_string(), r#""\u0001""#);
+    }
+}
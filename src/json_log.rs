@@ -0,0 +1,176 @@
+//! A `log` backend writing one JSON object per event, for runs whose stderr is
+//! collected by a log pipeline rather than read.
+
+use crate::json::JsonValue;
+use crate::timestamps::civil_from_days;
+use log::kv::{self, Key, Value, VisitSource, VisitValue};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Logs each record as `{"ts", "level", "msg", ...}` followed by the record's
+/// key-values, such as `line_number`, `reason` and `file` for rejected lines. Keys
+/// without a value are left out.
+pub struct JsonLogger<W: Write + Send> {
+    level: LevelFilter,
+    writer: Mutex<W>,
+}
+
+impl JsonLogger<std::io::Stderr> {
+    /// Install a logger writing to stderr as the global `log` backend.
+    pub fn init(level: LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(JsonLogger::new(level, std::io::stderr())))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> JsonLogger<W> {
+    pub fn new(level: LevelFilter, writer: W) -> Self {
+        Self { level, writer: Mutex::new(writer) }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<W: Write + Send> Log for JsonLogger<W> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = record_to_json(record, SystemTime::now());
+        if let Ok(mut writer) = self.writer.lock() {
+            // There is nowhere left to report a failure to log
+            let _ = writeln!(writer, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+/// The JSON object logged for `record` at time `now`.
+pub(crate) fn record_to_json(record: &Record, now: SystemTime) -> JsonValue {
+    let mut object = JsonValue::object();
+    object.insert("ts", timestamp(now));
+    object.insert("level", record.level().as_str());
+    object.insert("msg", record.args().to_string());
+    let _ = record.key_values().visit(&mut Members(&mut object));
+    object
+}
+
+struct Members<'a>(&'a mut JsonValue);
+
+impl<'kvs> VisitSource<'kvs> for Members<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut converted = JsonValue::Null;
+        value.visit(Converted(&mut converted))?;
+        if converted != JsonValue::Null {
+            self.0.insert(key.as_str(), converted);
+        }
+        Ok(())
+    }
+}
+
+struct Converted<'a>(&'a mut JsonValue);
+
+impl<'v> VisitValue<'v> for Converted<'_> {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        *self.0 = JsonValue::String(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        *self.0 = JsonValue::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        *self.0 = value.into();
+        Ok(())
+    }
+}
+
+/// `now` in RFC 3339 form in UTC, with milliseconds.
+fn timestamp(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let time = seconds % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::kv::ToValue;
+    use std::time::Duration;
+
+    #[test]
+    fn test_record_members() {
+        let file: Option<&str> = None;
+        let kvs: [(&str, Value); 3] = [
+            ("line_number", 7usize.to_value()),
+            ("reason", Value::from_display(&"invalid IP address")),
+            ("file", file.to_value()),
+        ];
+        let now = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let json = record_to_json(
+            &Record::builder().level(log::Level::Warn).args(format_args!("Failed to parse line {}", 7)).key_values(&kvs).build(),
+            now,
+        );
+        assert_eq!(
+            json.to_string(),
+            r#"{"ts":"2023-11-14T22:13:20.123Z","level":"WARN","msg":"Failed to parse line 7","line_number":7,"reason":"invalid IP address"}"#
+        );
+    }
+
+    #[test]
+    fn test_logger_writes_lines() {
+        let logger = JsonLogger::new(LevelFilter::Warn, Vec::new());
+        logger.log(&Record::builder().level(log::Level::Info).args(format_args!("quiet")).build());
+        logger.log(&Record::builder().level(log::Level::Warn).args(format_args!("say \"hi\"")).build());
+        let written = String::from_utf8(logger.into_inner()).unwrap();
+        assert_eq!(written.lines().count(), 1);
+        assert!(written.contains(r#""msg":"say \"hi\"""#), "{}", written);
+    }
+}
@@ -0,0 +1,275 @@
+//! Text over the pixels of a prefix, placed where the region has the most room.
+//!
+//! The region is the prefix's pixels within the view, which a crop may cut down to
+//! part of the prefix. Labels go at its pole of inaccessibility, taken as the centre
+//! of the largest square inside it nearest the centre of its bounds, so they stay on
+//! the region whatever its shape. Text too large for the region is drawn smaller,
+//! down to [`MIN_LABEL_SCALE`], and left out with a warning when even that does not fit.
+
+use crate::Heatmap;
+use crate::geometry::{PixelRect, blocks_for_prefix};
+use crate::text::{draw_text, fill_rect, text_height, text_width};
+use crate::theme::Theme;
+use image::RgbaImage;
+use ipnet::Ipv4Net;
+
+/// The smallest text scale a label is drawn at, that of the built-in font's pixels.
+pub const MIN_LABEL_SCALE: u32 = 1;
+
+/// Text drawn over the pixels of a prefix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    pub net: Ipv4Net,
+    pub text: String,
+}
+
+/// The pixels of a region as a grid of square cells within its bounds, each wholly
+/// inside or outside it, row by row from the top left.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Region {
+    bounds: PixelRect,
+    /// Side of a cell in pixels.
+    cell: u32,
+    columns: u32,
+    rows: u32,
+    inside: Vec<bool>,
+}
+
+impl Region {
+    /// The region made of `rects`, or `None` if there are none.
+    pub fn new(rects: &[PixelRect]) -> Option<Region> {
+        let bounds = rects.iter().copied().reduce(|bounds, rect| bounds.union(&rect))?;
+        // Cells as large as every edge allows, halved so squares can sit between them
+        let edges = rects
+            .iter()
+            .flat_map(|rect| [rect.x - bounds.x, rect.y - bounds.y, rect.width, rect.height])
+            .fold(bounds.width | bounds.height, |edges, edge| edges | edge);
+        let cell = ((1u32 << edges.trailing_zeros()) / 2).max(1);
+        let (columns, rows) = (bounds.width / cell, bounds.height / cell);
+        let mut inside = vec![false; columns as usize * rows as usize];
+        for rect in rects {
+            let (first_column, first_row) = ((rect.x - bounds.x) / cell, (rect.y - bounds.y) / cell);
+            for row in first_row..first_row + rect.height / cell {
+                let start = (row * columns + first_column) as usize;
+                inside[start..start + (rect.width / cell) as usize].fill(true);
+            }
+        }
+        Some(Region { bounds, cell, columns, rows, inside })
+    }
+
+    /// The pixels of `net` within `view` on `heatmap`'s map.
+    pub fn of_prefix(heatmap: &Heatmap, net: &Ipv4Net, view: Option<&Ipv4Net>) -> Option<Region> {
+        Region::new(&blocks_for_prefix(net, &heatmap.geometry(), view))
+    }
+
+    fn is_inside(&self, column: u32, row: u32) -> bool {
+        self.inside[(row * self.columns + column) as usize]
+    }
+
+    /// Whether every pixel of `rect` is in the region.
+    pub fn covers(&self, rect: &PixelRect) -> bool {
+        if rect.area() == 0 || self.bounds.intersect(rect) != Some(*rect) {
+            return false;
+        }
+        let columns = (rect.x - self.bounds.x) / self.cell..=(rect.x + rect.width - 1 - self.bounds.x) / self.cell;
+        let rows = (rect.y - self.bounds.y) / self.cell..=(rect.y + rect.height - 1 - self.bounds.y) / self.cell;
+        rows.into_iter().all(|row| columns.clone().all(|column| self.is_inside(column, row)))
+    }
+
+    /// The largest square inside the region, the one nearest the centre of its bounds
+    /// of those as large.
+    pub fn largest_square(&self) -> PixelRect {
+        // The side of the largest square ending at each cell, as its bottom right
+        let mut sides = vec![0u32; self.inside.len()];
+        let (mut best, mut best_distance) = (None, u64::MAX);
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                if !self.is_inside(column, row) {
+                    continue;
+                }
+                let index = (row * self.columns + column) as usize;
+                let side = match (column, row) {
+                    (0, _) | (_, 0) => 1,
+                    _ => {
+                        let columns = self.columns as usize;
+                        1 + sides[index - 1].min(sides[index - columns]).min(sides[index - columns - 1])
+                    }
+                };
+                sides[index] = side;
+                // Twice the offset of the square's centre from the centre of the bounds
+                let dx = (2 * (column + 1) - side) as i64 * self.cell as i64 - self.bounds.width as i64;
+                let dy = (2 * (row + 1) - side) as i64 * self.cell as i64 - self.bounds.height as i64;
+                let distance = (dx * dx + dy * dy) as u64;
+                let best_side = best.map_or(0, |(_, _, side)| side);
+                if side > best_side || (side == best_side && distance < best_distance) {
+                    (best, best_distance) = (Some((column + 1 - side, row + 1 - side, side)), distance);
+                }
+            }
+        }
+        let (column, row, side) = best.expect("a region has a cell inside it");
+        PixelRect {
+            x: self.bounds.x + column * self.cell,
+            y: self.bounds.y + row * self.cell,
+            width: side * self.cell,
+            height: side * self.cell,
+        }
+    }
+
+    /// Where text of `width` by `height` pixels goes: centred on the largest square,
+    /// or `None` if the region around it does not hold the text.
+    pub fn place(&self, width: u32, height: u32) -> Option<PixelRect> {
+        let pole = self.largest_square();
+        let (centre_x, centre_y) = (2 * pole.x + pole.width, 2 * pole.y + pole.height);
+        let text = PixelRect {
+            x: centre_x.checked_sub(width)? / 2,
+            y: centre_y.checked_sub(height)? / 2,
+            width,
+            height,
+        };
+        self.covers(&text).then_some(text)
+    }
+}
+
+impl Heatmap {
+    /// Draw `label` onto `image`, a rendering of this heatmap, on the pixels of its
+    /// prefix within `view`, at text `scale` or smaller where it does not fit.
+    ///
+    /// The text is in the foreground of `theme` on a box of its background, so it
+    /// reads over any heat; the box may reach a little past the region. Returns
+    /// whether the label was drawn, which it is not when it does not fit at
+    /// [`MIN_LABEL_SCALE`] or its prefix is out of view.
+    pub fn draw_label(&self, image: &mut RgbaImage, label: &Label, theme: &Theme, scale: u32, view: Option<&Ipv4Net>) -> bool {
+        let Some(region) = Region::of_prefix(self, &label.net, view) else {
+            return false;
+        };
+        let placed = (MIN_LABEL_SCALE..=scale.max(MIN_LABEL_SCALE))
+            .rev()
+            .find_map(|scale| Some((region.place(text_width(&label.text, scale), text_height(scale))?, scale)));
+        let Some((text, scale)) = placed else {
+            log::warn!("Label {:?} does not fit in {} even at the smallest text, leaving it out", label.text, label.net);
+            return false;
+        };
+        let (left, top, padding) = (text.x as i64, text.y as i64, scale as i64);
+        fill_rect(
+            image,
+            left - padding,
+            top - padding,
+            text.width + 2 * scale,
+            text.height + 2 * scale,
+            theme.background,
+        );
+        draw_text(image, left, top, &label.text, scale, theme.foreground);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> PixelRect {
+        PixelRect { x, y, width, height }
+    }
+
+    fn heatmap() -> Heatmap {
+        Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None)
+    }
+
+    /// The bounds of the pixels in `image` drawn in `theme`'s foreground.
+    fn ink(image: &RgbaImage, theme: &Theme) -> PixelRect {
+        let inked: Vec<(u32, u32)> =
+            image.enumerate_pixels().filter(|(_, _, p)| **p == theme.foreground).map(|(x, y, _)| (x, y)).collect();
+        let (min_x, max_x) = (inked.iter().map(|p| p.0).min().unwrap(), inked.iter().map(|p| p.0).max().unwrap());
+        let (min_y, max_y) = (inked.iter().map(|p| p.1).min().unwrap(), inked.iter().map(|p| p.1).max().unwrap());
+        rect(min_x, min_y, max_x - min_x + 1, max_y - min_y + 1)
+    }
+
+    #[test]
+    fn test_label_is_centred_on_its_prefix() {
+        let hm = heatmap();
+        let label = Label { net: "10.0.0.0/8".parse().unwrap(), text: "x".to_string() };
+        let mut image = RgbaImage::new(256, 256);
+        assert!(hm.draw_label(&mut image, &label, &Theme::LIGHT, 1, None));
+        let (x, y, width, height) = hm.prefix_rect(&label.net);
+        let inked = ink(&image, &Theme::LIGHT);
+        // The glyph's ink sits within a pixel of the centre of the prefix
+        assert!((2 * inked.x + inked.width - 1).abs_diff(2 * x + width - 1) <= 2, "{:?}", inked);
+        assert!((2 * inked.y + inked.height - 1).abs_diff(2 * y + height - 1) <= 4, "{:?}", inked);
+        // The box behind the text is the theme's background
+        assert_eq!(*image.get_pixel(inked.x - 1, inked.y), Theme::LIGHT.background);
+    }
+
+    #[test]
+    fn test_largest_square_of_an_l_shape() {
+        // A 64 pixel bar along the top with a 16 pixel wide arm down its left side
+        let region = Region::new(&[rect(0, 0, 64, 16), rect(0, 16, 16, 48)]).unwrap();
+        assert_eq!(region.cell, 8);
+        // The centre of the bounds, (32, 32), is outside the region
+        assert!(!region.covers(&rect(32, 32, 1, 1)));
+        let square = region.largest_square();
+        assert_eq!((square.width, square.height), (16, 16));
+        assert!(region.covers(&square));
+        // Of the 16 pixel squares, the middle of the bar and of the arm are nearest
+        // the centre, and the bar comes first
+        assert_eq!(square, rect(24, 0, 16, 16));
+        assert_eq!(region.place(40, 8), Some(rect(12, 4, 40, 8)));
+        // Text taller than the bar does not fit around its middle
+        assert_eq!(region.place(8, 20), None);
+        let thick = Region::new(&[rect(0, 0, 64, 32), rect(0, 32, 16, 32)]).unwrap();
+        assert_eq!(thick.largest_square(), rect(16, 0, 32, 32));
+        assert_eq!(thick.place(40, 8), Some(rect(12, 12, 40, 8)));
+    }
+
+    #[test]
+    fn test_largest_square_of_two_blocks() {
+        // The two blocks of an odd number of pixels make a rectangle twice as wide
+        // as high, whose largest squares slide along it
+        let region = Region::new(&[rect(32, 16, 16, 16), rect(48, 16, 16, 16)]).unwrap();
+        assert_eq!(region.largest_square(), rect(40, 16, 16, 16));
+        assert_eq!(region.place(30, 8), Some(rect(33, 20, 30, 8)));
+        assert_eq!(region.place(33, 8), None);
+        // Single pixels hold nothing larger than themselves
+        let pixel = Region::new(&[rect(3, 5, 1, 1)]).unwrap();
+        assert_eq!(pixel.place(1, 1), Some(rect(3, 5, 1, 1)));
+        assert_eq!(pixel.place(2, 1), None);
+        assert_eq!(Region::new(&[]), None);
+    }
+
+    #[test]
+    fn test_label_stays_within_a_clipped_prefix() {
+        let hm = heatmap();
+        let theme = Theme::LIGHT;
+        // A crop to 10.0.0.0/9 keeps the half of 10.0.0.0/8 in view
+        let (net, view): (Ipv4Net, Ipv4Net) = ("10.0.0.0/8".parse().unwrap(), "10.0.0.0/9".parse().unwrap());
+        let label = Label { net, text: "x".to_string() };
+        let visible = Region::of_prefix(&hm, &net, Some(&view)).unwrap();
+        assert_eq!(visible.bounds.area(), 128);
+        let mut image = RgbaImage::new(256, 256);
+        assert!(hm.draw_label(&mut image, &label, &theme, 1, Some(&view)));
+        assert!(visible.covers(&ink(&image, &theme)));
+        // Out of view, there is nowhere to draw
+        let mut image = RgbaImage::new(256, 256);
+        assert!(!hm.draw_label(&mut image, &label, &theme, 1, Some(&"192.0.0.0/8".parse().unwrap())));
+        assert!(image.pixels().all(|pixel| pixel.0[3] == 0));
+    }
+
+    #[test]
+    fn test_labels_shrink_to_fit_then_drop() {
+        let hm = heatmap();
+        let theme = Theme::LIGHT;
+        // 10.0.0.0/8 is 16 pixels square: "ab" is 22 pixels wide at scale 2, 11 at 1
+        let label = Label { net: "10.0.0.0/8".parse().unwrap(), text: "ab".to_string() };
+        let mut image = RgbaImage::new(256, 256);
+        assert!(hm.draw_label(&mut image, &label, &theme, 4, None));
+        let inked = ink(&image, &theme);
+        assert!(inked.width <= 11 && inked.height <= 8, "{:?}", inked);
+        let region = Region::of_prefix(&hm, &label.net, None).unwrap();
+        assert!(region.covers(&inked));
+        // Three letters are 17 pixels wide at the smallest scale
+        let long = Label { text: "abc".to_string(), ..label };
+        let mut image = RgbaImage::new(256, 256);
+        assert!(!hm.draw_label(&mut image, &long, &theme, 4, None));
+        assert!(image.pixels().all(|pixel| pixel.0[3] == 0));
+    }
+}
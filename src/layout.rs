@@ -0,0 +1,194 @@
+use crate::legend::Legend;
+use crate::text::{draw_text, text_height, text_width};
+use crate::theme::Theme;
+use image::{RgbaImage, imageops};
+
+/// An image with an optional title drawn above it.
+pub struct Panel {
+    pub image: RgbaImage,
+    pub title: Option<String>,
+}
+
+/// Arranges panels in a grid with titles and an optional shared legend below.
+pub struct Layout {
+    /// Space between panels and around the edges, in pixels.
+    pub gap: u32,
+    /// Integer text scale for titles and legend labels.
+    pub text_scale: u32,
+    /// Text scale for titles, which are then never shrunk to fit; defaults to
+    /// `text_scale`, shrinking titles wider than their panel.
+    pub title_scale: Option<u32>,
+    /// Number of panels per row.
+    pub columns: u32,
+    /// A line of small text right-aligned below everything else.
+    pub caption: Option<String>,
+    /// Colours of the canvas and text; the caption is muted.
+    pub theme: Theme,
+}
+
+impl Layout {
+    /// A layout with spacing and text sized relative to the panel size.
+    pub fn for_panel_size(panel_size: u32) -> Self {
+        Self {
+            gap: (panel_size / 32).max(8),
+            text_scale: (panel_size / 256).max(2),
+            title_scale: None,
+            columns: u32::MAX,
+            caption: None,
+            theme: Theme::default(),
+        }
+    }
+
+    /// Captions are drawn at half the text scale.
+    fn caption_scale(&self) -> u32 {
+        (self.text_scale / 2).max(1)
+    }
+
+    /// Compose `panels` (which must all have the same dimensions) into one image.
+    pub fn compose(&self, panels: &[Panel], legend: Option<&Legend>) -> RgbaImage {
+        let (mut canvas, ink) = self.compose_layers(panels, legend);
+        imageops::overlay(&mut canvas, &ink, 0, 0);
+        canvas
+    }
+
+    /// [`Layout::compose`] as two images of the same size: the background with the
+    /// panels, and the titles, legend and caption alone on a transparent canvas. The
+    /// text never covers a panel, so the second over the first is the composed image.
+    pub fn compose_layers(&self, panels: &[Panel], legend: Option<&Legend>) -> (RgbaImage, RgbaImage) {
+        let (panel_width, panel_height) = panels
+            .first()
+            .map(|p| p.image.dimensions())
+            .unwrap_or((0, 0));
+        let columns = self.columns.clamp(1, panels.len().max(1) as u32);
+        let rows = (panels.len() as u32).div_ceil(columns);
+        let has_titles = panels.iter().any(|p| p.title.is_some());
+        let title_scale = self.title_scale.unwrap_or(self.text_scale);
+        let title_height = if has_titles {
+            text_height(title_scale) + self.gap / 2
+        } else {
+            0
+        };
+        let cell_height = title_height + panel_height;
+        let legend_height = match legend {
+            Some(_) => Legend::height(self.text_scale) + self.gap,
+            None => 0,
+        };
+
+        let caption_scale = self.caption_scale();
+        let caption_height = match self.caption {
+            Some(_) => text_height(caption_scale) + self.gap / 2,
+            None => 0,
+        };
+
+        let width = self.gap + columns * (panel_width + self.gap);
+        let height = self.gap + rows * (cell_height + self.gap) + legend_height + caption_height;
+        let mut canvas = RgbaImage::from_pixel(width, height, self.theme.background);
+        let mut ink = RgbaImage::new(width, height);
+
+        for (i, panel) in panels.iter().enumerate() {
+            let left = self.gap + (i as u32 % columns) * (panel_width + self.gap);
+            let top = self.gap + (i as u32 / columns) * (cell_height + self.gap);
+            if let Some(title) = &panel.title {
+                // Shrink titles that would overflow into the neighbouring panel
+                let scale = match self.title_scale {
+                    Some(scale) => scale,
+                    None => (1..=self.text_scale)
+                        .rev()
+                        .find(|&scale| text_width(title, scale) <= panel_width)
+                        .unwrap_or(1),
+                };
+                let title_left = left as i64 + (panel_width as i64 - text_width(title, scale) as i64) / 2;
+                let title_top = top + text_height(title_scale) - text_height(scale);
+                draw_text(&mut ink, title_left.max(left as i64), title_top as i64, title, scale, self.theme.foreground);
+            }
+            imageops::overlay(&mut canvas, &panel.image, left as i64, (top + title_height) as i64);
+        }
+
+        if let Some(legend) = legend {
+            let legend_top = self.gap + rows * (cell_height + self.gap);
+            let legend_width = width - 2 * self.gap;
+            legend.draw(&mut ink, self.gap, legend_top, legend_width, self.text_scale, self.theme.foreground);
+        }
+        if let Some(caption) = &self.caption {
+            let left = width as i64 - self.gap as i64 - text_width(caption, caption_scale) as i64;
+            let top = height - caption_height;
+            draw_text(&mut ink, left.max(0), top as i64, caption, caption_scale, self.theme.muted);
+        }
+
+        (canvas, ink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::Palette;
+    use crate::scale::DomainType;
+    use image::Rgba;
+
+    fn panel(colour: Rgba<u8>, title: &str) -> Panel {
+        Panel {
+            image: RgbaImage::from_pixel(10, 10, colour),
+            title: Some(title.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_compose_side_by_side() {
+        let layout = Layout {
+            gap: 4,
+            text_scale: 1,
+            title_scale: None,
+            columns: u32::MAX,
+            caption: None,
+            theme: Theme::DARK,
+        };
+        let red = Rgba([255, 0, 0, 255]);
+        let blue = Rgba([0, 0, 255, 255]);
+        let canvas = layout.compose(&[panel(red, "a"), panel(blue, "b")], None);
+
+        let title_height = text_height(1) + 2;
+        assert_eq!(canvas.width(), 4 + 2 * (10 + 4));
+        assert_eq!(canvas.height(), 4 + title_height + 10 + 4);
+        assert_eq!(*canvas.get_pixel(4, 4 + title_height), red);
+        assert_eq!(*canvas.get_pixel(4 + 10 + 4, 4 + title_height), blue);
+        // The gap between panels shows the background
+        assert_eq!(*canvas.get_pixel(4 + 10 + 1, 4 + title_height), Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_compose_grid_with_legend() {
+        let mut layout = Layout::for_panel_size(10);
+        layout.columns = 2;
+        let white = Rgba([255, 255, 255, 255]);
+        let panels: Vec<Panel> = (0..3).map(|i| panel(white, &i.to_string())).collect();
+        let legend = Legend {
+            palette: Palette::builtin("magma").unwrap(),
+            curve: DomainType::Linear,
+            min_value: 0.0,
+            max_value: 1.0,
+            log_params: None,
+            label: None,
+            bands: Vec::new(),
+            strings: Default::default(),
+        };
+        let canvas = layout.compose(&panels, Some(&legend));
+        let gap = layout.gap;
+        let cell_height = text_height(layout.text_scale) + gap / 2 + 10;
+        assert_eq!(canvas.width(), gap + 2 * (10 + gap));
+        assert_eq!(
+            canvas.height(),
+            gap + 2 * (cell_height + gap) + Legend::height(layout.text_scale) + gap
+        );
+
+        layout.caption = Some("caption".to_string());
+        let (mut base, ink) = layout.compose_layers(&panels, Some(&legend));
+        assert_eq!(base.dimensions(), ink.dimensions());
+        // The text is opaque and clear of the panels
+        assert!(ink.pixels().all(|pixel| pixel.0[3] == 0 || pixel.0[3] == 255));
+        assert!(ink.pixels().any(|pixel| pixel.0[3] == 255));
+        assert_eq!(*ink.get_pixel(gap, gap + text_height(layout.text_scale) + gap / 2), Rgba([0, 0, 0, 0]));
+        imageops::overlay(&mut base, &ink, 0, 0);
+        assert_eq!(base, layout.compose(&panels, Some(&legend)));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::bands::LegendBand;
+use crate::palette::Palette;
+use crate::scale::{DomainType, LogParams, ScaleDomain};
+use crate::strings::Strings;
+use crate::text::{draw_text, fill_rect, text_height, text_width};
+use image::{Rgba, RgbaImage};
+
+/// Positions along the bar that are marked and labelled with their value.
+pub const LEGEND_TICKS: [f64; 5] = [0.0, 0.25, 0.5, 0.75, 1.0];
+
+/// A horizontal colour bar with ticks at [`LEGEND_TICKS`], each labelled with the
+/// value the curve maps there.
+#[derive(Clone, Debug)]
+pub struct Legend {
+    pub palette: Palette,
+    pub curve: DomainType,
+    pub min_value: f64,
+    pub max_value: f64,
+    /// Base and offset of a logarithmic curve, see [`crate::RenderOptions::log_params`].
+    pub log_params: Option<LogParams>,
+    /// Text under the tick labels; defaults to the curve name.
+    pub label: Option<String>,
+    /// Named bands labelled along the bar in place of the end labels and the text.
+    pub bands: Vec<LegendBand>,
+    /// Where the curve name comes from.
+    pub strings: Strings,
+}
+
+impl Legend {
+    /// Height of the legend (bar plus two rows of labels) at text `scale`.
+    pub fn height(scale: u32) -> u32 {
+        bar_height(scale) + scale * 3 + text_height(scale) * 2
+    }
+
+    /// The value at `position` along the bar, between 0 and 1.
+    pub fn value_at(&self, position: f64) -> f64 {
+        let domain = ScaleDomain::new(self.curve, self.min_value, self.max_value)
+            .and_then(|domain| match self.log_params {
+                Some(params) if self.curve == DomainType::Logarithmic => domain.with_log_params(params),
+                _ => Ok(domain),
+            });
+        match domain {
+            Ok(domain) => domain.invert(position),
+            // An empty domain has a single value
+            Err(_) => self.min_value,
+        }
+    }
+
+    /// Draw the legend with its top-left corner at (`x`, `y`).
+    pub fn draw(&self, canvas: &mut RgbaImage, x: u32, y: u32, width: u32, scale: u32, foreground: Rgba<u8>) {
+        let bar_height = bar_height(scale);
+        for i in 0..width {
+            let t = if width > 1 { i as f64 / (width - 1) as f64 } else { 0.0 };
+            let band = self.bands.iter().find(|band| t <= band.end).or(self.bands.last());
+            let [r, g, b] = band.and_then(|band| band.colour).unwrap_or_else(|| self.palette.eval(t));
+            fill_rect(canvas, (x + i) as i64, y as i64, 1, bar_height, Rgba([r, g, b, 255]));
+        }
+
+        let label_y = (y + bar_height + scale * 2) as i64;
+        if !self.bands.is_empty() {
+            self.draw_bands(canvas, x, y, width, scale, foreground);
+            return;
+        }
+        self.draw_ticks(canvas, x, y, width, scale, foreground);
+        let curve_label = self.label.clone().unwrap_or_else(|| self.strings.curve(self.curve));
+        draw_text(
+            canvas,
+            (x + width / 2) as i64 - text_width(&curve_label, scale) as i64 / 2,
+            label_y + (text_height(scale) + scale) as i64,
+            &curve_label,
+            scale,
+            foreground,
+        );
+    }
+}
+
+impl Legend {
+    /// Mark the ticks below the bar and label them, the end labels kept within
+    /// the bar. Inner labels that would overlap a neighbour are left out.
+    fn draw_ticks(&self, canvas: &mut RgbaImage, x: u32, y: u32, width: u32, scale: u32, foreground: Rgba<u8>) {
+        let label_y = (y + bar_height(scale) + scale * 2) as i64;
+        let (left, right) = (x as i64, (x + width) as i64);
+        let at = |t: f64| left + (t * (width.saturating_sub(1)) as f64).round() as i64;
+        let placed: Vec<(i64, String)> = LEGEND_TICKS
+            .iter()
+            .map(|&t| {
+                let label = format_value(self.value_at(t));
+                let label_width = text_width(&label, scale) as i64;
+                ((at(t) - label_width / 2).clamp(left, (right - label_width).max(left)), label)
+            })
+            .collect();
+        let gap = text_width(" ", scale) as i64;
+        let end = |(start, label): &(i64, String)| start + text_width(label, scale) as i64 + gap;
+        let last = placed.len() - 1;
+        let mut free = left;
+        for (index, (&t, tick)) in LEGEND_TICKS.iter().zip(&placed).enumerate() {
+            let tick_x = at(t).min(right - scale as i64);
+            fill_rect(canvas, tick_x, (y + bar_height(scale)) as i64, scale, scale, foreground);
+            if index == 0 || index == last || (tick.0 >= free && end(tick) <= placed[last].0) {
+                draw_text(canvas, tick.0, label_y, &tick.1, scale, foreground);
+                free = end(tick);
+            }
+        }
+    }
+}
+
+impl Legend {
+    /// Mark the band boundaries on the bar and centre each name under its band,
+    /// kept within the bar.
+    fn draw_bands(&self, canvas: &mut RgbaImage, x: u32, y: u32, width: u32, scale: u32, foreground: Rgba<u8>) {
+        let label_y = (y + bar_height(scale) + scale * 2) as i64;
+        let at = |t: f64| x as i64 + (t * (width.saturating_sub(1)) as f64).round() as i64;
+        for band in &self.bands[..self.bands.len() - 1] {
+            fill_rect(canvas, at(band.end), y as i64, scale, bar_height(scale), foreground);
+        }
+        // Names that would overlap the previous one move right, or are left out
+        let mut free = x as i64;
+        for band in &self.bands {
+            let name_width = text_width(&band.name, scale) as i64;
+            let centre = at((band.start + band.end) / 2.0);
+            let left = (centre - name_width / 2).max(free).min((x + width) as i64 - name_width);
+            if left < free {
+                continue;
+            }
+            draw_text(canvas, left, label_y, &band.name, scale, foreground);
+            free = left + name_width + text_width(" ", scale) as i64;
+        }
+    }
+}
+
+fn bar_height(scale: u32) -> u32 {
+    text_height(scale) * 3 / 2
+}
+
+/// Format a value compactly for labels, e.g. `950`, `12.5k`, `3.2M`.
+pub fn format_value(value: f64) -> String {
+    let magnitude = value.abs();
+    let (scaled, suffix) = if magnitude >= 1e9 {
+        (value / 1e9, "G")
+    } else if magnitude >= 1e6 {
+        (value / 1e6, "M")
+    } else if magnitude >= 1e3 {
+        (value / 1e3, "k")
+    } else {
+        (value, "")
+    };
+    let text = if scaled.fract() == 0.0 {
+        format!("{}", scaled)
+    } else if scaled.abs() >= 100.0 {
+        format!("{:.0}", scaled)
+    } else {
+        let text = format!("{:.1}", scaled);
+        text.trim_end_matches(".0").to_string()
+    };
+    format!("{}{}", text, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(0.0), "0");
+        assert_eq!(format_value(950.0), "950");
+        assert_eq!(format_value(12_500.0), "12.5k");
+        assert_eq!(format_value(3_200_000.0), "3.2M");
+        assert_eq!(format_value(-2_000.0), "-2k");
+        assert_eq!(format_value(0.5), "0.5");
+        assert_eq!(format_value(4_000_000_000.0), "4G");
+    }
+
+    #[test]
+    fn test_legend_draws_palette_ends() {
+        let legend = Legend {
+            palette: Palette::builtin("viridis").unwrap(),
+            curve: DomainType::Linear,
+            min_value: 0.0,
+            max_value: 100.0,
+            log_params: None,
+            label: None,
+            bands: Vec::new(),
+            strings: Strings::default(),
+        };
+        let mut canvas = RgbaImage::new(120, Legend::height(1));
+        legend.draw(&mut canvas, 10, 0, 100, 1, Rgba([255, 255, 255, 255]));
+        let [r, g, b] = legend.palette.eval(0.0);
+        assert_eq!(*canvas.get_pixel(10, 0), Rgba([r, g, b, 255]));
+        let [r, g, b] = legend.palette.eval(1.0);
+        assert_eq!(*canvas.get_pixel(109, 0), Rgba([r, g, b, 255]));
+    }
+
+    #[test]
+    fn test_ticks_are_labelled_with_curve_values() {
+        let legend = |curve: DomainType, log_params: Option<LogParams>| Legend {
+            palette: Palette::builtin("viridis").unwrap(),
+            curve,
+            min_value: 0.0,
+            max_value: 10_000.0,
+            log_params,
+            label: None,
+            bands: Vec::new(),
+            strings: Strings::default(),
+        };
+        let values = |legend: &Legend| LEGEND_TICKS.map(|t| format_value(legend.value_at(t)));
+        assert_eq!(values(&legend(DomainType::Linear, None)), ["0", "2.5k", "5k", "7.5k", "10k"]);
+        assert_eq!(values(&legend(DomainType::Logarithmic, None)), ["0", "9", "99", "999", "10k"]);
+        let log10 = legend(DomainType::Logarithmic, Some(LogParams::new(10.0, 1.0).unwrap()));
+        assert_eq!(values(&log10), ["0", "9", "99", "999", "10k"]);
+        // Only the upper half of a symlog bar holds positive values
+        assert_eq!(values(&legend(DomainType::Symlog { linthresh: 1.0 }, None)), ["0", "0", "0", "31.6", "10k"]);
+
+        let foreground = Rgba([255, 255, 255, 255]);
+        let linear = legend(DomainType::Linear, None);
+        let mut canvas = RgbaImage::new(201, Legend::height(1));
+        linear.draw(&mut canvas, 0, 0, 201, 1, foreground);
+        // A tick below the bar at each position
+        for x in [0, 50, 100, 150, 200] {
+            assert_eq!(*canvas.get_pixel(x, bar_height(1)), foreground, "tick at {}", x);
+        }
+        assert_ne!(*canvas.get_pixel(25, bar_height(1)), foreground);
+    }
+
+    #[test]
+    fn test_snapped_bands_are_solid_blocks() {
+        let palette = Palette::builtin("viridis").unwrap();
+        let band = |name: &str, start: f64, end: f64, t: f64| LegendBand {
+            name: name.to_string(),
+            start,
+            end,
+            colour: Some(palette.eval(t)),
+        };
+        let legend = Legend {
+            palette: palette.clone(),
+            curve: DomainType::Linear,
+            min_value: 0.0,
+            max_value: 100.0,
+            log_params: None,
+            label: None,
+            bands: vec![band("low", 0.0, 0.25, 0.125), band("high", 0.25, 1.0, 0.625)],
+            strings: Strings::default(),
+        };
+        let foreground = Rgba([255, 255, 255, 255]);
+        let mut canvas = RgbaImage::new(101, Legend::height(1));
+        legend.draw(&mut canvas, 0, 0, 101, 1, foreground);
+        let [r, g, b] = palette.eval(0.125);
+        assert_eq!(*canvas.get_pixel(0, 0), Rgba([r, g, b, 255]));
+        assert_eq!(*canvas.get_pixel(20, 0), Rgba([r, g, b, 255]));
+        // The boundary is marked, and the high band is one colour to the end
+        assert_eq!(*canvas.get_pixel(25, 0), foreground);
+        let [r, g, b] = palette.eval(0.625);
+        assert_eq!(*canvas.get_pixel(30, 0), Rgba([r, g, b, 255]));
+        assert_eq!(*canvas.get_pixel(100, 0), Rgba([r, g, b, 255]));
+        // Names are drawn below the bar
+        let label_rows = bar_height(1) + 2..Legend::height(1);
+        assert!(label_rows.flat_map(|y| (0..101).map(move |x| (x, y))).any(|(x, y)| *canvas.get_pixel(x, y) == foreground));
+    }
+}
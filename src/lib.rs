@@ -1,39 +1,222 @@
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 use colorous::Gradient;
-use image::{ImageBuffer, RgbaImage, Rgba};
+use image::RgbaImage;
 use std::io::BufRead;
 use std::net::Ipv4Addr;
-use std::str::FromStr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
 
+mod bands;
+mod bounds;
+mod braces;
+mod capabilities;
+mod caption;
+mod crop;
+mod categories;
+mod cells;
+mod changes;
+mod clamp;
+mod clipped;
+mod conflicts;
+mod dedup;
+mod distinct;
+mod dither;
+mod endian;
+mod expect;
+mod compare;
+mod convert;
+mod downsample;
+mod frame;
+mod geometry;
 mod hilbert;
+mod histogram;
+mod hll;
+#[cfg(feature = "http")]
+mod http;
+mod imgdiff;
+mod input;
+mod inspect;
+mod invert;
+mod json;
+mod json_log;
+mod label;
+mod layout;
+mod legend;
+mod live;
+#[cfg(any(feature = "serve", feature = "http"))]
+mod limits;
+mod mapped;
+mod memory;
+mod metrics;
+mod montage;
+mod multi;
+mod multiples;
+mod observer;
+mod parallel;
+mod outline;
+mod output;
+mod palette;
+mod percentile;
+mod pipeline;
+mod prefixes;
+mod preaggregate;
+mod preview;
+mod profile;
+mod rank;
+mod raw;
+mod rejects;
+mod render;
+mod sanity;
 mod scale;
+#[cfg(feature = "serve")]
+mod serve;
+mod shade;
+mod slash8;
+mod snapshot;
+mod state;
+mod stats;
+mod stream;
+mod streamed;
+mod strings;
+mod summarize;
+mod table;
+mod template;
+pub mod testdata;
+mod text;
+mod theme;
+mod timestamps;
+mod timing;
+mod validate;
+mod warnings;
 
 #[cfg(target_arch = "wasm32")]
 pub mod wasm;
 
-use hilbert::hilbert_d2xy;
+use cells::{Grid, Slab};
+use dedup::DedupWindow;
+use distinct::DistinctCounter;
+use hilbert::{hilbert_d2xy, hilbert_xy2d};
+use input::ParsedLine;
+use mapped::Mapping;
+use preaggregate::Preaggregator;
 use ipnet::Ipv4Net;
 use scale::ScaleDomain;
 
 // Re-export types for public API
-pub use scale::DomainType;
+pub use bands::{Band, Bands, LegendBand};
+pub use braces::MAX_BRACE_EXPANSIONS;
+pub use capabilities::Capabilities;
+pub use categories::CategoryColours;
+pub use changes::{Change, ChangeKind, changes_to_json, write_changes};
+pub use clamp::ClampCounts;
+pub use clipped::DEFAULT_CLIP_WARNING_PERCENT;
+pub use compare::SimilarityReport;
+pub use conflicts::{ConflictPolicy, ConflictSample, DEFAULT_CONFLICT_SAMPLES};
+pub use convert::{Conversion, Converter, OutputFormat};
+pub use distinct::{DistinctApprox, DistinctEstimate};
+pub use dither::{DEFAULT_DITHER_STRENGTH, DITHER_STRENGTHS, Dither, DitherPattern, parse_dither_strength};
+pub use expect::{
+    CidrValue, Expectation, ExpectationCheck, ExpectationStatus, parse_expectations, write_expectation_report,
+};
+pub use downsample::{Aggregation, ThumbnailSpec, check_downsample_size};
+pub use frame::Frame;
+pub use geometry::{Geometry, PixelRect, PrefixBlocks, blocks_for_prefix};
+pub use histogram::{HISTOGRAM_PERCENTILES, Histogram, HistogramBin};
+pub use hll::{DEFAULT_HLL_PRECISION, HLL_PRECISIONS, HyperLogLog};
+#[cfg(feature = "http")]
+pub use http::{FetchOptions, fetch};
+pub use imgdiff::{ImageDiffReport, compare_images, image_hash};
+pub use inspect::{Inspection, PngInfo, inspect, inspect_file};
+pub use input::{
+    CidrHostBits, InputFormat, MapV6, ParseOptions, Record, Sampling, ValueSource, WeightedInput, is_url, parse_in_addr,
+    parse_weight,
+};
+pub use json::JsonValue;
+pub use json_log::JsonLogger;
+pub use label::{Label, MIN_LABEL_SCALE};
+pub use layout::{Layout, Panel};
+pub use legend::Legend;
+pub use live::{DEFAULT_LIVE_QUEUE_SIZE, LiveInput, LiveOptions, LiveStats, QueueFull, QueueStats};
+pub use memory::{MemoryEstimate, MemoryPlan, format_bytes, parse_memory_size};
+pub use metrics::{Metrics, RENDER_DURATION_BUCKETS};
+pub use montage::render_montage;
+pub use multi::MultiHeatmap;
+pub use multiples::{hottest_prefixes, render_small_multiples};
+pub use observer::RecordObserver;
+pub use outline::Outline;
+pub use output::{PngCompression, PngEncoding, PngFilter, save_png, write_atomic};
+pub use palette::{
+    BUILTIN_PALETTES, COLOUR_LUT_BITS, ColourLut, DEFAULT_COLOUR_LUT_BITS, LutRounding, Palette, parse_colour_lut,
+    parse_hex_colour,
+    render_palette_previews, render_palette_strips,
+};
+pub use percentile::SortedValues;
+pub use pipeline::{
+    CropLayer, DataCropLayer, HeatLayer, LabelLayer, Layer, LegendLayer, OutlineLayer, RenderPipeline, ShadeLayer, Underlay,
+};
+pub use preaggregate::{DEFAULT_PREAGGREGATE_MEMORY, Preaggregation};
+pub use preview::{Preview, PreviewSpec};
+pub use rank::PrefixRank;
+pub use raw::RAW_MAGIC;
+pub use rejects::{DEFAULT_MAX_REJECT_SAMPLES, ErrorPolicy, Reject, RejectLog, RejectReason};
+pub use render::{RenderOptions, RenderSpec, parse_gamma};
+pub use sanity::{BlockSummary, DEFAULT_SANITY_FACTOR, SANITY_PREFIX_LEN, SanityReport, parse_sanity_factor};
+pub use scale::{BoundsError, DomainType, LogParams};
+pub use slash8::{SLASH8_LABELLED_BARS, Slash8Chart};
+pub use snapshot::HeatmapSnapshot;
+#[cfg(feature = "serve")]
+pub use serve::{ServeOptions, serve, serve_metrics};
+pub use shade::{DEFAULT_SHADE_SPACING, Shade, ShadeStyle};
+pub use state::{STATE_HEADER_LEN, StateHeader, is_state_file};
+pub use stats::{CoverageEntry, DEFAULT_COVERAGE_PREFIXES, Stats};
+pub use stream::ChunkedInput;
+pub use streamed::STREAMED_ENCODE_MIN_SIZE;
+pub use strings::{DEFAULT_STRINGS, Strings};
+pub use summarize::{InputSummary, Slash8Total, Summarizer};
+pub use table::{Align, Table, TextStyle, is_utf8_locale};
+pub use template::{OutputTemplate, TEMPLATE_VARIABLES, input_stem};
+pub use theme::Theme;
+pub use timestamps::{TimeWindow, parse_timestamp};
+pub use timing::{Phase, PhaseTimer};
+pub use validate::{Validation, Validator};
+pub use warnings::{DEFAULT_WARNINGS_PER_REASON, WarningLimiter};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// How a record's value is painted onto the pixels it covers. Where the value comes
+/// from is the [`ValueSource`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ValueMode {
+    /// Values are labels, coloured from a fixed palette; they cannot accumulate.
     Categorical,
+    /// Every covered pixel gets the whole value.
     Raw,
+    /// A pixel gets the value in proportion to the share of its addresses the record
+    /// covers, so a lone address in a /24 pixel adds 1/256 of its value (rounded down).
     Scaled,
 }
 
+impl ValueMode {
+    pub const ALL: [ValueMode; 3] = [ValueMode::Categorical, ValueMode::Raw, ValueMode::Scaled];
+
+    /// Every name the parser accepts, with the mode it stands for.
+    pub const NAMES: [(&'static str, ValueMode); 6] = [
+        ("categorical", ValueMode::Categorical),
+        ("labels", ValueMode::Categorical),
+        ("raw", ValueMode::Raw),
+        ("whole", ValueMode::Raw),
+        ("scaled", ValueMode::Scaled),
+        ("proportional", ValueMode::Scaled),
+    ];
+}
+
 impl std::str::FromStr for ValueMode {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "categorical" => Ok(ValueMode::Categorical),
-            "raw" => Ok(ValueMode::Raw),
-            "scaled" => Ok(ValueMode::Scaled),
-            _ => Err(format!(
+        let name = s.to_lowercase();
+        match ValueMode::NAMES.iter().find(|(known, _)| *known == name) {
+            Some(&(_, mode)) => Ok(mode),
+            None => Err(format!(
                 "Invalid value mode: {}. Use 'categorical', 'raw', or 'scaled'",
                 s
             )),
@@ -58,10 +241,18 @@ impl std::fmt::Display for ValueMode {
 /// a Hilbert curve, giving a side length of 2^((32 - bits_per_pixel) / 2).
 ///
 /// bits_per_pixel must be even (so that 32 - bits_per_pixel is even and the pixels form
-/// a perfect square) and at most 32.
+/// a perfect square) and at most 32. See [`Geometry`] for the rest of the map's dimensions.
 pub fn image_size_for_bpp(bits_per_pixel: u8) -> u32 {
-    let order = (32 - bits_per_pixel as u32) / 2;
-    1u32 << order
+    Geometry::for_bits_per_pixel(bits_per_pixel).width
+}
+
+/// `value` multiplied by `factor`, see [`Heatmap::value_factor`], saturating at the
+/// range of cells.
+pub(crate) fn weighted_value(value: i32, factor: f64) -> i32 {
+    match factor == 1.0 {
+        true => value,
+        false => (value as f64 * factor).round() as i32,
+    }
 }
 
 /// ColorBrewer2 Accent categorical palette (wraps for categories > 7)
@@ -76,24 +267,99 @@ const CATEGORICAL_PALETTE: [[u8; 3]; 8] = [
     [102, 102, 102],  // gray
 ];
 
+/// How [`Heatmap::colour_row`] turns values into colours.
+enum Colouring {
+    Categorical,
+    Scaled {
+        domain: ScaleDomain,
+        /// The bands values snap to, if any.
+        snapped: Option<Vec<bands::LegendBand>>,
+        /// The palette sampled for [`RenderOptions::colour_lut`], if set.
+        lut: Option<ColourLut>,
+    },
+}
+
 pub struct Heatmap {
-    buffer: Vec<Vec<i32>>,
+    buffer: Grid,
     curve: scale::DomainType,
     min_value: Option<f64>,
     max_value: Option<f64>,
     accumulate: bool,
     bits_per_pixel: u8,
+    /// The resolution this map was downsampled from, see [`Heatmap::downsample`].
+    downsampled_from: Option<u8>,
     colour_scale: &'static Gradient,
     value_mode: ValueMode,
-    separator: Option<char>,
+    parse_options: ParseOptions,
+    error_policy: ErrorPolicy,
+    lines_processed: u64,
+    cidr_host_bits: u64,
+    /// Records dropped for their value, see [`ParseOptions::ignore_values`].
+    ignored_values: u64,
+    /// Records dropped for their time, see [`ParseOptions::time_window`].
+    outside_window: u64,
+    /// Multiplier applied to each record's value, see [`Heatmap::set_weight`].
+    weight: f64,
+    /// Sum of the record values painted, after weighting.
+    weighted_total: i64,
+    /// Records are counted as clipped outside this prefix, see [`Heatmap::set_view`].
+    view: Option<Ipv4Net>,
+    records: u64,
+    clipped_records: u64,
+    clipped_value: i64,
+    /// Recent records, see [`Heatmap::set_dedup_window`].
+    dedup: Option<DedupWindow>,
+    deduplicated: u64,
+    /// How overwrites of a different value are resolved, see
+    /// [`Heatmap::set_conflict_policy`].
+    conflict_policy: ConflictPolicy,
+    conflicts: u64,
+    conflict_samples: Vec<ConflictSample>,
+    /// The first conflict of the line being painted, as its previous and new values.
+    line_conflict: Option<(i32, i32)>,
+    /// Sketches of the distinct addresses, see [`Heatmap::set_distinct_approx`].
+    distinct: Option<DistinctCounter>,
+    /// Records held back to be painted by key, see [`Heatmap::set_preaggregation`].
+    preaggregator: Option<Box<Preaggregator>>,
+    preaggregated_keys: u64,
+    rejects: RejectLog,
+    /// Limits the warnings logged for rejected lines.
+    warnings: WarningLimiter<RejectReason>,
+    /// Logged with rejected lines, see [`Heatmap::set_input_name`].
+    input_name: Option<String>,
+    /// One bit per pixel (row-major) set when anything was painted there.
+    touched: Slab<u64>,
+    /// Pixels skipped outside the buffer, see [`Heatmap::out_of_bounds`].
+    out_of_bounds: u64,
+    /// The state file holding `buffer` and `touched`, see [`Heatmap::map_state`].
+    mapping: Option<Rc<Mapping>>,
+    /// Encode plain renders row by row at any size, see [`Heatmap::set_low_memory`].
+    low_memory: bool,
+    timer: PhaseTimer,
+    /// Updated as input is processed and maps are rendered, see [`Heatmap::set_metrics`].
+    metrics: Option<Arc<Metrics>>,
+    /// Written every so often while input is processed, see [`Heatmap::set_preview`].
+    preview: Option<Box<Preview>>,
+    /// The chunks of the last [`Heatmap::snapshot`], shared with the next one.
+    snapshot_chunks: Option<snapshot::Chunks>,
+    /// See [`Heatmap::live_queues`].
+    live_queues: Option<LiveStats>,
 }
 
 impl Heatmap {
     /// Returns the side length of the output image in pixels.
     pub fn image_size(&self) -> u32 {
-        image_size_for_bpp(self.bits_per_pixel)
+        self.geometry().width
     }
 
+    /// log2 of the number of addresses each pixel stands for.
+    pub fn bits_per_pixel(&self) -> u8 {
+        self.bits_per_pixel
+    }
+
+    /// Panics if the map is too large for this platform, see
+    /// [`Geometry::check_platform`].
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         curve: scale::DomainType,
         min_value: Option<f64>,
@@ -109,8 +375,13 @@ impl Heatmap {
             ValueMode::Categorical => -1,
             ValueMode::Raw | ValueMode::Scaled => 0,
         };
-        let size = image_size_for_bpp(bits_per_pixel) as usize;
-        let buffer = vec![vec![init_value; size]; size];
+        let geometry = Geometry::for_bits_per_pixel(bits_per_pixel);
+        if let Err(err) = geometry.check_platform() {
+            panic!("{}", err);
+        }
+        let size = geometry.width as usize;
+        let buffer = Grid::new(size, init_value);
+        let touched = Slab::heap(vec![0u64; (size * size).div_ceil(64)]);
 
         Self {
             buffer,
@@ -119,192 +390,567 @@ impl Heatmap {
             max_value,
             accumulate,
             bits_per_pixel,
+            downsampled_from: None,
             colour_scale,
             value_mode,
-            separator,
+            parse_options: ParseOptions {
+                separator,
+                ..ParseOptions::default()
+            },
+            error_policy: ErrorPolicy::default(),
+            lines_processed: 0,
+            cidr_host_bits: 0,
+            ignored_values: 0,
+            outside_window: 0,
+            weight: 1.0,
+            weighted_total: 0,
+            view: None,
+            records: 0,
+            clipped_records: 0,
+            clipped_value: 0,
+            dedup: None,
+            deduplicated: 0,
+            conflict_policy: ConflictPolicy::default(),
+            conflicts: 0,
+            conflict_samples: Vec::new(),
+            line_conflict: None,
+            distinct: None,
+            preaggregator: None,
+            preaggregated_keys: 0,
+            rejects: RejectLog::default(),
+            warnings: WarningLimiter::default(),
+            input_name: None,
+            touched,
+            out_of_bounds: 0,
+            mapping: None,
+            low_memory: false,
+            timer: PhaseTimer::default(),
+            metrics: None,
+            preview: None,
+            snapshot_chunks: None,
+            live_queues: None,
         }
     }
 
-    fn ip_to_xy(&self, ip: u32) -> Option<(u32, u32)> {
-        let hilbert_curve_order = (32 - self.bits_per_pixel) as u32 / 2; // (addr_space_bits_per_image - addr_space_bits_per_pixel) / 2;
+    /// The file the following input is read from, logged as the `file` key of
+    /// warnings about its rejected lines. `None` for stdin.
+    pub fn set_input_name(&mut self, name: Option<&str>) {
+        self.input_name = name.map(str::to_string);
+    }
 
-        let shift = self.bits_per_pixel as u32;
-        let d = ip >> shift;
+    /// Count processed lines and renders in `metrics`, which may be shared with other
+    /// heatmaps and read from another thread.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        if let Some(metrics) = &metrics {
+            metrics.set_buffer_bytes(self.buffer_bytes());
+        }
+        self.metrics = metrics;
+    }
 
-        hilbert_d2xy(d as u64, hilbert_curve_order)
+    /// How input lines are parsed and which records are kept, see [`ParseOptions`].
+    /// Replaces the separator given to [`Heatmap::new`] too, and the rejected lines
+    /// kept so far.
+    pub fn set_parse_options(&mut self, options: ParseOptions) {
+        self.rejects = RejectLog::new(options.max_rejects);
+        self.parse_options = options;
     }
 
-    fn paint_pixel(&mut self, x: u32, y: u32, value: i32) {
-        if self.accumulate {
-            self.buffer[y as usize][x as usize] += value;
-        } else {
-            self.buffer[y as usize][x as usize] = value;
+    /// Record how long each processing phase takes, see [`Heatmap::timer`].
+    pub fn set_timing(&mut self, enabled: bool) {
+        self.timer = PhaseTimer::new(enabled);
+    }
+
+    pub fn timer(&self) -> &PhaseTimer {
+        &self.timer
+    }
+
+    /// Number of input lines read so far, including blank and rejected lines.
+    pub fn lines_processed(&self) -> u64 {
+        self.lines_processed
+    }
+
+    pub fn rejects(&self) -> &RejectLog {
+        &self.rejects
+    }
+
+    /// Remove and return the rejected line samples recorded so far.
+    pub fn take_errors(&mut self) -> Vec<Reject> {
+        self.rejects.take_samples()
+    }
+
+    /// What happens to lines that cannot be painted. Defaults to [`ErrorPolicy::Count`].
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    pub fn sampling(&self) -> Option<Sampling> {
+        self.parse_options.sampling
+    }
+
+    /// Number of records dropped for their value.
+    pub fn ignored_values(&self) -> u64 {
+        self.ignored_values
+    }
+
+    /// Number of records dropped for a timestamp outside the time window.
+    pub fn outside_window(&self) -> u64 {
+        self.outside_window
+    }
+
+    /// Multiply each record's value by `weight` as it is read, e.g. to merge inputs
+    /// sampled at different rates. Values are weighted after parsing and before the
+    /// scaling of [`ParseOptions::sampling`], then rounded; categorical values and
+    /// prefix lengths are never weighted. Defaults to 1.
+    pub fn set_weight(&mut self, weight: f64) {
+        self.weight = weight;
+    }
+
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    /// Sum of the values of the records processed so far, after weighting and sample
+    /// scaling, before they are spread over pixels.
+    pub fn weighted_total(&self) -> i64 {
+        self.weighted_total
+    }
+
+    pub fn value_source(&self) -> ValueSource {
+        self.parse_options.value_source
+    }
+
+    /// Check the value mode, value source, accumulation and downsampling `aggregation`
+    /// (if the map is downsampled) make sense together, before any input is read.
+    pub fn check_value_modes(&self, aggregation: Option<Aggregation>) -> Result<()> {
+        let source = self.parse_options.value_source;
+        if self.value_mode == ValueMode::Categorical && self.accumulate {
+            bail!("Categorical values are labels, so they cannot accumulate");
+        }
+        if source == ValueSource::Count && self.value_mode == ValueMode::Categorical {
+            bail!("Counts are not category labels; use the raw value mode to colour them");
+        }
+        if source == ValueSource::Count && !self.parse_options.ignore_values.is_empty() {
+            bail!("Ignored values are matched against the value column, which counting does not read");
         }
+        if source == ValueSource::Count && aggregation == Some(Aggregation::Mean) {
+            bail!("The mean of pixel counts is not a count; downsample counts with sum or max");
+        }
+        Ok(())
     }
 
-    pub fn paint_address(&mut self, addr: &Ipv4Addr, value: i32) -> Result<()> {
-        let paint_value = match self.value_mode {
-            ValueMode::Categorical | ValueMode::Raw => value,
-            ValueMode::Scaled => {
-                let ips_per_pixel = 1u64 << self.bits_per_pixel;
-                (value as f64 / ips_per_pixel as f64) as i32
-            }
-        };
+    /// Number of painted CIDR prefixes that had host bits set.
+    pub fn cidr_host_bits(&self) -> u64 {
+        self.cidr_host_bits
+    }
 
-        if let Some((x, y)) = self.ip_to_xy(u32::from(*addr)) {
-            self.paint_pixel(x, y, paint_value);
+    fn reject(&mut self, line_number: usize, line: &str, reason: RejectReason, message: String, report: bool) -> Result<()> {
+        let error = |message: &str| format!("Failed to parse line {}: {} - {}", line_number, reason, message);
+        match self.error_policy {
+            ErrorPolicy::Fail => {
+                let error = error(&message);
+                // Record the line even when failing, so it shows up in the run's counts
+                self.rejects.record(line_number, line, reason, message);
+                bail!(error)
+            }
+            ErrorPolicy::Count if report => {
+                let file = self.input_name.as_deref();
+                self.warnings.warn(reason, || {
+                    log::warn!(line_number = line_number, reason:% = reason, file = file; "{}", error(&message))
+                })
+            }
+            ErrorPolicy::Count | ErrorPolicy::Skip => {}
         }
+        self.rejects.record(line_number, line, reason, message);
         Ok(())
     }
 
-    pub fn paint_cidr_range(&mut self, cidr: &Ipv4Net, value: i32) -> Result<()> {
-        // Calculate how many IPs are represented by each pixel
-        let ips_per_pixel = 1u64 << self.bits_per_pixel;
-
-        // Calculate the range of pixels that this CIDR block covers
-        let first_ip = u32::from(cidr.network()) as u64;
-        let last_ip = u32::from(cidr.broadcast()) as u64;
-        let first_pixel_d = first_ip >> self.bits_per_pixel;
-        let last_pixel_d = last_ip >> self.bits_per_pixel;
-
-        // Iterate through the affected pixels
-        for pixel_d in first_pixel_d..=last_pixel_d {
-            // Calculate the IP range this pixel represents
-            let pixel_first_ip = pixel_d << self.bits_per_pixel;
-            let pixel_last_ip = pixel_first_ip + ips_per_pixel - 1;
-
-            // Calculate overlap between CIDR block and this pixel's IP range
-            let overlap_first = first_ip.max(pixel_first_ip);
-            let overlap_last = last_ip.min(pixel_last_ip);
-
-            if overlap_first <= overlap_last {
-                let paint_value = match self.value_mode {
-                    ValueMode::Categorical | ValueMode::Raw => value,
-                    ValueMode::Scaled => {
-                        let overlap_count = overlap_last - overlap_first + 1;
-                        (value as f64 * overlap_count as f64 / ips_per_pixel as f64) as i32
-                    }
-                };
+    #[cfg(test)]
+    fn ip_to_xy(&self, ip: u32) -> Option<(u32, u32)> {
+        let shift = self.bits_per_pixel as u32;
+        let d = ip >> shift;
+
+        hilbert_d2xy(d as u64, self.geometry().order)
+    }
+
+    /// Bounding rectangle `(x, y, width, height)` of the pixels covering `net`, see
+    /// [`blocks_for_prefix`]. Prefixes smaller than a pixel map to their single
+    /// pixel, and prefixes off the map to an empty rectangle.
+    pub fn prefix_rect(&self, net: &Ipv4Net) -> (u32, u32, u32, u32) {
+        let PixelRect { x, y, width, height } = blocks_for_prefix(net, &self.geometry(), None).bounds().unwrap_or_default();
+        (x, y, width, height)
+    }
+
+    /// Total value of every prefix of length `prefix_len`, in address order.
+    ///
+    /// `prefix_len` may not be finer than the pixel resolution.
+    pub fn prefix_totals(&self, prefix_len: u8) -> Result<Vec<(Ipv4Net, i64)>> {
+        let mut totals = Vec::with_capacity(1usize << prefix_len.min(32));
+        self.for_each_prefix_total(prefix_len, |net, total| totals.push((net, total)))?;
+        Ok(totals)
+    }
 
-                if let Some((x, y)) = self.ip_to_xy(pixel_first_ip as u32) {
-                    self.paint_pixel(x, y, paint_value);
+    /// Call `visit` with the total value of every prefix of length `prefix_len`, in
+    /// address order, without holding more than one total at a time.
+    ///
+    /// `prefix_len` may not be finer than the pixel resolution.
+    pub fn for_each_prefix_total(&self, prefix_len: u8, mut visit: impl FnMut(Ipv4Net, i64)) -> Result<()> {
+        self.check_prefix_resolution(prefix_len)?;
+        let Geometry { order, prefix_len_per_pixel: pixel_prefix_len, .. } = self.geometry();
+        let shift = pixel_prefix_len - prefix_len;
+        // An odd resolution leaves the upper half of the address space off the map
+        let pixels = 1u64 << (2 * order);
+        for prefix in 0..(1u64 << prefix_len) {
+            let mut total = 0i64;
+            for d in (prefix << shift)..((prefix + 1) << shift).min(pixels) {
+                if let Some((x, y)) = hilbert_d2xy(d, order) {
+                    let value = self.buffer[y as usize][x as usize];
+                    // Skip the "no data" sentinel of categorical mode
+                    if value > 0 || self.value_mode != ValueMode::Categorical {
+                        total += value as i64;
+                    }
                 }
             }
+            let network = (prefix << (32 - prefix_len as u32)) as u32;
+            visit(Ipv4Net::new(Ipv4Addr::from(network), prefix_len).expect("prefix length is at most 32"), total);
         }
+        Ok(())
+    }
 
+    /// Fail unless prefixes of length `prefix_len` are each a whole number of pixels.
+    pub fn check_prefix_resolution(&self, prefix_len: u8) -> Result<()> {
+        let pixel_prefix_len = self.geometry().prefix_len_per_pixel;
+        if prefix_len > pixel_prefix_len {
+            bail!(
+                "Prefix length /{} is finer than the pixel resolution (/{}) at bits_per_pixel {}",
+                prefix_len,
+                pixel_prefix_len,
+                self.bits_per_pixel
+            );
+        }
         Ok(())
     }
 
-    fn calculate_domain(&self) -> Result<ScaleDomain, &'static str> {
-        // Calculate overall min/max value if no value is provided.
-        let min_value = match self.min_value {
-            Some(v) => v,
-            None => self
-                .buffer
-                .iter()
-                .map(|row| row.iter().min().cloned().unwrap_or(0))
-                .min()
-                .unwrap_or(0) as f64,
-        };
+    /// Number of pixels that have been painted at least once.
+    pub fn touched_pixels(&self) -> u64 {
+        self.touched.iter().map(|word| word.count_ones() as u64).sum()
+    }
 
-        // If max_value wasn't explicitly set, use the dataset maximum
-        let max_value = match self.max_value {
-            Some(v) => v,
-            None => self
-                .buffer
-                .iter()
-                .map(|row| row.iter().max().cloned().unwrap_or(0))
-                .max()
-                .unwrap_or(0) as f64,
+    /// Number of prefixes of length `prefix_len` containing a painted address, and the
+    /// total number of such prefixes.
+    ///
+    /// For prefixes finer than the pixel resolution the painted addresses within a
+    /// pixel are not known, so the count is an upper bound assuming every prefix in a
+    /// touched pixel was painted.
+    pub fn coverage(&self, prefix_len: u8) -> (u64, u64) {
+        let prefix_len = prefix_len.min(32);
+        let total = 1u64 << prefix_len;
+        let Geometry { order, prefix_len_per_pixel: pixel_prefix_len, .. } = self.geometry();
+        if prefix_len > pixel_prefix_len {
+            let per_pixel = 1u64 << (prefix_len - pixel_prefix_len);
+            return ((self.touched_pixels() * per_pixel).min(total), total);
+        }
+
+        let size = self.image_size() as usize;
+        let shift = pixel_prefix_len - prefix_len;
+        let mut covered = vec![0u64; (total as usize).div_ceil(64)];
+        for (word_index, &word) in self.touched.iter().enumerate() {
+            let mut bits = word;
+            while bits != 0 {
+                let index = word_index * 64 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                let d = hilbert_xy2d((index % size) as u32, (index / size) as u32, order).expect("pixel is in the map");
+                let prefix = (d >> shift) as usize;
+                covered[prefix / 64] |= 1 << (prefix % 64);
+            }
+        }
+        (covered.iter().map(|word| word.count_ones() as u64).sum(), total)
+    }
+
+    fn is_touched(&self, x: usize, y: usize) -> bool {
+        let index = y * self.image_size() as usize + x;
+        self.touched[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn paint_pixel(&mut self, x: u32, y: u32, value: i32) {
+        let Some(index) = self.cell_index(x, y) else {
+            return;
+        };
+        let painted = self.touched[index / 64] & (1 << (index % 64)) != 0;
+        self.touched[index / 64] |= 1 << (index % 64);
+        let previous = self.buffer[y as usize][x as usize];
+        // Sums saturate at the range of cells rather than wrap
+        let value = if self.accumulate {
+            previous.saturating_add(value)
+        } else if painted && previous != value && self.detects_conflicts() {
+            self.conflict(previous, value)
+        } else {
+            self.parse_options.value_source.overwrite(previous, value)
         };
+        self.buffer[y as usize][x as usize] = value;
+    }
+
+    /// Add each share of a `--format cells` row to its pixel, see
+    /// [`input::for_each_cell_share`].
+    fn paint_cell_row(&mut self, record: &Record) {
+        let order = self.geometry().order;
+        let categorical = self.value_mode == ValueMode::Categorical;
+        input::for_each_cell_share(self.bits_per_pixel, record, |d, share| {
+            let Some((x, y)) = hilbert_d2xy(d, order) else {
+                return;
+            };
+            let Some(index) = self.cell_index(x, y) else {
+                return;
+            };
+            let painted = self.touched[index / 64] & (1 << (index % 64)) != 0;
+            self.touched[index / 64] |= 1 << (index % 64);
+            let cell = &mut self.buffer[y as usize][x as usize];
+            *cell = if painted && !categorical { cell.saturating_add(share) } else { share };
+        });
+    }
+
+    pub fn paint_address(&mut self, addr: &Ipv4Addr, value: i32) -> Result<()> {
+        self.paint_cidr_range(&Ipv4Net::from(*addr), value)
+    }
+
+    pub fn paint_cidr_range(&mut self, cidr: &Ipv4Net, value: i32) -> Result<()> {
+        let order = self.geometry().order;
+        let record = Record { net: *cidr, value };
+        let value_mode = self.parse_options.value_source.paint_mode(self.value_mode);
+        input::for_each_pixel(self.bits_per_pixel, value_mode, &record, |d, paint_value| {
+            if let Some((x, y)) = hilbert_d2xy(d, order) {
+                self.paint_pixel(x, y, paint_value);
+            }
+        });
+        Ok(())
+    }
+
+    /// Smallest and largest cell values in the buffer.
+    pub fn value_range(&self) -> (i32, i32) {
+        let min = self
+            .buffer
+            .iter()
+            .map(|row| row.iter().min().cloned().unwrap_or(0))
+            .min()
+            .unwrap_or(0);
+        let max = self
+            .buffer
+            .iter()
+            .map(|row| row.iter().max().cloned().unwrap_or(0))
+            .max()
+            .unwrap_or(0);
+        (min, max)
+    }
+
+    /// Default render options, from the curve, bounds and colour scale given to
+    /// [`Heatmap::new`]. Everything about colouring can be changed per call to
+    /// [`Heatmap::render`], so they are only a starting point.
+    pub fn render_options(&self) -> RenderOptions {
+        RenderOptions {
+            curve: self.curve,
+            min_value: self.min_value,
+            max_value: self.max_value,
+            min_percentile: None,
+            max_percentile: None,
+            log_params: None,
+            gamma: 1.0,
+            palette: Palette::from(self.colour_scale),
+            bands: None,
+            snap_to_bands: false,
+            colour_lut: Some(DEFAULT_COLOUR_LUT_BITS),
+            lut_rounding: LutRounding::Nearest,
+            dither: None,
+            png: PngEncoding::default(),
+            category_colours: None,
+            background: None,
+            strings: Strings::default(),
+        }
+    }
+
+    /// The colour domain `options` resolve to. Each end is taken from the explicit
+    /// bound if set, else from its percentile of the non-zero cells, else from the
+    /// dataset range.
+    pub fn domain_bounds(&self, options: &RenderOptions) -> (f64, f64) {
+        let (data_min, data_max) = self.value_range();
+        let sorted = (options.min_value.is_none() && options.min_percentile.is_some()
+            || options.max_value.is_none() && options.max_percentile.is_some())
+        .then(|| self.sorted_values());
+        let percentile = |p: Option<f64>| Some(sorted.as_ref()?.percentile(p?)? as f64);
+        let min_value = options
+            .min_value
+            .or_else(|| percentile(options.min_percentile))
+            .unwrap_or(data_min as f64);
+        let max_value = options
+            .max_value
+            .or_else(|| percentile(options.max_percentile))
+            .unwrap_or(data_max as f64);
+        (min_value, max_value)
+    }
+
+    fn calculate_domain(&self, options: &RenderOptions) -> Result<ScaleDomain, &'static str> {
+        let (min_value, max_value) = self.domain_bounds(options);
 
         log::debug!(
             "Colour scaling: curve={}, min={:?}, max={}",
-            self.curve,
+            options.curve,
             min_value,
             max_value
         );
 
-        ScaleDomain::new(self.curve, min_value, max_value)
-    }
-
-    fn process_input_from_reader<R: BufRead>(&mut self, reader: R) -> Result<()> {
-        for (line_num, line) in reader.lines().enumerate() {
-            let line = line.context("Failed to read line")?;
-            let parts: Vec<&str> = if let Some(sep) = self.separator {
-                line.split(sep)
-                    .map(|s| s.trim())
-                    .filter(|s| !s.is_empty())
-                    .collect()
-            } else {
-                line.split(|c: char| c == ',' || c.is_whitespace())
-                    .map(|s| s.trim())
-                    .filter(|s| !s.is_empty())
-                    .collect()
-            };
+        let mut domain = ScaleDomain::new(options.curve, min_value, max_value)?;
+        // The parameters shape the log curve only, as on the legend
+        if let Some(params) = options.log_params
+            && options.curve == DomainType::Logarithmic
+        {
+            domain = domain.with_log_params(params)?;
+        }
+        // A percentile minimum clamps the cells below it rather than hiding them
+        Ok(match options.min_value.is_none() && options.min_percentile.is_some() {
+            true => domain.clamp_below(),
+            false => domain,
+        })
+    }
+
+    pub fn process_input_from_reader<R: BufRead>(&mut self, reader: R) -> Result<()> {
+        let result = self.process_lines(reader, 0, None);
+        self.warnings.log_suppressed();
+        result
+    }
+
+    /// Rejected-line warnings that were counted without being logged.
+    pub fn suppressed_warnings(&self) -> u64 {
+        self.warnings.suppressed()
+    }
+
+    /// Log how many rejected-line warnings were suppressed since the last such count.
+    pub fn log_suppressed_warnings(&mut self) {
+        self.warnings.log_suppressed();
+    }
 
-            if parts.is_empty() {
-                continue;
+    /// Process `reader`, numbering its lines after `first_line` earlier ones and
+    /// passing them to `observer`, if any.
+    pub(crate) fn process_lines<R: BufRead>(
+        &mut self,
+        reader: R,
+        first_line: usize,
+        mut observer: Option<&mut dyn RecordObserver>,
+    ) -> Result<()> {
+        // The timer is moved out so the line callback can borrow the heatmap mutably
+        let timer = std::mem::take(&mut self.timer);
+        let options = self.parse_options.clone();
+        let (host_bits_before, conflicts_before) = (self.cidr_host_bits, self.conflicts);
+        let (lines_before, rejected_before, out_of_bounds_before) =
+            (self.lines_processed, self.rejects.total(), self.out_of_bounds);
+        let factor = self.value_factor();
+        let result = input::for_each_record(reader, first_line, &options, &timer, |line_number, line, parsed| {
+            if let Some(observer) = observer.as_deref_mut() {
+                observer::observe(observer, line_number, line, &parsed);
             }
+            let processed = self.process_parsed(&timer, factor, true, line_number, line, parsed);
+            self.tick_preview_at(line_number);
+            processed
+        });
+        self.timer = timer;
+        self.warn_host_bits(host_bits_before);
+        self.warn_conflicts(conflicts_before);
+        self.warn_out_of_bounds(out_of_bounds_before);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_lines(self.lines_processed - lines_before, self.rejects.total() - rejected_before);
+        }
+        result
+    }
 
-            let ip_str = parts[0];
-            let value = if parts.len() > 1 {
-                parts[1].parse::<i32>().unwrap_or(1)
-            } else {
-                1
-            };
+    /// The factor record values are multiplied by: the weight, and for sums of a
+    /// sample 1/rate, so they estimate the full input's.
+    fn value_factor(&self) -> f64 {
+        let weighted = self.value_mode != ValueMode::Categorical && self.parse_options.value_source != ValueSource::PrefixLen;
+        let scale_up = self
+            .parse_options
+            .sampling
+            .filter(|_| self.accumulate && self.value_mode != ValueMode::Categorical)
+            .map_or(1.0, |sampling| 1.0 / sampling.rate());
+        if weighted { self.weight * scale_up } else { scale_up }
+    }
 
-            // Check if this is a CIDR prefix
-            if ip_str.contains('/') {
-                match ip_str.parse::<Ipv4Net>() {
-                    Ok(cidr) => {
-                        self.paint_cidr_range(&cidr, value)?;
-                    }
-                    Err(e) => {
-                        log::warn!(
-                            "Failed to parse CIDR on line {}: {} - {}",
-                            line_num + 1,
-                            ip_str,
-                            e
-                        );
-                        continue;
-                    }
-                }
-            } else {
-                // Process as individual IP
-                let addr = if ip_str.chars().all(|c| c.is_ascii_digit()) {
-                    match ip_str.parse::<u32>() {
-                        Ok(ip) => Ipv4Addr::from(ip),
-                        Err(e) => {
-                            log::warn!(
-                                "Failed to parse integer IP on line {}: {} - {}",
-                                line_num + 1,
-                                ip_str,
-                                e
-                            );
-                            continue;
-                        }
-                    }
-                } else {
-                    match Ipv4Addr::from_str(ip_str) {
-                        Ok(addr) => addr,
-                        Err(e) => {
-                            log::warn!(
-                                "Failed to parse IP address on line {}: {} - {}",
-                                line_num + 1,
-                                ip_str,
-                                e
-                            );
-                            continue;
-                        }
+    /// Count one parsed line and paint or reject it. Rejects are only logged when
+    /// `report` is set.
+    pub(crate) fn process_parsed(
+        &mut self,
+        timer: &PhaseTimer,
+        factor: f64,
+        report: bool,
+        line_number: usize,
+        line: &str,
+        parsed: ParsedLine,
+    ) -> Result<()> {
+        self.lines_processed += 1;
+        match parsed {
+            ParsedLine::Blank | ParsedLine::Unsampled => Ok(()),
+            ParsedLine::Ignored => {
+                self.ignored_values += 1;
+                Ok(())
+            }
+            ParsedLine::OutsideWindow => {
+                self.outside_window += 1;
+                Ok(())
+            }
+            ParsedLine::Record(record) if !self.admit(&record) => Ok(()),
+            ParsedLine::Record(record) => {
+                let value = self.count_record(&record, factor);
+                timer.time(Phase::Paint, || self.paint_record(&record, value))?;
+                self.finish_line_conflicts(line_number, line)
+            }
+            ParsedLine::Records(records) => {
+                records.iter().try_for_each(|record| {
+                    if !self.admit(record) {
+                        return Ok(());
                     }
-                };
-
-                self.paint_address(&addr, value)?;
+                    let value = self.count_record(record, factor);
+                    timer.time(Phase::Paint, || self.paint_record(record, value))
+                })?;
+                self.finish_line_conflicts(line_number, line)
             }
+            ParsedLine::Rejected(reason, message) => self.reject(line_number, line, reason, message, report),
         }
+    }
 
-        Ok(())
+    /// Count a parsed record in the totals, returning the value it is painted with.
+    pub(crate) fn count_record(&mut self, record: &Record, factor: f64) -> i32 {
+        if record.has_host_bits() {
+            self.cidr_host_bits += 1;
+        }
+        let value = weighted_value(record.value, factor);
+        self.weighted_total += value as i64;
+        self.count_clipped(record, value);
+        self.count_distinct(record);
+        value
+    }
+
+    /// Paint a counted record with `value`, or with the distinct counts of its pixels.
+    fn paint_record(&mut self, record: &Record, value: i32) -> Result<()> {
+        if self.preaggregate(record, value)? {
+            return Ok(());
+        }
+        if self.paints_distinct() {
+            self.paint_distinct(record);
+            return Ok(());
+        }
+        if self.parse_options.format == InputFormat::Cells {
+            self.paint_cell_row(&Record { net: record.net, value });
+            return Ok(());
+        }
+        self.paint_cidr_range(&record.net, value)
+    }
+
+    /// Warn once about the prefixes with host bits set counted since `before`.
+    pub(crate) fn warn_host_bits(&self, before: u64) {
+        let host_bits = self.cidr_host_bits - before;
+        if host_bits > 0 && self.parse_options.cidr_host_bits == CidrHostBits::Warn {
+            log::warn!(
+                count = host_bits;
+                "{} CIDR prefixes had host bits set and were painted from their network address",
+                host_bits
+            );
+        }
     }
 
     pub fn process_input_from_string(&mut self, input: &str) -> Result<()> {
@@ -319,90 +965,172 @@ impl Heatmap {
         self.process_input_from_reader(reader)
     }
 
+    #[deprecated(note = "use `render(&options)?.into_raw()`, with options from `render_options()`")]
     pub fn get_rgba_data(&self) -> Result<Vec<u8>> {
+        let image = self.render(&self.render_options()).map_err(|e| anyhow!(e))?;
+        Ok(image.into_raw())
+    }
+
+    #[deprecated(note = "use `render`, with options from `render_options()`")]
+    pub fn create_image(&self) -> Result<RgbaImage, &'static str> {
+        self.render(&self.render_options())
+    }
+
+    /// Colourise the current buffer with `palette` instead of the configured colour scale.
+    #[deprecated(note = "use `render` with `RenderOptions::palette` set")]
+    pub fn create_image_with_palette(&self, palette: &Palette) -> Result<RgbaImage, &'static str> {
+        self.render(&RenderOptions {
+            palette: palette.clone(),
+            ..self.render_options()
+        })
+    }
+
+    /// Colourise the current buffer using `options`.
+    ///
+    /// The buffer is not modified, so the same processed input can be rendered
+    /// several times with different options.
+    pub fn render(&self, options: &RenderOptions) -> Result<RgbaImage, &'static str> {
         let image_size = self.image_size();
-        let size = (image_size * image_size * 4) as usize;
-        let mut rgba_data = Vec::with_capacity(size);
-
-        match self.value_mode {
-            ValueMode::Categorical => {
-                for y in 0..image_size {
-                    for x in 0..image_size {
-                        let value = self.buffer[y as usize][x as usize];
-                        if value < 0 {
-                            rgba_data.extend_from_slice(&[0, 0, 0, 0]);
-                        } else {
-                            let [r, g, b] = CATEGORICAL_PALETTE[value as usize % CATEGORICAL_PALETTE.len()];
-                            rgba_data.extend_from_slice(&[r, g, b, 255]);
-                        }
-                    }
-                }
-            }
-            ValueMode::Raw | ValueMode::Scaled => {
-                let domain = self.calculate_domain().map_err(|e| anyhow!(e))?;
-                for y in 0..image_size {
-                    for x in 0..image_size {
-                        let value = self.buffer[y as usize][x as usize];
-
-                        if let Some(scaled) = domain.scale(value.into()) {
-                            let (r, g, b) = self.colour_scale.eval_continuous(scaled).as_tuple();
-                            rgba_data.extend_from_slice(&[r, g, b, 255]);
-                        } else {
-                            rgba_data.extend_from_slice(&[0, 0, 0, 0]);
-                        }
-                    }
-                }
-            }
+        let mut image = RgbaImage::new(image_size, image_size);
+        self.render_into(options, &mut image)?;
+        Ok(image)
+    }
+
+    /// Colourise the buffer into `image`, which must be transparent and of the map's
+    /// size. Pixels without a colour are left transparent.
+    pub(crate) fn render_into(&self, options: &RenderOptions, image: &mut RgbaImage) -> Result<(), &'static str> {
+        let image_size = self.image_size();
+        if image.dimensions() != (image_size, image_size) {
+            return Err("Image size does not match the map");
+        }
+        let colouring = self.colouring(options)?;
+        let started = self.timer.start();
+        let began = Instant::now();
+        for (y, row) in image.chunks_exact_mut(image_size as usize * 4).enumerate() {
+            self.colour_row(y, &colouring, options, row);
         }
+        self.timer.stop(Phase::Colourise, started);
+        self.record_render(began);
+        Ok(())
+    }
 
-        Ok(rgba_data)
+    /// Count a render that began at `began` in the metrics, if any.
+    pub(crate) fn record_render(&self, began: Instant) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_render(began.elapsed());
+            metrics.set_buffer_bytes(self.buffer_bytes());
+        }
     }
 
-    pub fn create_image(&self) -> Result<RgbaImage, &'static str> {
-        let image_size = self.image_size();
-        let mut image = ImageBuffer::from_pixel(image_size, image_size, Rgba([0, 0, 0, 0]));
-
-        match self.value_mode {
-            ValueMode::Categorical => {
-                for y in 0..image_size {
-                    for x in 0..image_size {
-                        let value = self.buffer[y as usize][x as usize];
-                        if value >= 0 {
-                            let [r, g, b] = CATEGORICAL_PALETTE[value as usize % CATEGORICAL_PALETTE.len()];
-                            image.put_pixel(x, y, Rgba([r, g, b, 255]));
-                        }
-                    }
-                }
-            }
-            ValueMode::Raw | ValueMode::Scaled => {
-                let domain = self.calculate_domain()?;
-                for y in 0..image_size {
-                    for x in 0..image_size {
-                        let value = self.buffer[y as usize][x as usize];
-
-                        if let Some(scaled) = domain.scale(value.into()) {
-                            let (r, g, b) = self.colour_scale.eval_continuous(scaled).as_tuple();
-                            image.put_pixel(x, y, Rgba([r, g, b, 255]));
+    /// Work out how values map to colours for [`Heatmap::colour_row`].
+    fn colouring(&self, options: &RenderOptions) -> Result<Colouring, &'static str> {
+        if self.value_mode == ValueMode::Categorical {
+            return Ok(Colouring::Categorical);
+        }
+        let domain = self.timer.time(Phase::Domain, || self.calculate_domain(options))?;
+        let snapped = match &options.bands {
+            Some(bands) if options.snap_to_bands => Some(bands::band_spans(bands, &domain, options)),
+            _ => None,
+        };
+        let lut = options.colour_lut.map(|bits| ColourLut::new(&options.palette, bits).with_rounding(options.lut_rounding));
+        Ok(Colouring::Scaled { domain, snapped, lut })
+    }
+
+    /// Colourise row `y` of the buffer into `row`, four RGBA bytes per pixel. Pixels
+    /// without a colour take [`RenderOptions::background`], or are left as they are
+    /// without one, so `row` should start transparent.
+    fn colour_row(&self, y: usize, colouring: &Colouring, options: &RenderOptions, row: &mut [u8]) {
+        for (x, (&value, pixel)) in self.buffer[y].iter().zip(row.chunks_exact_mut(4)).enumerate() {
+            let colour = match colouring {
+                Colouring::Categorical if value >= 0 => Some(
+                    options
+                        .category_colours
+                        .as_ref()
+                        .and_then(|colours| colours.get(value))
+                        .unwrap_or(CATEGORICAL_PALETTE[value as usize % CATEGORICAL_PALETTE.len()]),
+                ),
+                Colouring::Categorical => None,
+                Colouring::Scaled { domain, snapped, lut } => domain.scale(value.into()).map(|scaled| {
+                    match (snapped, &options.bands) {
+                        (Some(spans), Some(bands)) => spans[bands.band_of(value.into())].colour.unwrap_or_default(),
+                        _ => {
+                            let scaled = if options.gamma == 1.0 { scaled } else { scaled.powf(options.gamma) };
+                            let scaled = match &options.dither {
+                                Some(dither) => dither.apply(scaled, x as u32, y as u32),
+                                None => scaled,
+                            };
+                            match lut {
+                                Some(lut) => lut.eval(scaled),
+                                None => options.palette.eval(scaled),
+                            }
                         }
                     }
-                }
+                }),
+            };
+            if let Some([r, g, b]) = colour.or(options.background) {
+                pixel.copy_from_slice(&[r, g, b, 255]);
             }
         }
-
-        Ok(image)
     }
 
+    #[deprecated(note = "use `save_with_options`, with options from `render_options()`")]
     pub fn save(&self, filename: &str) -> Result<(), anyhow::Error> {
-        let image = self.create_image().map_err(|err| anyhow!(err))?;
-        image
-            .save(filename)
-            .context(format!("Failed to save image to {}", filename))
+        self.save_with_options(filename, &self.render_options())
+    }
+
+    /// Render with `options` and save as PNG, recording the parameters as PNG text chunks.
+    pub fn save_with_options(&self, filename: &str, options: &RenderOptions) -> Result<()> {
+        if self.streams_encode() {
+            return self.save_streamed(filename, options, &self.png_metadata(options));
+        }
+        let image = self.render(options).map_err(|err| anyhow!(err))?;
+        self.timer.time(Phase::Encode, || {
+            output::save_png(filename, &image, &self.png_metadata(options), &options.png)
+        })
+    }
+
+    /// Parameters describing a rendering, stored in the output PNG.
+    pub fn png_metadata(&self, options: &RenderOptions) -> Vec<(String, String)> {
+        let optional = |v: Option<f64>| v.map_or_else(|| "auto".to_string(), |v| v.to_string());
+        let mut metadata = vec![
+            ("Software".to_string(), format!("ip-heatmap {}", env!("CARGO_PKG_VERSION"))),
+            ("curve".to_string(), options.curve.to_string()),
+            ("min_value".to_string(), optional(options.min_value)),
+            ("max_value".to_string(), optional(options.max_value)),
+            ("min_percentile".to_string(), optional(options.min_percentile)),
+            ("max_percentile".to_string(), optional(options.max_percentile)),
+            ("log_base".to_string(), optional(options.log_params.map(|p| p.base))),
+            ("log_offset".to_string(), optional(options.log_params.map(|p| p.offset))),
+            ("gamma".to_string(), options.gamma.to_string()),
+            ("colour_lut".to_string(), options.colour_lut.map_or_else(|| "off".to_string(), |bits| bits.to_string())),
+            ("palette".to_string(), options.palette.name().to_string()),
+            ("value_mode".to_string(), self.value_mode.to_string()),
+            ("bits_per_pixel".to_string(), self.bits_per_pixel.to_string()),
+            ("accumulate".to_string(), self.accumulate.to_string()),
+            ("sample_rate".to_string(), self.sampling().map_or(1.0, |s| s.rate()).to_string()),
+        ];
+        if let Some(bands) = &options.bands {
+            metadata.push(("legend_bands".to_string(), bands.to_string()));
+            metadata.push(("snap_to_bands".to_string(), options.snap_to_bands.to_string()));
+        }
+        if options.colour_lut.is_some() && options.lut_rounding != LutRounding::Nearest {
+            metadata.push(("lut_rounding".to_string(), options.lut_rounding.to_string()));
+        }
+        if let Some(dither) = &options.dither {
+            metadata.push(("dither".to_string(), dither.pattern.to_string()));
+            metadata.push(("dither_strength".to_string(), dither.strength.to_string()));
+        }
+        if let Some([r, g, b]) = options.background {
+            metadata.push(("background".to_string(), format!("#{:02x}{:02x}{:02x}", r, g, b)));
+        }
+        metadata
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use image::Rgba;
 
     #[test]
     fn test_image_size_for_bpp() {
@@ -465,7 +1193,7 @@ mod tests {
         // Use bpp=24 (16x16) to keep the test fast
         let mut hm = make_heatmap(24);
         hm.process_input_from_string("10.0.0.0/8 1\n").unwrap();
-        let img = hm.create_image().unwrap();
+        let img = hm.render(&hm.render_options()).unwrap();
         assert_eq!(img.width(), 16);
         assert_eq!(img.height(), 16);
     }
@@ -474,7 +1202,7 @@ mod tests {
     fn test_rgba_data_length() {
         let mut hm = make_heatmap(24);
         hm.process_input_from_string("10.0.0.0/8 1\n").unwrap();
-        let data = hm.get_rgba_data().unwrap();
+        let data = hm.render(&hm.render_options()).unwrap().into_raw();
         // 16*16 pixels, 4 bytes each
         assert_eq!(data.len(), 16 * 16 * 4);
     }
@@ -530,7 +1258,7 @@ mod tests {
         // bpp=24 gives a tiny 16x16 image; run the full pipeline end-to-end
         let mut hm = make_heatmap(24);
         hm.process_input_from_string("10.0.0.0/8 1\n192.168.0.0/16 5\n").unwrap();
-        let img = hm.create_image().unwrap();
+        let img = hm.render(&hm.render_options()).unwrap();
         assert_eq!(img.width(), 16);
         assert_eq!(img.height(), 16);
     }
@@ -542,7 +1270,7 @@ mod tests {
         for bpp in (8..=24).step_by(2) {
             let mut hm = make_heatmap(bpp);
             hm.process_input_from_string(input).unwrap();
-            let img = hm.create_image().unwrap();
+            let img = hm.render(&hm.render_options()).unwrap();
             let size = img.width();
             assert_eq!(size, img.height(), "image should be square at bpp={}", bpp);
             assert_eq!(size, image_size_for_bpp(bpp), "image dimensions should match image_size_for_bpp at bpp={}", bpp);
@@ -552,4 +1280,424 @@ mod tests {
             prev_size = Some(size);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_rejected_lines_are_recorded() {
+        let mut hm = make_heatmap(24);
+        hm.process_input_from_string("10.0.0.0/8 1\nnot-an-ip 2\n\n10.0.0.0/33\n99999999999\n")
+            .unwrap();
+        assert_eq!(hm.lines_processed(), 5);
+        assert_eq!(hm.rejects().total(), 3);
+
+        let errors = hm.take_errors();
+        let summary: Vec<(usize, RejectReason)> =
+            errors.iter().map(|r| (r.line_number, r.reason)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (2, RejectReason::InvalidIp),
+                (4, RejectReason::InvalidCidr),
+                (5, RejectReason::InvalidIntegerIp),
+            ]
+        );
+        assert_eq!(errors[0].content, "not-an-ip 2");
+        assert!(hm.take_errors().is_empty());
+        assert_eq!(hm.rejects().total(), 3);
+    }
+
+    #[test]
+    fn test_accumulated_values_saturate() {
+        let mut hm = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        hm.process_input_from_string("10.0.0.1 2147483647\n10.0.0.1 5\n10.9.0.1 -2147483648\n10.9.0.1 -1\n10.0.0.1 3000000000\n")
+            .unwrap();
+        let cells: Vec<i32> = hm.buffer.cells().iter().copied().filter(|&value| value != 0).collect();
+        assert_eq!(cells, vec![i32::MAX, i32::MIN]);
+        let errors = hm.take_errors();
+        assert_eq!((errors.len(), errors[0].reason), (1, RejectReason::ValueOutOfRange));
+    }
+
+    #[test]
+    fn test_max_rejects_limits_samples() {
+        let mut hm = make_heatmap(24);
+        hm.set_parse_options(ParseOptions::default().max_rejects(1));
+        hm.process_input_from_string("a\nb\nc\n").unwrap();
+        assert_eq!(hm.rejects().total(), 3);
+        assert_eq!(hm.rejects().samples().len(), 1);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_shims_match_render() {
+        let mut hm = make_heatmap(24);
+        hm.process_input_from_string("10.0.0.0/8 1\n192.168.0.0/16 5\n").unwrap();
+        let rendered = hm.render(&hm.render_options()).unwrap();
+        assert_eq!(hm.create_image().unwrap(), rendered);
+        assert_eq!(hm.get_rgba_data().unwrap(), rendered.clone().into_raw());
+        let inferno = Palette::from(&colorous::INFERNO);
+        let options = RenderOptions { palette: inferno.clone(), ..hm.render_options() };
+        assert_eq!(hm.create_image_with_palette(&inferno).unwrap(), hm.render(&options).unwrap());
+        let path = std::env::temp_dir().join(format!("ip-heatmap-shim-{}.png", std::process::id()));
+        hm.save(path.to_str().unwrap()).unwrap();
+        assert_eq!(image::open(&path).unwrap().to_rgba8(), rendered);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_render_options_match_constructor_state() {
+        // One buffer recoloured per call equals a heatmap built with those settings
+        let input = "10.0.0.0/8 1\n10.1.0.0/16 40\n192.168.0.0/16 5\n8.8.8.8 300\n";
+        let mut shared = make_heatmap(16);
+        shared.process_input_from_string(input).unwrap();
+        let curves = [DomainType::Linear, DomainType::Logarithmic, "symlog:2".parse().unwrap()];
+        let bounds = [(None, None), (Some(2.0), None), (None, Some(100.0)), (Some(1.0), Some(50.0))];
+        let gradients = [&colorous::MAGMA, &colorous::VIRIDIS];
+        for curve in curves {
+            for (min_value, max_value) in bounds {
+                for gradient in gradients {
+                    let mut fresh =
+                        Heatmap::new(curve, min_value, max_value, true, 16, gradient, ValueMode::Scaled, None);
+                    fresh.process_input_from_string(input).unwrap();
+                    let palette = Palette::from(gradient);
+                    let options = RenderOptions { curve, min_value, max_value, palette, ..shared.render_options() };
+                    let expected = fresh.render(&fresh.render_options()).unwrap();
+                    assert_eq!(shared.render(&options).unwrap(), expected, "{} {:?}", curve, (min_value, max_value));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_background_fills_uncoloured_pixels() {
+        let mut hm = make_heatmap(24);
+        hm.process_input_from_string("10.0.0.0/8 1\n").unwrap();
+        let transparent = hm.render(&hm.render_options()).unwrap();
+        let options = RenderOptions { background: Some([1, 2, 3]), ..hm.render_options() };
+        let filled = hm.render(&options).unwrap();
+        for (plain, filled) in transparent.pixels().zip(filled.pixels()) {
+            match plain.0[3] {
+                0 => assert_eq!(filled.0, [1, 2, 3, 255]),
+                _ => assert_eq!(filled, plain),
+            }
+        }
+        // Streamed rows are coloured the same way
+        let mut streamed = Vec::new();
+        hm.write_png_streamed(&mut streamed, &options, &[]).unwrap();
+        assert_eq!(image::load_from_memory(&streamed).unwrap().to_rgba8(), filled);
+    }
+
+    #[test]
+    fn test_render_with_different_options_from_one_buffer() {
+        let mut hm = make_heatmap(24);
+        hm.process_input_from_string("10.0.0.0/8 1000000\n11.0.0.0/8 10000000\n").unwrap();
+        let linear = hm.render(&RenderOptions::default()).unwrap();
+        let log = hm
+            .render(&RenderOptions {
+                curve: DomainType::Logarithmic,
+                ..RenderOptions::default()
+            })
+            .unwrap();
+        assert_eq!(linear.dimensions(), log.dimensions());
+        assert_ne!(linear, log);
+    }
+
+    #[test]
+    fn test_prefix_rect_even_and_odd_lengths() {
+        let hm = make_heatmap(16); // 256x256, /16 per pixel
+        // A /8 is 256 pixels: a 16x16 square
+        let (_, _, w, h) = hm.prefix_rect(&"10.0.0.0/8".parse().unwrap());
+        assert_eq!((w, h), (16, 16));
+        // A /7 is two adjacent 16x16 squares
+        let (_, _, w, h) = hm.prefix_rect(&"10.0.0.0/7".parse().unwrap());
+        assert_eq!(w * h, 512);
+        assert!((w, h) == (32, 16) || (w, h) == (16, 32));
+        // 0.0.0.0/2 is the top-left quadrant
+        assert_eq!(hm.prefix_rect(&"0.0.0.0/2".parse().unwrap()), (0, 0, 128, 128));
+        // Prefixes smaller than a pixel map to that pixel
+        let (x, y, w, h) = hm.prefix_rect(&"10.0.0.0/24".parse().unwrap());
+        assert_eq!((w, h), (1, 1));
+        assert_eq!(Some((x, y)), hm.ip_to_xy(u32::from(Ipv4Addr::new(10, 0, 0, 0))));
+    }
+
+    #[test]
+    fn test_prefix_totals() {
+        let mut hm = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        hm.process_input_from_string("10.1.0.0 5\n10.2.0.0 7\n192.168.0.0 1\n").unwrap();
+        let totals = hm.prefix_totals(8).unwrap();
+        assert_eq!(totals.len(), 256);
+        assert_eq!(totals[10], ("10.0.0.0/8".parse().unwrap(), 12));
+        assert_eq!(totals[192].1, 1);
+        assert_eq!(totals.iter().map(|t| t.1).sum::<i64>(), 13);
+        assert!(hm.prefix_totals(24).is_err());
+    }
+
+    /// A heatmap whose non-zero cells hold the values 1 to 100, one per /8.
+    fn uniform_heatmap() -> Heatmap {
+        let mut hm = make_heatmap(24);
+        for i in 0..100u32 {
+            let net = Ipv4Net::new(Ipv4Addr::from(i << 24), 8).unwrap();
+            hm.paint_cidr_range(&net, i as i32 + 1).unwrap();
+        }
+        hm
+    }
+
+    #[test]
+    fn test_winsorized_domain() {
+        let hm = uniform_heatmap();
+        let winsorized = RenderOptions {
+            min_percentile: Some(1.0),
+            max_percentile: Some(99.0),
+            ..hm.render_options()
+        };
+        assert_eq!(hm.domain_bounds(&winsorized), (1.0, 99.0));
+        let top_only = RenderOptions {
+            max_percentile: Some(90.0),
+            ..hm.render_options()
+        };
+        // Untouched cells keep the minimum at zero
+        assert_eq!(hm.domain_bounds(&top_only), (0.0, 90.0));
+    }
+
+    #[test]
+    fn test_log_params_only_apply_to_the_log_curve() {
+        let hm = uniform_heatmap();
+        // Untouched cells keep the minimum at zero, which no offset of 0 can take a log of
+        let log_params = Some(LogParams::new(10.0, 0.0).unwrap());
+        let linear = RenderOptions {
+            curve: DomainType::Linear,
+            log_params,
+            ..hm.render_options()
+        };
+        assert!(hm.calculate_domain(&linear).is_ok());
+        let log = RenderOptions {
+            curve: DomainType::Logarithmic,
+            ..linear
+        };
+        assert!(hm.calculate_domain(&log).is_err());
+    }
+
+    #[test]
+    fn test_explicit_bounds_win_per_endpoint() {
+        let hm = uniform_heatmap();
+        let options = RenderOptions {
+            min_value: Some(5.0),
+            min_percentile: Some(10.0),
+            max_percentile: Some(80.0),
+            ..hm.render_options()
+        };
+        assert_eq!(hm.domain_bounds(&options), (5.0, 80.0));
+        let options = RenderOptions {
+            max_value: Some(50.0),
+            ..options
+        };
+        assert_eq!(hm.domain_bounds(&options), (5.0, 50.0));
+    }
+
+    #[test]
+    fn test_winsorized_values_are_clamped_not_dropped() {
+        let hm = uniform_heatmap();
+        let options = RenderOptions {
+            min_percentile: Some(10.0),
+            max_percentile: Some(90.0),
+            ..hm.render_options()
+        };
+        let image = hm.render(&options).unwrap();
+        let pixel = |value: u32| {
+            let (x, y) = hm.ip_to_xy((value - 1) << 24).unwrap();
+            *image.get_pixel(x, y)
+        };
+        let [r, g, b] = options.palette.eval(0.0);
+        assert_eq!(pixel(3), Rgba([r, g, b, 255]));
+        assert_eq!(pixel(10), Rgba([r, g, b, 255]));
+        let [r, g, b] = options.palette.eval(1.0);
+        assert_eq!(pixel(95), Rgba([r, g, b, 255]));
+        // Untouched cells stay transparent
+        let (x, y) = hm.ip_to_xy(200 << 24).unwrap();
+        assert_eq!(image.get_pixel(x, y).0[3], 0);
+    }
+
+    #[test]
+    fn test_gamma_one_is_a_no_op() {
+        let hm = uniform_heatmap();
+        let options = hm.render_options();
+        let explicit = RenderOptions {
+            gamma: 1.0,
+            ..options.clone()
+        };
+        assert_eq!(hm.render(&options).unwrap().into_raw(), hm.render(&explicit).unwrap().into_raw());
+    }
+
+    #[test]
+    fn test_gamma_is_applied_after_the_curve() {
+        let hm = uniform_heatmap();
+        let options = RenderOptions {
+            gamma: 0.5,
+            colour_lut: None,
+            ..hm.render_options()
+        };
+        // Value 25 of 100 scales to 0.25, and 0.25 ^ 0.5 = 0.5
+        let (x, y) = hm.ip_to_xy(24 << 24).unwrap();
+        let [r, g, b] = options.palette.eval(0.5);
+        assert_eq!(*hm.render(&options).unwrap().get_pixel(x, y), Rgba([r, g, b, 255]));
+    }
+
+    #[test]
+    fn test_dither_keeps_the_mean_colour() {
+        // A quarter of the map at one value, between two of the palette's levels
+        let mut hm = Heatmap::new(DomainType::Linear, Some(0.0), Some(1000.0), true, 16, &colorous::VIRIDIS, ValueMode::Raw, None);
+        hm.process_input_from_string("0.0.0.0/2 503\n").unwrap();
+        let plain = hm.render(&hm.render_options()).unwrap();
+        let quarter = |image: &RgbaImage| -> Vec<Rgba<u8>> {
+            image.enumerate_pixels().filter(|(x, y, _)| *x < 128 && *y < 128).map(|(_, _, pixel)| *pixel).collect()
+        };
+        let mean = |pixels: &[Rgba<u8>], channel: usize| {
+            pixels.iter().map(|pixel| pixel.0[channel] as f64).sum::<f64>() / pixels.len() as f64
+        };
+        for pattern in DitherPattern::ALL {
+            for colour_lut in [Some(DEFAULT_COLOUR_LUT_BITS), None] {
+                let dither = Some(Dither { pattern, strength: 4.0 });
+                let options = RenderOptions { dither, colour_lut, ..hm.render_options() };
+                let dithered = hm.render(&options).unwrap();
+                // Reproducible, and the same as rendering again into another image
+                assert_eq!(dithered, hm.render(&options).unwrap());
+                let (before, after) = (quarter(&plain), quarter(&dithered));
+                assert!(before.iter().all(|pixel| *pixel == before[0]));
+                assert!(after.iter().any(|pixel| *pixel != before[0]), "{}", pattern);
+                // The plain colour is itself rounded to whole levels, by up to half of one
+                for channel in 0..3 {
+                    let (from, to) = (mean(&before, channel), mean(&after, channel));
+                    assert!((from - to).abs() < 0.6, "{} channel {}: {} became {}", pattern, channel, from, to);
+                }
+                // Only painted pixels are coloured
+                assert_eq!(dithered.pixels().filter(|pixel| pixel.0[3] == 255).count(), 128 * 128);
+            }
+        }
+        // Off by default, and at strength 0 the same as off
+        assert_eq!(hm.render_options().dither, None);
+        let none = RenderOptions { dither: Some(Dither { pattern: DitherPattern::BlueNoise, strength: 0.0 }), ..hm.render_options() };
+        assert_eq!(hm.render(&none).unwrap(), plain);
+    }
+
+    fn sampled_total(sampling: Option<Sampling>, input: &str) -> i64 {
+        let mut hm = Heatmap::new(DomainType::Linear, None, None, true, 24, &colorous::MAGMA, ValueMode::Raw, None);
+        hm.set_parse_options(ParseOptions::default().sampling(sampling));
+        hm.process_input_from_string(input).unwrap();
+        assert_eq!(hm.lines_processed(), 20_000);
+        hm.buffer.cells().iter().map(|&v| v as i64).sum()
+    }
+
+    #[test]
+    fn test_sampled_totals_estimate_the_full_run() {
+        let input: String = (0..20_000u32)
+            .map(|i| format!("{} {}\n", Ipv4Addr::from((i % 16) << 24 | i), i % 10 + 1))
+            .collect();
+        let full = sampled_total(None, &input) as f64;
+        assert_eq!(sampled_total(Some(Sampling::new(1.0, 3).unwrap()), &input) as f64, full);
+
+        let seeds = 0..8u64;
+        let mut sum = 0.0;
+        for seed in seeds.clone() {
+            let total = sampled_total(Some(Sampling::new(0.1, seed).unwrap()), &input) as f64;
+            assert!((total / full - 1.0).abs() < 0.1, "seed {}: {} vs {}", seed, total, full);
+            sum += total;
+        }
+        let mean = sum / seeds.end as f64;
+        assert!((mean / full - 1.0).abs() < 0.03, "{} vs {}", mean, full);
+    }
+
+    #[test]
+    fn test_prefix_len_most_specific_wins_in_any_order() {
+        // A routing-table-like fixture: a /8, a /16 and a /24 inside it, and a lone host
+        let lines = ["10.0.0.0/8 100", "10.1.0.0/16 100", "10.1.2.0/24", "10.1.3.7 5"];
+        let render = |lines: &[&str]| {
+            let mut hm =
+                Heatmap::new(DomainType::Linear, None, None, false, 8, &colorous::MAGMA, ValueMode::Scaled, None);
+            hm.set_parse_options(ParseOptions::default().value_source(ValueSource::PrefixLen));
+            hm.process_input_from_string(&lines.join("\n")).unwrap();
+            hm
+        };
+        let forward = render(&lines);
+        let mut reversed = lines;
+        reversed.reverse();
+        assert_eq!(forward.buffer, render(&reversed).buffer);
+
+        let cell = |hm: &Heatmap, addr: [u8; 4]| {
+            let (x, y) = hm.ip_to_xy(u32::from(Ipv4Addr::from(addr))).unwrap();
+            hm.buffer[y as usize][x as usize]
+        };
+        // Values are not divided over the pixel's 256 addresses
+        assert_eq!(cell(&forward, [10, 9, 0, 0]), 8);
+        assert_eq!(cell(&forward, [10, 1, 9, 0]), 16);
+        assert_eq!(cell(&forward, [10, 1, 2, 0]), 24);
+        assert_eq!(cell(&forward, [10, 1, 3, 0]), 32);
+        assert_eq!(cell(&forward, [11, 0, 0, 0]), 0);
+    }
+
+    #[test]
+    fn test_value_modes_and_sources() {
+        // At -z 16 each pixel is a /16: two addresses in one pixel, a prefix over four
+        // pixels, one inside a pixel, and an address without a value
+        let input = "10.0.0.1 5\n10.0.0.2 5\n10.4.0.0/14 8\n10.8.0.0/18 8\n10.9.0.1\n";
+        use ValueMode::*;
+        use ValueSource::*;
+        let cases = [
+            (Scaled, Column, false, [0, 8, 2, 0]),
+            (Scaled, Column, true, [0, 8, 2, 0]),
+            (Raw, Column, false, [5, 8, 8, 1]),
+            (Raw, Column, true, [10, 8, 8, 1]),
+            (Categorical, Column, false, [5, 8, 8, 1]),
+            (Scaled, PrefixLen, false, [32, 14, 18, 32]),
+            (Raw, PrefixLen, true, [64, 14, 18, 32]),
+            (Categorical, PrefixLen, false, [32, 14, 18, 32]),
+            (Scaled, Count, false, [1, 1, 1, 1]),
+            (Scaled, Count, true, [2, 1, 1, 1]),
+            (Raw, Count, true, [2, 1, 1, 1]),
+        ];
+        for (value_mode, source, accumulate, expected) in cases {
+            let mut hm = Heatmap::new(DomainType::Linear, None, None, accumulate, 16, &colorous::MAGMA, value_mode, None);
+            hm.set_parse_options(ParseOptions::default().value_source(source));
+            hm.check_value_modes(None).unwrap();
+            hm.process_input_from_string(input).unwrap();
+            let cell = |addr: [u8; 4]| {
+                let (x, y) = hm.ip_to_xy(u32::from(Ipv4Addr::from(addr))).unwrap();
+                hm.buffer[y as usize][x as usize]
+            };
+            let cells = [cell([10, 0, 0, 0]), cell([10, 4, 0, 0]), cell([10, 8, 0, 0]), cell([10, 9, 0, 0])];
+            assert_eq!(cells, expected, "{} {} accumulate={}", value_mode, source, accumulate);
+            // The /14 covers exactly four pixels
+            assert_eq!(cell([10, 7, 0, 0]), expected[1]);
+            assert_eq!(cell([10, 3, 0, 0]), if value_mode == Categorical { -1 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn test_conflicting_value_modes_are_refused() {
+        let heatmap = |value_mode, source, accumulate| {
+            let mut hm = Heatmap::new(DomainType::Linear, None, None, accumulate, 16, &colorous::MAGMA, value_mode, None);
+            hm.set_parse_options(ParseOptions::default().value_source(source));
+            hm
+        };
+        let error = |hm: &Heatmap, aggregation| hm.check_value_modes(aggregation).unwrap_err().to_string();
+        assert!(error(&heatmap(ValueMode::Categorical, ValueSource::Column, true), None).contains("cannot accumulate"));
+        assert!(error(&heatmap(ValueMode::Categorical, ValueSource::Count, false), None).contains("not category labels"));
+        let counts = heatmap(ValueMode::Raw, ValueSource::Count, true);
+        assert!(error(&counts, Some(Aggregation::Mean)).contains("downsample counts with sum or max"));
+        counts.check_value_modes(Some(Aggregation::Sum)).unwrap();
+        let mut ignoring = heatmap(ValueMode::Raw, ValueSource::Count, true);
+        ignoring.set_parse_options(ParseOptions::default().value_source(ValueSource::Count).ignore_values(vec!["-1".to_string()]));
+        assert!(error(&ignoring, None).contains("Ignored values"));
+        heatmap(ValueMode::Scaled, ValueSource::Column, true).check_value_modes(Some(Aggregation::Mean)).unwrap();
+    }
+
+    #[test]
+    fn test_value_mode_and_source_aliases() {
+        for (alias, mode) in [("labels", ValueMode::Categorical), ("whole", ValueMode::Raw), ("proportional", ValueMode::Scaled)] {
+            assert_eq!(alias.parse::<ValueMode>(), Ok(mode));
+            assert_eq!(mode.to_string().parse::<ValueMode>(), Ok(mode));
+        }
+        for alias in ["column", "value", "weight", "Bytes"] {
+            assert_eq!(alias.parse::<ValueSource>(), Ok(ValueSource::Column));
+        }
+        assert_eq!("count".parse::<ValueSource>(), Ok(ValueSource::Count));
+        assert_eq!(ValueSource::Count.to_string(), "count");
+    }
+}
@@ -0,0 +1,63 @@
+//! Readers bounding how long and how much a network peer can send, shared by the
+//! server and the URL input client.
+
+use std::io::Read;
+use std::time::Instant;
+
+/// Fails reads once `deadline` has passed, so a slow peer cannot hold a connection
+/// by sending a byte at a time within the socket timeout.
+pub(crate) struct Deadline<R> {
+    pub inner: R,
+    pub deadline: Instant,
+}
+
+impl<R: Read> Read for Deadline<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if Instant::now() >= self.deadline {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Fails reads beyond `remaining` bytes, bounding what a small gzip body expands to.
+pub(crate) struct Limited<R> {
+    pub inner: R,
+    pub remaining: u64,
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.remaining = self
+            .remaining
+            .checked_sub(read as u64)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::FileTooLarge, "body is too large"))?;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_decoded_size_is_limited() {
+        let mut limited = Limited { inner: [0u8; 100].as_slice(), remaining: 10 };
+        let mut sink = Vec::new();
+        let err = limited.read_to_end(&mut sink).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::FileTooLarge);
+
+        let mut exact = Limited { inner: [0u8; 10].as_slice(), remaining: 10 };
+        assert_eq!(exact.read_to_end(&mut sink).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_reads_stop_at_the_deadline() {
+        let mut late = Deadline { inner: [0u8; 10].as_slice(), deadline: Instant::now() - Duration::from_secs(1) };
+        assert_eq!(late.read(&mut [0; 4]).unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        let mut early = Deadline { inner: [0u8; 10].as_slice(), deadline: Instant::now() + Duration::from_secs(60) };
+        assert_eq!(early.read(&mut [0; 4]).unwrap(), 4);
+    }
+}
@@ -0,0 +1,647 @@
+//! Input read and parsed ahead of painting for long-running collections, with
+//! bounded queues between the stages.
+//!
+//! A reader thread hands chunks of input to a parser thread, which hands batches of
+//! parsed lines to the painting thread. Each queue holds a fixed number of items, so
+//! when painting stalls, e.g. while a snapshot is written, the parser and then the
+//! reader wait for room (or the parser drops whole batches, see [`QueueFull::Drop`])
+//! and memory stays bounded by the queue sizes, however far ahead the input runs.
+
+use crate::Heatmap;
+use crate::input::{self, ParseOptions, ParsedLine};
+use crate::json::JsonValue;
+use crate::timing::PhaseTimer;
+use anyhow::{Result, anyhow};
+use std::cell::RefCell;
+use std::fmt::{self, Display};
+use std::io::{BufRead, Read};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError, sync_channel};
+use std::time::{Duration, Instant};
+
+/// Items each queue holds unless `--live-queue-size` says otherwise.
+pub const DEFAULT_LIVE_QUEUE_SIZE: usize = 16;
+
+/// Bytes read from the input at a time.
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Lines parsed per batch. A batch is also handed on whenever the input is quiet, so
+/// a slow trickle of lines is painted as it arrives.
+const BATCH_LINES: usize = 4096;
+
+/// What the parser does with a batch when the queue to the painter is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueueFull {
+    /// Wait for room, holding back the reader and, once the pipe fills, the producer.
+    #[default]
+    Block,
+    /// Drop the batch and count its lines, for producers that must not be held back.
+    Drop,
+}
+
+impl FromStr for QueueFull {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(QueueFull::Block),
+            "drop" => Ok(QueueFull::Drop),
+            _ => Err(format!("Invalid queue policy: {}. Use 'block' or 'drop'", s)),
+        }
+    }
+}
+
+impl Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            QueueFull::Block => "block",
+            QueueFull::Drop => "drop",
+        })
+    }
+}
+
+/// The queue sizes of a [`LiveInput`] and what happens when one is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LiveOptions {
+    /// Items each queue holds, at least 1.
+    pub queue_size: usize,
+    pub when_full: QueueFull,
+}
+
+impl Default for LiveOptions {
+    fn default() -> Self {
+        LiveOptions { queue_size: DEFAULT_LIVE_QUEUE_SIZE, when_full: QueueFull::Block }
+    }
+}
+
+/// How a queue between two stages was used.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    pub capacity: usize,
+    /// Items handed on.
+    pub sent: u64,
+    /// Items that found the queue full, and then waited or were dropped.
+    pub full: u64,
+    /// Time the sending stage spent waiting for room.
+    pub blocked: Duration,
+    /// The most items waiting at once.
+    pub high_water: usize,
+}
+
+impl QueueStats {
+    fn to_json(self) -> JsonValue {
+        let mut json = JsonValue::object();
+        json.insert("capacity", self.capacity);
+        json.insert("sent", self.sent);
+        json.insert("full", self.full);
+        json.insert("blocked_ms", self.blocked.as_secs_f64() * 1e3);
+        json.insert("high_water", self.high_water);
+        json
+    }
+
+    /// e.g. `16 of 16 at most, full 3 times, 1.250 s blocked`.
+    pub fn to_text(&self) -> String {
+        format!(
+            "{} of {} at most, full {} times, {:.3} s blocked",
+            self.high_water,
+            self.capacity,
+            self.full,
+            self.blocked.as_secs_f64()
+        )
+    }
+}
+
+/// The queues of a [`LiveInput`], reported with the stats.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LiveStats {
+    /// Chunks of input from the reader to the parser.
+    pub read: QueueStats,
+    /// Batches of parsed lines from the parser to the painter.
+    pub parsed: QueueStats,
+    /// Lines dropped with [`QueueFull::Drop`], which are not counted as processed.
+    pub dropped_lines: u64,
+}
+
+impl LiveStats {
+    /// Whether either queue was ever full, so the input was held back or dropped.
+    pub fn was_full(&self) -> bool {
+        self.read.full + self.parsed.full > 0
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::object();
+        json.insert("read", self.read.to_json());
+        json.insert("parsed", self.parsed.to_json());
+        json.insert("dropped_lines", self.dropped_lines);
+        json
+    }
+}
+
+/// Counts kept by both ends of a queue.
+#[derive(Debug, Default)]
+struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    full: AtomicU64,
+    blocked_micros: AtomicU64,
+    high_water: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Counters {
+    fn stats(&self, capacity: usize) -> QueueStats {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        QueueStats {
+            capacity,
+            sent: load(&self.sent),
+            full: load(&self.full),
+            blocked: Duration::from_micros(load(&self.blocked_micros)),
+            // The channel holds no more than its capacity; the counts can run one ahead
+            high_water: (load(&self.high_water) as usize).min(capacity),
+        }
+    }
+}
+
+/// A bounded queue that counts how full it gets.
+fn queue<T>(capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (sender, receiver) = sync_channel(capacity);
+    let counters = Arc::new(Counters::default());
+    (QueueSender { sender, counters: Arc::clone(&counters) }, QueueReceiver { receiver, counters })
+}
+
+struct QueueSender<T> {
+    sender: SyncSender<T>,
+    counters: Arc<Counters>,
+}
+
+impl<T> QueueSender<T> {
+    /// Queue `item`, waiting for room or, with [`QueueFull::Drop`], dropping it when
+    /// the queue is full. Returns whether it was queued, or `Err` once the receiving
+    /// stage has stopped.
+    fn send(&self, item: T, when_full: QueueFull) -> Result<bool, ()> {
+        let item = match self.sender.try_send(item) {
+            Ok(()) => {
+                self.count_sent();
+                return Ok(true);
+            }
+            Err(TrySendError::Disconnected(_)) => return Err(()),
+            Err(TrySendError::Full(item)) => item,
+        };
+        self.counters.full.fetch_add(1, Ordering::Relaxed);
+        if when_full == QueueFull::Drop {
+            return Ok(false);
+        }
+        let started = Instant::now();
+        let sent = self.sender.send(item);
+        self.counters.blocked_micros.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        sent.map_err(|_| ())?;
+        self.count_sent();
+        Ok(true)
+    }
+
+    fn count_sent(&self) {
+        let sent = self.counters.sent.fetch_add(1, Ordering::Relaxed) + 1;
+        let waiting = sent.saturating_sub(self.counters.received.load(Ordering::Relaxed));
+        self.counters.high_water.fetch_max(waiting, Ordering::Relaxed);
+    }
+}
+
+struct QueueReceiver<T> {
+    receiver: Receiver<T>,
+    counters: Arc<Counters>,
+}
+
+impl<T> QueueReceiver<T> {
+    fn received<E>(&self, item: Result<T, E>) -> Result<T, E> {
+        if item.is_ok() {
+            self.counters.received.fetch_add(1, Ordering::Relaxed);
+        }
+        item
+    }
+
+    fn recv(&self) -> Option<T> {
+        self.received(self.receiver.recv()).ok()
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.received(self.receiver.try_recv())
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.received(self.receiver.recv_timeout(timeout))
+    }
+}
+
+/// Parsed lines in input order, with their text for rejects and conflicts.
+#[derive(Default)]
+struct Batch {
+    text: String,
+    /// Each line's number, the end of its text and its outcome.
+    lines: Vec<(usize, usize, ParsedLine)>,
+}
+
+/// The batch being filled by the parser.
+struct Outbox {
+    batch: Batch,
+    sender: QueueSender<Result<Batch>>,
+    when_full: QueueFull,
+    /// The parser's reading and parsing so far, and where it is shown to the painter.
+    timer: Rc<PhaseTimer>,
+    timings: Arc<Mutex<PhaseTimer>>,
+}
+
+impl Outbox {
+    /// Hand on the lines parsed so far. `Err` once the painter has stopped.
+    fn flush(&mut self) -> Result<(), ()> {
+        if self.timer.is_enabled()
+            && let Ok(mut timings) = self.timings.lock()
+        {
+            *timings = PhaseTimer::clone(&self.timer);
+        }
+        if self.batch.lines.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let lines = batch.lines.len() as u64;
+        if !self.sender.send(Ok(batch), self.when_full)? {
+            self.sender.counters.dropped.fetch_add(lines, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// The chunks from the reader as one stream for the line parser.
+struct ChunkReader {
+    chunks: QueueReceiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    offset: usize,
+    outbox: Rc<RefCell<Outbox>>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let length = available.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&available[..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+impl BufRead for ChunkReader {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.offset == self.chunk.len() {
+            let next = match self.chunks.try_recv() {
+                Ok(next) => Some(next),
+                Err(TryRecvError::Empty) => {
+                    // Every complete line before this chunk has been parsed, so hand
+                    // them to the painter rather than waiting on a quiet input
+                    if self.outbox.borrow_mut().flush().is_err() {
+                        return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "painting stopped"));
+                    }
+                    self.chunks.recv()
+                }
+                Err(TryRecvError::Disconnected) => None,
+            };
+            let Some(next) = next else { return Ok(&[]) };
+            self.chunk = next?;
+            self.offset = 0;
+        }
+        Ok(&self.chunk[self.offset..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.offset += amount;
+    }
+}
+
+fn read_chunks(mut reader: impl Read, chunks: QueueSender<std::io::Result<Vec<u8>>>) {
+    let mut chunk = vec![0; CHUNK_BYTES];
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(length) => Ok(chunk[..length].to_vec()),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        };
+        let failed = read.is_err();
+        // Chunks split lines anywhere, so they are never dropped
+        if chunks.send(read, QueueFull::Block).is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn parse_batches(
+    chunks: QueueReceiver<std::io::Result<Vec<u8>>>,
+    batches: QueueSender<Result<Batch>>,
+    options: ParseOptions,
+    when_full: QueueFull,
+    timings: Arc<Mutex<PhaseTimer>>,
+) {
+    let enabled = timings.lock().is_ok_and(|timings| timings.is_enabled());
+    let timer = Rc::new(PhaseTimer::new(enabled));
+    let outbox = Outbox { batch: Batch::default(), sender: batches, when_full, timer: Rc::clone(&timer), timings };
+    let outbox = Rc::new(RefCell::new(outbox));
+    let reader = ChunkReader { chunks, chunk: Vec::new(), offset: 0, outbox: Rc::clone(&outbox) };
+    let parsed = input::for_each_record(reader, 0, &options, &timer, |line_number, line, parsed| {
+        let mut outbox = outbox.borrow_mut();
+        outbox.batch.text.push_str(line);
+        let end = outbox.batch.text.len();
+        outbox.batch.lines.push((line_number, end, parsed));
+        if outbox.batch.lines.len() == BATCH_LINES {
+            outbox.flush().map_err(|()| anyhow!("Painting stopped"))?;
+        }
+        Ok(())
+    });
+    let mut outbox = outbox.borrow_mut();
+    // Unless painting stopped, the lines before a read error are painted and then it
+    // is reported
+    if outbox.flush().is_ok()
+        && let Err(err) = parsed
+    {
+        let _ = outbox.sender.send(Err(err), QueueFull::Block);
+    }
+}
+
+/// Input read and parsed on threads of their own while the caller paints it, see the
+/// [module documentation](self).
+///
+/// Dropping a `LiveInput` stops the parser, and the reader with it, once they next
+/// hand on an item; a reader blocked on a quiet input stops when it next reads.
+pub struct LiveInput {
+    batches: QueueReceiver<Result<Batch>>,
+    read: Arc<Counters>,
+    capacity: usize,
+    /// Counts before the input, for the warnings logged at its end.
+    before: (u64, u64, u64),
+    /// How long the parser thread has spent reading and parsing.
+    timings: Arc<Mutex<PhaseTimer>>,
+}
+
+impl LiveInput {
+    /// Start reading `reader` and parsing it with the parse options `heatmap` has now.
+    pub fn spawn(heatmap: &Heatmap, reader: impl Read + Send + 'static, options: LiveOptions) -> Self {
+        let capacity = options.queue_size.max(1);
+        let (chunk_sender, chunks) = queue(capacity);
+        let (batch_sender, batches) = queue(capacity);
+        let read = Arc::clone(&chunks.counters);
+        std::thread::spawn(move || read_chunks(reader, chunk_sender));
+        let parse_options = heatmap.parse_options.clone();
+        let timings = Arc::new(Mutex::new(PhaseTimer::new(heatmap.timer.is_enabled())));
+        let parser_timings = Arc::clone(&timings);
+        std::thread::spawn(move || parse_batches(chunks, batch_sender, parse_options, options.when_full, parser_timings));
+        let before = (heatmap.cidr_host_bits, heatmap.conflicts, heatmap.out_of_bounds);
+        LiveInput { batches, read, capacity, before, timings }
+    }
+
+    /// Paint the next batch of lines, waiting up to `timeout` for one. Returns `false`
+    /// once the input has ended and all of it was painted.
+    pub fn paint(&mut self, heatmap: &mut Heatmap, timeout: Duration) -> Result<bool> {
+        let painting = match self.batches.recv_timeout(timeout) {
+            Ok(batch) => batch.and_then(|batch| heatmap.paint_batch(batch)).map(|()| true),
+            Err(RecvTimeoutError::Timeout) => Ok(true),
+            Err(RecvTimeoutError::Disconnected) => Ok(false),
+        };
+        self.record(heatmap);
+        painting
+    }
+
+    pub fn stats(&self) -> LiveStats {
+        let parsed = &self.batches.counters;
+        LiveStats {
+            read: self.read.stats(self.capacity),
+            parsed: parsed.stats(self.capacity),
+            dropped_lines: parsed.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Log the warnings counted over the whole input, once it has ended or painting
+    /// stops early, and add the parser's reading and parsing to `heatmap`'s timer.
+    pub fn finish(self, heatmap: &mut Heatmap) {
+        if let Ok(timings) = self.timings.lock() {
+            heatmap.timer.merge(&timings);
+        }
+        let (host_bits, conflicts, out_of_bounds) = self.before;
+        heatmap.warn_host_bits(host_bits);
+        heatmap.warn_conflicts(conflicts);
+        heatmap.warn_out_of_bounds(out_of_bounds);
+        heatmap.log_suppressed_warnings();
+        self.record(heatmap);
+        let stats = self.stats();
+        if stats.dropped_lines > 0 {
+            log::warn!(
+                count = stats.dropped_lines;
+                "Dropped {} lines while painting fell behind (see --live-queue-full)",
+                stats.dropped_lines
+            );
+        }
+    }
+
+    fn record(&self, heatmap: &mut Heatmap) {
+        let stats = self.stats();
+        if let Some(metrics) = &heatmap.metrics {
+            metrics.record_live_queues(&stats);
+        }
+        heatmap.live_queues = Some(stats);
+    }
+}
+
+impl Heatmap {
+    /// The queues of the last [`LiveInput`] painted from, if any.
+    pub fn live_queues(&self) -> Option<&LiveStats> {
+        self.live_queues.as_ref()
+    }
+
+    fn paint_batch(&mut self, batch: Batch) -> Result<()> {
+        // The timer is moved out so the lines can be painted with the heatmap borrowed
+        let timer = std::mem::take(&mut self.timer);
+        let factor = self.value_factor();
+        let (lines_before, rejected_before) = (self.lines_processed, self.rejects.total());
+        let Batch { text, lines } = batch;
+        let mut start = 0;
+        let painted = lines.into_iter().try_for_each(|(line_number, end, parsed)| {
+            let line = &text[start..end];
+            start = end;
+            let processed = self.process_parsed(&timer, factor, true, line_number, line, parsed);
+            self.tick_preview_at(line_number);
+            processed
+        });
+        self.timer = timer;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_lines(self.lines_processed - lines_before, self.rejects.total() - rejected_before);
+        }
+        painted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+    use std::sync::atomic::AtomicBool;
+
+    const LINE: &[u8] = b"10.0.0.1 1\n";
+
+    /// A producer as fast as memory: `lines` copies of [`LINE`], or without end, in
+    /// reads of any size. Counts the bytes taken and flags when it is dropped.
+    struct Producer {
+        lines: Option<u64>,
+        served: Arc<AtomicU64>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Read for Producer {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            let served = self.served.load(Ordering::Relaxed);
+            let total = self.lines.map_or(u64::MAX, |lines| lines * LINE.len() as u64);
+            let length = (buffer.len() as u64).min(total - served) as usize;
+            for (index, byte) in buffer[..length].iter_mut().enumerate() {
+                *byte = LINE[(served as usize + index) % LINE.len()];
+            }
+            self.served.fetch_add(length as u64, Ordering::Relaxed);
+            Ok(length)
+        }
+    }
+
+    impl Drop for Producer {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn producer(lines: Option<u64>) -> (Producer, Arc<AtomicU64>, Arc<AtomicBool>) {
+        let (served, dropped) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicBool::new(false)));
+        (Producer { lines, served: Arc::clone(&served), dropped: Arc::clone(&dropped) }, served, dropped)
+    }
+
+    fn heatmap() -> Heatmap {
+        Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None)
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    fn paint_to_end(input: &mut LiveInput, heatmap: &mut Heatmap) {
+        while input.paint(heatmap, Duration::from_secs(10)).unwrap() {}
+    }
+
+    #[test]
+    fn test_stalled_painter_holds_back_a_fast_producer() {
+        let total = 200_000;
+        let (producer, served, _) = producer(Some(total));
+        let mut heatmap = heatmap();
+        let mut input = LiveInput::spawn(&heatmap, producer, LiveOptions { queue_size: 2, when_full: QueueFull::Block });
+        // Nothing is painted until both queues are full and the producer is held back
+        assert!(wait_for(|| input.stats().read.full > 0 && input.stats().parsed.high_water == 2));
+        std::thread::sleep(Duration::from_millis(100));
+        // A full queue of chunks, one being parsed and one being read, and a full queue
+        // of batches with one being filled
+        let in_flight = served.load(Ordering::Relaxed) / LINE.len() as u64;
+        let bound = 4 * CHUNK_BYTES as u64 / LINE.len() as u64 + 3 * BATCH_LINES as u64;
+        assert!(in_flight <= bound, "{} lines read ahead of painting, at most {} expected", in_flight, bound);
+        assert!(in_flight < total);
+
+        paint_to_end(&mut input, &mut heatmap);
+        input.finish(&mut heatmap);
+        assert_eq!(heatmap.lines_processed(), total);
+        let stats = heatmap.live_queues().copied().unwrap();
+        assert_eq!((stats.parsed.capacity, stats.parsed.high_water, stats.dropped_lines), (2, 2, 0));
+        assert!(stats.read.full > 0 && stats.read.blocked > Duration::ZERO, "{:?}", stats);
+        assert_eq!(stats.read.sent, (total * LINE.len() as u64).div_ceil(CHUNK_BYTES as u64));
+    }
+
+    #[test]
+    fn test_dropped_batches_are_counted() {
+        let total = 200_000;
+        let (producer, _, _) = producer(Some(total));
+        let mut heatmap = heatmap();
+        let mut input = LiveInput::spawn(&heatmap, producer, LiveOptions { queue_size: 1, when_full: QueueFull::Drop });
+        // The producer is never held back, so it ends while the painter is stalled
+        assert!(wait_for(|| input.stats().read.sent * CHUNK_BYTES as u64 >= total * LINE.len() as u64));
+        std::thread::sleep(Duration::from_millis(100));
+        paint_to_end(&mut input, &mut heatmap);
+        let stats = input.stats();
+        input.finish(&mut heatmap);
+        assert!(stats.dropped_lines > 0, "{:?}", stats);
+        assert!(stats.parsed.full > 0 && stats.parsed.blocked == Duration::ZERO, "{:?}", stats);
+        assert_eq!(heatmap.lines_processed() + stats.dropped_lines, total);
+    }
+
+    #[test]
+    fn test_dropping_the_input_stops_an_endless_producer() {
+        for when_full in [QueueFull::Block, QueueFull::Drop] {
+            let (producer, served, stopped) = producer(None);
+            let mut heatmap = heatmap();
+            let mut input = LiveInput::spawn(&heatmap, producer, LiveOptions { queue_size: 4, when_full });
+            for _ in 0..3 {
+                assert!(input.paint(&mut heatmap, Duration::from_secs(10)).unwrap());
+            }
+            assert!(served.load(Ordering::Relaxed) > 0);
+            drop(input);
+            // Both stages see the painter gone and end, dropping the reader
+            assert!(wait_for(|| stopped.load(Ordering::Relaxed)), "{} did not shut down", when_full);
+        }
+    }
+
+    #[test]
+    fn test_quiet_input_is_painted_as_it_arrives() {
+        let (sender, receiver) = std::sync::mpsc::channel::<Vec<u8>>();
+        struct Trickle(std::sync::mpsc::Receiver<Vec<u8>>);
+        impl Read for Trickle {
+            fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+                let Ok(bytes) = self.0.recv() else { return Ok(0) };
+                buffer[..bytes.len()].copy_from_slice(&bytes);
+                Ok(bytes.len())
+            }
+        }
+        let mut heatmap = heatmap();
+        let mut input = LiveInput::spawn(&heatmap, Trickle(receiver), LiveOptions::default());
+        sender.send(b"10.0.0.1 1\n10.0.0.2 2\n10.0.".to_vec()).unwrap();
+        // The complete lines are painted without waiting for a full batch
+        while heatmap.lines_processed() < 2 {
+            assert!(input.paint(&mut heatmap, Duration::from_secs(10)).unwrap());
+        }
+        sender.send(b"0.3 3\nnot an address\n".to_vec()).unwrap();
+        drop(sender);
+        paint_to_end(&mut input, &mut heatmap);
+        input.finish(&mut heatmap);
+        assert_eq!((heatmap.lines_processed(), heatmap.rejects().total()), (4, 1));
+        assert_eq!((heatmap.rejects().samples()[0].line_number, heatmap.rejects().samples()[0].content.as_str()), (4, "not an address"));
+    }
+
+    #[test]
+    fn test_read_errors_end_the_input() {
+        struct Failing(bool);
+        impl Read for Failing {
+            fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+                if std::mem::replace(&mut self.0, true) {
+                    return Err(std::io::Error::other("device gone"));
+                }
+                buffer[..LINE.len()].copy_from_slice(LINE);
+                Ok(LINE.len())
+            }
+        }
+        let mut heatmap = heatmap();
+        let mut input = LiveInput::spawn(&heatmap, Failing(false), LiveOptions::default());
+        let err = loop {
+            match input.paint(&mut heatmap, Duration::from_secs(10)) {
+                Ok(more) => assert!(more, "the error was lost"),
+                Err(err) => break err,
+            }
+        };
+        assert!(format!("{:#}", err).contains("device gone"), "{:#}", err);
+        assert_eq!(heatmap.lines_processed(), 1);
+    }
+}
@@ -1,6 +1,16 @@
-use anyhow::Result;
-use clap::{Parser, ValueEnum};
-use ip_heatmap::{Heatmap, DomainType, ValueMode};
+use anyhow::{Context, Result};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use ip_heatmap::{Aggregation, Bands, BoundsError, CategoryColours, CidrHostBits, ConflictPolicy, ErrorPolicy, Expectation, ExpectationStatus, Frame, Geometry, OutputTemplate, Heatmap, Strings, DomainType, InputFormat, LiveOptions, MapV6, MemoryPlan, ParseOptions, OutputFormat, PngCompression, PngFilter, Outline, Palette, Preview, PreviewSpec, QueueFull, Shade, RejectLog, RenderSpec, Theme, ThumbnailSpec, ValueMode, ValueSource, WeightedInput};
+use ipnet::Ipv4Net;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+mod exec;
+mod flag_conflicts;
+#[cfg(unix)]
+mod signals;
+mod stdout;
 
 #[derive(Clone, Debug, ValueEnum)]
 pub enum ColourScale {
@@ -9,78 +19,1680 @@ pub enum ColourScale {
     Magma,
 }
 
-#[derive(Parser)]
-#[command(name = "ip-heatmap")]
-#[command(about = "Generate Hilbert curve heatmaps of the IPv4 address space")]
-#[command(version = "0.1.0")]
-pub struct Args {
+/// How diagnostics are written to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines, configurable with RUST_LOG
+    #[default]
+    Text,
+    /// One JSON object per event, with the event's fields as members
+    Json,
+}
+
+/// Optional members of an `--out-dir` bundle.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum BundleMember {
+    /// cells.csv, as written by --export-cells
+    Cells,
+}
+
+#[derive(Parser)]
+#[command(name = "ip-heatmap")]
+#[command(about = "Generate Hilbert curve heatmaps of the IPv4 address space")]
+#[command(version = "0.1.0")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand the render flags apply, as they did before subcommands
+    #[command(flatten)]
+    render: RenderArgs,
+
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        short_alias = 'd',
+        global = true,
+        help = "Verbose output (-v for debug, -vv for trace)",
+        action = clap::ArgAction::Count
+    )]
+    verbose: u8,
+
+    #[arg(
+        long = "log-format",
+        value_enum,
+        default_value_t = LogFormat::Text,
+        global = true,
+        help = "Format of warnings and other diagnostics on stderr; json writes one object per event with ts, level, msg and fields such as line_number, reason and file"
+    )]
+    log_format: LogFormat,
+
+    #[arg(long, global = true, help = "Draw --stats, --coverage-report, --sanity-check and compare tables in ASCII only; the default on a stream that is not a terminal or in a locale that is not UTF-8")]
+    ascii: bool,
+
+    #[arg(long, global = true, help = "Leave out the ANSI styling of the report tables, as NO_COLOR does; the default on a stream that is not a terminal")]
+    no_color: bool,
+}
+
+/// `--ascii` and `--no-color`, which apply to every textual report.
+#[derive(Clone, Copy, Debug)]
+struct ReportText {
+    ascii: bool,
+    no_colour: bool,
+}
+
+impl ReportText {
+    /// The style of reports written to a stream that is a `terminal` or not.
+    fn style(self, terminal: bool) -> ip_heatmap::TextStyle {
+        let no_colour = self.no_colour || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        let utf8 = ip_heatmap::is_utf8_locale(|name| std::env::var(name).ok());
+        ip_heatmap::TextStyle::detect(terminal, utf8, self.ascii, no_colour)
+    }
+
+    fn stderr(self) -> ip_heatmap::TextStyle {
+        self.style(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+    }
+}
+
+/// Options of `render`, the default subcommand.
+#[derive(clap::Args)]
+pub struct RenderArgs {
+    #[arg(
+        long,
+        help = "Colour curve type: linear, log or symlog[:threshold]",
+        default_value = "linear"
+    )]
+    curve: DomainType,
+
+    #[arg(long, help = "Minimum value for colour scaling (defaults to 0)")]
+    min_value: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Maximum value for colour scaling (defaults to dataset maximum)"
+    )]
+    max_value: Option<f64>,
+
+    #[arg(long, help = "Swap --min-value and --max-value, with a warning, when the minimum is the larger")]
+    swap_min_max: bool,
+
+    #[arg(
+        long,
+        help = "Take the colour scale maximum from this percentile (0-100) of the non-zero cells",
+        conflicts_with = "winsorize"
+    )]
+    max_percentile: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "LOW,HIGH",
+        value_parser = parse_winsorize,
+        help = "Clamp the colour scale to these percentiles of the non-zero cells, e.g. 1,99"
+    )]
+    winsorize: Option<(f64, f64)>,
+
+    #[arg(long, help = "Base of the logarithmic curve, e.g. 10 (defaults to e)")]
+    log_base: Option<f64>,
+
+    #[arg(long, help = "Offset added to values before taking the logarithm, e.g. 1.0")]
+    log_offset: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Gamma applied to scaled values before colouring; below 1 brightens",
+        default_value = "1.0",
+        value_parser = ip_heatmap::parse_gamma
+    )]
+    gamma: f64,
+
+    #[arg(
+        long,
+        value_name = "BITS",
+        help = "Colour cells from the palette sampled at 2^BITS points (12 to 16), or off to evaluate it per cell",
+        default_value = "12",
+        value_parser = ip_heatmap::parse_colour_lut
+    )]
+    // Spelled out so clap parses `off` to None rather than treating the flag as optional
+    colour_lut: std::option::Option<u8>,
+
+    #[arg(
+        long,
+        help = "How scaled values pick a colour LUT entry: nearest, or floor for equal shares of the scale",
+        default_value = "nearest"
+    )]
+    lut_rounding: ip_heatmap::LutRounding,
+
+    #[arg(long, value_name = "PATTERN", help = "Dither scaled values to break up banding in smooth areas: bayer or blue-noise")]
+    dither: Option<ip_heatmap::DitherPattern>,
+
+    #[arg(
+        long,
+        requires = "dither",
+        value_name = "LEVELS",
+        help = "Colour levels (each 1/256 of the scale) --dither spreads values over, 0 to 64",
+        default_value_t = ip_heatmap::DEFAULT_DITHER_STRENGTH,
+        value_parser = ip_heatmap::parse_dither_strength
+    )]
+    dither_strength: f64,
+
+    #[arg(
+        short = 'A',
+        help = "Logarithmic scaling, min value (deprecated: use --min-value)"
+    )]
+    log_min: Option<f64>,
+
+    #[arg(
+        short = 'B',
+        help = "Logarithmic scaling, max value (deprecated: use --max-value)"
+    )]
+    log_max: Option<f64>,
+
+    #[arg(long, short = 'C', help = "Values accumulate in exact input mode")]
+    accumulate: bool,
+
+    #[arg(help = "Output filename", required_unless_present_any = ["render", "validate", "output_flag", "out_dir"])]
+    output: Option<String>,
+
+    #[arg(short = 'o', id = "output_flag", value_name = "OUTPUT", conflicts_with = "output", help = "Output filename, as an option")]
+    output_flag: Option<String>,
+
+    #[arg(long, help = "Take output names literally instead of expanding {date}, {bpp} and the other variables")]
+    no_template: bool,
+
+    #[arg(
+        long,
+        value_name = "SPEC",
+        help = "Label the legend with named bands, e.g. quiet:10,elevated:1000,hot: (thresholds ascend, the last band is open-ended)"
+    )]
+    legend_bands: Option<Bands>,
+
+    #[arg(long, requires = "legend_bands", help = "Colour each cell with the single colour of its --legend-bands band")]
+    snap_bands: bool,
+
+    #[arg(
+        long,
+        help = "PNG compression: fast, default or best; on a -z 8 map of 300k scanned addresses, default and best encode about 3x slower than fast for files a quarter the size",
+        default_value = "fast"
+    )]
+    png_compression: PngCompression,
+
+    #[arg(
+        long,
+        help = "PNG row filter: none, sub, up, average, paeth or adaptive (best per row); on sparse maps none is often smaller and faster",
+        default_value = "adaptive"
+    )]
+    png_filter: PngFilter,
+
+    #[arg(short = 't', long, help = "Title drawn above the map")]
+    title: Option<String>,
+
+    #[arg(long, help = "State below the map what one pixel stands for (the default with a legend)")]
+    pixel_caption: bool,
+
+    #[arg(long, conflicts_with = "pixel_caption", help = "Leave out the pixel caption drawn with a legend")]
+    no_pixel_caption: bool,
+
+    #[arg(
+        long,
+        value_name = "PREFIX:RRGGBB[:STYLE[:SPACING]]",
+        help = "Shade PREFIX in a translucent colour, or with a pattern of lines or dots SPACING pixels apart (repeatable)",
+        long_help = "Shade PREFIX in a colour (repeatable). STYLE is solid (a translucent wash, the default), hatch45, hatch-cross or dots; patterns leave the pixels between their lines or dots untouched, with SPACING pixels (default 6) from one to the next"
+    )]
+    shade: Vec<Shade>,
+
+    #[arg(
+        long,
+        value_name = "PREFIX[:RRGGBB[:WIDTH]]",
+        help = "Draw a border WIDTH pixels wide (default 1) inside the pixels of PREFIX, in the foreground colour unless given (repeatable)"
+    )]
+    outline: Vec<Outline>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "multiples",
+        help = "Write the outlines, labels, title, legend and caption alone on a transparent canvas to PATH, and the main output without them"
+    )]
+    annotation_layer: Option<String>,
+
+    #[arg(long, requires = "annotation_layer", help = "Write only the --annotation-layer, not the main output")]
+    no_data_layer: bool,
+
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Height of title and legend text, rounded to a multiple of 8 (defaults to the map size / 32)"
+    )]
+    font_size: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Height of the title, overriding --font-size; long titles are not shrunk"
+    )]
+    title_size: Option<u32>,
+
+    #[arg(short = 'u', long, help = "Draw a legend below the map with this label, e.g. the unit of the values")]
+    legend_label: Option<String>,
+
+    #[arg(
+        long = "text",
+        value_name = "KEY=TEXT",
+        help = "Replace a fixed text of legends, captions and charts, e.g. curve.linear=linear (repeatable)"
+    )]
+    texts: Vec<String>,
+
+    #[arg(short = 'y', long, help = "Only draw the pixels covering this prefix, e.g. 10.0.0.0/8")]
+    crop: Option<Ipv4Net>,
+
+    #[arg(
+        long,
+        value_name = "PADDING",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "0",
+        conflicts_with = "crop",
+        help = "Only draw the bounding box of the painted pixels, grown by PADDING pixels (--crop-to-data=8)"
+    )]
+    crop_to_data: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = ip_heatmap::DEFAULT_CLIP_WARNING_PERCENT,
+        help = "Warn, and note it in the title, when more than PERCENT of the records or value fall outside the map or --crop"
+    )]
+    clip_warning: f64,
+
+    #[arg(short = 'r', long, help = "Draw black on white instead of white on black, including unpainted pixels")]
+    reverse: bool,
+
+    #[arg(
+        long,
+        value_name = "RRGGBB",
+        value_parser = ip_heatmap::parse_hex_colour,
+        help = "Colour of the canvas and unpainted pixels; text turns black or white to contrast unless --foreground is given"
+    )]
+    background: Option<[u8; 3]>,
+
+    #[arg(
+        long,
+        value_name = "RRGGBB",
+        value_parser = ip_heatmap::parse_hex_colour,
+        help = "Colour of text and default outlines"
+    )]
+    foreground: Option<[u8; 3]>,
+
+    #[arg(long, help = "Fade the low end of the palette toward the background colour")]
+    fade_low: bool,
+
+    // Flags of the original ipv4-heatmap that have no equivalent, see reject_legacy_flags
+    #[arg(short = 'a', hide = true)]
+    legacy_annotations: Option<String>,
+
+    #[arg(short = 's', hide = true)]
+    legacy_shading: Option<String>,
+
+    #[arg(short = 'f', hide = true)]
+    legacy_font: Option<String>,
+
+    #[arg(short = 'p', hide = true)]
+    legacy_prefixes: Option<String>,
+
+    #[arg(short = 'm', hide = true)]
+    legacy_morton: bool,
+
+    #[arg(
+        long,
+        help = "Additional output as path[:key=value,...] with keys curve, palette, min, max (repeatable)"
+    )]
+    render: Vec<String>,
+
+    #[arg(long, help = "Downsample the outputs to this side length in pixels (a power of two)")]
+    output_size: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "PATH:SIZE",
+        help = "Also write a downsampled copy with the main output's colour scale, e.g. thumb.png:256 (repeatable)"
+    )]
+    thumbnail: Vec<ThumbnailSpec>,
+
+    #[arg(long, help = "How --output-size, --thumbnail and --preview combine cells: max, sum, or mean", default_value = "max")]
+    downsample: Aggregation,
+
+    #[arg(
+        long,
+        value_name = "PATH:SIZE:SECONDS",
+        help = "While input is read, write a downsampled preview of the map so far every SECONDS, e.g. preview.png:512:60"
+    )]
+    preview: Option<PreviewSpec>,
+
+    #[arg(long, help = "Refuse to overwrite existing output files", conflicts_with = "backup")]
+    no_clobber: bool,
+
+    #[arg(long, help = "Rename existing output files to <name>.bak before replacing them")]
+    backup: bool,
+
+    #[arg(
+        short = 'z',
+        help = "Address space bits per pixel; repeat to render one output per resolution from a single pass",
+        default_value = "8"
+    )]
+    bits_per_pixel: Vec<u8>,
+
+    #[arg(long, help = "Colour scale to use", default_value = "magma")]
+    colour_scale: ColourScale,
+
+    #[arg(
+        long,
+        help = "How values are painted: scaled (divided over the pixel's addresses), raw (whole) or categorical (labels)",
+        default_value = "scaled"
+    )]
+    value_mode: ValueMode,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Keep categorical colours stable across runs in this JSON mapping, read if present and updated with new categories"
+    )]
+    category_colours: Option<String>,
+
+    #[arg(
+        long,
+        help = "Render a grid of zoomed panels for the hottest prefixes of this length instead of the full map",
+        conflicts_with_all = ["title", "legend_label", "crop", "crop_to_data"]
+    )]
+    multiples: Option<u8>,
+
+    #[arg(long, help = "Number of panels for --multiples", default_value = "16")]
+    multiples_top: usize,
+
+    #[arg(long, help = "Panel side length in pixels for --multiples", default_value = "256")]
+    multiples_size: u32,
+
+    #[arg(long, help = "Write rejected input lines to this file")]
+    rejects: Option<String>,
+
+    #[arg(long, value_name = "FILE", help = "Write the smallest list of prefixes covering the painted pixels")]
+    export_prefixes: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "VALUE",
+        allow_negative_numbers = true,
+        requires = "export_prefixes",
+        help = "Only export pixels with at least this value"
+    )]
+    export_prefixes_threshold: Option<i32>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the cells in Hilbert curve (address) order as CSV, or as NumPy for a .npy file"
+    )]
+    export_profile: Option<String>,
+
+    #[arg(long, value_name = "FILE", help = "Render the Hilbert-order profile as a strip chart PNG")]
+    export_profile_strip: Option<String>,
+
+    #[arg(long, value_name = "FILE", help = "Write the painted cells as CSV: the prefix each covers, its value and x,y")]
+    export_cells: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with = "validate",
+        help = "Write map.png, stats.json, params.toml and rejects.txt (if any) into this new directory; single-file flags override members"
+    )]
+    out_dir: Option<String>,
+
+    #[arg(long, value_enum, value_delimiter = ',', requires = "out_dir", help = "Optional members of the --out-dir bundle")]
+    bundle: Vec<BundleMember>,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "validate",
+        help = "Prefixes that should show traffic, one per line with an optional threshold, checked after painting"
+    )]
+    expect: Option<String>,
+
+    #[arg(long, value_name = "FILE", requires = "expect", help = "Write the --expect prefixes below their threshold to this file")]
+    expect_report: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "expect",
+        default_value = "1",
+        help = "Least total value of an --expect prefix without a threshold of its own"
+    )]
+    expect_threshold: i64,
+
+    #[arg(long, requires = "expect", help = "Exit with 4 when an --expect prefix is below its threshold")]
+    expect_strict: bool,
+
+    #[arg(
+        long,
+        help = "Unparsable lines: skip (silently), count (with a warning) or fail",
+        default_value = "count"
+    )]
+    on_error: ErrorPolicy,
+
+    #[arg(
+        long,
+        help = "Pixels painted over a different value without -C: warn (keep the later value), error, accumulate or keep-max",
+        default_value = "warn"
+    )]
+    on_conflict: ConflictPolicy,
+
+    #[command(flatten)]
+    parse: ParseArgs,
+
+    #[arg(
+        long,
+        help = "Multiply every value by this after parsing, e.g. 1000 for a 0.1% sample",
+        default_value = "1",
+        value_parser = ip_heatmap::parse_weight
+    )]
+    weight: f64,
+
+    #[arg(
+        long = "input",
+        value_name = "FILE[:WEIGHT]",
+        conflicts_with = "validate",
+        help = "Read this file or http:// URL instead of stdin, its values multiplied by WEIGHT (repeatable)"
+    )]
+    inputs: Vec<WeightedInput>,
+
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with_all = ["validate", "inputs"],
+        help = "Run COMMAND with sh -c and read its stdout instead of stdin; a failing COMMAND fails the run with its exit status"
+    )]
+    exec: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "ITEMS",
+        default_value_t = ip_heatmap::DEFAULT_LIVE_QUEUE_SIZE as u16,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Chunks read and batches parsed that stdin or --exec may run ahead of painting, per queue"
+    )]
+    live_queue_size: u16,
+
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "block",
+        help = "When painting falls behind stdin or --exec: block (hold back the input) or drop (drop parsed lines, counted in --stats)"
+    )]
+    live_queue_full: QueueFull,
+
+    #[arg(
+        long,
+        help = "How input is encoded: text (address and value lines, or CSV), raw-u32v (see convert) or cells (--export-cells rows, repainted as their totals)",
+        default_value = "text"
+    )]
+    format: InputFormat,
+
+    #[arg(
+        long,
+        value_name = "RATE",
+        help = "Only process about this fraction of the lines (e.g. 0.01), scaling accumulated values by 1/RATE"
+    )]
+    sample: Option<f64>,
+
+    #[arg(long, help = "Seed choosing the lines for --sample", default_value = "0", requires = "sample")]
+    sample_seed: u64,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Drop a record when the same address and value is among the N records before it, e.g. retransmitted log lines"
+    )]
+    dedup_window: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "total|pixels",
+        help = "Estimate distinct addresses in bounded memory: in total, reported in --stats, or also per pixel, painted as the cell values"
+    )]
+    distinct_approx: Option<ip_heatmap::DistinctApprox>,
+
+    #[arg(
+        long,
+        value_name = "P",
+        default_value_t = ip_heatmap::DEFAULT_HLL_PRECISION,
+        requires = "distinct_approx",
+        help = "Distinct estimates use 2^P bytes per sketch (4 to 16), with a standard error of 104/sqrt(2^P) percent"
+    )]
+    distinct_precision: u8,
+
+    #[arg(
+        long,
+        conflicts_with = "distinct_approx",
+        help = "Sum the values of each address or prefix before painting it once, when input ends; keys beyond --preaggregate-memory are sorted to disk"
+    )]
+    preaggregate: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["distinct_approx", "preaggregate"],
+        help = "Paint each pixel with the exact number of distinct addresses and prefixes read in it, counted like --preaggregate"
+    )]
+    distinct: bool,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value = "256M",
+        value_parser = ip_heatmap::parse_memory_size,
+        help = "Memory for keys held by --preaggregate and --distinct before they are spilled to sorted runs in --tmpdir"
+    )]
+    preaggregate_memory: u64,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Directory for the runs spilled by --preaggregate and --distinct [default: the system's temporary directory]"
+    )]
+    tmpdir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with = "state_mmap",
+        help = "Render the unpainted space: every cell becomes the largest value minus its own"
+    )]
+    invert: bool,
+
+    #[arg(
+        long,
+        allow_negative_numbers = true,
+        conflicts_with = "state_mmap",
+        help = "Clear cells below this value after processing, as if nothing was painted there"
+    )]
+    floor: Option<i32>,
+
+    #[arg(
+        long,
+        allow_negative_numbers = true,
+        conflicts_with = "state_mmap",
+        help = "Cap cells above this value after processing; unlike --max-value this also changes state files and stats"
+    )]
+    ceiling: Option<i32>,
+
+    #[arg(long, help = "Print line, reject and pixel counts to stderr")]
+    stats: bool,
+
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        help = "Print the total value of PREFIX with its rank and percentile among all prefixes of its length (repeatable)"
+    )]
+    rank: Vec<Ipv4Net>,
+
+    #[arg(long, requires = "rank", help = "Outline each --rank prefix on the map and label it with its rank and percentile")]
+    rank_mark: bool,
+
+    #[arg(long, help = "Print the painted pixels and total value of each /4, warning about blocks far off the rest")]
+    sanity_check: bool,
+
+    #[arg(
+        long,
+        requires = "sanity_check",
+        help = "How many times above or below the overall figures a --sanity-check block may be",
+        default_value_t = ip_heatmap::DEFAULT_SANITY_FACTOR,
+        value_parser = ip_heatmap::parse_sanity_factor
+    )]
+    sanity_factor: f64,
+
+    #[arg(long, help = "Print how many prefixes of each --coverage-prefixes length contain painted addresses")]
+    coverage_report: bool,
+
+    #[arg(
+        long,
+        help = "Prefix lengths for --coverage-report",
+        value_parser = clap::value_parser!(u8).range(0..=32),
+        value_delimiter = ',',
+        default_values_t = ip_heatmap::DEFAULT_COVERAGE_PREFIXES
+    )]
+    coverage_prefixes: Vec<u8>,
+
+    #[arg(long, help = "Write run statistics as JSON to this file")]
+    stats_json: Option<String>,
+
+    #[arg(long, help = "Save the processed buffer to this state file")]
+    save_state: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Keep the buffer in this memory-mapped state file, resuming it if it exists"
+    )]
+    state_mmap: Option<String>,
+
+    #[arg(
+        long,
+        help = "Encode undecorated renders row by row at any size (always done from 8192x8192 up)"
+    )]
+    low_memory: bool,
+
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Parse and paint text input on this many threads (stdin is then read without SIGHUP snapshots)"
+    )]
+    threads: u16,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = ip_heatmap::parse_memory_size,
+        help = "Refuse to start when the buffers are projected to need more than this, e.g. 2G"
+    )]
+    max_memory: Option<u64>,
+
+    #[cfg(feature = "serve")]
+    #[arg(
+        long,
+        value_name = "ADDR",
+        help = "Serve Prometheus metrics (lines, rejects, renders, render time, buffer size) on http://ADDR/metrics while reading"
+    )]
+    metrics_listen: Option<String>,
+
+    #[arg(long, help = "Write a bar chart of the non-zero cell values to this PNG")]
+    histogram: Option<String>,
+
+    #[arg(long, help = "Print a text histogram and percentiles of the cell values to stderr")]
+    histogram_text: bool,
+
+    #[arg(long, help = "Number of histogram bins", default_value = "40")]
+    histogram_bins: usize,
+
+    #[arg(long, help = "Use linear instead of log-spaced histogram bins")]
+    histogram_linear: bool,
+
+    #[arg(long, value_name = "PNG", help = "Write a bar chart of the total value of each /8 to this PNG")]
+    slash8_chart: Option<String>,
+
+    #[arg(long, requires = "slash8_chart", help = "Scale the bars of --slash8-chart by log(1 + total)")]
+    slash8_chart_log: bool,
+
+    #[arg(long, help = "Only parse the input and report what a render would see, writing no output")]
+    validate: bool,
+
+    #[arg(
+        long,
+        help = "With --validate, fail if more than this percentage of lines is rejected",
+        default_value = "100"
+    )]
+    validate_max_reject_pct: f64,
+
+    #[arg(long, help = "Print the time spent in each processing phase to stderr")]
+    timing: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Render input into a heatmap (the default when no subcommand is given)
+    Render(Box<RenderArgs>),
+    /// Render a labelled preview strip for every available palette
+    Palettes(PalettesArgs),
+    /// Render inputs side by side with a shared colour domain
+    Montage(MontageArgs),
+    /// Print similarity metrics between two state files or inputs
+    Compare(CompareArgs),
+    /// Compare two PNG images pixel by pixel
+    Imgdiff(ImgdiffArgs),
+    /// Convert input to another format, e.g. binary records that render without parsing
+    Convert(ConvertArgs),
+    /// Print per-/8 totals and reject counts of input without rendering a map
+    Summarize(SummarizeArgs),
+    /// Describe a PNG, state file or raw-u32v file: its parameters or header
+    Inspect(InspectArgs),
+    /// Write synthetic input with hotspots, for demos, benchmarks and bug reports
+    Generate(GenerateArgs),
+    /// Render maps over HTTP: POST input to /render for a PNG
+    #[cfg(feature = "serve")]
+    Serve(ServeArgs),
+}
+
+#[cfg(feature = "serve")]
+#[derive(clap::Args)]
+struct ServeArgs {
+    #[arg(long, help = "Address to listen on; port 0 picks a free port", default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    #[arg(long, help = "Largest request body in bytes, after gzip decoding", default_value = "67108864")]
+    max_body_bytes: u64,
+
+    #[arg(long, help = "Requests rendered at once; more are answered 503", default_value = "4")]
+    max_concurrent: usize,
+
+    #[arg(long, help = "Seconds a request may take to send its input", default_value = "30")]
+    timeout: u64,
+
+    #[arg(
+        long,
+        help = "Finest -z a request may ask for",
+        value_parser = clap::value_parser!(u8).range(0..=32),
+        default_value = "8"
+    )]
+    min_bits_per_pixel: u8,
+
+    #[arg(long, value_name = "ADDR", help = "Serve Prometheus metrics of all requests on http://ADDR/metrics")]
+    metrics_listen: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct InspectArgs {
+    file: String,
+
+    #[arg(long, help = "Print the description as JSON")]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct GenerateArgs {
+    #[arg(help = "Output file, or - for stdout", default_value = "-")]
+    output: String,
+
+    #[arg(
+        long,
+        help = "Number of lines, optionally with a K, M or G suffix",
+        default_value = "1M",
+        value_parser = ip_heatmap::testdata::parse_line_count
+    )]
+    lines: u64,
+
+    #[arg(long, help = "Number of hotspot prefixes, given Zipf-distributed shares of the lines", default_value_t = 20)]
+    hotspots: usize,
+
+    #[arg(
+        long,
+        help = "Share of lines spread uniformly over the address space as background noise",
+        default_value = "0.2",
+        value_parser = ip_heatmap::testdata::parse_spread
+    )]
+    spread: f64,
+
+    #[arg(long, help = "Seed; the same seed and options always give the same lines", default_value_t = 0)]
+    seed: u64,
+}
+
+#[derive(clap::Args)]
+struct ConvertArgs {
+    #[arg(help = "Input file, or - for stdin")]
+    input: String,
+
+    #[arg(help = "Output file, or - for stdout")]
+    output: String,
+
+    #[arg(long, help = "Input format: text, raw-u32v or cells", default_value = "text")]
+    from: InputFormat,
+
+    #[arg(long, help = "Output format: text, csv, raw-u32v or aggregated (sorted text with repeats summed)")]
+    to: OutputFormat,
+
+    #[arg(
+        long,
+        short = 'z',
+        help = "With --to aggregated, also merge equal sibling prefixes that cover whole pixels at this many bits per pixel"
+    )]
+    bits_per_pixel: Option<u8>,
+
+    #[command(flatten)]
+    parse: ParseArgs,
+
+    #[arg(long, help = "Write rejected lines with their reason to this file")]
+    rejects: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct SummarizeArgs {
+    #[arg(help = "Input file, or - for stdin", default_value = "-")]
+    input: String,
+
+    #[arg(long, help = "Input format: text, raw-u32v or cells", default_value = "text")]
+    format: InputFormat,
+
+    #[command(flatten)]
+    parse: ParseArgs,
+
+    #[arg(
+        long,
+        help = "Multiply every value by this after parsing, e.g. 1000 for a 0.1% sample",
+        default_value = "1",
+        value_parser = ip_heatmap::parse_weight
+    )]
+    weight: f64,
+
+    #[arg(long, help = "Print the totals as JSON")]
+    json: bool,
+
+    #[arg(long, help = "Write rejected lines with their reason to this file")]
+    rejects: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct ImgdiffArgs {
+    a: String,
+
+    b: String,
+
+    #[arg(long, help = "Largest per-channel difference that still counts as equal", default_value = "0")]
+    tolerance: u8,
+
+    #[arg(long, help = "Print the report as JSON")]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct CompareArgs {
+    #[arg(help = "State file or input file")]
+    a: String,
+
+    #[arg(help = "State file or input file")]
+    b: String,
+
+    #[arg(long, help = "Print the metrics, or the changes, as JSON")]
+    json: bool,
+
+    #[arg(long, help = "List the prefixes whose value changed from A to B instead of the metrics")]
+    changes: bool,
+
+    #[arg(
+        long,
+        requires = "changes",
+        value_name = "VALUE",
+        help = "Only list pixels whose value changed by more than this",
+        default_value = "0"
+    )]
+    changes_threshold: i64,
+
+    #[arg(short = 'o', long, requires = "changes", help = "Write the changes to this file instead of stdout")]
+    output: Option<String>,
+
+    #[command(flatten)]
+    input: InputArgs,
+}
+
+/// How input lines are parsed and which records are kept, shared by `render`, `convert`
+/// and `summarize`.
+#[derive(clap::Args)]
+struct ParseArgs {
+    #[arg(
+        long,
+        help = "Maximum number of rejected lines to keep for reporting",
+        default_value_t = ip_heatmap::DEFAULT_MAX_REJECT_SAMPLES
+    )]
+    max_rejects: usize,
+
+    #[arg(
+        long,
+        help = "IPv6 addresses: off (reject all) or mapped (plot ::ffff:a.b.c.d as IPv4)",
+        default_value = "off"
+    )]
+    map_v6: MapV6,
+
+    #[arg(long, help = "Only accept addresses as four decimal octets without leading zeros")]
+    strict_ip: bool,
+
+    #[arg(
+        long,
+        help = "Expand one brace group in the address, e.g. 10.20.{0-255}.0/24 or 192.0.2.{1,5,9}, into a record per item"
+    )]
+    expand_braces: bool,
+
     #[arg(
         long,
-        help = "Colour curve type: linear or logarithmic",
-        default_value = "linear"
+        help = "CIDR prefixes with host bits set: warn (once, with a count), allow, or reject",
+        default_value = "warn"
     )]
-    curve: DomainType,
+    cidr_host_bits: CidrHostBits,
 
-    #[arg(long, help = "Minimum value for colour scaling (defaults to 0)")]
-    min_value: Option<f64>,
+    #[arg(
+        long,
+        help = "Where values come from: column (the value column, or --default-value without one; also value, weight, bytes), prefix-len (the prefix length, /32 for addresses) or count (1 per line)",
+        default_value = "column"
+    )]
+    value_from: ValueSource,
 
     #[arg(
         long,
-        help = "Maximum value for colour scaling (defaults to dataset maximum)"
+        value_name = "N",
+        default_value_t = 1,
+        allow_hyphen_values = true,
+        help = "Value of lines without a value column; value columns that are not integers, or overflow, are rejected"
     )]
-    max_value: Option<f64>,
+    default_value: i32,
 
     #[arg(
-        short = 'A',
-        help = "Logarithmic scaling, min value (deprecated: use --min-value)"
+        long,
+        value_name = "VALUE",
+        allow_hyphen_values = true,
+        help = "Drop lines whose value column is exactly this token, e.g. -1 (repeatable)"
     )]
-    log_min: Option<f64>,
+    ignore_value: Vec<String>,
 
     #[arg(
-        short = 'B',
-        help = "Logarithmic scaling, max value (deprecated: use --max-value)"
+        long,
+        value_name = "TIME",
+        value_parser = ip_heatmap::parse_timestamp,
+        help = "Drop records timestamped before this (epoch seconds or millis, or RFC 3339); the timestamp is the third field"
     )]
-    log_max: Option<f64>,
+    since: Option<i64>,
 
-    #[arg(long, short = 'C', help = "Values accumulate in exact input mode")]
-    accumulate: bool,
+    #[arg(
+        long,
+        value_name = "TIME",
+        value_parser = ip_heatmap::parse_timestamp,
+        help = "Drop records timestamped at or after this (epoch seconds or millis, or RFC 3339)"
+    )]
+    until: Option<i64>,
+}
 
-    #[arg(short = 'v', long = "verbose", help = "Verbose output (-v for debug, -vv for trace)", action = clap::ArgAction::Count)]
-    verbose: u8,
+impl ParseArgs {
+    /// The `--since`/`--until` window, for text input read as `format`.
+    fn time_window(&self, format: InputFormat) -> Result<Option<ip_heatmap::TimeWindow>> {
+        if self.since.is_none() && self.until.is_none() {
+            return Ok(None);
+        }
+        if format != InputFormat::Text {
+            anyhow::bail!("--since and --until need timestamps, which {} input does not have", format);
+        }
+        ip_heatmap::TimeWindow::new(self.since, self.until).map(Some).map_err(|err| anyhow::anyhow!(err))
+    }
 
-    #[arg(help = "Output filename")]
-    output: String,
+    /// The options for reading input in `format`.
+    fn parse_options(&self, format: InputFormat) -> Result<ParseOptions> {
+        Ok(ParseOptions::default()
+            .format(format)
+            .map_v6(self.map_v6)
+            .strict_ip(self.strict_ip)
+            .expand_braces(self.expand_braces)
+            .cidr_host_bits(self.cidr_host_bits)
+            .value_source(self.value_from)
+            .default_value(self.default_value)
+            .ignore_values(self.ignore_value.clone())
+            .time_window(self.time_window(format)?)
+            .max_rejects(self.max_rejects))
+    }
+}
+
+/// How subcommands that read input files, rather than state files, process them.
+#[derive(clap::Args)]
+struct InputArgs {
+    #[arg(long, short = 'C', help = "Values accumulate in input files")]
+    accumulate: bool,
 
     #[arg(
         short = 'z',
-        help = "Address space bits per pixel",
+        help = "Address space bits per pixel for input files",
         default_value = "8"
     )]
     bits_per_pixel: u8,
 
-    #[arg(long, help = "Colour scale to use", default_value = "magma")]
-    colour_scale: ColourScale,
-
-    #[arg(long, help = "Value mode: scaled (default), raw, or categorical", default_value = "scaled")]
+    #[arg(long, help = "Value mode for input files: scaled (default), raw, or categorical", default_value = "scaled")]
     value_mode: ValueMode,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+impl InputArgs {
+    /// Read `path` into a new heatmap coloured with `curve` between `min_value` and `max_value`.
+    fn load(&self, path: &str, curve: DomainType, min_value: Option<f64>, max_value: Option<f64>) -> Result<Heatmap> {
+        let mut heatmap = Heatmap::new(
+            curve,
+            min_value,
+            max_value,
+            self.accumulate,
+            self.bits_per_pixel,
+            &colorous::MAGMA,
+            self.value_mode,
+            None,
+        );
+        heatmap.check_value_modes(None)?;
+        heatmap.process_input_from_reader(open_input(path)?)?;
+        Ok(heatmap)
+    }
+}
+
+#[derive(clap::Args)]
+struct MontageArgs {
+    #[arg(help = "Input files, one panel each", required = true, num_args = 2..)]
+    inputs: Vec<String>,
+
+    #[arg(short = 'o', long, help = "Output filename")]
+    output: String,
+
+    #[arg(long, help = "Panel title (repeatable, defaults to the input filenames)")]
+    title: Vec<String>,
+
+    #[arg(
+        long = "text",
+        value_name = "KEY=TEXT",
+        help = "Replace a fixed text of the legend or difference panel, e.g. montage.difference=Differenz (repeatable)"
+    )]
+    texts: Vec<String>,
+
+    #[arg(long, help = "Add a panel showing the difference between the last and first input")]
+    diff: bool,
+
+    #[arg(
+        long,
+        help = "Colour curve for the --diff panel: symlog[:threshold], linear or log",
+        default_value = "symlog:1"
+    )]
+    diff_curve: DomainType,
+
+    #[arg(
+        long,
+        help = "Colour curve type: linear, log or symlog[:threshold]",
+        default_value = "linear"
+    )]
+    curve: DomainType,
+
+    #[arg(long, help = "Minimum value for colour scaling (defaults to the minimum over all inputs)")]
+    min_value: Option<f64>,
+
+    #[arg(long, help = "Maximum value for colour scaling (defaults to the maximum over all inputs)")]
+    max_value: Option<f64>,
+
+    #[arg(long, help = "Palette name or custom name=#rrggbb,... spec", default_value = "magma")]
+    palette: Palette,
+
+    #[command(flatten)]
+    input: InputArgs,
+}
+
+#[derive(clap::Args)]
+struct PalettesArgs {
+    #[arg(long, short = 'o', help = "Output filename")]
+    out: String,
+
+    #[arg(long, help = "Width of each palette bar in pixels", default_value = "512")]
+    width: u32,
+
+    #[arg(long, help = "Height of each palette bar in pixels", default_value = "24")]
+    bar_height: u32,
+
+    #[arg(
+        long = "palette-custom",
+        help = "Additional palette as name=#rrggbb,#rrggbb,... (repeatable)",
+        value_parser = Palette::parse_custom
+    )]
+    palette_custom: Vec<Palette>,
+
+    #[arg(long, help = "Render thumbnails of this input file under each palette")]
+    preview: Option<String>,
+
+    #[arg(long, help = "Thumbnail side length in pixels for --preview", default_value = "256")]
+    thumbnail_size: u32,
+
+    #[arg(
+        long,
+        help = "Colour curve type for --preview: linear or log",
+        default_value = "linear"
+    )]
+    curve: DomainType,
+
+    #[command(flatten)]
+    input: InputArgs,
+}
+
+/// Exit status of a run that completed but rejected some input lines.
+const EXIT_REJECTS: u8 = 3;
+/// Exit status of a run with `--expect-strict` that completed with expected prefixes
+/// below their threshold.
+const EXIT_EXPECTATIONS: u8 = 4;
+
+/// Size of the `--export-profile-strip` chart (narrower for tiny images).
+const PROFILE_STRIP_WIDTH: u32 = 2048;
+const PROFILE_STRIP_HEIGHT: u32 = 64;
+/// Four pixels per bar and the chart padding.
+const SLASH8_CHART_WIDTH: u32 = 1040;
+const SLASH8_CHART_HEIGHT: u32 = 320;
+/// Unmet `--expect` prefixes named in the warning; the report lists them all.
+const EXPECT_WARNING_PREFIXES: usize = 5;
+
+/// The final line printed to stderr, for scripts to grep.
+#[derive(Default)]
+struct Summary {
+    lines: u64,
+    rejected: u64,
+    pixels: u64,
+    /// Paints that overwrote a different value, only printed when there are some.
+    conflicts: u64,
+    /// `--expect` prefixes below their threshold, only printed when there are some.
+    unmet: u64,
+    /// Whether unmet expectations fail the run, see `--expect-strict`.
+    expect_strict: bool,
+    outputs: Vec<String>,
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let outputs = if self.outputs.is_empty() { "-".to_string() } else { self.outputs.join(",") };
+        write!(f, "ipv4-heatmap: lines={} rejected={} pixels={}", self.lines, self.rejected, self.pixels)?;
+        if self.conflicts > 0 {
+            write!(f, " conflicts={}", self.conflicts)?;
+        }
+        if self.unmet > 0 {
+            write!(f, " unmet={}", self.unmet)?;
+        }
+        write!(f, " output={}", outputs)
+    }
+}
+
+/// Exit codes: 0 on success, 1 on failure, 4 when `--expect-strict` expectations were
+/// not met, 3 when lines were rejected but the run otherwise succeeded and 141 when
+/// data written to stdout was cut off. Heatmap runs end with a [`Summary`] line.
+fn main() -> ExitCode {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
 
     // Configure logging based on verbose level
-    let log_level = match args.verbose {
+    let log_level = match cli.verbose {
         0 => log::LevelFilter::Warn,
         1 => log::LevelFilter::Debug,
         _ => log::LevelFilter::Trace,
     };
 
-    env_logger::Builder::from_default_env()
-        .filter_level(log_level)
-        .init();
+    match cli.log_format {
+        LogFormat::Text => env_logger::Builder::from_default_env()
+            .filter_level(log_level)
+            .init(),
+        LogFormat::Json => ip_heatmap::JsonLogger::init(log_level).expect("no logger is installed yet"),
+    }
+
+    let mut summary = Summary::default();
+    let text = ReportText { ascii: cli.ascii, no_colour: cli.no_color };
+    let result = match &mut cli.command {
+        Some(Command::Palettes(palettes_args)) => render_palettes(palettes_args),
+        Some(Command::Montage(montage_args)) => render_montage(montage_args),
+        Some(Command::Compare(compare_args)) => compare(compare_args, text),
+        Some(Command::Imgdiff(imgdiff_args)) => imgdiff(imgdiff_args),
+        Some(Command::Render(render_args)) => run_render(render_args, &matches, text, &mut summary),
+        Some(Command::Convert(convert_args)) => convert(convert_args, &mut summary),
+        Some(Command::Summarize(summarize_args)) => summarize(summarize_args, text, &mut summary),
+        Some(Command::Inspect(inspect_args)) => inspect(inspect_args),
+        Some(Command::Generate(generate_args)) => generate(generate_args),
+        #[cfg(feature = "serve")]
+        Some(Command::Serve(serve_args)) => serve(serve_args),
+        None => run_render(&mut cli.render, &matches, text, &mut summary),
+    };
+    let closed = result.as_ref().err().and_then(stdout::StdoutClosed::find).map(|closed| closed.data);
+    match (&result, closed) {
+        // Whoever closed stdout did not want the rest of the report
+        (Err(_), Some(false)) => return ExitCode::SUCCESS,
+        (Err(_), Some(true)) => eprintln!("Error: stdout was closed before all output was written"),
+        (Err(err), None) => eprintln!("Error: {:?}", err),
+        (Ok(()), _) => {}
+    }
+    if matches!(cli.command, None | Some(Command::Render(_))) {
+        eprintln!("{}", summary);
+    }
+    match result {
+        Err(_) if closed.is_some() => ExitCode::from(stdout::EXIT_STDOUT_CLOSED),
+        Err(err) => err.downcast_ref::<exec::CommandFailed>().map_or(ExitCode::FAILURE, |failed| ExitCode::from(failed.exit_code())),
+        Ok(()) if summary.expect_strict && summary.unmet > 0 => ExitCode::from(EXIT_EXPECTATIONS),
+        Ok(()) if summary.rejected > 0 => ExitCode::from(EXIT_REJECTS),
+        Ok(()) => ExitCode::SUCCESS,
+    }
+}
+
+/// The `render` subcommand, which also runs when no subcommand is given. `matches`
+/// are the command line's, for the `--out-dir` parameters.
+fn run_render(args: &mut RenderArgs, matches: &ArgMatches, text: ReportText, summary: &mut Summary) -> Result<()> {
+    reject_legacy_flags(args)?;
+    flag_conflicts::check(args)?;
+    warn_invalid_texts(&args.texts);
+    check_min_max(&mut args.min_value, &mut args.max_value, Some(args.swap_min_max))?;
+    if let (DomainType::Logarithmic, Some(min_value), Some(offset)) = (args.curve, args.min_value, args.log_offset)
+        && min_value + offset <= 0.0
+    {
+        anyhow::bail!("--min-value {} plus --log-offset {} must be greater than 0 on a log curve", min_value, offset);
+    }
+    check_downsample_sizes(args)?;
+    if !args.no_template {
+        expand_output_templates(args)?;
+    }
+    match args.validate {
+        true => validate(args, summary),
+        false => render(args, matches, text, summary),
+    }
+}
+
+fn render(args: &RenderArgs, matches: &ArgMatches, text: ReportText, summary: &mut Summary) -> Result<()> {
+    check_memory(args)?;
+    if args.bits_per_pixel.len() > 1 {
+        return render_resolutions(args, text, summary);
+    }
+    let bundle = args.out_dir.as_deref().map(Bundle::create).transpose()?;
+    // A single-file flag replaces its bundle member
+    let staged = |file: &Option<String>, member: &str| file.clone().or_else(|| bundle.as_ref().map(|bundle| bundle.member(member)));
+    let mut heatmap = new_heatmap(args, args.bits_per_pixel[0]);
+    // Parse render specs before processing input so mistakes fail fast
+    let mut base_options = base_render_options(args, &heatmap)?;
+    let mut renders: Vec<RenderSpec> = staged(&args.output.clone().or_else(|| args.output_flag.clone()), "map.png")
+        .into_iter()
+        .map(|output| RenderSpec {
+            output,
+            options: base_options.clone(),
+        })
+        .collect();
+    for spec in &args.render {
+        let mut render = RenderSpec::parse(spec, &base_options).map_err(|err| anyhow::anyhow!(err))?;
+        // A palette override replaces the faded palette
+        if args.fade_low && !matches!(render.options.palette, Palette::Faded { .. }) {
+            render.options.palette = render.options.palette.faded_toward(theme(args).background_rgb());
+        }
+        renders.push(render);
+    }
+    let expectations = args.expect.as_deref().map(read_expectations).transpose()?;
+    let outputs: Vec<&str> = renders
+        .iter()
+        .skip(args.no_data_layer as usize)
+        .map(|render| render.output.as_str())
+        .chain(args.annotation_layer.as_deref())
+        .chain(args.thumbnail.iter().map(|thumbnail| thumbnail.output.as_str()))
+        .chain(args.preview.iter().map(|preview| preview.output.as_str()))
+        .chain(
+            [
+                &args.save_state,
+                &args.histogram,
+                &args.slash8_chart,
+                &args.stats_json,
+                &args.rejects,
+                &args.export_prefixes,
+                &args.export_profile,
+                &args.export_profile_strip,
+                &args.export_cells,
+                &args.expect_report,
+            ]
+            .into_iter()
+                .flatten()
+                .map(String::as_str),
+        )
+        .collect();
+    if args.no_clobber
+        && let Some(existing) = outputs.iter().find(|output| Path::new(output).exists())
+    {
+        anyhow::bail!("Output {} already exists and --no-clobber was given", existing);
+    }
+
+    configure_input(&mut heatmap, args)?;
+    for net in &args.rank {
+        heatmap.check_prefix_resolution(net.prefix_len()).with_context(|| format!("Cannot rank {}", net))?;
+    }
+    #[cfg(feature = "serve")]
+    heatmap.set_metrics(args.metrics_listen.as_deref().map(start_metrics).transpose()?);
+    if let Some(state_file) = &args.state_mmap {
+        heatmap.map_state(state_file)?;
+    }
+    if let Some(spec) = &args.preview {
+        heatmap.set_preview(Some(Preview::new(spec.clone(), base_options.clone(), args.downsample)));
+    }
+    let mut frame = frame(args);
+    let mut backed_up = false;
+    let snapshot = |heatmap: &Heatmap| {
+        log::info!("Writing a snapshot after {} lines", heatmap.lines_processed());
+        if args.backup && !backed_up {
+            backup_outputs(&outputs)?;
+            backed_up = true;
+        }
+        if args.state_mmap.is_some() {
+            heatmap.sync_state()?;
+        }
+        write_images(args, heatmap, &renders, &base_options, &frame).map(|_| ())
+    };
+    let processed = if !args.inputs.is_empty() {
+        read_inputs(&mut heatmap, &args.inputs, args.weight, args.threads.into())
+    } else if let Some(command) = &args.exec {
+        read_command(&mut heatmap, command, live_options(args), snapshot)
+    } else if args.threads > 1 && args.format == InputFormat::Text {
+        // Parser threads read ahead of painting, so a snapshot would miss lines in flight
+        heatmap.process_input_parallel(std::io::stdin().lock(), args.threads.into())
+    } else if args.format == InputFormat::RawU32v {
+        // Snapshots split stdin into lines, which binary records do not have
+        heatmap.process_input()
+    } else {
+        read_stdin(&mut heatmap, live_options(args), snapshot)
+    };
+    // Keys read before a failure are painted too, for the state file
+    let painted = heatmap.finish_preaggregation();
+    let processed = processed.and(painted);
+    // Waits for a preview still being written
+    heatmap.set_preview(None);
+    summary.lines = heatmap.lines_processed();
+    summary.rejected = heatmap.rejects().total();
+    summary.pixels = heatmap.touched_pixels();
+    summary.conflicts = heatmap.conflicts();
+    if args.state_mmap.is_some() {
+        // Checkpoint what was read even when processing failed part-way
+        heatmap.sync_state()?;
+    }
+    processed?;
+    if args.floor.is_some() || args.ceiling.is_some() {
+        let counts = heatmap.clamp_cells(args.floor, args.ceiling)?;
+        log::info!("Cleared {} cells below the floor and capped {} at the ceiling", counts.floored, counts.capped);
+        summary.pixels = heatmap.touched_pixels();
+    }
+    if args.invert {
+        let unpainted = heatmap.invert()?;
+        log::info!("Inverted the map; {} cells were unpainted", unpainted);
+        summary.pixels = heatmap.touched_pixels();
+    }
+    if args.backup && !backed_up {
+        backup_outputs(&outputs)?;
+    }
+    warn_clipped(&heatmap, args.clip_warning, &mut frame, &base_options.strings);
+    if let Some(colours) = &mut base_options.category_colours {
+        heatmap.assign_category_colours(colours);
+        for render in &mut renders {
+            render.options.category_colours = Some(colours.clone());
+        }
+    }
+    for rank in heatmap.rank_prefixes(&args.rank)? {
+        eprintln!("rank {}", rank.to_text());
+        if args.rank_mark {
+            frame.outlines.push(Outline { net: rank.net, colour: None, width: 1 });
+            frame.labels.push(rank.label());
+        }
+    }
+    warn_nothing_to_crop(&heatmap, &frame);
+    summary.outputs = write_images(args, &heatmap, &renders, &base_options, &frame)?;
+    if let (Some(path), Some(colours)) = (&args.category_colours, &base_options.category_colours)
+        && colours.added() > 0
+    {
+        colours.save(path)?;
+        log::info!("Added {} categories to {}", colours.added(), path);
+    }
+
+    let rejects = args.rejects.clone().or_else(|| {
+        let rejected = heatmap.rejects().total() > 0;
+        bundle.as_ref().filter(|_| rejected).map(|bundle| bundle.member("rejects.txt"))
+    });
+    if let Some(rejects_file) = &rejects {
+        write_rejects(rejects_file, heatmap.rejects())?;
+    }
+
+    let cells = args.export_cells.clone().or_else(|| {
+        let bundled = args.bundle.contains(&BundleMember::Cells);
+        bundle.as_ref().filter(|_| bundled).map(|bundle| bundle.member("cells.csv"))
+    });
+    if let Some(cells_file) = &cells {
+        ip_heatmap::write_atomic(cells_file, |writer| Ok(heatmap.write_cells_csv(writer)?))
+            .with_context(|| format!("Failed to write cells {}", cells_file))?;
+    }
+
+    if let Some(prefixes_file) = &args.export_prefixes {
+        write_prefixes(prefixes_file, &heatmap, args.export_prefixes_threshold)?;
+    }
+
+    if let Some(expectations) = &expectations {
+        summary.unmet = check_expectations(args, &heatmap, expectations)?;
+        summary.expect_strict = args.expect_strict;
+    }
+
+    if let Some(profile_file) = &args.export_profile {
+        ip_heatmap::write_atomic(profile_file, |writer| match profile_file.ends_with(".npy") {
+            true => Ok(heatmap.write_profile_npy(writer)?),
+            false => Ok(heatmap.write_profile_csv(writer)?),
+        })
+        .with_context(|| format!("Failed to write profile {}", profile_file))?;
+    }
+
+    if let Some(strip_file) = &args.export_profile_strip {
+        let width = (heatmap.image_size() as u64).pow(2).min(PROFILE_STRIP_WIDTH as u64) as u32;
+        let strip = heatmap
+            .render_profile_strip(&base_options, width, PROFILE_STRIP_HEIGHT)
+            .map_err(|err| anyhow::anyhow!(err))?;
+        ip_heatmap::save_png(strip_file, &strip, &heatmap.png_metadata(&base_options), &base_options.png)?;
+    }
+
+    if let Some(state_file) = &args.save_state {
+        heatmap.save_state(state_file)?;
+    }
+
+    if args.histogram.is_some() || args.histogram_text {
+        let histogram = heatmap.histogram(args.histogram_bins, !args.histogram_linear);
+        if args.histogram_text {
+            eprint!("{}", histogram.to_text());
+        }
+        if let Some(histogram_file) = &args.histogram {
+            ip_heatmap::save_png(histogram_file, &histogram.render(800, 400, &base_options.strings), &[], &base_options.png)?;
+        }
+    }
+
+    if let Some(chart_file) = &args.slash8_chart {
+        let chart = heatmap.slash8_chart(args.slash8_chart_log)?;
+        let chart = chart.render(SLASH8_CHART_WIDTH, SLASH8_CHART_HEIGHT, &base_options.strings);
+        ip_heatmap::save_png(chart_file, &chart, &[], &base_options.png)?;
+    }
+
+    let coverage_prefixes: &[u8] = if args.coverage_report { &args.coverage_prefixes } else { &[] };
+    let stats = heatmap.stats(coverage_prefixes);
+    if stats.ipv6_skipped > 0 {
+        eprintln!("ipv6 skipped: {}", stats.ipv6_skipped);
+    }
+    if args.stats {
+        eprint!("{}", stats.to_text(text.stderr()));
+    }
+    if args.coverage_report {
+        eprint!("{}", stats.coverage_text(text.stderr()));
+    }
+    if args.sanity_check {
+        let report = heatmap.sanity_report()?;
+        eprint!("{}", report.to_text(text.stderr()));
+        for warning in report.warnings(args.sanity_factor) {
+            log::warn!("Sanity check: {}", warning);
+        }
+    }
+    if args.timing {
+        eprint!("{}", heatmap.timer().to_text());
+    }
+    if let Some(stats_file) = &staged(&args.stats_json, "stats.json") {
+        ip_heatmap::write_atomic(stats_file, |writer| Ok(writeln!(writer, "{}", stats.to_json())?))
+            .with_context(|| format!("Failed to write stats to {}", stats_file))?;
+    }
+
+    if let Some(bundle) = bundle {
+        let params = bundle.member("params.toml");
+        ip_heatmap::write_atomic(&params, |writer| Ok(write!(writer, "{}", params_toml(matches))?))
+            .with_context(|| format!("Failed to write parameters to {}", params))?;
+        bundle.finish(&mut summary.outputs)?;
+    }
+
+    Ok(())
+}
+
+/// The `--out-dir` bundle, staged in a hidden sibling directory that is renamed into
+/// place once every member is written, and removed if the run fails first.
+struct Bundle {
+    dir: PathBuf,
+    staging: PathBuf,
+}
+
+impl Bundle {
+    /// Stage a bundle for `dir`, which must not exist or be empty.
+    fn create(dir: &str) -> Result<Bundle> {
+        let dir = PathBuf::from(dir);
+        if let Ok(mut entries) = std::fs::read_dir(&dir)
+            && entries.next().is_some()
+        {
+            anyhow::bail!("Output directory {} already exists and is not empty", dir.display());
+        }
+        if dir.exists() && !dir.is_dir() {
+            anyhow::bail!("Output directory {} already exists and is not a directory", dir.display());
+        }
+        let name = dir.file_name().with_context(|| format!("Invalid output directory {}", dir.display()))?;
+        let parent = dir.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        std::fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        let staging = parent.join(format!(".{}.partial-{}", name.to_string_lossy(), std::process::id()));
+        std::fs::create_dir(&staging).with_context(|| format!("Failed to create {}", staging.display()))?;
+        Ok(Bundle { dir, staging })
+    }
+
+    /// Where the member `name` is written until the bundle is finished.
+    fn member(&self, name: &str) -> String {
+        self.staging.join(name).to_string_lossy().into_owned()
+    }
+
+    /// Move the bundle into place, pointing the staged paths in `outputs` there too.
+    fn finish(self, outputs: &mut [String]) -> Result<()> {
+        std::fs::rename(&self.staging, &self.dir)
+            .with_context(|| format!("Failed to move the bundle into {}", self.dir.display()))?;
+        for output in outputs {
+            if let Ok(member) = Path::new(output.as_str()).strip_prefix(&self.staging) {
+                *output = self.dir.join(member).to_string_lossy().into_owned();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Bundle {
+    fn drop(&mut self) {
+        // Nothing is left to remove once the bundle was moved into place
+        let _ = std::fs::remove_dir_all(&self.staging);
+    }
+}
+
+/// The render options of this run, given or defaulted, as TOML keyed by their long
+/// flag names.
+fn params_toml(matches: &ArgMatches) -> String {
+    let command = Cli::command();
+    let (command, matches) = match matches.subcommand() {
+        Some(("render", render)) => (command.find_subcommand("render").expect("render is a subcommand").clone(), render),
+        _ => (command, matches),
+    };
+    let quote = |value: &std::ffi::OsStr| {
+        let mut quoted = String::from("\"");
+        for c in value.to_string_lossy().chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    };
+    let mut toml = String::from("# Effective options of this run; defaults are marked\n");
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if arg.is_hide_set() {
+            continue;
+        }
+        let Some(values) = matches.try_get_raw(id).ok().flatten() else { continue };
+        let values: Vec<&std::ffi::OsStr> = values.collect();
+        let value = match arg.get_action() {
+            clap::ArgAction::SetTrue | clap::ArgAction::SetFalse | clap::ArgAction::Count => {
+                values.iter().map(|value| value.to_string_lossy()).collect::<Vec<_>>().join("")
+            }
+            clap::ArgAction::Append => {
+                format!("[{}]", values.iter().map(|value| quote(value)).collect::<Vec<_>>().join(", "))
+            }
+            _ => values.first().map_or_else(String::new, |value| quote(value)),
+        };
+        let default = matches.value_source(id) == Some(clap::parser::ValueSource::DefaultValue);
+        let key = arg.get_long().unwrap_or(id);
+        toml.push_str(&format!("{} = {}{}\n", key, value, if default { " # default" } else { "" }));
+    }
+    toml
+}
+
+/// What rendering at `bits_per_pixel` with these flags allocates.
+fn memory_plan(args: &RenderArgs, bits_per_pixel: u8) -> MemoryPlan {
+    MemoryPlan {
+        bits_per_pixel,
+        low_memory: args.low_memory,
+        decorated: !frame(args).is_plain() || args.legend_bands.is_some(),
+        output_size: args.output_size,
+        thumbnails: args.thumbnail.iter().map(|thumbnail| thumbnail.size).collect(),
+        percentiles: args.max_percentile.is_some()
+            || args.winsorize.is_some()
+            || args.histogram.is_some()
+            || args.histogram_text,
+        threads: args.threads.into(),
+    }
+}
+
+/// Check the --output-size and --thumbnail sizes fit every -z resolution, before
+/// any input is read.
+fn check_downsample_sizes(args: &RenderArgs) -> Result<()> {
+    for &bits_per_pixel in &args.bits_per_pixel {
+        if let Some(size) = args.output_size {
+            ip_heatmap::check_downsample_size(bits_per_pixel, size).context("Invalid --output-size")?;
+        }
+        for thumbnail in &args.thumbnail {
+            ip_heatmap::check_downsample_size(bits_per_pixel, thumbnail.size)
+                .with_context(|| format!("Invalid --thumbnail {}:{}", thumbnail.output, thumbnail.size))?;
+        }
+    }
+    Ok(())
+}
+
+/// Fail if a `-z` is too large for this platform, or the buffers of every `-z`
+/// together would exceed `--max-memory`.
+fn check_memory(args: &RenderArgs) -> Result<()> {
+    for &bits in &args.bits_per_pixel {
+        Geometry::for_bits_per_pixel(bits).check_platform().map_err(|err| anyhow::anyhow!(err))?;
+    }
+    let Some(limit) = args.max_memory else { return Ok(()) };
+    let plans: Vec<MemoryPlan> = args.bits_per_pixel.iter().map(|&bits| memory_plan(args, bits)).collect();
+    let total: u64 = plans.iter().map(|plan| plan.estimate().total()).sum();
+    if total <= limit {
+        return Ok(());
+    }
+    let finest = plans.iter().min_by_key(|plan| plan.bits_per_pixel).unwrap();
+    let mut suggestions = Vec::new();
+    // Each step of 2 bits per pixel quarters the map
+    let fitting = (finest.bits_per_pixel..=32).step_by(2).find(|&bits| {
+        let others: u64 = plans
+            .iter()
+            .filter(|plan| plan.bits_per_pixel != finest.bits_per_pixel)
+            .map(|plan| plan.estimate().total())
+            .sum();
+        others + MemoryPlan { bits_per_pixel: bits, ..finest.clone() }.estimate().total() <= limit
+    });
+    if let Some(bits) = fitting.filter(|&bits| bits != finest.bits_per_pixel) {
+        suggestions.push(format!("-z {} instead of -z {}", bits, finest.bits_per_pixel));
+    }
+    let estimate = finest.estimate();
+    if (MemoryPlan { low_memory: true, ..finest.clone() }).estimate().total() < estimate.total() {
+        suggestions.push("--low-memory to stream the image".to_string());
+    }
+    if estimate.bytes("sorted values") > 0 {
+        suggestions.push("--max-value instead of percentiles".to_string());
+    }
+    if estimate.bytes("input batches") > 0 {
+        suggestions.push("fewer --threads".to_string());
+    }
+    let mut message = match plans.len() {
+        1 => format!("Projected memory use {} exceeds --max-memory {}", estimate, ip_heatmap::format_bytes(limit)),
+        _ => format!(
+            "Projected memory use {} for {} resolutions exceeds --max-memory {}",
+            ip_heatmap::format_bytes(total),
+            plans.len(),
+            ip_heatmap::format_bytes(limit)
+        ),
+    };
+    if !suggestions.is_empty() {
+        message.push_str(&format!("; try {}", suggestions.join(", or ")));
+    }
+    anyhow::bail!(message)
+}
 
-    let output_file = args.output.clone();
-    
+fn new_heatmap(args: &RenderArgs, bits_per_pixel: u8) -> Heatmap {
     // Select colour scale based on command line argument
     let colour_scale = match args.colour_scale {
         ColourScale::Accessible | ColourScale::Cividis => &colorous::CIVIDIS,
@@ -94,18 +1706,877 @@ fn main() -> Result<()> {
         args.curve
     };
 
-    let mut heatmap = Heatmap::new(
+    Heatmap::new(
         curve,
         args.min_value,
         args.max_value,
         args.accumulate,
-        args.bits_per_pixel,
+        bits_per_pixel,
         colour_scale,
         args.value_mode,
         None,
+    )
+}
+
+/// Expand the variables of every output name, see [`OutputTemplate`]. A `--render`
+/// output's `{curve}` and `{palette}` are its own.
+fn expand_output_templates(args: &mut RenderArgs) -> Result<()> {
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64);
+    let curve = if args.log_min.is_some() || args.log_max.is_some() { DomainType::Logarithmic } else { args.curve };
+    let palette = match args.colour_scale {
+        ColourScale::Accessible | ColourScale::Cividis => "cividis",
+        ColourScale::Magma => "magma",
+    };
+    let template = OutputTemplate {
+        time,
+        bits_per_pixel: match args.bits_per_pixel[..] {
+            [bits_per_pixel] => Some(bits_per_pixel),
+            _ => None,
+        },
+        curve,
+        palette: palette.to_string(),
+        input_stem: ip_heatmap::input_stem(args.inputs.first().map(|input| input.path.as_str())),
+    };
+    let expand = |name: &mut String| -> Result<()> {
+        *name = template.expand(name).map_err(|err| anyhow::anyhow!("{} (or use --no-template)", err))?;
+        Ok(())
+    };
+    for name in [
+        &mut args.output,
+        &mut args.output_flag,
+        &mut args.annotation_layer,
+        &mut args.save_state,
+        &mut args.state_mmap,
+        &mut args.histogram,
+        &mut args.slash8_chart,
+        &mut args.stats_json,
+        &mut args.rejects,
+        &mut args.export_prefixes,
+        &mut args.export_profile,
+        &mut args.export_profile_strip,
+        &mut args.export_cells,
+        &mut args.expect_report,
+        &mut args.out_dir,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        expand(name)?;
+    }
+    for thumbnail in &mut args.thumbnail {
+        expand(&mut thumbnail.output)?;
+    }
+    let base = ip_heatmap::RenderOptions {
+        curve,
+        palette: Palette::builtin(palette).expect("colour scales are builtin palettes"),
+        ..Default::default()
+    };
+    for spec in &mut args.render {
+        let render = RenderSpec::parse(spec, &base).map_err(|err| anyhow::anyhow!(err))?;
+        let own = OutputTemplate {
+            curve: render.options.curve,
+            palette: render.options.palette.name().to_string(),
+            ..template.clone()
+        };
+        let expanded = own.expand(&render.output).map_err(|err| anyhow::anyhow!("{} (or use --no-template)", err))?;
+        *spec = format!("{}{}", expanded, &spec[render.output.len()..]);
+    }
+    Ok(())
+}
+
+/// Render options from the command line flags, before any `--render` overrides.
+fn base_render_options(args: &RenderArgs, heatmap: &Heatmap) -> Result<ip_heatmap::RenderOptions> {
+    let mut base_options = heatmap.render_options();
+    if let Some(path) = &args.category_colours {
+        base_options.category_colours = Some(CategoryColours::load(path)?);
+    }
+    base_options.gamma = args.gamma;
+    base_options.colour_lut = args.colour_lut;
+    base_options.lut_rounding = args.lut_rounding;
+    base_options.dither = args.dither.map(|pattern| ip_heatmap::Dither { pattern, strength: args.dither_strength });
+    base_options.strings = text_strings(&args.texts);
+    base_options.bands = args.legend_bands.clone();
+    base_options.snap_to_bands = args.snap_bands;
+    base_options.png = ip_heatmap::PngEncoding {
+        compression: args.png_compression,
+        filter: args.png_filter,
+    };
+    if args.log_base.is_some() || args.log_offset.is_some() {
+        let defaults = ip_heatmap::LogParams::default();
+        let params = ip_heatmap::LogParams::new(
+            args.log_base.unwrap_or(defaults.base),
+            args.log_offset.unwrap_or(defaults.offset),
+        )
+        .map_err(|err| anyhow::anyhow!(err))?;
+        base_options.log_params = Some(params);
+    }
+    match args.winsorize {
+        Some((low, high)) => {
+            base_options.min_percentile = Some(low);
+            base_options.max_percentile = Some(high);
+        }
+        None => {
+            if let Some(percentile) = args.max_percentile {
+                base_options.max_percentile = Some(check_percentile("--max-percentile", percentile)?);
+            }
+        }
+    }
+    if args.fade_low {
+        base_options.palette = base_options.palette.faded_toward(theme(args).background_rgb());
+    }
+    Ok(base_options)
+}
+
+/// Colours of the canvas and overlays from `-r`, `--background` and `--foreground`.
+fn theme(args: &RenderArgs) -> Theme {
+    Theme::resolve(args.reverse, args.background, args.foreground)
+}
+
+/// Apply the flags that control how input lines are read and painted.
+fn configure_input(heatmap: &mut Heatmap, args: &RenderArgs) -> Result<()> {
+    let mut options = args.parse.parse_options(args.format)?;
+    if let Some(rate) = args.sample {
+        let sampling = ip_heatmap::Sampling::new(rate, args.sample_seed).map_err(|err| anyhow::anyhow!(err))?;
+        options = options.sampling(Some(sampling));
+    }
+    heatmap.set_parse_options(options);
+    heatmap.set_timing(args.timing);
+    heatmap.set_error_policy(args.on_error);
+    heatmap.set_conflict_policy(args.on_conflict);
+    heatmap.set_low_memory(args.low_memory);
+    heatmap.set_view(args.crop);
+    heatmap.set_dedup_window(args.dedup_window);
+    heatmap.set_distinct_approx(args.distinct_approx, args.distinct_precision).map_err(|err| anyhow::anyhow!(err))?;
+    let preaggregation = match (args.preaggregate, args.distinct) {
+        (_, true) => Some(ip_heatmap::Preaggregation::Distinct),
+        (true, false) => Some(ip_heatmap::Preaggregation::Sum),
+        (false, false) => None,
+    };
+    heatmap
+        .set_preaggregation(preaggregation, args.preaggregate_memory, args.tmpdir.as_deref())
+        .map_err(|err| anyhow::anyhow!(err))?;
+    heatmap.set_weight(args.weight);
+    let downsampled = args.output_size.is_some() || !args.thumbnail.is_empty();
+    heatmap.check_value_modes(downsampled.then_some(args.downsample))
+}
+
+/// Warn when more than `threshold_percent` of the input fell outside the map or its
+/// crop, and say so in the title if there is one.
+fn warn_clipped(heatmap: &Heatmap, threshold_percent: f64, frame: &mut Frame, strings: &Strings) {
+    let stats = heatmap.stats(&[]);
+    let Some(note) = stats.clipped_note(threshold_percent) else {
+        return;
+    };
+    log::warn!(
+        "{}: {} of {} records and {} of {} value fell outside {}",
+        note,
+        stats.clipped_records,
+        stats.records,
+        stats.clipped_value,
+        stats.weighted_total,
+        match (frame.crop, heatmap.bits_per_pixel() % 2) {
+            (Some(net), _) => format!("--crop {}", net),
+            (None, 1) => "the 0.0.0.0/1 an odd -z covers".to_string(),
+            (None, _) => "the map".to_string(),
+        }
     );
-    heatmap.process_input()?;
-    heatmap.save(&output_file)?;
+    if let Some(title) = &mut frame.title {
+        let percent = format!("{:.0}", stats.clipped_percent());
+        *title = strings.format("title.clipped", &[("title", title), ("percent", &percent)]);
+    }
+}
+
+/// The texts of `--text` overrides, leaving out the invalid ones, which are warned
+/// about once by [`warn_invalid_texts`].
+fn text_strings(texts: &[String]) -> Strings {
+    let mut strings = Strings::default();
+    for text in texts {
+        let _ = strings.set_override(text);
+    }
+    strings
+}
+
+fn warn_invalid_texts(texts: &[String]) {
+    for text in texts {
+        if let Err(err) = Strings::default().set_override(text) {
+            log::warn!("Ignoring --text {}: {}", text, err);
+        }
+    }
+}
+
+/// Warn that `--crop-to-data` has no data to crop to, so the whole map is drawn.
+fn warn_nothing_to_crop(heatmap: &Heatmap, frame: &Frame) {
+    if frame.crop_to_data.is_some() && frame.crop.is_none() && heatmap.touched_pixels() == 0 {
+        log::warn!("Nothing was painted, so --crop-to-data draws the whole map");
+    }
+}
+
+/// Decorations of the main outputs.
+fn frame(args: &RenderArgs) -> Frame {
+    Frame {
+        title: args.title.clone(),
+        legend_label: args.legend_label.clone(),
+        crop: args.crop.map(|net| net.trunc()),
+        crop_to_data: args.crop_to_data,
+        font_size: args.font_size,
+        title_size: args.title_size,
+        shades: args.shade.clone(),
+        outlines: args.outline.clone(),
+        labels: Vec::new(),
+        theme: theme(args),
+        // On by default whenever a legend is drawn
+        pixel_caption: args.pixel_caption
+            || (!args.no_pixel_caption && (args.legend_label.is_some() || args.legend_bands.is_some())),
+    }
+}
+
+/// Render one map per `-z` value from a single pass over the input, inserting
+/// `-z<bits>` before the extension of each output name.
+fn render_resolutions(args: &RenderArgs, text: ReportText, summary: &mut Summary) -> Result<()> {
+    let mut heatmaps = Vec::with_capacity(args.bits_per_pixel.len());
+    for &bits_per_pixel in &args.bits_per_pixel {
+        let mut heatmap = new_heatmap(args, bits_per_pixel);
+        configure_input(&mut heatmap, args)?;
+        heatmaps.push(heatmap);
+    }
+    let outputs: Vec<(usize, String)> = args
+        .output
+        .iter()
+        .chain(&args.output_flag)
+        .flat_map(|output| {
+            let output = Path::new(output);
+            args.bits_per_pixel.iter().enumerate().map(move |(index, bits_per_pixel)| {
+                (index, resolution_output(output, *bits_per_pixel))
+            })
+        })
+        .collect();
+    if args.no_clobber
+        && let Some((_, existing)) = outputs.iter().find(|(_, output)| Path::new(output).exists())
+    {
+        anyhow::bail!("Output {} already exists and --no-clobber was given", existing);
+    }
+
+    let mut multi = ip_heatmap::MultiHeatmap::new(heatmaps)?;
+    let processed = match args.inputs.is_empty() {
+        true => multi.process_input_from_reader(std::io::stdin().lock()),
+        false => args.inputs.iter().try_for_each(|input| {
+            multi.set_weight(args.weight * input.weight);
+            multi.process_input_from_reader(open_input(&input.path)?)
+        }),
+    };
+    let first = &multi.heatmaps()[0];
+    summary.lines = first.lines_processed();
+    summary.rejected = first.rejects().total();
+    summary.pixels = first.touched_pixels();
+    summary.conflicts = first.conflicts();
+    processed?;
+
+    let frame = frame(args);
+    if args.backup {
+        backup_outputs(&outputs.iter().map(|(_, output)| output.as_str()).collect::<Vec<_>>())?;
+    }
+    for (index, output) in outputs {
+        let heatmap = &multi.heatmaps()[index];
+        let mut frame = frame.clone();
+        let options = base_render_options(args, heatmap)?;
+        warn_clipped(heatmap, args.clip_warning, &mut frame, &options.strings);
+        warn_nothing_to_crop(heatmap, &frame);
+        heatmap.save_framed(&output, &options, &frame)?;
+        summary.outputs.push(output);
+    }
+    if args.stats {
+        for heatmap in multi.heatmaps() {
+            eprintln!("-z {}:", heatmap.bits_per_pixel());
+            eprint!("{}", heatmap.stats(&[]).to_text(text.stderr()));
+        }
+    }
+    Ok(())
+}
+
+/// `map.png` becomes `map-z8.png` for 8 bits per pixel.
+fn resolution_output(output: &Path, bits_per_pixel: u8) -> String {
+    let stem = output.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let name = match output.extension() {
+        Some(extension) => format!("{}-z{}.{}", stem, bits_per_pixel, extension.to_string_lossy()),
+        None => format!("{}-z{}", stem, bits_per_pixel),
+    };
+    output.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Check `--min-value` and `--max-value` before any input is read. `swap` is whether
+/// `--swap-min-max` was given, or `None` for commands without it.
+fn check_min_max(min_value: &mut Option<f64>, max_value: &mut Option<f64>, swap: Option<bool>) -> Result<()> {
+    for (flag, value) in [("--min-value", *min_value), ("--max-value", *max_value)] {
+        if let Some(value) = value.filter(|value| !value.is_finite()) {
+            anyhow::bail!("{} must be a finite number: {}", flag, value);
+        }
+    }
+    let (Some(min), Some(max)) = (*min_value, *max_value) else {
+        return Ok(());
+    };
+    if swap == Some(true) && max < min {
+        log::warn!("--min-value {} is above --max-value {}; swapping them", min, max);
+        (*min_value, *max_value) = (Some(max), Some(min));
+        return Ok(());
+    }
+    match BoundsError::check(min, max) {
+        Ok(()) => Ok(()),
+        Err(BoundsError::Swapped) => anyhow::bail!(
+            "--max-value {} is less than --min-value {}. Swap them{}",
+            max,
+            min,
+            if swap.is_some() { ", or pass --swap-min-max" } else { "" }
+        ),
+        Err(BoundsError::Equal) => {
+            anyhow::bail!("--max-value {} equals --min-value {}, leaving no range of values to colour", max, min)
+        }
+        Err(err) => anyhow::bail!("{}", err.describe(min, max)),
+    }
+}
+
+fn check_percentile(flag: &str, percentile: f64) -> Result<f64> {
+    if !(0.0..=100.0).contains(&percentile) {
+        anyhow::bail!("{} must be between 0 and 100: {}", flag, percentile);
+    }
+    Ok(percentile)
+}
+
+/// Parse `--winsorize LOW,HIGH`, two percentiles with the low one below the high one.
+fn parse_winsorize(value: &str) -> Result<(f64, f64), String> {
+    let invalid = || format!("Invalid percentiles: {}. Use LOW,HIGH from 0 to 100, e.g. 1,99", value);
+    let (low, high) = value.split_once(',').ok_or_else(invalid)?;
+    let (low, high): (f64, f64) = (low.trim().parse().map_err(|_| invalid())?, high.trim().parse().map_err(|_| invalid())?);
+    if !(0.0..=100.0).contains(&low) || !(0.0..=100.0).contains(&high) {
+        return Err(invalid());
+    }
+    if low >= high {
+        return Err(format!("The low percentile must be below the high one: {}", value));
+    }
+    Ok((low, high))
+}
+
+/// Explain flags of the original C ipv4-heatmap that are accepted for compatibility
+/// but have no equivalent, instead of failing with a generic unknown-flag error.
+fn reject_legacy_flags(args: &RenderArgs) -> Result<()> {
+    let unsupported = [
+        (
+            args.legacy_annotations.is_some(),
+            "-a",
+            "annotation files",
+            Some("use --outline PREFIX[:RRGGBB[:WIDTH]] for each box of the file; its text is not drawn"),
+        ),
+        (args.legacy_shading.is_some(), "-s", "shading files", Some("use --shade PREFIX:RRGGBB for each line of the file")),
+        (args.legacy_font.is_some(), "-f", "fonts", Some("text always uses the built-in bitmap font")),
+        (args.legacy_prefixes.is_some(), "-p", "prefix files", Some("use --crop to focus on a prefix instead")),
+        (args.legacy_morton, "-m", "the Morton curve", Some("only the Hilbert curve is drawn")),
+    ];
+    if let Some((_, flag, feature, hint)) = unsupported.iter().find(|(given, ..)| *given) {
+        match hint {
+            Some(hint) => anyhow::bail!("{} ({}) from ipv4-heatmap is not implemented; {}", flag, feature, hint),
+            None => anyhow::bail!("{} ({}) from ipv4-heatmap is not implemented", flag, feature),
+        }
+    }
+    Ok(())
+}
+
+fn validate(args: &RenderArgs, summary: &mut Summary) -> Result<()> {
+    // More than one -z is refused with the other flag conflicts
+    let bits_per_pixel = args.bits_per_pixel[0];
+    let options = args.parse.parse_options(args.format)?;
+    let mut validator = ip_heatmap::Validator::new(bits_per_pixel, args.value_mode, args.accumulate, options);
+    validator.process_input_from_reader(std::io::stdin().lock())?;
+    let validation = validator.finish();
+    summary.lines = validation.lines;
+    summary.rejected = validation.rejects.total();
+    summary.pixels = validation.touched_pixels;
+
+    stdout::print(validation.to_text())?;
+    if let Some(stats_file) = &args.stats_json {
+        ip_heatmap::write_atomic(stats_file, |writer| Ok(writeln!(writer, "{}", validation.to_json())?))
+            .with_context(|| format!("Failed to write stats to {}", stats_file))?;
+    }
+    if let Some(rejects_file) = &args.rejects {
+        write_rejects(rejects_file, &validation.rejects)?;
+    }
+    if validation.reject_percent() > args.validate_max_reject_pct {
+        anyhow::bail!(
+            "{:.2}% of lines were rejected, more than the allowed {}%",
+            validation.reject_percent(),
+            args.validate_max_reject_pct
+        );
+    }
+    Ok(())
+}
+
+/// Move existing `outputs` aside to `<name>.bak`, replacing older backups.
+fn backup_outputs(outputs: &[&str]) -> Result<()> {
+    for output in outputs {
+        if Path::new(output).exists() {
+            let backup = format!("{}.bak", output);
+            std::fs::rename(output, &backup)
+                .with_context(|| format!("Failed to back up {} to {}", output, backup))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_rejects(filename: &str, rejects: &RejectLog) -> Result<()> {
+    ip_heatmap::write_atomic(filename, |writer| {
+        for reject in rejects.samples() {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}",
+                reject.line_number, reject.reason, reject.message, reject.content
+            )?;
+        }
+        let unlisted = rejects.total() - rejects.samples().len() as u64;
+        if unlisted > 0 {
+            writeln!(writer, "# {} more rejected lines not listed", unlisted)?;
+        }
+        Ok(())
+    })
+    .with_context(|| format!("Failed to write rejects file {}", filename))
+}
+
+fn read_expectations(filename: &str) -> Result<Vec<Expectation>> {
+    let text = std::fs::read_to_string(filename).with_context(|| format!("Failed to read expectations {}", filename))?;
+    ip_heatmap::parse_expectations(&text).map_err(|err| anyhow::anyhow!("{}: {}", filename, err))
+}
+
+/// Check the `--expect` prefixes, warn about those below their threshold and write
+/// the report, returning how many there are.
+fn check_expectations(args: &RenderArgs, heatmap: &Heatmap, expectations: &[Expectation]) -> Result<u64> {
+    let checks = heatmap.check_expectations(expectations, args.expect_threshold)?;
+    let unmet: Vec<String> = checks
+        .iter()
+        .filter(|check| check.status() != ExpectationStatus::Met)
+        .map(|check| format!("{} ({})", check.expectation.net, check.status()))
+        .collect();
+    if !unmet.is_empty() {
+        let shown = unmet.len().min(EXPECT_WARNING_PREFIXES);
+        log::warn!(
+            count = unmet.len();
+            "{} of {} expected prefixes are below their threshold: {}{}",
+            unmet.len(),
+            checks.len(),
+            unmet[..shown].join(", "),
+            if unmet.len() > shown { ", ..." } else { "" }
+        );
+    }
+    if let Some(report_file) = &args.expect_report {
+        ip_heatmap::write_atomic(report_file, |writer| Ok(ip_heatmap::write_expectation_report(&checks, writer)?))
+            .with_context(|| format!("Failed to write expectation report {}", report_file))?;
+    }
+    Ok(unmet.len() as u64)
+}
+
+fn write_prefixes(filename: &str, heatmap: &Heatmap, threshold: Option<i32>) -> Result<()> {
+    let prefixes = heatmap.covered_prefixes(threshold);
+    let pixel_prefix = heatmap.geometry().prefix_len_per_pixel;
+    ip_heatmap::write_atomic(filename, |writer| {
+        writeln!(
+            writer,
+            "# Prefixes covering the painted pixels; resolution /{} (bits_per_pixel {}), so each covers whole pixels",
+            pixel_prefix,
+            heatmap.bits_per_pixel()
+        )?;
+        match threshold {
+            Some(threshold) => writeln!(writer, "# Pixels with a value of at least {}", threshold)?,
+            None => writeln!(writer, "# All painted pixels")?,
+        }
+        for prefix in &prefixes {
+            writeln!(writer, "{}", prefix)?;
+        }
+        Ok(())
+    })
+    .with_context(|| format!("Failed to write prefix list {}", filename))
+}
+
+fn render_palettes(args: &PalettesArgs) -> Result<()> {
+    let palettes: Vec<Palette> = Palette::builtins()
+        .chain(args.palette_custom.iter().cloned())
+        .collect();
+
+    let image = match &args.preview {
+        Some(preview_file) => {
+            let heatmap = args.input.load(preview_file, args.curve, None, None)?;
+            ip_heatmap::render_palette_previews(&heatmap, &palettes, args.thumbnail_size)
+                .map_err(|err| anyhow::anyhow!(err))?
+        }
+        None => ip_heatmap::render_palette_strips(&palettes, args.width, args.bar_height),
+    };
+
+    ip_heatmap::save_png(&args.out, &image, &[], &ip_heatmap::PngEncoding::default())
+}
+
+fn render_montage(args: &MontageArgs) -> Result<()> {
+    warn_invalid_texts(&args.texts);
+    check_min_max(&mut args.min_value.clone(), &mut args.max_value.clone(), None)?;
+    let mut heatmaps = Vec::with_capacity(args.inputs.len());
+    for input in &args.inputs {
+        heatmaps.push(args.input.load(input, args.curve, args.min_value, args.max_value)?);
+    }
+
+    let titles: Vec<String> = if args.title.is_empty() {
+        args.inputs.clone()
+    } else {
+        args.title.clone()
+    };
+    let options = ip_heatmap::RenderOptions {
+        curve: args.curve,
+        min_value: args.min_value,
+        max_value: args.max_value,
+        min_percentile: None,
+        max_percentile: None,
+        log_params: None,
+        gamma: 1.0,
+        palette: args.palette.clone(),
+        strings: text_strings(&args.texts),
+        ..ip_heatmap::RenderOptions::default()
+    };
+    let refs: Vec<&Heatmap> = heatmaps.iter().collect();
+    let diff = args.diff.then_some(args.diff_curve);
+    let image = ip_heatmap::render_montage(&refs, &titles, &options, diff)?;
+    ip_heatmap::save_png(&args.output, &image, &[], &options.png)
+}
+
+fn compare(args: &CompareArgs, text: ReportText) -> Result<()> {
+    let load = |path: &str| -> Result<Heatmap> {
+        if ip_heatmap::is_state_file(path)? {
+            return Heatmap::load_state(path);
+        }
+        args.input.load(path, DomainType::Linear, None, None)
+    };
+    let (a, b) = (load(&args.a)?, load(&args.b)?);
+    if args.changes {
+        let changes = a.changes(&b, args.changes_threshold)?;
+        let write = |writer: &mut dyn Write| -> Result<()> {
+            match args.json {
+                true => writeln!(writer, "{}", ip_heatmap::changes_to_json(&changes, args.changes_threshold))?,
+                false => ip_heatmap::write_changes(writer, &changes)?,
+            }
+            Ok(())
+        };
+        return match &args.output {
+            Some(output) => ip_heatmap::write_atomic(output, |writer| write(writer)),
+            None => write(&mut stdout::Stdout::report()),
+        };
+    }
+    let report = a.similarity(&b)?;
+    if args.json {
+        stdout::println(report.to_json())?;
+    } else {
+        stdout::print(report.to_text(text.style(std::io::IsTerminal::is_terminal(&std::io::stdout()))))?;
+    }
+    Ok(())
+}
+
+/// Write the renders and thumbnails of the current buffer, returning the files written.
+fn write_images(
+    args: &RenderArgs,
+    heatmap: &Heatmap,
+    renders: &[RenderSpec],
+    base_options: &ip_heatmap::RenderOptions,
+    frame: &Frame,
+) -> Result<Vec<String>> {
+    let mut written = Vec::new();
+    let reduced = match args.output_size {
+        Some(size) => Some(heatmap.downsample(size, args.downsample)?),
+        None => None,
+    };
+    let display = reduced.as_ref().unwrap_or(heatmap);
+    for (index, render) in renders.iter().enumerate() {
+        // The annotations are split from the main output only
+        if let Some(annotations) = args.annotation_layer.as_deref().filter(|_| index == 0) {
+            let data = (!args.no_data_layer).then_some(render.output.as_str());
+            display.save_framed_layers(data, annotations, &render.options, frame)?;
+            written.extend(data.map(str::to_string));
+            written.push(annotations.to_string());
+            continue;
+        }
+        match args.multiples {
+            Some(prefix_len) => {
+                let grid = ip_heatmap::render_small_multiples(
+                    display,
+                    prefix_len,
+                    args.multiples_top,
+                    args.multiples_size,
+                    &render.options,
+                )?;
+                heatmap.timer().time(ip_heatmap::Phase::Encode, || {
+                    ip_heatmap::save_png(&render.output, &grid, &display.png_metadata(&render.options), &render.options.png)
+                })?;
+            }
+            None => display.save_framed(&render.output, &render.options, frame)?,
+        }
+        written.push(render.output.clone());
+    }
+    if !args.thumbnail.is_empty() {
+        // Thumbnails keep the colour domain of the main output
+        let (min_value, max_value) = display.domain_bounds(base_options);
+        let options = ip_heatmap::RenderOptions {
+            min_value: Some(min_value),
+            max_value: Some(max_value),
+            ..base_options.clone()
+        };
+        for thumbnail in &args.thumbnail {
+            heatmap
+                .downsample(thumbnail.size, args.downsample)?
+                .save_with_options(&thumbnail.output, &options)?;
+            written.push(thumbnail.output.clone());
+        }
+    }
+    Ok(written)
+}
+
+fn live_options(args: &RenderArgs) -> LiveOptions {
+    LiveOptions { queue_size: args.live_queue_size.into(), when_full: args.live_queue_full }
+}
+
+/// Process stdin, writing a snapshot with `snapshot` on SIGHUP and stopping early on
+/// SIGTERM or SIGINT.
+///
+/// Stdin is read on a separate thread so signals are noticed while waiting for input,
+/// e.g. from a FIFO that stays open between writers.
+#[cfg(unix)]
+fn read_stdin(heatmap: &mut Heatmap, live: LiveOptions, snapshot: impl FnMut(&Heatmap) -> Result<()>) -> Result<()> {
+    read_stream(heatmap, std::io::stdin(), live, snapshot).map(|_| ())
+}
+
+/// Why [`read_stream`] stopped reading.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Ended {
+    EndOfInput,
+    Signal,
+}
+
+/// Paint the lines read and parsed from `reader` on threads of their own, see
+/// [`ip_heatmap::LiveInput`], writing a snapshot on SIGHUP and stopping early on SIGINT
+/// or SIGTERM.
+///
+/// Signals are looked at between batches of lines, so a snapshot waits for at most
+/// one batch to be painted; while it is written the queues hold back the input.
+#[cfg(unix)]
+fn read_stream(
+    heatmap: &mut Heatmap,
+    reader: impl std::io::Read + Send + 'static,
+    live: LiveOptions,
+    mut snapshot: impl FnMut(&Heatmap) -> Result<()>,
+) -> Result<Ended> {
+    use signals::Request;
+
+    signals::install();
+    let mut input = ip_heatmap::LiveInput::spawn(heatmap, reader, live);
+    loop {
+        let painting = input.paint(heatmap, std::time::Duration::from_millis(100));
+        if !matches!(painting, Ok(true)) {
+            input.finish(heatmap);
+            return painting.map(|_| Ended::EndOfInput);
+        }
+        // Also between short batches and while the input is quiet
+        heatmap.tick_preview();
+        match signals::take_request() {
+            Some(Request::Snapshot) => {
+                // A failed snapshot should not end a long-running collection
+                if let Err(err) = snapshot(heatmap) {
+                    log::error!("Failed to write snapshot: {:#}", err);
+                }
+            }
+            Some(Request::Terminate) => {
+                // Lines still queued, and a partial last line, are dropped
+                input.finish(heatmap);
+                log::info!("Stopping after {} lines on signal", heatmap.lines_processed());
+                return Ok(Ended::Signal);
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn read_stdin(heatmap: &mut Heatmap, _live: LiveOptions, _snapshot: impl FnMut(&Heatmap) -> Result<()>) -> Result<()> {
+    heatmap.process_input()
+}
+
+#[cfg(not(unix))]
+fn read_stream(
+    heatmap: &mut Heatmap,
+    reader: impl std::io::Read + Send + 'static,
+    _live: LiveOptions,
+    _snapshot: impl FnMut(&Heatmap) -> Result<()>,
+) -> Result<Ended> {
+    heatmap.process_input_parallel(std::io::BufReader::new(reader), 1).map(|_| Ended::EndOfInput)
+}
+
+/// Paint the stdout of `command`, run by the shell, as [`read_stream`] does stdin.
+///
+/// The child is killed when reading stops early, on a signal or a parse failure,
+/// and a child that exits unsuccessfully fails the run with its exit status.
+fn read_command(
+    heatmap: &mut Heatmap,
+    command: &str,
+    live: LiveOptions,
+    snapshot: impl FnMut(&Heatmap) -> Result<()>,
+) -> Result<()> {
+    let mut child = exec::spawn(command)?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let read = read_stream(heatmap, stdout, live, snapshot);
+    if read.is_err() || matches!(read, Ok(Ended::Signal)) {
+        exec::kill(&mut child, command);
+        return read.map(|_| ());
+    }
+    exec::wait(&mut child, command)
+}
+
+fn convert(args: &ConvertArgs, summary: &mut Summary) -> Result<()> {
+    let mut converter = ip_heatmap::Converter::new(args.parse.parse_options(args.from)?);
+    converter.set_merge_siblings(args.bits_per_pixel);
+    let reader: Box<dyn std::io::BufRead> = match args.input.as_str() {
+        "-" => Box::new(std::io::stdin().lock()),
+        path => open_input(path)?,
+    };
+    let conversion = match args.output.as_str() {
+        "-" => converter.convert(reader, stdout::Stdout::data(), args.to)?,
+        path => {
+            let mut conversion = None;
+            ip_heatmap::write_atomic(path, |writer| {
+                conversion = Some(converter.convert(reader, writer, args.to)?);
+                Ok(())
+            })?;
+            conversion.expect("written")
+        }
+    };
+    eprintln!("{}", conversion.to_text());
+    summary.rejected = conversion.rejects.total();
+    if let Some(rejects_file) = &args.rejects {
+        write_rejects(rejects_file, &conversion.rejects)?;
+    }
+    Ok(())
+}
+
+fn summarize(args: &SummarizeArgs, text: ReportText, summary: &mut Summary) -> Result<()> {
+    let mut summarizer = ip_heatmap::Summarizer::new(args.parse.parse_options(args.format)?);
+    summarizer.set_weight(args.weight);
+    match args.input.as_str() {
+        "-" => summarizer.process_input_from_reader(std::io::stdin().lock())?,
+        path => summarizer.process_input_from_reader(open_input(path)?)?,
+    }
+    let input_summary = summarizer.finish();
+    summary.lines = input_summary.lines;
+    summary.rejected = input_summary.rejects.total();
+
+    if args.json {
+        stdout::println(input_summary.to_json())?;
+    } else {
+        stdout::print(input_summary.to_text(text.style(std::io::IsTerminal::is_terminal(&std::io::stdout()))))?;
+    }
+    if let Some(rejects_file) = &args.rejects {
+        write_rejects(rejects_file, &input_summary.rejects)?;
+    }
+    Ok(())
+}
+
+fn inspect(args: &InspectArgs) -> Result<()> {
+    let inspection = ip_heatmap::inspect_file(&args.file)?;
+    if args.json {
+        stdout::println(inspection.to_json())?;
+    } else {
+        stdout::println(inspection)?;
+    }
+    Ok(())
+}
+
+fn generate(args: &GenerateArgs) -> Result<()> {
+    let config =
+        ip_heatmap::testdata::GenerateConfig { lines: args.lines, hotspots: args.hotspots, spread: args.spread, seed: args.seed };
+    let write = |writer: &mut dyn Write| -> Result<()> {
+        for line in ip_heatmap::testdata::generate(config) {
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(writer.flush()?)
+    };
+    match args.output.as_str() {
+        // Any prefix of the lines is test input too, so a reader stopping early loses nothing
+        "-" => write(&mut std::io::BufWriter::new(stdout::Stdout::report())),
+        path => ip_heatmap::write_atomic(path, |writer| write(writer)),
+    }
+}
+
+#[cfg(feature = "serve")]
+fn serve(args: &ServeArgs) -> Result<()> {
+    let listener = std::net::TcpListener::bind(&args.listen)
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    // Printed even without -v, so callers that asked for port 0 learn the port
+    eprintln!("Listening on http://{}", listener.local_addr()?);
+    let options = ip_heatmap::ServeOptions {
+        max_body_bytes: args.max_body_bytes,
+        max_concurrent: args.max_concurrent,
+        request_timeout: std::time::Duration::from_secs(args.timeout),
+        min_bits_per_pixel: args.min_bits_per_pixel,
+        metrics: args.metrics_listen.as_deref().map(start_metrics).transpose()?,
+    };
+    ip_heatmap::serve(listener, &options)
+}
+
+/// Serve a new set of metrics on `listen` from a background thread.
+#[cfg(feature = "serve")]
+fn start_metrics(listen: &str) -> Result<std::sync::Arc<ip_heatmap::Metrics>> {
+    let listener = std::net::TcpListener::bind(listen).with_context(|| format!("Failed to listen on {}", listen))?;
+    eprintln!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+    let metrics = std::sync::Arc::new(ip_heatmap::Metrics::new());
+    let shared = std::sync::Arc::clone(&metrics);
+    std::thread::spawn(move || {
+        if let Err(err) = ip_heatmap::serve_metrics(listener, shared) {
+            log::error!("Stopped serving metrics: {:#}", err);
+        }
+    });
+    Ok(metrics)
+}
+
+fn imgdiff(args: &ImgdiffArgs) -> Result<()> {
+    let load = |path: &str| -> Result<image::RgbaImage> {
+        Ok(image::open(path)
+            .with_context(|| format!("Failed to read image {}", path))?
+            .to_rgba8())
+    };
+    let report = ip_heatmap::compare_images(&load(&args.a)?, &load(&args.b)?, args.tolerance)?;
+    if args.json {
+        stdout::println(report.to_json())?;
+    } else {
+        stdout::println(report)?;
+    }
+    if !report.matches() {
+        anyhow::bail!("{} pixels differ by more than {}", report.differing_pixels, args.tolerance);
+    }
+    Ok(())
+}
 
+/// Process each input file in turn, its values weighted by `weight` times its own.
+fn read_inputs(heatmap: &mut Heatmap, inputs: &[WeightedInput], weight: f64, threads: usize) -> Result<()> {
+    for input in inputs {
+        heatmap.set_weight(weight * input.weight);
+        heatmap.set_input_name(Some(&input.path));
+        let processed = heatmap.process_input_parallel(open_input(&input.path)?, threads);
+        heatmap.set_input_name(None);
+        heatmap.set_weight(weight);
+        processed?;
+        // Parallel reads paint without stopping for previews
+        heatmap.tick_preview();
+    }
     Ok(())
 }
+
+/// Open an input file, or fetch an `http://` URL with the `http` feature.
+fn open_input(filename: &str) -> Result<Box<dyn std::io::BufRead>> {
+    if ip_heatmap::is_url(filename) {
+        #[cfg(feature = "http")]
+        return ip_heatmap::fetch(filename, &ip_heatmap::FetchOptions::default());
+        #[cfg(not(feature = "http"))]
+        anyhow::bail!("Cannot read {}: URL inputs need the http cargo feature", filename);
+    }
+    let file = std::fs::File::open(filename)
+        .with_context(|| format!("Failed to open input {}", filename))?;
+    Ok(Box::new(std::io::BufReader::new(file)))
+}
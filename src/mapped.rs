@@ -0,0 +1,118 @@
+//! A read-write shared memory mapping of a whole file.
+//!
+//! Only available on 64-bit little-endian Linux and macOS, where state files can be
+//! used in place; elsewhere [`Mapping::map`] fails.
+
+use std::fs::File;
+use std::io;
+
+pub(crate) struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    _file: File,
+}
+
+/// Whether [`Mapping::map`] is supported on this platform.
+pub(crate) const SUPPORTED: bool = cfg!(all(
+    any(target_os = "linux", target_os = "macos"),
+    target_pointer_width = "64",
+    target_endian = "little"
+));
+
+impl Mapping {
+    /// Map the first `len` bytes of `file`, which must be at least that long.
+    pub(crate) fn map(file: File, len: usize) -> io::Result<Self> {
+        let ptr = sys::map(&file, len)?;
+        Ok(Mapping { ptr, len, _file: file })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Copy `bytes` to the start of the mapping.
+    ///
+    /// The caller must not hold a slab over that region.
+    pub(crate) fn write_at_start(&self, bytes: &[u8]) {
+        assert!(bytes.len() <= self.len);
+        // Safety: in bounds, and nothing else references this region
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr, bytes.len()) }
+    }
+
+    /// Flush modified pages to the file and wait for the write to finish.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        sys::sync(self.ptr, self.len)
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        sys::unmap(self.ptr, self.len);
+    }
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "macos"), target_pointer_width = "64", target_endian = "little"))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::raw::{c_int, c_void};
+
+    const PROT_READ: c_int = 1;
+    const PROT_WRITE: c_int = 2;
+    const MAP_SHARED: c_int = 1;
+    #[cfg(target_os = "linux")]
+    const MS_SYNC: c_int = 4;
+    #[cfg(target_os = "macos")]
+    const MS_SYNC: c_int = 0x10;
+
+    unsafe extern "C" {
+        fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+        fn msync(addr: *mut c_void, len: usize, flags: c_int) -> c_int;
+    }
+
+    pub(super) fn map(file: &File, len: usize) -> io::Result<*mut u8> {
+        // Safety: a fresh mapping chosen by the kernel aliases no Rust memory
+        let ptr = unsafe {
+            mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ptr.cast())
+    }
+
+    pub(super) fn sync(ptr: *mut u8, len: usize) -> io::Result<()> {
+        if unsafe { msync(ptr.cast(), len, MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub(super) fn unmap(ptr: *mut u8, len: usize) {
+        unsafe {
+            munmap(ptr.cast(), len);
+        }
+    }
+}
+
+#[cfg(not(all(any(target_os = "linux", target_os = "macos"), target_pointer_width = "64", target_endian = "little")))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+
+    pub(super) fn map(_file: &File, _len: usize) -> io::Result<*mut u8> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "memory-mapped state files are not supported on this platform"))
+    }
+
+    pub(super) fn sync(_ptr: *mut u8, _len: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn unmap(_ptr: *mut u8, _len: usize) {}
+}
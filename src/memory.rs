@@ -0,0 +1,238 @@
+//! Projected memory use of a run, so over-large invocations can be refused before
+//! the buffer is allocated.
+//!
+//! Each module holding a large buffer adds its share to a [`MemoryEstimate`] from a
+//! [`MemoryPlan`]; a module adding a buffer should add it to the estimate too.
+
+use crate::{Heatmap, image_size_for_bpp};
+use std::fmt::{self, Display};
+
+/// What a run will do that needs memory beyond a bare map.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryPlan {
+    pub bits_per_pixel: u8,
+    /// See [`Heatmap::set_low_memory`].
+    pub low_memory: bool,
+    /// Renders with a title, legend or other framing, which are never streamed.
+    pub decorated: bool,
+    /// Side length the map is downsampled to before rendering, see `--output-size`.
+    pub output_size: Option<u32>,
+    /// Side lengths of thumbnails, which are rendered one at a time.
+    pub thumbnails: Vec<u32>,
+    /// Whether the colour domain comes from percentiles of the cells.
+    pub percentiles: bool,
+    /// See [`Heatmap::process_input_parallel`].
+    pub threads: usize,
+}
+
+impl MemoryPlan {
+    /// A map at `bits_per_pixel` rendered once, undecorated, from serial input.
+    pub fn new(bits_per_pixel: u8) -> Self {
+        MemoryPlan {
+            bits_per_pixel,
+            low_memory: false,
+            decorated: false,
+            output_size: None,
+            thumbnails: Vec::new(),
+            percentiles: false,
+            threads: 1,
+        }
+    }
+
+    /// Side length of the map.
+    pub fn image_size(&self) -> u32 {
+        image_size_for_bpp(self.bits_per_pixel)
+    }
+
+    pub fn estimate(&self) -> MemoryEstimate {
+        let mut estimate = MemoryEstimate::default();
+        crate::cells::add_memory(self, &mut estimate);
+        crate::downsample::add_memory(self, &mut estimate);
+        crate::streamed::add_memory(self, &mut estimate);
+        crate::percentile::add_memory(self, &mut estimate);
+        crate::parallel::add_memory(self, &mut estimate);
+        estimate
+    }
+}
+
+/// Bytes a run is projected to hold at its peak, by the buffer holding them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryEstimate {
+    components: Vec<(&'static str, u64)>,
+}
+
+impl MemoryEstimate {
+    /// Count `bytes` held by `component`, adding to any bytes it already holds.
+    pub fn add(&mut self, component: &'static str, bytes: u64) {
+        match self.components.iter_mut().find(|(name, _)| *name == component) {
+            Some((_, held)) => *held += bytes,
+            None => self.components.push((component, bytes)),
+        }
+    }
+
+    /// The components in the order they were added, and their bytes.
+    pub fn components(&self) -> &[(&'static str, u64)] {
+        &self.components
+    }
+
+    /// Bytes held by `component`, 0 if it holds none.
+    pub fn bytes(&self, component: &str) -> u64 {
+        self.components.iter().find(|(name, _)| *name == component).map_or(0, |(_, bytes)| *bytes)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.components.iter().map(|(_, bytes)| bytes).sum()
+    }
+}
+
+impl Display for MemoryEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_bytes(self.total()))?;
+        let parts: Vec<String> = self
+            .components
+            .iter()
+            .filter(|(_, bytes)| *bytes > 0)
+            .map(|(name, bytes)| format!("{} {}", name, format_bytes(*bytes)))
+            .collect();
+        if !parts.is_empty() {
+            write!(f, " ({})", parts.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Heatmap {
+    /// Projected peak memory of processing into this map and rendering it once,
+    /// undecorated. See [`MemoryPlan`] for runs doing more.
+    pub fn estimated_memory(&self) -> MemoryEstimate {
+        MemoryPlan {
+            low_memory: self.low_memory,
+            ..MemoryPlan::new(self.bits_per_pixel)
+        }
+        .estimate()
+    }
+}
+
+const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// Parse a byte count with an optional binary suffix: `2G`, `512MiB`, `1.5g`, `4096`.
+pub fn parse_memory_size(s: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid memory size: {}. Use bytes or a K, M, G or T suffix, e.g. 2G", s);
+    let trimmed = s.trim();
+    let digits = trimmed.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(digits);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let suffix = suffix.trim().to_ascii_uppercase();
+    let unit = suffix.strip_suffix("IB").or_else(|| suffix.strip_suffix('B')).unwrap_or(&suffix);
+    let power = match unit {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return Err(invalid()),
+    };
+    let bytes = number * 1024f64.powi(power);
+    if !bytes.is_finite() || bytes < 1.0 || bytes > u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
+
+/// `bytes` in the largest binary unit keeping the number at least 1, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    let unit = ((63u32.saturating_sub(bytes.leading_zeros())) / 10).min(UNITS.len() as u32 - 1);
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", bytes as f64 / (1u64 << (10 * unit)) as f64, UNITS[unit as usize]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    const MIB: u64 = 1 << 20;
+
+    #[test]
+    fn test_standard_configurations() {
+        // -z 16: 256x256, rendered in memory
+        let estimate = MemoryPlan::new(16).estimate();
+        assert_eq!(
+            estimate.components(),
+            [("cells", 256 * 1024), ("touched mask", 8 * 1024), ("image", 256 * 1024)].as_slice()
+        );
+
+        // -z 8: 4096x4096
+        let estimate = MemoryPlan::new(8).estimate();
+        assert_eq!(estimate.bytes("cells"), 64 * MIB);
+        assert_eq!(estimate.bytes("touched mask"), 2 * MIB);
+        assert_eq!(estimate.bytes("image"), 64 * MIB);
+        assert_eq!(estimate.total(), 130 * MIB);
+
+        // -z 8 streamed with --low-memory, 256 and 1024 pixel thumbnails and percentiles
+        let plan = MemoryPlan {
+            low_memory: true,
+            thumbnails: vec![256, 1024],
+            percentiles: true,
+            ..MemoryPlan::new(8)
+        };
+        let estimate = plan.estimate();
+        assert_eq!(estimate.bytes("image"), 16 * 1024);
+        assert_eq!(estimate.bytes("thumbnail"), 4 * MIB + 128 * 1024 + 4 * 1024);
+        assert_eq!(estimate.bytes("sorted values"), 64 * MIB);
+
+        // -z 0 streams the 65536x65536 image regardless
+        let estimate = MemoryPlan::new(0).estimate();
+        assert_eq!(estimate.bytes("cells"), 16 * 1024 * MIB);
+        assert_eq!(estimate.bytes("touched mask"), 512 * MIB);
+        assert_eq!(estimate.bytes("image"), 256 * 1024);
+        // Unless framed
+        let decorated = MemoryPlan { decorated: true, ..MemoryPlan::new(0) };
+        assert_eq!(decorated.estimate().bytes("image"), 16 * 1024 * MIB);
+    }
+
+    #[test]
+    fn test_downsampled_output_and_threads() {
+        let plan = MemoryPlan {
+            output_size: Some(1024),
+            threads: 8,
+            ..MemoryPlan::new(8)
+        };
+        let estimate = plan.estimate();
+        assert_eq!(estimate.bytes("downsampled map"), 4 * MIB + 128 * 1024);
+        // The image is of the downsampled map
+        assert_eq!(estimate.bytes("image"), 4 * MIB);
+        assert!(estimate.bytes("input batches") > 0);
+        assert_eq!(MemoryPlan::new(8).estimate().bytes("input batches"), 0);
+    }
+
+    #[test]
+    fn test_heatmap_estimate_matches_allocation() {
+        let heatmap = Heatmap::new(DomainType::Linear, None, None, false, 18, &colorous::MAGMA, ValueMode::Raw, None);
+        let estimate = heatmap.estimated_memory();
+        assert_eq!(estimate.bytes("cells") + estimate.bytes("touched mask"), heatmap.buffer_bytes());
+        assert_eq!(estimate.to_string(), "130.0 KiB (cells 64.0 KiB, touched mask 2.0 KiB, image 64.0 KiB)");
+    }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_memory_size("512MiB"), Ok(512 << 20));
+        assert_eq!(parse_memory_size("1.5g"), Ok(3 << 29));
+        assert_eq!(parse_memory_size("4096"), Ok(4096));
+        assert_eq!(parse_memory_size("64 KB"), Ok(64 << 10));
+        assert!(parse_memory_size("2X").is_err());
+        assert!(parse_memory_size("G").is_err());
+        assert!(parse_memory_size("0").is_err());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(2 << 30), "2.0 GiB");
+    }
+}
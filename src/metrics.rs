@@ -0,0 +1,218 @@
+//! Operational metrics for long-running processes, written in the Prometheus text
+//! exposition format.
+
+use crate::{Heatmap, LiveStats};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Upper bounds of the render duration histogram buckets, in seconds.
+pub const RENDER_DURATION_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/// Counters and gauges shared between the threads processing input, rendering and
+/// serving `/metrics`. Updates are relaxed atomic operations, made once per chunk of
+/// input or render rather than per line.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    lines_processed: AtomicU64,
+    rejected_lines: AtomicU64,
+    renders: AtomicU64,
+    /// Renders no longer than each of [`RENDER_DURATION_BUCKETS`] (not cumulative).
+    render_buckets: [AtomicU64; RENDER_DURATION_BUCKETS.len()],
+    render_micros: AtomicU64,
+    buffer_bytes: AtomicU64,
+    last_render_millis: AtomicU64,
+    /// The queues of live input, see [`Metrics::record_live_queues`]; 0 before any.
+    live_queue_capacity: AtomicU64,
+    /// Per queue, read then parsed.
+    live_queue_high_water: [AtomicU64; 2],
+    live_queue_full: [AtomicU64; 2],
+    live_queue_blocked_micros: [AtomicU64; 2],
+    live_dropped_lines: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count input lines processed and rejected.
+    pub fn record_lines(&self, processed: u64, rejected: u64) {
+        self.lines_processed.fetch_add(processed, Ordering::Relaxed);
+        self.rejected_lines.fetch_add(rejected, Ordering::Relaxed);
+    }
+
+    /// Count a completed render that took `duration`.
+    pub fn record_render(&self, duration: Duration) {
+        self.renders.fetch_add(1, Ordering::Relaxed);
+        self.render_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = RENDER_DURATION_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.render_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.last_render_millis.store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Set the bytes held by the cell buffer and touched mask.
+    pub fn set_buffer_bytes(&self, bytes: u64) {
+        self.buffer_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Set the queue counts of live input, which are totals since it started.
+    pub fn record_live_queues(&self, stats: &LiveStats) {
+        self.live_queue_capacity.store(stats.read.capacity as u64, Ordering::Relaxed);
+        for (index, queue) in [stats.read, stats.parsed].iter().enumerate() {
+            self.live_queue_high_water[index].store(queue.high_water as u64, Ordering::Relaxed);
+            self.live_queue_full[index].store(queue.full, Ordering::Relaxed);
+            self.live_queue_blocked_micros[index].store(queue.blocked.as_micros() as u64, Ordering::Relaxed);
+        }
+        self.live_dropped_lines.store(stats.dropped_lines, Ordering::Relaxed);
+    }
+
+    pub fn lines_processed(&self) -> u64 {
+        self.lines_processed.load(Ordering::Relaxed)
+    }
+
+    pub fn renders(&self) -> u64 {
+        self.renders.load(Ordering::Relaxed)
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+        };
+        metric(
+            "ip_heatmap_lines_processed_total",
+            "counter",
+            "Input lines processed, including rejected ones.",
+            load(&self.lines_processed).to_string(),
+        );
+        metric(
+            "ip_heatmap_rejected_lines_total",
+            "counter",
+            "Input lines that could not be painted.",
+            load(&self.rejected_lines).to_string(),
+        );
+        metric("ip_heatmap_renders_total", "counter", "Maps colourised.", load(&self.renders).to_string());
+        metric(
+            "ip_heatmap_buffer_bytes",
+            "gauge",
+            "Bytes held by the cell buffer and touched mask.",
+            load(&self.buffer_bytes).to_string(),
+        );
+        metric(
+            "ip_heatmap_last_render_timestamp_seconds",
+            "gauge",
+            "Unix time the last render completed, 0 before the first.",
+            format!("{:.3}", load(&self.last_render_millis) as f64 / 1000.0),
+        );
+
+        let name = "ip_heatmap_render_duration_seconds";
+        let _ = writeln!(text, "# HELP {} Time spent colourising maps.\n# TYPE {} histogram", name, name);
+        let mut cumulative = 0;
+        for (bound, count) in RENDER_DURATION_BUCKETS.iter().zip(&self.render_buckets) {
+            cumulative += load(count);
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let renders = load(&self.renders);
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, renders);
+        let _ = writeln!(text, "{}_sum {}", name, load(&self.render_micros) as f64 / 1e6);
+        let _ = writeln!(text, "{}_count {}", name, renders);
+
+        if load(&self.live_queue_capacity) > 0 {
+            self.write_live_queues(&mut text);
+        }
+        text
+    }
+
+    fn write_live_queues(&self, text: &mut String) {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let _ = writeln!(
+            text,
+            "# HELP ip_heatmap_live_queue_capacity Items each live input queue holds.\n\
+             # TYPE ip_heatmap_live_queue_capacity gauge\nip_heatmap_live_queue_capacity {}",
+            load(&self.live_queue_capacity)
+        );
+        let per_queue = [
+            ("ip_heatmap_live_queue_high_water", "gauge", "The most items waiting at once.", &self.live_queue_high_water),
+            ("ip_heatmap_live_queue_full_total", "counter", "Items that found the queue full.", &self.live_queue_full),
+        ];
+        for (name, kind, help, values) in per_queue {
+            let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (queue, value) in ["read", "parsed"].iter().zip(values) {
+                let _ = writeln!(text, "{}{{queue=\"{}\"}} {}", name, queue, load(value));
+            }
+        }
+        let name = "ip_heatmap_live_queue_blocked_seconds_total";
+        let _ = writeln!(text, "# HELP {} Time spent waiting for room in the queue.\n# TYPE {} counter", name, name);
+        for (queue, value) in ["read", "parsed"].iter().zip(&self.live_queue_blocked_micros) {
+            let _ = writeln!(text, "{}{{queue=\"{}\"}} {}", name, queue, load(value) as f64 / 1e6);
+        }
+        let name = "ip_heatmap_live_dropped_lines_total";
+        let _ = writeln!(
+            text,
+            "# HELP {} Lines dropped while painting fell behind.\n# TYPE {} counter\n{} {}",
+            name,
+            name,
+            name,
+            load(&self.live_dropped_lines)
+        );
+    }
+}
+
+impl Heatmap {
+    /// Bytes held by the cell buffer and touched mask, whether on the heap or mapped.
+    pub fn buffer_bytes(&self) -> u64 {
+        (self.buffer.cells().len() * 4 + self.touched.len() * 8) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.record_lines(10, 2);
+        metrics.record_lines(5, 0);
+        metrics.record_render(Duration::from_millis(30));
+        metrics.record_render(Duration::from_secs(20));
+        metrics.set_buffer_bytes(1024);
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE ip_heatmap_lines_processed_total counter\nip_heatmap_lines_processed_total 15\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_rejected_lines_total 2\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_buffer_bytes 1024\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_render_duration_seconds_bucket{le=\"0.025\"} 0\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_render_duration_seconds_bucket{le=\"0.05\"} 1\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_render_duration_seconds_bucket{le=\"10\"} 1\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_render_duration_seconds_bucket{le=\"+Inf\"} 2\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_render_duration_seconds_sum 20.03\n"), "{}", text);
+        assert!(!text.contains("ip_heatmap_last_render_timestamp_seconds 0.000"), "{}", text);
+        assert!(!text.contains("ip_heatmap_live_queue"), "{}", text);
+    }
+
+    #[test]
+    fn test_live_queue_metrics() {
+        let metrics = Metrics::new();
+        let mut stats = LiveStats::default();
+        stats.read.capacity = 4;
+        stats.parsed.capacity = 4;
+        stats.parsed.high_water = 4;
+        stats.parsed.full = 7;
+        stats.read.blocked = Duration::from_millis(1500);
+        stats.dropped_lines = 12;
+        metrics.record_live_queues(&stats);
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE ip_heatmap_live_queue_capacity gauge\nip_heatmap_live_queue_capacity 4\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_live_queue_high_water{queue=\"parsed\"} 4\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_live_queue_full_total{queue=\"read\"} 0\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_live_queue_full_total{queue=\"parsed\"} 7\n"), "{}", text);
+        assert!(text.contains("ip_heatmap_live_queue_blocked_seconds_total{queue=\"read\"} 1.5\n"), "{}", text);
+        assert!(text.contains("# TYPE ip_heatmap_live_dropped_lines_total counter\nip_heatmap_live_dropped_lines_total 12\n"), "{}", text);
+    }
+}
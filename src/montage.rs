@@ -0,0 +1,196 @@
+use crate::Heatmap;
+use crate::layout::{Layout, Panel};
+use crate::legend::Legend;
+use crate::palette::Palette;
+use crate::render::RenderOptions;
+use crate::scale::{DomainType, ScaleDomain};
+use anyhow::{Result, anyhow, bail};
+use image::{Rgba, RgbaImage};
+
+/// Render heatmaps side by side with a shared colour domain and a single legend.
+///
+/// The colour domain is computed over all inputs (unless fixed in `options`), so equal
+/// colours mean equal values across panels. With a `diff` curve, a further panel shows
+/// the difference between the last and the first heatmap on a diverging palette.
+pub fn render_montage(
+    heatmaps: &[&Heatmap],
+    titles: &[String],
+    options: &RenderOptions,
+    diff: Option<DomainType>,
+) -> Result<RgbaImage> {
+    let first = heatmaps.first().ok_or_else(|| anyhow!("Montage needs at least one input"))?;
+    for heatmap in heatmaps {
+        if heatmap.bits_per_pixel != first.bits_per_pixel {
+            bail!(
+                "Cannot combine heatmaps with different bits_per_pixel ({} and {})",
+                first.bits_per_pixel,
+                heatmap.bits_per_pixel
+            );
+        }
+    }
+    if diff.is_some() && heatmaps.len() < 2 {
+        bail!("A difference panel needs at least two inputs");
+    }
+
+    let shared = shared_options(heatmaps, options);
+    let mut panels = Vec::with_capacity(heatmaps.len() + 1);
+    for (i, heatmap) in heatmaps.iter().enumerate() {
+        panels.push(Panel {
+            image: heatmap.render(&shared).map_err(|err| anyhow!(err))?,
+            title: titles.get(i).cloned(),
+        });
+    }
+    if let Some(curve) = diff {
+        let last = heatmaps[heatmaps.len() - 1];
+        panels.push(Panel {
+            image: render_difference(first, last, curve),
+            title: Some(options.strings.get("montage.difference").to_string()),
+        });
+    }
+
+    let legend = Legend {
+        palette: shared.palette.clone(),
+        curve: shared.curve,
+        min_value: shared.min_value.unwrap_or(0.0),
+        max_value: shared.max_value.unwrap_or(0.0),
+        log_params: shared.log_params,
+        label: None,
+        bands: first.legend_bands(&shared).map_err(|err| anyhow!(err))?,
+        strings: shared.strings.clone(),
+    };
+    let layout = Layout::for_panel_size(first.image_size());
+    Ok(layout.compose(&panels, Some(&legend)))
+}
+
+/// Fix the colour domain to the union of all inputs' value ranges.
+fn shared_options(heatmaps: &[&Heatmap], options: &RenderOptions) -> RenderOptions {
+    let ranges: Vec<(i32, i32)> = heatmaps.iter().map(|h| h.value_range()).collect();
+    let min = ranges.iter().map(|r| r.0).min().unwrap_or(0) as f64;
+    let max = ranges.iter().map(|r| r.1).max().unwrap_or(0) as f64;
+    RenderOptions {
+        min_value: Some(options.min_value.unwrap_or(min)),
+        max_value: Some(options.max_value.unwrap_or(max)),
+        ..options.clone()
+    }
+}
+
+/// Render `after - before` with red for increases and blue for decreases, symmetric
+/// around zero on `curve`.
+fn render_difference(before: &Heatmap, after: &Heatmap, curve: DomainType) -> RgbaImage {
+    let palette = Palette::Builtin {
+        name: "red-blue",
+        gradient: &colorous::RED_BLUE,
+    };
+    let size = before.image_size();
+    let delta = |x: usize, y: usize| after.buffer[y][x] as f64 - before.buffer[y][x] as f64;
+
+    let mut largest = 0.0f64;
+    for y in 0..size as usize {
+        for x in 0..size as usize {
+            largest = largest.max(delta(x, y).abs());
+        }
+    }
+
+    let mut image = RgbaImage::from_pixel(size, size, Rgba([0, 0, 0, 0]));
+    // Curves other than symlog are not symmetric, so they scale the magnitude instead
+    let (Ok(symmetric), Ok(magnitude)) = (
+        ScaleDomain::new(curve, -largest, largest),
+        ScaleDomain::new(curve, 0.0, largest),
+    ) else {
+        return image;
+    };
+    for y in 0..size {
+        for x in 0..size {
+            let d = delta(x as usize, y as usize);
+            let t = match curve {
+                _ if d == 0.0 => None,
+                DomainType::Symlog { linthresh } => Some(symmetric.scale_symlog(d, linthresh)),
+                _ => magnitude.scale(d.abs()).map(|m| 0.5 + 0.5 * m * d.signum()),
+            };
+            if let Some(t) = t {
+                let [r, g, b] = palette.eval(1.0 - t);
+                image.put_pixel(x, y, Rgba([r, g, b, 255]));
+            }
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ValueMode;
+
+    fn heatmap(bits_per_pixel: u8, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_shared_domain_covers_all_inputs() {
+        let a = heatmap(24, "10.0.0.1 5\n");
+        let b = heatmap(24, "10.0.0.1 50\n");
+        let shared = shared_options(&[&a, &b], &RenderOptions::default());
+        assert_eq!(shared.min_value, Some(0.0));
+        assert_eq!(shared.max_value, Some(50.0));
+
+        // Explicit bounds win
+        let fixed = RenderOptions {
+            max_value: Some(10.0),
+            ..RenderOptions::default()
+        };
+        assert_eq!(shared_options(&[&a, &b], &fixed).max_value, Some(10.0));
+    }
+
+    #[test]
+    fn test_montage_panels_use_same_colours_for_same_values() {
+        let a = heatmap(24, "10.0.0.1 5\n11.0.0.1 50\n");
+        let b = heatmap(24, "10.0.0.1 5\n");
+        let titles = vec!["a".to_string(), "b".to_string()];
+        let image = render_montage(&[&a, &b], &titles, &RenderOptions::default(), Some(DomainType::Linear)).unwrap();
+
+        let layout = Layout::for_panel_size(16);
+        let panel_top = layout.gap + crate::text::text_height(layout.text_scale) + layout.gap / 2;
+        let (x, y) = a.ip_to_xy(u32::from(std::net::Ipv4Addr::new(10, 0, 0, 1))).unwrap();
+        let in_a = *image.get_pixel(layout.gap + x, panel_top + y);
+        let in_b = *image.get_pixel(layout.gap * 2 + 16 + x, panel_top + y);
+        assert_eq!(in_a, in_b);
+        assert_eq!(image.width(), layout.gap + 3 * (16 + layout.gap));
+    }
+
+    #[test]
+    fn test_montage_rejects_mismatched_bits_per_pixel() {
+        let a = heatmap(24, "10.0.0.1 5\n");
+        let b = heatmap(22, "10.0.0.1 5\n");
+        assert!(render_montage(&[&a, &b], &[], &RenderOptions::default(), None).is_err());
+    }
+
+    #[test]
+    fn test_difference_colours_increase_and_decrease() {
+        let before = heatmap(24, "10.0.0.1 10\n11.0.0.1 10\n");
+        let after = heatmap(24, "10.0.0.1 20\n11.0.0.1 5\n12.0.0.1 0\n");
+        for curve in [DomainType::Linear, DomainType::Symlog { linthresh: 1.0 }] {
+            let diff = render_difference(&before, &after, curve);
+            let pixel = |a, b, c, d| {
+                let (x, y) = before.ip_to_xy(u32::from(std::net::Ipv4Addr::new(a, b, c, d))).unwrap();
+                *diff.get_pixel(x, y)
+            };
+            let increase = pixel(10, 0, 0, 1);
+            let decrease = pixel(11, 0, 0, 1);
+            // Increases lean red, decreases lean blue, unchanged cells are transparent
+            assert!(increase[0] > increase[2], "{}", curve);
+            assert!(decrease[2] > decrease[0], "{}", curve);
+            assert_eq!(pixel(12, 0, 0, 1)[3], 0);
+        }
+    }
+}
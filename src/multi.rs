@@ -0,0 +1,106 @@
+//! Several resolutions painted from a single pass over the input.
+
+use crate::Heatmap;
+use crate::input;
+use anyhow::{Result, bail};
+use std::io::BufRead;
+
+/// Heatmaps of different resolutions fed from one stream of parsed records, so the
+/// input is read and parsed once. Memory use is the sum of the heatmaps' buffers.
+pub struct MultiHeatmap {
+    heatmaps: Vec<Heatmap>,
+}
+
+impl MultiHeatmap {
+    /// Lines are parsed with the settings of the first heatmap, and only the first
+    /// logs rejected lines; every heatmap counts them.
+    pub fn new(heatmaps: Vec<Heatmap>) -> Result<Self> {
+        if heatmaps.is_empty() {
+            bail!("A multi-resolution heatmap needs at least one heatmap");
+        }
+        Ok(Self { heatmaps })
+    }
+
+    pub fn heatmaps(&self) -> &[Heatmap] {
+        &self.heatmaps
+    }
+
+    pub fn into_heatmaps(self) -> Vec<Heatmap> {
+        self.heatmaps
+    }
+
+    /// Set the weight of every heatmap, see [`Heatmap::set_weight`].
+    pub fn set_weight(&mut self, weight: f64) {
+        for heatmap in &mut self.heatmaps {
+            heatmap.set_weight(weight);
+        }
+    }
+
+    /// Paint every record of `reader` into each heatmap.
+    pub fn process_input_from_reader<R: BufRead>(&mut self, reader: R) -> Result<()> {
+        let first = &mut self.heatmaps[0];
+        let timer = std::mem::take(&mut first.timer);
+        let options = first.parse_options.clone();
+        let (host_bits_before, conflicts_before) = (first.cidr_host_bits, first.conflicts);
+        let factors: Vec<f64> = self.heatmaps.iter().map(|heatmap| heatmap.value_factor()).collect();
+        let heatmaps = &mut self.heatmaps;
+        let result = input::for_each_record(reader, 0, &options, &timer, |line_number, line, parsed| {
+            for (index, heatmap) in heatmaps.iter_mut().enumerate() {
+                heatmap.process_parsed(&timer, factors[index], index == 0, line_number, line, parsed.clone())?;
+            }
+            Ok(())
+        });
+        self.heatmaps[0].timer = timer;
+        self.heatmaps[0].warn_host_bits(host_bits_before);
+        self.heatmaps[0].warn_conflicts(conflicts_before);
+        self.heatmaps[0].log_suppressed_warnings();
+        result
+    }
+
+    pub fn process_input_from_string(&mut self, input: &str) -> Result<()> {
+        self.process_input_from_reader(std::io::Cursor::new(input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ErrorPolicy, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8) -> Heatmap {
+        Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Scaled,
+            None,
+        )
+    }
+
+    const INPUT: &str = "10.0.0.1 300\n10.1.0.0/16 7\n192.168.1.0/24 1000\nbad line\n8.8.8.8\n";
+
+    #[test]
+    fn test_each_resolution_matches_its_own_run() {
+        let mut multi = MultiHeatmap::new(vec![heatmap(8), heatmap(16), heatmap(24)]).unwrap();
+        multi.process_input_from_string(INPUT).unwrap();
+        for hm in multi.heatmaps() {
+            let mut single = heatmap(hm.bits_per_pixel());
+            single.process_input_from_string(INPUT).unwrap();
+            assert_eq!(hm.buffer, single.buffer, "bits_per_pixel {}", hm.bits_per_pixel());
+            assert_eq!(hm.touched_pixels(), single.touched_pixels());
+            assert_eq!((hm.lines_processed(), hm.rejects().total()), (5, 1));
+        }
+    }
+
+    #[test]
+    fn test_failing_policy_stops_every_resolution() {
+        let mut first = heatmap(16);
+        first.set_error_policy(ErrorPolicy::Fail);
+        let mut multi = MultiHeatmap::new(vec![first, heatmap(24)]).unwrap();
+        assert!(multi.process_input_from_string(INPUT).is_err());
+        assert!(MultiHeatmap::new(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::Heatmap;
+use crate::layout::{Layout, Panel};
+use crate::legend::{Legend, format_value};
+use crate::render::RenderOptions;
+use anyhow::{Result, anyhow, bail};
+use image::imageops::{self, FilterType};
+use ipnet::Ipv4Net;
+
+/// The `top` prefixes of length `prefix_len` with the largest total value, descending.
+///
+/// Prefixes without any value are never included, so fewer than `top` may be returned.
+pub fn hottest_prefixes(heatmap: &Heatmap, prefix_len: u8, top: usize) -> Result<Vec<(Ipv4Net, i64)>> {
+    let mut totals: Vec<(Ipv4Net, i64)> = heatmap
+        .prefix_totals(prefix_len)?
+        .into_iter()
+        .filter(|(_, total)| *total > 0)
+        .collect();
+    // Stable sort keeps address order for equal totals
+    totals.sort_by_key(|t| std::cmp::Reverse(t.1));
+    totals.truncate(top);
+    Ok(totals)
+}
+
+/// Render a grid of zoomed panels for the hottest prefixes of length `prefix_len`.
+///
+/// All panels share the colour domain of the full map and a single legend. Each panel
+/// is labelled with its prefix and total value.
+pub fn render_small_multiples(
+    heatmap: &Heatmap,
+    prefix_len: u8,
+    top: usize,
+    panel_size: u32,
+    options: &RenderOptions,
+) -> Result<image::RgbaImage> {
+    let hottest = hottest_prefixes(heatmap, prefix_len, top)?;
+    if hottest.is_empty() {
+        bail!("No /{} prefix contains any painted value", prefix_len);
+    }
+
+    // Panels are crops of one full rendering, so they share its colour domain
+    let (min_value, max_value) = heatmap.domain_bounds(options);
+    let full = heatmap.render(options).map_err(|err| anyhow!(err))?;
+
+    let panels: Vec<Panel> = hottest
+        .iter()
+        .map(|(net, total)| {
+            let (x, y, width, height) = heatmap.prefix_rect(net);
+            let crop = imageops::crop_imm(&full, x, y, width, height).to_image();
+            // Two-square prefixes keep their 2:1 aspect ratio within the panel size
+            let scale = panel_size as f64 / width.max(height) as f64;
+            let panel_width = ((width as f64 * scale).round() as u32).max(1);
+            let panel_height = ((height as f64 * scale).round() as u32).max(1);
+            Panel {
+                image: imageops::resize(&crop, panel_width, panel_height, FilterType::Nearest),
+                title: Some(format!("{} ({})", net, format_value(*total as f64))),
+            }
+        })
+        .collect();
+
+    let mut layout = Layout::for_panel_size(panel_size);
+    layout.columns = (panels.len() as f64).sqrt().ceil() as u32;
+    let legend = Legend {
+        palette: options.palette.clone(),
+        curve: options.curve,
+        min_value,
+        max_value,
+        log_params: options.log_params,
+        label: None,
+        bands: heatmap.legend_bands(options).map_err(|err| anyhow!(err))?,
+        strings: options.strings.clone(),
+    };
+    Ok(layout.compose(&panels, Some(&legend)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap(input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_hottest_prefixes_ordered_descending() {
+        let hm = heatmap("10.0.0.0 5\n20.0.0.0 50\n30.0.0.0 20\n30.1.0.0 20\n");
+        let hottest = hottest_prefixes(&hm, 8, 2).unwrap();
+        let nets: Vec<String> = hottest.iter().map(|(net, _)| net.to_string()).collect();
+        assert_eq!(nets, vec!["20.0.0.0/8", "30.0.0.0/8"]);
+        assert_eq!(hottest[1].1, 40);
+    }
+
+    #[test]
+    fn test_grid_shrinks_when_fewer_prefixes_are_hot() {
+        let hm = heatmap("10.0.0.0 5\n20.0.0.0 50\n");
+        assert_eq!(hottest_prefixes(&hm, 8, 16).unwrap().len(), 2);
+
+        let image = render_small_multiples(&hm, 8, 16, 32, &RenderOptions::default()).unwrap();
+        let layout = Layout::for_panel_size(32);
+        // Two panels fit in a 2x1 grid
+        assert_eq!(image.width(), layout.gap + 2 * (32 + layout.gap));
+    }
+
+    #[test]
+    fn test_empty_heatmap_is_an_error() {
+        let hm = heatmap("");
+        assert!(render_small_multiples(&hm, 8, 16, 32, &RenderOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Observing the records a heatmap paints, so they can feed other aggregation
+//! without parsing the input twice.
+
+use crate::Heatmap;
+use crate::input::{ParsedLine, Record};
+use crate::rejects::RejectReason;
+use anyhow::Result;
+use std::io::BufRead;
+
+/// Sees each input line [`Heatmap::process_reader_with`] paints or rejects, in input
+/// order. Closures taking a `&Record` observe records only; implementing the trait
+/// for `&mut T` keeps the observer usable after processing.
+///
+/// Observers are borrowed for the duration of the call and invoked on the calling
+/// thread, so they need not be `Send` or `Sync`.
+pub trait RecordObserver {
+    /// Called with each parsed record before it is painted. The value is the one
+    /// parsed, before [`Heatmap::set_weight`] and sampling factors are applied.
+    fn record(&mut self, line_number: usize, record: &Record);
+
+    /// Called with each rejected line before it is counted (or fails the run under
+    /// [`crate::ErrorPolicy::Fail`]). Does nothing by default.
+    fn rejected(&mut self, line_number: usize, line: &str, reason: RejectReason, message: &str) {
+        let _ = (line_number, line, reason, message);
+    }
+}
+
+impl<F: FnMut(&Record)> RecordObserver for F {
+    fn record(&mut self, _line_number: usize, record: &Record) {
+        self(record)
+    }
+}
+
+impl Heatmap {
+    /// [`Heatmap::process_input_from_reader`], passing each record and rejected line
+    /// to `observer` as it is processed.
+    pub fn process_reader_with<R: BufRead>(&mut self, reader: R, mut observer: impl RecordObserver) -> Result<()> {
+        let result = self.process_lines(reader, 0, Some(&mut observer));
+        self.log_suppressed_warnings();
+        result
+    }
+}
+
+/// Pass `parsed` to `observer` if it is a record or a reject.
+pub(crate) fn observe(observer: &mut dyn RecordObserver, line_number: usize, line: &str, parsed: &ParsedLine) {
+    match parsed {
+        ParsedLine::Record(record) => observer.record(line_number, record),
+        ParsedLine::Records(records) => records.iter().for_each(|record| observer.record(line_number, record)),
+        ParsedLine::Rejected(reason, message) => observer.rejected(line_number, line, *reason, message),
+        ParsedLine::Blank | ParsedLine::Unsampled | ParsedLine::Ignored | ParsedLine::OutsideWindow => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    #[derive(Default)]
+    struct Collected {
+        records: Vec<(usize, String, i32)>,
+        rejected: Vec<(usize, RejectReason)>,
+    }
+
+    impl RecordObserver for &mut Collected {
+        fn record(&mut self, line_number: usize, record: &Record) {
+            self.records.push((line_number, record.net.to_string(), record.value));
+        }
+
+        fn rejected(&mut self, line_number: usize, _line: &str, reason: RejectReason, _message: &str) {
+            self.rejected.push((line_number, reason));
+        }
+    }
+
+    fn heatmap() -> Heatmap {
+        Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None)
+    }
+
+    #[test]
+    fn test_observer_sees_painted_records_in_order() {
+        let input = "10.0.0.1 5\n\nbad 1\n10.1.0.0/16 3\n10.0.0.0/40 2\n8.8.8.8 -2\n";
+        let mut observed = heatmap();
+        let mut collected = Collected::default();
+        observed.process_reader_with(input.as_bytes(), &mut collected).unwrap();
+        assert_eq!(
+            collected.records,
+            vec![(1, "10.0.0.1/32".to_string(), 5), (4, "10.1.0.0/16".to_string(), 3), (6, "8.8.8.8/32".to_string(), -2)]
+        );
+        assert_eq!(collected.rejected, vec![(3, RejectReason::InvalidIp), (5, RejectReason::InvalidCidr)]);
+
+        // Observing does not change what is painted
+        let mut plain = heatmap();
+        plain.process_input_from_string(input).unwrap();
+        assert_eq!(observed.render(&observed.render_options()).unwrap(), plain.render(&plain.render_options()).unwrap());
+        assert_eq!(observed.rejects().total(), 2);
+    }
+
+    #[test]
+    fn test_closure_observer() {
+        let mut values = Vec::new();
+        let mut heatmap = heatmap();
+        heatmap.set_parse_options(crate::ParseOptions::default().ignore_values(vec!["-1".to_string()]));
+        heatmap.process_reader_with("10.0.0.1 5\n10.0.0.2 -1\n10.0.0.3 7\n".as_bytes(), |record: &Record| values.push(record.value)).unwrap();
+        assert_eq!(values, vec![5, 7]);
+    }
+}
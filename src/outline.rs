@@ -0,0 +1,154 @@
+use crate::Heatmap;
+use crate::palette::parse_hex_colour;
+use crate::text::fill_rect;
+use crate::theme::Theme;
+use image::{Rgba, RgbaImage};
+use ipnet::Ipv4Net;
+use std::str::FromStr;
+
+/// A border drawn around the pixels of a prefix, written `prefix[:rrggbb[:width]]`.
+/// Leaving out the colour, or leaving it empty as in `prefix::2`, draws in the
+/// theme's foreground.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outline {
+    pub net: Ipv4Net,
+    pub colour: Option<[u8; 3]>,
+    /// Stroke width in pixels, drawn inside the prefix's region.
+    pub width: u32,
+}
+
+impl FromStr for Outline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let net = parts.next().unwrap_or_default();
+        let net = net
+            .parse::<Ipv4Net>()
+            .map_err(|_| format!("Invalid outline prefix: {}", net))?
+            .trunc();
+        let colour = match parts.next() {
+            None | Some("") => None,
+            Some(colour) => Some(parse_hex_colour(colour)?),
+        };
+        let width = match parts.next() {
+            Some(width) => match width.parse::<u32>() {
+                Ok(width) if width > 0 => width,
+                _ => return Err(format!("Outline width must be a positive number of pixels: {}", width)),
+            },
+            None => 1,
+        };
+        if parts.next().is_some() {
+            return Err(format!("Invalid outline: {}. Use prefix[:rrggbb[:width]]", s));
+        }
+        Ok(Self { net, colour, width })
+    }
+}
+
+impl Heatmap {
+    /// Draw `outline` onto `image`, a rendering of this heatmap.
+    ///
+    /// The stroke runs along the inside of the region, so outlines of adjacent
+    /// prefixes do not overlap. The two squares of an odd-length prefix always share
+    /// a whole side, so their union is a rectangle and no seam is drawn between them.
+    /// Outlines without a colour are drawn in the foreground of `theme`.
+    pub fn draw_outline(&self, image: &mut RgbaImage, outline: &Outline, theme: &Theme) {
+        let (x, y, width, height) = self.prefix_rect(&outline.net);
+        let stroke = outline.width.min(width.div_ceil(2)).min(height.div_ceil(2));
+        let colour = match outline.colour {
+            Some([r, g, b]) => Rgba([r, g, b, 255]),
+            None => theme.foreground,
+        };
+        let (x, y) = (x as i64, y as i64);
+        fill_rect(image, x, y, width, stroke, colour);
+        fill_rect(image, x, y + (height - stroke) as i64, width, stroke, colour);
+        fill_rect(image, x, y, stroke, height, colour);
+        fill_rect(image, x + (width - stroke) as i64, y, stroke, height, colour);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap() -> Heatmap {
+        Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        )
+    }
+
+    fn inked(image: &RgbaImage) -> Vec<(u32, u32)> {
+        image
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p.0[3] == 255)
+            .map(|(x, y, _)| (x, y))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_outline() {
+        let outline: Outline = "192.0.2.0/24:ff0000:2".parse().unwrap();
+        assert_eq!((outline.colour, outline.width), (Some([255, 0, 0]), 2));
+        assert_eq!("10.1.2.3/8:00ff00".parse::<Outline>().unwrap().net, "10.0.0.0/8".parse().unwrap());
+        let themed: Outline = "10.0.0.0/8".parse().unwrap();
+        assert_eq!((themed.colour, themed.width), (None, 1));
+        assert_eq!("10.0.0.0/8::3".parse::<Outline>().unwrap(), Outline { width: 3, ..themed });
+        for invalid in ["10.0.0.0/8:red", "10.0.0.0/8:ff0000:0", "host:ff0000", "10.0.0.0/8:ff0000:1:2"] {
+            assert!(invalid.parse::<Outline>().is_err(), "{} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn test_odd_prefix_outline_has_no_seam() {
+        let hm = heatmap();
+        // A /7 is two 16x16 squares at 16 bits per pixel
+        let outline: Outline = "10.0.0.0/7:ff0000:1".parse().unwrap();
+        let (x, y, width, height) = hm.prefix_rect(&outline.net);
+        assert_eq!(width * height, 512);
+        let mut image = RgbaImage::new(256, 256);
+        hm.draw_outline(&mut image, &outline, &Theme::DARK);
+        // Only the perimeter of the 32x16 union is drawn
+        let pixels = inked(&image);
+        assert_eq!(pixels.len() as u32, 2 * (width + height) - 4);
+        let on_edge = |(px, py): (u32, u32)| px == x || px == x + width - 1 || py == y || py == y + height - 1;
+        assert!(pixels.into_iter().all(on_edge));
+    }
+
+    #[test]
+    fn test_stroke_stays_inside_the_region() {
+        let hm = heatmap();
+        let mut image = RgbaImage::new(256, 256);
+        let left: Outline = "10.0.0.0/8:ff0000:3".parse().unwrap();
+        let right: Outline = "11.0.0.0/8:0000ff:3".parse().unwrap();
+        hm.draw_outline(&mut image, &left, &Theme::DARK);
+        hm.draw_outline(&mut image, &right, &Theme::DARK);
+        for (outline, colour) in [(&left, [255, 0, 0]), (&right, [0, 0, 255])] {
+            let (x, y, width, height) = hm.prefix_rect(&outline.net);
+            let inside = |px: u32, py: u32| (x..x + width).contains(&px) && (y..y + height).contains(&py);
+            for (px, py, pixel) in image.enumerate_pixels() {
+                if pixel.0[..3] == colour {
+                    assert!(inside(px, py));
+                }
+            }
+        }
+        // Without a colour the stroke is the theme's foreground
+        let mut image = RgbaImage::new(256, 256);
+        let themed: Outline = "10.0.0.0/8".parse().unwrap();
+        hm.draw_outline(&mut image, &themed, &Theme::LIGHT);
+        let (x, y, _, _) = hm.prefix_rect(&themed.net);
+        assert_eq!(*image.get_pixel(x, y), Theme::LIGHT.foreground);
+
+        // A stroke wider than the region just fills it
+        let mut image = RgbaImage::new(256, 256);
+        hm.draw_outline(&mut image, &"10.0.0.1/32:ffffff:5".parse().unwrap(), &Theme::DARK);
+        assert_eq!(inked(&image).len(), 1);
+    }
+}
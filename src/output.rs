@@ -0,0 +1,343 @@
+use anyhow::{Context, Result, anyhow};
+use image::RgbaImage;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How hard the PNG encoder compresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PngCompression {
+    /// The `image` crate's default and the fastest.
+    #[default]
+    Fast,
+    Default,
+    /// The smallest files and the slowest.
+    Best,
+}
+
+impl PngCompression {
+    pub const ALL: [PngCompression; 3] = [PngCompression::Fast, PngCompression::Default, PngCompression::Best];
+}
+
+impl std::str::FromStr for PngCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            _ => Err(format!("Invalid PNG compression: {}. Use 'fast', 'default' or 'best'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for PngCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PngCompression::Fast => write!(f, "fast"),
+            PngCompression::Default => write!(f, "default"),
+            PngCompression::Best => write!(f, "best"),
+        }
+    }
+}
+
+/// The filter PNG rows are predicted with before compression.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    /// Pick the best filter for each row.
+    #[default]
+    Adaptive,
+}
+
+impl PngFilter {
+    pub const ALL: [PngFilter; 6] = [
+        PngFilter::None,
+        PngFilter::Sub,
+        PngFilter::Up,
+        PngFilter::Average,
+        PngFilter::Paeth,
+        PngFilter::Adaptive,
+    ];
+}
+
+impl std::str::FromStr for PngFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(PngFilter::None),
+            "sub" => Ok(PngFilter::Sub),
+            "up" => Ok(PngFilter::Up),
+            "average" | "avg" => Ok(PngFilter::Average),
+            "paeth" => Ok(PngFilter::Paeth),
+            "adaptive" => Ok(PngFilter::Adaptive),
+            _ => Err(format!(
+                "Invalid PNG filter: {}. Use 'none', 'sub', 'up', 'average', 'paeth' or 'adaptive'",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for PngFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PngFilter::None => write!(f, "none"),
+            PngFilter::Sub => write!(f, "sub"),
+            PngFilter::Up => write!(f, "up"),
+            PngFilter::Average => write!(f, "average"),
+            PngFilter::Paeth => write!(f, "paeth"),
+            PngFilter::Adaptive => write!(f, "adaptive"),
+        }
+    }
+}
+
+/// PNG encoder settings. The default matches the `image` crate's (fast compression,
+/// adaptive filtering), so pixel data is encoded as it always was.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PngEncoding {
+    pub compression: PngCompression,
+    pub filter: PngFilter,
+}
+
+/// Encode `image` as PNG, adding one tEXt chunk per metadata entry.
+pub fn write_png<W: Write>(
+    writer: W,
+    image: &RgbaImage,
+    metadata: &[(String, String)],
+    encoding: &PngEncoding,
+) -> Result<()> {
+    let encoder = png_encoder(writer, image.width(), image.height(), metadata, encoding)?;
+    let mut writer = encoder.write_header().context("Failed to write PNG header")?;
+    writer
+        .write_image_data(image.as_raw())
+        .context("Failed to write PNG image data")?;
+    writer.finish().context("Failed to finish PNG")?;
+    Ok(())
+}
+
+/// Encode a `width` x `height` RGBA PNG whose rows `fill_row` writes one at a time
+/// into a transparent buffer, so the whole image is never held in memory.
+///
+/// The filters are those of [`write_png`], but the compressed stream differs, so
+/// the files decode to the same pixels without being byte-identical.
+pub(crate) fn write_png_rows<W: Write>(
+    writer: W,
+    width: u32,
+    height: u32,
+    metadata: &[(String, String)],
+    encoding: &PngEncoding,
+    mut fill_row: impl FnMut(u32, &mut [u8]),
+) -> Result<()> {
+    let encoder = png_encoder(writer, width, height, metadata, encoding)?;
+    let mut writer = encoder.write_header().context("Failed to write PNG header")?;
+    let mut stream = writer.stream_writer().context("Failed to start PNG image data")?;
+    let mut row = vec![0u8; width as usize * 4];
+    for y in 0..height {
+        row.fill(0);
+        fill_row(y, &mut row);
+        stream.write_all(&row).context("Failed to write PNG image data")?;
+    }
+    stream.finish().context("Failed to finish PNG")?;
+    Ok(())
+}
+
+fn png_encoder<W: Write>(
+    writer: W,
+    width: u32,
+    height: u32,
+    metadata: &[(String, String)],
+    encoding: &PngEncoding,
+) -> Result<png::Encoder<'static, W>> {
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(match encoding.compression {
+        PngCompression::Fast => png::Compression::Fast,
+        PngCompression::Default => png::Compression::Default,
+        PngCompression::Best => png::Compression::Best,
+    });
+    let (filter, adaptive) = match encoding.filter {
+        PngFilter::None => (png::FilterType::NoFilter, png::AdaptiveFilterType::NonAdaptive),
+        PngFilter::Sub => (png::FilterType::Sub, png::AdaptiveFilterType::NonAdaptive),
+        PngFilter::Up => (png::FilterType::Up, png::AdaptiveFilterType::NonAdaptive),
+        PngFilter::Average => (png::FilterType::Avg, png::AdaptiveFilterType::NonAdaptive),
+        PngFilter::Paeth => (png::FilterType::Paeth, png::AdaptiveFilterType::NonAdaptive),
+        PngFilter::Adaptive => (png::FilterType::Sub, png::AdaptiveFilterType::Adaptive),
+    };
+    encoder.set_filter(filter);
+    encoder.set_adaptive_filter(adaptive);
+    for (keyword, text) in metadata {
+        encoder
+            .add_text_chunk(keyword.clone(), text.clone())
+            .with_context(|| format!("Invalid PNG metadata entry {}", keyword))?;
+    }
+    Ok(encoder)
+}
+
+/// Save `image` as a PNG file with metadata, replacing any existing file atomically.
+pub fn save_png(filename: &str, image: &RgbaImage, metadata: &[(String, String)], encoding: &PngEncoding) -> Result<()> {
+    write_atomic(filename, |writer| write_png(writer, image, metadata, encoding))
+        .with_context(|| format!("Failed to save image to {}", filename))
+}
+
+/// Write `filename` through a temporary file in the same directory that is renamed
+/// over the target once complete, so readers never see a partial file. The temporary
+/// file is removed if writing fails.
+pub fn write_atomic(filename: &str, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    let path = Path::new(filename);
+    let temp = temp_path(path)?;
+    let result = File::create(&temp)
+        .with_context(|| format!("Failed to create {}", temp.display()))
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            let file = writer.into_inner().map_err(|err| err.into_error())?;
+            file.sync_all()?;
+            Ok(std::fs::rename(&temp, path)?)
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// A hidden, unique sibling of `path`, e.g. `maps/.out.png.1234-0.tmp`.
+fn temp_path(path: &Path) -> Result<PathBuf> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Output path has no file name: {}", path.display()))?;
+    let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+    Ok(path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name.to_string_lossy(),
+        std::process::id(),
+        unique
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ip-heatmap-output-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_atomic_write_replaces_existing_file() {
+        let dir = scratch_dir("replace");
+        let target = dir.join("out.png");
+        std::fs::write(&target, b"old contents").unwrap();
+        write_atomic(target.to_str().unwrap(), |writer| Ok(writer.write_all(b"new")?)).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert_eq!(entries(&dir), ["out.png"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write_keeps_existing_file_and_cleans_up() {
+        let dir = scratch_dir("failure");
+        let target = dir.join("out.png");
+        std::fs::write(&target, b"old contents").unwrap();
+        let result = write_atomic(target.to_str().unwrap(), |writer| {
+            writer.write_all(b"partial")?;
+            Err(anyhow!("encoder failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&target).unwrap(), b"old contents");
+        assert_eq!(entries(&dir), ["out.png"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_temp_file_is_a_hidden_sibling() {
+        let temp = temp_path(Path::new("maps/out.png")).unwrap();
+        assert_eq!(temp.parent(), Some(Path::new("maps")));
+        assert!(temp.file_name().unwrap().to_string_lossy().starts_with(".out.png."));
+        assert_ne!(temp, temp_path(Path::new("maps/out.png")).unwrap());
+        assert!(temp_path(Path::new("")).is_err());
+    }
+
+    #[test]
+    fn test_png_round_trip_with_metadata() {
+        let mut image = RgbaImage::new(3, 2);
+        image.put_pixel(1, 1, Rgba([10, 20, 30, 255]));
+        let metadata = vec![("curve".to_string(), "linear".to_string())];
+
+        let mut encoded = Vec::new();
+        write_png(&mut encoded, &image, &metadata, &PngEncoding::default()).unwrap();
+
+        let decoder = png::Decoder::new(encoded.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let text: Vec<(String, String)> = reader
+            .info()
+            .uncompressed_latin1_text
+            .iter()
+            .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+            .collect();
+        assert_eq!(text, metadata);
+
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(pixels, image.into_raw());
+    }
+
+    #[test]
+    fn test_every_encoding_keeps_the_pixels() {
+        let mut image = RgbaImage::new(37, 11);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            if (x * y) % 3 != 0 {
+                *pixel = Rgba([(x * 7) as u8, (y * 23) as u8, (x + y) as u8, 255]);
+            }
+        }
+        let decode = |bytes: &[u8]| image::load_from_memory(bytes).unwrap().to_rgba8();
+        let compressions = [PngCompression::Fast, PngCompression::Default, PngCompression::Best];
+        let filters = ["none", "sub", "up", "average", "paeth", "adaptive"];
+        let mut sizes = Vec::new();
+        for compression in compressions {
+            for filter in filters {
+                let encoding = PngEncoding {
+                    compression,
+                    filter: filter.parse().unwrap(),
+                };
+                let mut encoded = Vec::new();
+                write_png(&mut encoded, &image, &[], &encoding).unwrap();
+                assert_eq!(decode(&encoded), image, "{:?}", encoding);
+                sizes.push(encoded.len());
+
+                let mut streamed = Vec::new();
+                write_png_rows(&mut streamed, 37, 11, &[], &encoding, |y, row| {
+                    row.copy_from_slice(&image.as_raw()[y as usize * 37 * 4..(y as usize + 1) * 37 * 4]);
+                })
+                .unwrap();
+                assert_eq!(decode(&streamed), image, "{:?}", encoding);
+            }
+        }
+        // Compression levels are not all the same, or they would not be worth choosing
+        assert!(sizes.iter().any(|&size| size != sizes[0]));
+        assert!("zopfli".parse::<PngCompression>().is_err());
+        assert!("diagonal".parse::<PngFilter>().is_err());
+    }
+}
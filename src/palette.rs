@@ -0,0 +1,561 @@
+use crate::Heatmap;
+use crate::render::RenderOptions;
+use crate::text::{draw_text, fill_rect, text_height, text_width};
+use colorous::Gradient;
+use image::{Rgba, RgbaImage, imageops};
+use std::fmt::Display;
+use std::str::FromStr;
+
+const SHEET_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const SHEET_FOREGROUND: Rgba<u8> = Rgba([0, 0, 0, 255]);
+const THUMBNAIL_BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 255]);
+const SHEET_PADDING: u32 = 8;
+const LABEL_SCALE: u32 = 2;
+
+/// Built-in gradients, in the order they are listed to users.
+pub const BUILTIN_PALETTES: [(&str, &Gradient); 8] = [
+    ("magma", &colorous::MAGMA),
+    ("inferno", &colorous::INFERNO),
+    ("plasma", &colorous::PLASMA),
+    ("viridis", &colorous::VIRIDIS),
+    ("cividis", &colorous::CIVIDIS),
+    ("turbo", &colorous::TURBO),
+    ("warm", &colorous::WARM),
+    ("cool", &colorous::COOL),
+];
+
+/// Alternative names accepted for built-in gradients.
+const PALETTE_ALIASES: [(&str, &str); 1] = [("accessible", "cividis")];
+
+/// A continuous colour palette mapping [0, 1] to RGB.
+#[derive(Clone, Debug)]
+pub enum Palette {
+    Builtin {
+        name: &'static str,
+        gradient: &'static Gradient,
+    },
+    /// Evenly spaced colour stops with linear interpolation in between.
+    Custom { name: String, stops: Vec<[u8; 3]> },
+    /// `palette` with its low end blended toward `toward`, see [`Palette::faded_toward`].
+    Faded { palette: Box<Palette>, toward: [u8; 3] },
+}
+
+/// Part of the scale over which [`Palette::faded_toward`] blends.
+const FADE_SPAN: f64 = 0.25;
+/// Share of the palette colour kept at the very low end, so the lowest values
+/// stay visible.
+const FADE_FLOOR: f64 = 0.2;
+
+impl Palette {
+    /// Look up a built-in palette (or alias) by name, case-insensitively.
+    pub fn builtin(name: &str) -> Option<Palette> {
+        let name = name.to_lowercase();
+        let name = PALETTE_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == name)
+            .map(|(_, target)| target.to_string())
+            .unwrap_or(name);
+        BUILTIN_PALETTES
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(name, gradient)| Palette::Builtin { name, gradient })
+    }
+
+    /// Every name [`Palette::builtin`] accepts: the built-in palettes, then their aliases.
+    pub fn builtin_names() -> impl Iterator<Item = &'static str> {
+        BUILTIN_PALETTES.iter().map(|(name, _)| *name).chain(PALETTE_ALIASES.iter().map(|(alias, _)| *alias))
+    }
+
+    /// All built-in palettes.
+    pub fn builtins() -> impl Iterator<Item = Palette> {
+        BUILTIN_PALETTES
+            .iter()
+            .map(|(name, gradient)| Palette::Builtin { name, gradient })
+    }
+
+    /// Parse a custom palette of the form `name=#rrggbb,#rrggbb[,...]`.
+    ///
+    /// At least two colour stops are required; the leading `#` is optional.
+    pub fn parse_custom(spec: &str) -> Result<Palette, String> {
+        let (name, stops) = spec.split_once('=').ok_or_else(|| {
+            format!(
+                "Invalid custom palette: {}. Use 'name=#rrggbb,#rrggbb,...'",
+                spec
+            )
+        })?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("Custom palette has no name: {}", spec));
+        }
+        let stops = stops
+            .split(',')
+            .map(|stop| parse_hex_colour(stop.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if stops.len() < 2 {
+            return Err(format!(
+                "Custom palette {} needs at least two colours",
+                name
+            ));
+        }
+        Ok(Palette::Custom {
+            name: name.to_string(),
+            stops,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Palette::Builtin { name, .. } => name,
+            Palette::Custom { name, .. } => name,
+            Palette::Faded { palette, .. } => palette.name(),
+        }
+    }
+
+    /// This palette with the lowest quarter of the scale blended toward `colour`,
+    /// typically the background, so low values recede into it instead of standing
+    /// out as the palette's darkest (or lightest) colour.
+    pub fn faded_toward(self, colour: [u8; 3]) -> Palette {
+        Palette::Faded {
+            palette: Box::new(self),
+            toward: colour,
+        }
+    }
+
+    /// Evaluate the palette at `t`, which is clamped to [0, 1].
+    pub fn eval(&self, t: f64) -> [u8; 3] {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        match self {
+            Palette::Builtin { gradient, .. } => {
+                let (r, g, b) = gradient.eval_continuous(t).as_tuple();
+                [r, g, b]
+            }
+            Palette::Custom { stops, .. } => {
+                let segments = (stops.len() - 1) as f64;
+                let position = t * segments;
+                let index = (position.floor() as usize).min(stops.len() - 2);
+                let frac = position - index as f64;
+                let (from, to) = (stops[index], stops[index + 1]);
+                let mut rgb = [0u8; 3];
+                for channel in 0..3 {
+                    let value = from[channel] as f64
+                        + (to[channel] as f64 - from[channel] as f64) * frac;
+                    rgb[channel] = value.round() as u8;
+                }
+                rgb
+            }
+            Palette::Faded { palette, toward } => {
+                let colour = palette.eval(t);
+                let kept = FADE_FLOOR + (1.0 - FADE_FLOOR) * (t / FADE_SPAN).min(1.0);
+                let mix = |channel: usize| {
+                    (toward[channel] as f64 + (colour[channel] as f64 - toward[channel] as f64) * kept).round() as u8
+                };
+                [mix(0), mix(1), mix(2)]
+            }
+        }
+    }
+}
+
+/// Default bits of the scaled value a [`ColourLut`] is indexed by: 4096 entries.
+pub const DEFAULT_COLOUR_LUT_BITS: u8 = 12;
+
+/// Bits a [`ColourLut`] may be indexed by.
+pub const COLOUR_LUT_BITS: std::ops::RangeInclusive<u8> = 12..=16;
+
+/// How a [`ColourLut`] picks the entry for a scaled value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum LutRounding {
+    /// The nearest entry, rounding halves up, so the first and last entries cover
+    /// half as much of the scale as the others.
+    #[default]
+    Nearest,
+    /// The entry whose equal share of the scale holds the value, like the original
+    /// ipv4-heatmap's 256 colours; `1.0` still takes the last entry.
+    Floor,
+}
+
+impl LutRounding {
+    pub const ALL: [LutRounding; 2] = [LutRounding::Nearest, LutRounding::Floor];
+}
+
+impl FromStr for LutRounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nearest" => Ok(LutRounding::Nearest),
+            "floor" => Ok(LutRounding::Floor),
+            _ => Err(format!("Invalid LUT rounding: {}. Use 'nearest' or 'floor'", s)),
+        }
+    }
+}
+
+impl Display for LutRounding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LutRounding::Nearest => write!(f, "nearest"),
+            LutRounding::Floor => write!(f, "floor"),
+        }
+    }
+}
+
+/// A palette sampled at `2^bits` evenly spaced points, so renders look colours up
+/// instead of interpolating the palette for every pixel. At the default size no
+/// channel differs from [`Palette::eval`] by more than 1.
+#[derive(Clone, Debug)]
+pub struct ColourLut {
+    colours: Vec<[u8; 3]>,
+    rounding: LutRounding,
+}
+
+impl ColourLut {
+    pub fn new(palette: &Palette, bits: u8) -> Self {
+        let last = (1usize << bits) - 1;
+        let colours = (0..=last).map(|index| palette.eval(index as f64 / last as f64)).collect();
+        ColourLut { colours, rounding: LutRounding::Nearest }
+    }
+
+    /// Pick entries with `rounding` instead of [`LutRounding::Nearest`].
+    pub fn with_rounding(mut self, rounding: LutRounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// The entry `t` is coloured from. `t` is clamped to [0, 1] and NaN taken as 0,
+    /// so 0 is always the first entry and 1 the last and no value is out of range.
+    pub fn index(&self, t: f64) -> usize {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        let last = self.colours.len() - 1;
+        let index = match self.rounding {
+            LutRounding::Nearest => (t * last as f64 + 0.5).floor() as usize,
+            LutRounding::Floor => ((t * self.colours.len() as f64).floor() as usize).min(last),
+        };
+        debug_assert!(index <= last, "{} gave LUT index {} of {}", t, index, last);
+        index
+    }
+
+    /// The colour of the entry [`ColourLut::index`] picks for `t`.
+    pub fn eval(&self, t: f64) -> [u8; 3] {
+        self.colours[self.index(t)]
+    }
+}
+
+/// Parse colour LUT bits (12 to 16), or `off` for exact palette evaluation.
+pub fn parse_colour_lut(value: &str) -> Result<Option<u8>, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    match value.parse::<u8>() {
+        Ok(bits) if COLOUR_LUT_BITS.contains(&bits) => Ok(Some(bits)),
+        _ => Err(format!(
+            "Invalid colour LUT size: {}. Use {} to {} bits, or off",
+            value,
+            COLOUR_LUT_BITS.start(),
+            COLOUR_LUT_BITS.end()
+        )),
+    }
+}
+
+impl From<&'static Gradient> for Palette {
+    fn from(gradient: &'static Gradient) -> Self {
+        // Gradients are constants without identity; their Debug output names them.
+        let debug_name = format!("{:?}", gradient);
+        let name = BUILTIN_PALETTES
+            .iter()
+            .find(|(_, builtin)| format!("{:?}", builtin) == debug_name)
+            .map(|(name, _)| *name)
+            .unwrap_or("gradient");
+        Palette::Builtin { name, gradient }
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(palette) = Palette::builtin(s) {
+            return Ok(palette);
+        }
+        if s.contains('=') {
+            return Palette::parse_custom(s);
+        }
+        let names: Vec<&str> = BUILTIN_PALETTES.iter().map(|(name, _)| *name).collect();
+        Err(format!(
+            "Invalid colour scale: {}. Supported: {}",
+            s,
+            names.join(", ")
+        ))
+    }
+}
+
+impl Display for Palette {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Parse a `#rrggbb` (or `rrggbb`) colour.
+pub fn parse_hex_colour(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid colour: {}. Use '#rrggbb'", s));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok([channel(0), channel(2), channel(4)])
+}
+
+/// Render one labelled horizontal gradient bar per palette.
+pub fn render_palette_strips(palettes: &[Palette], bar_width: u32, bar_height: u32) -> RgbaImage {
+    let label_width = palettes
+        .iter()
+        .map(|p| text_width(p.name(), LABEL_SCALE))
+        .max()
+        .unwrap_or(0);
+    let row_height = bar_height.max(text_height(LABEL_SCALE));
+    let width = SHEET_PADDING * 3 + label_width + bar_width;
+    let height = SHEET_PADDING + palettes.len() as u32 * (row_height + SHEET_PADDING);
+    let mut image = RgbaImage::from_pixel(width, height, SHEET_BACKGROUND);
+
+    for (i, palette) in palettes.iter().enumerate() {
+        let top = SHEET_PADDING + i as u32 * (row_height + SHEET_PADDING);
+        let label_top = top + (row_height - text_height(LABEL_SCALE)) / 2;
+        draw_text(
+            &mut image,
+            SHEET_PADDING as i64,
+            label_top as i64,
+            palette.name(),
+            LABEL_SCALE,
+            SHEET_FOREGROUND,
+        );
+        let bar_left = SHEET_PADDING * 2 + label_width;
+        draw_gradient_bar(&mut image, bar_left, top, bar_width, row_height, palette);
+    }
+
+    image
+}
+
+/// Render thumbnails of `heatmap` recoloured with every palette, laid out in a grid.
+///
+/// The heatmap buffer is processed once and only recoloured per palette.
+pub fn render_palette_previews(
+    heatmap: &Heatmap,
+    palettes: &[Palette],
+    thumbnail_size: u32,
+) -> Result<RgbaImage, &'static str> {
+    let columns = (palettes.len() as f64).sqrt().ceil().max(1.0) as u32;
+    let rows = (palettes.len() as u32).div_ceil(columns);
+    let bar_height = thumbnail_size / 16 + 1;
+    let cell_width = thumbnail_size;
+    let cell_height = thumbnail_size + bar_height + text_height(LABEL_SCALE) + SHEET_PADDING;
+    let width = SHEET_PADDING + columns * (cell_width + SHEET_PADDING);
+    let height = SHEET_PADDING + rows * (cell_height + SHEET_PADDING);
+    let mut image = RgbaImage::from_pixel(width, height, SHEET_BACKGROUND);
+
+    for (i, palette) in palettes.iter().enumerate() {
+        let left = SHEET_PADDING + (i as u32 % columns) * (cell_width + SHEET_PADDING);
+        let top = SHEET_PADDING + (i as u32 / columns) * (cell_height + SHEET_PADDING);
+
+        let rendered = heatmap.render(&RenderOptions { palette: palette.clone(), ..heatmap.render_options() })?;
+        let thumbnail = imageops::thumbnail(&rendered, thumbnail_size, thumbnail_size);
+        fill_rect(
+            &mut image,
+            left as i64,
+            top as i64,
+            thumbnail_size,
+            thumbnail_size,
+            THUMBNAIL_BACKGROUND,
+        );
+        imageops::overlay(&mut image, &thumbnail, left as i64, top as i64);
+
+        draw_gradient_bar(&mut image, left, top + thumbnail_size, cell_width, bar_height, palette);
+        draw_text(
+            &mut image,
+            left as i64,
+            (top + thumbnail_size + bar_height + SHEET_PADDING / 2) as i64,
+            palette.name(),
+            LABEL_SCALE,
+            SHEET_FOREGROUND,
+        );
+    }
+
+    Ok(image)
+}
+
+fn draw_gradient_bar(image: &mut RgbaImage, left: u32, top: u32, width: u32, height: u32, palette: &Palette) {
+    for x in 0..width {
+        let t = if width > 1 { x as f64 / (width - 1) as f64 } else { 0.0 };
+        let [r, g, b] = palette.eval(t);
+        fill_rect(image, (left + x) as i64, top as i64, 1, height, Rgba([r, g, b, 255]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_lookup_and_alias() {
+        assert_eq!(Palette::builtin("Magma").unwrap().name(), "magma");
+        assert_eq!(Palette::builtin("accessible").unwrap().name(), "cividis");
+        assert!(Palette::builtin("nonexistent").is_none());
+        assert_eq!(Palette::builtins().count(), BUILTIN_PALETTES.len());
+    }
+
+    #[test]
+    fn test_builtin_matches_gradient() {
+        let palette = Palette::builtin("viridis").unwrap();
+        let (r, g, b) = colorous::VIRIDIS.eval_continuous(0.3).as_tuple();
+        assert_eq!(palette.eval(0.3), [r, g, b]);
+    }
+
+    #[test]
+    fn test_from_gradient_finds_name() {
+        assert_eq!(Palette::from(&colorous::TURBO).name(), "turbo");
+    }
+
+    #[test]
+    fn test_custom_palette_interpolates() {
+        let palette: Palette = "mono=#000000,#ffffff".parse().unwrap();
+        assert_eq!(palette.name(), "mono");
+        assert_eq!(palette.eval(0.0), [0, 0, 0]);
+        assert_eq!(palette.eval(1.0), [255, 255, 255]);
+        assert_eq!(palette.eval(0.5), [128, 128, 128]);
+        // Out-of-range and NaN inputs are clamped
+        assert_eq!(palette.eval(2.0), [255, 255, 255]);
+        assert_eq!(palette.eval(f64::NAN), [0, 0, 0]);
+
+        let three: Palette = "rgb=ff0000,00ff00,0000ff".parse().unwrap();
+        assert_eq!(three.eval(0.5), [0, 255, 0]);
+        assert_eq!(three.eval(0.75), [0, 128, 128]);
+    }
+
+    #[test]
+    fn test_custom_palette_errors() {
+        assert!(Palette::parse_custom("nocolours").is_err());
+        assert!(Palette::parse_custom("=#000000,#ffffff").is_err());
+        assert!(Palette::parse_custom("one=#000000").is_err());
+        assert!(Palette::parse_custom("bad=#00000g,#ffffff").is_err());
+        assert!("unknown".parse::<Palette>().is_err());
+    }
+
+    #[test]
+    fn test_palette_strips_layout() {
+        let palettes: Vec<Palette> = Palette::builtins().collect();
+        let image = render_palette_strips(&palettes, 100, 20);
+        let label_width = palettes.iter().map(|p| text_width(p.name(), LABEL_SCALE)).max().unwrap();
+        assert_eq!(image.width(), SHEET_PADDING * 3 + label_width + 100);
+        assert_eq!(image.height(), SHEET_PADDING + 8 * (20 + SHEET_PADDING));
+
+        // The first bar starts with the palette's low end and ends with its high end
+        let bar_left = SHEET_PADDING * 2 + label_width;
+        let [r, g, b] = palettes[0].eval(0.0);
+        assert_eq!(*image.get_pixel(bar_left, SHEET_PADDING), Rgba([r, g, b, 255]));
+        let [r, g, b] = palettes[0].eval(1.0);
+        assert_eq!(*image.get_pixel(bar_left + 99, SHEET_PADDING), Rgba([r, g, b, 255]));
+    }
+
+    #[test]
+    fn test_palette_previews_reuse_buffer() {
+        let mut heatmap = Heatmap::new(
+            crate::DomainType::Linear,
+            None,
+            None,
+            true,
+            24,
+            &colorous::MAGMA,
+            crate::ValueMode::Scaled,
+            None,
+        );
+        heatmap.process_input_from_string("10.0.0.0/8 1\n").unwrap();
+        let palettes: Vec<Palette> = Palette::builtins().take(3).collect();
+        let image = render_palette_previews(&heatmap, &palettes, 16).unwrap();
+        // Three palettes fit in a 2x2 grid
+        assert_eq!(image.width(), SHEET_PADDING + 2 * (16 + SHEET_PADDING));
+
+        // Recolouring must agree with a heatmap configured for that palette directly
+        let options = RenderOptions { palette: palettes[1].clone(), ..heatmap.render_options() };
+        let direct = heatmap.render(&options).unwrap();
+        let mut inferno = Heatmap::new(
+            crate::DomainType::Linear,
+            None,
+            None,
+            true,
+            24,
+            &colorous::INFERNO,
+            crate::ValueMode::Scaled,
+            None,
+        );
+        inferno.process_input_from_string("10.0.0.0/8 1\n").unwrap();
+        assert_eq!(direct, inferno.render(&inferno.render_options()).unwrap());
+    }
+
+    #[test]
+    fn test_colour_lut_is_within_one_of_exact() {
+        let custom = Palette::parse_custom("steep=#000000,#ffffff,#000000,#ff00ff").unwrap();
+        for palette in Palette::builtins().chain([custom]) {
+            let lut = ColourLut::new(&palette, DEFAULT_COLOUR_LUT_BITS);
+            let mut max_delta = 0;
+            for step in 0..=100_000 {
+                let t = step as f64 / 100_000.0;
+                let (exact, looked_up) = (palette.eval(t), lut.eval(t));
+                for channel in 0..3 {
+                    max_delta = max_delta.max(exact[channel].abs_diff(looked_up[channel]));
+                }
+            }
+            assert!(max_delta <= 1, "{}: {}", palette.name(), max_delta);
+            assert_eq!(lut.eval(0.0), palette.eval(0.0));
+            assert_eq!(lut.eval(1.0), palette.eval(1.0));
+            assert_eq!(lut.eval(f64::NAN), palette.eval(0.0));
+            assert_eq!(lut.eval(7.0), palette.eval(1.0));
+        }
+    }
+
+    #[test]
+    fn test_lut_index_endpoints() {
+        let lut = ColourLut::new(&Palette::from(&colorous::MAGMA), 12);
+        let floor = lut.clone().with_rounding(LutRounding::Floor);
+        let just_below_one = 1.0 - f64::EPSILON;
+        for lut in [&lut, &floor] {
+            assert_eq!(lut.index(0.0), 0);
+            assert_eq!(lut.index(1.0), 4095);
+            assert_eq!(lut.index(just_below_one), 4095);
+            assert_eq!(lut.index(f64::MIN_POSITIVE), 0);
+            // Values the scale should never give stay in range
+            assert_eq!(lut.index(-0.5), 0);
+            assert_eq!(lut.index(1.0 + 1e-9), 4095);
+            assert_eq!(lut.index(f64::INFINITY), 4095);
+            assert_eq!(lut.index(f64::NEG_INFINITY), 0);
+            assert_eq!(lut.index(f64::NAN), 0);
+            assert_eq!(lut.index(-f64::NAN), 0);
+        }
+        // Nearest rounds halves up, so the ends cover half an entry's share
+        let half = 0.5 / 4095.0;
+        assert_eq!((lut.index(half), lut.index(half * 0.999)), (1, 0));
+        assert_eq!((lut.index(1.0 - half), lut.index(1.0 - half * 1.001)), (4095, 4094));
+        // Floor gives every entry an equal share
+        assert_eq!((floor.index(1.0 / 4096.0), floor.index(0.999 / 4096.0)), (1, 0));
+        assert_eq!((floor.index(4095.0 / 4096.0), floor.index(4094.999 / 4096.0)), (4095, 4094));
+        assert_eq!(floor.index(0.5), 2048);
+        assert_eq!(lut.index(0.5), 2048);
+    }
+
+    #[test]
+    fn test_last_custom_stop_is_reached() {
+        let palette = Palette::parse_custom("two=#000000,#ff0000,#ffffff").unwrap();
+        assert_eq!(palette.eval(1.0), [255, 255, 255]);
+        assert_eq!(palette.eval(1.0 - f64::EPSILON), [255, 255, 255]);
+        assert_eq!(palette.eval(f64::NAN), [0, 0, 0]);
+        let lut = ColourLut::new(&palette, 12).with_rounding(LutRounding::Floor);
+        assert_eq!((lut.eval(1.0), lut.eval(0.0)), ([255, 255, 255], [0, 0, 0]));
+    }
+
+    #[test]
+    fn test_parse_colour_lut() {
+        assert_eq!(parse_colour_lut("12"), Ok(Some(12)));
+        assert_eq!(parse_colour_lut("16"), Ok(Some(16)));
+        assert_eq!(parse_colour_lut("OFF"), Ok(None));
+        assert!(parse_colour_lut("8").is_err());
+        assert!(parse_colour_lut("17").is_err());
+        assert!(parse_colour_lut("many").is_err());
+    }
+}
@@ -0,0 +1,484 @@
+//! Parsing and painting on several threads with the same result as one.
+//!
+//! The calling thread splits input into batches of lines (the cheap part) and keeps
+//! the counts and rejects. Parser threads parse each batch and work out the pixels
+//! its records cover, sorted into one queue per band of the buffer. Each band has a
+//! single owner thread that applies its queue, and batches reach the owners in input
+//! order, so every cell sees its updates in the order a serial run makes them.
+//! Memory stays bounded by the batches in flight whatever the thread count.
+
+use crate::cells::{Grid, Slab};
+use crate::hilbert::hilbert_d2xy;
+use crate::timing::PhaseTimer;
+use crate::memory::{MemoryEstimate, MemoryPlan};
+use crate::input::{self, InputFormat, ParseOptions, ParsedLine, Record, ValueSource};
+use crate::{ConflictPolicy, ErrorPolicy, Geometry, Heatmap, ValueMode, weighted_value};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::sync::{Arc, Mutex};
+
+/// Lines parsed per batch.
+const BATCH_LINES: usize = 16 * 1024;
+
+/// Records covering more pixels than this are sent to every band owner whole, rather
+/// than as one queued update per pixel, so `/0` at `-z 0` does not queue 2^32 of them.
+const MAX_QUEUED_PIXELS: u64 = 4096;
+
+/// Lines read from the input that a parser thread turns into a [`ParsedBatch`].
+struct Batch {
+    sequence: u64,
+    /// Line text, with each line's number and end offset in `ends`.
+    text: String,
+    ends: Vec<(usize, usize, LineKind)>,
+}
+
+#[derive(Clone, Copy)]
+enum LineKind {
+    Text,
+    Unsampled,
+    Overlong,
+}
+
+/// The outcome of each line of a batch, and the updates each band gets from it.
+struct ParsedBatch {
+    sequence: u64,
+    /// Rejected lines keep their text for the reject log; others have none.
+    lines: Vec<(usize, ParsedLine, String)>,
+    bands: Vec<BandUpdates>,
+}
+
+/// A band's updates from one batch, in input order: the pixels in `pixels`, with each
+/// of `records` painted whole once the pixels before its position are.
+#[derive(Default)]
+struct BandUpdates {
+    pixels: Vec<(u32, i32)>,
+    records: Vec<(usize, Record)>,
+}
+
+/// How pixels are split into bands and painted.
+#[derive(Clone)]
+struct Layout {
+    bits_per_pixel: u8,
+    image_size: usize,
+    /// Pixels per band, a multiple of 64 so no touched word is shared between bands.
+    band_pixels: usize,
+    bands: usize,
+    paint_mode: ValueMode,
+    accumulate: bool,
+    value_source: ValueSource,
+    /// Set when painting over a different value is a conflict, resolved by this.
+    conflicts: Option<ConflictPolicy>,
+    factor: f64,
+}
+
+impl Layout {
+    /// Call `paint` with the buffer index and value of every pixel `record` covers.
+    fn for_each_index(&self, record: &Record, mut paint: impl FnMut(usize, i32)) {
+        let order = Geometry::for_bits_per_pixel(self.bits_per_pixel).order;
+        input::for_each_pixel(self.bits_per_pixel, self.paint_mode, record, |d, value| {
+            if let Some((x, y)) = hilbert_d2xy(d, order) {
+                paint(y as usize * self.image_size + x as usize, value);
+            }
+        });
+    }
+
+    fn parse(&self, batch: Batch, options: &ParseOptions) -> ParsedBatch {
+        let mut lines = Vec::with_capacity(batch.ends.len());
+        let mut bands: Vec<BandUpdates> = (0..self.bands).map(|_| BandUpdates::default()).collect();
+        let mut start = 0;
+        for (line_number, end, kind) in batch.ends {
+            let text = &batch.text[start..end];
+            start = end;
+            let line = match kind {
+                LineKind::Text => input::TextLine::Text(text.into()),
+                LineKind::Unsampled => input::TextLine::Unsampled,
+                LineKind::Overlong => input::TextLine::Overlong(text.into()),
+            };
+            let parsed = line.parse(options);
+            match &parsed {
+                ParsedLine::Record(record) => {
+                    self.queue(record, &mut bands);
+                    lines.push((line_number, parsed, String::new()));
+                }
+                ParsedLine::Records(records) => {
+                    for record in records {
+                        self.queue(record, &mut bands);
+                    }
+                    lines.push((line_number, parsed, String::new()));
+                }
+                ParsedLine::Rejected(..) => lines.push((line_number, parsed, text.to_string())),
+                _ => lines.push((line_number, parsed, String::new())),
+            }
+        }
+        ParsedBatch { sequence: batch.sequence, lines, bands }
+    }
+
+    /// Queue the weighted pixels of `record` for the bands owning them.
+    fn queue(&self, record: &Record, bands: &mut [BandUpdates]) {
+        let weighted = Record { net: record.net, value: weighted_value(record.value, self.factor) };
+        if pixel_count(self.bits_per_pixel, &record.net) > MAX_QUEUED_PIXELS {
+            for band in bands {
+                band.records.push((band.pixels.len(), weighted));
+            }
+        } else {
+            self.for_each_index(&weighted, |index, value| {
+                bands[index / self.band_pixels].pixels.push((index as u32, value));
+            });
+        }
+    }
+}
+
+/// Pixels a network covers at this resolution.
+fn pixel_count(bits_per_pixel: u8, net: &ipnet::Ipv4Net) -> u64 {
+    let first = u32::from(net.network()) as u64 >> bits_per_pixel;
+    let last = u32::from(net.broadcast()) as u64 >> bits_per_pixel;
+    last - first + 1
+}
+
+/// The cells and touched words of one band, and where it starts in the buffer.
+struct Band<'a> {
+    first_pixel: usize,
+    cells: &'a mut [i32],
+    touched: &'a mut [u64],
+    /// Paints that overwrote a different value, see [`Heatmap::conflicts`].
+    conflicts: u64,
+}
+
+impl Band<'_> {
+    fn paint(&mut self, layout: &Layout, index: usize, value: i32) {
+        let local = index - self.first_pixel;
+        let painted = self.touched[local / 64] & (1 << (local % 64)) != 0;
+        self.touched[local / 64] |= 1 << (local % 64);
+        let cell = &mut self.cells[local];
+        if layout.accumulate {
+            *cell = cell.saturating_add(value);
+        } else if let Some(policy) = layout.conflicts.filter(|_| painted && *cell != value) {
+            self.conflicts += 1;
+            *cell = policy.resolve(*cell, value);
+        } else {
+            *cell = layout.value_source.overwrite(*cell, value);
+        }
+    }
+
+    fn apply(&mut self, layout: &Layout, updates: BandUpdates) {
+        let end = self.first_pixel + self.cells.len();
+        let mut painted = 0;
+        for (position, record) in updates.records {
+            for &(index, value) in &updates.pixels[painted..position] {
+                self.paint(layout, index as usize, value);
+            }
+            painted = position;
+            layout.for_each_index(&record, |index, value| {
+                if (self.first_pixel..end).contains(&index) {
+                    self.paint(layout, index, value);
+                }
+            });
+        }
+        for &(index, value) in &updates.pixels[painted..] {
+            self.paint(layout, index as usize, value);
+        }
+    }
+}
+
+impl Heatmap {
+    /// [`Heatmap::process_input_from_reader`] with `threads` parser threads. The
+    /// buffer, counts and rejects are the same as those of a serial run.
+    ///
+    /// Falls back to a serial run for one thread, binary input, tiny maps, and
+    /// [`ErrorPolicy::Fail`] and [`ConflictPolicy::Error`], which must stop painting
+    /// at the failing line. Conflicting lines are counted but not sampled.
+    pub fn process_input_parallel<R: BufRead>(&mut self, reader: R, threads: usize) -> Result<()> {
+        let pixels = self.image_size() as usize * self.image_size() as usize;
+        if threads <= 1
+            || pixels < 64
+            || self.parse_options.format != InputFormat::Text
+            || self.error_policy == ErrorPolicy::Fail
+            || (self.conflict_policy == ConflictPolicy::Error && self.detects_conflicts())
+            // Whether a record repeats one before it depends on the order they are read
+            || self.dedup.is_some()
+            || self.paints_distinct()
+            || self.preaggregation().is_some()
+            // Bands are cut from the buffer by the geometry; a serial run skips what does not fit
+            || self.buffer.len() != self.image_size() as usize
+        {
+            return self.process_input_from_reader(reader);
+        }
+        // A few owners keep up with many parsers: applying an update is one addition
+        let bands = (threads / 4).clamp(1, pixels / 64);
+        let band_pixels = pixels.div_ceil(bands).next_multiple_of(64);
+        let layout = Layout {
+            bits_per_pixel: self.bits_per_pixel,
+            image_size: self.image_size() as usize,
+            band_pixels,
+            bands: pixels.div_ceil(band_pixels),
+            paint_mode: self.parse_options.value_source.paint_mode(self.value_mode),
+            accumulate: self.accumulate,
+            value_source: self.parse_options.value_source,
+            conflicts: Some(self.conflict_policy).filter(|_| self.detects_conflicts()),
+            factor: self.value_factor(),
+        };
+
+        // The buffer is lent to the band owners while the rest of the heatmap counts
+        let mut buffer = std::mem::replace(&mut self.buffer, Grid::new(0, 0));
+        let mut touched = std::mem::replace(&mut self.touched, Slab::heap(Vec::new()));
+        let (lines_before, rejected_before, host_bits_before, conflicts_before) =
+            (self.lines_processed, self.rejects.total(), self.cidr_host_bits, self.conflicts);
+        let result = self.process_bands(reader, threads, &layout, buffer.cells_mut(), &mut touched);
+        self.buffer = buffer;
+        self.touched = touched;
+
+        self.warn_host_bits(host_bits_before);
+        self.warn_conflicts(conflicts_before);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_lines(self.lines_processed - lines_before, self.rejects.total() - rejected_before);
+        }
+        self.warnings.log_suppressed();
+        result
+    }
+
+    fn process_bands<R: BufRead>(
+        &mut self,
+        reader: R,
+        threads: usize,
+        layout: &Layout,
+        cells: &mut [i32],
+        touched: &mut [u64],
+    ) -> Result<()> {
+        let options = self.parse_options.clone();
+        let timer = std::mem::take(&mut self.timer);
+        let in_flight_limit = threads * 2;
+        let result = std::thread::scope(|scope| {
+            let (batch_sender, batch_receiver) = sync_channel::<Batch>(in_flight_limit);
+            let batch_receiver = Arc::new(Mutex::new(batch_receiver));
+            let (parsed_sender, parsed_receiver) = sync_channel::<ParsedBatch>(in_flight_limit);
+            for _ in 0..threads {
+                let (receiver, sender, options) = (Arc::clone(&batch_receiver), parsed_sender.clone(), &options);
+                scope.spawn(move || {
+                    loop {
+                        let batch = match receiver.lock() {
+                            Ok(receiver) => receiver.recv(),
+                            Err(_) => return,
+                        };
+                        let Ok(batch) = batch else { return };
+                        if sender.send(layout.parse(batch, options)).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+            drop(parsed_sender);
+
+            let mut owners = Vec::with_capacity(layout.bands);
+            let mut band_threads = Vec::with_capacity(layout.bands);
+            let band_cells = cells.chunks_mut(layout.band_pixels);
+            let band_touched = touched.chunks_mut(layout.band_pixels.div_ceil(64));
+            for (index, (cells, touched)) in band_cells.zip(band_touched).enumerate() {
+                let (sender, receiver) = sync_channel::<BandUpdates>(in_flight_limit);
+                let mut band = Band { first_pixel: index * layout.band_pixels, cells, touched, conflicts: 0 };
+                band_threads.push(scope.spawn(move || {
+                    for updates in receiver {
+                        band.apply(layout, updates);
+                    }
+                    band.conflicts
+                }));
+                owners.push(sender);
+            }
+
+            let mut coordinator = Coordinator {
+                heatmap: &mut *self,
+                timer: &timer,
+                owners,
+                pending: BTreeMap::new(),
+                next_sequence: 0,
+                in_flight: 0,
+                parsed: parsed_receiver,
+            };
+            let mut batch = Batch { sequence: 0, text: String::new(), ends: Vec::with_capacity(BATCH_LINES) };
+            let read = input::for_each_text_line(reader, 0, &options, &timer, |line_number, line| {
+                let kind = match line {
+                    input::TextLine::Text(_) => LineKind::Text,
+                    input::TextLine::Unsampled => LineKind::Unsampled,
+                    input::TextLine::Overlong(_) => LineKind::Overlong,
+                };
+                batch.text.push_str(line.text());
+                batch.ends.push((line_number, batch.text.len(), kind));
+                if batch.ends.len() == BATCH_LINES {
+                    let sequence = batch.sequence + 1;
+                    let full = std::mem::replace(
+                        &mut batch,
+                        Batch { sequence, text: String::new(), ends: Vec::with_capacity(BATCH_LINES) },
+                    );
+                    coordinator.send(&batch_sender, full, in_flight_limit)?;
+                }
+                Ok(())
+            });
+            let sent = read.and_then(|()| coordinator.send(&batch_sender, batch, in_flight_limit));
+            drop(batch_sender);
+            // Finish the batches in flight even after an error, so the counts match the
+            // lines that were painted
+            let finished = coordinator.drain(0);
+            // The owners finish once their queues close
+            drop(coordinator);
+            self.conflicts += band_threads.into_iter().map(|band| band.join().unwrap_or_default()).sum::<u64>();
+            sent.and(finished)
+        });
+        self.timer = timer;
+        result
+    }
+}
+
+/// Hands batches to the parsers and their results to the band owners in order.
+struct Coordinator<'a> {
+    heatmap: &'a mut Heatmap,
+    timer: &'a PhaseTimer,
+    owners: Vec<SyncSender<BandUpdates>>,
+    /// Parsed batches that arrived before an earlier one.
+    pending: BTreeMap<u64, ParsedBatch>,
+    next_sequence: u64,
+    in_flight: usize,
+    parsed: Receiver<ParsedBatch>,
+}
+
+impl Coordinator<'_> {
+    fn send(&mut self, sender: &SyncSender<Batch>, batch: Batch, in_flight_limit: usize) -> Result<()> {
+        self.drain(in_flight_limit - 1)?;
+        if sender.send(batch).is_ok() {
+            self.in_flight += 1;
+        }
+        Ok(())
+    }
+
+    /// Apply parsed batches until at most `in_flight` are outstanding.
+    fn drain(&mut self, in_flight: usize) -> Result<()> {
+        while self.in_flight > in_flight {
+            let Ok(parsed) = self.parsed.recv() else { break };
+            self.pending.insert(parsed.sequence, parsed);
+            while let Some(parsed) = self.pending.remove(&self.next_sequence) {
+                self.next_sequence += 1;
+                self.in_flight -= 1;
+                self.apply(parsed)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, parsed: ParsedBatch) -> Result<()> {
+        let heatmap = &mut *self.heatmap;
+        let factor = heatmap.value_factor();
+        for (line_number, parsed, line) in parsed.lines {
+            match parsed {
+                ParsedLine::Record(record) => {
+                    heatmap.lines_processed += 1;
+                    heatmap.count_record(&record, factor);
+                }
+                ParsedLine::Records(records) => {
+                    heatmap.lines_processed += 1;
+                    for record in &records {
+                        heatmap.count_record(record, factor);
+                    }
+                }
+                other => heatmap.process_parsed(self.timer, factor, true, line_number, &line, other)?,
+            }
+        }
+        for (owner, updates) in self.owners.iter().zip(parsed.bands) {
+            // An owner only stops when its thread panicked, which the scope reports
+            let _ = owner.send(updates);
+        }
+        Ok(())
+    }
+}
+
+/// Bytes a line takes while its batch is in flight, for lines of about 32 bytes: the
+/// text, its end offset, its outcome and a queued pixel update.
+const BATCH_BYTES_PER_LINE: u64 = 128;
+
+/// Add the batches in flight with more than one thread: up to two per thread waiting
+/// to be parsed or counted, and as many again queued for the band owners.
+pub(crate) fn add_memory(plan: &MemoryPlan, estimate: &mut MemoryEstimate) {
+    if plan.threads > 1 {
+        let batches = 4 * plan.threads as u64;
+        estimate.add("input batches", batches * BATCH_LINES as u64 * BATCH_BYTES_PER_LINE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    fn buffer_hash(heatmap: &Heatmap) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        heatmap.buffer.cells().hash(&mut hasher);
+        heatmap.touched[..].hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Lines of addresses, prefixes (some large), bad lines and CR-separated lines.
+    fn input(lines: usize) -> String {
+        let mut input = String::new();
+        for line in 0..lines {
+            let octet = |shift: usize| (line.wrapping_mul(2_654_435_761) >> shift) % 256;
+            match line % 97 {
+                0 => input.push_str(&format!("{}.0.0.0/{} {}\n", octet(8), 6 + line % 5, line % 7)),
+                1 => input.push_str("not-an-address 3\n"),
+                2 => input.push_str(&format!("10.{}.0.0/16 2\r10.0.0.{} 1\n", octet(4), octet(12))),
+                _ => input.push_str(&format!("{}.{}.{}.{} {}\n", octet(0), octet(8), octet(16), octet(24), line % 11)),
+            }
+        }
+        input
+    }
+
+    fn heatmap(accumulate: bool, value_mode: ValueMode) -> Heatmap {
+        Heatmap::new(DomainType::Linear, None, None, accumulate, 16, &colorous::MAGMA, value_mode, None)
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let input = input(60_000);
+        for (accumulate, value_mode) in [(true, ValueMode::Raw), (true, ValueMode::Scaled), (false, ValueMode::Raw)] {
+            let mut serial = heatmap(accumulate, value_mode);
+            serial.process_input_from_string(&input).unwrap();
+            let mut parallel = heatmap(accumulate, value_mode);
+            parallel.process_input_parallel(input.as_bytes(), 8).unwrap();
+            assert_eq!(buffer_hash(&parallel), buffer_hash(&serial), "accumulate={} {:?}", accumulate, value_mode);
+            assert_eq!(parallel.lines_processed(), serial.lines_processed());
+            assert_eq!(parallel.rejects().total(), serial.rejects().total());
+            assert_eq!(parallel.take_errors(), serial.take_errors());
+            assert_eq!(parallel.weighted_total, serial.weighted_total);
+            assert_eq!(parallel.conflicts(), serial.conflicts());
+        }
+        let mut keep_max = heatmap(false, ValueMode::Raw);
+        keep_max.set_conflict_policy(ConflictPolicy::KeepMax);
+        keep_max.process_input_parallel(input.as_bytes(), 8).unwrap();
+        let mut serial = heatmap(false, ValueMode::Raw);
+        serial.set_conflict_policy(ConflictPolicy::KeepMax);
+        serial.process_input_from_string(&input).unwrap();
+        assert_eq!((buffer_hash(&keep_max), keep_max.conflicts()), (buffer_hash(&serial), serial.conflicts()));
+        assert!(serial.conflicts() > 0);
+    }
+
+    #[test]
+    fn test_parallel_runs_are_identical() {
+        let input = input(40_000);
+        let run = || {
+            let mut heatmap = heatmap(true, ValueMode::Raw);
+            heatmap.process_input_parallel(input.as_bytes(), 6).unwrap();
+            buffer_hash(&heatmap)
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn test_large_records_are_painted_by_every_band() {
+        let input = "0.0.0.0/1 3\n10.0.0.1 5\n128.0.0.0/2 1\n";
+        let mut serial = heatmap(true, ValueMode::Raw);
+        serial.process_input_from_string(input).unwrap();
+        let mut parallel = heatmap(true, ValueMode::Raw);
+        parallel.process_input_parallel(input.as_bytes(), 16).unwrap();
+        assert_eq!(buffer_hash(&parallel), buffer_hash(&serial));
+        assert_eq!(parallel.touched_pixels(), serial.touched_pixels());
+    }
+}
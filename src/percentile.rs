@@ -0,0 +1,76 @@
+use crate::memory::{MemoryEstimate, MemoryPlan};
+use crate::{Heatmap, ValueMode};
+
+/// Non-zero cell values of a heatmap in ascending order, for percentile lookups.
+pub struct SortedValues {
+    values: Vec<i32>,
+}
+
+impl SortedValues {
+    pub fn new(mut values: Vec<i32>) -> Self {
+        values.sort_unstable();
+        Self { values }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn values(&self) -> &[i32] {
+        &self.values
+    }
+
+    /// The nearest-rank `p`th percentile (0-100), or `None` without values.
+    pub fn percentile(&self, p: f64) -> Option<i32> {
+        if self.values.is_empty() {
+            return None;
+        }
+        // The epsilon keeps e.g. p99.9 of 1000 values at rank 999 despite rounding
+        let rank = (p.clamp(0.0, 100.0) * self.values.len() as f64 / 100.0 - 1e-9).ceil() as usize;
+        Some(self.values[rank.clamp(1, self.values.len()) - 1])
+    }
+}
+
+impl Heatmap {
+    /// All non-zero cell values, sorted.
+    pub fn sorted_values(&self) -> SortedValues {
+        let values = self
+            .buffer
+            .iter()
+            .flatten()
+            .copied()
+            // Skip the "no data" sentinel of categorical mode
+            .filter(|&value| value != 0 && (value > 0 || self.value_mode != ValueMode::Categorical))
+            .collect();
+        SortedValues::new(values)
+    }
+}
+
+/// Add the sorted copy of the cells percentile domains are taken from, at most one
+/// value per cell.
+pub(crate) fn add_memory(plan: &MemoryPlan, estimate: &mut MemoryEstimate) {
+    if plan.percentiles {
+        let size = plan.image_size() as u64;
+        estimate.add("sorted values", size * size * 4);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nearest_rank_percentiles() {
+        let sorted = SortedValues::new((1..=100).rev().collect());
+        assert_eq!(sorted.percentile(50.0), Some(50));
+        assert_eq!(sorted.percentile(90.0), Some(90));
+        assert_eq!(sorted.percentile(99.9), Some(100));
+        assert_eq!(sorted.percentile(0.0), Some(1));
+        assert_eq!(sorted.percentile(100.0), Some(100));
+        assert_eq!(SortedValues::new(vec![]).percentile(50.0), None);
+    }
+}
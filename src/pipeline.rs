@@ -0,0 +1,522 @@
+//! Rendering as an ordered stack of layers composited onto one RGBA canvas.
+//!
+//! The canvas starts transparent at the map's size. Each [`Layer`] draws onto it in
+//! turn, and a layer such as [`CropLayer`] or [`LegendLayer`] may replace it with a
+//! canvas of another size, so layers drawing in map coordinates go before those.
+//! [`RenderPipeline::framed`] is the pipeline behind [`Heatmap::render_framed`];
+//! embedders can insert their own layers into it, including plain closures.
+//! [`RenderPipeline::render_layered`] keeps outlines, labels and the frame's text
+//! on a canvas of their own, for editing or restyling them apart from the data.
+
+use crate::Heatmap;
+use crate::frame::Frame;
+use crate::label::Label;
+use crate::layout::{Layout, Panel};
+use crate::legend::Legend;
+use crate::outline::Outline;
+use crate::render::RenderOptions;
+use crate::shade::{self, Shade};
+use crate::text::scale_for_size;
+use crate::theme::Theme;
+use image::{Rgba, RgbaImage, imageops};
+use ipnet::Ipv4Net;
+
+/// One step of a [`RenderPipeline`].
+pub trait Layer {
+    /// Draw onto `canvas`, a rendering of `heatmap` so far, in the colours of `theme`
+    /// where the layer has none of its own. Layers may replace the canvas, e.g. to
+    /// crop or frame it.
+    fn composite(&self, heatmap: &Heatmap, theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str>;
+
+    /// Draw as [`Layer::composite`] would, but with the map's data on `canvas` and
+    /// annotations on `overlay`, which always have the same size. By default the
+    /// layer draws on `canvas`, and fails if it resized it.
+    fn composite_layered(
+        &self,
+        heatmap: &Heatmap,
+        theme: &Theme,
+        canvas: &mut RgbaImage,
+        overlay: &mut RgbaImage,
+    ) -> Result<(), &'static str> {
+        self.composite(heatmap, theme, canvas)?;
+        if canvas.dimensions() != overlay.dimensions() {
+            return Err("A layer resized the canvas but not the overlay");
+        }
+        Ok(())
+    }
+}
+
+/// Closures are layers, for one-off drawing.
+impl<F> Layer for F
+where
+    F: Fn(&Heatmap, &Theme, &mut RgbaImage) -> Result<(), &'static str>,
+{
+    fn composite(&self, heatmap: &Heatmap, theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str> {
+        self(heatmap, theme, canvas)
+    }
+}
+
+/// Ordered layers, composited bottom first, sharing one [`Theme`].
+#[derive(Default)]
+pub struct RenderPipeline {
+    layers: Vec<Box<dyn Layer>>,
+    theme: Theme,
+}
+
+impl RenderPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The layers of [`Heatmap::render_framed`]: the heat map, the theme's background
+    /// under it unless the theme is [`Theme::DARK`], each shade, each outline, each
+    /// label, the crop to a prefix or to the data, then the title, legend and caption
+    /// if any.
+    pub fn framed(options: &RenderOptions, frame: &Frame) -> Self {
+        let mut pipeline = Self::new();
+        pipeline.set_theme(frame.theme);
+        pipeline.push(HeatLayer(options.clone()));
+        if frame.theme != Theme::DARK {
+            pipeline.push(Underlay(frame.theme.background));
+        }
+        for shade in &frame.shades {
+            pipeline.push(ShadeLayer(shade.clone()));
+        }
+        for outline in &frame.outlines {
+            pipeline.push(OutlineLayer(outline.clone()));
+        }
+        for label in &frame.labels {
+            pipeline.push(LabelLayer { label: label.clone(), font_size: frame.font_size, view: frame.crop });
+        }
+        if let Some(net) = frame.crop {
+            pipeline.push(CropLayer(net));
+        } else if let Some(padding) = frame.crop_to_data {
+            pipeline.push(DataCropLayer { padding });
+        }
+        if frame.title.is_some() || frame.legend_label.is_some() || options.bands.is_some() || frame.pixel_caption {
+            pipeline.push(LegendLayer {
+                options: options.clone(),
+                frame: frame.clone(),
+            });
+        }
+        pipeline
+    }
+
+    /// Colours for layers without their own. Defaults to [`Theme::DARK`].
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    pub fn theme(&self) -> &Theme {
+        &self.theme
+    }
+
+    /// Add `layer` on top.
+    pub fn push(&mut self, layer: impl Layer + 'static) {
+        self.layers.push(Box::new(layer));
+    }
+
+    /// Add `layer` at position `index`, above the layers before it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than [`RenderPipeline::len`].
+    pub fn insert(&mut self, index: usize, layer: impl Layer + 'static) {
+        self.layers.insert(index, Box::new(layer));
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Composite every layer onto a transparent canvas of `heatmap`'s size.
+    pub fn render(&self, heatmap: &Heatmap) -> Result<RgbaImage, &'static str> {
+        let size = heatmap.image_size();
+        let mut canvas = RgbaImage::new(size, size);
+        for layer in &self.layers {
+            layer.composite(heatmap, &self.theme, &mut canvas)?;
+        }
+        Ok(canvas)
+    }
+
+    /// Like [`RenderPipeline::render`], but with outlines, labels, titles, the legend
+    /// and the caption on a second, transparent canvas of the same size. Every pixel
+    /// of that overlay is transparent or opaque, and laid over the first canvas it
+    /// gives the image [`RenderPipeline::render`] returns.
+    pub fn render_layered(&self, heatmap: &Heatmap) -> Result<(RgbaImage, RgbaImage), &'static str> {
+        let size = heatmap.image_size();
+        let (mut canvas, mut overlay) = (RgbaImage::new(size, size), RgbaImage::new(size, size));
+        for layer in &self.layers {
+            layer.composite_layered(heatmap, &self.theme, &mut canvas, &mut overlay)?;
+        }
+        Ok((canvas, overlay))
+    }
+}
+
+/// A solid colour under everything drawn before it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Underlay(pub Rgba<u8>);
+
+impl Layer for Underlay {
+    fn composite(&self, _heatmap: &Heatmap, _theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str> {
+        for pixel in canvas.pixels_mut() {
+            *pixel = over(self.0, *pixel);
+        }
+        Ok(())
+    }
+}
+
+/// The colourised map, composited over the canvas. Needs a canvas of the map's size.
+#[derive(Clone, Debug)]
+pub struct HeatLayer(pub RenderOptions);
+
+impl Layer for HeatLayer {
+    fn composite(&self, heatmap: &Heatmap, _theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str> {
+        // Colour an empty canvas in place rather than holding a second image
+        if canvas.pixels().all(|pixel| pixel.0[3] == 0) {
+            return heatmap.render_into(&self.0, canvas);
+        }
+        let heat = heatmap.render(&self.0)?;
+        if heat.dimensions() != canvas.dimensions() {
+            return Err("Image size does not match the map");
+        }
+        for (pixel, &top) in canvas.pixels_mut().zip(heat.pixels()) {
+            *pixel = over(*pixel, top);
+        }
+        Ok(())
+    }
+}
+
+/// A prefix shaded in a colour or pattern, see [`Heatmap::draw_shade`].
+#[derive(Clone, Debug)]
+pub struct ShadeLayer(pub Shade);
+
+impl Layer for ShadeLayer {
+    fn composite(&self, heatmap: &Heatmap, _theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str> {
+        heatmap.draw_shade(canvas, &self.0);
+        Ok(())
+    }
+}
+
+/// A border around a prefix, see [`Heatmap::draw_outline`].
+#[derive(Clone, Debug)]
+pub struct OutlineLayer(pub Outline);
+
+impl Layer for OutlineLayer {
+    fn composite(&self, heatmap: &Heatmap, theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str> {
+        heatmap.draw_outline(canvas, &self.0, theme);
+        Ok(())
+    }
+
+    fn composite_layered(
+        &self,
+        heatmap: &Heatmap,
+        theme: &Theme,
+        _canvas: &mut RgbaImage,
+        overlay: &mut RgbaImage,
+    ) -> Result<(), &'static str> {
+        self.composite(heatmap, theme, overlay)
+    }
+}
+
+/// Text over a prefix, see [`Heatmap::draw_label`], `font_size` pixels high or
+/// sized to the map like the legend's text, placed within the part of the prefix
+/// in `view`.
+#[derive(Clone, Debug)]
+pub struct LabelLayer {
+    pub label: Label,
+    pub font_size: Option<u32>,
+    pub view: Option<Ipv4Net>,
+}
+
+impl Layer for LabelLayer {
+    fn composite(&self, heatmap: &Heatmap, theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str> {
+        let scale = self.font_size.map_or_else(|| Layout::for_panel_size(canvas.width()).text_scale, scale_for_size);
+        heatmap.draw_label(canvas, &self.label, theme, scale, self.view.as_ref());
+        Ok(())
+    }
+
+    fn composite_layered(
+        &self,
+        heatmap: &Heatmap,
+        theme: &Theme,
+        _canvas: &mut RgbaImage,
+        overlay: &mut RgbaImage,
+    ) -> Result<(), &'static str> {
+        self.composite(heatmap, theme, overlay)
+    }
+}
+
+/// Keeps only the pixels covering a prefix. Layers after it no longer draw in map
+/// coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CropLayer(pub Ipv4Net);
+
+impl Layer for CropLayer {
+    fn composite(&self, heatmap: &Heatmap, _theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str> {
+        let (x, y, width, height) = heatmap.prefix_rect(&self.0);
+        *canvas = imageops::crop_imm(canvas, x, y, width, height).to_image();
+        Ok(())
+    }
+
+    fn composite_layered(
+        &self,
+        heatmap: &Heatmap,
+        theme: &Theme,
+        canvas: &mut RgbaImage,
+        overlay: &mut RgbaImage,
+    ) -> Result<(), &'static str> {
+        self.composite(heatmap, theme, canvas)?;
+        self.composite(heatmap, theme, overlay)
+    }
+}
+
+/// Keeps only the painted pixels and `padding` pixels around them, or the whole
+/// canvas if nothing was painted. Layers after it no longer draw in map coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataCropLayer {
+    pub padding: u32,
+}
+
+impl Layer for DataCropLayer {
+    fn composite(&self, heatmap: &Heatmap, _theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str> {
+        if let Some((x, y, width, height)) = heatmap.painted_bounds(self.padding) {
+            *canvas = imageops::crop_imm(canvas, x, y, width, height).to_image();
+        }
+        Ok(())
+    }
+
+    fn composite_layered(
+        &self,
+        heatmap: &Heatmap,
+        theme: &Theme,
+        canvas: &mut RgbaImage,
+        overlay: &mut RgbaImage,
+    ) -> Result<(), &'static str> {
+        self.composite(heatmap, theme, canvas)?;
+        self.composite(heatmap, theme, overlay)
+    }
+}
+
+/// Lays the canvas out as a panel with the title, legend and pixel caption of
+/// `frame`, the legend showing the colour scale of `options`. The shades, outlines
+/// and crop of `frame` are layers of their own and are not drawn here.
+#[derive(Clone, Debug)]
+pub struct LegendLayer {
+    pub options: RenderOptions,
+    pub frame: Frame,
+}
+
+impl LegendLayer {
+    /// The layout and legend for a canvas `width` pixels wide.
+    fn layout(&self, heatmap: &Heatmap, theme: &Theme, width: u32) -> Result<(Layout, Option<Legend>), &'static str> {
+        let (options, frame) = (&self.options, &self.frame);
+        let (min_value, max_value) = heatmap.domain_bounds(options);
+        // Bands are shown on a legend even without a label
+        let legend = match frame.legend_label.is_some() || options.bands.is_some() {
+            true => Some(Legend {
+                palette: options.palette.clone(),
+                curve: options.curve,
+                min_value,
+                max_value,
+                log_params: options.log_params,
+                label: frame.legend_label.clone(),
+                bands: heatmap.legend_bands(options)?,
+                strings: options.strings.clone(),
+            }),
+            false => None,
+        };
+        let mut layout = Layout::for_panel_size(width);
+        layout.theme = *theme;
+        if let Some(size) = frame.font_size {
+            layout.text_scale = scale_for_size(size);
+        }
+        layout.title_scale = frame.title_size.map(scale_for_size);
+        layout.caption = frame.pixel_caption.then(|| heatmap.pixel_caption(frame.crop.as_ref(), &options.strings));
+        Ok((layout, legend))
+    }
+
+    fn panel(&self, canvas: &mut RgbaImage) -> Panel {
+        Panel {
+            image: std::mem::take(canvas),
+            title: self.frame.title.clone(),
+        }
+    }
+}
+
+impl Layer for LegendLayer {
+    fn composite(&self, heatmap: &Heatmap, theme: &Theme, canvas: &mut RgbaImage) -> Result<(), &'static str> {
+        let (layout, legend) = self.layout(heatmap, theme, canvas.width())?;
+        *canvas = layout.compose(&[self.panel(canvas)], legend.as_ref());
+        Ok(())
+    }
+
+    /// The background and map on `canvas`; the title, legend and caption over
+    /// `overlay` on a transparent background.
+    fn composite_layered(
+        &self,
+        heatmap: &Heatmap,
+        theme: &Theme,
+        canvas: &mut RgbaImage,
+        overlay: &mut RgbaImage,
+    ) -> Result<(), &'static str> {
+        let (mut layout, legend) = self.layout(heatmap, theme, canvas.width())?;
+        *canvas = layout.compose_layers(&[self.panel(canvas)], legend.as_ref()).0;
+        layout.theme.background = Rgba([0, 0, 0, 0]);
+        *overlay = layout.compose(&[self.panel(overlay)], legend.as_ref());
+        Ok(())
+    }
+}
+
+/// `top` composited over `below`.
+fn over(below: Rgba<u8>, top: Rgba<u8>) -> Rgba<u8> {
+    match top.0[3] {
+        255 => top,
+        0 => below,
+        alpha => shade::blend(below, [top.0[0], top.0[1], top.0[2]], alpha as f64 / 255.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap() -> Heatmap {
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.process_input_from_string("10.0.0.1 5\n192.168.0.0/16 3\n").unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_framed_pipeline_matches_render() {
+        let hm = heatmap();
+        let options = hm.render_options();
+        let pipeline = RenderPipeline::framed(&options, &Frame::default());
+        assert_eq!(pipeline.len(), 1);
+        assert_eq!(pipeline.render(&hm).unwrap(), hm.render(&options).unwrap());
+
+        let frame = Frame {
+            title: Some("scan".to_string()),
+            crop: Some("192.0.0.0/2".parse().unwrap()),
+            outlines: vec!["192.168.0.0/16:ff0000:1".parse().unwrap()],
+            labels: vec![Label { net: "192.168.0.0/16".parse().unwrap(), text: "home".to_string() }],
+            ..Frame::default()
+        };
+        // Heat, outline, label, crop and legend
+        assert_eq!(RenderPipeline::framed(&options, &frame).len(), 5);
+    }
+
+    #[test]
+    fn test_underlay_shows_through_empty_pixels() {
+        let hm = heatmap();
+        let options = hm.render_options();
+        let white = Rgba([255, 255, 255, 255]);
+        let mut pipeline = RenderPipeline::new();
+        pipeline.push(Underlay(white));
+        pipeline.push(HeatLayer(options.clone()));
+        let image = pipeline.render(&hm).unwrap();
+        let plain = hm.render(&options).unwrap();
+        for (pixel, &heat) in image.pixels().zip(plain.pixels()) {
+            assert_eq!(*pixel, if heat.0[3] == 0 { white } else { heat });
+        }
+        // A translucent underlay is blended under the heat layer's opaque pixels
+        let mut pipeline = RenderPipeline::new();
+        pipeline.push(Underlay(Rgba([0, 0, 255, 128])));
+        pipeline.push(HeatLayer(options));
+        let image = pipeline.render(&hm).unwrap();
+        assert!(image.pixels().all(|pixel| pixel.0[3] == 255 || *pixel == Rgba([0, 0, 255, 128])));
+    }
+
+    #[test]
+    fn test_closures_are_layers() {
+        let hm = heatmap();
+        let mut pipeline = RenderPipeline::framed(&hm.render_options(), &Frame::default());
+        pipeline.insert(0, |_: &Heatmap, _: &Theme, canvas: &mut RgbaImage| {
+            canvas.put_pixel(0, 0, Rgba([1, 2, 3, 255]));
+            Ok(())
+        });
+        pipeline.push(|_: &Heatmap, _: &Theme, canvas: &mut RgbaImage| match canvas.get_pixel(0, 0).0 {
+            [1, 2, 3, 255] => Ok(()),
+            _ => Err("the heat layer painted over the first pixel"),
+        });
+        // 0.0.0.0 has no value, so the first pixel keeps the colour drawn under it
+        assert_eq!(pipeline.render(&hm).unwrap().get_pixel(0, 0).0, [1, 2, 3, 255]);
+
+        let mut failing = RenderPipeline::new();
+        failing.push(|_: &Heatmap, _: &Theme, _: &mut RgbaImage| Err("no"));
+        assert_eq!(failing.render(&hm), Err("no"));
+    }
+
+    #[test]
+    fn test_layered_render_composites_to_render() {
+        let hm = heatmap();
+        let options = hm.render_options();
+        let frame = Frame {
+            title: Some("scan".to_string()),
+            legend_label: Some("hosts".to_string()),
+            crop: Some("192.0.0.0/2".parse().unwrap()),
+            outlines: vec!["192.168.0.0/16:ff0000:1".parse().unwrap(), "200.0.0.0/8".parse().unwrap()],
+            labels: vec![Label { net: "192.168.0.0/16".parse().unwrap(), text: "home".to_string() }],
+            shades: vec!["192.0.0.0/8:00ff00".parse().unwrap()],
+            pixel_caption: true,
+            theme: Theme::LIGHT,
+            ..Frame::default()
+        };
+        for frame in [Frame::default(), frame] {
+            let pipeline = RenderPipeline::framed(&options, &frame);
+            let combined = pipeline.render(&hm).unwrap();
+            let (mut image, overlay) = pipeline.render_layered(&hm).unwrap();
+            assert_eq!(image.dimensions(), combined.dimensions());
+            assert!(overlay.pixels().all(|pixel| pixel.0[3] == 0 || pixel.0[3] == 255));
+            assert_eq!(overlay.pixels().any(|pixel| pixel.0[3] == 255), !frame.is_plain());
+            imageops::overlay(&mut image, &overlay, 0, 0);
+            assert_eq!(image, combined);
+        }
+
+        // Layers of their own draw on the data, and may not resize it alone
+        let mut pipeline = RenderPipeline::framed(&options, &Frame::default());
+        pipeline.push(|_: &Heatmap, _: &Theme, canvas: &mut RgbaImage| {
+            *canvas = RgbaImage::new(1, 1);
+            Ok(())
+        });
+        assert!(pipeline.render_layered(&hm).is_err());
+    }
+
+    #[test]
+    fn test_text_overrides_change_only_their_text() {
+        let hm = heatmap();
+        let frame = Frame { legend_label: Some("hosts".to_string()), pixel_caption: true, ..Frame::default() };
+        let render = |strings: &[&str]| {
+            let mut options = hm.render_options();
+            for text in strings {
+                options.strings.set_override(text).unwrap();
+            }
+            hm.render_framed(&options, &frame).unwrap()
+        };
+        let default = render(&[]);
+        assert_eq!(render(&["caption.pixel=1 pixel = /{prefix} ({addresses} addresses)"]), default);
+        let translated = render(&["caption.pixel=1 Pixel = /{prefix} ({addresses} Adressen)"]);
+        assert_eq!(translated.dimensions(), default.dimensions());
+        // Only the caption, below the map, differs
+        let differing: Vec<u32> = (0..default.height())
+            .filter(|&y| (0..default.width()).any(|x| default.get_pixel(x, y) != translated.get_pixel(x, y)))
+            .collect();
+        let map_height = hm.render(&hm.render_options()).unwrap().height();
+        assert!(!differing.is_empty());
+        assert!(differing.iter().all(|&y| y >= map_height), "{:?}", differing);
+    }
+
+    #[test]
+    fn test_heat_layer_needs_the_map_size() {
+        let hm = heatmap();
+        let mut pipeline = RenderPipeline::new();
+        pipeline.push(CropLayer("10.0.0.0/8".parse().unwrap()));
+        pipeline.push(HeatLayer(hm.render_options()));
+        assert!(pipeline.render(&hm).is_err());
+    }
+}
@@ -0,0 +1,513 @@
+//! Records merged by key before they are painted, `--preaggregate` and `--distinct`.
+//!
+//! Keys are gathered in memory up to a budget, then sorted, merged and spilled to
+//! a run file, so inputs with more keys than fit in memory cost disk I/O rather
+//! than RAM. When input ends the runs are merge-sorted and every key is painted
+//! once.
+
+use crate::distinct::key;
+use crate::hilbert::hilbert_d2xy;
+use crate::input::{self, Record};
+use crate::{ConflictPolicy, Heatmap, ValueMode};
+use anyhow::{Context, Result, bail};
+use ipnet::Ipv4Net;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Memory for keys waiting to be spilled, by default.
+pub const DEFAULT_PREAGGREGATE_MEMORY: u64 = 256 << 20;
+
+/// Bytes a waiting key takes: the key and its merged value.
+const ENTRY_BYTES: u64 = 16;
+
+/// Runs read at once while merging; more are merged in passes.
+const MAX_OPEN_RUNS: usize = 64;
+
+/// How the records of a key are merged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Preaggregation {
+    /// Sum the values of each key and paint it once with the sum.
+    Sum,
+    /// Paint 1 for each distinct key in every pixel it covers, whatever its values.
+    Distinct,
+}
+
+impl FromStr for Preaggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sum" => Ok(Preaggregation::Sum),
+            "distinct" => Ok(Preaggregation::Distinct),
+            _ => Err(format!("Invalid preaggregation: {}. Use 'sum' or 'distinct'", s)),
+        }
+    }
+}
+
+impl Display for Preaggregation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Preaggregation::Sum => write!(f, "sum"),
+            Preaggregation::Distinct => write!(f, "distinct"),
+        }
+    }
+}
+
+/// A directory of run files, removed with everything in it when dropped, so runs
+/// do not outlive a failed run.
+struct SpillDir {
+    path: PathBuf,
+}
+
+impl SpillDir {
+    fn create(parent: &Path) -> Result<Self> {
+        for attempt in 0u32.. {
+            let path = parent.join(format!("ip-heatmap-runs-{}-{}", std::process::id(), attempt));
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(SpillDir { path }),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Failed to create a directory for runs in {}", parent.display()));
+                }
+            }
+        }
+        unreachable!("attempts are unbounded")
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Writes a run: each key as the varint of its difference from the one before,
+/// which keeps sorted keys to a byte or two, then its value as a zigzag varint.
+struct RunWriter {
+    writer: BufWriter<File>,
+    previous: u64,
+}
+
+impl RunWriter {
+    fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create run {}", path.display()))?;
+        Ok(RunWriter { writer: BufWriter::new(file), previous: 0 })
+    }
+
+    fn write(&mut self, key: u64, value: i64) -> Result<()> {
+        write_varint(&mut self.writer, key - self.previous)?;
+        write_varint(&mut self.writer, ((value << 1) ^ (value >> 63)) as u64)?;
+        self.previous = key;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.writer.flush().context("Failed to write a run")
+    }
+}
+
+fn write_varint(writer: &mut impl Write, mut value: u64) -> Result<()> {
+    let mut bytes = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        bytes[len] = byte | if value == 0 { 0 } else { 0x80 };
+        len += 1;
+        if value == 0 {
+            break;
+        }
+    }
+    writer.write_all(&bytes[..len]).context("Failed to write a run")
+}
+
+/// Reads back a run written by [`RunWriter`].
+struct RunReader {
+    reader: BufReader<File>,
+    previous: u64,
+}
+
+impl RunReader {
+    fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open run {}", path.display()))?;
+        Ok(RunReader { reader: BufReader::new(file), previous: 0 })
+    }
+
+    fn next(&mut self) -> Result<Option<(u64, i64)>> {
+        let Some(delta) = self.varint(true)? else {
+            return Ok(None);
+        };
+        let zigzag = self.varint(false)?.expect("a value follows its key");
+        self.previous += delta;
+        Ok(Some((self.previous, ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64))))
+    }
+
+    /// The next varint, or `None` at the end of the run if `at_end` may be there.
+    fn varint(&mut self, at_end: bool) -> Result<Option<u64>> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let mut byte = [0u8];
+            match self.reader.read(&mut byte).context("Failed to read a run")? {
+                0 if at_end && shift == 0 => return Ok(None),
+                0 => bail!("Truncated run"),
+                _ => {}
+            }
+            if shift > 63 {
+                bail!("Corrupt run: varint is too long");
+            }
+            value |= ((byte[0] & 0x7f) as u64) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(value));
+            }
+            shift += 7;
+        }
+    }
+}
+
+/// Sort `entries` by key and merge the values of equal keys.
+fn sort_and_merge(entries: &mut Vec<(u64, i64)>) {
+    entries.sort_unstable_by_key(|&(key, _)| key);
+    entries.dedup_by(|(key, value), (kept_key, kept_value)| {
+        let same = key == kept_key;
+        if same {
+            *kept_value = kept_value.saturating_add(*value);
+        }
+        same
+    });
+}
+
+/// Merge sorted `runs` into `visit`, once per key with its merged value.
+fn merge_runs(runs: &[PathBuf], mut visit: impl FnMut(u64, i64) -> Result<()>) -> Result<()> {
+    let mut readers = runs.iter().map(|path| RunReader::open(path)).collect::<Result<Vec<_>>>()?;
+    let mut heads = BinaryHeap::with_capacity(readers.len());
+    for (index, reader) in readers.iter_mut().enumerate() {
+        if let Some((key, value)) = reader.next()? {
+            heads.push(Reverse((key, index, value)));
+        }
+    }
+    let mut current: Option<(u64, i64)> = None;
+    while let Some(Reverse((key, index, value))) = heads.pop() {
+        if let Some(next) = readers[index].next()? {
+            heads.push(Reverse((next.0, index, next.1)));
+        }
+        current = match current {
+            Some((current_key, total)) if current_key == key => Some((key, total.saturating_add(value))),
+            Some((current_key, total)) => {
+                visit(current_key, total)?;
+                Some((key, value))
+            }
+            None => Some((key, value)),
+        };
+    }
+    match current {
+        Some((key, total)) => visit(key, total),
+        None => Ok(()),
+    }
+}
+
+/// Keys waiting to be painted, see [`Heatmap::set_preaggregation`].
+pub(crate) struct Preaggregator {
+    mode: Preaggregation,
+    /// Keys held before a spill.
+    budget: usize,
+    parent: PathBuf,
+    pending: Vec<(u64, i64)>,
+    spill: Option<SpillDir>,
+    runs: Vec<PathBuf>,
+    /// Runs written so far, including those merged away.
+    written: usize,
+}
+
+impl Preaggregator {
+    fn push(&mut self, key: u64, value: i64) -> Result<()> {
+        self.pending.push((key, value));
+        if self.pending.len() >= self.budget {
+            sort_and_merge(&mut self.pending);
+            // Merging may have freed enough room not to spill yet
+            if self.pending.len() * 2 > self.budget {
+                self.spill()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_run(&mut self) -> Result<PathBuf> {
+        if self.spill.is_none() {
+            self.spill = Some(SpillDir::create(&self.parent)?);
+        }
+        let dir = self.spill.as_ref().expect("the directory was just made");
+        self.written += 1;
+        Ok(dir.path.join(format!("run-{}", self.written)))
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        sort_and_merge(&mut self.pending);
+        let path = self.next_run()?;
+        let mut writer = RunWriter::create(&path)?;
+        for &(key, value) in &self.pending {
+            writer.write(key, value)?;
+        }
+        writer.finish()?;
+        log::debug!("Spilled {} keys to {}", self.pending.len(), path.display());
+        self.pending.clear();
+        self.runs.push(path);
+        Ok(())
+    }
+
+    /// Call `visit` once for every key in key order with its merged value,
+    /// returning the number of runs spilled.
+    fn finish(mut self, mut visit: impl FnMut(u64, i64) -> Result<()>) -> Result<usize> {
+        if self.runs.is_empty() {
+            sort_and_merge(&mut self.pending);
+            self.pending.iter().try_for_each(|&(key, value)| visit(key, value))?;
+            return Ok(0);
+        }
+        if !self.pending.is_empty() {
+            self.spill()?;
+        }
+        let spilled = self.runs.len();
+        // Merge in passes while there are too many runs to keep open at once
+        while self.runs.len() > MAX_OPEN_RUNS {
+            let runs = std::mem::take(&mut self.runs);
+            for group in runs.chunks(MAX_OPEN_RUNS) {
+                let path = self.next_run()?;
+                let mut writer = RunWriter::create(&path)?;
+                merge_runs(group, |key, value| writer.write(key, value))?;
+                writer.finish()?;
+                for run in group {
+                    let _ = std::fs::remove_file(run);
+                }
+                self.runs.push(path);
+            }
+        }
+        merge_runs(&self.runs, visit)?;
+        Ok(spilled)
+    }
+}
+
+impl Heatmap {
+    /// Merge records by key, their network address and prefix length, and paint
+    /// each key once when [`Heatmap::finish_preaggregation`] is called.
+    ///
+    /// Up to `memory` bytes of keys are held at once; beyond that they are sorted
+    /// and spilled to runs in a directory made in `tmpdir` (the system's temporary
+    /// directory by default), which is removed when painting ends or fails.
+    pub fn set_preaggregation(
+        &mut self,
+        mode: Option<Preaggregation>,
+        memory: u64,
+        tmpdir: Option<&Path>,
+    ) -> Result<(), String> {
+        if mode.is_some() && self.value_mode == ValueMode::Categorical {
+            return Err("Categorical values are labels, so they cannot be preaggregated".to_string());
+        }
+        if mode.is_some() && self.paints_distinct() {
+            return Err("Preaggregation cannot be combined with distinct estimates per pixel".to_string());
+        }
+        self.preaggregator = mode.map(|mode| {
+            Box::new(Preaggregator {
+                mode,
+                budget: (memory / ENTRY_BYTES).max(1) as usize,
+                parent: tmpdir.map_or_else(std::env::temp_dir, Path::to_path_buf),
+                pending: Vec::new(),
+                spill: None,
+                runs: Vec::new(),
+                written: 0,
+            })
+        });
+        Ok(())
+    }
+
+    pub fn preaggregation(&self) -> Option<Preaggregation> {
+        self.preaggregator.as_ref().map(|preaggregator| preaggregator.mode)
+    }
+
+    /// Distinct keys painted by [`Heatmap::finish_preaggregation`].
+    pub fn preaggregated_keys(&self) -> u64 {
+        self.preaggregated_keys
+    }
+
+    /// Hold `record` back to be painted with its key, if records are preaggregated.
+    pub(crate) fn preaggregate(&mut self, record: &Record, value: i32) -> Result<bool> {
+        match &mut self.preaggregator {
+            Some(preaggregator) => preaggregator.push(key(record), value as i64).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Paint every key held back since [`Heatmap::set_preaggregation`], once, and
+    /// stop preaggregating. Call it when input ends, before rendering.
+    pub fn finish_preaggregation(&mut self) -> Result<()> {
+        let Some(preaggregator) = self.preaggregator.take() else {
+            return Ok(());
+        };
+        let mode = preaggregator.mode;
+        let conflicts_before = self.conflicts();
+        let spilled = preaggregator.finish(|key, value| {
+            let net = Ipv4Net::new(Ipv4Addr::from((key >> 8) as u32), (key & 0xff) as u8).expect("keys hold prefix lengths");
+            self.preaggregated_keys += 1;
+            match mode {
+                Preaggregation::Sum => {
+                    let value = value.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+                    self.paint_record(&Record { net, value }, value)?;
+                    match self.line_conflict.take() {
+                        Some((previous, value)) if self.conflict_policy() == ConflictPolicy::Error => {
+                            bail!("{} paints {} over {} in a pixel painted before", net, value, previous)
+                        }
+                        _ => Ok(()),
+                    }
+                }
+                Preaggregation::Distinct => {
+                    self.paint_distinct_key(&net);
+                    Ok(())
+                }
+            }
+        })?;
+        self.warn_conflicts(conflicts_before);
+        if spilled > 0 {
+            log::info!("Merged {} keys from {} runs spilled to disk", self.preaggregated_keys, spilled);
+        }
+        Ok(())
+    }
+
+    /// Add 1 to every pixel `net` covers.
+    fn paint_distinct_key(&mut self, net: &Ipv4Net) {
+        let order = self.geometry().order;
+        let record = Record { net: *net, value: 1 };
+        input::for_each_pixel(self.bits_per_pixel, ValueMode::Raw, &record, |d, _| {
+            let Some((x, y)) = hilbert_d2xy(d, order) else {
+                return;
+            };
+            let Some(index) = self.cell_index(x, y) else {
+                return;
+            };
+            let painted = self.touched[index / 64] & (1 << (index % 64)) != 0;
+            self.touched[index / 64] |= 1 << (index % 64);
+            let cell = &mut self.buffer[y as usize][x as usize];
+            *cell = if painted { cell.saturating_add(1) } else { 1 };
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+
+    fn heatmap(accumulate: bool) -> Heatmap {
+        Heatmap::new(DomainType::Linear, None, None, accumulate, 16, &colorous::MAGMA, ValueMode::Raw, None)
+    }
+
+    /// Lines with many repeated keys across the map.
+    fn input() -> String {
+        (0..3000u32)
+            .map(|i| {
+                let address = Ipv4Addr::from((i * 7919 % 500) << 16 | (i % 3));
+                match i % 5 {
+                    0 => format!("{}/30 {}\n", address, i % 11),
+                    _ => format!("{} {}\n", address, i % 11),
+                }
+            })
+            .collect()
+    }
+
+    fn processed(mode: Preaggregation, memory: u64, tmpdir: &Path) -> Heatmap {
+        let mut heatmap = heatmap(false);
+        heatmap.set_preaggregation(Some(mode), memory, Some(tmpdir)).unwrap();
+        heatmap.process_input_from_string(&input()).unwrap();
+        heatmap.finish_preaggregation().unwrap();
+        heatmap
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ip-heatmap-preaggregate-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_spilled_runs_match_memory() {
+        let dir = scratch_dir("spill");
+        for mode in [Preaggregation::Sum, Preaggregation::Distinct] {
+            let in_memory = processed(mode, DEFAULT_PREAGGREGATE_MEMORY, &dir);
+            // 40 keys at a time spills dozens of runs, and more than can be merged at once
+            let mut spilled = heatmap(false);
+            spilled.set_preaggregation(Some(mode), 40 * ENTRY_BYTES, Some(&dir)).unwrap();
+            spilled.process_input_from_string(&input()).unwrap();
+            let runs = spilled.preaggregator.as_ref().unwrap().runs.len();
+            assert!(runs > MAX_OPEN_RUNS, "{} runs", runs);
+            spilled.finish_preaggregation().unwrap();
+            assert_eq!(spilled.buffer, in_memory.buffer, "{}", mode);
+            assert_eq!(spilled.touched_pixels(), in_memory.touched_pixels());
+            assert_eq!(spilled.preaggregated_keys(), in_memory.preaggregated_keys());
+        }
+        // The runs went with the painting
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sum_matches_accumulating() {
+        let dir = scratch_dir("sum");
+        // Without -C a key painted once with its sum is what accumulating gives
+        // where keys do not overlap
+        let mut accumulated = heatmap(true);
+        accumulated.process_input_from_string("10.0.0.1 2\n10.0.0.1 3\n10.1.0.0/16 4\n").unwrap();
+        let mut preaggregated = heatmap(false);
+        preaggregated.set_preaggregation(Some(Preaggregation::Sum), 16, Some(&dir)).unwrap();
+        preaggregated.process_input_from_string("10.0.0.1 2\n10.0.0.1 3\n10.1.0.0/16 4\n").unwrap();
+        preaggregated.finish_preaggregation().unwrap();
+        assert_eq!(preaggregated.buffer, accumulated.buffer);
+        assert_eq!(preaggregated.preaggregated_keys(), 2);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_distinct_counts_keys_once() {
+        let mut heatmap = heatmap(false);
+        heatmap.set_preaggregation(Some(Preaggregation::Distinct), DEFAULT_PREAGGREGATE_MEMORY, None).unwrap();
+        heatmap.process_input_from_string("10.0.0.1 5\n10.0.0.1 9\n10.0.0.2 1\n10.0.0.0/24 1\n11.0.0.1 4\n").unwrap();
+        heatmap.finish_preaggregation().unwrap();
+        let (x, y) = heatmap.ip_to_xy(u32::from(Ipv4Addr::new(10, 0, 0, 1))).unwrap();
+        assert_eq!(heatmap.buffer[y as usize][x as usize], 3);
+        assert_eq!(heatmap.preaggregated_keys(), 4);
+        assert_eq!(heatmap.value_range(), (0, 3));
+    }
+
+    #[test]
+    fn test_run_encoding() {
+        let dir = scratch_dir("encoding");
+        let path = dir.join("run");
+        let entries = [(0, 0), (1, -1), (300, i64::MAX), (u64::MAX >> 1, i64::MIN), (u64::MAX, 7)];
+        let mut writer = RunWriter::create(&path).unwrap();
+        for (key, value) in entries {
+            writer.write(key, value).unwrap();
+        }
+        writer.finish().unwrap();
+        let mut reader = RunReader::open(&path).unwrap();
+        for entry in entries {
+            assert_eq!(reader.next().unwrap(), Some(entry));
+        }
+        assert_eq!(reader.next().unwrap(), None);
+        // A run cut short mid-entry is an error, not a short read
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let mut reader = RunReader::open(&path).unwrap();
+        assert!((0..entries.len()).try_for_each(|_| reader.next().map(drop)).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_preaggregation_names() {
+        assert_eq!("Distinct".parse::<Preaggregation>(), Ok(Preaggregation::Distinct));
+        assert_eq!(Preaggregation::Sum.to_string(), "sum");
+        assert!("max".parse::<Preaggregation>().is_err());
+    }
+}
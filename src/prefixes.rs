@@ -0,0 +1,81 @@
+use crate::Heatmap;
+use crate::hilbert::hilbert_d2xy;
+use ipnet::Ipv4Net;
+use std::net::Ipv4Addr;
+
+impl Heatmap {
+    /// The smallest set of prefixes covering every painted pixel whose value is at
+    /// least `threshold` (every painted pixel when `None`), sorted by address.
+    ///
+    /// Each pixel stands for a whole /(32 - bits_per_pixel), so the cover is only as
+    /// fine as the image: a single painted address yields the prefix of its pixel.
+    pub fn covered_prefixes(&self, threshold: Option<i32>) -> Vec<Ipv4Net> {
+        let geometry = self.geometry();
+        let (order, prefix_len) = (geometry.order, geometry.prefix_len_per_pixel);
+        let pixels = geometry.pixels();
+        let mut prefixes = Vec::new();
+        // Walking the curve visits the pixels in address order
+        for d in 0..pixels {
+            let Some((x, y)) = hilbert_d2xy(d, order) else {
+                continue;
+            };
+            let (x, y) = (x as usize, y as usize);
+            if !self.is_touched(x, y) || threshold.is_some_and(|threshold| self.buffer[y][x] < threshold) {
+                continue;
+            }
+            let network = Ipv4Addr::from(((d << self.bits_per_pixel) & 0xffff_ffff) as u32);
+            prefixes.push(Ipv4Net::new(network, prefix_len).expect("prefix length is at most 32"));
+        }
+        Ipv4Net::aggregate(&prefixes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    fn nets(prefixes: &[&str]) -> Vec<Ipv4Net> {
+        prefixes.iter().map(|prefix| prefix.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_siblings_merge() {
+        // Four adjacent /16s make a /14; the lone address stays a /16 pixel
+        let hm = heatmap(16, "10.0.0.0/16\n10.1.0.0/16\n10.2.0.0/15\n192.168.1.1\n");
+        assert_eq!(hm.covered_prefixes(None), nets(&["10.0.0.0/14", "192.168.0.0/16"]));
+    }
+
+    #[test]
+    fn test_adjacent_but_unaligned_prefixes_stay_apart() {
+        // 10.1/16 and 10.2/16 are adjacent but not siblings
+        let hm = heatmap(16, "10.1.0.0/16\n10.2.0.0/16\n");
+        assert_eq!(hm.covered_prefixes(None), nets(&["10.1.0.0/16", "10.2.0.0/16"]));
+        let whole = heatmap(16, "0.0.0.0/0\n");
+        assert_eq!(whole.covered_prefixes(None), nets(&["0.0.0.0/0"]));
+        assert!(heatmap(16, "").covered_prefixes(None).is_empty());
+    }
+
+    #[test]
+    fn test_threshold() {
+        let hm = heatmap(24, "10.0.0.0/8 5\n11.0.0.0/8 1\n12.0.0.0/8 9\n13.0.0.0/8 2\n");
+        assert_eq!(hm.covered_prefixes(None), nets(&["10.0.0.0/7", "12.0.0.0/7"]));
+        assert_eq!(hm.covered_prefixes(Some(5)), nets(&["10.0.0.0/8", "12.0.0.0/8"]));
+        assert!(hm.covered_prefixes(Some(10)).is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+//! Small previews of the buffer written every so often during a long run, to check
+//! that the data looks right before the run ends.
+
+use crate::Heatmap;
+use crate::downsample::Aggregation;
+use crate::output::save_png;
+use crate::render::RenderOptions;
+use std::str::FromStr;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Lines painted between looks at the clock.
+const LINES_PER_CHECK: usize = 1024;
+
+/// A preview to write while input is processed: `path:size:seconds`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreviewSpec {
+    pub output: String,
+    /// Side of the preview in pixels, a power of two.
+    pub size: u32,
+    pub interval: Duration,
+}
+
+impl FromStr for PreviewSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid preview spec: {}. Use path:size:seconds, e.g. preview.png:512:60", spec);
+        let mut parts = spec.rsplitn(3, ':');
+        let (Some(seconds), Some(size), Some(output)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        if output.is_empty() {
+            return Err(invalid());
+        }
+        let size = match size.parse::<u32>() {
+            Ok(size) if size.is_power_of_two() => size,
+            _ => return Err(format!("Invalid preview size: {}. Use a power of two such as 512", size)),
+        };
+        let interval = match seconds.parse::<f64>() {
+            Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Duration::from_secs_f64(seconds),
+            _ => return Err(format!("Invalid preview interval: {}. Use a number of seconds greater than 0", seconds)),
+        };
+        Ok(PreviewSpec { output: output.to_string(), size, interval })
+    }
+}
+
+/// Writes a downsampled preview of a heatmap every [`PreviewSpec::interval`].
+///
+/// The painting thread only takes a [`crate::HeatmapSnapshot`], which copies the
+/// chunks of the buffer painted since the last preview; the preview is reduced,
+/// coloured, encoded and written on a thread of its own. A preview that falls due
+/// while the last one is still being written is skipped rather than queued.
+#[derive(Debug)]
+pub struct Preview {
+    spec: PreviewSpec,
+    options: RenderOptions,
+    aggregation: Aggregation,
+    due: Instant,
+    writing: Option<JoinHandle<()>>,
+    written: u64,
+    skipped: u64,
+}
+
+impl Preview {
+    /// A preview rendered with `options`, reducing blocks of cells by `aggregation`.
+    /// The first is written one interval from now.
+    pub fn new(spec: PreviewSpec, options: RenderOptions, aggregation: Aggregation) -> Self {
+        let due = Instant::now() + spec.interval;
+        Preview { spec, options, aggregation, due, writing: None, written: 0, skipped: 0 }
+    }
+
+    /// Previews started so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Previews skipped because the one before was still being written.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Start writing a preview of `heatmap` if one is due.
+    pub fn tick(&mut self, heatmap: &mut Heatmap) {
+        let now = Instant::now();
+        if now < self.due {
+            return;
+        }
+        self.due = now + self.spec.interval;
+        if self.writing.as_ref().is_some_and(|writing| !writing.is_finished()) {
+            self.skipped += 1;
+            log::debug!("Skipping a preview, the last one is still being written");
+            return;
+        }
+        if let Some(writing) = self.writing.take() {
+            let _ = writing.join();
+        }
+        let snapshot = heatmap.snapshot();
+        let size = self.spec.size.min(snapshot.image_size());
+        let (output, options, aggregation) = (self.spec.output.clone(), self.options.clone(), self.aggregation);
+        log::debug!("Writing a preview of {} lines to {}", snapshot.lines_processed(), output);
+        self.written += 1;
+        self.writing = Some(std::thread::spawn(move || {
+            let image = snapshot
+                .downsample(size, aggregation)
+                .and_then(|preview| Ok((preview.render(&options).map_err(anyhow::Error::msg)?, preview)));
+            let (image, preview) = match image {
+                Ok(rendered) => rendered,
+                Err(err) => {
+                    log::warn!("Failed to render a preview: {:#}", err);
+                    return;
+                }
+            };
+            if let Err(err) = save_png(&output, &image, &preview.png_metadata(&options), &options.png) {
+                log::warn!("Failed to write a preview: {:#}", err);
+            }
+        }));
+    }
+
+    /// Wait for a preview still being written.
+    pub fn finish(&mut self) {
+        if let Some(writing) = self.writing.take() {
+            let _ = writing.join();
+        }
+    }
+}
+
+impl Drop for Preview {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl Heatmap {
+    /// Write `preview` while input is processed, see [`Preview`]. Input read in
+    /// parallel is previewed only between calls.
+    pub fn set_preview(&mut self, preview: Option<Preview>) {
+        self.preview = preview.map(Box::new);
+    }
+
+    /// The preview, if any, to see how many were written.
+    pub fn preview(&self) -> Option<&Preview> {
+        self.preview.as_deref()
+    }
+
+    /// Write a preview if one is due, for callers waiting on slow input between
+    /// lines. Processing calls this itself.
+    pub fn tick_preview(&mut self) {
+        if let Some(mut preview) = self.preview.take() {
+            preview.tick(self);
+            self.preview = Some(preview);
+        }
+    }
+
+    /// [`Heatmap::tick_preview`] every [`LINES_PER_CHECK`] lines.
+    pub(crate) fn tick_preview_at(&mut self, line_number: usize) {
+        if self.preview.is_some() && line_number.is_multiple_of(LINES_PER_CHECK) {
+            self.tick_preview();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    #[test]
+    fn test_preview_spec() {
+        let spec: PreviewSpec = "runs/preview.png:512:60".parse().unwrap();
+        assert_eq!(spec, PreviewSpec { output: "runs/preview.png".to_string(), size: 512, interval: Duration::from_secs(60) });
+        assert_eq!("c:/p.png:64:0.25".parse::<PreviewSpec>().unwrap().output, "c:/p.png");
+        for invalid in ["preview.png:512", "preview.png:500:60", "preview.png:512:0", ":512:60", "p.png:512:soon"] {
+            assert!(invalid.parse::<PreviewSpec>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_previews_are_written_when_due() {
+        let output = std::env::temp_dir().join(format!("ip-heatmap-preview-{}.png", std::process::id()));
+        let spec = PreviewSpec { output: output.to_str().unwrap().to_string(), size: 64, interval: Duration::from_millis(500) };
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.set_preview(Some(Preview::new(spec, RenderOptions::default(), Aggregation::Max)));
+        heatmap.process_input_from_string("10.0.0.1 5\n").unwrap();
+        // Not due yet
+        heatmap.tick_preview();
+        assert_eq!(heatmap.preview().unwrap().written(), 0);
+        std::thread::sleep(Duration::from_millis(550));
+        heatmap.tick_preview();
+        assert_eq!(heatmap.preview().unwrap().written(), 1);
+        heatmap.set_preview(None);
+        let preview = image::open(&output).unwrap();
+        assert_eq!((preview.width(), preview.height()), (64, 64));
+        std::fs::remove_file(&output).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use crate::Heatmap;
+use crate::endian;
+use crate::hilbert::hilbert_d2xy;
+use crate::render::RenderOptions;
+use image::{Rgba, RgbaImage};
+use std::io::{Result, Write};
+use std::net::Ipv4Addr;
+
+impl Heatmap {
+    /// The cells in Hilbert curve order, i.e. in order of address: entry `d` holds the
+    /// pixel of addresses `d << bits_per_pixel` onwards.
+    pub fn profile(&self) -> Vec<i32> {
+        let geometry = self.geometry();
+        (0..geometry.pixels())
+            .map(|d| {
+                let (x, y) = hilbert_d2xy(d, geometry.order).expect("d is on the curve");
+                self.buffer[y as usize][x as usize]
+            })
+            .collect()
+    }
+
+    /// Write the profile as CSV with the offset, first address and value of each pixel.
+    pub fn write_profile_csv<W: Write>(&self, mut writer: W) -> Result<()> {
+        writeln!(writer, "offset,address,value")?;
+        for (d, value) in self.profile().into_iter().enumerate() {
+            let address = Ipv4Addr::from(((d as u64) << self.bits_per_pixel) as u32);
+            writeln!(writer, "{},{},{}", d, address, value)?;
+        }
+        Ok(())
+    }
+
+    /// Write the painted cells as CSV in address order: the prefix each pixel covers,
+    /// its value and its position in the image.
+    pub fn write_cells_csv<W: Write>(&self, mut writer: W) -> Result<()> {
+        let geometry = self.geometry();
+        writeln!(writer, "cidr,value,x,y")?;
+        for d in 0..geometry.pixels() {
+            let (x, y) = hilbert_d2xy(d, geometry.order).expect("d is on the curve");
+            if !self.is_touched(x as usize, y as usize) {
+                continue;
+            }
+            let address = Ipv4Addr::from((d << self.bits_per_pixel) as u32);
+            let value = self.buffer[y as usize][x as usize];
+            writeln!(writer, "{}/{},{},{},{}", address, geometry.prefix_len_per_pixel, value, x, y)?;
+        }
+        Ok(())
+    }
+
+    /// Write the profile as a one-dimensional NumPy `.npy` array of little-endian i32.
+    pub fn write_profile_npy<W: Write>(&self, mut writer: W) -> Result<()> {
+        let profile = self.profile();
+        let mut header = format!("{{'descr': '<i4', 'fortran_order': False, 'shape': ({},), }}", profile.len());
+        // Magic, version and length take 10 bytes; the header ends in a newline and
+        // pads the whole preamble to a multiple of 64 bytes
+        let padding = (64 - (10 + header.len() + 1) % 64) % 64;
+        header.extend(std::iter::repeat_n(' ', padding));
+        header.push('\n');
+        writer.write_all(b"\x93NUMPY\x01\x00")?;
+        writer.write_all(&(header.len() as u16).to_le_bytes())?;
+        writer.write_all(header.as_bytes())?;
+        let mut bytes = Vec::new();
+        for values in profile.chunks(self.image_size() as usize) {
+            bytes.clear();
+            endian::extend_i32s(&mut bytes, values);
+            writer.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Render the profile as a horizontal strip `width` pixels wide (a power of two), each
+    /// column coloured by the largest value of its run of curve positions.
+    pub fn render_profile_strip(
+        &self,
+        options: &RenderOptions,
+        width: u32,
+        height: u32,
+    ) -> std::result::Result<RgbaImage, &'static str> {
+        let profile = self.profile();
+        if !width.is_power_of_two() || width as usize > profile.len() {
+            return Err("Profile strip width must be a power of two no larger than the profile");
+        }
+        let domain = self.calculate_domain(options)?;
+        let run = profile.len() / width as usize;
+        let mut image = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 0]));
+        for (x, values) in profile.chunks_exact(run).enumerate() {
+            let value = values.iter().copied().max().unwrap_or(0);
+            if let Some(scaled) = domain.scale(value.into()) {
+                let scaled = if options.gamma == 1.0 { scaled } else { scaled.powf(options.gamma) };
+                let [r, g, b] = options.palette.eval(scaled);
+                for y in 0..height {
+                    image.put_pixel(x as u32, y, Rgba([r, g, b, 255]));
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_profile_keeps_the_buffer_sum() {
+        let hm = heatmap(16, "10.0.0.1 5\n192.168.0.0/16 3\n8.8.8.8 2\n10.0.0.2 4\n");
+        let profile = hm.profile();
+        assert_eq!(profile.len(), 256 * 256);
+        let sum = |values: &[i32]| values.iter().map(|&v| v as i64).sum::<i64>();
+        assert_eq!(sum(&profile), sum(hm.buffer.cells()));
+    }
+
+    #[test]
+    fn test_cells_csv_lists_painted_pixels_by_address() {
+        let hm = heatmap(16, "192.168.1.1 3
+10.0.0.0/15 2
+10.0.0.1 4
+");
+        let mut csv = Vec::new();
+        hm.write_cells_csv(&mut csv).unwrap();
+        let lines: Vec<String> = String::from_utf8(csv).unwrap().lines().map(|line| line.to_string()).collect();
+        let (x, y) = hilbert_d2xy(0xc0a8, 8).unwrap();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "cidr,value,x,y");
+        assert!(lines[1].starts_with("10.0.0.0/16,6,"), "{}", lines[1]);
+        assert!(lines[2].starts_with("10.1.0.0/16,2,"), "{}", lines[2]);
+        assert_eq!(lines[3], format!("192.168.0.0/16,3,{},{}", x, y));
+    }
+
+    #[test]
+    fn test_hot_prefix_is_contiguous() {
+        // At bits_per_pixel 12 a /16 is 16 pixels, from offset 10.0.0.0 >> 12
+        let hm = heatmap(12, "10.0.0.0/16 7\n");
+        let profile = hm.profile();
+        let start = 10 << 12;
+        for (d, &value) in profile.iter().enumerate() {
+            let expected = if (start..start + 16).contains(&d) { 7 } else { 0 };
+            assert_eq!(value, expected, "offset {}", d);
+        }
+    }
+
+    #[test]
+    fn test_csv_and_npy() {
+        let hm = heatmap(28, "16.0.0.1 3\n");
+        let mut csv = Vec::new();
+        hm.write_profile_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 17);
+        assert_eq!(csv.lines().nth(2), Some("1,16.0.0.0,3"));
+
+        let mut npy = Vec::new();
+        hm.write_profile_npy(&mut npy).unwrap();
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        assert!(std::str::from_utf8(&npy[10..10 + header_len]).unwrap().contains("'shape': (16,)"));
+        let data = &npy[10 + header_len..];
+        assert_eq!(data.len(), 16 * 4);
+        assert_eq!(i32::from_le_bytes(data[4..8].try_into().unwrap()), 3);
+    }
+
+    #[test]
+    fn test_profile_strip() {
+        let hm = heatmap(16, "10.0.0.0/8 5\n");
+        let options = hm.render_options();
+        let strip = hm.render_profile_strip(&options, 256, 8).unwrap();
+        assert_eq!(strip.dimensions(), (256, 8));
+        // 10.0.0.0/8 is the 11th of 256 columns
+        assert_eq!(strip.get_pixel(10, 0).0[3], 255);
+        assert_eq!(strip.get_pixel(11, 0).0[3], 0);
+        assert!(hm.render_profile_strip(&options, 300, 8).is_err());
+    }
+}
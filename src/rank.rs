@@ -0,0 +1,141 @@
+//! How hot a prefix of interest is relative to every other prefix of its length.
+
+use crate::hilbert::hilbert_d2xy;
+use crate::label::Label;
+use crate::{Heatmap, ValueMode};
+use anyhow::{Result, bail};
+use ipnet::Ipv4Net;
+
+/// Where one prefix stands among all prefixes of its length, see
+/// [`Heatmap::rank_prefixes`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrefixRank {
+    pub net: Ipv4Net,
+    /// Summed value of the prefix's pixels.
+    pub total: i64,
+    /// 1 for the hottest prefix. Prefixes with equal totals share a rank, as in
+    /// sports standings.
+    pub rank: u64,
+    /// Number of prefixes of the length, ranked or not, e.g. 2^24 for a /24.
+    pub prefixes: u64,
+    /// Percentage of those prefixes whose total is at most this one's.
+    pub percentile: f64,
+}
+
+impl PrefixRank {
+    /// One line such as `203.0.113.0/24: total 1234, rank 17 of 16777216, percentile 99.9999`.
+    pub fn to_text(&self) -> String {
+        format!(
+            "{}: total {}, rank {} of {}, percentile {:.4}",
+            self.net, self.total, self.rank, self.prefixes, self.percentile
+        )
+    }
+
+    /// A label for the prefix on the map, its rank and percentile.
+    pub fn label(&self) -> Label {
+        Label { net: self.net, text: format!("#{} p{:.2}", self.rank, self.percentile) }
+    }
+}
+
+impl Heatmap {
+    /// The rank and percentile of each of `targets` among all prefixes of its length.
+    ///
+    /// The totals of each length are compared as they are summed rather than kept,
+    /// so ranking a /24 walks the map once without holding 2^24 totals. A prefix
+    /// may not be finer than the pixel resolution.
+    pub fn rank_prefixes(&self, targets: &[Ipv4Net]) -> Result<Vec<PrefixRank>> {
+        if self.value_mode == ValueMode::Categorical && !targets.is_empty() {
+            bail!("Categorical values are labels, so prefixes cannot be ranked by them");
+        }
+        for target in targets {
+            self.check_prefix_resolution(target.prefix_len())?;
+        }
+        let mut ranks: Vec<PrefixRank> = targets
+            .iter()
+            .map(|&net| PrefixRank { net: net.trunc(), total: 0, rank: 1, prefixes: 0, percentile: 0.0 })
+            .collect();
+        // Every prefix is compared to the targets, so their totals come first
+        let geometry = self.geometry();
+        for rank in &mut ranks {
+            let first_d = u32::from(rank.net.network()) as u64 >> self.bits_per_pixel;
+            let count = 1u64 << (geometry.prefix_len_per_pixel - rank.net.prefix_len());
+            rank.total = (first_d..first_d + count)
+                .filter_map(|d| hilbert_d2xy(d, geometry.order))
+                .map(|(x, y)| self.buffer[y as usize][x as usize] as i64)
+                .sum();
+        }
+        let mut lengths: Vec<u8> = ranks.iter().map(|rank| rank.net.prefix_len()).collect();
+        lengths.sort_unstable();
+        lengths.dedup();
+        for prefix_len in lengths {
+            let mut at_most = vec![0u64; ranks.len()];
+            self.for_each_prefix_total(prefix_len, |_, total| {
+                for (i, rank) in ranks.iter_mut().enumerate().filter(|(_, rank)| rank.net.prefix_len() == prefix_len) {
+                    rank.prefixes += 1;
+                    match total > rank.total {
+                        true => rank.rank += 1,
+                        false => at_most[i] += 1,
+                    }
+                }
+            })?;
+            for (i, rank) in ranks.iter_mut().enumerate().filter(|(_, rank)| rank.net.prefix_len() == prefix_len) {
+                rank.percentile = at_most[i] as f64 * 100.0 / rank.prefixes as f64;
+            }
+        }
+        Ok(ranks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+
+    fn heatmap(input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    fn nets(prefixes: &[&str]) -> Vec<Ipv4Net> {
+        prefixes.iter().map(|prefix| prefix.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_ranks_follow_a_known_ordering() {
+        // The /16s of 10.0.0.0/8 hold 1 to 100, 10.99/16 the hottest
+        let input: String = (0..100).map(|i| format!("10.{}.0.1 {}\n", i, i + 1)).collect();
+        let hm = heatmap(&input);
+        let ranks = hm.rank_prefixes(&nets(&["10.99.0.0/16", "10.0.0.0/16", "10.50.0.0/16", "11.0.0.0/16"])).unwrap();
+        assert_eq!((ranks[0].total, ranks[0].rank, ranks[0].prefixes), (100, 1, 65536));
+        assert_eq!(ranks[0].percentile, 100.0);
+        assert_eq!((ranks[1].total, ranks[1].rank), (1, 100));
+        assert_eq!((ranks[2].total, ranks[2].rank), (51, 50));
+        // Everything below 10.50/16 and the empty /16s are at most its total
+        assert_eq!(ranks[2].percentile, (65536.0 - 49.0) * 100.0 / 65536.0);
+        // The empty /16s share the rank after the last painted one
+        assert_eq!((ranks[3].total, ranks[3].rank), (0, 101));
+        assert_eq!(ranks[3].to_text(), format!("11.0.0.0/16: total 0, rank 101 of 65536, percentile {:.4}", ranks[3].percentile));
+        assert_eq!(ranks[0].label().text, "#1 p100.00");
+    }
+
+    #[test]
+    fn test_ties_and_lengths_are_ranked_apart() {
+        let hm = heatmap("10.0.0.1 5\n11.0.0.1 5\n12.0.0.1 9\n");
+        let ranks = hm.rank_prefixes(&nets(&["10.0.0.0/8", "11.0.0.0/8", "11.0.0.0/16", "12.1.2.3/12"])).unwrap();
+        assert_eq!(ranks.iter().map(|rank| rank.rank).collect::<Vec<_>>(), [2, 2, 2, 1]);
+        assert_eq!(ranks[3].net, "12.0.0.0/12".parse().unwrap());
+        assert_eq!(ranks.iter().map(|rank| rank.prefixes).collect::<Vec<_>>(), [256, 256, 65536, 4096]);
+        assert!(hm.rank_prefixes(&nets(&["10.0.0.0/24"])).is_err());
+        assert!(hm.rank_prefixes(&[]).unwrap().is_empty());
+    }
+}
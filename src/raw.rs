@@ -0,0 +1,165 @@
+//! The `raw-u32v` binary input format: already parsed records that are read back
+//! without tokenizing or validating text.
+//!
+//! A file starts with the 8 byte magic `IPHMRAW1` followed by 9 byte records: the
+//! network address as a little-endian u32, the prefix length as a byte and the value
+//! as a little-endian i32.
+
+use crate::endian;
+use crate::input::{CidrHostBits, ParseOptions, ParsedLine, Record, ValueSource};
+use crate::rejects::RejectReason;
+use anyhow::{Context, Result, bail};
+use ipnet::Ipv4Net;
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+
+pub const RAW_MAGIC: &[u8; 8] = b"IPHMRAW1";
+const RECORD_LEN: usize = 9;
+
+/// Writes records in the `raw-u32v` format.
+pub(crate) struct RawWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> RawWriter<W> {
+    pub fn new(mut writer: W) -> std::io::Result<Self> {
+        writer.write_all(RAW_MAGIC)?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, net: &Ipv4Net, value: i32) -> std::io::Result<()> {
+        let mut record = [0u8; RECORD_LEN];
+        record[..4].copy_from_slice(&u32::from(net.addr()).to_le_bytes());
+        record[4] = net.prefix_len();
+        record[5..].copy_from_slice(&value.to_le_bytes());
+        self.writer.write_all(&record)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Call `on_record` with the number and parse outcome of every record of `reader`,
+/// applying the options that still mean something for parsed records: sampling,
+/// ignored values, the value source and rejected host bits.
+pub(crate) fn for_each_raw_record<R: Read>(
+    mut reader: R,
+    options: &ParseOptions,
+    mut on_record: impl FnMut(usize, ParsedLine) -> Result<()>,
+) -> Result<()> {
+    let mut magic = [0u8; 8];
+    let read = read_full(&mut reader, &mut magic)?;
+    if read == 0 {
+        return Ok(());
+    }
+    if read < magic.len() || &magic != RAW_MAGIC {
+        bail!("Input is not in the raw-u32v format (it should start with {:?})", "IPHMRAW1");
+    }
+    let mut record = [0u8; RECORD_LEN];
+    let mut number = 0;
+    loop {
+        match read_full(&mut reader, &mut record)? {
+            0 => return Ok(()),
+            RECORD_LEN => {}
+            read => bail!("Truncated raw-u32v record {}: {} of {} bytes", number + 1, read, RECORD_LEN),
+        }
+        number += 1;
+        if let Some(sampling) = &options.sampling
+            && !sampling.keeps(number)
+        {
+            on_record(number, ParsedLine::Unsampled)?;
+            continue;
+        }
+        on_record(number, parse_record(&record, options))?;
+    }
+}
+
+fn parse_record(record: &[u8; RECORD_LEN], options: &ParseOptions) -> ParsedLine {
+    let address = Ipv4Addr::from(endian::u32_at(record, 0));
+    let value = endian::i32_at(record, 5);
+    let Ok(net) = Ipv4Net::new(address, record[4]) else {
+        return ParsedLine::Rejected(RejectReason::InvalidCidr, format!("prefix length {}", record[4]));
+    };
+    if options.cidr_host_bits == CidrHostBits::Reject && net.addr() != net.network() {
+        return ParsedLine::Rejected(RejectReason::CidrHostBits, format!("network address is {}", net.trunc()));
+    }
+    if options.ignore_values.iter().any(|ignored| *ignored == value.to_string()) {
+        return ParsedLine::Ignored;
+    }
+    let value = match options.value_source {
+        ValueSource::Column => value,
+        ValueSource::PrefixLen => net.prefix_len() as i32,
+        ValueSource::Count => 1,
+    };
+    ParsedLine::Record(Record { net, value })
+}
+
+/// Fill `buffer` unless the input ends first, returning the bytes read.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(length) => read += length,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).context("Failed to read raw-u32v input"),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(bytes: &[u8], options: &ParseOptions) -> Result<Vec<(usize, Option<Record>)>> {
+        let mut records = Vec::new();
+        for_each_raw_record(bytes, options, |number, parsed| {
+            records.push((number, if let ParsedLine::Record(record) = parsed { Some(record) } else { None }));
+            Ok(())
+        })?;
+        Ok(records)
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let mut writer = RawWriter::new(Vec::new()).unwrap();
+        writer.write(&"10.0.0.1/32".parse().unwrap(), 5).unwrap();
+        writer.write(&"192.168.0.0/16".parse().unwrap(), -3).unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len(), 8 + 2 * RECORD_LEN);
+        // The layout is little-endian whatever the host
+        assert_eq!(&bytes[8..17], &[1, 0, 0, 10, 32, 5, 0, 0, 0]);
+
+        let records = records(&bytes, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (1, Some(Record { net: "10.0.0.1/32".parse().unwrap(), value: 5 })),
+                (2, Some(Record { net: "192.168.0.0/16".parse().unwrap(), value: -3 })),
+            ]
+        );
+
+        let options = ParseOptions {
+            ignore_values: vec!["-3".to_string()],
+            value_source: ValueSource::PrefixLen,
+            ..ParseOptions::default()
+        };
+        let records = self::records(&bytes, &options).unwrap();
+        assert_eq!(records[0].1.unwrap().value, 32);
+        assert_eq!(records[1], (2, None));
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert!(records(b"", &ParseOptions::default()).unwrap().is_empty());
+        assert!(records(b"10.0.0.1 5\n", &ParseOptions::default()).is_err());
+        let mut truncated = RAW_MAGIC.to_vec();
+        truncated.extend([1, 0, 0, 10, 32]);
+        assert!(records(&truncated, &ParseOptions::default()).is_err());
+        let mut bad_prefix = RAW_MAGIC.to_vec();
+        bad_prefix.extend([1, 0, 0, 10, 33, 5, 0, 0, 0]);
+        assert_eq!(records(&bad_prefix, &ParseOptions::default()).unwrap(), vec![(1, None)]);
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// Default number of rejected lines kept as samples.
+pub const DEFAULT_MAX_REJECT_SAMPLES: usize = 100;
+
+/// Rejected line content is truncated to this many characters.
+const MAX_CONTENT_CHARS: usize = 120;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    InvalidCidr,
+    InvalidIntegerIp,
+    InvalidIp,
+    LineTooLong,
+    Ipv6,
+    CidrHostBits,
+    /// A malformed brace group, see [`crate::ParseOptions::expand_braces`].
+    InvalidBraces,
+    /// A missing or malformed timestamp, see [`crate::ParseOptions::time_window`].
+    InvalidTimestamp,
+    /// A row of `--format cells` without an integer value.
+    InvalidCell,
+    /// A reverse-DNS name whose labels are not 1 to 4 octets, see
+    /// [`crate::parse_in_addr`].
+    InvalidInAddr,
+    /// A value column that is not an integer, such as `2.5` or `n/a`.
+    InvalidValue,
+    /// A value column outside the range of cell values, such as
+    /// `99999999999999999999`.
+    ValueOutOfRange,
+}
+
+impl RejectReason {
+    pub const ALL: [RejectReason; 12] = [
+        RejectReason::InvalidCidr,
+        RejectReason::InvalidIntegerIp,
+        RejectReason::InvalidIp,
+        RejectReason::LineTooLong,
+        RejectReason::Ipv6,
+        RejectReason::CidrHostBits,
+        RejectReason::InvalidBraces,
+        RejectReason::InvalidTimestamp,
+        RejectReason::InvalidCell,
+        RejectReason::InvalidInAddr,
+        RejectReason::InvalidValue,
+        RejectReason::ValueOutOfRange,
+    ];
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::InvalidCidr => write!(f, "invalid CIDR"),
+            RejectReason::InvalidIntegerIp => write!(f, "invalid integer IP"),
+            RejectReason::InvalidIp => write!(f, "invalid IP address"),
+            RejectReason::LineTooLong => write!(f, "line too long"),
+            RejectReason::Ipv6 => write!(f, "IPv6 address"),
+            RejectReason::CidrHostBits => write!(f, "CIDR host bits set"),
+            RejectReason::InvalidBraces => write!(f, "invalid brace group"),
+            RejectReason::InvalidTimestamp => write!(f, "invalid timestamp"),
+            RejectReason::InvalidCell => write!(f, "invalid cell row"),
+            RejectReason::InvalidInAddr => write!(f, "invalid in-addr.arpa name"),
+            RejectReason::InvalidValue => write!(f, "invalid value"),
+            RejectReason::ValueOutOfRange => write!(f, "value out of range"),
+        }
+    }
+}
+
+/// What to do with input lines that cannot be painted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ErrorPolicy {
+    /// Count the line without logging it.
+    Skip,
+    /// Count the line and log a warning.
+    #[default]
+    Count,
+    /// Abort processing.
+    Fail,
+}
+
+impl ErrorPolicy {
+    pub const ALL: [ErrorPolicy; 3] = [ErrorPolicy::Skip, ErrorPolicy::Count, ErrorPolicy::Fail];
+}
+
+impl std::str::FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(ErrorPolicy::Skip),
+            "count" => Ok(ErrorPolicy::Count),
+            "fail" => Ok(ErrorPolicy::Fail),
+            _ => Err(format!("Invalid error policy: {}. Use 'skip', 'count', or 'fail'", s)),
+        }
+    }
+}
+
+impl Display for ErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorPolicy::Skip => write!(f, "skip"),
+            ErrorPolicy::Count => write!(f, "count"),
+            ErrorPolicy::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// A single input line that could not be painted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reject {
+    /// 1-based line number in the input.
+    pub line_number: usize,
+    /// The offending line, truncated to a bounded length.
+    pub content: String,
+    pub reason: RejectReason,
+    /// Parser error message for the offending token.
+    pub message: String,
+}
+
+impl Display for Reject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}: {} ({}): {}",
+            self.line_number, self.reason, self.message, self.content
+        )
+    }
+}
+
+/// Records rejected input lines.
+///
+/// Every reject is counted, but only the first `max_samples` are kept so that
+/// garbage input cannot grow memory without bound.
+#[derive(Clone, Debug)]
+pub struct RejectLog {
+    max_samples: usize,
+    samples: Vec<Reject>,
+    total: u64,
+    by_reason: HashMap<RejectReason, u64>,
+}
+
+impl Default for RejectLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_REJECT_SAMPLES)
+    }
+}
+
+impl RejectLog {
+    pub fn new(max_samples: usize) -> Self {
+        Self {
+            max_samples,
+            samples: Vec::new(),
+            total: 0,
+            by_reason: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, line_number: usize, content: &str, reason: RejectReason, message: String) {
+        self.total += 1;
+        *self.by_reason.entry(reason).or_insert(0) += 1;
+        if self.samples.len() < self.max_samples {
+            self.samples.push(Reject {
+                line_number,
+                content: truncate_content(content),
+                reason,
+                message,
+            });
+        }
+    }
+
+    /// Total number of rejected lines, including those not kept as samples.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Number of rejected lines with the given reason.
+    pub fn count(&self, reason: RejectReason) -> u64 {
+        self.by_reason.get(&reason).copied().unwrap_or(0)
+    }
+
+    pub fn samples(&self) -> &[Reject] {
+        &self.samples
+    }
+
+    pub fn max_samples(&self) -> usize {
+        self.max_samples
+    }
+
+    /// Remove and return the recorded samples. The total count is kept.
+    pub fn take_samples(&mut self) -> Vec<Reject> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+fn truncate_content(content: &str) -> String {
+    match content.char_indices().nth(MAX_CONTENT_CHARS) {
+        Some((idx, _)) => format!("{}...", &content[..idx]),
+        None => content.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reject_log_caps_samples_but_counts_all() {
+        let mut log = RejectLog::new(2);
+        for i in 1..=5 {
+            log.record(i, "garbage", RejectReason::InvalidIp, "bad".to_string());
+        }
+        assert_eq!(log.total(), 5);
+        assert_eq!(log.samples().len(), 2);
+        assert_eq!(log.samples()[0].line_number, 1);
+        assert_eq!(log.samples()[1].line_number, 2);
+        assert_eq!(log.count(RejectReason::InvalidIp), 5);
+        assert_eq!(log.count(RejectReason::Ipv6), 0);
+    }
+
+    #[test]
+    fn test_reject_content_is_truncated() {
+        let mut log = RejectLog::new(1);
+        let long_line = "x".repeat(1000);
+        log.record(1, &long_line, RejectReason::InvalidIp, "bad".to_string());
+        let content = &log.samples()[0].content;
+        assert_eq!(content.chars().count(), MAX_CONTENT_CHARS + 3);
+        assert!(content.ends_with("..."));
+    }
+
+    #[test]
+    fn test_take_samples_keeps_total() {
+        let mut log = RejectLog::new(10);
+        log.record(3, "1.2.3", RejectReason::InvalidIp, "bad".to_string());
+        let taken = log.take_samples();
+        assert_eq!(taken.len(), 1);
+        assert!(log.samples().is_empty());
+        assert_eq!(log.total(), 1);
+    }
+}
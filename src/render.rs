@@ -0,0 +1,308 @@
+use crate::bands::Bands;
+use crate::categories::CategoryColours;
+use crate::dither::{Dither, DitherPattern, parse_dither_strength};
+use crate::output::PngEncoding;
+use crate::palette::{DEFAULT_COLOUR_LUT_BITS, LutRounding, Palette, parse_colour_lut, parse_hex_colour};
+use crate::scale::{BoundsError, DomainType, LogParams};
+use crate::strings::Strings;
+
+/// Options controlling how a processed buffer is turned into an image.
+///
+/// These are independent of how the buffer was built, so one processed input can be
+/// rendered several times with different options.
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    pub curve: DomainType,
+    /// Lower bound of the colour domain (defaults to the dataset minimum).
+    pub min_value: Option<f64>,
+    /// Upper bound of the colour domain (defaults to the dataset maximum).
+    pub max_value: Option<f64>,
+    /// Take the lower bound from this percentile (0-100) of the non-zero cells instead
+    /// of the minimum; cells below it are clamped to the bottom colour.
+    pub min_percentile: Option<f64>,
+    /// Take the upper bound from this percentile of the non-zero cells instead of the
+    /// maximum; cells above it are clamped to the top colour.
+    pub max_percentile: Option<f64>,
+    /// Base and offset of the logarithmic curve (defaults to `ln(value - min + 1)`).
+    pub log_params: Option<LogParams>,
+    /// Exponent applied to the scaled [0, 1] value before the palette lookup; values
+    /// below 1 brighten the image. 1.0 leaves it unchanged.
+    pub gamma: f64,
+    pub palette: Palette,
+    /// Named value bands shown on the legend instead of the domain's end values.
+    pub bands: Option<Bands>,
+    /// Colour each cell with the single colour of its band.
+    pub snap_to_bands: bool,
+    /// Bits of the [`ColourLut`] cells are coloured from, or `None` to evaluate the
+    /// palette exactly for every cell.
+    pub colour_lut: Option<u8>,
+    /// How scaled values pick their [`ColourLut`] entry.
+    pub lut_rounding: LutRounding,
+    /// Dither scaled values, after `gamma`, to break up banding; off by default.
+    pub dither: Option<Dither>,
+    /// How the PNG is compressed; this does not change the pixels.
+    pub png: PngEncoding,
+    /// Colours of categorical values. Without them, or for categories they do not
+    /// name, categories take the Accent palette by value.
+    pub category_colours: Option<CategoryColours>,
+    /// Colour of the pixels left uncoloured, which are transparent without one.
+    pub background: Option<[u8; 3]>,
+    /// The text of legends, captions and charts, see [`Strings`].
+    pub strings: Strings,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            curve: DomainType::Linear,
+            min_value: None,
+            max_value: None,
+            min_percentile: None,
+            max_percentile: None,
+            log_params: None,
+            gamma: 1.0,
+            palette: Palette::from(&colorous::MAGMA),
+            bands: None,
+            snap_to_bands: false,
+            colour_lut: Some(DEFAULT_COLOUR_LUT_BITS),
+            lut_rounding: LutRounding::Nearest,
+            dither: None,
+            png: PngEncoding::default(),
+            category_colours: None,
+            background: None,
+            strings: Strings::default(),
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Apply comma-separated `key=value` overrides, e.g. `curve=log,palette=viridis`.
+    ///
+    /// Supported keys are `curve`, `palette` (or `colour-scale`), `min` (or `min-value`),
+    /// `max` (or `max-value`), `min-percentile`, `max-percentile`, `log-base`,
+    /// `log-offset`, `gamma`, `colour-lut`, `lut-rounding`, `dither` (a pattern or `none`),
+    /// `dither-strength`, `background` (`#rrggbb` or `none`), `png-compression` and `png-filter`.
+    pub fn apply_overrides(&mut self, overrides: &str) -> Result<(), String> {
+        for item in overrides.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid render option: {}. Use key=value", item))?;
+            match key.trim() {
+                "curve" => self.curve = value.parse()?,
+                "palette" | "colour-scale" => self.palette = value.parse()?,
+                "min" | "min-value" => self.min_value = Some(parse_bound(key, value)?),
+                "max" | "max-value" => self.max_value = Some(parse_bound(key, value)?),
+                "min-percentile" => self.min_percentile = Some(parse_percentile(key, value)?),
+                "max-percentile" => self.max_percentile = Some(parse_percentile(key, value)?),
+                "log-base" | "log-offset" => {
+                    let params = self.log_params.unwrap_or_default();
+                    let (base, offset) = match key.trim() {
+                        "log-base" => (parse_bound(key, value)?, params.offset),
+                        _ => (params.base, parse_bound(key, value)?),
+                    };
+                    self.log_params = Some(LogParams::new(base, offset)?);
+                }
+                "gamma" => self.gamma = parse_gamma(value)?,
+                "colour-lut" => self.colour_lut = parse_colour_lut(value)?,
+                "lut-rounding" => self.lut_rounding = value.trim().parse()?,
+                "dither" => {
+                    self.dither = match value.trim() {
+                        "none" => None,
+                        pattern => Some(Dither { pattern: pattern.parse()?, ..self.dither.unwrap_or(Dither::new(DitherPattern::Bayer)) }),
+                    }
+                }
+                "dither-strength" => {
+                    let dither = self.dither.ok_or("dither-strength needs dither=bayer or dither=blue-noise first")?;
+                    self.dither = Some(Dither { strength: parse_dither_strength(value)?, ..dither });
+                }
+                "background" => {
+                    self.background = match value.trim() {
+                        "none" => None,
+                        colour => Some(parse_hex_colour(colour)?),
+                    }
+                }
+                "png-compression" => self.png.compression = value.parse()?,
+                "png-filter" => self.png.filter = value.parse()?,
+                other => {
+                    return Err(format!(
+                        "Unknown render option: {}. Use curve, palette, min, max, min-percentile, max-percentile, log-base, log-offset, gamma, colour-lut, lut-rounding, dither, dither-strength, background, png-compression or png-filter",
+                        other
+                    ));
+                }
+            }
+        }
+        if let (Some(min_value), Some(max_value)) = (self.min_value, self.max_value) {
+            BoundsError::check(min_value, max_value).map_err(|err| err.describe(min_value, max_value))?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_bound(key: &str, value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(bound) if bound.is_finite() => Ok(bound),
+        _ => Err(format!("Invalid value for {}: {}. Use a finite number", key, value)),
+    }
+}
+
+/// Parse a gamma, which must be positive.
+pub fn parse_gamma(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(gamma) if gamma.is_finite() && gamma > 0.0 => Ok(gamma),
+        _ => Err(format!("Gamma must be a number greater than 0: {}", value)),
+    }
+}
+
+/// Parse a percentile in 0-100.
+pub(crate) fn parse_percentile(key: &str, value: &str) -> Result<f64, String> {
+    let percentile = parse_bound(key, value)?;
+    if !(0.0..=100.0).contains(&percentile) {
+        return Err(format!("{} must be between 0 and 100: {}", key, value));
+    }
+    Ok(percentile)
+}
+
+/// An output file together with the options used to render it.
+#[derive(Clone, Debug)]
+pub struct RenderSpec {
+    pub output: String,
+    pub options: RenderOptions,
+}
+
+impl RenderSpec {
+    /// Parse `path[:key=value,...]`, starting from `base` options.
+    pub fn parse(spec: &str, base: &RenderOptions) -> Result<RenderSpec, String> {
+        let mut options = base.clone();
+        let output = match spec.rsplit_once(':') {
+            Some((path, overrides)) if overrides.contains('=') => {
+                options.apply_overrides(overrides)?;
+                path
+            }
+            _ => spec,
+        };
+        if output.is_empty() {
+            return Err(format!("Render spec has no output path: {}", spec));
+        }
+        Ok(RenderSpec {
+            output: output.to_string(),
+            options,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_spec_without_overrides() {
+        let spec = RenderSpec::parse("out.png", &RenderOptions::default()).unwrap();
+        assert_eq!(spec.output, "out.png");
+        assert!(matches!(spec.options.curve, DomainType::Linear));
+        assert_eq!(spec.options.palette.name(), "magma");
+    }
+
+    #[test]
+    fn test_render_spec_with_overrides() {
+        let spec = RenderSpec::parse(
+            "maps/out-log.png:curve=log,palette=viridis,min=1,max=1000",
+            &RenderOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(spec.output, "maps/out-log.png");
+        assert!(matches!(spec.options.curve, DomainType::Logarithmic));
+        assert_eq!(spec.options.palette.name(), "viridis");
+        assert_eq!(spec.options.min_value, Some(1.0));
+        assert_eq!(spec.options.max_value, Some(1000.0));
+    }
+
+    #[test]
+    fn test_render_spec_percentiles() {
+        let spec = RenderSpec::parse("out.png:min-percentile=1,max-percentile=99.5", &RenderOptions::default()).unwrap();
+        assert_eq!(spec.options.min_percentile, Some(1.0));
+        assert_eq!(spec.options.max_percentile, Some(99.5));
+    }
+
+    #[test]
+    fn test_render_spec_lut_rounding() {
+        let spec = RenderSpec::parse("out.png:lut-rounding=floor", &RenderOptions::default()).unwrap();
+        assert_eq!(spec.options.lut_rounding, LutRounding::Floor);
+        assert!(RenderSpec::parse("out.png:lut-rounding=up", &RenderOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_render_spec_dither() {
+        let defaults = RenderOptions::default();
+        let spec = RenderSpec::parse("out.png:dither=blue-noise,dither-strength=2", &defaults).unwrap();
+        assert_eq!(spec.options.dither, Some(Dither { pattern: DitherPattern::BlueNoise, strength: 2.0 }));
+        // The pattern can change, keeping the base's strength, or dithering be turned off
+        let base = spec.options;
+        let bayer = RenderSpec::parse("out.png:dither=bayer", &base).unwrap().options.dither;
+        assert_eq!(bayer, Some(Dither { pattern: DitherPattern::Bayer, strength: 2.0 }));
+        assert_eq!(RenderSpec::parse("out.png:dither=none", &base).unwrap().options.dither, None);
+        assert!(RenderSpec::parse("out.png:dither-strength=2", &defaults).is_err());
+        assert!(RenderSpec::parse("out.png:dither=floyd", &defaults).is_err());
+        assert!(RenderSpec::parse("out.png:dither=bayer,dither-strength=100", &defaults).is_err());
+    }
+
+    #[test]
+    fn test_render_spec_log_params() {
+        let spec = RenderSpec::parse("out.png:curve=log,log-base=10,log-offset=1", &RenderOptions::default()).unwrap();
+        assert_eq!(spec.options.log_params, Some(LogParams { base: 10.0, offset: 1.0 }));
+        assert!(RenderSpec::parse("out.png:log-base=1", &RenderOptions::default()).is_err());
+        assert!(RenderSpec::parse("out.png:log-offset=-2", &RenderOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_render_spec_bounds_are_checked() {
+        let parse = |spec: &str, base: &RenderOptions| RenderSpec::parse(spec, base).map(|spec| spec.options.max_value);
+        let defaults = RenderOptions::default();
+        assert_eq!(parse("out.png:min=1000,max=10", &defaults).unwrap_err(), "Max value 10 is less than min value 1000");
+        assert!(parse("out.png:min=5,max=5", &defaults).unwrap_err().contains("equals min value 5"));
+        assert!(parse("out.png:max=inf", &defaults).unwrap_err().contains("finite"));
+        // Either bound may come from the base options
+        let base = RenderOptions { max_value: Some(50.0), ..RenderOptions::default() };
+        assert!(parse("out.png:min=60", &base).is_err());
+        assert_eq!(parse("out.png:min=40", &base), Ok(Some(50.0)));
+    }
+
+    #[test]
+    fn test_render_spec_background() {
+        let spec = RenderSpec::parse("out.png:background=#102030", &RenderOptions::default()).unwrap();
+        assert_eq!(spec.options.background, Some([0x10, 0x20, 0x30]));
+        let base = RenderOptions { background: Some([0, 0, 0]), ..RenderOptions::default() };
+        assert_eq!(RenderSpec::parse("out.png:background=none", &base).unwrap().options.background, None);
+        assert!(RenderSpec::parse("out.png:background=grey", &base).is_err());
+    }
+
+    #[test]
+    fn test_render_spec_keeps_base_options() {
+        let base = RenderOptions {
+            max_value: Some(50.0),
+            ..RenderOptions::default()
+        };
+        let spec = RenderSpec::parse("out.png:palette=turbo", &base).unwrap();
+        assert_eq!(spec.options.max_value, Some(50.0));
+        assert_eq!(spec.options.palette.name(), "turbo");
+    }
+
+    #[test]
+    fn test_render_spec_errors() {
+        let base = RenderOptions::default();
+        assert!(RenderSpec::parse("out.png:curve=cubic", &base).is_err());
+        assert!(RenderSpec::parse("out.png:shape=round", &base).is_err());
+        assert!(RenderSpec::parse("out.png:min=low", &base).is_err());
+        assert!(RenderSpec::parse(":curve=log", &base).is_err());
+        assert!(RenderSpec::parse("out.png:max-percentile=101", &base).is_err());
+        assert!(RenderSpec::parse("out.png:gamma=0", &base).is_err());
+        assert!(RenderSpec::parse("out.png:gamma=-1", &base).is_err());
+        assert!(RenderSpec::parse("out.png:png-compression=max", &base).is_err());
+    }
+
+    #[test]
+    fn test_render_spec_png_encoding() {
+        let spec = RenderSpec::parse("keep.png:png-compression=best,png-filter=paeth", &RenderOptions::default()).unwrap();
+        assert_eq!(spec.options.png.compression, crate::PngCompression::Best);
+        assert_eq!(spec.options.png.filter, crate::PngFilter::Paeth);
+    }
+}
@@ -0,0 +1,244 @@
+//! A per-block tripwire for systematically missing data, such as a preprocessing
+//! bug that zeroes half of a /1.
+
+use crate::Heatmap;
+use crate::hilbert::hilbert_d2xy;
+use crate::table::{Align, Table, TextStyle};
+use anyhow::Result;
+use ipnet::Ipv4Net;
+
+/// Blocks of the sanity check are /4s, sixteen of them.
+pub const SANITY_PREFIX_LEN: u8 = 4;
+
+/// How many times above or below the overall figures a block may be before it is
+/// reported.
+pub const DEFAULT_SANITY_FACTOR: f64 = 4.0;
+
+/// Parse a `--sanity-factor`, which must be greater than 1.
+pub fn parse_sanity_factor(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor > 1.0 => Ok(factor),
+        _ => Err(format!("Sanity factor must be a number greater than 1: {}", value)),
+    }
+}
+
+/// The multicast and reserved space of 224.0.0.0/3, which is expected to be empty
+/// and so is neither compared nor part of the overall figures.
+const NOT_UNICAST: u32 = 0xe000_0000;
+
+/// Painted pixels and total value of one block of the map.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockSummary {
+    pub net: Ipv4Net,
+    /// Pixels of the map in the block, 0 for the upper half at an odd resolution.
+    pub pixels: u64,
+    pub painted: u64,
+    pub total: i64,
+}
+
+impl BlockSummary {
+    /// Fraction of the block's pixels that are painted.
+    pub fn painted_fraction(&self) -> f64 {
+        match self.pixels {
+            0 => 0.0,
+            pixels => self.painted as f64 / pixels as f64,
+        }
+    }
+
+    /// Total value per pixel of the block.
+    pub fn value_per_pixel(&self) -> f64 {
+        match self.pixels {
+            0 => 0.0,
+            pixels => self.total as f64 / pixels as f64,
+        }
+    }
+
+    /// Whether the block takes part in the comparison: it is drawn on the map and
+    /// is unicast space.
+    fn compared(&self) -> bool {
+        self.pixels > 0 && u32::from(self.net.network()) < NOT_UNICAST
+    }
+}
+
+/// The blocks of a map, see [`Heatmap::sanity_report`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SanityReport {
+    pub blocks: Vec<BlockSummary>,
+}
+
+impl SanityReport {
+    /// The compared blocks taken together.
+    pub fn overall(&self) -> BlockSummary {
+        let mut overall = BlockSummary { net: Ipv4Net::default(), pixels: 0, painted: 0, total: 0 };
+        for block in self.blocks.iter().filter(|block| block.compared()) {
+            overall.pixels += block.pixels;
+            overall.painted += block.painted;
+            overall.total += block.total;
+        }
+        overall
+    }
+
+    /// One line per block whose painted fraction or value per pixel is more than
+    /// `factor` times above or below the overall one. A map with nothing painted
+    /// has nothing to compare against and no warnings.
+    pub fn warnings(&self, factor: f64) -> Vec<String> {
+        let overall = self.overall();
+        let deviates = |value: f64, overall: f64| overall > 0.0 && !(overall / factor..=overall * factor).contains(&value);
+        let mut warnings = Vec::new();
+        for block in self.blocks.iter().filter(|block| block.compared()) {
+            if deviates(block.painted_fraction(), overall.painted_fraction()) {
+                warnings.push(format!(
+                    "{} has {:.2}% of its pixels painted against {:.2}% overall",
+                    block.net,
+                    block.painted_fraction() * 100.0,
+                    overall.painted_fraction() * 100.0
+                ));
+            } else if deviates(block.value_per_pixel(), overall.value_per_pixel()) {
+                warnings.push(format!(
+                    "{} has a value of {:.3} per pixel against {:.3} overall",
+                    block.net,
+                    block.value_per_pixel(),
+                    overall.value_per_pixel()
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// A table of the blocks, with the overall figures last.
+    pub fn to_text(&self, style: TextStyle) -> String {
+        let mut table = Table::new(&[
+            ("block", Align::Left),
+            ("painted", Align::Right),
+            ("share", Align::Right),
+            ("total", Align::Right),
+            ("note", Align::Left),
+        ]);
+        let overall = self.overall();
+        for block in self.blocks.iter().chain([&overall]) {
+            let label = match block.net.prefix_len() {
+                0 => "overall".to_string(),
+                _ => block.net.to_string(),
+            };
+            let note = match (block.pixels, block.net.prefix_len() == 0 || block.compared()) {
+                (0, _) => "not on the map",
+                (_, false) => "not unicast, not compared",
+                _ => "",
+            };
+            table.push(vec![
+                label,
+                block.painted.to_string(),
+                format!("{:.2}%", block.painted_fraction() * 100.0),
+                block.total.to_string(),
+                note.to_string(),
+            ]);
+        }
+        table.render(style)
+    }
+}
+
+impl Heatmap {
+    /// Painted pixels and total value of each [`SANITY_PREFIX_LEN`] block.
+    ///
+    /// Fails at resolutions coarser than a block, as [`Heatmap::prefix_totals`] does.
+    pub fn sanity_report(&self) -> Result<SanityReport> {
+        let totals = self.prefix_totals(SANITY_PREFIX_LEN)?;
+        let geometry = self.geometry();
+        let shift = geometry.prefix_len_per_pixel - SANITY_PREFIX_LEN;
+        let mut blocks: Vec<BlockSummary> =
+            totals.into_iter().map(|(net, total)| BlockSummary { net, pixels: 0, painted: 0, total }).collect();
+        for d in 0..geometry.pixels() {
+            let block = &mut blocks[(d >> shift) as usize];
+            block.pixels += 1;
+            if let Some((x, y)) = hilbert_d2xy(d, geometry.order)
+                && self.is_touched(x as usize, y as usize)
+            {
+                block.painted += 1;
+            }
+        }
+        Ok(SanityReport { blocks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    /// A /8 painted with 3 at the start of every unicast /4 but `empty`.
+    fn every_block_but(empty: u8) -> String {
+        (0..14u8).filter(|&block| block != empty).map(|block| format!("{}.0.0.0/8 3\n", block * 16)).collect()
+    }
+
+    #[test]
+    fn test_an_empty_block_is_reported() {
+        let report = heatmap(16, &every_block_but(7)).sanity_report().unwrap();
+        assert_eq!(report.blocks.len(), 16);
+        assert_eq!(report.blocks[0], BlockSummary { net: "0.0.0.0/4".parse().unwrap(), pixels: 4096, painted: 256, total: 768 });
+        assert_eq!(report.blocks[7].painted, 0);
+        let warnings = report.warnings(DEFAULT_SANITY_FACTOR);
+        assert_eq!(warnings, ["112.0.0.0/4 has 0.00% of its pixels painted against 5.80% overall"]);
+        // Multicast and reserved space is expected to be empty
+        assert!(heatmap(16, &every_block_but(14)).sanity_report().unwrap().warnings(DEFAULT_SANITY_FACTOR).is_empty());
+        let text = report.to_text(TextStyle::ASCII);
+        assert!(text.contains("| 112.0.0.0/4 |       0 | 0.00% |     0 |                           |\n"), "{}", text);
+        assert!(text.contains("| 224.0.0.0/4 |       0 | 0.00% |     0 | not unicast, not compared |\n"), "{}", text);
+        assert!(text.contains("| overall     |    3328 | 5.80% |  9984 |                           |\n+-"), "{}", text);
+        // The same table drawn with box-drawing characters
+        let boxed: String = report
+            .to_text(TextStyle { unicode: true, colour: false })
+            .chars()
+            .map(|c| match c {
+                '│' => '|',
+                '─' => '-',
+                '┌' | '┬' | '┐' | '├' | '┼' | '┤' | '└' | '┴' | '┘' => '+',
+                c => c,
+            })
+            .collect();
+        assert_eq!(boxed, text);
+    }
+
+    #[test]
+    fn test_values_are_compared_too() {
+        let input = every_block_but(14) + "32.1.0.0/16 20000\n";
+        let warnings = heatmap(16, &input).sanity_report().unwrap().warnings(DEFAULT_SANITY_FACTOR);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].starts_with("32.0.0.0/4 has a value of 5.070 per pixel"), "{:?}", warnings);
+        // A looser factor lets it through
+        assert!(heatmap(16, &input).sanity_report().unwrap().warnings(100.0).is_empty());
+    }
+
+    #[test]
+    fn test_sanity_factor() {
+        assert_eq!(parse_sanity_factor("2.5"), Ok(2.5));
+        for invalid in ["1", "0.5", "-4", "inf", "NaN", "four"] {
+            assert!(parse_sanity_factor(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_odd_resolutions_and_empty_maps() {
+        // The upper half of the address space is not drawn at an odd resolution
+        let report = heatmap(17, &every_block_but(14)).sanity_report().unwrap();
+        assert!(report.blocks[8..].iter().all(|block| block.pixels == 0));
+        assert!(report.warnings(DEFAULT_SANITY_FACTOR).is_empty());
+        assert!(report.to_text(TextStyle::ASCII).contains("| 128.0.0.0/4 |       0 | 0.00% |     0 | not on the map "));
+        assert!(heatmap(16, "").sanity_report().unwrap().warnings(DEFAULT_SANITY_FACTOR).is_empty());
+        assert!(heatmap(30, "").sanity_report().is_err());
+    }
+}
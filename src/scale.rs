@@ -1,21 +1,67 @@
 use std::fmt::Display;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
 
+/// The curve values are mapped through before colouring, written `linear`, `log`
+/// (or `logarithmic`) and `symlog[:threshold]`.
 #[derive(Clone, Copy, Debug)]
 pub enum DomainType {
     Linear,
     Logarithmic,
+    /// Linear within ±`linthresh` and logarithmic outside, for data of both signs.
+    Symlog { linthresh: f64 },
+}
+
+/// Thresholds compare by their bits, so every curve equals itself and hashes alike.
+impl PartialEq for DomainType {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (DomainType::Symlog { linthresh: a }, DomainType::Symlog { linthresh: b }) => a.to_bits() == b.to_bits(),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl Eq for DomainType {}
+
+impl Hash for DomainType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        if let DomainType::Symlog { linthresh } = self {
+            linthresh.to_bits().hash(state);
+        }
+    }
+}
+
+impl DomainType {
+    /// Every curve name the parser accepts, with the curve it stands for. `symlog`
+    /// also takes a threshold, `symlog:10`.
+    pub const NAMES: [(&'static str, DomainType); 4] = [
+        ("linear", DomainType::Linear),
+        ("logarithmic", DomainType::Logarithmic),
+        ("log", DomainType::Logarithmic),
+        ("symlog", DomainType::Symlog { linthresh: 1.0 }),
+    ];
 }
 
 impl FromStr for DomainType {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "linear" => Ok(DomainType::Linear),
-            "logarithmic" | "log" => Ok(DomainType::Logarithmic),
+        let lower = s.to_lowercase();
+        let (name, parameter) = match lower.split_once(':') {
+            Some((name, parameter)) => (name, Some(parameter)),
+            None => (lower.as_str(), None),
+        };
+        let curve = DomainType::NAMES.iter().find(|(known, _)| *known == name).map(|&(_, curve)| curve);
+        match (curve, parameter) {
+            (Some(curve), None) => Ok(curve),
+            (Some(DomainType::Symlog { .. }), Some(linthresh)) => match linthresh.parse::<f64>() {
+                Ok(linthresh) if linthresh.is_finite() && linthresh > 0.0 => Ok(DomainType::Symlog { linthresh }),
+                _ => Err(format!("Invalid symlog threshold: {}. Use a positive number", linthresh)),
+            },
             _ => Err(format!(
-                "Invalid curve type: {}. Use 'linear' or 'logarithmic'",
+                "Invalid curve type: {}. Use 'linear', 'log' or 'symlog[:threshold]'",
                 s
             )),
         }
@@ -27,6 +73,84 @@ impl Display for DomainType {
         match self {
             DomainType::Linear => write!(f, "linear"),
             DomainType::Logarithmic => write!(f, "log"),
+            DomainType::Symlog { linthresh } => write!(f, "symlog:{}", linthresh),
+        }
+    }
+}
+
+/// Base and offset of a logarithmic curve, which then maps values through
+/// `log_base(value + offset)` between the ends of the domain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogParams {
+    pub base: f64,
+    pub offset: f64,
+}
+
+impl Default for LogParams {
+    fn default() -> Self {
+        Self {
+            base: std::f64::consts::E,
+            offset: 0.0,
+        }
+    }
+}
+
+impl LogParams {
+    pub fn new(base: f64, offset: f64) -> Result<Self, String> {
+        if !base.is_finite() || base <= 1.0 {
+            return Err(format!("Log base must be greater than 1: {}", base));
+        }
+        if !offset.is_finite() || offset < 0.0 {
+            return Err(format!("Log offset must be at least 0: {}", offset));
+        }
+        Ok(Self { base, offset })
+    }
+
+    fn log(&self, value: f64) -> f64 {
+        (value + self.offset).log(self.base)
+    }
+}
+
+/// Why a minimum and maximum cannot bound a colour domain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoundsError {
+    NotFinite,
+    /// The maximum is below the minimum, as when the two were passed the wrong way round.
+    Swapped,
+    /// The bounds are equal, leaving no range to colour.
+    Equal,
+}
+
+impl BoundsError {
+    pub fn check(min_value: f64, max_value: f64) -> Result<(), BoundsError> {
+        if !min_value.is_finite() || !max_value.is_finite() {
+            Err(BoundsError::NotFinite)
+        } else if max_value < min_value {
+            Err(BoundsError::Swapped)
+        } else if max_value == min_value {
+            Err(BoundsError::Equal)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The constraint that failed, for errors that cannot name the values.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BoundsError::NotFinite => "Min and max values must be finite numbers",
+            BoundsError::Swapped => "Max value is less than min value",
+            BoundsError::Equal => "Max value equals min value, leaving no range of values to colour",
+        }
+    }
+
+    /// The constraint that failed, naming the values.
+    pub fn describe(self, min_value: f64, max_value: f64) -> String {
+        match self {
+            BoundsError::NotFinite => format!("Min value {} and max value {} must be finite numbers", min_value, max_value),
+            BoundsError::Swapped => format!("Max value {} is less than min value {}", max_value, min_value),
+            BoundsError::Equal => {
+                format!("Max value {} equals min value {}, leaving no range of values to colour", max_value, min_value)
+            }
         }
     }
 }
@@ -35,6 +159,8 @@ pub struct ScaleDomain {
     domain_type: DomainType,
     min_value: f64,
     max_value: f64,
+    clamp_below: bool,
+    log_params: Option<LogParams>,
 }
 
 impl ScaleDomain {
@@ -43,22 +169,43 @@ impl ScaleDomain {
         min_value: f64,
         max_value: f64,
     ) -> Result<Self, &'static str> {
-        if max_value <= min_value {
-            return Err(
-                "Min value must be greater than 0 and max value must be greater than min value",
-            );
-        }
+        BoundsError::check(min_value, max_value).map_err(BoundsError::as_str)?;
         Ok(Self {
             domain_type,
             min_value,
             max_value,
+            clamp_below: false,
+            log_params: None,
         })
     }
 
+    /// Use `log_base(value + offset)` for the logarithmic curve instead of the default
+    /// `ln(value - min + 1)`. The offset must make `min + offset` positive.
+    pub fn with_log_params(mut self, params: LogParams) -> Result<Self, &'static str> {
+        if self.min_value + params.offset <= 0.0 {
+            return Err("Log offset must make min value + offset greater than 0");
+        }
+        self.log_params = Some(params);
+        Ok(self)
+    }
+
+    /// Map non-zero values at or below the minimum to 0.0 instead of "no data".
+    /// Zero is still treated as no data.
+    pub fn clamp_below(mut self) -> Self {
+        self.clamp_below = true;
+        self
+    }
+
     pub fn scale(&self, value: f64) -> Option<f64> {
+        if self.clamp_below && value <= self.min_value {
+            return (value != 0.0).then_some(0.0);
+        }
         match self.domain_type {
             DomainType::Linear => self.scale_linear(value),
             DomainType::Logarithmic => self.scale_logarithmic(value),
+            // Zero cells hold no data
+            DomainType::Symlog { .. } if value == 0.0 => None,
+            DomainType::Symlog { linthresh } => Some(self.scale_symlog(value, linthresh)),
         }
     }
 
@@ -84,12 +231,76 @@ impl ScaleDomain {
             return Some(1.0);
         }
 
+        if let Some(params) = &self.log_params {
+            let low = params.log(self.min_value);
+            return Some((params.log(value) - low) / (params.log(self.max_value) - low));
+        }
+
         let offset = value - self.min_value;
         let range = self.max_value - self.min_value;
         Some((offset + 1.0).ln() / (range + 1.0).ln())
     }
 }
 
+/// The symmetric log transfer function: `x / linthresh` within ±`linthresh`, and
+/// `±(1 + log10(|x| / linthresh))` outside, continuous at the threshold.
+pub fn symlog(x: f64, linthresh: f64) -> f64 {
+    let magnitude = x.abs() / linthresh;
+    if magnitude <= 1.0 {
+        x / linthresh
+    } else {
+        x.signum() * (1.0 + magnitude.log10())
+    }
+}
+
+impl ScaleDomain {
+    /// Map `value` through [`symlog`] onto [0, 1] with zero at 0.5, so the larger of
+    /// |min| and |max| reaches the end of the palette. Values outside are clamped.
+    pub fn scale_symlog(&self, value: f64, linthresh: f64) -> f64 {
+        let extent = self.symlog_extent(linthresh);
+        let value = value.clamp(self.min_value, self.max_value);
+        (0.5 + 0.5 * symlog(value, linthresh) / extent).clamp(0.0, 1.0)
+    }
+
+    fn symlog_extent(&self, linthresh: f64) -> f64 {
+        symlog(self.min_value, linthresh).abs().max(symlog(self.max_value, linthresh).abs())
+    }
+}
+
+/// The inverse of [`symlog`].
+pub fn symlog_inverse(y: f64, linthresh: f64) -> f64 {
+    if y.abs() <= 1.0 {
+        y * linthresh
+    } else {
+        y.signum() * linthresh * 10f64.powf(y.abs() - 1.0)
+    }
+}
+
+impl ScaleDomain {
+    /// The value that [`ScaleDomain::scale`] maps to `scaled`, clamped to [0, 1]
+    /// first. Positions the curve never reaches, such as below 0.5 on a symlog
+    /// domain with no negative values, give the nearest end of the domain.
+    pub fn invert(&self, scaled: f64) -> f64 {
+        let scaled = scaled.clamp(0.0, 1.0);
+        let (min, max) = (self.min_value, self.max_value);
+        match self.domain_type {
+            DomainType::Linear => min + scaled * (max - min),
+            DomainType::Logarithmic => match &self.log_params {
+                Some(params) => {
+                    let low = params.log(min);
+                    params.base.powf(low + scaled * (params.log(max) - low)) - params.offset
+                }
+                None => min + (max - min + 1.0).powf(scaled) - 1.0,
+            },
+            DomainType::Symlog { linthresh } => {
+                let y = (scaled - 0.5) * 2.0 * self.symlog_extent(linthresh);
+                symlog_inverse(y, linthresh)
+            }
+        }
+        .clamp(min, max)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -103,6 +314,15 @@ mod tests {
         assert_eq!(domain.scale_linear(-5.0), None);
     }
 
+    #[test]
+    fn test_clamp_below_keeps_zero_as_no_data() {
+        let domain = ScaleDomain::new(DomainType::Linear, 10.0, 100.0).unwrap().clamp_below();
+        assert_eq!(domain.scale(10.0), Some(0.0));
+        assert_eq!(domain.scale(3.0), Some(0.0));
+        assert_eq!(domain.scale(0.0), None);
+        assert_eq!(domain.scale(55.0), Some(0.5));
+    }
+
     #[test]
     fn test_linear_scale_interpolation() {
         let domain = ScaleDomain::new(DomainType::Linear, 10.0, 100.0).unwrap();
@@ -192,6 +412,25 @@ mod tests {
         
     }
 
+    #[test]
+    fn test_bounds_errors_name_the_failed_constraint() {
+        let error = |min, max| ScaleDomain::new(DomainType::Linear, min, max).err();
+        assert_eq!(error(1000.0, 10.0), Some("Max value is less than min value"));
+        assert_eq!(error(5.0, 5.0), Some(BoundsError::Equal.as_str()));
+        assert_eq!(error(f64::NAN, 5.0), Some(BoundsError::NotFinite.as_str()));
+        assert_eq!(error(0.0, f64::INFINITY), Some(BoundsError::NotFinite.as_str()));
+        // Negative minimums are fine
+        assert_eq!(error(-10.0, -5.0), None);
+
+        assert_eq!(BoundsError::check(1000.0, 10.0), Err(BoundsError::Swapped));
+        assert_eq!(BoundsError::Swapped.describe(1000.0, 10.0), "Max value 10 is less than min value 1000");
+        assert_eq!(
+            BoundsError::Equal.describe(5.0, 5.0),
+            "Max value 5 equals min value 5, leaving no range of values to colour"
+        );
+        assert!(BoundsError::NotFinite.describe(f64::NAN, 1.0).starts_with("Min value NaN and max value 1"));
+    }
+
     #[test]
     fn test_scale_method_dispatch() {
         let linear_domain = ScaleDomain::new(DomainType::Linear, 10.0, 100.0).unwrap();
@@ -207,4 +446,185 @@ mod tests {
         assert_eq!(linear_domain.scale(5.0), None);
         assert_eq!(log_domain.scale(5.0), None);
     }
+
+    #[test]
+    fn test_parse_symlog() {
+        assert!(matches!("symlog".parse(), Ok(DomainType::Symlog { linthresh }) if linthresh == 1.0));
+        assert!(matches!("symlog:10".parse(), Ok(DomainType::Symlog { linthresh }) if linthresh == 10.0));
+        assert!("symlog:0".parse::<DomainType>().is_err());
+        assert!("symlog:-1".parse::<DomainType>().is_err());
+        assert!("linear:2".parse::<DomainType>().is_err());
+        assert_eq!(DomainType::Symlog { linthresh: 2.5 }.to_string(), "symlog:2.5");
+    }
+
+    #[test]
+    fn test_symlog_is_continuous_at_threshold() {
+        for linthresh in [0.5, 1.0, 10.0] {
+            for sign in [-1.0, 1.0] {
+                let at = sign * linthresh;
+                let below = symlog(at * (1.0 - 1e-9), linthresh);
+                let above = symlog(at * (1.0 + 1e-9), linthresh);
+                assert!((symlog(at, linthresh) - sign).abs() < 1e-12);
+                assert!((below - above).abs() < 1e-6, "jump at {}", at);
+            }
+        }
+    }
+
+    #[test]
+    fn test_symlog_scale_is_monotonic() {
+        let domain = ScaleDomain::new(DomainType::Symlog { linthresh: 10.0 }, -5000.0, 20000.0).unwrap();
+        let mut previous = -1.0;
+        let mut value = -6000.0;
+        while value <= 21000.0 {
+            let scaled = domain.scale_symlog(value, 10.0);
+            assert!(scaled >= previous, "not monotonic at {}", value);
+            assert!((0.0..=1.0).contains(&scaled));
+            previous = scaled;
+            value += 7.5;
+        }
+        assert_eq!(domain.scale_symlog(0.0, 10.0), 0.5);
+        assert_eq!(domain.scale_symlog(20000.0, 10.0), 1.0);
+        assert!(domain.scale_symlog(-5000.0, 10.0) > 0.0);
+        // Zero cells are no data when rendering
+        assert_eq!(domain.scale(0.0), None);
+    }
+
+    #[test]
+    fn test_log_params_validation() {
+        assert!(LogParams::new(10.0, 1.0).is_ok());
+        assert!(LogParams::new(1.0, 0.0).is_err());
+        assert!(LogParams::new(0.5, 0.0).is_err());
+        assert!(LogParams::new(10.0, -1.0).is_err());
+        let domain = |min| ScaleDomain::new(DomainType::Logarithmic, min, 100.0).unwrap();
+        assert!(domain(0.0).with_log_params(LogParams::new(10.0, 0.0).unwrap()).is_err());
+        assert!(domain(-1.0).with_log_params(LogParams::new(10.0, 1.0).unwrap()).is_err());
+        assert!(domain(0.0).with_log_params(LogParams::new(10.0, 1.0).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_log10_with_offset_one() {
+        let domain = ScaleDomain::new(DomainType::Logarithmic, 0.0, 999.0)
+            .unwrap()
+            .with_log_params(LogParams::new(10.0, 1.0).unwrap())
+            .unwrap();
+        // log10(v + 1) / log10(1000)
+        assert!((domain.scale_logarithmic(9.0).unwrap() - 1.0 / 3.0).abs() < 1e-12);
+        assert!((domain.scale_logarithmic(99.0).unwrap() - 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!(domain.scale_logarithmic(999.0), Some(1.0));
+        assert_eq!(domain.scale_logarithmic(0.0), None);
+    }
+
+    /// Values spread unevenly over `min..=max`, from a fixed linear congruential sequence.
+    fn samples(min: f64, max: f64) -> Vec<f64> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut values: Vec<f64> = (0..500)
+            .map(|_| {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                let unit = (state >> 11) as f64 / (1u64 << 53) as f64;
+                min + unit.powi(3) * (max - min)
+            })
+            .collect();
+        values.extend([min, max]);
+        values
+    }
+
+    fn domains() -> Vec<ScaleDomain> {
+        let log10 = LogParams::new(10.0, 1.0).unwrap();
+        vec![
+            ScaleDomain::new(DomainType::Linear, 10.0, 100.0).unwrap(),
+            ScaleDomain::new(DomainType::Linear, -50.0, 3.0).unwrap(),
+            ScaleDomain::new(DomainType::Logarithmic, 1.0, 1e6).unwrap(),
+            ScaleDomain::new(DomainType::Logarithmic, 0.0, 999.0).unwrap().with_log_params(log10).unwrap(),
+            ScaleDomain::new(DomainType::Logarithmic, 0.3, 5000.0)
+                .unwrap()
+                .with_log_params(LogParams::new(2.0, 0.25).unwrap())
+                .unwrap(),
+            ScaleDomain::new(DomainType::Symlog { linthresh: 10.0 }, -5000.0, 20000.0).unwrap(),
+            ScaleDomain::new(DomainType::Symlog { linthresh: 1.0 }, 0.0, 1e5).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_invert_round_trips_values() {
+        for domain in domains() {
+            for value in samples(domain.min_value, domain.max_value) {
+                // Linear and log curves have no position for the minimum itself
+                let Some(scaled) = domain.scale(value).or((value == domain.min_value).then_some(0.0)) else {
+                    continue;
+                };
+                let inverted = domain.invert(scaled);
+                let tolerance = 1e-9 * value.abs().max(domain.max_value - domain.min_value);
+                assert!(
+                    (inverted - value).abs() <= tolerance,
+                    "{:?}: {} scales to {} and back to {}",
+                    domain.domain_type,
+                    value,
+                    scaled,
+                    inverted
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_invert_round_trips_positions() {
+        for domain in domains() {
+            let reachable = match domain.domain_type {
+                DomainType::Symlog { linthresh } => {
+                    domain.scale_symlog(domain.min_value, linthresh)..=domain.scale_symlog(domain.max_value, linthresh)
+                }
+                _ => 0.0..=1.0,
+            };
+            for step in 1..=100 {
+                let scaled = step as f64 / 100.0;
+                let inverted = domain.invert(scaled);
+                assert!((domain.min_value..=domain.max_value).contains(&inverted));
+                if reachable.contains(&scaled) && inverted != 0.0 {
+                    let again = domain.scale(inverted).unwrap();
+                    assert!((again - scaled).abs() < 1e-9, "{:?}: {} -> {} -> {}", domain.domain_type, scaled, inverted, again);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_invert_known_positions() {
+        let linear = ScaleDomain::new(DomainType::Linear, 10.0, 100.0).unwrap();
+        assert_eq!([0.0, 0.5, 1.0, 2.0].map(|scaled| linear.invert(scaled)), [10.0, 55.0, 100.0, 100.0]);
+        let log = ScaleDomain::new(DomainType::Logarithmic, 1.0, 1000.0).unwrap();
+        assert!((log.invert(1.0 / 3.0) - 10.0).abs() < 1e-9);
+        let symmetric = ScaleDomain::new(DomainType::Symlog { linthresh: 1.0 }, -100.0, 100.0).unwrap();
+        assert_eq!(symmetric.invert(0.5), 0.0);
+        // The threshold is a third of the way from zero to 100, at 1 + log10(100)
+        assert!((symmetric.invert(0.5 + 0.5 / 3.0) - 1.0).abs() < 1e-9);
+        assert!((symmetric.invert(0.0) + 100.0).abs() < 1e-9);
+        // Positions below the smallest value give the end of the domain
+        let positive = ScaleDomain::new(DomainType::Symlog { linthresh: 1.0 }, 0.0, 100.0).unwrap();
+        assert_eq!(positive.invert(0.25), 0.0);
+        for value in samples(-1e4, 1e4) {
+            assert!((symlog_inverse(symlog(value, 3.0), 3.0) - value).abs() <= 1e-9 * value.abs().max(1.0));
+        }
+    }
+
+    #[test]
+    fn test_log_base_preserves_ordering() {
+        // Increasing values inside the domain, unevenly spaced
+        let values: Vec<f64> = (1..200).map(|i| (i as f64).powf(1.7) * 0.37).collect();
+        let scaled = |base: f64| -> Vec<f64> {
+            let domain = ScaleDomain::new(DomainType::Logarithmic, 0.3, 5000.0)
+                .unwrap()
+                .with_log_params(LogParams::new(base, 0.25).unwrap())
+                .unwrap();
+            values.iter().map(|&v| domain.scale(v).unwrap()).collect()
+        };
+        let reference = scaled(std::f64::consts::E);
+        for base in [1.5, 2.0, 10.0, 1000.0] {
+            let other = scaled(base);
+            assert!(other.windows(2).all(|pair| pair[0] < pair[1]), "base {} reorders values", base);
+            // The base cancels out of the ratio, up to rounding
+            for (a, b) in reference.iter().zip(&other) {
+                assert!((a - b).abs() < 1e-9);
+            }
+        }
+    }
 }
@@ -0,0 +1,409 @@
+//! A small HTTP server rendering heatmaps on demand: `POST /render` takes
+//! newline-delimited input and answers with the PNG, `GET /healthz` answers `ok`.
+//!
+//! Each connection carries one request. Uploads are bounded in size (after gzip
+//! decoding too), in concurrency and in time, so a single client cannot exhaust the
+//! machine.
+
+use crate::limits::{Deadline, Limited};
+use crate::output;
+use crate::{DomainType, ErrorPolicy, Heatmap, Metrics, ValueMode};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Longest request line plus headers accepted.
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+
+/// Limits protecting the server from large or slow requests.
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    /// Largest request body, in bytes after any gzip decoding.
+    pub max_body_bytes: u64,
+    /// Requests rendered at once; further ones are answered 503 straight away.
+    pub max_concurrent: usize,
+    /// Time from accepting a connection until its input has been read.
+    pub request_timeout: Duration,
+    /// Finest resolution a request may ask for; `-z 8` is a 4096-pixel map.
+    pub min_bits_per_pixel: u8,
+    /// Shared by the heatmaps of all requests, see [`serve_metrics`].
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 64 * 1024 * 1024,
+            max_concurrent: 4,
+            request_timeout: Duration::from_secs(30),
+            min_bits_per_pixel: 8,
+            metrics: None,
+        }
+    }
+}
+
+/// A request that cannot be answered with a map.
+#[derive(Debug, PartialEq)]
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self { status, message: message.into() }
+    }
+}
+
+/// The request line and headers of a request.
+#[derive(Debug, PartialEq)]
+struct RequestHead {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    /// Header names are lower case.
+    headers: HashMap<String, String>,
+}
+
+/// Answer requests on `listener` until accepting a connection fails.
+pub fn serve(listener: TcpListener, options: &ServeOptions) -> Result<()> {
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = stream.context("Failed to accept a connection")?;
+        if active.fetch_add(1, Ordering::SeqCst) >= options.max_concurrent {
+            active.fetch_sub(1, Ordering::SeqCst);
+            // Refusing takes no rendering, so it is not counted against the cap
+            std::thread::spawn(move || {
+                let error = HttpError::new(503, "Too many requests in progress");
+                let headers = [("Retry-After", "1".to_string())];
+                let _ = write_response(&stream, error.status, "text/plain", &headers, error.message.as_bytes());
+                close(&stream);
+            });
+            continue;
+        }
+        let (active, options) = (Arc::clone(&active), options.clone());
+        std::thread::spawn(move || {
+            if let Err(err) = handle_connection(&stream, &options) {
+                log::debug!("Connection ended early: {:#}", err);
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+            close(&stream);
+        });
+    }
+    Ok(())
+}
+
+/// Answer `GET /metrics` on `listener` with `metrics` in the Prometheus text format,
+/// one scrape at a time, until accepting a connection fails.
+pub fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream.context("Failed to accept a connection")?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        let response = read_head(&mut BufReader::new(&stream)).and_then(|head| match (head.method.as_str(), head.path.as_str()) {
+            ("GET", "/metrics") => Ok(metrics.to_prometheus()),
+            (_, "/metrics") => Err(HttpError::new(405, format!("{} is not allowed on /metrics", head.method))),
+            (_, path) => Err(HttpError::new(404, format!("No such endpoint: {}", path))),
+        });
+        let written = match response {
+            Ok(text) => write_response(&stream, 200, "text/plain; version=0.0.4", &[], text.as_bytes()),
+            Err(error) => write_response(&stream, error.status, "text/plain", &[], format!("{}\n", error.message).as_bytes()),
+        };
+        if let Err(err) = written {
+            log::debug!("Metrics scrape ended early: {:#}", err);
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Write);
+    }
+    Ok(())
+}
+
+/// Read one request from `stream` and write its response.
+fn handle_connection(stream: &TcpStream, options: &ServeOptions) -> Result<()> {
+    let deadline = Instant::now() + options.request_timeout;
+    stream.set_read_timeout(Some(options.request_timeout))?;
+    stream.set_write_timeout(Some(options.request_timeout))?;
+    let mut reader = BufReader::new(Deadline { inner: stream, deadline });
+    let response = read_head(&mut reader).and_then(|head| respond(&head, &mut reader, stream, options));
+    match response {
+        Ok((content_type, headers, body)) => write_response(stream, 200, content_type, &headers, &body),
+        Err(error) => {
+            log::debug!("Answering {}: {}", error.status, error.message);
+            write_response(stream, error.status, "text/plain", &[], format!("{}\n", error.message).as_bytes())
+        }
+    }
+}
+
+type Response = (&'static str, Vec<(&'static str, String)>, Vec<u8>);
+
+fn respond<R: BufRead>(
+    head: &RequestHead,
+    reader: &mut R,
+    stream: &TcpStream,
+    options: &ServeOptions,
+) -> Result<Response, HttpError> {
+    match (head.method.as_str(), head.path.as_str()) {
+        ("GET", "/healthz") => Ok(("text/plain", Vec::new(), b"ok\n".to_vec())),
+        ("POST", "/render") => {
+            let length = content_length(head, options)?;
+            if head.headers.get("expect").is_some_and(|value| value.eq_ignore_ascii_case("100-continue")) {
+                let _ = (&*stream).write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+            }
+            let body = reader.take(length);
+            let (png, rejected) = match head.headers.get("content-encoding").map(|value| value.to_ascii_lowercase()) {
+                None => render(&head.query, body, options)?,
+                Some(encoding) if encoding == "gzip" => {
+                    let decoded = flate2::read::GzDecoder::new(body);
+                    let limited = Limited { inner: decoded, remaining: options.max_body_bytes };
+                    render(&head.query, BufReader::new(limited), options)?
+                }
+                Some(encoding) => return Err(HttpError::new(415, format!("Unsupported Content-Encoding: {}", encoding))),
+            };
+            Ok(("image/png", vec![("X-Rejected-Lines", rejected.to_string())], png))
+        }
+        (_, "/healthz" | "/render") => Err(HttpError::new(405, format!("{} is not allowed on {}", head.method, head.path))),
+        (_, path) => Err(HttpError::new(404, format!("No such endpoint: {}", path))),
+    }
+}
+
+/// The body length of a render request, which must be given and within the limit.
+fn content_length(head: &RequestHead, options: &ServeOptions) -> Result<u64, HttpError> {
+    if head.headers.contains_key("transfer-encoding") {
+        return Err(HttpError::new(411, "Chunked uploads are not supported; send Content-Length"));
+    }
+    let length = head
+        .headers
+        .get("content-length")
+        .ok_or_else(|| HttpError::new(411, "Content-Length is required"))?;
+    let length: u64 = length
+        .parse()
+        .map_err(|_| HttpError::new(400, format!("Invalid Content-Length: {}", length)))?;
+    if length > options.max_body_bytes {
+        return Err(HttpError::new(413, format!("Request body is larger than {} bytes", options.max_body_bytes)));
+    }
+    Ok(length)
+}
+
+/// Paint `input` with the options in `query` and encode the map, returning the PNG
+/// and the number of rejected lines.
+///
+/// `z`, `accumulate` and `value-mode` choose how input is painted; every other key is
+/// a render option as in [`crate::RenderOptions::apply_overrides`].
+fn render<R: BufRead>(query: &[(String, String)], input: R, options: &ServeOptions) -> Result<(Vec<u8>, u64), HttpError> {
+    let bad_request = |message: String| HttpError::new(400, message);
+    let (mut bits_per_pixel, mut accumulate, mut value_mode) = (options.min_bits_per_pixel.max(8), false, ValueMode::Scaled);
+    let mut overrides = Vec::new();
+    for (key, value) in query {
+        match key.as_str() {
+            "z" => {
+                bits_per_pixel = value
+                    .parse()
+                    .ok()
+                    .filter(|bits| (options.min_bits_per_pixel..=32).contains(bits))
+                    .ok_or_else(|| bad_request(format!("z must be from {} to 32: {}", options.min_bits_per_pixel, value)))?
+            }
+            "accumulate" => {
+                accumulate = match value.as_str() {
+                    "" | "true" | "1" => true,
+                    "false" | "0" => false,
+                    _ => return Err(bad_request(format!("accumulate must be true or false: {}", value))),
+                }
+            }
+            "value-mode" => value_mode = value.parse().map_err(bad_request)?,
+            _ => overrides.push(format!("{}={}", key, value)),
+        }
+    }
+    let mut heatmap = Heatmap::new(DomainType::Linear, None, None, accumulate, bits_per_pixel, &colorous::MAGMA, value_mode, None);
+    let mut render_options = heatmap.render_options();
+    render_options.apply_overrides(&overrides.join(",")).map_err(bad_request)?;
+    heatmap.set_error_policy(ErrorPolicy::Count);
+    heatmap.set_metrics(options.metrics.clone());
+    heatmap.process_input_from_reader(input).map_err(|err| input_error(&err))?;
+
+    let image = heatmap.render(&render_options).map_err(|err| HttpError::new(422, err))?;
+    let mut png = Vec::new();
+    output::write_png(&mut png, &image, &[], &render_options.png).map_err(|err| HttpError::new(500, format!("{:#}", err)))?;
+    Ok((png, heatmap.rejects().total()))
+}
+
+/// The response for input that could not be read to the end.
+fn input_error(err: &anyhow::Error) -> HttpError {
+    let kind = err.chain().find_map(|cause| cause.downcast_ref::<std::io::Error>()).map(std::io::Error::kind);
+    match kind {
+        Some(std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock) => HttpError::new(408, "Timed out reading the request body"),
+        Some(std::io::ErrorKind::FileTooLarge) => HttpError::new(413, "Decoded request body is too large"),
+        _ => HttpError::new(400, format!("Failed to read input: {:#}", err)),
+    }
+}
+
+/// Read the request line and headers.
+fn read_head<R: BufRead>(reader: &mut R) -> Result<RequestHead, HttpError> {
+    let mut limited = reader.take(MAX_HEAD_BYTES);
+    let mut next_line = || {
+        let mut line = String::new();
+        match limited.read_line(&mut line) {
+            Ok(_) if line.ends_with('\n') => Ok(line.trim_end().to_string()),
+            Ok(_) => Err(HttpError::new(431, "Request head is incomplete or too large")),
+            Err(err) if matches!(err.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock) => {
+                Err(HttpError::new(408, "Timed out reading the request"))
+            }
+            Err(err) => Err(HttpError::new(400, format!("Failed to read the request: {}", err))),
+        }
+    };
+    let request_line = next_line()?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(HttpError::new(400, format!("Invalid request line: {}", request_line)));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::new(505, format!("Unsupported protocol: {}", version)));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut headers = HashMap::new();
+    loop {
+        let line = next_line()?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| HttpError::new(400, format!("Invalid header: {}", line)))?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+    Ok(RequestHead {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: parse_query(query)?,
+        headers,
+    })
+}
+
+/// Split and percent-decode `a=1&b=two`.
+fn parse_query(query: &str) -> Result<Vec<(String, String)>, HttpError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> Result<String, HttpError> {
+    let invalid = || HttpError::new(400, format!("Invalid percent-encoding in query: {}", text));
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.bytes();
+    while let Some(byte) = rest.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [rest.next().ok_or_else(invalid)?, rest.next().ok_or_else(invalid)?];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+fn write_response(
+    mut stream: &TcpStream,
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        reason_phrase(status),
+        content_type,
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Close `stream` after its response, first reading (a bounded amount of) what the
+/// client is still sending: closing with unread input would reset the connection,
+/// and the client could lose the response.
+fn close(stream: &TcpStream) {
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+    let _ = std::io::copy(&mut stream.take(MAX_HEAD_BYTES), &mut std::io::sink());
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(request: &str) -> Result<RequestHead, HttpError> {
+        read_head(&mut request.as_bytes())
+    }
+
+    #[test]
+    fn test_read_head() {
+        let parsed = head("POST /render?z=16&palette=vir%69dis&min=+1 HTTP/1.1\r\nContent-Length: 12\r\nHost: x\r\n\r\nbody").unwrap();
+        assert_eq!(parsed.method, "POST");
+        assert_eq!(parsed.path, "/render");
+        assert_eq!(
+            parsed.query,
+            vec![("z".into(), "16".into()), ("palette".into(), "viridis".into()), ("min".into(), " 1".into())]
+        );
+        assert_eq!(parsed.headers["content-length"], "12");
+
+        assert_eq!(head("GET /healthz HTTP/1.1\r\n").unwrap_err().status, 431);
+        assert_eq!(head("GET /healthz\r\n\r\n").unwrap_err().status, 400);
+        assert_eq!(head("GET /healthz SPDY/3\r\n\r\n").unwrap_err().status, 505);
+        assert_eq!(head("GET /?a=%zz HTTP/1.1\r\n\r\n").unwrap_err().status, 400);
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_HEAD_BYTES as usize));
+        assert_eq!(head(&long).unwrap_err().status, 431);
+    }
+
+    #[test]
+    fn test_render_query() {
+        let query = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>();
+        let options = ServeOptions::default();
+        let input = "10.0.0.1 5\nbad\n10.1.0.0/16 3\n";
+        let (png, rejected) =
+            render(&query(&[("z", "16"), ("curve", "log"), ("value-mode", "raw")]), input.as_bytes(), &options).unwrap();
+        assert_eq!(rejected, 1);
+        let image = image::load_from_memory(&png).unwrap();
+        assert_eq!((image.width(), image.height()), (256, 256));
+
+        let status = |pairs: &[(&str, &str)]| render(&query(pairs), input.as_bytes(), &options).unwrap_err().status;
+        assert_eq!(status(&[("z", "6")]), 400);
+        assert_eq!(status(&[("z", "33")]), 400);
+        assert_eq!(status(&[("curve", "cubic")]), 400);
+        assert_eq!(status(&[("accumulate", "maybe")]), 400);
+    }
+}
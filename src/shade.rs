@@ -0,0 +1,208 @@
+use crate::Heatmap;
+use crate::palette::parse_hex_colour;
+use image::{Rgba, RgbaImage};
+use ipnet::Ipv4Net;
+use std::str::FromStr;
+
+/// Line spacing of patterned shades, in pixels, when none is given.
+pub const DEFAULT_SHADE_SPACING: u32 = 6;
+
+/// Opacity of a solid shade.
+const SOLID_ALPHA: f64 = 0.5;
+
+/// How a shade covers its prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ShadeStyle {
+    /// A translucent wash of the shade colour.
+    Solid,
+    /// Diagonal lines rising to the right.
+    Hatch45,
+    /// Diagonal lines in both directions.
+    HatchCross,
+    /// A grid of single-pixel dots.
+    Dots,
+}
+
+impl ShadeStyle {
+    pub const ALL: [ShadeStyle; 4] = [ShadeStyle::Solid, ShadeStyle::Hatch45, ShadeStyle::HatchCross, ShadeStyle::Dots];
+
+    /// Whether the pattern inks the pixel at `(x, y)` of the image. Patterns are
+    /// phased on image coordinates rather than the prefix, so neighbouring shades of
+    /// the same style and spacing continue each other's lines.
+    fn inks(self, x: u32, y: u32, spacing: u32) -> bool {
+        let (x, y, spacing) = (x as i64, y as i64, spacing as i64);
+        match self {
+            Self::Solid => true,
+            Self::Hatch45 => (x + y) % spacing == 0,
+            Self::HatchCross => (x + y) % spacing == 0 || (x - y).rem_euclid(spacing) == 0,
+            Self::Dots => x % spacing == 0 && y % spacing == 0,
+        }
+    }
+}
+
+impl FromStr for ShadeStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "solid" => Ok(Self::Solid),
+            "hatch45" => Ok(Self::Hatch45),
+            "hatch-cross" => Ok(Self::HatchCross),
+            "dots" => Ok(Self::Dots),
+            _ => Err(format!("Invalid shade style: {}. Use 'solid', 'hatch45', 'hatch-cross' or 'dots'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ShadeStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Solid => "solid",
+            Self::Hatch45 => "hatch45",
+            Self::HatchCross => "hatch-cross",
+            Self::Dots => "dots",
+        })
+    }
+}
+
+/// A prefix shaded in a colour and style, written `prefix:rrggbb[:style[:spacing]]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shade {
+    pub net: Ipv4Net,
+    pub colour: [u8; 3],
+    pub style: ShadeStyle,
+    /// Distance in pixels between the lines or dots of a pattern.
+    pub spacing: u32,
+}
+
+impl FromStr for Shade {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("Invalid shade: {}. Use prefix:rrggbb[:style[:spacing]]", s);
+        let mut parts = s.split(':');
+        let net = parts.next().unwrap_or_default();
+        let net = net
+            .parse::<Ipv4Net>()
+            .map_err(|_| format!("Invalid shade prefix: {}", net))?
+            .trunc();
+        let colour = parts.next().ok_or_else(usage).and_then(parse_hex_colour)?;
+        let style = match parts.next() {
+            Some(style) => style.parse()?,
+            None => ShadeStyle::Solid,
+        };
+        let spacing = match parts.next() {
+            Some(spacing) => match spacing.parse::<u32>() {
+                Ok(spacing) if spacing >= 2 => spacing,
+                _ => return Err(format!("Shade spacing must be at least 2 pixels: {}", spacing)),
+            },
+            None => DEFAULT_SHADE_SPACING,
+        };
+        if parts.next().is_some() {
+            return Err(usage());
+        }
+        Ok(Self {
+            net,
+            colour,
+            style,
+            spacing,
+        })
+    }
+}
+
+/// `colour` at `alpha` composited over `pixel`.
+pub(crate) fn blend(pixel: Rgba<u8>, colour: [u8; 3], alpha: f64) -> Rgba<u8> {
+    let below = pixel.0[3] as f64 / 255.0;
+    let out = alpha + below * (1.0 - alpha);
+    let channel = |index: usize| {
+        let mixed = (colour[index] as f64 * alpha + pixel.0[index] as f64 * below * (1.0 - alpha)) / out;
+        mixed.round() as u8
+    };
+    Rgba([channel(0), channel(1), channel(2), (out * 255.0).round() as u8])
+}
+
+impl Heatmap {
+    /// Draw `shade` onto `image`, a rendering of this heatmap.
+    ///
+    /// Patterns ink their lines or dots opaquely and leave the pixels between them
+    /// untouched, so the data shows through; a solid shade is a translucent wash.
+    pub fn draw_shade(&self, image: &mut RgbaImage, shade: &Shade) {
+        let (x, y, width, height) = self.prefix_rect(&shade.net);
+        let [r, g, b] = shade.colour;
+        for py in y..(y + height).min(image.height()) {
+            for px in x..(x + width).min(image.width()) {
+                if !shade.style.inks(px, py, shade.spacing) {
+                    continue;
+                }
+                let pixel = image.get_pixel_mut(px, py);
+                *pixel = match shade.style {
+                    ShadeStyle::Solid => blend(*pixel, shade.colour, SOLID_ALPHA),
+                    _ => Rgba([r, g, b, 255]),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap() -> Heatmap {
+        Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_parse_shade() {
+        let shade: Shade = "192.0.2.0/24:ff0000".parse().unwrap();
+        assert_eq!((shade.style, shade.spacing), (ShadeStyle::Solid, DEFAULT_SHADE_SPACING));
+        let shade: Shade = "10.0.0.0/8:00ff00:hatch-cross:4".parse().unwrap();
+        assert_eq!((shade.colour, shade.style, shade.spacing), ([0, 255, 0], ShadeStyle::HatchCross, 4));
+        for style in ["solid", "hatch45", "hatch-cross", "dots"] {
+            assert_eq!(style.parse::<ShadeStyle>().unwrap().to_string(), style);
+        }
+        for invalid in ["10.0.0.0/8", "10.0.0.0/8:ff0000:zigzag", "10.0.0.0/8:ff0000:dots:1", "10.0.0.0/8:ff0000:dots:4:x"] {
+            assert!(invalid.parse::<Shade>().is_err(), "{} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn test_adjacent_shades_tile_seamlessly() {
+        let hm = heatmap();
+        for style in ["hatch45", "hatch-cross", "dots"] {
+            let mut halves = RgbaImage::new(256, 256);
+            for net in ["10.0.0.0/8", "11.0.0.0/8"] {
+                hm.draw_shade(&mut halves, &format!("{}:ff0000:{}:5", net, style).parse().unwrap());
+            }
+            let mut whole = RgbaImage::new(256, 256);
+            hm.draw_shade(&mut whole, &format!("10.0.0.0/7:ff0000:{}:5", style).parse().unwrap());
+            assert_eq!(halves, whole, "{}", style);
+        }
+    }
+
+    #[test]
+    fn test_gaps_stay_transparent() {
+        let hm = heatmap();
+        let mut image = RgbaImage::from_pixel(256, 256, Rgba([1, 2, 3, 255]));
+        hm.draw_shade(&mut image, &"0.0.0.0/0:ffffff:hatch45:4".parse().unwrap());
+        let inked = image.pixels().filter(|p| p.0 == [255, 255, 255, 255]).count();
+        let kept = image.pixels().filter(|p| p.0 == [1, 2, 3, 255]).count();
+        assert_eq!((inked, kept), (256 * 256 / 4, 256 * 256 * 3 / 4));
+
+        let mut image = RgbaImage::new(256, 256);
+        hm.draw_shade(&mut image, &"10.0.0.0/8:ff0000".parse().unwrap());
+        let (x, y, _, _) = hm.prefix_rect(&"10.0.0.0/8".parse().unwrap());
+        assert_eq!(image.get_pixel(x, y).0, [255, 0, 0, 128]);
+        assert_eq!(blend(Rgba([0, 0, 255, 255]), [255, 0, 0], 0.5).0, [128, 0, 128, 255]);
+    }
+}
@@ -0,0 +1,60 @@
+//! SIGHUP, SIGTERM and SIGINT handling for long-running reads from a pipe.
+//!
+//! The handlers only set flags; the main loop polls them between chunks of input.
+
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SIGHUP: c_int = 1;
+const SIGINT: c_int = 2;
+const SIGTERM: c_int = 15;
+
+/// Exit status of a process killed by SIGINT, as reported by shells.
+const INTERRUPTED: c_int = 128 + SIGINT;
+
+static SNAPSHOT: AtomicBool = AtomicBool::new(false);
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn _exit(status: c_int) -> !;
+}
+
+extern "C" fn handle(signum: c_int) {
+    match signum {
+        SIGHUP => SNAPSHOT.store(true, Ordering::SeqCst),
+        SIGINT if TERMINATE.swap(true, Ordering::SeqCst) => {
+            // A second interrupt gives up on the final render
+            unsafe { _exit(INTERRUPTED) }
+        }
+        _ => TERMINATE.store(true, Ordering::SeqCst),
+    }
+}
+
+/// What the main loop was asked to do by a signal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request {
+    /// Write the outputs from the current buffer and keep reading.
+    Snapshot,
+    /// Stop reading, write the outputs and exit.
+    Terminate,
+}
+
+pub fn install() {
+    for signum in [SIGHUP, SIGINT, SIGTERM] {
+        unsafe {
+            signal(signum, handle);
+        }
+    }
+}
+
+/// The pending request, if any. Termination wins over a pending snapshot.
+pub fn take_request() -> Option<Request> {
+    if TERMINATE.load(Ordering::SeqCst) {
+        Some(Request::Terminate)
+    } else if SNAPSHOT.swap(false, Ordering::SeqCst) {
+        Some(Request::Snapshot)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,167 @@
+//! Bar chart of the total value of each /8, a companion to the map.
+
+use crate::Heatmap;
+use crate::histogram::{CHART_BACKGROUND, CHART_BAR, CHART_FOREGROUND, CHART_PADDING, CHART_TEXT_SCALE};
+use crate::legend::format_value;
+use crate::scale::DomainType;
+use crate::strings::Strings;
+use crate::text::{draw_text, fill_rect, text_height, text_width};
+use anyhow::Result;
+use image::RgbaImage;
+
+/// Bars labelled with their /8, largest first, when there is room for the label.
+pub const SLASH8_LABELLED_BARS: usize = 5;
+
+/// Total value of each of the 256 /8s, in address order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Slash8Chart {
+    pub totals: Vec<i64>,
+    /// Bar heights are proportional to log(1 + total) instead of the total.
+    pub log_scale: bool,
+}
+
+impl Slash8Chart {
+    /// First octets of the `count` largest positive totals, largest first and in
+    /// address order for equal totals.
+    pub fn top(&self, count: usize) -> Vec<u8> {
+        let mut octets: Vec<u8> = (0..=255).filter(|&octet| self.totals[octet as usize] > 0).collect();
+        // Stable sort keeps address order for equal totals
+        octets.sort_by_key(|&octet| std::cmp::Reverse(self.totals[octet as usize]));
+        octets.truncate(count);
+        octets
+    }
+
+    /// Height of the bar of `total` in a plot `plot_height` high, where `largest` is
+    /// the largest total. Non-positive totals have no bar.
+    fn bar_height(&self, total: i64, largest: i64, plot_height: u32) -> u32 {
+        if total <= 0 || largest <= 0 {
+            return 0;
+        }
+        let fraction = match self.log_scale {
+            true => (total as f64).ln_1p() / (largest as f64).ln_1p(),
+            false => total as f64 / largest as f64,
+        };
+        // Keep every non-empty /8 visible
+        ((fraction * plot_height as f64).round() as u32).clamp(1, plot_height.max(1))
+    }
+
+    /// A chart of 256 equal bars ordered by first octet, with the
+    /// [`SLASH8_LABELLED_BARS`] largest labelled and the octets at the axis ends,
+    /// titled in the words of `strings`.
+    pub fn render(&self, width: u32, height: u32, strings: &Strings) -> RgbaImage {
+        let mut image = RgbaImage::from_pixel(width, height, CHART_BACKGROUND);
+        let label_height = text_height(CHART_TEXT_SCALE);
+        // Every bar gets the same whole number of pixels, centred in the padding
+        let slot = (width.saturating_sub(CHART_PADDING * 2) / 256).max(1);
+        let plot_width = slot * 256;
+        let plot_left = (width.saturating_sub(plot_width) / 2) as i64;
+        // Room for the title and, above the tallest bar, its label
+        let plot_top = CHART_PADDING * 2 + CHART_PADDING / 2 + label_height * 2;
+        let plot_height = height.saturating_sub(plot_top + CHART_PADDING * 2 + label_height);
+        let plot_bottom = (plot_top + plot_height) as i64;
+
+        let largest = self.totals.iter().copied().max().unwrap_or(0);
+        let curve = strings.curve(if self.log_scale { DomainType::Logarithmic } else { DomainType::Linear });
+        let title = strings.format("chart.slash8", &[("curve", &curve), ("max", &format_value(largest.max(0) as f64))]);
+        draw_text(&mut image, CHART_PADDING as i64, CHART_PADDING as i64, &title, CHART_TEXT_SCALE, CHART_FOREGROUND);
+
+        // Leave a one pixel gap between bars when there is room
+        let bar_width = if slot > 2 { slot - 1 } else { slot };
+        let bar_left = |octet: u8| plot_left + (octet as u32 * slot) as i64;
+        for (octet, &total) in self.totals.iter().enumerate() {
+            let bar_height = self.bar_height(total, largest, plot_height);
+            let top = plot_bottom - bar_height as i64;
+            fill_rect(&mut image, bar_left(octet as u8), top, bar_width, bar_height, CHART_BAR);
+        }
+
+        // Label the largest bars above their tops, skipping labels that would overlap
+        // a larger bar's
+        let mut placed: Vec<(i64, i64)> = Vec::new();
+        for octet in self.top(SLASH8_LABELLED_BARS) {
+            let label = format!("{}/8", octet);
+            let label_width = text_width(&label, CHART_TEXT_SCALE) as i64;
+            let centre = bar_left(octet) + bar_width as i64 / 2;
+            let x = (centre - label_width / 2).clamp(0, (width as i64 - label_width).max(0));
+            let gap = CHART_PADDING as i64;
+            if placed.iter().any(|&(start, end)| x < end + gap && start < x + label_width + gap) {
+                continue;
+            }
+            let bar_height = self.bar_height(self.totals[octet as usize], largest, plot_height);
+            let y = plot_bottom - (bar_height + CHART_PADDING / 2 + label_height) as i64;
+            draw_text(&mut image, x, y, &label, CHART_TEXT_SCALE, CHART_FOREGROUND);
+            placed.push((x, x + label_width));
+        }
+
+        // Axis line and the first and last octet
+        fill_rect(&mut image, plot_left, plot_bottom, plot_width, 1, CHART_FOREGROUND);
+        let label_top = plot_bottom + CHART_PADDING as i64;
+        draw_text(&mut image, plot_left, label_top, "0", CHART_TEXT_SCALE, CHART_FOREGROUND);
+        let high_left = plot_left + plot_width as i64 - text_width("255", CHART_TEXT_SCALE) as i64;
+        draw_text(&mut image, high_left, label_top, "255", CHART_TEXT_SCALE, CHART_FOREGROUND);
+        image
+    }
+}
+
+impl Heatmap {
+    /// Total value of each /8, summed over the Hilbert blocks of the map as for
+    /// [`crate::hottest_prefixes`]. Fails when a pixel is coarser than a /8.
+    pub fn slash8_chart(&self, log_scale: bool) -> Result<Slash8Chart> {
+        let totals = self.prefix_totals(8)?.into_iter().map(|(_, total)| total).collect();
+        Ok(Slash8Chart { totals, log_scale })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap(input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_totals_per_slash8() {
+        let chart = heatmap("10.0.0.0 5\n10.200.0.0 7\n11.0.0.0 2\n255.255.0.0 1\n").slash8_chart(false).unwrap();
+        assert_eq!(chart.totals.len(), 256);
+        assert_eq!((chart.totals[10], chart.totals[11], chart.totals[255]), (12, 2, 1));
+        assert_eq!(chart.totals.iter().sum::<i64>(), 15);
+        assert_eq!(chart.top(2), [10, 11]);
+        assert_eq!(chart.top(10), [10, 11, 255]);
+    }
+
+    #[test]
+    fn test_log_scale_lifts_small_bars() {
+        let linear = Slash8Chart { totals: vec![0; 256], log_scale: false };
+        let log = Slash8Chart { log_scale: true, ..linear.clone() };
+        assert_eq!(linear.bar_height(10, 10_000, 100), 1);
+        assert_eq!(linear.bar_height(5_000, 10_000, 100), 50);
+        assert_eq!(log.bar_height(10, 10_000, 100), 26);
+        assert_eq!(log.bar_height(10_000, 10_000, 100), 100);
+        assert_eq!(log.bar_height(-3, 10_000, 100), 0);
+    }
+
+    #[test]
+    fn test_render_draws_bars_and_labels() {
+        let chart = heatmap("10.0.0.0 5\n192.168.0.0 3\n").slash8_chart(true).unwrap();
+        // 4 pixels per bar: 3 drawn and a gap
+        let image = chart.render(256 * 4 + 16, 240, &Strings::default());
+        assert_eq!(image.dimensions(), (1040, 240));
+        let bar_columns: Vec<u32> = (0..1040).filter(|&x| image.get_pixel(x, 200) == &CHART_BAR).collect();
+        assert_eq!(bar_columns, [48, 49, 50, 776, 777, 778]);
+        // Both bars are labelled above their tops
+        let labelled = |x: std::ops::Range<u32>| {
+            x.into_iter().any(|x| (40..190).any(|y| image.get_pixel(x, y) == &CHART_FOREGROUND))
+        };
+        assert!(labelled(30..70) && labelled(750..810));
+        assert!(!labelled(300..700));
+    }
+
+    #[test]
+    fn test_coarse_maps_are_refused() {
+        let coarse = Heatmap::new(DomainType::Linear, None, None, true, 26, &colorous::MAGMA, ValueMode::Raw, None);
+        assert!(coarse.slash8_chart(false).is_err());
+    }
+}
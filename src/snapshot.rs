@@ -0,0 +1,270 @@
+//! Copy-on-write snapshots of a heatmap's buffer, to render the current state on
+//! other threads while input is still being painted.
+//!
+//! A [`HeatmapSnapshot`] holds the cells and touched mask in chunks of whole rows
+//! (see [`crate::cells::CHUNK_CELLS`]), each behind an [`Arc`]. The heatmap keeps the
+//! chunks of its last snapshot and the next one copies only the chunks written since,
+//! sharing the rest, so taking one costs the chunks touched rather than the buffer.
+//! Snapshots are immutable, `Send` and `Sync`, and cheap to clone.
+
+use crate::downsample::Aggregation;
+use crate::input::ParseOptions;
+use crate::render::RenderOptions;
+use crate::{Heatmap, ValueMode, scale};
+use anyhow::Result;
+use colorous::Gradient;
+use image::RgbaImage;
+use std::sync::Arc;
+
+/// What a heatmap made from another keeps of it: how it paints and colours, but
+/// none of its cells or counts.
+#[derive(Clone)]
+pub(crate) struct Settings {
+    pub curve: scale::DomainType,
+    pub min_value: Option<f64>,
+    pub max_value: Option<f64>,
+    pub accumulate: bool,
+    pub bits_per_pixel: u8,
+    pub downsampled_from: Option<u8>,
+    pub colour_scale: &'static Gradient,
+    pub value_mode: ValueMode,
+    pub parse_options: ParseOptions,
+    pub lines_processed: u64,
+    pub low_memory: bool,
+}
+
+impl Settings {
+    /// An empty heatmap with these settings, at `bits_per_pixel`.
+    pub fn heatmap(&self, bits_per_pixel: u8) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            self.curve,
+            self.min_value,
+            self.max_value,
+            self.accumulate,
+            bits_per_pixel,
+            self.colour_scale,
+            self.value_mode,
+            self.parse_options.separator,
+        );
+        heatmap.parse_options = self.parse_options.clone();
+        heatmap.downsampled_from = self.downsampled_from;
+        heatmap.lines_processed = self.lines_processed;
+        heatmap.low_memory = self.low_memory;
+        heatmap
+    }
+}
+
+/// The chunks of a snapshot, in buffer order.
+#[derive(Clone, Default)]
+pub(crate) struct Chunks {
+    cells: Vec<Arc<[i32]>>,
+    touched: Vec<Arc<[u64]>>,
+}
+
+/// The buffer of a [`Heatmap`] as it was when [`Heatmap::snapshot`] was called.
+///
+/// Records that `--preaggregate` holds back are only in snapshots taken after they
+/// are painted.
+#[derive(Clone)]
+pub struct HeatmapSnapshot {
+    settings: Settings,
+    size: usize,
+    chunk_rows: usize,
+    chunks: Chunks,
+}
+
+impl Heatmap {
+    pub(crate) fn settings(&self) -> Settings {
+        Settings {
+            curve: self.curve,
+            min_value: self.min_value,
+            max_value: self.max_value,
+            accumulate: self.accumulate,
+            bits_per_pixel: self.bits_per_pixel,
+            downsampled_from: self.downsampled_from,
+            colour_scale: self.colour_scale,
+            value_mode: self.value_mode,
+            parse_options: self.parse_options.clone(),
+            lines_processed: self.lines_processed,
+            low_memory: self.low_memory,
+        }
+    }
+
+    /// The buffer as it is now, to render on any thread while painting goes on.
+    ///
+    /// Only the chunks of the buffer written since the last snapshot are copied; the
+    /// others are shared with it.
+    pub fn snapshot(&mut self) -> HeatmapSnapshot {
+        let dirty = self.buffer.take_dirty();
+        let (size, chunk_rows) = (self.buffer.len(), self.buffer.chunk_rows());
+        // A buffer replaced since, as by a state file, starts with every chunk written
+        let previous = self.snapshot_chunks.take().filter(|chunks| chunks.cells.len() == dirty.len());
+        let mut chunks = Chunks::default();
+        for (chunk, &written) in dirty.iter().enumerate() {
+            match &previous {
+                Some(previous) if !written => {
+                    chunks.cells.push(Arc::clone(&previous.cells[chunk]));
+                    chunks.touched.push(Arc::clone(&previous.touched[chunk]));
+                }
+                _ => {
+                    let first = chunk * chunk_rows * size;
+                    let end = ((chunk + 1) * chunk_rows).min(size) * size;
+                    let words = (first / 64).min(self.touched.len())..end.div_ceil(64).min(self.touched.len());
+                    chunks.cells.push(Arc::from(&self.buffer.cells()[first..end]));
+                    chunks.touched.push(Arc::from(&self.touched[words]));
+                }
+            }
+        }
+        self.snapshot_chunks = Some(chunks.clone());
+        HeatmapSnapshot { settings: self.settings(), size, chunk_rows, chunks }
+    }
+}
+
+impl HeatmapSnapshot {
+    pub fn image_size(&self) -> u32 {
+        self.size as u32
+    }
+
+    pub fn bits_per_pixel(&self) -> u8 {
+        self.settings.bits_per_pixel
+    }
+
+    /// Input lines processed when the snapshot was taken.
+    pub fn lines_processed(&self) -> u64 {
+        self.settings.lines_processed
+    }
+
+    /// Number of pixels painted when the snapshot was taken.
+    pub fn touched_pixels(&self) -> u64 {
+        self.chunks.touched.iter().flat_map(|chunk| chunk.iter()).map(|word| word.count_ones() as u64).sum()
+    }
+
+    /// The value of pixel (`x`, `y`), or `None` if it was not painted.
+    pub fn cell(&self, x: usize, y: usize) -> Option<i32> {
+        let chunk = y / self.chunk_rows;
+        // Chunks hold whole rows, and all but a lone chunk a multiple of 64 cells
+        let index = (y - chunk * self.chunk_rows) * self.size + x;
+        let word = *self.chunks.touched[chunk].get(index / 64)?;
+        (word & (1 << (index % 64)) != 0).then(|| self.chunks.cells[chunk][index])
+    }
+
+    /// A heatmap of its own holding the snapshot, which can be rendered, framed or
+    /// saved like any other. The counts of input read are not part of a snapshot.
+    pub fn to_heatmap(&self) -> Heatmap {
+        let mut heatmap = self.settings.heatmap(self.settings.bits_per_pixel);
+        let cells = heatmap.buffer.cells_mut();
+        for (chunk, cells_chunk) in self.chunks.cells.iter().zip(cells.chunks_mut(self.chunk_rows * self.size)) {
+            cells_chunk.copy_from_slice(chunk);
+        }
+        for (word, &snapshot) in heatmap.touched.iter_mut().zip(self.chunks.touched.iter().flat_map(|chunk| chunk.iter())) {
+            *word = snapshot;
+        }
+        heatmap
+    }
+
+    /// Colourise the snapshot, as [`Heatmap::render`] does.
+    pub fn render(&self, options: &RenderOptions) -> Result<RgbaImage, &'static str> {
+        self.to_heatmap().render(options)
+    }
+
+    /// A heatmap of the snapshot reduced to `size` pixels per side, as
+    /// [`Heatmap::downsample`] makes, without a full size copy.
+    pub fn downsample(&self, size: u32, aggregation: Aggregation) -> Result<Heatmap> {
+        crate::downsample::downsample_cells(&self.settings, self.image_size(), size, aggregation, |x, y| self.cell(x, y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+    use std::sync::mpsc;
+
+    fn heatmap(bits_per_pixel: u8) -> Heatmap {
+        Heatmap::new(DomainType::Linear, None, None, true, bits_per_pixel, &colorous::MAGMA, ValueMode::Raw, None)
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_snapshot_matches_the_heatmap() {
+        assert_send_sync::<HeatmapSnapshot>();
+        let mut hm = heatmap(16);
+        hm.process_input_from_string("10.0.0.1 5\n192.168.0.0/16 3\n255.255.255.255 7\n").unwrap();
+        let snapshot = hm.snapshot();
+        let options = hm.render_options();
+        assert_eq!(snapshot.render(&options).unwrap(), hm.render(&options).unwrap());
+        assert_eq!((snapshot.touched_pixels(), snapshot.lines_processed()), (hm.touched_pixels(), 3));
+        let (from_snapshot, from_heatmap) = (snapshot.downsample(64, Aggregation::Sum).unwrap(), hm.downsample(64, Aggregation::Sum).unwrap());
+        assert_eq!(from_snapshot.buffer, from_heatmap.buffer);
+        assert_eq!(from_snapshot.touched, from_heatmap.touched);
+        assert!(snapshot.downsample(48, Aggregation::Max).is_err());
+    }
+
+    #[test]
+    fn test_snapshots_share_unwritten_chunks() {
+        // 1024 rows of 1024 cells, in 16 chunks
+        let mut hm = heatmap(12);
+        hm.paint_address(&"0.0.0.1".parse().unwrap(), 1).unwrap();
+        let first = hm.snapshot();
+        assert_eq!(first.chunks.cells.len(), 16);
+        hm.paint_address(&"255.255.255.255".parse().unwrap(), 2).unwrap();
+        let second = hm.snapshot();
+        let shared = |a: &HeatmapSnapshot, b: &HeatmapSnapshot| {
+            a.chunks.cells.iter().zip(&b.chunks.cells).filter(|(a, b)| Arc::ptr_eq(a, b)).count()
+        };
+        assert_eq!(shared(&first, &second), 15);
+        // The first snapshot still holds the buffer as it was
+        assert_eq!((first.touched_pixels(), second.touched_pixels()), (1, 2));
+        let size = hm.image_size() as usize;
+        assert_eq!((first.cell(size - 1, 0), second.cell(size - 1, 0)), (None, Some(2)));
+        assert_eq!(shared(&second, &hm.snapshot()), 16);
+        // Writes that may touch any cell copy every chunk again
+        hm.buffer.cells_mut();
+        assert_eq!(shared(&second, &hm.snapshot()), 0);
+    }
+
+    #[test]
+    fn test_snapshots_render_while_painting() {
+        const LINES: u32 = 20_000;
+        let (sender, receiver) = mpsc::sync_channel::<(u32, HeatmapSnapshot)>(4);
+        let writer = std::thread::spawn(move || {
+            // Four chunks, so most snapshots share some of them
+            let mut hm = heatmap(14);
+            for line in 1..=LINES {
+                // Every line paints a pixel of its own, spread over the whole map
+                let address = std::net::Ipv4Addr::from((line.wrapping_mul(2_654_435_761) & 0x3ffff) << 14);
+                hm.process_input_from_string(&format!("{} {}\n", address, line)).unwrap();
+                if line % 500 == 0 && sender.send((line, hm.snapshot())).is_err() {
+                    break;
+                }
+            }
+        });
+        let receiver = Arc::new(std::sync::Mutex::new(receiver));
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                std::thread::spawn(move || {
+                    let mut rendered = 0;
+                    loop {
+                        let received = receiver.lock().unwrap().recv();
+                        let Ok((lines, snapshot)) = received else {
+                            break rendered;
+                        };
+                        // Exactly the lines read so far, and nothing painted later
+                        assert_eq!((snapshot.lines_processed(), snapshot.touched_pixels()), (lines as u64, lines as u64));
+                        let copy = snapshot.to_heatmap();
+                        let total: i64 = copy.buffer.cells().iter().map(|&value| value as i64).sum();
+                        assert_eq!(total, lines as i64 * (lines as i64 + 1) / 2);
+                        let image = snapshot.render(&copy.render_options()).unwrap();
+                        assert_eq!(image.pixels().filter(|pixel| pixel.0[3] > 0).count(), lines as usize);
+                        rendered += 1;
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        let rendered: u32 = readers.into_iter().map(|reader| reader.join().unwrap()).sum();
+        assert_eq!(rendered, LINES / 500);
+    }
+}
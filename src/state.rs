@@ -0,0 +1,442 @@
+//! Saved heatmap buffers ("state files").
+//!
+//! A state file is a fixed-size little-endian header followed by the cell values
+//! (`i32`, row-major) and the touched-pixel mask (`u64` words). The header carries
+//! summary statistics so a file can be inspected without reading the buffer.
+
+use crate::cells::{Grid, Slab};
+use crate::endian;
+use crate::mapped::{self, Mapping};
+use crate::{Geometry, Heatmap, ValueMode, image_size_for_bpp};
+use anyhow::{Context, Result, anyhow, bail};
+use std::io::{BufReader, Read, Write};
+use std::rc::Rc;
+
+const MAGIC: &[u8; 8] = b"IPHMSTAT";
+const VERSION: u32 = 1;
+/// Length of the header in bytes.
+pub const STATE_HEADER_LEN: usize = 56;
+/// Touched mask words converted at a time.
+const MASK_CHUNK_WORDS: usize = 1024;
+
+/// The header of a state file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateHeader {
+    pub version: u32,
+    pub bits_per_pixel: u8,
+    pub value_mode: ValueMode,
+    pub accumulate: bool,
+    /// Input lines processed into the buffer.
+    pub lines: u64,
+    pub nonzero_cells: u64,
+    pub min_value: i32,
+    pub max_value: i32,
+    pub total: i64,
+    pub touched_pixels: u64,
+}
+
+impl StateHeader {
+    fn for_heatmap(heatmap: &Heatmap) -> Self {
+        let (min_value, max_value) = heatmap.value_range();
+        let mut nonzero_cells = 0;
+        let mut total = 0i64;
+        for &value in heatmap.buffer.iter().flatten() {
+            // Skip the "no data" sentinel of categorical mode
+            if value != 0 && (value > 0 || heatmap.value_mode != ValueMode::Categorical) {
+                nonzero_cells += 1;
+                total += value as i64;
+            }
+        }
+        Self {
+            version: VERSION,
+            bits_per_pixel: heatmap.bits_per_pixel,
+            value_mode: heatmap.value_mode,
+            accumulate: heatmap.accumulate,
+            lines: heatmap.lines_processed,
+            nonzero_cells,
+            min_value,
+            max_value,
+            total,
+            touched_pixels: heatmap.touched_pixels(),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; STATE_HEADER_LEN] {
+        let mut bytes = [0u8; STATE_HEADER_LEN];
+        bytes[0..8].copy_from_slice(MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12] = self.bits_per_pixel;
+        bytes[13] = match self.value_mode {
+            ValueMode::Raw => 0,
+            ValueMode::Scaled => 1,
+            ValueMode::Categorical => 2,
+        };
+        bytes[14] = self.accumulate as u8;
+        bytes[16..24].copy_from_slice(&self.lines.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.nonzero_cells.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.min_value.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.max_value.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.total.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.touched_pixels.to_le_bytes());
+        bytes
+    }
+
+    /// Parse and validate a header.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < MAGIC.len() || &bytes[0..8] != MAGIC {
+            bail!("Not a heatmap state file (bad magic)");
+        }
+        if bytes.len() < STATE_HEADER_LEN {
+            bail!(
+                "Truncated state file header ({} of {} bytes)",
+                bytes.len(),
+                STATE_HEADER_LEN
+            );
+        }
+        let u64_at = |offset: usize| endian::u64_at(bytes, offset);
+
+        let version = endian::u32_at(bytes, 8);
+        if version != VERSION {
+            bail!("Unsupported state file version {} (expected {})", version, VERSION);
+        }
+        let bits_per_pixel = bytes[12];
+        if bits_per_pixel > 32 || !bits_per_pixel.is_multiple_of(2) {
+            bail!("Invalid bits_per_pixel {} in state file", bits_per_pixel);
+        }
+        let value_mode = match bytes[13] {
+            0 => ValueMode::Raw,
+            1 => ValueMode::Scaled,
+            2 => ValueMode::Categorical,
+            other => bail!("Invalid value mode {} in state file", other),
+        };
+        Ok(Self {
+            version,
+            bits_per_pixel,
+            value_mode,
+            accumulate: bytes[14] != 0,
+            lines: u64_at(16),
+            nonzero_cells: u64_at(24),
+            min_value: endian::i32_at(bytes, 32),
+            max_value: endian::i32_at(bytes, 36),
+            total: u64_at(40) as i64,
+            touched_pixels: u64_at(48),
+        })
+    }
+
+    /// Read the header at the start of `reader`.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut bytes = Vec::with_capacity(STATE_HEADER_LEN);
+        reader
+            .take(STATE_HEADER_LEN as u64)
+            .read_to_end(&mut bytes)
+            .context("Failed to read state file header")?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// The length of a complete state file at `bits_per_pixel`.
+pub(crate) fn state_file_len(bits_per_pixel: u8) -> u64 {
+    let size = image_size_for_bpp(bits_per_pixel) as u64;
+    let cells = size * size;
+    STATE_HEADER_LEN as u64 + cells * 4 + cells.div_ceil(64) * 8
+}
+
+/// Whether the file at `path` starts with the state file magic.
+pub fn is_state_file(path: &str) -> Result<bool> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    file.take(MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .with_context(|| format!("Failed to read {}", path))?;
+    Ok(magic == MAGIC)
+}
+
+impl Heatmap {
+    /// Write the buffer and its header to `writer`.
+    pub fn write_state<W: Write>(&self, mut writer: W) -> Result<()> {
+        writer.write_all(&StateHeader::for_heatmap(self).to_bytes())?;
+        let mut row_bytes = Vec::with_capacity(self.image_size() as usize * 4);
+        for row in &self.buffer {
+            row_bytes.clear();
+            endian::extend_i32s(&mut row_bytes, row);
+            writer.write_all(&row_bytes)?;
+        }
+        for words in self.touched.chunks(MASK_CHUNK_WORDS) {
+            row_bytes.clear();
+            endian::extend_u64s(&mut row_bytes, words);
+            writer.write_all(&row_bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Save the buffer to a state file, to be restored with [`Heatmap::load_state`].
+    pub fn save_state(&self, filename: &str) -> Result<()> {
+        crate::output::write_atomic(filename, |writer| self.write_state(writer))
+            .with_context(|| format!("Failed to write state file {}", filename))
+    }
+
+    /// Restore a heatmap written by [`Heatmap::write_state`].
+    ///
+    /// Rendering parameters are not part of the state and take their defaults.
+    pub fn read_state<R: Read>(mut reader: R) -> Result<Self> {
+        let header = StateHeader::read(&mut reader)?;
+        Geometry::for_bits_per_pixel(header.bits_per_pixel).check_platform().map_err(|err| anyhow!(err))?;
+        let mut heatmap = Heatmap::new(
+            crate::DomainType::Linear,
+            None,
+            None,
+            header.accumulate,
+            header.bits_per_pixel,
+            &colorous::MAGMA,
+            header.value_mode,
+            None,
+        );
+        heatmap.lines_processed = header.lines;
+
+        let size = image_size_for_bpp(header.bits_per_pixel) as usize;
+        let mut row_bytes = vec![0u8; size * 4];
+        for row in heatmap.buffer.iter_mut() {
+            reader
+                .read_exact(&mut row_bytes)
+                .context("Truncated state file: buffer is incomplete")?;
+            endian::read_i32s(&row_bytes, row);
+        }
+        let mut mask_bytes = vec![0u8; MASK_CHUNK_WORDS * 8];
+        for words in heatmap.touched.chunks_mut(MASK_CHUNK_WORDS) {
+            let word_bytes = &mut mask_bytes[..words.len() * 8];
+            reader
+                .read_exact(word_bytes)
+                .context("Truncated state file: touched mask is incomplete")?;
+            endian::read_u64s(word_bytes, words);
+        }
+        heatmap.discard_stray_touched();
+        Ok(heatmap)
+    }
+
+    /// Load a state file written by [`Heatmap::save_state`].
+    pub fn load_state(filename: &str) -> Result<Self> {
+        let file = std::fs::File::open(filename)
+            .with_context(|| format!("Failed to open state file {}", filename))?;
+        Self::read_state(BufReader::new(file))
+            .with_context(|| format!("Failed to load state file {}", filename))
+    }
+}
+
+impl Heatmap {
+    /// Keep the buffer in the state file at `path`, memory-mapped, rather than on the heap.
+    ///
+    /// A missing or empty file is created; an existing one is resumed, and must match
+    /// this heatmap's bits_per_pixel, value mode and accumulation. Call this before
+    /// processing any input. The file is only consistent after [`Heatmap::sync_state`];
+    /// in between, the OS writes changed pages back whenever it likes.
+    pub fn map_state(&mut self, path: &str) -> Result<()> {
+        if !mapped::SUPPORTED {
+            bail!("Memory-mapped state files are only supported on 64-bit little-endian Linux and macOS");
+        }
+        if self.lines_processed > 0 {
+            bail!("The state file must be mapped before processing input");
+        }
+        let size = self.image_size() as usize;
+        let cells = size * size;
+        // The touched mask follows the cells and must stay 8-byte aligned
+        if !cells.is_multiple_of(2) {
+            bail!("Memory-mapped state files need bits_per_pixel below 32");
+        }
+        let len = state_file_len(self.bits_per_pixel) as usize;
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open state file {}", path))?;
+        let file_len = file.metadata()?.len();
+        let resumed = if file_len > 0 {
+            let header = StateHeader::read(&mut &file).with_context(|| format!("Failed to resume state file {}", path))?;
+            if (header.bits_per_pixel, header.value_mode, header.accumulate)
+                != (self.bits_per_pixel, self.value_mode, self.accumulate)
+            {
+                bail!(
+                    "State file {} holds bits_per_pixel {}, value mode {}, accumulate {}; \
+                     this run uses {}, {}, {}",
+                    path,
+                    header.bits_per_pixel,
+                    header.value_mode,
+                    header.accumulate,
+                    self.bits_per_pixel,
+                    self.value_mode,
+                    self.accumulate
+                );
+            }
+            if file_len != len as u64 {
+                bail!("State file {} is {} bytes, expected {}", path, file_len, len);
+            }
+            Some(header)
+        } else {
+            file.set_len(len as u64)
+                .with_context(|| format!("Failed to size state file {}", path))?;
+            None
+        };
+
+        let mapping = Rc::new(Mapping::map(file, len).with_context(|| format!("Failed to map state file {}", path))?);
+        let mut buffer = Grid::from_slab(size, Slab::mapped(&mapping, STATE_HEADER_LEN, cells));
+        let mut touched = Slab::mapped(&mapping, STATE_HEADER_LEN + cells * 4, cells.div_ceil(64));
+        match &resumed {
+            Some(header) => self.lines_processed = header.lines,
+            None => {
+                // A new file reads as zeros; copying only the rest leaves empty pages sparse
+                for (cell, &value) in buffer.cells_mut().iter_mut().zip(self.buffer.cells()) {
+                    if value != 0 {
+                        *cell = value;
+                    }
+                }
+                for (word, &bits) in touched.iter_mut().zip(self.touched.iter()) {
+                    if bits != 0 {
+                        *word = bits;
+                    }
+                }
+            }
+        }
+        self.buffer = buffer;
+        self.touched = touched;
+        self.mapping = Some(mapping);
+        if resumed.is_some() {
+            self.discard_stray_touched();
+        }
+        self.sync_state()
+    }
+
+    /// Drop touched bits past the last pixel, which a damaged state file may hold.
+    fn discard_stray_touched(&mut self) {
+        let stray = self.clear_stray_touched();
+        if stray > 0 {
+            log::warn!(
+                count = stray;
+                "The state file marks {} pixels past the end of the map as painted; ignoring them",
+                stray
+            );
+        }
+    }
+
+    /// Checkpoint a buffer mapped with [`Heatmap::map_state`]: update the header and
+    /// wait until all changes are on disk.
+    pub fn sync_state(&self) -> Result<()> {
+        let Some(mapping) = &self.mapping else {
+            bail!("The buffer is not memory-mapped");
+        };
+        mapping.write_at_start(&StateHeader::for_heatmap(self).to_bytes());
+        mapping.sync().context("Failed to sync memory-mapped state file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DomainType;
+
+    fn heatmap(input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let original = heatmap("10.0.0.1 5\n10.0.0.2 7\n192.168.0.0/16 3\n");
+        let mut bytes = Vec::new();
+        original.write_state(&mut bytes).unwrap();
+
+        let header = StateHeader::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(header.bits_per_pixel, 16);
+        assert_eq!(header.lines, 3);
+        assert_eq!(header.nonzero_cells, 2);
+        assert_eq!((header.min_value, header.max_value), (0, 12));
+        assert_eq!(header.total, 15);
+
+        let restored = Heatmap::read_state(bytes.as_slice()).unwrap();
+        assert_eq!(restored.buffer, original.buffer);
+        assert_eq!(restored.touched, original.touched);
+        assert_eq!(restored.lines_processed(), 3);
+    }
+
+    #[test]
+    fn test_invalid_state_files_are_rejected() {
+        let mut bytes = Vec::new();
+        heatmap("10.0.0.1\n").write_state(&mut bytes).unwrap();
+
+        assert!(Heatmap::read_state(&b"10.0.0.1\n"[..]).is_err());
+        let truncated = Heatmap::read_state(&bytes[..bytes.len() - 1]).err().unwrap();
+        assert!(format!("{:#}", truncated).contains("Truncated"));
+
+        let mut bad_version = bytes.clone();
+        bad_version[8] = 9;
+        assert!(Heatmap::read_state(bad_version.as_slice()).is_err());
+    }
+
+    fn empty_heatmap(value_mode: ValueMode) -> Heatmap {
+        Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, value_mode, None)
+    }
+
+    #[test]
+    fn test_resume_mapped_state() {
+        if !mapped::SUPPORTED {
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("ip-heatmap-mapped-{}.state", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let mut first = empty_heatmap(ValueMode::Raw);
+        first.map_state(path).unwrap();
+        first.process_input_from_string("10.0.0.1 5\n192.168.0.0/16 3\n").unwrap();
+        first.sync_state().unwrap();
+        drop(first);
+
+        // The mapping is an ordinary state file
+        let loaded = Heatmap::load_state(path).unwrap();
+        assert_eq!(loaded.buffer, heatmap("10.0.0.1 5\n192.168.0.0/16 3\n").buffer);
+
+        let mut resumed = empty_heatmap(ValueMode::Raw);
+        resumed.map_state(path).unwrap();
+        assert_eq!(resumed.lines_processed(), 2);
+        resumed.process_input_from_string("10.0.0.2 7\n").unwrap();
+        resumed.sync_state().unwrap();
+        let expected = heatmap("10.0.0.1 5\n192.168.0.0/16 3\n10.0.0.2 7\n");
+        assert_eq!(resumed.buffer, expected.buffer);
+        assert_eq!(resumed.touched, expected.touched);
+        drop(resumed);
+
+        let mismatch = empty_heatmap(ValueMode::Categorical).map_state(path).err().unwrap();
+        assert!(format!("{:#}", mismatch).contains("value mode raw"), "{:#}", mismatch);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_new_mapped_state_keeps_initial_values() {
+        if !mapped::SUPPORTED {
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("ip-heatmap-mapped-new-{}.state", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        // Categorical buffers start at the -1 "no data" sentinel, not zero
+        let mut hm = empty_heatmap(ValueMode::Categorical);
+        hm.map_state(path).unwrap();
+        assert!(hm.buffer.cells().iter().all(|&value| value == -1));
+        assert!(hm.sync_state().is_ok());
+        assert!(heatmap("").sync_state().is_err());
+        drop(hm);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,524 @@
+use crate::Heatmap;
+use crate::distinct::DistinctEstimate;
+use crate::json::JsonValue;
+use crate::live::LiveStats;
+use crate::rejects::RejectReason;
+use crate::table::{Align, Table, TextStyle};
+use crate::timing::Phase;
+use std::time::Duration;
+
+/// Prefix lengths reported by `--coverage-report` unless configured otherwise.
+pub const DEFAULT_COVERAGE_PREFIXES: [u8; 3] = [8, 16, 24];
+
+/// How many prefixes of one length contain at least one painted address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoverageEntry {
+    pub prefix_len: u8,
+    pub covered: u64,
+    pub total: u64,
+    /// False when the prefix is finer than a pixel and `covered` is an upper bound.
+    pub exact: bool,
+}
+
+impl CoverageEntry {
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.covered as f64 * 100.0 / self.total as f64
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut entry = JsonValue::object();
+        entry.insert("prefix_len", self.prefix_len);
+        entry.insert("covered", self.covered);
+        entry.insert("total", self.total);
+        entry.insert("percent", self.percent());
+        entry.insert("exact", self.exact);
+        entry
+    }
+}
+
+/// Summary of a processed heatmap, reported with `--stats-json`.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub lines: u64,
+    pub rejected: u64,
+    /// Rejected lines holding IPv6 addresses (included in `rejected`).
+    pub ipv6_skipped: u64,
+    /// Painted prefixes that had host bits set.
+    pub cidr_host_bits: u64,
+    /// Records dropped by `--ignore-value`.
+    pub ignored_values: u64,
+    /// Records dropped by `--since` and `--until`.
+    pub outside_window: u64,
+    /// Records dropped as repeats by `--dedup-window`.
+    pub deduplicated: u64,
+    /// Paints that overwrote a different value without `-C`, see `--on-conflict`.
+    pub conflicts: u64,
+    /// Distinct keys painted with `--preaggregate` or `--distinct`.
+    pub preaggregated_keys: u64,
+    /// Pixels skipped outside a buffer that does not match the map.
+    pub out_of_bounds: u64,
+    /// Estimated distinct addresses with `--distinct-approx`.
+    pub distinct: Option<DistinctEstimate>,
+    /// Warnings about rejected lines that were counted without being logged.
+    pub suppressed_warnings: u64,
+    pub touched_pixels: u64,
+    /// Sum of the record values read, after `--weight` and sample scaling.
+    pub weighted_total: i64,
+    /// Records read, including those outside the view.
+    pub records: u64,
+    /// Records outside the view, see [`Heatmap::set_view`].
+    pub clipped_records: u64,
+    /// Sum of the values of the clipped records.
+    pub clipped_value: i64,
+    /// Fraction of lines processed with `--sample`, 1 for a full run.
+    pub sample_rate: f64,
+    pub coverage: Vec<CoverageEntry>,
+    /// Time spent per phase, empty unless timing was enabled.
+    pub timing: Vec<(Phase, Duration)>,
+    /// The queues input was read through while painting, see [`crate::LiveInput`].
+    pub live_queues: Option<LiveStats>,
+}
+
+impl Stats {
+    pub fn to_json(&self) -> JsonValue {
+        let mut stats = JsonValue::object();
+        stats.insert("lines", self.lines);
+        stats.insert("rejected", self.rejected);
+        stats.insert("ipv6_skipped", self.ipv6_skipped);
+        stats.insert("cidr_host_bits", self.cidr_host_bits);
+        stats.insert("ignored_values", self.ignored_values);
+        stats.insert("outside_window", self.outside_window);
+        stats.insert("deduplicated", self.deduplicated);
+        stats.insert("conflicts", self.conflicts);
+        stats.insert("out_of_bounds", self.out_of_bounds);
+        if self.preaggregated_keys > 0 {
+            stats.insert("preaggregated_keys", self.preaggregated_keys);
+        }
+        if let Some(distinct) = &self.distinct {
+            let mut estimate = JsonValue::object();
+            estimate.insert("estimate", distinct.count.round() as u64);
+            estimate.insert("relative_error", distinct.relative_error);
+            estimate.insert("approximate", true);
+            stats.insert("distinct", estimate);
+        }
+        stats.insert("suppressed_warnings", self.suppressed_warnings);
+        stats.insert("touched_pixels", self.touched_pixels);
+        stats.insert("weighted_total", self.weighted_total);
+        stats.insert("records", self.records);
+        stats.insert("clipped_records", self.clipped_records);
+        stats.insert("clipped_value", self.clipped_value);
+        stats.insert("clipped_percent", self.clipped_percent());
+        stats.insert("sample_rate", self.sample_rate);
+        if !self.coverage.is_empty() {
+            let coverage: Vec<JsonValue> = self.coverage.iter().map(CoverageEntry::to_json).collect();
+            stats.insert("coverage", coverage);
+        }
+        if !self.timing.is_empty() {
+            let mut timing = JsonValue::object();
+            for (phase, duration) in &self.timing {
+                timing.insert(&format!("{}_ms", phase.name()), duration.as_secs_f64() * 1e3);
+            }
+            stats.insert("timing", timing);
+        }
+        // The queues depend on how the run was scheduled, so like the timing they are
+        // left out of reports that should be the same for the same input
+        if let Some(live_queues) = self.live_queues.filter(|queues| queues.was_full() || !self.timing.is_empty()) {
+            stats.insert("live_queues", live_queues.to_json());
+        }
+        stats
+    }
+
+    /// Counts as printed by `--stats`.
+    pub fn to_text(&self, style: TextStyle) -> String {
+        let mut table = Table::new(&[("count", Align::Left), ("value", Align::Left)]);
+        let mut row = |name: &str, value: String| table.push(vec![name.to_string(), value]);
+        row("lines", self.lines.to_string());
+        row("rejected", self.rejected.to_string());
+        row("ipv6 skipped", self.ipv6_skipped.to_string());
+        row("cidr host bits", self.cidr_host_bits.to_string());
+        row("ignored values", self.ignored_values.to_string());
+        if self.outside_window > 0 {
+            row("outside window", self.outside_window.to_string());
+        }
+        if self.deduplicated > 0 {
+            row("deduplicated", self.deduplicated.to_string());
+        }
+        if self.conflicts > 0 {
+            row("conflicts", self.conflicts.to_string());
+        }
+        if self.preaggregated_keys > 0 {
+            row("distinct keys", self.preaggregated_keys.to_string());
+        }
+        if self.out_of_bounds > 0 {
+            row("out of bounds", self.out_of_bounds.to_string());
+        }
+        if let Some(distinct) = &self.distinct {
+            row(
+                "distinct",
+                format!(
+                    "~{} (approximate, {}{:.1}%)",
+                    distinct.count.round() as u64,
+                    style.plus_minus(),
+                    distinct.relative_error * 100.0
+                ),
+            );
+        }
+        if self.suppressed_warnings > 0 {
+            row("suppressed warnings", self.suppressed_warnings.to_string());
+        }
+        row("touched pixels", self.touched_pixels.to_string());
+        row("value total", self.weighted_total.to_string());
+        if self.clipped_records > 0 {
+            row(
+                "outside view",
+                format!("{} records, {} value ({:.1}%)", self.clipped_records, self.clipped_value, self.clipped_percent()),
+            );
+        }
+        if self.sample_rate < 1.0 {
+            row("sample rate", format!("{} (painted values are estimates)", self.sample_rate));
+        }
+        // Queues that kept up are not worth a row
+        if let Some(live_queues) = self.live_queues.filter(LiveStats::was_full) {
+            row("read queue", live_queues.read.to_text());
+            row("parsed queue", live_queues.parsed.to_text());
+            if live_queues.dropped_lines > 0 {
+                row("dropped lines", live_queues.dropped_lines.to_string());
+            }
+        }
+        table.render(style)
+    }
+
+    /// Share of the input outside the view in percent: the larger of the share of
+    /// records and, when the total is positive, the share of value.
+    pub fn clipped_percent(&self) -> f64 {
+        let share = |part: f64, total: f64| if total > 0.0 { part * 100.0 / total } else { 0.0 };
+        let records = share(self.clipped_records as f64, self.records as f64);
+        records.max(share(self.clipped_value as f64, self.weighted_total as f64))
+    }
+
+    /// A note such as `40% of data outside view` when more than `threshold_percent`
+    /// of the input was clipped.
+    pub fn clipped_note(&self, threshold_percent: f64) -> Option<String> {
+        let percent = self.clipped_percent();
+        (self.clipped_records > 0 && percent > threshold_percent)
+            .then(|| format!("{:.0}% of data outside view", percent))
+    }
+
+    /// The coverage table as printed by `--coverage-report`, with a note column
+    /// when a prefix is finer than a pixel.
+    pub fn coverage_text(&self, style: TextStyle) -> String {
+        let mut columns = vec![("prefix", Align::Left), ("covered", Align::Right), ("of", Align::Right), ("share", Align::Right)];
+        let notes = self.coverage.iter().any(|entry| !entry.exact);
+        if notes {
+            columns.push(("note", Align::Left));
+        }
+        let mut table = Table::new(&columns);
+        for entry in &self.coverage {
+            let mut row = vec![
+                format!("/{}", entry.prefix_len),
+                entry.covered.to_string(),
+                entry.total.to_string(),
+                format!("{:.2}%", entry.percent()),
+            ];
+            if notes && !entry.exact {
+                row.push("upper bound, finer than a pixel".to_string());
+            }
+            table.push(row);
+        }
+        table.render(style)
+    }
+}
+
+impl Heatmap {
+    /// Coverage of each of `prefix_lens`, see [`Heatmap::coverage`].
+    pub fn coverage_report(&self, prefix_lens: &[u8]) -> Vec<CoverageEntry> {
+        prefix_lens
+            .iter()
+            .map(|&prefix_len| {
+                let (covered, total) = self.coverage(prefix_len);
+                CoverageEntry {
+                    prefix_len: prefix_len.min(32),
+                    covered,
+                    total,
+                    exact: prefix_len <= self.geometry().prefix_len_per_pixel,
+                }
+            })
+            .collect()
+    }
+
+    /// Summary statistics, including coverage for `coverage_prefixes`.
+    pub fn stats(&self, coverage_prefixes: &[u8]) -> Stats {
+        Stats {
+            lines: self.lines_processed(),
+            rejected: self.rejects().total(),
+            ipv6_skipped: self.rejects().count(RejectReason::Ipv6),
+            cidr_host_bits: self.cidr_host_bits(),
+            ignored_values: self.ignored_values(),
+            outside_window: self.outside_window(),
+            deduplicated: self.deduplicated(),
+            conflicts: self.conflicts(),
+            preaggregated_keys: self.preaggregated_keys(),
+            out_of_bounds: self.out_of_bounds(),
+            distinct: self.distinct_estimate(),
+            suppressed_warnings: self.suppressed_warnings(),
+            touched_pixels: self.touched_pixels(),
+            weighted_total: self.weighted_total(),
+            records: self.records(),
+            clipped_records: self.clipped_records(),
+            clipped_value: self.clipped_value(),
+            sample_rate: self.sampling().map_or(1.0, |sampling| sampling.rate()),
+            coverage: self.coverage_report(coverage_prefixes),
+            timing: match self.timer().is_enabled() {
+                true => self.timer().totals(),
+                false => Vec::new(),
+            },
+            live_queues: self.live_queues().copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CidrHostBits, DomainType, ValueMode};
+
+    fn heatmap(bits_per_pixel: u8, input: &str) -> Heatmap {
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        );
+        heatmap.process_input_from_string(input).unwrap();
+        heatmap
+    }
+
+    #[test]
+    fn test_coverage_counts_distinct_prefixes() {
+        let hm = heatmap(8, "10.0.0.1\n10.0.0.2\n10.1.0.1\n192.168.1.1\n");
+        assert_eq!(hm.coverage(8), (2, 256));
+        assert_eq!(hm.coverage(16), (3, 65536));
+        assert_eq!(hm.coverage(24), (3, 1 << 24));
+        assert_eq!(hm.coverage(0), (1, 1));
+    }
+
+    #[test]
+    fn test_coverage_of_cidr_ranges() {
+        let hm = heatmap(12, "10.0.0.0/8\n11.0.0.0/15\n");
+        assert_eq!(hm.coverage(8), (2, 256));
+        assert_eq!(hm.coverage(16), (256 + 2, 65536));
+        assert_eq!(hm.coverage(20), (4096 + 32, 1 << 20));
+    }
+
+    #[test]
+    fn test_coverage_finer_than_pixel_is_upper_bound() {
+        let hm = heatmap(16, "10.0.0.1\n10.0.0.2\n");
+        // One /16 pixel touched: at most 256 /24s contain a painted address
+        assert_eq!(hm.coverage(24), (256, 1 << 24));
+        let report = hm.coverage_report(&[16, 24]);
+        assert!(report[0].exact);
+        assert!(!report[1].exact);
+        assert!(hm.stats(&[16, 24]).coverage_text(TextStyle::ASCII).contains("upper bound"));
+    }
+
+    #[test]
+    fn test_coverage_table() {
+        let stats = heatmap(16, "10.0.0.1\n10.1.0.1\n").stats(&[8, 16, 24]);
+        assert_eq!(
+            stats.coverage_text(TextStyle::ASCII),
+            "+--------+---------+----------+-------+---------------------------------+\n\
+             | prefix | covered |       of | share | note                            |\n\
+             +--------+---------+----------+-------+---------------------------------+\n\
+             | /8     |       1 |      256 | 0.39% |                                 |\n\
+             | /16    |       2 |    65536 | 0.00% |                                 |\n\
+             | /24    |     512 | 16777216 | 0.00% | upper bound, finer than a pixel |\n\
+             +--------+---------+----------+-------+---------------------------------+\n"
+        );
+        assert_eq!(
+            heatmap(16, "10.0.0.1\n").stats(&[8]).coverage_text(TextStyle::TERMINAL),
+            "┌────────┬─────────┬─────┬───────┐\n\
+             │ \x1b[1mprefix\x1b[0m │ \x1b[1mcovered\x1b[0m │ \x1b[1m of\x1b[0m │ \x1b[1mshare\x1b[0m │\n\
+             ├────────┼─────────┼─────┼───────┤\n\
+             │ /8     │       1 │ 256 │ 0.39% │\n\
+             └────────┴─────────┴─────┴───────┘\n"
+        );
+    }
+
+    #[test]
+    fn test_stats_table() {
+        let mut stats = heatmap(16, "10.0.0.1 4\nbad\n").stats(&[]);
+        stats.distinct = Some(DistinctEstimate { count: 1.0, relative_error: 0.008 });
+        assert_eq!(
+            stats.to_text(TextStyle::ASCII),
+            "+----------------+---------------------------+\n\
+             | count          | value                     |\n\
+             +----------------+---------------------------+\n\
+             | lines          | 2                         |\n\
+             | rejected       | 1                         |\n\
+             | ipv6 skipped   | 0                         |\n\
+             | cidr host bits | 0                         |\n\
+             | ignored values | 0                         |\n\
+             | distinct       | ~1 (approximate, +/-0.8%) |\n\
+             | touched pixels | 1                         |\n\
+             | value total    | 4                         |\n\
+             +----------------+---------------------------+\n"
+        );
+        assert_eq!(
+            stats.to_text(TextStyle { unicode: true, colour: false }),
+            "┌────────────────┬─────────────────────────┐\n\
+             │ count          │ value                   │\n\
+             ├────────────────┼─────────────────────────┤\n\
+             │ lines          │ 2                       │\n\
+             │ rejected       │ 1                       │\n\
+             │ ipv6 skipped   │ 0                       │\n\
+             │ cidr host bits │ 0                       │\n\
+             │ ignored values │ 0                       │\n\
+             │ distinct       │ ~1 (approximate, ±0.8%) │\n\
+             │ touched pixels │ 1                       │\n\
+             │ value total    │ 4                       │\n\
+             └────────────────┴─────────────────────────┘\n"
+        );
+    }
+
+    #[test]
+    fn test_empty_heatmap_has_no_coverage() {
+        let hm = heatmap(8, "");
+        assert_eq!(hm.coverage(8), (0, 256));
+        assert_eq!(hm.touched_pixels(), 0);
+    }
+
+    #[test]
+    fn test_stats_json() {
+        let hm = heatmap(16, "10.0.0.1\nnot an ip\n");
+        let json = hm.stats(&[8]).to_json();
+        assert_eq!(json.get("lines"), Some(&JsonValue::Int(2)));
+        assert_eq!(json.get("rejected"), Some(&JsonValue::Int(1)));
+        assert_eq!(json.get("touched_pixels"), Some(&JsonValue::Int(1)));
+        assert!(json.to_string().contains(r#""coverage":[{"prefix_len":8,"covered":1,"total":256"#));
+        assert_eq!(json.get("timing"), None);
+    }
+
+    #[test]
+    fn test_live_queues_are_listed_once_full() {
+        let hm = heatmap(16, "10.0.0.1\n");
+        let mut stats = hm.stats(&[]);
+        let mut queues = LiveStats::default();
+        queues.read.capacity = 2;
+        queues.parsed.capacity = 2;
+        stats.live_queues = Some(queues);
+        assert!(!stats.to_text(TextStyle::ASCII).contains("queue"));
+        assert_eq!(stats.to_json().get("live_queues"), None);
+        stats.timing = hm.timer().totals();
+        assert!(stats.to_json().to_string().contains(r#""live_queues":{"read":{"capacity":2,"sent":0,"full":0"#));
+        queues.parsed.full = 3;
+        queues.parsed.high_water = 2;
+        queues.dropped_lines = 4096;
+        stats.live_queues = Some(queues);
+        let text = stats.to_text(TextStyle::ASCII);
+        assert!(text.contains("| parsed queue   | 2 of 2 at most, full 3 times, 0.000 s blocked |\n"), "{}", text);
+        assert!(text.contains("| dropped lines  | 4096"), "{}", text);
+    }
+
+    #[test]
+    fn test_ipv6_lines_are_counted_separately() {
+        let hm = heatmap(16, "10.0.0.1\n2001:db8::1\n[::1]:443\nbad\n");
+        let stats = hm.stats(&[]);
+        assert_eq!(stats.rejected, 3);
+        assert_eq!(stats.ipv6_skipped, 2);
+    }
+
+    #[test]
+    fn test_cidr_host_bits_policies() {
+        let input = "10.1.2.3/16\n10.2.0.0/16\n10.3.4.5/24\n";
+        for policy in [CidrHostBits::Warn, CidrHostBits::Allow, CidrHostBits::Reject] {
+            let mut hm = heatmap(16, "");
+            hm.set_parse_options(crate::ParseOptions::default().cidr_host_bits(policy));
+            hm.process_input_from_string(input).unwrap();
+            let stats = hm.stats(&[]);
+            match policy {
+                CidrHostBits::Reject => {
+                    assert_eq!((stats.cidr_host_bits, stats.rejected, stats.touched_pixels), (0, 2, 1));
+                }
+                _ => assert_eq!((stats.cidr_host_bits, stats.rejected, stats.touched_pixels), (2, 0, 3)),
+            }
+        }
+        assert!(heatmap(16, "10.1.2.3/16\n").stats(&[]).to_text(TextStyle::ASCII).contains("| cidr host bits | 1 "));
+    }
+
+    #[test]
+    fn test_records_outside_the_time_window_are_counted_not_painted() {
+        let mut hm = heatmap(16, "");
+        hm.set_parse_options(crate::ParseOptions::default().time_window(Some(crate::TimeWindow::new(Some(1_000_000), None).unwrap())));
+        hm.process_input_from_string("10.0.0.1 1 999
+10.1.0.1 2 1000
+10.2.0.1 3
+").unwrap();
+        let stats = hm.stats(&[]);
+        assert_eq!((stats.outside_window, stats.rejected, stats.touched_pixels), (1, 1, 1));
+        assert_eq!(stats.to_json().get("outside_window"), Some(&JsonValue::Int(1)));
+        assert!(stats.to_text(TextStyle::ASCII).contains("| outside window | 1 "));
+    }
+
+    #[test]
+    fn test_ignored_values_are_counted_not_painted() {
+        let mut hm = heatmap(16, "");
+        hm.set_parse_options(crate::ParseOptions::default().ignore_values(vec!["-1".to_string(), "0".to_string()]));
+        hm.process_input_from_string("10.0.0.1 -1\n10.1.0.0/16 0\n10.2.0.1 4\n10.3.0.1\n").unwrap();
+        let stats = hm.stats(&[]);
+        assert_eq!((stats.ignored_values, stats.rejected, stats.touched_pixels), (2, 0, 2));
+        assert_eq!(stats.to_json().get("ignored_values"), Some(&JsonValue::Int(2)));
+        assert!(stats.to_text(TextStyle::ASCII).contains("| ignored values | 2 "));
+    }
+
+    #[test]
+    fn test_clipped_share_and_note() {
+        let mut hm = heatmap(16, "");
+        hm.set_view(Some("10.0.0.0/8".parse().unwrap()));
+        // Half the records, holding a tenth of the value, are outside the view
+        hm.process_input_from_string("10.0.0.1 9
+10.1.0.1 9
+11.0.0.1 1
+12.0.0.1 1
+").unwrap();
+        let stats = hm.stats(&[]);
+        assert_eq!((stats.records, stats.clipped_records, stats.clipped_value), (4, 2, 2));
+        assert_eq!(stats.clipped_percent(), 50.0);
+        assert_eq!(stats.clipped_note(25.0).as_deref(), Some("50% of data outside view"));
+        assert_eq!(stats.clipped_note(50.0), None);
+        assert_eq!(stats.to_json().get("clipped_records"), Some(&JsonValue::Int(2)));
+        assert!(stats.to_text(TextStyle::ASCII).contains("| outside view   | 2 records, 2 value (50.0%) |\n"));
+
+        // Most of the value outside the view also counts
+        let mut hm = heatmap(16, "");
+        hm.set_view(Some("10.0.0.0/8".parse().unwrap()));
+        hm.process_input_from_string("10.0.0.1 1
+10.1.0.1 1
+10.2.0.1 1
+11.0.0.1 7
+").unwrap();
+        assert_eq!(hm.stats(&[]).clipped_percent(), 70.0);
+        assert!(!heatmap(16, "10.0.0.1
+").stats(&[]).to_text(TextStyle::ASCII).contains("outside view"));
+    }
+
+    #[test]
+    fn test_stats_json_contains_every_phase() {
+        let mut hm = heatmap(16, "");
+        hm.set_timing(true);
+        hm.process_input_from_string("10.0.0.1\n").unwrap();
+        hm.render(&hm.render_options()).unwrap();
+        let json = hm.stats(&[]).to_json();
+        let timing = json.get("timing").expect("timing is reported");
+        for key in ["read_ms", "parse_ms", "paint_ms", "domain_ms", "colourise_ms", "encode_ms"] {
+            assert!(timing.get(key).is_some(), "missing {}", key);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Writing to stdout, whose reader may go away early, as `head` does.
+//!
+//! Rust ignores SIGPIPE, so writes to a pipe nobody reads fail with `BrokenPipe`,
+//! which `print!` turns into a panic. Everything written to stdout goes through
+//! [`Stdout`] instead, which fails such writes with [`StdoutClosed`] for `main`
+//! to tell apart from other errors.
+
+use anyhow::Result;
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+
+/// Exit status of a run whose data on stdout was cut off, as shells report a
+/// process killed by SIGPIPE.
+pub const EXIT_STDOUT_CLOSED: u8 = 128 + 13;
+
+/// The reader of stdout went away before everything was written.
+#[derive(Debug)]
+pub struct StdoutClosed {
+    /// Whether data was lost, rather than the rest of a report nobody reads.
+    pub data: bool,
+}
+
+impl StdoutClosed {
+    /// The `StdoutClosed` behind `err`, if a write to [`Stdout`] caused it.
+    pub fn find(err: &anyhow::Error) -> Option<&StdoutClosed> {
+        err.chain()
+            .filter_map(|cause| cause.downcast_ref::<io::Error>())
+            .find_map(|err| err.get_ref()?.downcast_ref::<StdoutClosed>())
+    }
+}
+
+impl fmt::Display for StdoutClosed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "stdout was closed")
+    }
+}
+
+impl std::error::Error for StdoutClosed {}
+
+/// Stdout for reports (`data` false) or for output that is incomplete if cut off.
+pub struct Stdout {
+    out: io::StdoutLock<'static>,
+    data: bool,
+}
+
+impl Stdout {
+    pub fn report() -> Self {
+        Stdout { out: io::stdout().lock(), data: false }
+    }
+
+    pub fn data() -> Self {
+        Stdout { out: io::stdout().lock(), data: true }
+    }
+
+    fn closed(&self, err: io::Error) -> io::Error {
+        match err.kind() {
+            ErrorKind::BrokenPipe => io::Error::new(ErrorKind::BrokenPipe, StdoutClosed { data: self.data }),
+            _ => err,
+        }
+    }
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.write(buf).map_err(|err| self.closed(err))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush().map_err(|err| self.closed(err))
+    }
+}
+
+/// Write the report `text` to stdout, like `print!`.
+pub fn print(text: impl fmt::Display) -> Result<()> {
+    let mut stdout = Stdout::report();
+    write!(stdout, "{}", text)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Write the report `text` and a newline to stdout, like `println!`.
+pub fn println(text: impl fmt::Display) -> Result<()> {
+    print(format_args!("{}\n", text))
+}
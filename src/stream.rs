@@ -0,0 +1,92 @@
+use crate::Heatmap;
+use anyhow::Result;
+
+/// Feeds a heatmap from input that arrives in arbitrary chunks, such as reads from a
+/// pipe, so the buffer can be rendered between chunks.
+///
+/// Only complete lines are processed; a trailing partial line waits for the rest of
+/// its bytes. Line numbers in rejects continue across chunks.
+#[derive(Debug, Default)]
+pub struct ChunkedInput {
+    pending: Vec<u8>,
+    lines: usize,
+}
+
+impl ChunkedInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process the complete lines of `chunk` (after any bytes left from earlier chunks).
+    pub fn feed(&mut self, heatmap: &mut Heatmap, chunk: &[u8]) -> Result<()> {
+        self.pending.extend_from_slice(chunk);
+        let Some(end) = self.pending.iter().rposition(|&byte| byte == b'\n') else {
+            return Ok(());
+        };
+        let rest = self.pending.split_off(end + 1);
+        let complete = std::mem::replace(&mut self.pending, rest);
+        self.process(heatmap, &complete)
+    }
+
+    /// Bytes of a partial line still waiting for its end.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Process the final line when the input ended without a newline.
+    pub fn finish(mut self, heatmap: &mut Heatmap) -> Result<()> {
+        let rest = std::mem::take(&mut self.pending);
+        let result = self.process(heatmap, &rest);
+        heatmap.log_suppressed_warnings();
+        result
+    }
+
+    fn process(&mut self, heatmap: &mut Heatmap, lines: &[u8]) -> Result<()> {
+        let before = heatmap.lines_processed();
+        let result = heatmap.process_lines(lines, self.lines, None);
+        self.lines += (heatmap.lines_processed() - before) as usize;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, ValueMode};
+
+    fn heatmap() -> Heatmap {
+        Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            true,
+            16,
+            &colorous::MAGMA,
+            ValueMode::Raw,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_chunks_match_whole_input() {
+        let input = "10.0.0.1 5\n192.168.0.0/16 3\nbogus\n8.8.8.8 2";
+        let mut whole = heatmap();
+        whole.process_input_from_string(input).unwrap();
+
+        let mut chunked = heatmap();
+        let mut feed = ChunkedInput::new();
+        for chunk in input.as_bytes().chunks(7) {
+            feed.feed(&mut chunked, chunk).unwrap();
+        }
+        // The last line has no newline yet
+        assert_eq!(feed.pending(), "8.8.8.8 2".len());
+        assert_eq!(chunked.lines_processed(), 3);
+        feed.finish(&mut chunked).unwrap();
+
+        assert_eq!(chunked.buffer, whole.buffer);
+        assert_eq!(chunked.lines_processed(), whole.lines_processed());
+        let line_numbers = |hm: &Heatmap| hm.rejects().samples().iter().map(|r| r.line_number).collect::<Vec<_>>();
+        assert_eq!(line_numbers(&chunked), [3]);
+        assert_eq!(line_numbers(&chunked), line_numbers(&whole));
+    }
+}
@@ -0,0 +1,111 @@
+//! Encoding renders row by row, so large maps never hold a full RGBA image next to
+//! the buffer.
+
+use crate::Heatmap;
+use crate::memory::{MemoryEstimate, MemoryPlan};
+use crate::output;
+use crate::render::RenderOptions;
+use crate::timing::Phase;
+use anyhow::{Result, anyhow};
+use std::io::Write;
+
+/// Side length from which renders are streamed even without
+/// [`Heatmap::set_low_memory`]: 8192 pixels, `-z 6` and finer.
+pub const STREAMED_ENCODE_MIN_SIZE: u32 = 8192;
+
+impl Heatmap {
+    /// Stream plain renders even below [`STREAMED_ENCODE_MIN_SIZE`].
+    pub fn set_low_memory(&mut self, low_memory: bool) {
+        self.low_memory = low_memory;
+    }
+
+    /// Whether plain renders of this map are encoded row by row.
+    pub fn streams_encode(&self) -> bool {
+        self.low_memory || self.image_size() >= STREAMED_ENCODE_MIN_SIZE
+    }
+
+    /// Colourise with `options` and encode as PNG one row at a time. The pixels are
+    /// those of [`Heatmap::render`].
+    pub fn write_png_streamed<W: Write>(
+        &self,
+        writer: W,
+        options: &RenderOptions,
+        metadata: &[(String, String)],
+    ) -> Result<()> {
+        let colouring = self.colouring(options).map_err(|err| anyhow!(err))?;
+        let size = self.image_size();
+        // Colouring and encoding alternate, so the clock is read between them
+        let began = std::time::Instant::now();
+        let mut last = self.timer.start();
+        output::write_png_rows(writer, size, size, metadata, &options.png, |y, row| {
+            let started = self.timer.stop(Phase::Encode, last);
+            self.colour_row(y as usize, &colouring, options, row);
+            last = self.timer.stop(Phase::Colourise, started);
+        })?;
+        self.timer.stop(Phase::Encode, last);
+        self.record_render(began);
+        Ok(())
+    }
+
+    /// [`Heatmap::write_png_streamed`] into `filename`, replacing it atomically.
+    pub fn save_streamed(&self, filename: &str, options: &RenderOptions, metadata: &[(String, String)]) -> Result<()> {
+        output::write_atomic(filename, |writer| self.write_png_streamed(writer, options, metadata))
+            .map_err(|err| err.context(format!("Failed to save image to {}", filename)))
+    }
+}
+
+/// Bytes of rendering a `size` pixel map: a full RGBA image, or one row when streamed.
+pub(crate) fn image_bytes(size: u32, streamed: bool) -> u64 {
+    let row = size as u64 * 4;
+    if streamed { row } else { row * size as u64 }
+}
+
+/// Add the image of the main render, of the downsampled map if there is one.
+pub(crate) fn add_memory(plan: &MemoryPlan, estimate: &mut MemoryEstimate) {
+    let size = plan.output_size.unwrap_or(plan.image_size());
+    let streamed = !plan.decorated && (plan.low_memory || size >= STREAMED_ENCODE_MIN_SIZE);
+    estimate.add("image", image_bytes(size, streamed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bands, DomainType, ValueMode};
+
+    fn decode(bytes: &[u8]) -> image::RgbaImage {
+        image::load_from_memory(bytes).unwrap().to_rgba8()
+    }
+
+    fn assert_same_pixels(heatmap: &Heatmap, options: &RenderOptions) {
+        let mut streamed = Vec::new();
+        heatmap.write_png_streamed(&mut streamed, options, &[]).unwrap();
+        let mut whole = Vec::new();
+        output::write_png(&mut whole, &heatmap.render(options).unwrap(), &[], &options.png).unwrap();
+        assert_eq!(decode(&streamed), decode(&whole));
+    }
+
+    #[test]
+    fn test_streamed_pixels_match_render() {
+        let input = "10.0.0.1 5\n10.1.0.0/16 3\n192.168.0.0/12 40\n8.8.8.8 -2\n";
+        let mut heatmap = Heatmap::new(DomainType::Logarithmic, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.process_input_from_string(input).unwrap();
+        let mut options = heatmap.render_options();
+        assert_same_pixels(&heatmap, &options);
+        options.gamma = 0.5;
+        options.bands = Some("low:4,high:".parse::<Bands>().unwrap());
+        options.snap_to_bands = true;
+        assert_same_pixels(&heatmap, &options);
+
+        let mut categorical = Heatmap::new(DomainType::Linear, None, None, false, 16, &colorous::MAGMA, ValueMode::Categorical, None);
+        categorical.process_input_from_string("10.0.0.1 2\n11.0.0.0/8 7\n").unwrap();
+        assert_same_pixels(&categorical, &categorical.render_options());
+    }
+
+    #[test]
+    fn test_streaming_threshold() {
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        assert!(!heatmap.streams_encode());
+        heatmap.set_low_memory(true);
+        assert!(heatmap.streams_encode());
+    }
+}
@@ -0,0 +1,116 @@
+//! The fixed text drawn into images, such as the legend's curve name and the pixel
+//! caption, with overrides to translate it.
+//!
+//! Strings may hold `{name}` placeholders, which [`Strings::format`] fills in; an
+//! override can move or leave them out. The built-in font only draws printable
+//! ASCII, so other characters show as `?`.
+
+use crate::scale::DomainType;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+/// Every key with its default text.
+pub const DEFAULT_STRINGS: [(&str, &str); 12] = [
+    ("curve.linear", "linear"),
+    ("curve.log", "log"),
+    ("curve.symlog", "symlog:{threshold}"),
+    ("caption.pixel", "1 pixel = /{prefix} ({addresses} addresses)"),
+    ("caption.pixel-one-address", "1 pixel = /{prefix} ({addresses} address)"),
+    ("caption.downsampled", "{caption} after {factor}x downsampling"),
+    ("caption.crop", "{caption} in {prefix}"),
+    ("caption.half-map", "{caption}, map covers 0.0.0.0/1"),
+    ("title.clipped", "{title} ({percent}% of data outside view)"),
+    ("montage.difference", "difference"),
+    ("chart.histogram", "cells by value ({curve}), max bin {max}"),
+    ("chart.slash8", "total value per /8 ({curve}), max {max}"),
+];
+
+/// The text of every key in [`DEFAULT_STRINGS`], with any overrides.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Strings {
+    overrides: BTreeMap<&'static str, String>,
+}
+
+impl Strings {
+    /// Use `text` for `key`. Fails for a key not in [`DEFAULT_STRINGS`].
+    pub fn set(&mut self, key: &str, text: &str) -> Result<(), String> {
+        let Some(&(key, _)) = DEFAULT_STRINGS.iter().find(|(known, _)| *known == key) else {
+            let keys: Vec<&str> = DEFAULT_STRINGS.iter().map(|(key, _)| *key).collect();
+            return Err(format!("Unknown text key: {}. Use one of {}", key, keys.join(", ")));
+        };
+        self.overrides.insert(key, text.to_string());
+        Ok(())
+    }
+
+    /// Apply an override written `key=text`, as `--text` takes it.
+    pub fn set_override(&mut self, spec: &str) -> Result<(), String> {
+        let (key, text) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid text override: {}. Use key=text", spec))?;
+        self.set(key.trim(), text)
+    }
+
+    /// The text of `key`, which must be in [`DEFAULT_STRINGS`].
+    pub fn get(&self, key: &str) -> &str {
+        if let Some(text) = self.overrides.get(key) {
+            return text;
+        }
+        match DEFAULT_STRINGS.iter().find(|(known, _)| *known == key) {
+            Some((_, text)) => text,
+            None => panic!("{} is not in DEFAULT_STRINGS", key),
+        }
+    }
+
+    /// The text of `key` with each `{name}` of `values` replaced by its value.
+    pub fn format(&self, key: &str, values: &[(&str, &dyn Display)]) -> String {
+        let mut text = self.get(key).to_string();
+        for (name, value) in values {
+            text = text.replace(&format!("{{{}}}", name), &value.to_string());
+        }
+        text
+    }
+
+    /// The name of `curve`, as the legend shows it.
+    pub fn curve(&self, curve: DomainType) -> String {
+        match curve {
+            DomainType::Linear => self.get("curve.linear").to_string(),
+            DomainType::Logarithmic => self.get("curve.log").to_string(),
+            DomainType::Symlog { linthresh } => self.format("curve.symlog", &[("threshold", &linthresh)]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults_and_overrides() {
+        let mut strings = Strings::default();
+        // The defaults name curves as they are written on the command line
+        for curve in [DomainType::Linear, DomainType::Logarithmic, DomainType::Symlog { linthresh: 2.5 }] {
+            assert_eq!(strings.curve(curve), curve.to_string());
+        }
+        strings.set_override("curve.linear=linear (de)").unwrap();
+        strings.set_override("caption.crop={caption}, Ausschnitt {prefix}").unwrap();
+        assert_eq!(strings.curve(DomainType::Linear), "linear (de)");
+        let caption = strings.format("caption.crop", &[("caption", &"1 Pixel = /24"), ("prefix", &"10.0.0.0/8")]);
+        assert_eq!(caption, "1 Pixel = /24, Ausschnitt 10.0.0.0/8");
+        assert_ne!(strings, Strings::default());
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let mut strings = Strings::default();
+        let error = strings.set_override("legend.title=Titel").unwrap_err();
+        assert!(error.starts_with("Unknown text key: legend.title. Use one of curve.linear, curve.log"), "{}", error);
+        assert!(strings.set_override("curve.linear").is_err());
+        assert_eq!(strings, Strings::default());
+    }
+
+    #[test]
+    fn test_keys_are_unique() {
+        let keys: std::collections::HashSet<&str> = DEFAULT_STRINGS.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys.len(), DEFAULT_STRINGS.len());
+    }
+}
@@ -0,0 +1,251 @@
+//! Totals per /8 straight from the parser, for when only the numbers are wanted.
+
+use crate::input::{self, ParseOptions, ParsedLine, Record, ValueSource};
+use crate::json::JsonValue;
+use crate::rejects::{RejectLog, RejectReason};
+use crate::table::{Align, Table, TextStyle};
+use crate::timing::PhaseTimer;
+use crate::weighted_value;
+use anyhow::Result;
+use std::io::BufRead;
+
+/// The records and value of one /8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Slash8Total {
+    pub records: u64,
+    /// Sum of the record values, after the weight.
+    pub value: i64,
+}
+
+/// Parses input like [`crate::Heatmap`] would, but only adds each record to the /8
+/// of its network address, so memory stays at 256 totals whatever the input or map.
+/// A prefix wider than a /8 counts in its first /8.
+pub struct Summarizer {
+    parse_options: ParseOptions,
+    weight: f64,
+    lines: u64,
+    cidr_host_bits: u64,
+    ignored_values: u64,
+    outside_window: u64,
+    slash8: [Slash8Total; 256],
+    rejects: RejectLog,
+}
+
+/// The outcome of a [`Summarizer`] run.
+#[derive(Clone, Debug)]
+pub struct InputSummary {
+    pub lines: u64,
+    /// Prefixes that were accepted with host bits set.
+    pub cidr_host_bits: u64,
+    /// Records dropped for their value.
+    pub ignored_values: u64,
+    /// Records dropped for their time.
+    pub outside_window: u64,
+    /// The totals of each /8, in address order.
+    pub slash8: [Slash8Total; 256],
+    pub rejects: RejectLog,
+}
+
+impl Default for Summarizer {
+    fn default() -> Self {
+        Self::new(ParseOptions::default())
+    }
+}
+
+impl Summarizer {
+    /// A summarizer reading input with `options`.
+    pub fn new(options: ParseOptions) -> Self {
+        Self {
+            rejects: RejectLog::new(options.max_rejects),
+            parse_options: options,
+            weight: 1.0,
+            lines: 0,
+            cidr_host_bits: 0,
+            ignored_values: 0,
+            outside_window: 0,
+            slash8: [Slash8Total::default(); 256],
+        }
+    }
+
+    /// Multiply record values by `weight`, see [`crate::Heatmap::set_weight`].
+    pub fn set_weight(&mut self, weight: f64) {
+        self.weight = weight;
+    }
+
+    pub fn process_input_from_reader<R: BufRead>(&mut self, reader: R) -> Result<()> {
+        let options = self.parse_options.clone();
+        // Prefix lengths are not quantities, so they are not weighted
+        let factor = if options.value_source == ValueSource::PrefixLen { 1.0 } else { self.weight };
+        input::for_each_record(reader, 0, &options, &PhaseTimer::default(), |line_number, line, parsed| {
+            self.lines += 1;
+            match parsed {
+                ParsedLine::Blank | ParsedLine::Unsampled => {}
+                ParsedLine::Ignored => self.ignored_values += 1,
+                ParsedLine::OutsideWindow => self.outside_window += 1,
+                ParsedLine::Record(record) => self.add(&record, factor),
+                ParsedLine::Records(records) => records.iter().for_each(|record| self.add(record, factor)),
+                ParsedLine::Rejected(reason, message) => self.rejects.record(line_number, line, reason, message),
+            }
+            Ok(())
+        })
+    }
+
+    fn add(&mut self, record: &Record, factor: f64) {
+        if record.has_host_bits() {
+            self.cidr_host_bits += 1;
+        }
+        let total = &mut self.slash8[record.net.network().octets()[0] as usize];
+        total.records += 1;
+        total.value += weighted_value(record.value, factor) as i64;
+    }
+
+    pub fn process_input_from_string(&mut self, input: &str) -> Result<()> {
+        self.process_input_from_reader(std::io::Cursor::new(input))
+    }
+
+    pub fn finish(self) -> InputSummary {
+        InputSummary {
+            lines: self.lines,
+            cidr_host_bits: self.cidr_host_bits,
+            ignored_values: self.ignored_values,
+            outside_window: self.outside_window,
+            slash8: self.slash8,
+            rejects: self.rejects,
+        }
+    }
+}
+
+impl InputSummary {
+    /// Lines that parsed into an address or prefix, or one per record of an expanded line.
+    pub fn records(&self) -> u64 {
+        self.slash8.iter().map(|total| total.records).sum()
+    }
+
+    /// Sum of the record values, after the weight.
+    pub fn value(&self) -> i64 {
+        self.slash8.iter().map(|total| total.value).sum()
+    }
+
+    /// The /8s with records, as first octets and their totals.
+    fn used(&self) -> impl Iterator<Item = (u8, &Slash8Total)> {
+        (0..=255).zip(&self.slash8).filter(|(_, total)| total.records > 0)
+    }
+
+    /// The /8s with records, then the totals and the rejects by reason.
+    pub fn to_text(&self, style: TextStyle) -> String {
+        let columns = [("/8", Align::Left), ("records", Align::Right), ("value", Align::Right)];
+        let mut slash8 = Table::new(&columns);
+        for (octet, total) in self.used() {
+            slash8.push(vec![format!("{}.0.0.0/8", octet), total.records.to_string(), total.value.to_string()]);
+        }
+        let mut totals = Table::new(&[("count", Align::Left), ("value", Align::Right)]);
+        let mut row = |name: &str, value: u64| totals.push(vec![name.to_string(), value.to_string()]);
+        row("lines", self.lines);
+        row("records", self.records());
+        row("rejected", self.rejects.total());
+        for reason in RejectReason::ALL {
+            if self.rejects.count(reason) > 0 {
+                row(&format!("  {}", reason), self.rejects.count(reason));
+            }
+        }
+        if self.cidr_host_bits > 0 {
+            row("cidr host bits", self.cidr_host_bits);
+        }
+        if self.ignored_values > 0 {
+            row("ignored values", self.ignored_values);
+        }
+        if self.outside_window > 0 {
+            row("outside window", self.outside_window);
+        }
+        totals.push(vec!["value total".to_string(), self.value().to_string()]);
+        match slash8.is_empty() {
+            true => totals.render(style),
+            false => slash8.render(style) + &totals.render(style),
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::object();
+        json.insert("lines", self.lines);
+        json.insert("records", self.records());
+        json.insert("rejected", self.rejects.total());
+        let mut reasons = JsonValue::object();
+        for reason in RejectReason::ALL {
+            if self.rejects.count(reason) > 0 {
+                reasons.insert(&reason.to_string(), self.rejects.count(reason));
+            }
+        }
+        json.insert("rejected_by_reason", reasons);
+        json.insert("ipv6_skipped", self.rejects.count(RejectReason::Ipv6));
+        json.insert("cidr_host_bits", self.cidr_host_bits);
+        json.insert("ignored_values", self.ignored_values);
+        json.insert("outside_window", self.outside_window);
+        json.insert("weighted_total", self.value());
+        let slash8: Vec<JsonValue> = self
+            .used()
+            .map(|(octet, total)| {
+                let mut entry = JsonValue::object();
+                entry.insert("octet", octet);
+                entry.insert("records", total.records);
+                entry.insert("value", total.value);
+                entry
+            })
+            .collect();
+        json.insert("slash8", slash8);
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, Heatmap, ValueMode};
+
+    const INPUT: &str = "10.0.0.1 5\n10.2.0.0/16 3\n\nnot-an-ip\n11.0.0.1 -2\n2001:db8::1 4\n10.0.0.5/24 1\n";
+
+    fn summarize(input: &str) -> InputSummary {
+        let mut summarizer = Summarizer::default();
+        summarizer.process_input_from_string(input).unwrap();
+        summarizer.finish()
+    }
+
+    #[test]
+    fn test_totals_per_slash8() {
+        let summary = summarize(INPUT);
+        assert_eq!((summary.lines, summary.records(), summary.rejects.total()), (7, 4, 2));
+        assert_eq!(summary.slash8[10], Slash8Total { records: 3, value: 9 });
+        assert_eq!(summary.slash8[11], Slash8Total { records: 1, value: -2 });
+        assert_eq!(summary.used().count(), 2);
+        assert_eq!((summary.value(), summary.cidr_host_bits), (7, 1));
+        assert_eq!(summary.rejects.count(RejectReason::Ipv6), 1);
+    }
+
+    #[test]
+    fn test_totals_match_heatmap_stats() {
+        let mut heatmap = Heatmap::new(DomainType::Linear, None, None, true, 16, &colorous::MAGMA, ValueMode::Raw, None);
+        heatmap.set_weight(2.5);
+        heatmap.process_input_from_string(INPUT).unwrap();
+        let stats = heatmap.stats(&[]);
+        let mut summarizer = Summarizer::default();
+        summarizer.set_weight(2.5);
+        summarizer.process_input_from_string(INPUT).unwrap();
+        let summary = summarizer.finish();
+        assert_eq!(
+            (summary.lines, summary.records(), summary.value(), summary.rejects.total(), summary.cidr_host_bits),
+            (stats.lines, stats.records, stats.weighted_total, stats.rejected, stats.cidr_host_bits)
+        );
+    }
+
+    #[test]
+    fn test_summary_text_and_json() {
+        let text = summarize(INPUT).to_text(TextStyle::ASCII);
+        assert!(text.starts_with("+------------+---------+-------+\n| /8         | records | value |\n"), "{}", text);
+        assert!(text.contains("| 10.0.0.0/8 |       3 |     9 |\n| 11.0.0.0/8 |       1 |    -2 |\n"), "{}", text);
+        assert!(text.contains("| rejected             |     2 |\n|   invalid IP address |     1 |\n"), "{}", text);
+        let json = summarize(INPUT).to_json().to_string();
+        assert!(json.contains(r#""rejected_by_reason":{"invalid IP address":1,"IPv6 address":1}"#), "{}", json);
+        assert!(json.contains(r#""slash8":[{"octet":10,"records":3,"value":9},{"octet":11,"records":1,"value":-2}]"#), "{}", json);
+        // Nothing read leaves only the totals
+        assert!(summarize("").to_text(TextStyle::ASCII).starts_with("+-------------+-------+\n| count       | value |\n"));
+    }
+}
@@ -0,0 +1,192 @@
+//! Bordered text tables shared by the textual reports, `--stats`,
+//! `--coverage-report`, `--sanity-check` and `compare`, so that they line up the
+//! same way and fall back to ASCII together.
+
+/// How a report is drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextStyle {
+    /// Box-drawing characters and symbols such as `±`, rather than ASCII only.
+    pub unicode: bool,
+    /// ANSI escapes, bold headers.
+    pub colour: bool,
+}
+
+impl TextStyle {
+    /// ASCII without escapes, for pipes, files and terminals that show no more.
+    pub const ASCII: TextStyle = TextStyle { unicode: false, colour: false };
+    /// Box drawing and bold headers.
+    pub const TERMINAL: TextStyle = TextStyle { unicode: true, colour: true };
+
+    /// The style of a stream: plain ASCII unless it is a `terminal`, box drawing
+    /// only in a `utf8` locale and without `ascii`, escapes only without `no_colour`.
+    pub fn detect(terminal: bool, utf8: bool, ascii: bool, no_colour: bool) -> Self {
+        TextStyle { unicode: terminal && utf8 && !ascii, colour: terminal && !no_colour }
+    }
+
+    /// `±`, or `+/-` in ASCII.
+    pub fn plus_minus(&self) -> &'static str {
+        if self.unicode { "±" } else { "+/-" }
+    }
+
+    fn bold(&self, text: &str) -> String {
+        match self.colour {
+            true => format!("\x1b[1m{}\x1b[0m", text),
+            false => text.to_string(),
+        }
+    }
+}
+
+/// Whether the locale's character set is UTF-8, going by the first of `LC_ALL`,
+/// `LC_CTYPE` and `LANG` that `var` finds set and not empty.
+pub fn is_utf8_locale(var: impl Fn(&str) -> Option<String>) -> bool {
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .find_map(|name| var(name).filter(|value| !value.is_empty()))
+        .is_some_and(|locale| {
+            let locale = locale.to_ascii_lowercase();
+            locale.contains("utf-8") || locale.contains("utf8")
+        })
+}
+
+/// Which side of its column a cell keeps to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+/// Rows of text under a header, drawn with a border and padded columns.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Table {
+    headers: Vec<String>,
+    align: Vec<Align>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(columns: &[(&str, Align)]) -> Self {
+        Table {
+            headers: columns.iter().map(|(header, _)| header.to_string()).collect(),
+            align: columns.iter().map(|&(_, align)| align).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    /// Add a row, with blank cells for any missing at its end.
+    pub fn push(&mut self, mut row: Vec<String>) {
+        debug_assert!(row.len() <= self.headers.len(), "{:?} has more cells than {:?}", row, self.headers);
+        row.resize(self.headers.len(), String::new());
+        self.rows.push(row);
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The table in `style`, every line ending in a newline.
+    pub fn render(&self, style: TextStyle) -> String {
+        let widths: Vec<usize> = (0..self.headers.len())
+            .map(|column| {
+                let cells = self.rows.iter().map(|row| &row[column]).chain([&self.headers[column]]);
+                cells.map(|cell| cell.chars().count()).max().unwrap_or(0)
+            })
+            .collect();
+        // Corners and joints of the top, middle and bottom rules, then the lines
+        let (top, middle, bottom, horizontal, vertical) = match style.unicode {
+            true => (['┌', '┬', '┐'], ['├', '┼', '┤'], ['└', '┴', '┘'], '─', '│'),
+            false => (['+'; 3], ['+'; 3], ['+'; 3], '-', '|'),
+        };
+        let rule = |[left, joint, right]: [char; 3]| {
+            let segments: Vec<String> = widths.iter().map(|&width| horizontal.to_string().repeat(width + 2)).collect();
+            format!("{}{}{}\n", left, segments.join(&joint.to_string()), right)
+        };
+        let line = |cells: &[String], header: bool| {
+            let cells: Vec<String> = cells
+                .iter()
+                .enumerate()
+                .map(|(column, cell)| {
+                    let padding = " ".repeat(widths[column] - cell.chars().count());
+                    let padded = match self.align[column] {
+                        Align::Left => format!("{}{}", cell, padding),
+                        Align::Right => format!("{}{}", padding, cell),
+                    };
+                    if header { style.bold(&padded) } else { padded }
+                })
+                .collect();
+            format!("{} {} {}\n", vertical, cells.join(&format!(" {} ", vertical)), vertical)
+        };
+        let mut text = rule(top);
+        text.push_str(&line(&self.headers, true));
+        text.push_str(&rule(middle));
+        for row in &self.rows {
+            text.push_str(&line(row, false));
+        }
+        text.push_str(&rule(bottom));
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> Table {
+        let mut table = Table::new(&[("name", Align::Left), ("count", Align::Right)]);
+        table.push(vec!["lines".to_string(), "12".to_string()]);
+        table.push(vec!["±".to_string()]);
+        table
+    }
+
+    #[test]
+    fn test_ascii_table() {
+        assert_eq!(
+            table().render(TextStyle::ASCII),
+            "+-------+-------+\n\
+             | name  | count |\n\
+             +-------+-------+\n\
+             | lines |    12 |\n\
+             | ±     |       |\n\
+             +-------+-------+\n"
+        );
+    }
+
+    #[test]
+    fn test_terminal_table() {
+        assert_eq!(
+            table().render(TextStyle::TERMINAL),
+            "┌───────┬───────┐\n\
+             │ \x1b[1mname \x1b[0m │ \x1b[1mcount\x1b[0m │\n\
+             ├───────┼───────┤\n\
+             │ lines │    12 │\n\
+             │ ±     │       │\n\
+             └───────┴───────┘\n"
+        );
+        let plain = table().render(TextStyle { unicode: true, colour: false });
+        assert!(plain.contains("│ name  │ count │\n") && !plain.contains('\x1b'));
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(TextStyle::detect(false, true, false, false), TextStyle::ASCII);
+        assert_eq!(TextStyle::detect(true, true, false, false), TextStyle::TERMINAL);
+        assert_eq!(TextStyle::detect(true, false, false, false), TextStyle { unicode: false, colour: true });
+        assert_eq!(TextStyle::detect(true, true, true, true), TextStyle::ASCII);
+        assert_eq!(TextStyle::ASCII.plus_minus(), "+/-");
+    }
+
+    #[test]
+    fn test_utf8_locale() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.to_string())
+        };
+        assert!(is_utf8_locale(env(&[("LANG", "en_GB.UTF-8")])));
+        assert!(is_utf8_locale(env(&[("LC_ALL", "C.utf8"), ("LANG", "C")])));
+        assert!(!is_utf8_locale(env(&[("LC_ALL", "C"), ("LANG", "en_GB.UTF-8")])));
+        assert!(is_utf8_locale(env(&[("LC_ALL", ""), ("LANG", "en_GB.UTF-8")])));
+        assert!(!is_utf8_locale(env(&[])));
+    }
+}
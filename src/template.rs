@@ -0,0 +1,153 @@
+//! Variables in output file names, such as `map-{date}-z{bpp}-{curve}.png`.
+
+use crate::DomainType;
+use crate::timestamps::civil_from_days;
+use std::path::Path;
+
+/// The variables an output name can use, each written in braces.
+pub const TEMPLATE_VARIABLES: [&str; 6] = ["date", "datetime", "bpp", "curve", "palette", "input-stem"];
+
+/// The values of the [`TEMPLATE_VARIABLES`] for one run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputTemplate {
+    /// When the run started, in milliseconds since the epoch. Dates are in UTC.
+    pub time: i64,
+    /// Unset when a run renders several resolutions, whose names get `-z<bits>`
+    /// inserted instead.
+    pub bits_per_pixel: Option<u8>,
+    pub curve: DomainType,
+    pub palette: String,
+    /// See [`input_stem`].
+    pub input_stem: String,
+}
+
+/// The name of the first input file without its directory and extension, or
+/// `stdin` when reading standard input.
+pub fn input_stem(first_input: Option<&str>) -> String {
+    first_input
+        .and_then(|path| Path::new(path).file_stem())
+        .map_or_else(|| "stdin".to_string(), |stem| stem.to_string_lossy().into_owned())
+}
+
+impl OutputTemplate {
+    /// `name` with each `{variable}` replaced by its value, and `{{` and `}}` by a
+    /// literal brace.
+    pub fn expand(&self, name: &str) -> Result<String, String> {
+        let mut expanded = String::with_capacity(name.len());
+        let mut rest = name;
+        while let Some(brace) = rest.find(['{', '}']) {
+            expanded.push_str(&rest[..brace]);
+            let after = &rest[brace + 1..];
+            if rest[brace..].starts_with("{{") || rest[brace..].starts_with("}}") {
+                expanded.push_str(&rest[brace..brace + 1]);
+                rest = &after[1..];
+                continue;
+            }
+            if rest[brace..].starts_with('}') {
+                return Err(format!("Unmatched }} in output name {}. Write }}}} for a literal brace", name));
+            }
+            let Some(end) = after.find('}') else {
+                return Err(format!("Unclosed {{ in output name {}. Write {{{{ for a literal brace", name));
+            };
+            expanded.push_str(&self.value(&after[..end], name)?);
+            rest = &after[end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    fn value(&self, variable: &str, name: &str) -> Result<String, String> {
+        let days = self.time.div_euclid(86_400_000);
+        let (year, month, day) = civil_from_days(days);
+        let date = format!("{:04}-{:02}-{:02}", year, month, day);
+        Ok(match variable {
+            "date" => date,
+            "datetime" => {
+                let seconds = self.time.rem_euclid(86_400_000) / 1000;
+                format!("{}T{:02}{:02}{:02}Z", date, seconds / 3600, seconds / 60 % 60, seconds % 60)
+            }
+            "bpp" => match self.bits_per_pixel {
+                Some(bits_per_pixel) => bits_per_pixel.to_string(),
+                None => return Err(format!("{{bpp}} in output name {} needs a single -z", name)),
+            },
+            // A symlog threshold follows a dash rather than a colon
+            "curve" => self.curve.to_string().replace(':', "-"),
+            "palette" => self.palette.clone(),
+            "input-stem" => self.input_stem.clone(),
+            _ => {
+                let known: Vec<String> = TEMPLATE_VARIABLES.iter().map(|known| format!("{{{}}}", known)).collect();
+                return Err(format!(
+                    "Unknown variable {{{}}} in output name {}. Use {} or {}",
+                    variable,
+                    name,
+                    known[..known.len() - 1].join(", "),
+                    known[known.len() - 1]
+                ));
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-06-01T07:08:09.500Z
+    const TIME: i64 = 1_717_225_689_500;
+
+    fn template() -> OutputTemplate {
+        OutputTemplate {
+            time: TIME,
+            bits_per_pixel: Some(8),
+            curve: DomainType::Logarithmic,
+            palette: "magma".to_string(),
+            input_stem: "scans".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_variables() {
+        let template = template();
+        let cases = [
+            ("{date}", "2024-06-01"),
+            ("{datetime}", "2024-06-01T070809Z"),
+            ("{bpp}", "8"),
+            ("{curve}", "log"),
+            ("{palette}", "magma"),
+            ("{input-stem}", "scans"),
+            ("out/map-{date}-z{bpp}-{curve}.png", "out/map-2024-06-01-z8-log.png"),
+            ("plain.png", "plain.png"),
+        ];
+        for (name, expanded) in cases {
+            assert_eq!(template.expand(name), Ok(expanded.to_string()), "{}", name);
+        }
+        let symlog = OutputTemplate { curve: DomainType::Symlog { linthresh: 10.0 }, ..template.clone() };
+        assert_eq!(symlog.expand("{curve}.png"), Ok("symlog-10.png".to_string()));
+        let before_epoch = OutputTemplate { time: -1, ..template.clone() };
+        assert_eq!(before_epoch.expand("{datetime}"), Ok("1969-12-31T235959Z".to_string()));
+    }
+
+    #[test]
+    fn test_escapes_and_errors() {
+        let template = template();
+        assert_eq!(template.expand("{{date}}-{date}.png"), Ok("{date}-2024-06-01.png".to_string()));
+        assert_eq!(template.expand("a}}b{{"), Ok("a}b{".to_string()));
+        let unknown = template.expand("map-{day}.png").unwrap_err();
+        assert!(unknown.starts_with("Unknown variable {day} in output name map-{day}.png"), "{}", unknown);
+        for variable in TEMPLATE_VARIABLES {
+            assert!(unknown.contains(&format!("{{{}}}", variable)), "{}", unknown);
+        }
+        assert!(template.expand("map-{date.png").unwrap_err().starts_with("Unclosed {"));
+        assert!(template.expand("map-}.png").unwrap_err().starts_with("Unmatched }"));
+        let resolutions = OutputTemplate { bits_per_pixel: None, ..template };
+        assert!(resolutions.expand("map-{bpp}.png").is_err());
+        assert_eq!(resolutions.expand("map-{date}.png"), Ok("map-2024-06-01.png".to_string()));
+    }
+
+    #[test]
+    fn test_input_stem() {
+        assert_eq!(input_stem(Some("/var/log/scans.2024.txt")), "scans.2024");
+        assert_eq!(input_stem(Some("scans")), "scans");
+        assert_eq!(input_stem(None), "stdin");
+    }
+}
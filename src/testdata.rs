@@ -0,0 +1,213 @@
+//! Synthetic `address value` input with hotspots, for demos, benchmarks and bug
+//! reports.
+//!
+//! [`generate`] picks [`GenerateConfig::hotspots`] prefixes, each aligned to its
+//! length, and gives them Zipf-distributed shares of the lines: the hotspot ranked `k`
+//! gets a share proportional to `1/k`. The [`GenerateConfig::spread`] of the lines
+//! left over are background noise, uniform over the whole address space. The output
+//! depends on nothing but the config, so a seed pins a fixture.
+
+use crate::input::splitmix64;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+
+/// Lengths hotspot prefixes are drawn from, so each covers many pixels of a `-z 8`
+/// map without filling the picture.
+pub const HOTSPOT_PREFIX_LENS: RangeInclusive<u8> = 12..=24;
+
+/// Values are drawn uniformly from 1 to this.
+pub const MAX_GENERATED_VALUE: u32 = 100;
+
+/// What [`generate`] makes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GenerateConfig {
+    pub lines: u64,
+    /// Number of hotspot prefixes; with none every line is background noise.
+    pub hotspots: usize,
+    /// Share of lines that are background noise, from 0 to 1.
+    pub spread: f64,
+    pub seed: u64,
+}
+
+impl Default for GenerateConfig {
+    fn default() -> Self {
+        GenerateConfig { lines: 1_000_000, hotspots: 20, spread: 0.2, seed: 0 }
+    }
+}
+
+/// SplitMix64 as a stream of draws.
+struct Random {
+    state: u64,
+}
+
+impl Random {
+    fn next_u64(&mut self) -> u64 {
+        let value = splitmix64(self.state);
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        value
+    }
+
+    /// Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in [0, `n`), close enough for test data.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// `config.lines` lines of `address value`, without line endings.
+pub fn generate(config: GenerateConfig) -> impl Iterator<Item = String> {
+    let mut random = Random { state: config.seed };
+    let (shortest, longest) = (*HOTSPOT_PREFIX_LENS.start(), *HOTSPOT_PREFIX_LENS.end());
+    let hotspots: Vec<(u32, u8)> = (0..config.hotspots)
+        .map(|_| {
+            let len = shortest + random.below((longest - shortest + 1) as u64) as u8;
+            (random.next_u64() as u32 & (u32::MAX << (32 - len)), len)
+        })
+        .collect();
+    // Where each hotspot's share ends, summing to 1
+    let total: f64 = (1..=hotspots.len()).map(|rank| 1.0 / rank as f64).sum();
+    let mut ends = Vec::with_capacity(hotspots.len());
+    let mut end = 0.0;
+    for rank in 1..=hotspots.len() {
+        end += 1.0 / rank as f64 / total;
+        ends.push(end);
+    }
+    let spread = if hotspots.is_empty() { 1.0 } else { config.spread.clamp(0.0, 1.0) };
+    (0..config.lines).map(move |_| {
+        let address = if random.next_f64() < spread {
+            random.next_u64() as u32
+        } else {
+            let pick = random.next_f64();
+            let (network, len) = hotspots[ends.partition_point(|&end| end <= pick).min(hotspots.len() - 1)];
+            network | (random.next_u64() as u32 >> len)
+        };
+        let value = 1 + random.below(MAX_GENERATED_VALUE as u64);
+        format!("{} {}", Ipv4Addr::from(address), value)
+    })
+}
+
+/// Parse a line count, optionally with a decimal `K`, `M` or `G` suffix, e.g. `10M`.
+pub fn parse_line_count(s: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid line count: {}. Use a number with an optional K, M or G suffix, e.g. 10M", s);
+    let trimmed = s.trim();
+    let digits = trimmed.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(digits);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let power = match suffix.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 3,
+        "M" => 6,
+        "G" => 9,
+        _ => return Err(invalid()),
+    };
+    let lines = number * 10f64.powi(power);
+    if lines.fract() != 0.0 || lines > u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(lines as u64)
+}
+
+/// Parse a [`GenerateConfig::spread`], which must be from 0 to 1.
+pub fn parse_spread(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(spread) if (0.0..=1.0).contains(&spread) => Ok(spread),
+        _ => Err(format!("Spread must be a share of lines from 0 to 1, got {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipnet::Ipv4Net;
+
+    fn config(lines: u64, hotspots: usize, spread: f64, seed: u64) -> GenerateConfig {
+        GenerateConfig { lines, hotspots, spread, seed }
+    }
+
+    #[test]
+    fn test_fixed_seed_pins_the_output() {
+        // Fixtures made with `generate --lines 5 --seed 42` stay what they were
+        let lines: Vec<String> = generate(config(5, 20, 0.2, 42)).collect();
+        assert_eq!(
+            lines,
+            ["178.102.247.241 30", "14.74.226.113 56", "74.240.103.117 49", "228.198.143.91 70", "71.237.108.0 53"]
+        );
+        // A seed gives the same lines every time, and another seed others
+        assert!(generate(config(1000, 20, 0.2, 42)).eq(generate(config(1000, 20, 0.2, 42))));
+        assert!(!generate(config(1000, 20, 0.2, 42)).eq(generate(config(1000, 20, 0.2, 43))));
+        // Asking for more lines only adds to the end
+        assert!(generate(config(100, 20, 0.2, 42)).eq(generate(config(1000, 20, 0.2, 42)).take(100)));
+    }
+
+    /// The hotspots [`generate`] picks first for `seed`.
+    fn hotspots(seed: u64, count: usize) -> Vec<Ipv4Net> {
+        let mut random = Random { state: seed };
+        let (shortest, longest) = (*HOTSPOT_PREFIX_LENS.start(), *HOTSPOT_PREFIX_LENS.end());
+        (0..count)
+            .map(|_| {
+                let len = shortest + random.below((longest - shortest + 1) as u64) as u8;
+                Ipv4Net::new(Ipv4Addr::from(random.next_u64() as u32), len).unwrap().trunc()
+            })
+            .collect()
+    }
+
+    fn address(line: &str) -> Ipv4Addr {
+        line.split_once(' ').unwrap().0.parse().unwrap()
+    }
+
+    #[test]
+    fn test_hotspots_get_zipf_shares() {
+        const LINES: u64 = 200_000;
+        let hotspots = hotspots(7, 4);
+        let mut counts = [0u64; 4];
+        let mut values = 0u64;
+        for line in generate(config(LINES, 4, 0.0, 7)) {
+            let value: u32 = line.split_once(' ').unwrap().1.parse().unwrap();
+            assert!((1..=MAX_GENERATED_VALUE).contains(&value), "{}", line);
+            values += value as u64;
+            let hotspot = hotspots.iter().position(|net| net.contains(&address(&line))).expect("in a hotspot");
+            counts[hotspot] += 1;
+        }
+        // Shares of 1/k over 1 + 1/2 + 1/3 + 1/4, give or take chance
+        for (rank, &count) in counts.iter().enumerate() {
+            let share = count as f64 / LINES as f64;
+            let expected = 1.0 / (rank + 1) as f64 / (25.0 / 12.0);
+            assert!((share - expected).abs() < 0.01, "hotspot {}: {} of lines, expected {}", rank + 1, share, expected);
+        }
+        let mean = values as f64 / LINES as f64;
+        assert!((mean - 50.5).abs() < 1.0, "{}", mean);
+    }
+
+    #[test]
+    fn test_spread_is_the_share_of_background_noise() {
+        let hotspot = hotspots(1, 1)[0];
+        let in_hotspot = |spread: f64| generate(config(10_000, 1, spread, 1)).filter(|line| hotspot.contains(&address(line))).count();
+        assert_eq!(in_hotspot(0.0), 10_000);
+        let noise = 10_000 - in_hotspot(0.3);
+        assert!((2_800..3_200).contains(&noise), "{}", noise);
+        assert!(in_hotspot(1.0) < 10);
+        // Without hotspots everything is noise, whatever the spread
+        let distinct: std::collections::HashSet<Ipv4Addr> = generate(config(1_000, 0, 0.0, 1)).map(|line| address(&line)).collect();
+        assert!(distinct.len() > 990);
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        assert_eq!(parse_line_count("10M"), Ok(10_000_000));
+        assert_eq!(parse_line_count("2.5k"), Ok(2_500));
+        assert_eq!(parse_line_count("1000"), Ok(1_000));
+        assert_eq!(parse_line_count("0"), Ok(0));
+        for invalid in ["", "ten", "10X", "1.5", "-1", "1e3"] {
+            assert!(parse_line_count(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(parse_spread("0.2"), Ok(0.2));
+        assert_eq!(parse_spread("1"), Ok(1.0));
+        let error = parse_spread("1.5").unwrap_err();
+        assert_eq!(error, "Spread must be a share of lines from 0 to 1, got 1.5");
+        assert!(parse_spread("NaN").is_err());
+    }
+}
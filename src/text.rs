@@ -0,0 +1,229 @@
+use image::{Rgba, RgbaImage};
+
+/// Width of a glyph in font pixels, excluding the one-pixel spacing column.
+const GLYPH_WIDTH: u32 = 5;
+/// Height of a glyph in font pixels, including the descender row.
+const GLYPH_HEIGHT: u32 = 8;
+
+/// Classic 5x8 bitmap font for printable ASCII (0x20..=0x7E).
+///
+/// Each glyph is five columns; bit 0 of a column is the top row.
+const FONT_5X8: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // #
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x56, 0x20, 0x50], // &
+    [0x00, 0x08, 0x07, 0x03, 0x00], // '
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // )
+    [0x2A, 0x1C, 0x7F, 0x1C, 0x2A], // *
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // +
+    [0x00, 0x80, 0x70, 0x30, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x00, 0x60, 0x60, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // 0
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // 1
+    [0x72, 0x49, 0x49, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x49, 0x4D, 0x33], // 3
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3C, 0x4A, 0x49, 0x49, 0x31], // 6
+    [0x41, 0x21, 0x11, 0x09, 0x07], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x46, 0x49, 0x49, 0x29, 0x1E], // 9
+    [0x00, 0x00, 0x14, 0x00, 0x00], // :
+    [0x00, 0x40, 0x34, 0x00, 0x00], // ;
+    [0x00, 0x08, 0x14, 0x22, 0x41], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x59, 0x09, 0x06], // ?
+    [0x3E, 0x41, 0x5D, 0x59, 0x4E], // @
+    [0x7C, 0x12, 0x11, 0x12, 0x7C], // A
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7F, 0x41, 0x41, 0x41, 0x3E], // D
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3E, 0x41, 0x41, 0x51, 0x73], // G
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // H
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // J
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7F, 0x02, 0x1C, 0x02, 0x7F], // M
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // N
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // O
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // Q
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // R
+    [0x26, 0x49, 0x49, 0x49, 0x32], // S
+    [0x03, 0x01, 0x7F, 0x01, 0x03], // T
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // U
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // V
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x03, 0x04, 0x78, 0x04, 0x03], // Y
+    [0x61, 0x59, 0x49, 0x4D, 0x43], // Z
+    [0x00, 0x7F, 0x41, 0x41, 0x41], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // backslash
+    [0x00, 0x41, 0x41, 0x41, 0x7F], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x03, 0x07, 0x08, 0x00], // `
+    [0x20, 0x54, 0x54, 0x78, 0x40], // a
+    [0x7F, 0x28, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x28], // c
+    [0x38, 0x44, 0x44, 0x28, 0x7F], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x00, 0x08, 0x7E, 0x09, 0x02], // f
+    [0x18, 0xA4, 0xA4, 0x9C, 0x78], // g
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // i
+    [0x20, 0x40, 0x40, 0x3D, 0x00], // j
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // l
+    [0x7C, 0x04, 0x78, 0x04, 0x78], // m
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0xFC, 0x18, 0x24, 0x24, 0x18], // p
+    [0x18, 0x24, 0x24, 0x18, 0xFC], // q
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x24], // s
+    [0x04, 0x04, 0x3F, 0x44, 0x24], // t
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // u
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // v
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x4C, 0x90, 0x90, 0x90, 0x7C], // y
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x77, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x02, 0x01, 0x02, 0x04, 0x02], // ~
+];
+
+fn glyph(c: char) -> &'static [u8; 5] {
+    let index = match c {
+        ' '..='~' => c as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT_5X8[index]
+}
+
+/// Width in pixels of `text` rendered at integer `scale`.
+pub fn text_width(text: &str, scale: u32) -> u32 {
+    let chars = text.chars().count() as u32;
+    if chars == 0 {
+        return 0;
+    }
+    (chars * (GLYPH_WIDTH + 1) - 1) * scale
+}
+
+/// Height in pixels of a line of text rendered at integer `scale`.
+pub fn text_height(scale: u32) -> u32 {
+    GLYPH_HEIGHT * scale
+}
+
+/// The integer scale whose text is closest to `size` pixels high, at least 1.
+///
+/// Text is drawn with the embedded bitmap font, so sizes snap to multiples of its
+/// height.
+pub fn scale_for_size(size: u32) -> u32 {
+    ((size + GLYPH_HEIGHT / 2) / GLYPH_HEIGHT).max(1)
+}
+
+/// Draw `text` with its top-left corner at (`x`, `y`). Pixels falling outside
+/// the image are clipped.
+pub fn draw_text(image: &mut RgbaImage, x: i64, y: i64, text: &str, scale: u32, colour: Rgba<u8>) {
+    let scale = scale.max(1) as i64;
+    let mut cursor = x;
+    for c in text.chars() {
+        for (col, bits) in glyph(c).iter().enumerate() {
+            for row in 0..GLYPH_HEIGHT as i64 {
+                if bits & (1 << row) == 0 {
+                    continue;
+                }
+                let px = cursor + col as i64 * scale;
+                let py = y + row * scale;
+                fill_rect(image, px, py, scale as u32, scale as u32, colour);
+            }
+        }
+        cursor += (GLYPH_WIDTH as i64 + 1) * scale;
+    }
+}
+
+/// Fill an axis-aligned rectangle, clipped to the image.
+pub fn fill_rect(image: &mut RgbaImage, x: i64, y: i64, width: u32, height: u32, colour: Rgba<u8>) {
+    let x0 = x.max(0);
+    let y0 = y.max(0);
+    let x1 = (x + width as i64).min(image.width() as i64);
+    let y1 = (y + height as i64).min(image.height() as i64);
+    for py in y0..y1 {
+        for px in x0..x1 {
+            image.put_pixel(px as u32, py as u32, colour);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_metrics() {
+        assert_eq!(text_width("", 1), 0);
+        assert_eq!(text_width("A", 1), 5);
+        assert_eq!(text_width("AB", 2), 22);
+        assert_eq!(text_height(3), 24);
+    }
+
+    #[test]
+    fn test_draw_text_stays_within_bounds() {
+        let mut image = RgbaImage::new(40, 10);
+        let ink = Rgba([255, 255, 255, 255]);
+        draw_text(&mut image, 1, 1, "Hi", 1, ink);
+        let inked: Vec<(u32, u32)> = image
+            .enumerate_pixels()
+            .filter(|(_, _, p)| **p == ink)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        assert!(!inked.is_empty());
+        let width = text_width("Hi", 1);
+        assert!(inked.iter().all(|&(x, y)| (1..1 + width).contains(&x) && (1..9).contains(&y)));
+    }
+
+    #[test]
+    fn test_draw_text_clips_outside_image() {
+        let mut image = RgbaImage::new(4, 4);
+        // Must not panic when text runs off every edge
+        draw_text(&mut image, -3, -3, "clipped text", 2, Rgba([1, 2, 3, 255]));
+    }
+
+    #[test]
+    fn test_sizes_snap_to_the_font_height() {
+        assert_eq!(scale_for_size(1), 1);
+        assert_eq!(scale_for_size(8), 1);
+        assert_eq!(scale_for_size(12), 2);
+        assert_eq!(scale_for_size(24), 3);
+        assert_eq!(text_height(scale_for_size(24)), 24);
+    }
+
+    #[test]
+    fn test_inked_pixels_grow_with_the_square_of_the_scale() {
+        let ink = Rgba([255, 255, 255, 255]);
+        let inked = |scale: u32| {
+            let mut image = RgbaImage::new(text_width("ip-heatmap 0.1", scale), text_height(scale));
+            draw_text(&mut image, 0, 0, "ip-heatmap 0.1", scale, ink);
+            image.pixels().filter(|p| **p == ink).count()
+        };
+        let base = inked(1);
+        // Roughly a third of each 5x8 glyph cell is ink
+        let cells = 14 * 5 * 8;
+        assert!((cells / 5..cells / 2).contains(&base), "{} inked pixels", base);
+        assert_eq!(inked(3), base * 9);
+    }
+}
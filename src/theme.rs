@@ -0,0 +1,100 @@
+//! Colours of everything drawn around and over the heat data.
+
+use image::Rgba;
+
+/// Colours shared by the overlay layers: the canvas around the map (and, when not
+/// [`Theme::DARK`], unpainted pixels), text and strokes, and secondary text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Theme {
+    pub background: Rgba<u8>,
+    pub foreground: Rgba<u8>,
+    /// Secondary text such as the pixel caption, between the background and
+    /// foreground.
+    pub muted: Rgba<u8>,
+}
+
+impl Theme {
+    /// White on black, the default. Unpainted pixels stay transparent.
+    pub const DARK: Theme = Theme {
+        background: Rgba([0, 0, 0, 255]),
+        foreground: Rgba([255, 255, 255, 255]),
+        muted: Rgba([153, 153, 153, 255]),
+    };
+
+    /// Black on white, as `-r` of the original ipv4-heatmap.
+    pub const LIGHT: Theme = Theme {
+        background: Rgba([255, 255, 255, 255]),
+        foreground: Rgba([0, 0, 0, 255]),
+        muted: Rgba([102, 102, 102, 255]),
+    };
+
+    /// `foreground` on `background`, with the muted colour 60% of the way from the
+    /// background to the foreground.
+    pub fn new(background: [u8; 3], foreground: [u8; 3]) -> Self {
+        let muted = |channel: usize| (background[channel] as f64 * 0.4 + foreground[channel] as f64 * 0.6).round() as u8;
+        Theme {
+            background: opaque(background),
+            foreground: opaque(foreground),
+            muted: Rgba([muted(0), muted(1), muted(2), 255]),
+        }
+    }
+
+    /// The theme of `-r`/`--reverse`, `--background` and `--foreground`. A background
+    /// without a foreground gets black or white text, whichever contrasts more.
+    pub fn resolve(reverse: bool, background: Option<[u8; 3]>, foreground: Option<[u8; 3]>) -> Self {
+        let base = if reverse { Theme::LIGHT } else { Theme::DARK };
+        if background.is_none() && foreground.is_none() {
+            return base;
+        }
+        let rgb = |colour: Rgba<u8>| [colour.0[0], colour.0[1], colour.0[2]];
+        let background = background.unwrap_or(rgb(base.background));
+        let foreground = foreground.unwrap_or_else(|| match luminance(background) > 0.5 {
+            true => [0, 0, 0],
+            false => [255, 255, 255],
+        });
+        Theme::new(background, foreground)
+    }
+
+    /// The background as RGB, e.g. to fade a palette toward it.
+    pub fn background_rgb(&self) -> [u8; 3] {
+        [self.background.0[0], self.background.0[1], self.background.0[2]]
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::DARK
+    }
+}
+
+fn opaque([r, g, b]: [u8; 3]) -> Rgba<u8> {
+    Rgba([r, g, b, 255])
+}
+
+/// Relative luminance in [0, 1], from sRGB without linearising.
+fn luminance([r, g, b]: [u8; 3]) -> f64 {
+    (0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64) / 255.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(Theme::resolve(false, None, None), Theme::DARK);
+        assert_eq!(Theme::resolve(true, None, None), Theme::LIGHT);
+        // The built-in themes follow the same rule for the muted colour
+        assert_eq!(Theme::new([0, 0, 0], [255, 255, 255]), Theme::DARK);
+        assert_eq!(Theme::new([255, 255, 255], [0, 0, 0]), Theme::LIGHT);
+
+        // A light background gets dark text, and an explicit foreground wins
+        let cream = Theme::resolve(false, Some([255, 250, 230]), None);
+        assert_eq!(cream.foreground, Rgba([0, 0, 0, 255]));
+        let navy = Theme::resolve(true, Some([0, 0, 80]), None);
+        assert_eq!(navy.foreground, Rgba([255, 255, 255, 255]));
+        let themed = Theme::resolve(true, None, Some([200, 0, 0]));
+        assert_eq!((themed.background, themed.foreground), (Theme::LIGHT.background, Rgba([200, 0, 0, 255])));
+        assert_eq!(themed.muted, Rgba([222, 102, 102, 255]));
+    }
+}
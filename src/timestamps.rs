@@ -0,0 +1,187 @@
+//! Timestamps of input lines and the `--since`/`--until` window they are kept in.
+
+/// Integer timestamps from this magnitude on are milliseconds: as seconds they
+/// would be after the year 5000, as milliseconds they are after 1973.
+const EPOCH_MILLIS_FROM: i64 = 100_000_000_000;
+
+/// Parse a timestamp into milliseconds since the epoch: epoch seconds, epoch
+/// milliseconds (told apart by magnitude) or RFC 3339, such as
+/// `2024-06-01T00:00:00Z` or `2024-06-01 02:00:00.250+02:00`.
+pub fn parse_timestamp(s: &str) -> Result<i64, String> {
+    let s = s.trim();
+    if let Ok(epoch) = s.parse::<i64>() {
+        return Ok(if epoch.abs() >= EPOCH_MILLIS_FROM { epoch } else { epoch.saturating_mul(1000) });
+    }
+    parse_rfc3339(s).ok_or_else(|| {
+        format!("Invalid timestamp: {}. Use epoch seconds, epoch milliseconds or RFC 3339, e.g. 2024-06-01T00:00:00Z", s)
+    })
+}
+
+fn parse_rfc3339(s: &str) -> Option<i64> {
+    let number = |digits: &str| digits.bytes().all(|b| b.is_ascii_digit()).then(|| digits.parse::<i64>().ok()).flatten();
+    let bytes = s.as_bytes();
+    if !s.is_ascii() || bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' || !matches!(bytes[10], b'T' | b't' | b' ') {
+        return None;
+    }
+    if bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    let (year, month, day) = (number(&s[0..4])?, number(&s[5..7])?, number(&s[8..10])?);
+    let (hour, minute, second) = (number(&s[11..13])?, number(&s[14..16])?, number(&s[17..19])?);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => return None,
+    };
+    // A leap second is read as the last second of its minute
+    if !(1..=days_in_month).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let mut rest = &s[19..];
+    let mut millis = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        let padded = format!("{:0<3}", &fraction[..digits.min(3)]);
+        millis = number(&padded)?;
+        rest = &fraction[digits..];
+    }
+    let offset_minutes = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && matches!(&rest[..1], "+" | "-") && &rest[3..4] == ":" => {
+            let (hours, minutes) = (number(&rest[1..3])?, number(&rest[4..6])?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = hours * 60 + minutes;
+            if rest.starts_with('-') { -offset } else { offset }
+        }
+        _ => return None,
+    };
+    let seconds = days_from_civil(year, month as u32, day as u32) * 86_400 + hour * 3600 + minute * 60
+        + second.min(59)
+        - offset_minutes * 60;
+    Some(seconds * 1000 + millis)
+}
+
+/// The proleptic Gregorian date `days` after 1970-01-01, after Howard Hinnant's
+/// `civil_from_days`.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Days from 1970-01-01 to a proleptic Gregorian date, the inverse of
+/// [`civil_from_days`].
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let day_of_year = (153 * mp + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The times records are kept for, in milliseconds since the epoch: from `since`
+/// inclusive until `until` exclusive, so consecutive windows share no record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeWindow {
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl TimeWindow {
+    /// A window open on the sides without a bound; `since` must be before `until`.
+    pub fn new(since: Option<i64>, until: Option<i64>) -> Result<Self, String> {
+        if let (Some(since), Some(until)) = (since, until)
+            && since >= until
+        {
+            return Err("The time window must start before it ends".to_string());
+        }
+        Ok(TimeWindow { since, until })
+    }
+
+    pub fn since(&self) -> Option<i64> {
+        self.since
+    }
+
+    pub fn until(&self) -> Option<i64> {
+        self.until
+    }
+
+    pub fn contains(&self, millis: i64) -> bool {
+        self.since.is_none_or(|since| millis >= since) && self.until.is_none_or(|until| millis < until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JUNE_1: i64 = 1_717_200_000_000;
+
+    #[test]
+    fn test_timestamp_forms() {
+        for form in [
+            "1717200000",
+            "1717200000000",
+            "2024-06-01T00:00:00Z",
+            "2024-06-01t00:00:00z",
+            "2024-06-01 02:00:00+02:00",
+            "2024-05-31T19:30:00.000-04:30",
+        ] {
+            assert_eq!(parse_timestamp(form), Ok(JUNE_1), "{}", form);
+        }
+        assert_eq!(parse_timestamp("2024-06-01T00:00:00.25Z"), Ok(JUNE_1 + 250));
+        assert_eq!(parse_timestamp("2024-06-01T00:00:00.123456789Z"), Ok(JUNE_1 + 123));
+        assert_eq!(parse_timestamp("0"), Ok(0));
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), Ok(-1000));
+        assert_eq!(parse_timestamp("2000-02-29T00:00:00Z"), Ok(951_782_400_000));
+        for invalid in ["", "yesterday", "2024-06-01", "2024-06-01T00:00:00", "2023-02-29T00:00:00Z", "2024-06-01T24:00:00Z"] {
+            assert!(parse_timestamp(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse_timestamp("2024-06-01T00:00:00+2:00").is_err());
+        assert!(parse_timestamp("2024-06-01T00:00:00.Z").is_err());
+    }
+
+    #[test]
+    fn test_window_boundaries() {
+        let day = 86_400_000;
+        let window = TimeWindow::new(Some(JUNE_1), Some(JUNE_1 + day)).unwrap();
+        assert!(!window.contains(JUNE_1 - 1));
+        assert!(window.contains(JUNE_1));
+        assert!(window.contains(JUNE_1 + day - 1));
+        assert!(!window.contains(JUNE_1 + day));
+        // The next day's window starts where this one ends
+        let next = TimeWindow::new(Some(JUNE_1 + day), None).unwrap();
+        assert!(next.contains(JUNE_1 + day) && next.contains(i64::MAX));
+        let before = TimeWindow::new(None, Some(JUNE_1)).unwrap();
+        assert!(before.contains(i64::MIN) && !before.contains(JUNE_1));
+        assert!(TimeWindow::new(Some(JUNE_1), Some(JUNE_1)).is_err());
+        assert!(TimeWindow::new(None, None).unwrap().contains(0));
+    }
+
+    #[test]
+    fn test_civil_days_round_trip() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        for days in (-800_000..800_000).step_by(997) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::cell::Cell;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// The major phases of a run, in pipeline order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Read,
+    Parse,
+    Paint,
+    Domain,
+    Colourise,
+    Encode,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::Read,
+        Phase::Parse,
+        Phase::Paint,
+        Phase::Domain,
+        Phase::Colourise,
+        Phase::Encode,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Read => "read",
+            Phase::Parse => "parse",
+            Phase::Paint => "paint",
+            Phase::Domain => "domain",
+            Phase::Colourise => "colourise",
+            Phase::Encode => "encode",
+        }
+    }
+}
+
+/// Accumulates wall-clock time per [`Phase`].
+///
+/// A disabled timer never reads the clock, so instrumented code costs a branch.
+#[derive(Clone, Debug, Default)]
+pub struct PhaseTimer {
+    enabled: bool,
+    totals: [Cell<Duration>; 6],
+}
+
+impl PhaseTimer {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Mark the start of a phase; pass the result to [`PhaseTimer::stop`].
+    pub fn start(&self) -> Option<Instant> {
+        self.enabled.then(Instant::now)
+    }
+
+    /// Add the time since `started` to `phase` and return the current instant, so
+    /// consecutive phases can be chained without reading the clock twice.
+    pub fn stop(&self, phase: Phase, started: Option<Instant>) -> Option<Instant> {
+        let started = started?;
+        let now = Instant::now();
+        let total = &self.totals[phase as usize];
+        total.set(total.get() + (now - started));
+        Some(now)
+    }
+
+    /// Run `f`, adding its duration to `phase`.
+    pub fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let started = self.start();
+        let result = f();
+        self.stop(phase, started);
+        result
+    }
+
+    /// Add the durations `other` recorded, e.g. on another thread, to this timer's.
+    pub fn merge(&self, other: &PhaseTimer) {
+        for (total, other) in self.totals.iter().zip(&other.totals) {
+            total.set(total.get() + other.get());
+        }
+    }
+
+    pub fn total(&self, phase: Phase) -> Duration {
+        self.totals[phase as usize].get()
+    }
+
+    /// A table of the phase durations and their share of the total.
+    pub fn to_text(&self) -> String {
+        let sum: Duration = Phase::ALL.iter().map(|&phase| self.total(phase)).sum();
+        let mut text = String::new();
+        for phase in Phase::ALL {
+            let total = self.total(phase);
+            let share = if sum.is_zero() {
+                0.0
+            } else {
+                total.as_secs_f64() * 100.0 / sum.as_secs_f64()
+            };
+            let _ = writeln!(
+                text,
+                "{:<10} {:>10.3} ms {:>5.1}%",
+                phase.name(),
+                total.as_secs_f64() * 1e3,
+                share
+            );
+        }
+        let _ = writeln!(text, "{:<10} {:>10.3} ms", "total", sum.as_secs_f64() * 1e3);
+        text
+    }
+
+    /// The duration of every phase, in pipeline order.
+    pub fn totals(&self) -> Vec<(Phase, Duration)> {
+        Phase::ALL.iter().map(|&phase| (phase, self.total(phase))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_timer_records_nothing() {
+        let timer = PhaseTimer::new(false);
+        assert!(timer.start().is_none());
+        timer.time(Phase::Paint, || std::thread::sleep(Duration::from_millis(1)));
+        assert!(timer.total(Phase::Paint).is_zero());
+    }
+
+    #[test]
+    fn test_enabled_timer_accumulates() {
+        let timer = PhaseTimer::new(true);
+        timer.time(Phase::Encode, || std::thread::sleep(Duration::from_millis(2)));
+        timer.time(Phase::Encode, || std::thread::sleep(Duration::from_millis(2)));
+        assert!(timer.total(Phase::Encode) >= Duration::from_millis(4));
+        assert!(timer.total(Phase::Read).is_zero());
+    }
+
+    #[test]
+    fn test_merge_adds_totals() {
+        let (timer, other) = (PhaseTimer::new(true), PhaseTimer::new(true));
+        timer.time(Phase::Parse, || std::thread::sleep(Duration::from_millis(1)));
+        other.time(Phase::Parse, || std::thread::sleep(Duration::from_millis(1)));
+        other.time(Phase::Read, || std::thread::sleep(Duration::from_millis(1)));
+        let parse = timer.total(Phase::Parse) + other.total(Phase::Parse);
+        timer.merge(&other);
+        assert_eq!(timer.total(Phase::Parse), parse);
+        assert_eq!(timer.total(Phase::Read), other.total(Phase::Read));
+    }
+}
@@ -0,0 +1,299 @@
+//! Dry-run parsing that reports what a render would see without allocating the image.
+
+use crate::input::{self, InputFormat, ParseOptions, ParsedLine, Record, ValueSource};
+use crate::json::JsonValue;
+use crate::rejects::{RejectLog, RejectReason};
+use crate::timing::PhaseTimer;
+use crate::{ValueMode, image_size_for_bpp};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::BufRead;
+
+/// Parses input like [`crate::Heatmap`] would, but only keeps the cells that were
+/// painted, so memory grows with the number of touched pixels rather than the image.
+pub struct Validator {
+    bits_per_pixel: u8,
+    value_mode: ValueMode,
+    accumulate: bool,
+    parse_options: ParseOptions,
+    cells: HashMap<u64, i32>,
+    lines: u64,
+    records: u64,
+    cidr_host_bits: u64,
+    ignored_values: u64,
+    outside_window: u64,
+    input_range: Option<(i32, i32)>,
+    rejects: RejectLog,
+}
+
+/// The outcome of a [`Validator`] run.
+#[derive(Clone, Debug)]
+pub struct Validation {
+    pub lines: u64,
+    /// Lines that parsed into an address or prefix.
+    pub records: u64,
+    /// Prefixes that were accepted with host bits set.
+    pub cidr_host_bits: u64,
+    /// Records dropped for their value.
+    pub ignored_values: u64,
+    /// Records dropped for their time.
+    pub outside_window: u64,
+    pub rejects: RejectLog,
+    /// Smallest and largest value given on input lines.
+    pub input_range: Option<(i32, i32)>,
+    pub touched_pixels: u64,
+    pub total_pixels: u64,
+    /// The colour domain automatic scaling would pick.
+    pub auto_min: i32,
+    pub auto_max: i32,
+}
+
+impl Validator {
+    /// A validator reading input with `options`, as a heatmap with these settings would.
+    pub fn new(bits_per_pixel: u8, value_mode: ValueMode, accumulate: bool, options: ParseOptions) -> Self {
+        Self {
+            bits_per_pixel,
+            value_mode,
+            accumulate,
+            rejects: RejectLog::new(options.max_rejects),
+            parse_options: options,
+            cells: HashMap::new(),
+            lines: 0,
+            records: 0,
+            cidr_host_bits: 0,
+            ignored_values: 0,
+            outside_window: 0,
+            input_range: None,
+        }
+    }
+
+    fn init_value(&self) -> i32 {
+        match self.value_mode {
+            ValueMode::Categorical => -1,
+            ValueMode::Raw | ValueMode::Scaled => 0,
+        }
+    }
+
+    pub fn process_input_from_reader<R: BufRead>(&mut self, reader: R) -> Result<()> {
+        let init_value = self.init_value();
+        let options = self.parse_options.clone();
+        input::for_each_record(reader, 0, &options, &PhaseTimer::default(), |line_number, line, parsed| {
+            self.lines += 1;
+            match parsed {
+                ParsedLine::Blank | ParsedLine::Unsampled => {}
+                ParsedLine::Ignored => self.ignored_values += 1,
+                ParsedLine::OutsideWindow => self.outside_window += 1,
+                ParsedLine::Record(record) => self.paint(&record, options.value_source, init_value),
+                ParsedLine::Records(records) => {
+                    for record in &records {
+                        self.paint(record, options.value_source, init_value);
+                    }
+                }
+                ParsedLine::Rejected(reason, message) => {
+                    self.rejects.record(line_number, line, reason, message);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn paint(&mut self, record: &Record, value_source: ValueSource, init_value: i32) {
+        self.records += 1;
+        if record.has_host_bits() {
+            self.cidr_host_bits += 1;
+        }
+        let (low, high) = self.input_range.unwrap_or((record.value, record.value));
+        self.input_range = Some((low.min(record.value), high.max(record.value)));
+        let cells = &mut self.cells;
+        if self.parse_options.format == InputFormat::Cells {
+            let categorical = self.value_mode == ValueMode::Categorical;
+            input::for_each_cell_share(self.bits_per_pixel, record, |d, share| {
+                cells.entry(d).and_modify(|cell| *cell = if categorical { share } else { cell.saturating_add(share) }).or_insert(share);
+            });
+            return;
+        }
+        let accumulate = self.accumulate;
+        let value_mode = value_source.paint_mode(self.value_mode);
+        input::for_each_pixel(self.bits_per_pixel, value_mode, record, |d, value| {
+            let cell = cells.entry(d).or_insert(init_value);
+            if accumulate {
+                *cell = cell.saturating_add(value);
+            } else {
+                *cell = value_source.overwrite(*cell, value);
+            }
+        });
+    }
+
+    pub fn process_input_from_string(&mut self, input: &str) -> Result<()> {
+        self.process_input_from_reader(std::io::Cursor::new(input))
+    }
+
+    pub fn finish(self) -> Validation {
+        let size = image_size_for_bpp(self.bits_per_pixel) as u64;
+        let total_pixels = size * size;
+        let touched_pixels = self.cells.len() as u64;
+        // Untouched pixels keep their initial value, which takes part in auto-scaling
+        let untouched = (touched_pixels < total_pixels).then(|| self.init_value());
+        let values = self.cells.values().copied().chain(untouched);
+        let auto_min = values.clone().min().unwrap_or(0);
+        let auto_max = values.max().unwrap_or(0);
+        Validation {
+            lines: self.lines,
+            records: self.records,
+            cidr_host_bits: self.cidr_host_bits,
+            ignored_values: self.ignored_values,
+            outside_window: self.outside_window,
+            rejects: self.rejects,
+            input_range: self.input_range,
+            touched_pixels,
+            total_pixels,
+            auto_min,
+            auto_max,
+        }
+    }
+}
+
+impl Validation {
+    /// Rejected lines as a percentage of non-blank lines.
+    pub fn reject_percent(&self) -> f64 {
+        let parsed = self.records + self.rejects.total();
+        if parsed == 0 {
+            0.0
+        } else {
+            self.rejects.total() as f64 * 100.0 / parsed as f64
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "lines:          {}", self.lines);
+        let _ = writeln!(text, "records:        {}", self.records);
+        let _ = writeln!(
+            text,
+            "rejected:       {} ({:.2}%)",
+            self.rejects.total(),
+            self.reject_percent()
+        );
+        let ipv6_skipped = self.rejects.count(RejectReason::Ipv6);
+        if ipv6_skipped > 0 {
+            let _ = writeln!(text, "ipv6 skipped:   {}", ipv6_skipped);
+        }
+        if self.cidr_host_bits > 0 {
+            let _ = writeln!(text, "cidr host bits: {}", self.cidr_host_bits);
+        }
+        if self.ignored_values > 0 {
+            let _ = writeln!(text, "ignored values: {}", self.ignored_values);
+        }
+        if self.outside_window > 0 {
+            let _ = writeln!(text, "outside window: {}", self.outside_window);
+        }
+        for reject in self.rejects.samples() {
+            let _ = writeln!(
+                text,
+                "  line {}: {}: {}: {}",
+                reject.line_number, reject.reason, reject.message, reject.content
+            );
+        }
+        match self.input_range {
+            Some((low, high)) => {
+                let _ = writeln!(text, "input values:   {} to {}", low, high);
+            }
+            None => {
+                let _ = writeln!(text, "input values:   none");
+            }
+        }
+        let _ = writeln!(
+            text,
+            "touched pixels: {} of {}",
+            self.touched_pixels, self.total_pixels
+        );
+        let _ = writeln!(text, "auto scale:     min {}, max {}", self.auto_min, self.auto_max);
+        text
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::object();
+        json.insert("lines", self.lines);
+        json.insert("records", self.records);
+        json.insert("rejected", self.rejects.total());
+        json.insert("reject_percent", self.reject_percent());
+        json.insert("ipv6_skipped", self.rejects.count(RejectReason::Ipv6));
+        json.insert("cidr_host_bits", self.cidr_host_bits);
+        json.insert("ignored_values", self.ignored_values);
+        json.insert("outside_window", self.outside_window);
+        let samples: Vec<JsonValue> = self
+            .rejects
+            .samples()
+            .iter()
+            .map(|reject| {
+                let mut sample = JsonValue::object();
+                sample.insert("line_number", reject.line_number);
+                sample.insert("reason", reject.reason.to_string());
+                sample.insert("message", reject.message.as_str());
+                sample.insert("content", reject.content.as_str());
+                sample
+            })
+            .collect();
+        json.insert("reject_samples", samples);
+        json.insert("input_min", self.input_range.map(|r| r.0 as i64));
+        json.insert("input_max", self.input_range.map(|r| r.1 as i64));
+        json.insert("touched_pixels", self.touched_pixels);
+        json.insert("total_pixels", self.total_pixels);
+        json.insert("auto_min", self.auto_min as i64);
+        json.insert("auto_max", self.auto_max as i64);
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainType, Heatmap};
+
+    const INPUT: &str = "10.0.0.1 5\n10.0.0.2 7\n\nnot-an-ip\n192.168.0.0/16 3\n1.2.3.4 -2\n";
+
+    fn validate(accumulate: bool) -> Validation {
+        let mut validator = Validator::new(16, ValueMode::Raw, accumulate, ParseOptions::default());
+        validator.process_input_from_string(INPUT).unwrap();
+        validator.finish()
+    }
+
+    #[test]
+    fn test_validation_counts() {
+        let validation = validate(true);
+        assert_eq!(validation.lines, 6);
+        assert_eq!(validation.records, 4);
+        assert_eq!(validation.rejects.total(), 1);
+        assert_eq!(validation.reject_percent(), 20.0);
+        assert_eq!(validation.input_range, Some((-2, 7)));
+        assert_eq!(validation.touched_pixels, 3);
+        assert_eq!(validation.total_pixels, 65536);
+    }
+
+    #[test]
+    fn test_auto_scale_matches_heatmap() {
+        for accumulate in [false, true] {
+            let validation = validate(accumulate);
+            let mut heatmap = Heatmap::new(
+                DomainType::Linear,
+                None,
+                None,
+                accumulate,
+                16,
+                &colorous::MAGMA,
+                ValueMode::Raw,
+                None,
+            );
+            heatmap.process_input_from_string(INPUT).unwrap();
+            assert_eq!((validation.auto_min, validation.auto_max), heatmap.value_range());
+            assert_eq!(validation.touched_pixels, heatmap.touched_pixels());
+        }
+    }
+
+    #[test]
+    fn test_json_lists_reject_samples() {
+        let json = validate(false).to_json().to_string();
+        assert!(json.contains(r#""reject_samples":[{"line_number":4,"reason":"invalid IP address""#));
+    }
+}
@@ -0,0 +1,119 @@
+//! Rate-limited warnings, so input with millions of bad lines logs a bounded number
+//! of messages instead of one per line.
+
+use crate::caption::group_digits;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Warnings of each reason logged verbatim before the rest are suppressed.
+pub const DEFAULT_WARNINGS_PER_REASON: u64 = 10;
+
+/// How often a count of the warnings suppressed so far is logged.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Logs the first warnings of each reason and counts the rest, logging how many were
+/// suppressed every [`SUMMARY_INTERVAL`] and when [`WarningLimiter::log_suppressed`]
+/// is called.
+#[derive(Clone, Debug)]
+pub struct WarningLimiter<K> {
+    per_reason: u64,
+    interval: Duration,
+    /// Warnings seen per reason, logged or not.
+    seen: HashMap<K, u64>,
+    suppressed: u64,
+    /// Suppressed warnings not yet included in a logged count.
+    unreported: u64,
+    last_summary: Option<Instant>,
+}
+
+impl<K> Default for WarningLimiter<K> {
+    fn default() -> Self {
+        Self::new(DEFAULT_WARNINGS_PER_REASON)
+    }
+}
+
+impl<K> WarningLimiter<K> {
+    pub fn new(per_reason: u64) -> Self {
+        Self {
+            per_reason,
+            interval: SUMMARY_INTERVAL,
+            seen: HashMap::new(),
+            suppressed: 0,
+            unreported: 0,
+            last_summary: None,
+        }
+    }
+
+    /// Warnings suppressed so far.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    /// Log how many warnings were suppressed since the last count, if any.
+    pub fn log_suppressed(&mut self) {
+        if self.unreported > 0 {
+            log::warn!(suppressed = self.unreported; "…suppressed {} similar warnings", group_digits(self.unreported));
+            self.unreported = 0;
+        }
+        self.last_summary = Some(Instant::now());
+    }
+}
+
+impl<K: Eq + Hash + Display + Clone> WarningLimiter<K> {
+    /// Call `log`, which logs the warning, unless enough warnings of `reason` were
+    /// logged already. It is only called when the warning is logged, so its message
+    /// and key-values are only formatted then.
+    pub fn warn(&mut self, reason: K, log: impl FnOnce()) {
+        let seen = self.seen.entry(reason.clone()).or_insert(0);
+        *seen += 1;
+        if *seen <= self.per_reason {
+            log();
+            if *seen == self.per_reason {
+                log::warn!(reason:% = reason; "Further {} warnings are counted without being logged", reason);
+            }
+            return;
+        }
+        self.suppressed += 1;
+        self.unreported += 1;
+        let started = *self.last_summary.get_or_insert_with(Instant::now);
+        // Reading the clock for every line would cost more than the warning saved
+        if self.unreported.is_multiple_of(1024) && started.elapsed() >= self.interval {
+            self.log_suppressed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings_are_limited_per_reason() {
+        let mut limiter = WarningLimiter::new(2);
+        for line in 0..100 {
+            let reason = if line % 10 == 0 { "rare" } else { "common" };
+            limiter.warn(reason, || log::warn!("line {}", line));
+        }
+        // Two of each reason are logged, the other 96 counted
+        assert_eq!(limiter.seen[&"rare"], 10);
+        assert_eq!(limiter.suppressed(), 96);
+        limiter.log_suppressed();
+        assert_eq!(limiter.unreported, 0);
+        assert_eq!(limiter.suppressed(), 96);
+    }
+
+    #[test]
+    fn test_periodic_summary() {
+        let mut limiter = WarningLimiter::new(0);
+        limiter.interval = Duration::ZERO;
+        for _ in 0..1023 {
+            limiter.warn(1, || {});
+        }
+        assert_eq!(limiter.unreported, 1023);
+        limiter.warn(1, || {});
+        assert_eq!(limiter.unreported, 0);
+        assert_eq!(limiter.suppressed(), 1024);
+    }
+}
@@ -1,5 +1,5 @@
 use wasm_bindgen::prelude::*;
-use crate::{Heatmap, DomainType, ValueMode, image_size_for_bpp};
+use crate::{Aggregation, Capabilities, Geometry, Heatmap, DomainType, InputFormat, Palette, ParseOptions, Reject, RenderOptions, ValueMode, parse_hex_colour};
 use colorous;
 
 #[wasm_bindgen(start)]
@@ -9,10 +9,305 @@ pub fn init_panic_hook() {
 
 #[wasm_bindgen]
 pub fn get_image_size(bits_per_pixel: u8) -> u32 {
-    image_size_for_bpp(bits_per_pixel)
+    Geometry::for_bits_per_pixel(bits_per_pixel).width
+}
+
+/// The dimensions of a map, as reported to the page. `ipsPerPixel` is a number
+/// rather than a `BigInt`: at most 2^32, it is exact either way.
+#[wasm_bindgen(js_name = Geometry)]
+pub struct JsGeometry {
+    pub width: u32,
+    pub height: u32,
+    pub order: u32,
+    #[wasm_bindgen(js_name = ipsPerPixel)]
+    pub ips_per_pixel: f64,
+    #[wasm_bindgen(js_name = prefixLenPerPixel)]
+    pub prefix_len_per_pixel: u8,
+}
+
+impl From<Geometry> for JsGeometry {
+    fn from(geometry: Geometry) -> Self {
+        Self {
+            width: geometry.width,
+            height: geometry.height,
+            order: geometry.order,
+            ips_per_pixel: geometry.ips_per_pixel as f64,
+            prefix_len_per_pixel: geometry.prefix_len_per_pixel,
+        }
+    }
+}
+
+/// What this module accepts, see [`capabilities`]. The lists come from the tables
+/// the parsers consult and include aliases; `json` is the same as a JSON string.
+#[wasm_bindgen(js_name = Capabilities, getter_with_clone)]
+pub struct JsCapabilities {
+    pub version: String,
+    pub curves: Vec<String>,
+    pub palettes: Vec<String>,
+    #[wasm_bindgen(js_name = inputFormats)]
+    pub input_formats: Vec<String>,
+    #[wasm_bindgen(js_name = valueModes)]
+    pub value_modes: Vec<String>,
+    #[wasm_bindgen(js_name = minBitsPerPixel)]
+    pub min_bits_per_pixel: u8,
+    #[wasm_bindgen(js_name = maxBitsPerPixel)]
+    pub max_bits_per_pixel: u8,
+    #[wasm_bindgen(js_name = evenBitsPerPixelOnly)]
+    pub even_bits_per_pixel_only: bool,
+    #[wasm_bindgen(js_name = maxLineBytes)]
+    pub max_line_bytes: u32,
+    /// Unset: only memory bounds the input.
+    #[wasm_bindgen(js_name = maxInputBytes)]
+    pub max_input_bytes: Option<f64>,
+    pub json: String,
+}
+
+impl From<Capabilities> for JsCapabilities {
+    fn from(capabilities: Capabilities) -> Self {
+        let strings = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        Self {
+            version: capabilities.version.to_string(),
+            curves: strings(&capabilities.curves),
+            palettes: strings(&capabilities.palettes),
+            input_formats: strings(&capabilities.input_formats),
+            value_modes: strings(&capabilities.value_modes),
+            min_bits_per_pixel: capabilities.min_bits_per_pixel,
+            max_bits_per_pixel: capabilities.max_bits_per_pixel,
+            even_bits_per_pixel_only: capabilities.even_bits_per_pixel_only,
+            max_line_bytes: capabilities.max_line_bytes as u32,
+            max_input_bytes: capabilities.max_input_bytes.map(|bytes| bytes as f64),
+            json: capabilities.to_json().to_string(),
+        }
+    }
+}
+
+/// The version, curves, palettes, input formats, value modes and limits of this
+/// module, for pages that build their controls from what the loaded module supports.
+#[wasm_bindgen]
+pub fn capabilities() -> JsValue {
+    JsCapabilities::from(Capabilities::current()).into()
+}
+
+#[wasm_bindgen]
+pub fn get_geometry(bits_per_pixel: u8) -> Result<JsGeometry, JsValue> {
+    if bits_per_pixel > 32 {
+        return Err(JsValue::from_str(&format!("bits_per_pixel cannot exceed 32 (got {})", bits_per_pixel)));
+    }
+    Ok(Geometry::for_bits_per_pixel(bits_per_pixel).into())
+}
+
+/// A rejected input line, as reported to the page.
+#[wasm_bindgen(getter_with_clone)]
+pub struct ParseError {
+    pub line_number: u32,
+    pub content: String,
+    pub reason: String,
+}
+
+impl From<Reject> for ParseError {
+    fn from(reject: Reject) -> Self {
+        Self {
+            line_number: reject.line_number as u32,
+            content: reject.content,
+            reason: format!("{}: {}", reject.reason, reject.message),
+        }
+    }
+}
+
+/// Result of a heatmap generation: the RGBA pixels plus rejected line reports.
+#[wasm_bindgen]
+pub struct GeneratedHeatmap {
+    rgba: Vec<u8>,
+    size: u32,
+    lines: u32,
+    rejected: u32,
+    errors: Vec<Reject>,
 }
 
 #[wasm_bindgen]
+impl GeneratedHeatmap {
+    /// RGBA pixel data, suitable for `ImageData`.
+    pub fn rgba(&self) -> Vec<u8> {
+        self.rgba.clone()
+    }
+
+    /// Side length of the image in pixels.
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Total number of input lines read.
+    #[wasm_bindgen(getter)]
+    pub fn lines(&self) -> u32 {
+        self.lines
+    }
+
+    /// Total number of rejected lines, including those beyond the sample limit.
+    #[wasm_bindgen(getter)]
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+
+    /// Remove and return the recorded rejected lines (at most `max_errors`).
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        std::mem::take(&mut self.errors)
+            .into_iter()
+            .map(ParseError::from)
+            .collect()
+    }
+}
+
+/// The built-in colour scale named `colour_scale`.
+fn builtin_palette(colour_scale: &str) -> Result<Palette, JsValue> {
+    Palette::builtin(colour_scale).ok_or_else(|| {
+        let names: Vec<&str> = Palette::builtin_names().collect();
+        JsValue::from_str(&format!("Invalid colour scale: {}. Supported: {}", colour_scale, names.join(", ")))
+    })
+}
+
+/// Input processed once, to be coloured any number of times with `render` as the
+/// page changes the curve, bounds or palette.
+#[wasm_bindgen(js_name = ProcessedHeatmap)]
+pub struct ProcessedHeatmap {
+    heatmap: Heatmap,
+}
+
+#[wasm_bindgen(js_class = ProcessedHeatmap)]
+impl ProcessedHeatmap {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        input_data: &str,
+        accumulate: bool,
+        bits_per_pixel: u8,
+        value_mode: &str,
+        separator: Option<String>,
+        max_errors: Option<u32>,
+        input_format: Option<String>,
+    ) -> Result<ProcessedHeatmap, JsValue> {
+        let capabilities = Capabilities::current();
+        capabilities.check_bits_per_pixel(bits_per_pixel).map_err(|e| JsValue::from_str(&e))?;
+
+        // Only formats of text can be passed as a string
+        let input_format = match input_format {
+            Some(name) if capabilities.input_formats.contains(&name.to_lowercase().as_str()) => {
+                name.parse::<InputFormat>().map_err(|e| JsValue::from_str(&e))?
+            }
+            Some(name) => {
+                return Err(JsValue::from_str(&format!(
+                    "Invalid input format: {}. Supported: {}",
+                    name,
+                    capabilities.input_formats.join(", ")
+                )));
+            }
+            None => InputFormat::Text,
+        };
+
+        // Parse value mode
+        let value_mode: ValueMode = value_mode.parse()
+            .map_err(|e: String| JsValue::from_str(&e))?;
+
+        // Parse separator
+        let sep_char = separator.and_then(|s| s.chars().next());
+
+        // The curve, bounds and colour scale are chosen per render
+        let mut heatmap = Heatmap::new(
+            DomainType::Linear,
+            None,
+            None,
+            accumulate,
+            bits_per_pixel,
+            &colorous::MAGMA,
+            value_mode,
+            sep_char,
+        );
+        let mut options = ParseOptions::default().separator(sep_char).format(input_format);
+        if let Some(max_errors) = max_errors {
+            options = options.max_rejects(max_errors as usize);
+        }
+        heatmap.set_parse_options(options);
+        heatmap.check_value_modes(None)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        // Process input
+        heatmap.process_input_from_string(input_data)
+            .map_err(|e| JsValue::from_str(&format!("Failed to process input: {}", e)))?;
+        Ok(ProcessedHeatmap { heatmap })
+    }
+
+    /// RGBA pixel data, suitable for `ImageData`: a square of side `output_size`
+    /// when given, downsampled by the maximum of each block, or the full map.
+    /// `background` (`#rrggbb`) fills the pixels no input reached, which are
+    /// otherwise transparent.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(
+        &self,
+        curve_type: &str,
+        min_value: Option<f64>,
+        max_value: Option<f64>,
+        colour_scale: &str,
+        gamma: Option<f64>,
+        output_size: Option<u32>,
+        background: Option<String>,
+    ) -> Result<Vec<u8>, JsValue> {
+        let curve: DomainType = curve_type.parse().map_err(|err: String| JsValue::from_str(&err))?;
+        let palette = builtin_palette(colour_scale)?;
+        let gamma = gamma.unwrap_or(1.0);
+        if !gamma.is_finite() || gamma <= 0.0 {
+            return Err(JsValue::from_str(&format!("Gamma must be a number greater than 0: {}", gamma)));
+        }
+        let background = background
+            .map(|colour| parse_hex_colour(&colour))
+            .transpose()
+            .map_err(|err| JsValue::from_str(&err))?;
+        let options = RenderOptions {
+            curve,
+            min_value,
+            max_value,
+            palette,
+            gamma,
+            background,
+            ..self.heatmap.render_options()
+        };
+        // Downsample in value space (keeping hotspots) for smaller canvases
+        let reduced = match output_size {
+            Some(size) => Some(self.heatmap.downsample(size, Aggregation::Max)
+                .map_err(|e| JsValue::from_str(&e.to_string()))?),
+            None => None,
+        };
+        let display = reduced.as_ref().unwrap_or(&self.heatmap);
+        Ok(display.render(&options)
+            .map_err(|e| JsValue::from_str(&format!("Failed to generate RGBA data: {}", e)))?
+            .into_raw())
+    }
+
+    /// Side length in pixels of a rendering at `output_size`.
+    pub fn size(&self, output_size: Option<u32>) -> u32 {
+        output_size.unwrap_or_else(|| self.heatmap.image_size())
+    }
+
+    /// Total number of input lines read.
+    #[wasm_bindgen(getter)]
+    pub fn lines(&self) -> u32 {
+        self.heatmap.lines_processed() as u32
+    }
+
+    /// Total number of rejected lines, including those beyond the sample limit.
+    #[wasm_bindgen(getter)]
+    pub fn rejected(&self) -> u32 {
+        self.heatmap.rejects().total() as u32
+    }
+
+    /// Remove and return the recorded rejected lines (at most `max_errors`).
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        self.heatmap.take_errors().into_iter().map(ParseError::from).collect()
+    }
+}
+
+/// Process and colour in one call. Pages that recolour the same input should keep
+/// a [`ProcessedHeatmap`] instead.
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
 pub fn generate_heatmap(
     input_data: &str,
     curve_type: &str,
@@ -23,73 +318,20 @@ pub fn generate_heatmap(
     colour_scale: &str,
     value_mode: &str,
     separator: Option<String>,
-) -> Result<Vec<u8>, JsValue> {
-    // Parse curve type
-    let domain_type = match curve_type.to_lowercase().as_str() {
-        "linear" => DomainType::Linear,
-        "logarithmic" => DomainType::Logarithmic,
-        _ => return Err(JsValue::from_str(&format!("Invalid curve type: {}. Must be 'linear' or 'logarithmic'", curve_type))),
-    };
-
-    // Validate bits_per_pixel: must be even, and in range [8, 24]
-    if bits_per_pixel < 8 {
-        return Err(JsValue::from_str(&format!(
-            "bits_per_pixel must be at least 8 (got {}). Each pixel represents 2^bits_per_pixel IPs.",
-            bits_per_pixel
-        )));
-    }
-    if bits_per_pixel > 24 {
-        return Err(JsValue::from_str(&format!(
-            "bits_per_pixel cannot exceed 24 (got {})",
-            bits_per_pixel
-        )));
-    }
-    if bits_per_pixel % 2 != 0 {
-        return Err(JsValue::from_str(&format!(
-            "bits_per_pixel must be even (got {})",
-            bits_per_pixel
-        )));
-    }
-
-    // Parse color scale
-    let gradient = match colour_scale.to_lowercase().as_str() {
-        "magma" => &colorous::MAGMA,
-        "inferno" => &colorous::INFERNO,
-        "plasma" => &colorous::PLASMA,
-        "viridis" => &colorous::VIRIDIS,
-        "cividis" => &colorous::CIVIDIS,
-        "turbo" => &colorous::TURBO,
-        "warm" => &colorous::WARM,
-        "cool" => &colorous::COOL,
-        _ => return Err(JsValue::from_str(&format!("Invalid colour scale: {}. Supported: magma, inferno, plasma, viridis, cividis, turbo, warm, cool", colour_scale))),
-    };
-
-    // Parse value mode
-    let value_mode: ValueMode = value_mode.parse()
-        .map_err(|e: String| JsValue::from_str(&e))?;
-
-    // Parse separator
-    let sep_char = separator.and_then(|s| s.chars().next());
-
-    // Create heatmap
-    let mut heatmap = Heatmap::new(
-        domain_type,
-        min_value,
-        max_value,
-        accumulate,
-        bits_per_pixel,
-        gradient,
-        value_mode,
-        sep_char,
-    );
-
-    // Process input
-    heatmap.process_input_from_string(input_data)
-        .map_err(|e| JsValue::from_str(&format!("Failed to process input: {}", e)))?;
-
-    // Get RGBA data
-    let rgba_data = heatmap.get_rgba_data()
-        .map_err(|e| JsValue::from_str(&format!("Failed to generate RGBA data: {}", e)))?;
-
-    Ok(rgba_data)
+    max_errors: Option<u32>,
+    gamma: Option<f64>,
+    output_size: Option<u32>,
+) -> Result<GeneratedHeatmap, JsValue> {
+    // Check the rendering's arguments before reading any input
+    curve_type.parse::<DomainType>().map_err(|err: String| JsValue::from_str(&err))?;
+    builtin_palette(colour_scale)?;
+    let mut processed = ProcessedHeatmap::new(input_data, accumulate, bits_per_pixel, value_mode, separator, max_errors, None)?;
+    let rgba = processed.render(curve_type, min_value, max_value, colour_scale, gamma, output_size, None)?;
+    Ok(GeneratedHeatmap {
+        rgba,
+        size: processed.size(output_size),
+        lines: processed.lines(),
+        rejected: processed.rejected(),
+        errors: processed.heatmap.take_errors(),
+    })
 }
@@ -0,0 +1,85 @@
+//! `--annotation-layer` splits one render into the data and a transparent image of
+//! everything drawn over it, which laid back together give the combined output.
+
+mod common;
+
+use common::{run_in, scratch_dir};
+use image::RgbaImage;
+use std::path::Path;
+use std::process::Output;
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    let input = "10.0.0.1 5\n10.1.0.0/16 3\n192.168.0.0/16 9\n172.16.0.0/12 1\n";
+    run_in(dir, &[&["-z", "16", "--value-mode", "raw"], args].concat(), input)
+}
+
+fn open(dir: &Path, name: &str) -> RgbaImage {
+    image::open(dir.join(name)).unwrap().to_rgba8()
+}
+
+/// `top` over `bottom`, for overlays whose pixels are transparent or opaque.
+fn composite(bottom: &RgbaImage, top: &RgbaImage) -> RgbaImage {
+    assert_eq!(bottom.dimensions(), top.dimensions());
+    let mut image = bottom.clone();
+    for (pixel, &over) in image.pixels_mut().zip(top.pixels()) {
+        match over.0[3] {
+            0 => {}
+            255 => *pixel = over,
+            alpha => panic!("overlay pixel with alpha {}", alpha),
+        }
+    }
+    image
+}
+
+const FRAMED: [&str; 17] = [
+    "-t",
+    "scan",
+    "--legend-label",
+    "hosts",
+    "--outline",
+    "10.0.0.0/8:ff0000:2",
+    "--outline",
+    "192.168.0.0/16",
+    "--rank",
+    "172.16.0.0/12",
+    "--rank-mark",
+    "--shade",
+    "10.0.0.0/8:00ff00",
+    "--reverse",
+    "--crop",
+    "0.0.0.0/1",
+    "--pixel-caption",
+];
+
+#[test]
+fn test_layers_composite_to_combined() {
+    let dir = scratch_dir("composite");
+    let result = run(&dir, &[&FRAMED[..], &["combined.png"]].concat());
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let result = run(&dir, &[&FRAMED[..], &["--annotation-layer", "annotations.png", "data.png"]].concat());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.contains("output=data.png,annotations.png"), "{}", stderr);
+
+    let (combined, data, annotations) = (open(&dir, "combined.png"), open(&dir, "data.png"), open(&dir, "annotations.png"));
+    assert_ne!(data, combined, "the data layer has no annotations");
+    assert!(annotations.pixels().any(|pixel| pixel.0[3] == 255));
+    assert!(annotations.pixels().any(|pixel| pixel.0[3] == 0));
+    assert!(composite(&data, &annotations) == combined, "the layers do not add up to the combined image");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_no_data_layer() {
+    let dir = scratch_dir("no-data");
+    let result = run(&dir, &["-t", "scan", "--annotation-layer", "annotations.png", "--no-data-layer", "data.png"]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(!dir.join("data.png").exists());
+    let annotations = open(&dir, "annotations.png");
+    assert!(annotations.pixels().all(|pixel| pixel.0[3] == 0 || pixel.0[3] == 255));
+    assert!(annotations.pixels().any(|pixel| pixel.0[3] == 255));
+
+    let result = run(&dir, &["--no-data-layer", "data.png"]);
+    assert!(!result.status.success(), "--no-data-layer needs --annotation-layer");
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,72 @@
+//! A reader that closes stdout early, like `head`, ends a report quietly and a data
+//! stream with a one-line error, and never with a panic.
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Output, Stdio};
+
+fn spawn(args: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("binary runs")
+}
+
+/// Run with stdout closed before anything is written to it.
+fn run_closed(args: &[&str], stdin: &str) -> Output {
+    let mut child = spawn(args);
+    drop(child.stdout.take());
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+fn input(lines: usize) -> String {
+    (0..lines).map(|line| format!("{}.{}.0.1 {}\n", line % 256, line / 256 % 256, line % 7)).collect()
+}
+
+#[test]
+fn test_reports_end_quietly() {
+    let cases: [&[&str]; 4] = [
+        &["summarize"],
+        &["summarize", "--json"],
+        &["-z", "16", "--validate"],
+        &["generate", "--lines", "100K"],
+    ];
+    for args in cases {
+        let result = run_closed(args, &input(1000));
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert_eq!(result.status.code(), Some(0), "{:?}: {}", args, stderr);
+        assert!(!stderr.contains("panicked") && !stderr.contains("Error"), "{:?}: {}", args, stderr);
+    }
+}
+
+#[test]
+fn test_cut_off_data_fails_with_one_line() {
+    for to in ["text", "raw-u32v"] {
+        let result = run_closed(&["convert", "-", "-", "--to", to], &input(1000));
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert_eq!(result.status.code(), Some(141), "{}: {}", to, stderr);
+        assert_eq!(stderr.matches("stdout was closed").count(), 1, "{}: {}", to, stderr);
+        assert!(!stderr.contains("panicked"), "{}: {}", to, stderr);
+    }
+}
+
+#[test]
+fn test_reader_closing_after_the_first_line() {
+    let mut child = spawn(&["convert", "-", "-", "--to", "text"]);
+    let mut stdin = child.stdin.take().unwrap();
+    let feeder = std::thread::spawn(move || {
+        let _ = stdin.write_all(input(200_000).as_bytes());
+    });
+    let mut first = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut first).unwrap();
+    assert_eq!(first, "0.0.0.1 0\n");
+    let result = child.wait_with_output().unwrap();
+    feeder.join().unwrap();
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(141), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
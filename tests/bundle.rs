@@ -0,0 +1,58 @@
+//! `--out-dir` writes a bundle of consistently named files, or nothing at all.
+
+mod common;
+
+use common::{entries, run, scratch_dir};
+
+const INPUT: &str = "10.0.0.1 5\nnot an ip\n192.168.0.0/16 2\n";
+
+#[test]
+fn test_bundle_contents() {
+    let scratch = scratch_dir("contents");
+    let bundle = scratch.join("run");
+    let bundle_arg = bundle.to_str().unwrap();
+    let output = run(&["-z", "16", "--value-mode", "raw", "--out-dir", bundle_arg, "--bundle", "cells"], INPUT);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr);
+    assert!(stderr.trim_end().ends_with(&format!("output={}/map.png", bundle_arg)), "{}", stderr);
+    // Nothing is left of the staging directory
+    assert_eq!(entries(&scratch), ["run"]);
+    assert_eq!(entries(&bundle), ["cells.csv", "map.png", "params.toml", "rejects.txt", "stats.json"]);
+
+    let read = |name: &str| std::fs::read_to_string(bundle.join(name)).unwrap();
+    assert!(read("stats.json").starts_with("{\"lines\":3,\"rejected\":1,"), "{}", read("stats.json"));
+    let cells: Vec<String> = read("cells.csv").lines().map(|line| line.split(',').take(2).collect::<Vec<_>>().join(",")).collect();
+    assert_eq!(cells, ["cidr,value", "10.0.0.0/16,5", "192.168.0.0/16,2"]);
+    assert!(read("rejects.txt").starts_with("2\t"), "{}", read("rejects.txt"));
+    let params = read("params.toml");
+    for line in ["bits_per_pixel = [\"16\"]", "value-mode = \"raw\"", "bundle = [\"cells\"]", "accumulate = false # default"] {
+        assert!(params.lines().any(|param| param == line), "{} is not in:\n{}", line, params);
+    }
+    assert!(std::fs::read(bundle.join("map.png")).unwrap().starts_with(b"\x89PNG"));
+
+    // A bundle is never written over another
+    let again = run(&["-z", "16", "--out-dir", bundle_arg], INPUT);
+    assert_eq!(again.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&again.stderr).contains("already exists and is not empty"));
+    std::fs::remove_dir_all(&scratch).unwrap();
+}
+
+#[test]
+fn test_single_file_flags_override_members() {
+    let scratch = scratch_dir("override");
+    let bundle = scratch.join("run");
+    let stats = scratch.join("elsewhere.json");
+    let args = ["-z", "16", "--value-mode", "raw", "--out-dir", bundle.to_str().unwrap(), "--stats-json", stats.to_str().unwrap()];
+    let output = run(&args, "10.0.0.1 1\n11.0.0.1 4\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // Without rejects or --bundle cells there are neither
+    assert_eq!(entries(&bundle), ["map.png", "params.toml"]);
+    assert!(std::fs::read_to_string(&stats).unwrap().starts_with("{\"lines\":2,"));
+
+    // A failing run leaves no half-written bundle behind
+    let failed = scratch.join("failed");
+    let output = run(&["-z", "16", "--on-error", "fail", "--out-dir", failed.to_str().unwrap()], INPUT);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(entries(&scratch), ["elsewhere.json", "run"]);
+    std::fs::remove_dir_all(&scratch).unwrap();
+}
@@ -0,0 +1,61 @@
+//! `--category-colours` keeps the colour of each category from one run to the next.
+
+mod common;
+
+use common::{run, scratch_dir};
+use ip_heatmap::{DomainType, Heatmap, ValueMode};
+use std::path::Path;
+
+/// The colour of the pixel of 10.`second`.0.0/16 at 16 bits per pixel.
+fn colour(path: &Path, second: u8) -> [u8; 4] {
+    let image = image::open(path).unwrap().to_rgba8();
+    let heatmap = Heatmap::new(DomainType::Linear, None, None, false, 16, &colorous::MAGMA, ValueMode::Categorical, None);
+    let (x, y, _, _) = heatmap.prefix_rect(&format!("10.{}.0.0/16", second).parse().unwrap());
+    image.get_pixel(x, y).0
+}
+
+#[test]
+fn test_shared_categories_keep_their_colours() {
+    let dir = scratch_dir("shared");
+    let mapping = dir.join("colours.json");
+    let render = |name: &str, input: &str| {
+        let output = dir.join(name);
+        let args = ["-z", "16", "--value-mode", "categorical", "--category-colours", mapping.to_str().unwrap()];
+        let result = run(&[&args[..], &[output.to_str().unwrap()]].concat(), input);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        output
+    };
+    // Categories 3, 13335 and 15169 at 10.0/16, 10.1/16 and 10.2/16
+    let first = render("first.png", "10.0.0.1 3\n10.1.0.1 13335\n10.2.0.1 15169\n");
+    let written = std::fs::read_to_string(&mapping).unwrap();
+    assert_eq!(written.lines().count(), 5, "{}", written);
+    // The same categories elsewhere, and a new one
+    let second = render("second.png", "10.2.0.1 3\n10.0.0.1 13335\n10.3.0.1 7\n10.1.0.1 15169\n");
+    assert_ne!(colour(&first, 0), colour(&first, 1));
+    assert_eq!(colour(&second, 2), colour(&first, 0));
+    assert_eq!(colour(&second, 0), colour(&first, 1));
+    assert_eq!(colour(&second, 1), colour(&first, 2));
+    let updated = std::fs::read_to_string(&mapping).unwrap();
+    assert!(updated.contains("\"7\": \"#") && updated.lines().count() == 6, "{}", updated);
+    // Lines of the first run are kept as they were
+    assert!(written.lines().filter(|line| line.contains(':')).all(|line| updated.contains(line.trim_end_matches(','))));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_mapping_errors() {
+    let dir = scratch_dir("errors");
+    let mapping = dir.join("colours.json");
+    std::fs::write(&mapping, "{\"3\": \"red\"}").unwrap();
+    let output = dir.join("map.png");
+    let args = ["-z", "16", "--category-colours", mapping.to_str().unwrap(), output.to_str().unwrap()];
+    let result = run(&[&args[..], &["--value-mode", "categorical"]].concat(), "10.0.0.1 3\n");
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("Invalid colour: red"), "{}", stderr);
+
+    let scaled = run(&args, "10.0.0.1 3\n");
+    assert!(String::from_utf8_lossy(&scaled.stderr).contains("needs --value-mode categorical"));
+    assert!(!output.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,36 @@
+//! `compare --changes` lists what changed between two saved runs.
+
+mod common;
+
+use common::{run, scratch_dir};
+
+#[test]
+fn test_changes_between_state_files() {
+    let dir = scratch_dir("states");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    for (name, input) in [
+        ("a", "10.0.0.0/16 50\n10.1.0.0/16 50\n172.16.0.0/12 5\n"),
+        ("b", "10.0.0.0/16 50\n10.1.0.0/16 50\n172.16.0.0/12 6\n192.168.0.0/16 80\n"),
+    ] {
+        let (state, output) = (path(&format!("{}.state", name)), path(&format!("{}.png", name)));
+        let result = run(&["-z", "16", "--value-mode", "raw", "--save-state", &state, &output], input);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    }
+
+    let (a, b, report) = (path("a.state"), path("b.state"), path("changes.txt"));
+    let result = run(&["compare", "--changes", &a, &b, "--changes-threshold", "10", "-o", &report], "");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let report = std::fs::read_to_string(&report).unwrap();
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", report);
+    assert!(lines[1].starts_with("192.168.0.0/16"), "{}", report);
+    assert!(lines[1].ends_with("+80  appeared"), "{}", report);
+
+    // Without the threshold the small rise in 172.16.0.0/12 shows up too, after the larger change
+    let result = run(&["compare", "--changes", "--json", &a, &b], "");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let json = String::from_utf8(result.stdout).unwrap();
+    assert!(json.starts_with(r#"{"threshold":0,"changes":[{"prefix":"192.168.0.0/16""#), "{}", json);
+    assert!(json.contains(r#"{"prefix":"172.16.0.0/12","old":80,"new":96,"delta":16,"change":"increased"}"#), "{}", json);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
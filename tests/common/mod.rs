@@ -0,0 +1,50 @@
+//! Helpers for the tests that run the binary, shared with `mod common;`. Each test
+//! crate uses some of them.
+#![allow(dead_code)]
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// An empty directory for the test `name`, apart from those of other test crates and
+/// of other runs.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let suite = env!("CARGO_CRATE_NAME").replace('_', "-");
+    let dir = std::env::temp_dir().join(format!("ip-heatmap-{}-{}-{}", suite, std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The binary with `args`, its standard streams piped.
+pub fn command(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ip-heatmap"));
+    command.args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    command
+}
+
+/// Run `command` with `stdin` as its input.
+pub fn output(command: &mut Command, stdin: impl AsRef<[u8]>) -> Output {
+    let mut child = command.spawn().expect("binary runs");
+    // Runs that fail on their flags exit without reading stdin
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_ref());
+    child.wait_with_output().unwrap()
+}
+
+/// Run the binary with `args` and `stdin` as its input.
+pub fn run(args: &[&str], stdin: impl AsRef<[u8]>) -> Output {
+    output(&mut command(args), stdin)
+}
+
+/// [`run`] in `dir`, where relative paths in `args` are.
+pub fn run_in(dir: &Path, args: &[&str], stdin: impl AsRef<[u8]>) -> Output {
+    output(command(args).current_dir(dir), stdin)
+}
+
+/// The names of the files in `dir`, sorted.
+pub fn entries(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> =
+        std::fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+    names.sort();
+    names
+}
@@ -0,0 +1,78 @@
+//! Rendering converted input paints the same cells as rendering the original.
+
+mod common;
+
+use common::{run, scratch_dir};
+use std::path::Path;
+
+const INPUT: &str = "10.0.0.1 5\n10.2.0.0/16 3\n10.3.0.0/16 3\n11.0.0.0/12 40\n10.0.0.2 5\n\
+                     not an address\n10.0.0.1 1\n192.168.3.4/16 2\n::ffff:8.8.8.8 7\n8.8.4.4 -1\n";
+
+/// Render `input` read as `format` and return the cells of its saved state, without
+/// the header, whose line count differs between formats.
+fn render_cells(dir: &Path, name: &str, format: &str, input: &[u8]) -> Vec<u8> {
+    let state = dir.join(format!("{}.state", name)).to_str().unwrap().to_string();
+    let output = dir.join(format!("{}.png", name)).to_str().unwrap().to_string();
+    let args = [
+        "-z", "16", "-C", "--value-mode", "raw", "--map-v6", "mapped", "--format", format, "--save-state", &state,
+        &output,
+    ];
+    let result = run(&args, input);
+    assert!(matches!(result.status.code(), Some(0 | 3)), "{}", String::from_utf8_lossy(&result.stderr));
+    std::fs::read(&state).unwrap()[ip_heatmap::STATE_HEADER_LEN..].to_vec()
+}
+
+#[test]
+fn test_converted_input_renders_the_same() {
+    let dir = scratch_dir("round-trip");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    std::fs::write(path("input.txt"), INPUT).unwrap();
+    let original = render_cells(&dir, "original", "text", INPUT.as_bytes());
+
+    let cases = [("raw-u32v", "raw-u32v", "raw"), ("csv", "text", "csv"), ("aggregated", "text", "aggregated")];
+    for (to, format, name) in cases {
+        let (input, output) = (path("input.txt"), path(&format!("converted.{}", name)));
+        let result = run(&["convert", "--to", to, "--map-v6", "mapped", "-z", "16", &input, &output], b"");
+        // The input has one unparsable line
+        assert_eq!(result.status.code(), Some(3), "{}", String::from_utf8_lossy(&result.stderr));
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert!(stderr.contains("lines: 10, records: 9, "), "{}", stderr);
+        assert!(stderr.contains("rejected: 1"), "{}", stderr);
+
+        let converted = std::fs::read(&output).unwrap();
+        assert_eq!(render_cells(&dir, name, format, &converted), original, "{}", to);
+    }
+
+    // Aggregating sums repeats and merges the two /16s into a /15
+    let aggregated = std::fs::read_to_string(path("converted.aggregated")).unwrap();
+    assert_eq!(aggregated, "8.8.4.4 -1\n8.8.8.8 7\n10.0.0.1 6\n10.0.0.2 5\n10.2.0.0/15 3\n11.0.0.0/12 40\n192.168.0.0/16 2\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_stdin_and_stdout() {
+    let result = run(&["convert", "--to", "raw-u32v", "-", "-"], b"10.0.0.1 5\n");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(&result.stdout[..8], ip_heatmap::RAW_MAGIC);
+    let result = run(&["convert", "--from", "raw-u32v", "--to", "text", "-", "-"], &result.stdout);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(result.stdout, b"10.0.0.1 5\n");
+
+    // Text is not raw input
+    let result = run(&["convert", "--from", "raw-u32v", "--to", "text", "-", "-"], b"10.0.0.1 5\n");
+    assert_eq!(result.status.code(), Some(1));
+}
+
+#[test]
+fn test_expanded_braces() {
+    let input = b"10.20.{0-2}.0/24 4\n192.0.2.{1,9},7\n10.{1,2\n";
+    let result = run(&["convert", "--expand-braces", "--to", "text", "-", "-"], input);
+    assert_eq!(result.status.code(), Some(3), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "10.20.0.0/24 4\n10.20.1.0/24 4\n10.20.2.0/24 4\n192.0.2.1 7\n192.0.2.9 7\n"
+    );
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("lines: 3, records: 5, "), "{}", stderr);
+    assert!(stderr.contains("rejected: 1"), "{}", stderr);
+}
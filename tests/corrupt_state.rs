@@ -0,0 +1,54 @@
+//! A damaged state file is read with a warning instead of a panic deep in
+//! processing or rendering.
+
+mod common;
+
+use common::{command, output, scratch_dir};
+use std::path::Path;
+use std::process::Output;
+
+fn run(dir: &Path, args: &[&str], stdin: &str) -> Output {
+    output(command(args).current_dir(dir).env("RUST_BACKTRACE", "0"), stdin)
+}
+
+/// A 2x2 map whose cells hold extreme values and whose touched mask marks every
+/// bit of its one word, 60 of them past the last pixel.
+fn corrupt_state(dir: &Path, name: &str) {
+    let result = run(dir, &["-z", "30", "--value-mode", "raw", "--save-state", name, "map.png"], "10.0.0.1 5\n");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let path = dir.join(name);
+    let mut bytes = std::fs::read(&path).unwrap();
+    assert_eq!(bytes.len(), 56 + 4 * 4 + 8);
+    for (cell, value) in [i32::MAX, i32::MIN, -5, 0].into_iter().enumerate() {
+        bytes[56 + cell * 4..60 + cell * 4].copy_from_slice(&value.to_le_bytes());
+    }
+    bytes[72..].fill(0xff);
+    std::fs::write(&path, bytes).unwrap();
+}
+
+#[test]
+fn test_stray_touched_bits_are_ignored_on_load() {
+    let dir = scratch_dir("load");
+    corrupt_state(&dir, "bad.state");
+    let result = run(&dir, &["compare", "bad.state", "bad.state"], "");
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.contains("marks 60 pixels past the end of the map as painted"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_resumed_corrupt_state_renders() {
+    let dir = scratch_dir("resume");
+    corrupt_state(&dir, "bad.state");
+    let args = ["-z", "30", "--value-mode", "raw", "--state-mmap", "bad.state", "--stats", "--coverage-report"];
+    let result = run(&dir, &[&args[..], &["out.png"]].concat(), "10.0.0.9 1\n");
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.contains("marks 60 pixels past the end of the map as painted"), "{}", stderr);
+    // Only the four pixels of the map count, however many bits the file set
+    assert!(stderr.lines().last().unwrap().starts_with("ipv4-heatmap: lines=2 rejected=0 pixels=4 "), "{}", stderr);
+    assert!(std::fs::read(dir.join("out.png")).unwrap().starts_with(b"\x89PNG"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
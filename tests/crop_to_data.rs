@@ -0,0 +1,67 @@
+//! `--crop-to-data` crops the output to the painted pixels and records where the
+//! crop sits on the whole map.
+
+mod common;
+
+use common::{run_in, scratch_dir};
+use std::path::Path;
+use std::process::Output;
+
+fn run(dir: &Path, args: &[&str], stdin: &str) -> Output {
+    let fixed = ["-z", "16", "--value-mode", "raw", "--min-value", "0", "--max-value", "10"];
+    run_in(dir, &[&fixed, args].concat(), stdin)
+}
+
+/// The dimensions of a PNG and whether its text chunks hold `keyword` set to `text`.
+fn png(dir: &Path, name: &str, keyword: &str, text: &str) -> ((u32, u32), bool) {
+    let bytes = std::fs::read(dir.join(name)).unwrap();
+    let chunk = [keyword.as_bytes(), b"\0", text.as_bytes()].concat();
+    let found = bytes.windows(chunk.len()).any(|window| window == chunk);
+    (image::load_from_memory(&bytes).unwrap().to_rgba8().dimensions(), found)
+}
+
+#[test]
+fn test_crop_to_corners() {
+    let dir = scratch_dir("corners");
+    // On a 256x256 map 0.0.0.0/8 is the top left 16x16 square and 255.0.0.0/8 the top right
+    let cases = [
+        ("0.0.0.1 5\n", "--crop-to-data", "0,0", "1x1"),
+        ("0.0.0.0/8 5\n", "--crop-to-data=4", "0,0", "20x20"),
+        ("255.0.0.0/8 5\n", "--crop-to-data=4", "236,0", "20x20"),
+        ("0.0.0.0/8 5\n255.0.0.0/8 5\n", "--crop-to-data", "0,0", "256x16"),
+        ("128.0.0.0/8 5\n", "--crop-to-data=2", "126,126", "20x20"),
+    ];
+    for (input, flag, origin, size) in cases {
+        let result = run(&dir, &[flag, "map.png"], input);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        let ((width, height), has_origin) = png(&dir, "map.png", "crop-origin", origin);
+        assert_eq!(format!("{}x{}", width, height), size, "{:?}", input);
+        assert!(has_origin, "{:?} should record crop-origin {}", input, origin);
+        assert!(png(&dir, "map.png", "crop-size", size).1);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_title_frames_the_cropped_map() {
+    let dir = scratch_dir("title");
+    let result = run(&dir, &["--crop-to-data", "-t", "scan", "map.png"], "10.0.0.0/8 5\n");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let ((width, _), _) = png(&dir, "map.png", "crop-size", "16x16");
+    // The layout sizes its margins to the cropped map, not the whole one
+    assert!(width < 64, "{}", width);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_nothing_painted_keeps_the_whole_map() {
+    let dir = scratch_dir("empty");
+    let result = run(&dir, &["--crop-to-data", "map.png"], "");
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.contains("Nothing was painted, so --crop-to-data draws the whole map"), "{}", stderr);
+    let ((width, height), has_origin) = png(&dir, "map.png", "crop-origin", "");
+    assert_eq!((width, height), (256, 256));
+    assert!(!has_origin);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,45 @@
+//! `--distinct-approx` estimates the distinct addresses of an input within its error.
+
+mod common;
+
+use common::run;
+use std::collections::HashSet;
+
+/// The number after `"key":` in `json`.
+fn number(json: &str, key: &str) -> f64 {
+    let start = json.find(&format!("\"{}\":", key)).unwrap_or_else(|| panic!("no {} in {}", key, json)) + key.len() + 3;
+    let end = json[start..].find([',', '}']).unwrap() + start;
+    json[start..end].parse().unwrap()
+}
+
+#[test]
+fn test_approximate_matches_exact_count() {
+    // 50,000 lines drawn from 20,000 addresses, most of them repeated
+    let mut input = String::new();
+    let mut exact = HashSet::new();
+    for line in 0u32..50_000 {
+        let address = std::net::Ipv4Addr::from((line.wrapping_mul(2_654_435_761) % 20_000).wrapping_mul(40_503));
+        exact.insert(address);
+        input.push_str(&format!("{} 1\n", address));
+    }
+    let output = std::env::temp_dir().join(format!("ip-heatmap-distinct-{}.png", std::process::id()));
+    let stats_path = std::env::temp_dir().join(format!("ip-heatmap-distinct-{}.json", std::process::id()));
+    for (mode, precision) in [("total", "12"), ("pixels", "8")] {
+        let args = [
+            "-z", "16", "--value-mode", "raw", "--distinct-approx", mode, "--distinct-precision", precision, "--stats",
+            "--stats-json", stats_path.to_str().unwrap(), output.to_str().unwrap(),
+        ];
+        let result = run(&args, &input);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        assert!(String::from_utf8_lossy(&result.stderr).contains("(approximate, +/-"));
+
+        let stats = std::fs::read_to_string(&stats_path).unwrap();
+        assert!(stats.contains(r#""approximate":true"#), "{}", stats);
+        let estimate = number(&stats, "estimate");
+        let relative_error = number(&stats, "relative_error");
+        let error = (estimate - exact.len() as f64).abs() / exact.len() as f64;
+        assert!(error < 3.0 * relative_error, "{}: {} for {}", mode, estimate, exact.len());
+    }
+    let _ = std::fs::remove_file(&output);
+    let _ = std::fs::remove_file(&stats_path);
+}
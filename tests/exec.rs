@@ -0,0 +1,103 @@
+//! `--exec` reads the stdout of a child command, passes its failures on and stops
+//! it when the run ends early.
+#![cfg(unix)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+fn output_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ip-heatmap-exec-{}-{}.png", std::process::id(), name))
+}
+
+fn run(command: &str, args: &[&str], output: &Path) -> Output {
+    let _ = std::fs::remove_file(output);
+    Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+        .args(["-z", "16", "--value-mode", "raw", "--exec", command])
+        .args(args)
+        .arg(output)
+        .stdin(Stdio::null())
+        .output()
+        .expect("binary runs")
+}
+
+fn summary(stderr: &[u8]) -> String {
+    String::from_utf8_lossy(stderr).lines().last().unwrap_or_default().to_string()
+}
+
+#[test]
+fn test_command_output_is_painted() {
+    let output = output_path("success");
+    let result = run("printf '10.0.0.1 5\\n10.1.0.0/16 3\\n'; echo oops >&2", &[], &output);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(0), "{}", stderr);
+    // The command's own stderr passes through
+    assert!(stderr.lines().any(|line| line == "oops"), "{}", stderr);
+    assert_eq!(summary(&result.stderr), format!("ipv4-heatmap: lines=2 rejected=0 pixels=2 output={}", output.display()));
+    assert!(std::fs::read(&output).unwrap().starts_with(b"\x89PNG"));
+    std::fs::remove_file(&output).unwrap();
+}
+
+#[test]
+fn test_failing_command_fails_the_run() {
+    let output = output_path("failure");
+    let result = run("exit 7", &[], &output);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(7), "{}", stderr);
+    assert!(stderr.contains("Command `exit 7` exited with status 7"), "{}", stderr);
+    assert!(!output.exists());
+}
+
+#[test]
+fn test_partial_output_before_a_failure_is_not_rendered() {
+    let output = output_path("partial");
+    let result = run("printf '10.0.0.1\\n10.1.0.1\\n'; echo 'capture truncated' >&2; exit 3", &[], &output);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(3), "{}", stderr);
+    assert!(stderr.contains("capture truncated"), "{}", stderr);
+    assert!(!output.exists(), "a map of a truncated capture was written");
+}
+
+#[test]
+fn test_early_parse_failure_stops_the_command() {
+    let output = output_path("parse");
+    let started = Instant::now();
+    // `yes` never ends on its own, and holds our stderr open until it is stopped
+    let result = run("yes bogus", &["--on-error", "fail"], &output);
+    assert!(started.elapsed() < Duration::from_secs(20));
+    assert_eq!(result.status.code(), Some(1), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(!output.exists());
+}
+
+#[test]
+fn test_interrupt_stops_the_command_and_renders() {
+    let output = output_path("int");
+    let _ = std::fs::remove_file(&output);
+    let child = Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+        .args(["-z", "16", "--value-mode", "raw", "--exec", "printf '10.0.0.1\\n'; exec sleep 60"])
+        .arg(&output)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("binary runs");
+    std::thread::sleep(Duration::from_millis(500));
+    let started = Instant::now();
+    let status = Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    // The sleeping command shares our stderr, so the output only ends once it is stopped
+    let result = child.wait_with_output().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(20), "the command outlived the run");
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(summary(&result.stderr).starts_with("ipv4-heatmap: lines=1 rejected=0 pixels=1 "));
+    assert!(std::fs::read(&output).unwrap().starts_with(b"\x89PNG"));
+    std::fs::remove_file(&output).unwrap();
+}
+
+#[test]
+fn test_exec_needs_text_input() {
+    let output = output_path("raw");
+    let result = run("true", &["--format", "raw-u32v"], &output);
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("--exec reads lines of text"));
+}
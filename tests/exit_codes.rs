@@ -0,0 +1,179 @@
+//! The exit-code contract: 0 on success, 1 on failure, 3 when lines were rejected and
+//! 4 when `--expect-strict` prefixes are missing, with a summary line at the end of stderr.
+
+mod common;
+
+use common::run;
+use std::path::PathBuf;
+use std::process::Output;
+
+const CLEAN: &str = "10.0.0.1\n10.0.0.2\n192.168.0.0/16\n";
+const WITH_REJECTS: &str = "10.0.0.1\nnot an ip\n10.1.0.0/16\n2001:db8::1\n";
+
+fn output_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ip-heatmap-exit-{}-{}.png", std::process::id(), name))
+}
+
+fn summary(output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stderr.lines().last().unwrap_or_default().to_string()
+}
+
+#[test]
+fn test_exit_codes_and_summary() {
+    let path = output_path("map");
+    let path_arg = path.to_str().unwrap();
+    let map_summary = |lines: u64, rejected: u64, pixels: u64| {
+        format!("ipv4-heatmap: lines={} rejected={} pixels={} output={}", lines, rejected, pixels, path_arg)
+    };
+    // (arguments, input, exit code, summary line)
+    let cases = [
+        (vec!["-z", "16", path_arg], CLEAN, 0, map_summary(3, 0, 2)),
+        (vec!["-z", "16", path_arg], WITH_REJECTS, 3, map_summary(4, 2, 2)),
+        (vec!["-z", "16", "--on-error", "skip", path_arg], WITH_REJECTS, 3, map_summary(4, 2, 2)),
+        (
+            vec!["-z", "16", "--on-error", "fail", path_arg],
+            WITH_REJECTS,
+            1,
+            "ipv4-heatmap: lines=2 rejected=1 pixels=1 output=-".to_string(),
+        ),
+        (
+            vec!["-z", "16", "--validate"],
+            WITH_REJECTS,
+            3,
+            "ipv4-heatmap: lines=4 rejected=2 pixels=2 output=-".to_string(),
+        ),
+        (
+            vec!["-z", "16", "--validate", "--validate-max-reject-pct", "10"],
+            WITH_REJECTS,
+            1,
+            "ipv4-heatmap: lines=4 rejected=2 pixels=2 output=-".to_string(),
+        ),
+        (
+            vec!["-z", "16", "/nonexistent-dir/map.png"],
+            CLEAN,
+            1,
+            "ipv4-heatmap: lines=3 rejected=0 pixels=2 output=-".to_string(),
+        ),
+    ];
+    for (args, input, code, expected) in &cases {
+        let output = run(args, input);
+        assert_eq!(output.status.code(), Some(*code), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        assert_eq!(summary(&output), *expected, "{:?}", args);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_failures_are_reported_before_the_summary() {
+    let output = run(&["-z", "16", "--on-error", "fail", "unused.png"], WITH_REJECTS);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Error: "), "{}", stderr);
+    assert!(stderr.trim_end().ends_with("output=-"), "{}", stderr);
+}
+
+#[test]
+fn test_conflict_policies() {
+    let path = output_path("conflicts");
+    let path_arg = path.to_str().unwrap();
+    let input = "10.0.0.1 5\n10.0.0.2 3\n10.1.0.1 4\n";
+    let conflicts = |policy: &str| run(&["-z", "16", "--value-mode", "raw", "--on-conflict", policy, path_arg], input);
+
+    let warn = conflicts("warn");
+    let stderr = String::from_utf8_lossy(&warn.stderr);
+    assert_eq!(warn.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.contains("1 paints overwrote a different value"), "{}", stderr);
+    assert!(stderr.contains("line 2: 10.0.0.2 3 (painted 3 over 5)"), "{}", stderr);
+    let summary_line = format!("ipv4-heatmap: lines=3 rejected=0 pixels=2 conflicts=1 output={}", path_arg);
+    assert_eq!(summary(&warn), summary_line);
+
+    for policy in ["accumulate", "keep-max"] {
+        let output = conflicts(policy);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(0), "{}: {}", policy, stderr);
+        assert!(!stderr.contains("painted over"), "{}: {}", policy, stderr);
+        assert_eq!(summary(&output), summary_line, "{}", policy);
+    }
+
+    let error = conflicts("error");
+    let stderr = String::from_utf8_lossy(&error.stderr);
+    assert_eq!(error.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("Line 2 paints 3 over 5"), "{}", stderr);
+    assert_eq!(summary(&error), "ipv4-heatmap: lines=2 rejected=0 pixels=1 conflicts=1 output=-");
+
+    let accumulated = run(&["-z", "16", "--value-mode", "raw", "-C", "--on-conflict", "error", path_arg], input);
+    assert_eq!(accumulated.status.code(), Some(0), "{}", String::from_utf8_lossy(&accumulated.stderr));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_expectations() {
+    let path = output_path("expect");
+    let expected = std::env::temp_dir().join(format!("ip-heatmap-exit-{}-expected.txt", std::process::id()));
+    let report = std::env::temp_dir().join(format!("ip-heatmap-exit-{}-report.txt", std::process::id()));
+    std::fs::write(&expected, "# anycast\n10.0.0.0/16\n10.0.0.0/15 20\n192.168.0.0/16\n172.16.0.0/12\n").unwrap();
+    let (path_arg, expected_arg, report_arg) = (path.to_str().unwrap(), expected.to_str().unwrap(), report.to_str().unwrap());
+    let input = "10.0.0.1 5\n10.0.0.2 3\n192.168.1.1 1\n";
+    let args = ["-z", "16", "-C", "--value-mode", "raw", "--expect", expected_arg, "--expect-report", report_arg, path_arg];
+
+    let lenient = run(&args, input);
+    let stderr = String::from_utf8_lossy(&lenient.stderr);
+    assert_eq!(lenient.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.contains("2 of 4 expected prefixes are below their threshold: 10.0.0.0/15 (low), 172.16.0.0/12 (absent)"));
+    let summary_line = format!("ipv4-heatmap: lines=3 rejected=0 pixels=2 unmet=2 output={}", path_arg);
+    assert_eq!(summary(&lenient), summary_line);
+    let lines: Vec<String> = std::fs::read_to_string(&report).unwrap().lines().map(str::to_string).collect();
+    assert_eq!(lines[0], "# 2 of 4 expected prefixes are below their threshold");
+    assert_eq!(lines[2..], ["10.0.0.0/15\tlow\t8\t20\t1/2", "172.16.0.0/12\tabsent\t0\t1\t0/16"]);
+
+    let strict = run(&[&args[..], &["--expect-strict"]].concat(), input);
+    assert_eq!(strict.status.code(), Some(4), "{}", String::from_utf8_lossy(&strict.stderr));
+    assert_eq!(summary(&strict), summary_line);
+    // Unmet expectations outrank rejected lines
+    let rejected = run(&[&args[..], &["--expect-strict"]].concat(), format!("{}not an ip\n", input));
+    assert_eq!(rejected.status.code(), Some(4));
+
+    let met = run(&[&args[..], &["--expect-strict", "--expect-threshold", "0"]].concat(), format!("{}172.16.0.1 0\n10.1.0.1 15\n", input));
+    assert_eq!(met.status.code(), Some(0), "{}", String::from_utf8_lossy(&met.stderr));
+    assert_eq!(std::fs::read_to_string(&report).unwrap().lines().count(), 2);
+
+    std::fs::write(&expected, "10.0.0.0/16 lots\n").unwrap();
+    let invalid = run(&args, input);
+    assert_eq!(invalid.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&invalid.stderr).contains("Line 1: invalid threshold: lots"));
+    for file in [&path, &expected, &report] {
+        std::fs::remove_file(file).unwrap();
+    }
+}
+
+#[test]
+fn test_min_max_are_checked_before_reading_input() {
+    let path = output_path("bounds");
+    let path_arg = path.to_str().unwrap();
+    // The input does not exist, so only an early check can be what fails
+    let missing = ["--input", "/nonexistent/ip-heatmap-input.txt"];
+    let run_bounds = |bounds: &[&str]| run(&[&["-z", "16", path_arg], &missing[..], bounds].concat(), "");
+    let cases: [(&[&str], &str); 4] = [
+        (
+            &["--min-value", "1000", "--max-value", "10"],
+            "--max-value 10 is less than --min-value 1000. Swap them, or pass --swap-min-max",
+        ),
+        (&["--min-value", "5", "--max-value", "5"], "--max-value 5 equals --min-value 5"),
+        (&["--max-value", "inf"], "--max-value must be a finite number: inf"),
+        (&["--curve", "log", "--log-offset", "1", "--min-value=-2"], "--min-value -2 plus --log-offset 1"),
+    ];
+    for (bounds, message) in cases {
+        let output = run_bounds(bounds);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(stderr.contains(message) && !stderr.contains("Failed to open input"), "{:?}: {}", bounds, stderr);
+    }
+
+    // Swapped bounds render as intended with --swap-min-max, after a warning
+    let swap = ["--min-value", "10", "--max-value", "1", "--swap-min-max"];
+    let swapped = run(&[&["-z", "16", "--value-mode", "raw", path_arg], &swap[..]].concat(), "10.0.0.1 5\n");
+    let stderr = String::from_utf8_lossy(&swapped.stderr);
+    assert!(swapped.status.success(), "{}", stderr);
+    assert!(stderr.contains("--min-value 10 is above --max-value 1; swapping them"), "{}", stderr);
+    let _ = std::fs::remove_file(&path);
+}
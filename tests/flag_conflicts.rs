@@ -0,0 +1,116 @@
+//! Flag combinations that cannot work together fail before any input is read, all
+//! reported at once, with a message saying what to use instead.
+
+mod common;
+
+use common::{run_in, scratch_dir};
+use std::path::Path;
+use std::process::Output;
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    run_in(dir, args, "10.0.0.1 5\n")
+}
+
+/// Fail with `args`, which name files that do not exist, before opening any of them.
+fn fails_early(dir: &Path, args: &[&str]) -> String {
+    let result = run(dir, &[args, &["map.png"]].concat());
+    let stderr = String::from_utf8_lossy(&result.stderr).to_string();
+    assert_eq!(result.status.code(), Some(1), "{:?}: {}", args, stderr);
+    assert!(!stderr.contains("Failed") && !stderr.contains("No such file"), "{:?}: {}", args, stderr);
+    assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0, "{:?} wrote files", args);
+    stderr
+}
+
+#[test]
+fn test_every_declared_conflict() {
+    let dir = scratch_dir("each");
+    let cases: [(&[&str], &str); 16] = [
+        (&["--category-colours", "colours.txt"], "--category-colours colours categories, so it needs --value-mode categorical"),
+        (&["--value-mode", "categorical", "-C"], "Categorical values are labels, so they cannot accumulate; drop -C"),
+        (&["--value-mode", "categorical", "--on-conflict", "accumulate"], "Categorical values cannot be accumulated"),
+        (&["--value-mode", "categorical", "--expect", "expect.txt"], "--expect thresholds do not apply"),
+        (&["--value-mode", "categorical", "--distinct"], "--preaggregate and --distinct cannot sum or count them"),
+        (&["--value-mode", "categorical", "--distinct-approx", "pixels"], "use --distinct-approx total"),
+        (&["--value-mode", "categorical", "--value-from", "count"], "use --value-mode raw to colour --value-from count"),
+        (&["--value-from", "count", "--ignore-value", "-"], "which --value-from count does not read"),
+        (&["--value-from", "count", "--output-size", "64", "--downsample", "mean"], "--downsample sum or max"),
+        (&["--value-from", "prefix-len", "--weight", "2"], "Weights do not apply to categorical values or prefix lengths"),
+        (&["--format", "cells", "--value-from", "count"], "--value-from does not apply to --format cells"),
+        (&["--format", "raw-u32v", "--exec", "true"], "--exec reads lines of text, use --format text or cells"),
+        (&["--format", "raw-u32v", "--since", "0"], "--since and --until need timestamps, which only --format text input has"),
+        (&["--curve", "linear", "--log-base", "10"], "--log-base and --log-offset shape the log curve"),
+        (&["--log-offset", "1"], "--log-base and --log-offset shape the log curve"),
+        (&["--validate", "-z", "8", "-z", "16"], "--validate takes a single -z"),
+    ];
+    for (args, message) in cases {
+        let stderr = fails_early(&dir, args);
+        assert!(stderr.contains(message), "{:?}: {}", args, stderr);
+        assert!(!stderr.contains("conflicting options"), "{:?}: {}", args, stderr);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_all_conflicts_are_reported_at_once() {
+    let dir = scratch_dir("all");
+    let stderr = fails_early(&dir, &["--value-mode", "categorical", "-C", "--weight", "2", "--format", "cells", "--value-from", "count"]);
+    assert!(stderr.contains("Error: 4 conflicting options:\n  Categorical values are labels, so they cannot accumulate"), "{}", stderr);
+    for message in ["Counts are not category labels", "Weights do not apply", "does not apply to --format cells"] {
+        assert_eq!(stderr.matches(message).count(), 1, "{}: {}", message, stderr);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_flags_needing_a_single_resolution() {
+    let dir = scratch_dir("resolutions");
+    let args = [
+        "-z", "8", "-z", "16", "--render", "other.png", "--thumbnail", "thumb.png:64", "--output-size", "64",
+        "--annotation-layer", "layer.png", "--save-state", "state.bin", "--histogram", "histogram.png",
+        "--slash8-chart", "slash8.png", "--export-prefixes", "prefixes.txt", "--export-cells", "cells.csv",
+        "--expect", "expect.txt", "--export-profile", "profile.csv", "--floor", "1", "--invert", "--rank", "10.0.0.0/8",
+        "--preview", "preview.png:64:1", "--distinct",
+    ];
+    let stderr = fails_early(&dir, &args);
+    let flags = [
+        "--render", "--thumbnail", "--output-size", "--annotation-layer", "--save-state", "--histogram", "--slash8-chart",
+        "--export-prefixes", "--export-cells", "--expect", "--export-profile", "--floor/--ceiling", "--invert", "--rank",
+        "--preview", "--preaggregate/--distinct",
+    ];
+    assert!(stderr.contains(&format!("Error: {} conflicting options:\n", flags.len())), "{}", stderr);
+    for flag in flags {
+        assert!(stderr.contains(&format!("\n  {} cannot be combined with more than one -z", flag)), "{}: {}", flag, stderr);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_downsampled_sizes_are_checked_before_input() {
+    let dir = scratch_dir("sizes");
+    for (args, message) in [
+        (&["--thumbnail", "thumb.png:512"][..], "Invalid --thumbnail thumb.png:512"),
+        (&["--thumbnail", "thumb.png:100"], "Invalid --thumbnail thumb.png:100"),
+        (&["--output-size", "512"], "Invalid --output-size"),
+    ] {
+        let stderr = fails_early(&dir, &[&["-z", "16", "--input", "input.txt"], args].concat());
+        assert!(stderr.contains(message), "{:?}: {}", args, stderr);
+        assert!(stderr.contains("power of two up to 256 at bits_per_pixel 16"), "{:?}: {}", args, stderr);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_reports_needing_a_single_resolution() {
+    let dir = scratch_dir("reports");
+    for (args, flag) in [
+        (&["--stats-json", "stats.json"][..], "--stats-json"),
+        (&["--rejects", "rejects.txt"], "--rejects"),
+        (&["--coverage-report"], "--coverage-report"),
+        (&["--sanity-check"], "--sanity-check"),
+        (&["--timing"], "--timing"),
+    ] {
+        let stderr = fails_early(&dir, &[&["-z", "16", "-z", "20", "--input", "input.txt"], args].concat());
+        assert!(stderr.contains(&format!("{} cannot be combined with more than one -z", flag)), "{:?}: {}", args, stderr);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
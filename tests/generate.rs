@@ -0,0 +1,45 @@
+//! `ip-heatmap generate` writes the same synthetic input for the same options, which
+//! every other subcommand reads without rejects.
+
+mod common;
+
+use common::scratch_dir;
+use std::process::Output;
+
+fn run(args: &[&str]) -> Output {
+    common::run(args, "")
+}
+
+#[test]
+fn test_generate_is_reproducible() {
+    let result = run(&["generate", "--lines", "5", "--seed", "42"]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(
+        String::from_utf8(result.stdout).unwrap(),
+        "178.102.247.241 30\n14.74.226.113 56\n74.240.103.117 49\n228.198.143.91 70\n71.237.108.0 53\n"
+    );
+    let dir = scratch_dir("file");
+    let path = dir.join("fixture.txt");
+    let to_file = run(&["generate", path.to_str().unwrap(), "--lines", "2k", "--hotspots", "3", "--spread", "0.5"]);
+    assert!(to_file.status.success() && to_file.stdout.is_empty());
+    let to_stdout = run(&["generate", "--lines", "2000", "--hotspots", "3", "--spread", "0.5"]);
+    assert_eq!(std::fs::read(&path).unwrap(), to_stdout.stdout);
+    let summary = run(&["summarize", path.to_str().unwrap()]);
+    let report = String::from_utf8_lossy(&summary.stdout);
+    assert_eq!(summary.status.code(), Some(0), "{}", report);
+    assert!(report.contains("2000"), "{}", report);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_invalid_options_are_refused() {
+    for (args, message) in [
+        (&["generate", "--spread", "1.5"][..], "Spread must be a share of lines from 0 to 1, got 1.5"),
+        (&["generate", "--lines", "lots"][..], "Invalid line count: lots"),
+    ] {
+        let result = run(args);
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert_eq!(result.status.code(), Some(2), "{:?}: {}", args, stderr);
+        assert!(stderr.contains(message), "{:?}: {}", args, stderr);
+    }
+}
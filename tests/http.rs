@@ -0,0 +1,90 @@
+//! `--input` reads `http://` URLs with the `http` feature, and names the feature
+//! without it.
+
+mod common;
+
+use std::process::Output;
+
+fn run(args: &[&str]) -> Output {
+    common::run(args, "")
+}
+
+/// Answer `connections` requests in turn with the status line and headers of the
+/// fixture for their path and its body, or 404, and return the base URL.
+#[cfg(feature = "http")]
+fn server(fixtures: Vec<(&'static str, &'static str, Vec<u8>)>, connections: usize) -> String {
+    use std::io::{BufRead, BufReader, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming().take(connections) {
+            let mut stream = stream.unwrap();
+            let mut request_line = String::new();
+            let mut reader = BufReader::new(&stream);
+            reader.read_line(&mut request_line).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let path = request_line.split(' ').nth(1).unwrap_or_default().to_string();
+            let response = match fixtures.iter().find(|(fixture, _, _)| *fixture == path) {
+                Some((_, head, body)) => {
+                    [format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n", head, body.len()).as_bytes(), body].concat()
+                }
+                None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            };
+            stream.write_all(&response).unwrap();
+        }
+    });
+    url
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn test_plain_gzip_and_redirected_inputs() {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"11.1.0.0/16 2\nnot-an-address\n").unwrap();
+    let url = server(
+        vec![
+            ("/old.txt", "301 Moved Permanently\r\nLocation: /plain.txt", Vec::new()),
+            ("/plain.txt", "200 OK", b"10.0.0.1 5\n".to_vec()),
+            ("/dnsbl.txt.gz", "200 OK", encoder.finish().unwrap()),
+        ],
+        4,
+    );
+    let plain = format!("{}/old.txt", url);
+    let gzipped = format!("{}/dnsbl.txt.gz:3", url);
+    let dir = std::env::temp_dir().join(format!("ip-heatmap-http-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("map.png");
+    let output =
+        run(&["-z", "20", "--value-mode", "raw", "--input", &plain, "--input", &gzipped, out.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // The rejected line makes the exit code 3
+    assert_eq!(output.status.code(), Some(3), "{}", stderr);
+    assert!(stderr.contains("lines=3 rejected=1 pixels=2"), "{}", stderr);
+    assert!(out.exists());
+
+    let missing = run(&["-z", "20", "--input", &format!("{}/gone.txt", url), out.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&missing.stderr);
+    assert_eq!(missing.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("HTTP 404 Not Found"), "{}", stderr);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn test_https_needs_tls() {
+    let output = run(&["-z", "20", "--input", "https://example.org/dnsbl.txt.gz", "-"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("https needs TLS"));
+}
+
+#[cfg(not(feature = "http"))]
+#[test]
+fn test_urls_need_the_feature() {
+    let output = run(&["-z", "20", "--input", "http://127.0.0.1:9/dnsbl.txt", "-"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("need the http cargo feature"));
+}
@@ -0,0 +1,36 @@
+//! Reverse-DNS names from a passive DNS export are plotted as the addresses and
+//! zones they name.
+
+use std::fs::File;
+use std::process::{Command, Output};
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pdns-ptr.txt");
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+        .args(args)
+        .stdin(File::open(FIXTURE).unwrap())
+        .output()
+        .expect("binary runs")
+}
+
+fn summary(output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stderr.lines().last().unwrap_or_default().to_string()
+}
+
+#[test]
+fn test_passive_dns_export_is_plotted() {
+    let validated = run(&["-z", "24", "--value-mode", "raw", "--validate"]);
+    let stdout = String::from_utf8_lossy(&validated.stdout);
+    // The RFC 2317 delegation label 0/26 is not an octet
+    assert_eq!(validated.status.code(), Some(3), "{}", stdout);
+    assert!(stdout.contains("line 7: invalid in-addr.arpa name: label '0/26' is not a decimal number"), "{}", stdout);
+    // 192.0.2.0/24, 198.51.100.0/24 and 203.0.113.0/24, the zone and its host sharing a pixel
+    assert_eq!(summary(&validated), "ipv4-heatmap: lines=7 rejected=1 pixels=3 output=-");
+
+    let path = std::env::temp_dir().join(format!("ip-heatmap-in-addr-{}.png", std::process::id()));
+    let failed = run(&["-z", "24", "--value-mode", "raw", "--on-error", "fail", path.to_str().unwrap()]);
+    assert_eq!(failed.status.code(), Some(1), "{}", String::from_utf8_lossy(&failed.stderr));
+    assert!(!path.exists());
+}
@@ -0,0 +1,81 @@
+//! `inspect` describes the files ip-heatmap writes and says what is wrong with damaged ones.
+
+mod common;
+
+use common::{command, output, scratch_dir};
+use std::process::Output;
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    output(command(args).env("RUST_BACKTRACE", "0"), stdin)
+}
+
+fn inspect(path: &str) -> (Option<i32>, String, String) {
+    let result = run(&["inspect", "--json", path], "");
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    (result.status.code(), stdout, String::from_utf8_lossy(&result.stderr).into_owned())
+}
+
+/// A copy of `path` cut to `len` bytes.
+fn truncated(path: &str, len: usize) -> String {
+    let bytes = std::fs::read(path).unwrap();
+    let copy = format!("{}.truncated", path);
+    std::fs::write(&copy, &bytes[..len]).unwrap();
+    copy
+}
+
+#[test]
+fn test_rendered_files() {
+    let dir = scratch_dir("rendered");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let (png, state) = (path("map.png"), path("run.state"));
+    let result = run(
+        &["-z", "16", "-C", "--value-mode", "raw", "--curve", "log", "--save-state", &state, &png],
+        "10.0.0.1 5\n10.1.0.0/16 3\n",
+    );
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+
+    let (code, json, stderr) = inspect(&png);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert!(json.starts_with(r#"{"type":"png","width":256,"height":256,"bit_depth":8,"colour_type":"rgba","text":{"#), "{}", json);
+    assert!(json.contains(r#""curve":"log""#), "{}", json);
+    assert!(json.contains(r#""bits_per_pixel":"16""#), "{}", json);
+
+    let (code, json, stderr) = inspect(&state);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert_eq!(
+        json.trim(),
+        r#"{"type":"state","version":1,"bits_per_pixel":16,"value_mode":"raw","accumulate":true,"lines":2,"nonzero_cells":2,"min_value":0,"max_value":5,"total":8,"touched_pixels":2}"#
+    );
+
+    let (code, _, stderr) = inspect(&truncated(&png, 200));
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains("Truncated PNG: chunk"), "{}", stderr);
+    let (code, _, stderr) = inspect(&truncated(&state, 30));
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains("Truncated state file header (30 of 56 bytes)"), "{}", stderr);
+    let (code, _, stderr) = inspect(&truncated(&state, 4096));
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains("Truncated state file: 4096 of"), "{}", stderr);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_other_files() {
+    // The golden images are written without parameters
+    let golden = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/linear.png");
+    let result = run(&["inspect", golden], "");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let text = String::from_utf8(result.stdout).unwrap();
+    assert!(text.contains("no parameters"), "{}", text);
+
+    let dir = scratch_dir("other");
+    let input = dir.join("input.txt").to_str().unwrap().to_string();
+    std::fs::write(&input, "10.0.0.1 5\n").unwrap();
+    let (code, _, stderr) = inspect(&input);
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains("Not a PNG image, state file or raw-u32v file (starts with 31 30"), "{}", stderr);
+    let (code, _, stderr) = inspect(dir.join("missing").to_str().unwrap());
+    assert_eq!(code, Some(1));
+    assert!(stderr.contains("Failed to open"), "{}", stderr);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
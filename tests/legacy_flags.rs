@@ -0,0 +1,75 @@
+//! Invocations written for the original C ipv4-heatmap keep working, or fail with an
+//! explanation of what to use instead.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+const INPUT: &str = "10.0.0.1\n10.0.0.2\n10.1.0.0/16\n192.168.1.1\n";
+
+/// Name, arguments before the output and the expected image dimensions, if fixed.
+type Case = (&'static str, &'static [&'static str], Option<(u32, u32)>);
+
+fn output_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ip-heatmap-legacy-{}-{}.png", std::process::id(), name))
+}
+
+fn run(args: &[&str]) -> Output {
+    common::run(args, INPUT)
+}
+
+fn dimensions(path: &Path) -> (u32, u32) {
+    let image = image::open(path).expect("output is a PNG");
+    (image.width(), image.height())
+}
+
+#[test]
+fn test_supported_legacy_flags() {
+    let cases: &[Case] = &[
+        ("bits", &["-z", "16"], Some((256, 256))),
+        ("log", &["-z", "16", "-A", "1", "-B", "100"], Some((256, 256))),
+        ("accumulate", &["-z", "16", "-C"], Some((256, 256))),
+        ("debug", &["-z", "16", "-d"], Some((256, 256))),
+        ("crop", &["-z", "16", "-y", "10.0.0.0/8"], Some((16, 16))),
+        ("title", &["-z", "16", "-t", "scan results"], None),
+        ("legend", &["-z", "16", "-u", "hosts"], None),
+        ("all", &["-z", "16", "-C", "-t", "scan", "-u", "hosts", "-y", "10.0.0.0/8"], None),
+    ];
+    for (name, args, expected) in cases {
+        let path = output_path(name);
+        let path_arg = path.to_str().unwrap();
+        let mut full: Vec<&str> = args.to_vec();
+        full.extend(["-o", path_arg]);
+        let output = run(&full);
+        assert!(output.status.success(), "{}: {}", name, String::from_utf8_lossy(&output.stderr));
+        let (width, height) = dimensions(&path);
+        match expected {
+            Some(expected) => assert_eq!((width, height), *expected, "{}", name),
+            // Titles and legends extend the canvas below and around the map
+            None => assert!(height > width, "{}: {}x{}", name, width, height),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn test_unsupported_legacy_flags_explain_themselves() {
+    let cases: &[(&[&str], &str)] = &[
+        (&["-a", "annotations.txt"], "-a (annotation files) from ipv4-heatmap is not implemented; use --outline PREFIX"),
+        (&["-s", "shading.txt"], "-s (shading files) from ipv4-heatmap is not implemented; use --shade PREFIX:RRGGBB"),
+        (&["-f", "Luxi Mono"], "built-in bitmap font"),
+        (&["-p", "prefixes.txt"], "use --crop"),
+        (&["-m"], "only the Hilbert curve"),
+    ];
+    for (args, message) in cases {
+        let path = output_path("unsupported");
+        let mut full: Vec<&str> = args.to_vec();
+        full.push(path.to_str().unwrap());
+        let output = run(&full);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{:?} should fail", args);
+        assert!(stderr.contains(message), "{:?}: {}", args, stderr);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,139 @@
+//! Stdin is read and parsed ahead of painting through bounded queues, which hold back
+//! or drop a producer that outruns painting and never keep the run from ending.
+#![cfg(unix)]
+
+mod common;
+
+use common::scratch_dir;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+fn spawn(dir: &Path, args: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+        .args(["-z", "12", "--min-value", "0", "--max-value", "10", "--timing", "--stats-json", "stats.json"])
+        .args(args)
+        .arg("map.png")
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("binary runs")
+}
+
+/// Write `lines` lines to the child as fast as the pipe takes them, or until the pipe
+/// closes when `lines` is `None`, on a thread of its own.
+fn produce(child: &mut Child, lines: Option<usize>) -> std::thread::JoinHandle<usize> {
+    let mut stdin = child.stdin.take().unwrap();
+    std::thread::spawn(move || {
+        let block: String = (0..1024).map(|line| format!("10.{}.{}.0/24 1\n", line % 256, line / 256)).collect();
+        let mut written = 0;
+        while lines.is_none_or(|lines| written < lines) {
+            if stdin.write_all(block.as_bytes()).is_err() {
+                break;
+            }
+            written += 1024;
+        }
+        written
+    })
+}
+
+/// The number after `"key":` in the first place it appears after `within`.
+fn number(json: &str, within: &str, key: &str) -> u64 {
+    let start = json.find(within).unwrap_or_else(|| panic!("no {} in {}", within, json));
+    let rest = &json[start..];
+    let at = rest.find(&format!("\"{}\":", key)).unwrap() + key.len() + 3;
+    rest[at..].split(|c: char| !c.is_ascii_digit()).next().unwrap().parse().unwrap()
+}
+
+fn wait(child: Child, within: Duration) -> std::process::Output {
+    let deadline = Instant::now() + within;
+    let id = child.id();
+    let waiter = std::thread::spawn(move || child.wait_with_output().unwrap());
+    while !waiter.is_finished() {
+        assert!(Instant::now() < deadline, "process {} did not exit", id);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    waiter.join().unwrap()
+}
+
+#[test]
+fn test_fast_producer_is_painted_in_full() {
+    let dir = scratch_dir("block");
+    let mut child = spawn(&dir, &["--live-queue-size", "1"]);
+    let producer = produce(&mut child, Some(200 * 1024));
+    let written = producer.join().unwrap();
+    let result = wait(child, Duration::from_secs(60));
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let stats = std::fs::read_to_string(dir.join("stats.json")).unwrap();
+    assert_eq!(number(&stats, "", "lines"), written as u64);
+    assert_eq!(number(&stats, "\"live_queues\"", "capacity"), 1);
+    assert_eq!(number(&stats, "\"parsed\"", "capacity"), 1);
+    assert_eq!(number(&stats, "\"live_queues\"", "dropped_lines"), 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_timing_includes_reading_and_parsing() {
+    let dir = scratch_dir("timing");
+    let mut child = spawn(&dir, &[]);
+    produce(&mut child, Some(50 * 1024)).join().unwrap();
+    let result = wait(child, Duration::from_secs(60));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    // Done on the parser thread, and still in the table printed at the end
+    for phase in ["read", "parse"] {
+        let row = stderr.lines().find(|line| line.split_whitespace().next() == Some(phase)).unwrap_or_else(|| panic!("{}", stderr));
+        let ms: f64 = row.split_whitespace().nth(1).unwrap().parse().unwrap();
+        assert!(ms > 0.0, "{}", row);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_dropped_lines_are_accounted_for() {
+    let dir = scratch_dir("drop");
+    let mut child = spawn(&dir, &["--live-queue-size", "1", "--live-queue-full", "drop"]);
+    let written = produce(&mut child, Some(200 * 1024)).join().unwrap();
+    let result = wait(child, Duration::from_secs(60));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    let stats = std::fs::read_to_string(dir.join("stats.json")).unwrap();
+    let dropped = number(&stats, "\"live_queues\"", "dropped_lines");
+    assert_eq!(number(&stats, "", "lines") + dropped, written as u64);
+    assert_eq!(dropped > 0, stderr.contains(&format!("Dropped {} lines while painting fell behind", dropped)), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_term_ends_a_run_with_an_endless_producer() {
+    for policy in ["block", "drop"] {
+        let dir = scratch_dir(policy);
+        let mut child = spawn(&dir, &["--live-queue-size", "2", "--live-queue-full", policy]);
+        let producer = produce(&mut child, None);
+        std::thread::sleep(Duration::from_millis(500));
+        let status = Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+        assert!(status.success());
+        let result = wait(child, Duration::from_secs(30));
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        // The producer sees the pipe close once the run has ended
+        let written = producer.join().unwrap();
+        let stats = std::fs::read_to_string(dir.join("stats.json")).unwrap();
+        assert!(number(&stats, "", "lines") <= written as u64);
+        assert_eq!(number(&stats, "\"read\"", "capacity"), 2);
+        assert!(image::open(dir.join("map.png")).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
+
+#[test]
+fn test_queue_size_must_be_positive() {
+    let dir = scratch_dir("zero");
+    let mut child = spawn(&dir, &["--live-queue-size", "0"]);
+    drop(child.stdin.take());
+    let result = wait(child, Duration::from_secs(30));
+    assert_eq!(result.status.code(), Some(2));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,136 @@
+//! `--log-format json` writes one JSON object per diagnostic, with its fields as members.
+
+mod common;
+
+use common::{command, output};
+use std::collections::HashMap;
+use std::process::Output;
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    output(command(args).env_remove("RUST_LOG"), stdin)
+}
+
+#[derive(Debug, PartialEq)]
+enum Member {
+    String(String),
+    Number(f64),
+}
+
+/// Parse a flat JSON object of strings and numbers, the shape every log event has.
+fn parse_event(line: &str) -> HashMap<String, Member> {
+    let mut chars = line.chars().peekable();
+    let mut members = HashMap::new();
+    assert_eq!(chars.next(), Some('{'), "{}", line);
+    let string = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        assert_eq!(chars.next(), Some('"'), "{}", line);
+        let mut value = String::new();
+        loop {
+            match chars.next().expect("unterminated string") {
+                '"' => return value,
+                '\\' => match chars.next().unwrap() {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        value.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                    }
+                    escaped => value.push(escaped),
+                },
+                c => value.push(c),
+            }
+        }
+    };
+    loop {
+        let key = string(&mut chars);
+        assert_eq!(chars.next(), Some(':'), "{}", line);
+        let value = match chars.peek() {
+            Some('"') => Member::String(string(&mut chars)),
+            _ => {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| !matches!(c, ',' | '}')) {
+                    number.push(c);
+                }
+                Member::Number(number.parse().unwrap_or_else(|_| panic!("not a number in {}", line)))
+            }
+        };
+        members.insert(key, value);
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => break,
+            other => panic!("unexpected {:?} in {}", other, line),
+        }
+    }
+    assert_eq!(chars.next(), None, "{}", line);
+    members
+}
+
+fn text(value: &str) -> Member {
+    Member::String(value.to_string())
+}
+
+#[test]
+fn test_rejected_lines_are_json_events() {
+    let dir = std::env::temp_dir().join(format!("ip-heatmap-log-format-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let (input, output) = (dir.join("input.txt"), dir.join("map.png"));
+    let mut lines = String::from("10.0.0.1 5\n10.0.0.0/40 2\n");
+    for line in 0..20 {
+        lines.push_str(&format!("host-{}.example 1\n", line));
+    }
+    std::fs::write(&input, lines).unwrap();
+    let result = run(
+        &[
+            "--log-format",
+            "json",
+            "-z",
+            "16",
+            "--value-mode",
+            "raw",
+            "--input",
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+        ],
+        "",
+    );
+    assert_eq!(result.status.code(), Some(3));
+
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    let (events, plain): (Vec<&str>, Vec<&str>) = stderr.lines().partition(|line| line.starts_with('{'));
+    // Only the run summary stays a plain line
+    assert_eq!(plain.len(), 1, "{}", stderr);
+    assert!(plain[0].starts_with("ipv4-heatmap: lines=22 rejected=21"), "{}", stderr);
+    let events: Vec<_> = events.into_iter().map(parse_event).collect();
+
+    let cidr = &events[0];
+    assert_eq!(cidr["level"], text("WARN"));
+    assert_eq!(cidr["line_number"], Member::Number(2.0));
+    assert_eq!(cidr["reason"], text("invalid CIDR"));
+    assert_eq!(cidr["file"], text(input.to_str().unwrap()));
+    assert!(matches!(&cidr["msg"], Member::String(msg) if msg.starts_with("Failed to parse line 2: invalid CIDR")));
+    assert!(matches!(&cidr["ts"], Member::String(ts) if ts.len() == 24 && ts.ends_with('Z')));
+
+    // The same rate limiting applies as to text output
+    let invalid_ip = events.iter().filter(|event| event.get("reason") == Some(&text("invalid IP address")));
+    let line_numbers: Vec<_> = invalid_ip.filter_map(|event| event.get("line_number")).collect();
+    assert_eq!(line_numbers.len(), 10, "{}", stderr);
+    assert_eq!(line_numbers[0], &Member::Number(3.0));
+    let suppressed = events.iter().find_map(|event| event.get("suppressed"));
+    assert_eq!(suppressed, Some(&Member::Number(10.0)), "{}", stderr);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_stdin_events_have_no_file() {
+    let output = std::env::temp_dir().join(format!("ip-heatmap-log-format-stdin-{}.png", std::process::id()));
+    let result = run(
+        &["--log-format", "json", "-z", "16", "--value-mode", "raw", output.to_str().unwrap()],
+        "10.0.0.1 5\nbad 1\n",
+    );
+    assert_eq!(result.status.code(), Some(3));
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    let event = parse_event(stderr.lines().next().unwrap());
+    assert_eq!(event["line_number"], Member::Number(2.0));
+    assert!(!event.contains_key("file"), "{}", stderr);
+    let _ = std::fs::remove_file(&output);
+}
@@ -0,0 +1,71 @@
+//! Streamed renders decode to the same pixels and parameters as whole-image ones.
+
+mod common;
+
+use common::{run, scratch_dir};
+
+const INPUT: &str = "0.0.0.0/2 5\n10.0.0.1 50\n10.1.0.0/16 3\n172.16.0.0/12 40\n8.8.8.8 2\n";
+
+#[test]
+fn test_low_memory_render_matches() {
+    let dir = scratch_dir("match");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let cases: [&[&str]; 3] = [
+        &["-z", "16", "--value-mode", "raw", "--curve", "log"],
+        &["-z", "14", "-C", "--value-mode", "raw", "--gamma", "0.5", "--output-size", "128"],
+        &["-z", "16", "--value-mode", "categorical"],
+    ];
+    for (index, args) in cases.into_iter().enumerate() {
+        let (whole, streamed) = (path(&format!("whole-{}.png", index)), path(&format!("streamed-{}.png", index)));
+        let mut whole_args = args.to_vec();
+        whole_args.push(&whole);
+        let result = run(&whole_args, INPUT);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        let mut streamed_args = args.to_vec();
+        streamed_args.extend(["--low-memory", &streamed]);
+        let result = run(&streamed_args, INPUT);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+
+        let decode = |path: &str| image::open(path).unwrap().to_rgba8();
+        assert_eq!(decode(&whole), decode(&streamed), "{:?}", args);
+        assert_eq!(
+            ip_heatmap::inspect_file(&whole).unwrap(),
+            ip_heatmap::inspect_file(&streamed).unwrap(),
+            "{:?}",
+            args
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_decorated_renders_still_work() {
+    let dir = scratch_dir("decorated");
+    let output = dir.join("map.png").to_str().unwrap().to_string();
+    let result = run(&["-z", "16", "--value-mode", "raw", "--low-memory", "-t", "Scan", &output], INPUT);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    // The title makes the image taller than the map
+    let image = image::open(&output).unwrap();
+    assert!(image.height() > 256);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_max_memory_refuses_large_maps() {
+    let dir = scratch_dir("max-memory");
+    let output = dir.join("map.png").to_str().unwrap().to_string();
+    let result = run(&["-z", "0", "--max-memory", "2G", &output], INPUT);
+    assert_eq!(result.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("Projected memory use 16.5 GiB"), "{}", stderr);
+    assert!(stderr.contains("try -z 4 instead of -z 0"), "{}", stderr);
+    assert!(!std::path::Path::new(&output).exists());
+
+    // A map within the limit renders as usual
+    let result = run(&["-z", "16", "--max-memory", "1M", &output], INPUT);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+
+    let result = run(&["-z", "16", "--max-memory", "lots", &output], INPUT);
+    assert_eq!(result.status.code(), Some(2));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,147 @@
+//! `--metrics-listen` serves counters that grow as input is read and maps are rendered.
+#![cfg(all(unix, feature = "serve"))]
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{ChildStderr, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// The address printed on the next stderr line starting with `prefix`.
+fn address(stderr: &mut BufReader<ChildStderr>, prefix: &str) -> String {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        assert!(stderr.read_line(&mut line).unwrap() > 0, "no line starting with {}", prefix);
+        if let Some(rest) = line.trim().strip_prefix(prefix) {
+            return rest.trim_end_matches("/metrics").to_string();
+        }
+    }
+}
+
+fn get(address: &str, target: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", target).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"), "{}", response);
+    response.split_once("\r\n\r\n").unwrap().1.to_string()
+}
+
+/// Samples by name (with labels), checking every sample has HELP and TYPE lines.
+fn scrape(address: &str) -> HashMap<String, f64> {
+    let text = get(address, "/metrics");
+    let mut samples = HashMap::new();
+    for line in text.lines().filter(|line| !line.starts_with('#')) {
+        let (name, value) = line.rsplit_once(' ').unwrap();
+        let family = name.split(['{', ' ']).next().unwrap();
+        let family = ["_bucket", "_sum", "_count"].iter().fold(family, |family, suffix| family.trim_end_matches(suffix));
+        assert!(text.contains(&format!("# TYPE {} ", family)), "{} has no TYPE in\n{}", name, text);
+        samples.insert(name.to_string(), value.parse().unwrap());
+    }
+    samples
+}
+
+/// Scrape until `ready` holds for the samples, failing after a few seconds.
+fn scrape_until(address: &str, ready: impl Fn(&HashMap<String, f64>) -> bool) -> HashMap<String, f64> {
+    let started = Instant::now();
+    loop {
+        let samples = scrape(address);
+        if ready(&samples) {
+            return samples;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "metrics never became ready: {:?}", samples);
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_pipe_metrics_grow() {
+    let output = std::env::temp_dir().join(format!("ip-heatmap-metrics-{}.png", std::process::id()));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+        .args(["-z", "16", "--value-mode", "raw", "--metrics-listen", "127.0.0.1:0", output.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("binary runs");
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let metrics = address(&mut stderr, "Serving metrics on http://");
+    let mut stdin = child.stdin.take().unwrap();
+
+    let before = scrape(&metrics);
+    for name in [
+        "ip_heatmap_lines_processed_total",
+        "ip_heatmap_rejected_lines_total",
+        "ip_heatmap_renders_total",
+        "ip_heatmap_buffer_bytes",
+        "ip_heatmap_last_render_timestamp_seconds",
+        "ip_heatmap_render_duration_seconds_count",
+        "ip_heatmap_render_duration_seconds_bucket{le=\"+Inf\"}",
+    ] {
+        assert!(before.contains_key(name), "{} missing from {:?}", name, before);
+    }
+    // Cells and the touched mask of a -z 16 map
+    assert_eq!(before["ip_heatmap_buffer_bytes"], (256.0 * 256.0 * 4.0) + (256.0 * 256.0 / 8.0));
+
+    stdin.write_all(b"10.0.0.1 5\nbad 1\n10.1.0.0/16 3\n").unwrap();
+    stdin.flush().unwrap();
+    let first = scrape_until(&metrics, |samples| samples["ip_heatmap_lines_processed_total"] >= 3.0);
+    assert_eq!(first["ip_heatmap_rejected_lines_total"], 1.0);
+
+    // A snapshot renders the map
+    let killed = Command::new("kill").args(["-HUP", &child.id().to_string()]).status().unwrap();
+    assert!(killed.success());
+    let rendered = scrape_until(&metrics, |samples| samples["ip_heatmap_renders_total"] >= 1.0);
+    assert_eq!(rendered["ip_heatmap_render_duration_seconds_count"], rendered["ip_heatmap_renders_total"]);
+    assert!(rendered["ip_heatmap_last_render_timestamp_seconds"] > 1.0e9);
+
+    stdin.write_all(b"8.8.8.8 2\n8.8.4.4 1\n").unwrap();
+    stdin.flush().unwrap();
+    let second = scrape_until(&metrics, |samples| samples["ip_heatmap_lines_processed_total"] >= 5.0);
+    for (name, value) in &first {
+        if name.ends_with("_total") || name.contains("_bucket") {
+            assert!(second[name] >= *value, "{} went from {} to {}", name, value, second[name]);
+        }
+    }
+    drop(stdin);
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(3));
+    let _ = std::fs::remove_file(&output);
+}
+
+#[test]
+fn test_serve_metrics_count_requests() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+        .args(["serve", "--listen", "127.0.0.1:0", "--metrics-listen", "127.0.0.1:0"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("binary runs");
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let server = address(&mut stderr, "Listening on http://");
+    let metrics = address(&mut stderr, "Serving metrics on http://");
+
+    for _ in 0..2 {
+        let body = "10.0.0.1 5\nbad 1\n";
+        let mut stream = TcpStream::connect(&server).unwrap();
+        write!(stream, "POST /render?z=20&value-mode=raw HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+    let samples = scrape(&metrics);
+    assert_eq!(samples["ip_heatmap_lines_processed_total"], 4.0);
+    assert_eq!(samples["ip_heatmap_rejected_lines_total"], 2.0);
+    assert_eq!(samples["ip_heatmap_renders_total"], 2.0);
+
+    let mut stream = TcpStream::connect(&metrics).unwrap();
+    stream.write_all(b"GET /other HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    let _ = child.kill();
+    let _ = child.wait();
+}
@@ -0,0 +1,70 @@
+//! Existing outputs are replaced atomically, kept with --no-clobber and moved aside
+//! with --backup.
+
+mod common;
+
+use common::{entries, scratch_dir};
+use std::path::Path;
+use std::process::Output;
+
+const INPUT: &str = "10.0.0.1\n10.1.0.0/16\n";
+
+fn run(args: &[&str]) -> Output {
+    common::run(args, INPUT)
+}
+
+fn is_png(path: &Path) -> bool {
+    std::fs::read(path).unwrap().starts_with(b"\x89PNG")
+}
+
+#[test]
+fn test_existing_output_is_replaced() {
+    let dir = scratch_dir("replace");
+    let output = dir.join("map.png");
+    std::fs::write(&output, b"stale").unwrap();
+    let result = run(&["-z", "16", output.to_str().unwrap()]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(is_png(&output));
+    // No temporary files are left behind
+    assert_eq!(entries(&dir), ["map.png"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_no_clobber_keeps_existing_output() {
+    let dir = scratch_dir("no-clobber");
+    let output = dir.join("map.png");
+    let thumbnail = dir.join("thumb.png");
+    std::fs::write(&thumbnail, b"stale").unwrap();
+    let thumbnail_spec = format!("{}:64", thumbnail.display());
+    let result = run(&["-z", "16", "--no-clobber", "--thumbnail", &thumbnail_spec, output.to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("already exists"));
+    assert_eq!(std::fs::read(&thumbnail).unwrap(), b"stale");
+    assert_eq!(entries(&dir), ["thumb.png"]);
+
+    std::fs::remove_file(&thumbnail).unwrap();
+    let result = run(&["-z", "16", "--no-clobber", output.to_str().unwrap()]);
+    assert!(result.status.success());
+    assert!(is_png(&output));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_backup_renames_previous_output() {
+    let dir = scratch_dir("backup");
+    let output = dir.join("map.png");
+    std::fs::write(&output, b"previous").unwrap();
+    std::fs::write(dir.join("map.png.bak"), b"older").unwrap();
+    let result = run(&["-z", "16", "--backup", output.to_str().unwrap()]);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(is_png(&output));
+    assert_eq!(std::fs::read(dir.join("map.png.bak")).unwrap(), b"previous");
+    assert_eq!(entries(&dir), ["map.png", "map.png.bak"]);
+
+    // Without an existing output there is nothing to back up
+    let fresh = dir.join("fresh.png");
+    assert!(run(&["-z", "16", "--backup", fresh.to_str().unwrap()]).status.success());
+    assert!(!dir.join("fresh.png.bak").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,45 @@
+//! `--winsorize LOW,HIGH` takes both percentiles as one value, in either form of a
+//! long flag, and the output path after it stays the output path.
+
+mod common;
+
+use common::{run_in, scratch_dir};
+use std::path::Path;
+use std::process::Output;
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    let input: String = (0..200).map(|i| format!("10.{}.0.1 {}\n", i, i + 1)).collect();
+    run_in(dir, args, input)
+}
+
+#[test]
+fn test_winsorize_takes_one_value() {
+    let dir = scratch_dir("forms");
+    for args in [&["--winsorize", "1,99", "spaced.png"][..], &["--winsorize=1,99", "joined.png"]] {
+        let args = [&["--value-mode", "raw", "-z", "16"], args].concat();
+        let result = run(&dir, &args);
+        assert!(result.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&result.stderr));
+        assert!(image::open(dir.join(args.last().unwrap())).is_ok(), "{:?}", args);
+    }
+    // Both forms clamp the same way
+    assert_eq!(std::fs::read(dir.join("spaced.png")).unwrap(), std::fs::read(dir.join("joined.png")).unwrap());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_winsorize_rejects_bad_percentiles() {
+    let dir = scratch_dir("invalid");
+    for (value, message) in [
+        ("1", "Use LOW,HIGH from 0 to 100"),
+        ("1,x", "Use LOW,HIGH from 0 to 100"),
+        ("1,101", "Use LOW,HIGH from 0 to 100"),
+        ("99,1", "The low percentile must be below the high one"),
+    ] {
+        let result = run(&dir, &["--value-mode", "raw", "--winsorize", value, "map.png"]);
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert!(!result.status.success(), "{}", value);
+        assert!(stderr.contains(message), "{}: {}", value, stderr);
+    }
+    assert!(!dir.join("map.png").exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
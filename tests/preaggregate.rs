@@ -0,0 +1,69 @@
+//! `--preaggregate` and `--distinct` paint each key once, spilling sorted runs to
+//! `--tmpdir` when the keys do not fit `--preaggregate-memory`.
+
+mod common;
+
+use common::{run_in, scratch_dir};
+use std::path::Path;
+use std::process::Output;
+
+fn run(dir: &Path, args: &[&str], stdin: &str) -> Output {
+    run_in(dir, &[&["-z", "16", "--value-mode", "raw"], args].concat(), stdin)
+}
+
+/// Repeated addresses and prefixes spread over the map.
+fn input() -> String {
+    (0..2000u32)
+        .map(|i| format!("{}.{}.0.{} {}\n", i * 37 % 223, i % 7, i % 4, i % 13 + 1))
+        .collect()
+}
+
+#[test]
+fn test_tiny_budget_matches_memory() {
+    let dir = scratch_dir("budget");
+    let runs = dir.join("runs");
+    std::fs::create_dir(&runs).unwrap();
+    for flag in ["--preaggregate", "--distinct"] {
+        let result = run(&dir, &[flag, "--state-mmap", "memory.state", "memory.png"], &input());
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        // 32 keys at a time spills a run every few dozen lines
+        let args = [flag, "--preaggregate-memory", "512", "--tmpdir", "runs", "-v", "--stats", "--state-mmap", "disk.state"];
+        let result = run(&dir, &[&args[..], &["disk.png"]].concat(), &input());
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert!(result.status.success(), "{}", stderr);
+        assert!(stderr.contains("runs spilled to disk"), "{}", stderr);
+        assert!(stderr.contains("| distinct keys "), "{}", stderr);
+        let read = |name: &str| std::fs::read(dir.join(name)).unwrap();
+        assert_eq!(read("disk.state"), read("memory.state"), "{}", flag);
+        assert_eq!(read("disk.png"), read("memory.png"), "{}", flag);
+        assert_eq!(std::fs::read_dir(&runs).unwrap().count(), 0, "runs were left behind");
+        for name in ["memory.state", "disk.state"] {
+            std::fs::remove_file(dir.join(name)).unwrap();
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_runs_are_removed_on_failure() {
+    let dir = scratch_dir("failure");
+    let runs = dir.join("runs");
+    std::fs::create_dir(&runs).unwrap();
+    let input = format!("{}not an address\n", input());
+    let args = ["--distinct", "--preaggregate-memory", "512", "--tmpdir", "runs", "--on-error", "fail", "map.png"];
+    let result = run(&dir, &args, &input);
+    assert_eq!(result.status.code(), Some(1), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(std::fs::read_dir(&runs).unwrap().count(), 0, "runs were left behind");
+    assert!(!dir.join("map.png").exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_distinct_counts_repeats_once() {
+    let dir = scratch_dir("distinct");
+    let result = run(&dir, &["--distinct", "--stats-json", "stats.json", "map.png"], "10.0.0.1 5\n10.0.0.1 9\n10.0.0.2 1\n");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let stats = std::fs::read_to_string(dir.join("stats.json")).unwrap();
+    assert!(stats.contains("\"preaggregated_keys\":2"), "{}", stats);
+    let _ = std::fs::remove_dir_all(&dir);
+}
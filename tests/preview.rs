@@ -0,0 +1,57 @@
+//! `--preview` writes small maps of the input read so far while a slow run goes on.
+
+mod common;
+
+use common::scratch_dir;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+#[test]
+fn test_previews_of_a_slow_input() {
+    let dir = scratch_dir("slow");
+    let (output, preview) = (dir.join("map.png"), dir.join("preview.png"));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+        .args(["-z", "16", "--value-mode", "raw", "--preview", &format!("{}:64:0.05", preview.display())])
+        .arg(&output)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("binary runs");
+    let mut stdin = child.stdin.take().unwrap();
+    // A reader that trickles lines in, as a long collection would
+    let mut seen_preview = false;
+    for line in 0..20 {
+        writeln!(stdin, "10.{}.0.1 {}", line, line + 1).unwrap();
+        stdin.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        seen_preview |= preview.exists();
+    }
+    assert!(seen_preview, "no preview was written while input was still open");
+    drop(stdin);
+    let result = child.wait_with_output().unwrap();
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+
+    let image = image::open(&preview).expect("the preview decodes");
+    assert_eq!((image.width(), image.height()), (64, 64));
+    let full = image::open(&output).unwrap();
+    assert_eq!((full.width(), full.height()), (256, 256));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_preview_spec_is_checked() {
+    let dir = scratch_dir("spec");
+    let output = dir.join("map.png");
+    for spec in ["preview.png:500:60", "preview.png:512", "preview.png:512:0"] {
+        let result = Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+            .args(["-z", "16", "--preview", spec])
+            .arg(&output)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert_eq!(result.status.code(), Some(2), "{}", spec);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
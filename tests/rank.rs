@@ -0,0 +1,36 @@
+//! `--rank` reports where prefixes of interest stand, and `--rank-mark` marks them.
+
+mod common;
+
+use common::run;
+
+#[test]
+fn test_ranks_are_printed_and_marked() {
+    // 10.0/16 to 10.99/16 hold 1 to 100
+    let input: String = (0..100).map(|i| format!("10.{}.0.1 {}\n", i, i + 1)).collect();
+    let dir = std::env::temp_dir();
+    let plain = dir.join(format!("ip-heatmap-rank-{}-plain.png", std::process::id()));
+    let marked = dir.join(format!("ip-heatmap-rank-{}-marked.png", std::process::id()));
+    let args = ["-z", "16", "--value-mode", "raw", "--rank", "10.98.0.0/16", "--rank", "10.0.0.0/8"];
+
+    let output = run(&[&args[..], &[plain.to_str().unwrap()]].concat(), &input);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("rank 10.98.0.0/16: total 99, rank 2 of 65536, percentile 99.9985\n"), "{}", stderr);
+    assert!(stderr.contains("rank 10.0.0.0/8: total 5050, rank 1 of 256, percentile 100.0000\n"), "{}", stderr);
+
+    let output = run(&[&args[..], &["--rank-mark", marked.to_str().unwrap()]].concat(), &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_ne!(std::fs::read(&plain).unwrap(), std::fs::read(&marked).unwrap());
+    std::fs::remove_file(&plain).unwrap();
+    std::fs::remove_file(&marked).unwrap();
+}
+
+#[test]
+fn test_prefixes_finer_than_a_pixel_are_refused_up_front() {
+    let output = run(&["-z", "16", "--rank", "10.0.0.0/24", "unused.png"], "10.0.0.1\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("Cannot rank 10.0.0.0/24") && stderr.contains("finer than the pixel resolution"), "{}", stderr);
+    assert!(stderr.contains("lines=0"), "{}", stderr);
+}
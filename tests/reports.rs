@@ -0,0 +1,86 @@
+//! The textual reports are drawn in ASCII on pipes, and with box drawing and bold
+//! headers only on a UTF-8 terminal that allows them.
+
+mod common;
+
+use common::{command, output, scratch_dir};
+use std::process::{Command, Output, Stdio};
+
+fn run(args: &[&str], stdin: &str) -> Output {
+    output(command(args).env("LANG", "C.UTF-8").env_remove("LC_ALL").env_remove("LC_CTYPE").env_remove("NO_COLOR"), stdin)
+}
+
+const INPUT: &str = "10.0.0.1 4\n10.1.0.1 2\n";
+
+#[test]
+fn test_reports_on_a_pipe_are_ascii() {
+    let dir = scratch_dir("pipe");
+    let output = dir.join("map.png").to_str().unwrap().to_string();
+    let result = run(&["-z", "16", "--value-mode", "raw", "--stats", "--coverage-report", &output], INPUT);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.is_ascii() && !stderr.contains('\x1b'), "{}", stderr);
+    assert!(stderr.contains("| count          | value |\n+----------------+-------+\n| lines          | 2     |\n"), "{}", stderr);
+    assert!(stderr.contains("| /8     |       1 |      256 | 0.39% |                                 |\n"), "{}", stderr);
+
+    let state = dir.join("map.state").to_str().unwrap().to_string();
+    let result = run(&["-z", "16", "--value-mode", "raw", "--save-state", &state, &output], INPUT);
+    assert!(result.status.success());
+    let result = run(&["compare", &state, &state], "");
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "+---------+----------+------------------+\n\
+         | metric  |    value | over             |\n\
+         +---------+----------+------------------+\n\
+         | cosine  | 1.000000 |                  |\n\
+         | pearson | 1.000000 | 2 non-zero cells |\n\
+         | jaccard | 1.000000 | touched pixels   |\n\
+         +---------+----------+------------------+\n"
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Run `args` on a pseudo-terminal with `script`, or `None` where it is missing.
+#[cfg(target_os = "linux")]
+fn run_on_terminal(args: &str, env: &[(&str, &str)]) -> Option<String> {
+    let command = format!("{} {}", env!("CARGO_BIN_EXE_ip-heatmap"), args);
+    let output = Command::new("script")
+        .args(["-qec", &command, "/dev/null"])
+        .env("LANG", "C.UTF-8")
+        .env_remove("LC_ALL")
+        .env_remove("LC_CTYPE")
+        .env_remove("NO_COLOR")
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n"))
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_reports_on_a_terminal() {
+    let dir = scratch_dir("terminal");
+    let (state, output) = (dir.join("map.state"), dir.join("map.png"));
+    let result = run(
+        &["-z", "16", "--value-mode", "raw", "--save-state", state.to_str().unwrap(), output.to_str().unwrap()],
+        INPUT,
+    );
+    assert!(result.status.success());
+    let compare = format!("compare {0} {0}", state.display());
+    let Some(boxed) = run_on_terminal(&compare, &[]) else {
+        eprintln!("script is not available, skipping");
+        return;
+    };
+    assert!(boxed.contains("┌─────────┬──────────┬──────────────────┐\n"), "{}", boxed);
+    assert!(boxed.contains("│ \x1b[1mmetric \x1b[0m │"), "{}", boxed);
+    assert!(boxed.contains("│ pearson │ 1.000000 │ 2 non-zero cells │\n"), "{}", boxed);
+
+    let plain = run_on_terminal(&compare, &[("NO_COLOR", "1")]).unwrap();
+    assert!(plain.contains("│ metric  │") && !plain.contains('\x1b'), "{}", plain);
+    let ascii = run_on_terminal(&format!("{} --ascii --no-color", compare), &[]).unwrap();
+    assert!(ascii.contains("| metric  |    value | over             |\n") && ascii.is_ascii(), "{}", ascii);
+    let c_locale = run_on_terminal(&compare, &[("LC_ALL", "C")]).unwrap();
+    assert!(c_locale.contains("| pearson | 1.000000 |"), "{}", c_locale);
+    let _ = std::fs::remove_dir_all(&dir);
+}
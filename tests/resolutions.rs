@@ -0,0 +1,46 @@
+//! Repeated -z values render one output per resolution from a single pass, each the
+//! same as a run at that resolution alone.
+
+mod common;
+
+use common::scratch_dir;
+use std::path::Path;
+use std::process::Output;
+
+const INPUT: &str = "10.0.0.0/16 5\n10.1.2.3 100\n192.168.0.0/16 9\nnot an address\n";
+
+fn run(args: &[&str]) -> Output {
+    common::run(args, INPUT)
+}
+
+fn path(dir: &Path, name: &str) -> String {
+    dir.join(name).to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_each_resolution_matches_a_single_run() {
+    let dir = scratch_dir("match");
+    let result = run(&["--value-mode", "raw", "-z", "16", "-z", "24", "-z", "12", &path(&dir, "map.png")]);
+    assert_eq!(result.status.code(), Some(3), "{}", String::from_utf8_lossy(&result.stderr));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    // The reject is logged once, not once per resolution
+    assert_eq!(stderr.matches("Failed to parse line 4").count(), 1, "{}", stderr);
+    for bits_per_pixel in ["16", "24", "12"] {
+        let single = path(&dir, &format!("single-{}.png", bits_per_pixel));
+        let result = run(&["--value-mode", "raw", "-z", bits_per_pixel, &single]);
+        assert_eq!(result.status.code(), Some(3));
+        let multi = path(&dir, &format!("map-z{}.png", bits_per_pixel));
+        assert_eq!(std::fs::read(&multi).unwrap(), std::fs::read(&single).unwrap(), "-z {}", bits_per_pixel);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_unsupported_flags_are_refused() {
+    let dir = scratch_dir("unsupported");
+    let result = run(&["-z", "16", "-z", "24", "--save-state", &path(&dir, "s"), &path(&dir, "map.png")]);
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("--save-state cannot be combined"));
+    assert!(!dir.join("map-z16.png").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,126 @@
+//! `serve` renders posted input over HTTP within its size and time limits.
+#![cfg(feature = "serve")]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+
+/// A running `serve` process, killed when dropped.
+struct Server {
+    child: Child,
+    address: String,
+}
+
+impl Server {
+    fn start(args: &[&str]) -> Server {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("binary runs");
+        let mut line = String::new();
+        BufReader::new(child.stderr.take().unwrap()).read_line(&mut line).unwrap();
+        let address = line.trim().strip_prefix("Listening on http://").expect("listening line").to_string();
+        Server { child, address }
+    }
+
+    /// Send a raw request and return the status, headers and body of the response.
+    fn request(&self, head: &str, body: &[u8]) -> (u16, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        stream.write_all(head.as_bytes()).unwrap();
+        // The server may answer before reading a body it refuses
+        let _ = stream.write_all(body);
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|window| window == b"\r\n\r\n").expect("complete response");
+        let headers = String::from_utf8(response[..split].to_vec()).unwrap();
+        let status = headers.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, headers, response[split + 4..].to_vec())
+    }
+
+    fn post(&self, target: &str, extra_headers: &str, body: &[u8]) -> (u16, String, Vec<u8>) {
+        let head = format!("POST {} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n{}\r\n", target, body.len(), extra_headers);
+        self.request(&head, body)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+const INPUT: &str = "10.0.0.1 5\n10.1.0.0/16 3\nnot-an-address 1\n8.8.8.8 2\n";
+
+#[test]
+fn test_render_and_healthz() {
+    let server = Server::start(&[]);
+    let (status, _, body) = server.request("GET /healthz HTTP/1.1\r\nHost: test\r\n\r\n", b"");
+    assert_eq!((status, body.as_slice()), (200, b"ok\n".as_slice()));
+
+    let (status, headers, body) = server.post("/render?z=16&value-mode=raw&curve=log&palette=viridis", "", INPUT.as_bytes());
+    assert_eq!(status, 200, "{}", String::from_utf8_lossy(&body));
+    assert!(headers.contains("Content-Type: image/png"), "{}", headers);
+    assert!(headers.contains("X-Rejected-Lines: 1"), "{}", headers);
+    let image = image::load_from_memory(&body).unwrap();
+    assert_eq!((image.width(), image.height()), (256, 256));
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(INPUT.as_bytes()).unwrap();
+    let gzipped = encoder.finish().unwrap();
+    let (status, _, body) = server.post("/render?z=20&value-mode=raw", "Content-Encoding: gzip\r\n", &gzipped);
+    assert_eq!(status, 200, "{}", String::from_utf8_lossy(&body));
+    assert_eq!(image::load_from_memory(&body).unwrap().width(), 64);
+}
+
+#[test]
+fn test_limits() {
+    let server = Server::start(&["--max-body-bytes", "1000", "--timeout", "1"]);
+    let (status, _, _) = server.post("/render?z=16", "", &vec![b'\n'; 1001]);
+    assert_eq!(status, 413);
+
+    // A small gzip upload may still expand beyond the limit
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&vec![b'\n'; 100_000]).unwrap();
+    let gzipped = encoder.finish().unwrap();
+    assert!(gzipped.len() < 1000);
+    let (status, _, _) = server.post("/render?z=16", "Content-Encoding: gzip\r\n", &gzipped);
+    assert_eq!(status, 413);
+
+    let (status, _, body) = server.post("/render?z=2", "", INPUT.as_bytes());
+    assert_eq!(status, 400);
+    assert!(String::from_utf8_lossy(&body).contains("z must be from 8 to 32"));
+    let (status, _, _) = server.post("/render?curve=cubic", "", INPUT.as_bytes());
+    assert_eq!(status, 400);
+    let (status, _, _) = server.request("GET /render HTTP/1.1\r\n\r\n", b"");
+    assert_eq!(status, 405);
+    let (status, _, _) = server.request("GET /missing HTTP/1.1\r\n\r\n", b"");
+    assert_eq!(status, 404);
+
+    // A body that never arrives times out
+    let (status, _, _) = server.request("POST /render HTTP/1.1\r\nContent-Length: 100\r\n\r\n10.0.0.1 5\n", b"");
+    assert_eq!(status, 408);
+}
+
+#[test]
+fn test_concurrency_cap() {
+    let server = Server::start(&["--max-concurrent", "1", "--timeout", "5"]);
+    // Hold the only slot with a request whose body is still outstanding
+    let mut slow = TcpStream::connect(&server.address).unwrap();
+    slow.write_all(b"POST /render?z=16&value-mode=raw HTTP/1.1\r\nContent-Length: 11\r\n\r\n10.0").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let (status, headers, _) = server.request("GET /healthz HTTP/1.1\r\n\r\n", b"");
+    assert_eq!(status, 503);
+    assert!(headers.contains("Retry-After: 1"), "{}", headers);
+
+    slow.write_all(b".0.1 5\n").unwrap();
+    let mut response = Vec::new();
+    slow.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"), "{}", String::from_utf8_lossy(&response));
+    let (status, _, _) = server.request("GET /healthz HTTP/1.1\r\n\r\n", b"");
+    assert_eq!(status, 200);
+}
@@ -0,0 +1,102 @@
+//! SIGHUP writes a snapshot while input is still open; SIGTERM and SIGINT write the
+//! final image and exit.
+#![cfg(unix)]
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+fn output_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ip-heatmap-signals-{}-{}.png", std::process::id(), name))
+}
+
+fn spawn(output: &Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_ip-heatmap"))
+        .args(["-z", "16", output.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("binary runs")
+}
+
+fn kill(child: &Child, signal: &str) {
+    let status = Command::new("kill")
+        .args([format!("-{}", signal), child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+fn is_png(path: &Path) -> bool {
+    std::fs::read(path).is_ok_and(|bytes| bytes.starts_with(b"\x89PNG"))
+}
+
+fn summary(stderr: &[u8]) -> String {
+    String::from_utf8_lossy(stderr).lines().last().unwrap_or_default().to_string()
+}
+
+#[test]
+fn test_hup_snapshot_then_term() {
+    let output = output_path("hup");
+    let _ = std::fs::remove_file(&output);
+    let mut child = spawn(&output);
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"10.0.0.1\n10.1.0.0/16\n").unwrap();
+    stdin.flush().unwrap();
+    // Give the lines time to reach the buffer before asking for a snapshot
+    std::thread::sleep(Duration::from_millis(300));
+
+    kill(&child, "HUP");
+    assert!(wait_for(|| is_png(&output)), "no snapshot written");
+    let snapshot = std::fs::read(&output).unwrap();
+    assert!(child.try_wait().unwrap().is_none(), "HUP must not end the process");
+
+    stdin.write_all(b"192.168.0.0/16\n").unwrap();
+    stdin.flush().unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    // Stdin stays open: only the signal ends the run
+    kill(&child, "TERM");
+    let result = child.wait_with_output().unwrap();
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    assert_eq!(
+        summary(&result.stderr),
+        format!("ipv4-heatmap: lines=3 rejected=0 pixels=3 output={}", output.display())
+    );
+    assert!(is_png(&output));
+    assert_ne!(std::fs::read(&output).unwrap(), snapshot);
+    drop(stdin);
+    std::fs::remove_file(&output).unwrap();
+}
+
+#[test]
+fn test_int_renders_and_exits() {
+    let output = output_path("int");
+    let _ = std::fs::remove_file(&output);
+    let mut child = spawn(&output);
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"10.0.0.1\n10.1.0.0/16\n10.2.").unwrap();
+    stdin.flush().unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+
+    kill(&child, "INT");
+    let result = child.wait_with_output().unwrap();
+    assert_eq!(result.status.code(), Some(0), "{}", String::from_utf8_lossy(&result.stderr));
+    // The partial last line is dropped
+    assert!(summary(&result.stderr).starts_with("ipv4-heatmap: lines=2 rejected=0 pixels=2 "));
+    assert!(is_png(&output));
+    drop(stdin);
+    std::fs::remove_file(&output).unwrap();
+}
@@ -0,0 +1,83 @@
+//! Option enums print the names their parsers take, and their parse errors name them.
+
+use ip_heatmap::{
+    Aggregation, CidrHostBits, DistinctApprox, DitherPattern, DomainType, ErrorPolicy, InputFormat, LutRounding, MapV6,
+    OutputFormat, PngCompression, PngFilter, ShadeStyle, ValueMode, ValueSource,
+};
+use std::collections::HashSet;
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::str::FromStr;
+
+/// Every variant prints a name that parses back to it in any case, and the error for
+/// an unknown name lists every printed name, each of which parses.
+fn check_names<T>(all: &[T])
+where
+    T: FromStr<Err = String> + Display + Debug + Copy + Eq + Hash,
+{
+    assert_eq!(all.iter().collect::<HashSet<_>>().len(), all.len(), "{:?}", all);
+    for &variant in all {
+        let name = variant.to_string();
+        assert_eq!(name.parse::<T>(), Ok(variant), "{}", name);
+        assert_eq!(name.to_uppercase().parse::<T>(), Ok(variant), "{}", name);
+    }
+    let error = "bogus".parse::<T>().unwrap_err();
+    assert!(error.contains("bogus"), "{}", error);
+    let listed: Vec<&str> = error.split('\'').skip(1).step_by(2).collect();
+    for &variant in all {
+        assert!(listed.contains(&variant.to_string().as_str()), "{} is not in: {}", variant, error);
+    }
+    for name in listed {
+        assert!(name.parse::<T>().is_ok(), "{} from: {}", name, error);
+    }
+}
+
+#[test]
+fn test_option_names_round_trip() {
+    check_names(&ValueMode::ALL);
+    check_names(&ValueSource::ALL);
+    check_names(&Aggregation::ALL);
+    check_names(&MapV6::ALL);
+    check_names(&CidrHostBits::ALL);
+    check_names(&InputFormat::ALL);
+    check_names(&OutputFormat::ALL);
+    check_names(&PngCompression::ALL);
+    check_names(&PngFilter::ALL);
+    check_names(&ErrorPolicy::ALL);
+    check_names(&ShadeStyle::ALL);
+    check_names(&DistinctApprox::ALL);
+    check_names(&LutRounding::ALL);
+    check_names(&DitherPattern::ALL);
+}
+
+#[test]
+fn test_curves_round_trip() {
+    let curve = |s: &str| s.parse::<DomainType>();
+    assert_eq!(DomainType::Logarithmic.to_string(), "log");
+    assert_eq!(curve("logarithmic"), Ok(DomainType::Logarithmic));
+    assert_eq!(curve("symlog"), Ok(DomainType::Symlog { linthresh: 1.0 }));
+    let error = curve("bogus").unwrap_err();
+    assert!(error.contains("'linear', 'log' or 'symlog[:threshold]'"), "{}", error);
+
+    // Thresholds over many magnitudes print exactly enough digits to parse back
+    let mut thresholds = vec![1.0, 0.5, 10.0, 1e-9, 123456.789, f64::MIN_POSITIVE, f64::MAX];
+    let mut bits: u64 = 0x3ff0_0000_0000_0001;
+    for _ in 0..1000 {
+        bits = bits.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+        let threshold = f64::from_bits(bits >> 2);
+        if threshold.is_finite() && threshold > 0.0 {
+            thresholds.push(threshold);
+        }
+    }
+    let mut curves = HashSet::new();
+    for linthresh in thresholds {
+        let symlog = DomainType::Symlog { linthresh };
+        assert_eq!(curve(&symlog.to_string()), Ok(symlog), "{}", symlog);
+        curves.insert(symlog);
+    }
+    for simple in [DomainType::Linear, DomainType::Logarithmic] {
+        assert_eq!(curve(&simple.to_string()), Ok(simple));
+        assert!(curves.insert(simple));
+    }
+    assert_ne!(DomainType::Symlog { linthresh: 1.0 }, DomainType::Symlog { linthresh: 2.0 });
+}
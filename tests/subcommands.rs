@@ -0,0 +1,110 @@
+//! `ip-heatmap render` and the flags without a subcommand are the same command.
+
+mod common;
+
+use common::{command, output, scratch_dir};
+use std::path::Path;
+use std::process::Output;
+
+fn run(dir: &Path, args: &[&str], stdin: &str) -> Output {
+    // Backtraces differ by call site
+    output(command(args).current_dir(dir).env("RUST_BACKTRACE", "0"), stdin)
+}
+
+/// The files of `dir` with their contents, sorted by name.
+fn files(dir: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut files: Vec<(String, Vec<u8>)> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            (path.file_name().unwrap().to_string_lossy().into_owned(), std::fs::read(&path).unwrap())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Stderr without the timestamps of log lines.
+fn messages(output: &Output) -> Vec<String> {
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .map(|line| match line.strip_prefix('[').and_then(|rest| rest.split_once("Z ")) {
+            Some((_, message)) => message.to_string(),
+            None => line.to_string(),
+        })
+        .collect()
+}
+
+const INPUT: &str = "10.0.0.1 5\n10.0.0.0/16 3\n11.0.0.0/12 40\n192.168.1.1 -1\n::ffff:8.8.8.8 7\n8.8.4.4 12\nnot an address\n";
+
+/// Run `args` without a subcommand and with `render` in fresh directories, and check
+/// both give the same exit status, messages and files. Returns the exit status.
+fn assert_same_as_render(name: &str, args: &[&str]) -> Option<i32> {
+    let plain = scratch_dir(&format!("{}-plain", name));
+    let render = scratch_dir(&format!("{}-render", name));
+    let plain_result = run(&plain, args, INPUT);
+    let render_args: Vec<&str> = std::iter::once("render").chain(args.iter().copied()).collect();
+    let render_result = run(&render, &render_args, INPUT);
+
+    assert_eq!(plain_result.status.code(), render_result.status.code(), "{}", name);
+    assert_eq!(plain_result.stdout, render_result.stdout, "{}", name);
+    assert_eq!(messages(&plain_result), messages(&render_result), "{}", name);
+    assert_eq!(files(&plain), files(&render), "{}", name);
+    std::fs::remove_dir_all(&plain).unwrap();
+    std::fs::remove_dir_all(&render).unwrap();
+    plain_result.status.code()
+}
+
+#[test]
+fn test_framed_render_with_exports() {
+    let args = [
+        "-z", "16", "-C", "--value-mode", "raw", "--curve", "log", "--log-base", "2", "--min-value", "1",
+        "--max-value", "100", "--gamma", "0.8", "-t", "Scan", "-u", "hosts", "--font-size", "16", "-r", "--fade-low",
+        "--outline", "10.0.0.0/8:ff0000:2", "--shade", "11.0.0.0/8:00ff00:dots:4", "--legend-bands",
+        "low:5,high:", "--snap-bands", "--map-v6", "mapped", "--ignore-value", "-1", "--stats-json",
+        "stats.json", "--export-prefixes", "prefixes.txt", "--save-state", "run.state", "--histogram",
+        "histogram.png", "--slash8-chart", "slash8.png", "--slash8-chart-log", "--rejects", "rejects.txt", "map.png",
+    ];
+    // The input has one unparsable line
+    assert_eq!(assert_same_as_render("framed", &args), Some(3));
+}
+
+#[test]
+fn test_downsampled_render_with_clamping() {
+    let args = [
+        "-z", "16", "--value-mode", "raw", "--output-size", "64", "--downsample", "sum",
+        "--thumbnail", "thumb.png:32", "--render", "log.png:curve=log", "--floor", "2", "--ceiling", "20",
+        "--sample", "0.5", "--sample-seed", "3", "--weight", "2", "--coverage-report", "--png-compression", "default",
+        "--png-filter", "paeth", "map.png",
+    ];
+    assert_eq!(assert_same_as_render("downsampled", &args), Some(3));
+}
+
+#[test]
+fn test_validate_and_failures() {
+    assert_same_as_render("validate", &["--validate", "-z", "16", "--value-mode", "raw"]);
+    assert_eq!(assert_same_as_render("legacy", &["-a", "annotations.txt", "map.png"]), Some(1));
+    // Usage errors name the subcommand, so only their status is the same
+    let dir = scratch_dir("usage");
+    let cases: [&[&str]; 2] = [&["--pixel-caption", "--no-pixel-caption", "map.png"], &["-z", "16", "--curve=bogus"]];
+    for args in cases {
+        let plain = run(&dir, args, "");
+        let render_args: Vec<&str> = std::iter::once("render").chain(args.iter().copied()).collect();
+        let render = run(&dir, &render_args, "");
+        assert_eq!(plain.status.code(), Some(2), "{:?}", args);
+        assert_eq!(render.status.code(), Some(2), "{:?}", args);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_other_subcommands_are_unchanged() {
+    let dir = scratch_dir("others");
+    let result = run(&dir, &["palettes", "-o", "palettes.png"], "");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(dir.join("palettes.png").exists());
+    // Render flags still cannot be combined with another subcommand
+    let result = run(&dir, &["-z", "16", "palettes", "-o", "palettes.png"], "");
+    assert!(!result.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,76 @@
+//! `summarize` prints the totals the full pipeline would count, per /8 and overall,
+//! without rendering a map.
+
+mod common;
+
+use common::{run_in, scratch_dir};
+
+const FIXTURE: &str = "10.0.0.1 5\n10.2.0.0/16 3\nnot-an-ip\n\n11.0.0.1 2\n10.0.0.9/24 1\n2001:db8::1 4\n192.168.0.0/23 7\n";
+
+/// The number after `"key":` in a flat JSON object.
+fn number(json: &str, key: &str) -> i64 {
+    let at = json.find(&format!("\"{}\":", key)).unwrap_or_else(|| panic!("no {} in {}", key, json)) + key.len() + 3;
+    json[at..].split(|c: char| c != '-' && !c.is_ascii_digit()).next().unwrap().parse().unwrap()
+}
+
+#[test]
+fn test_totals_match_the_full_pipeline() {
+    let dir = scratch_dir("totals");
+    let rendered = run_in(
+        &dir,
+        &["-z", "24", "--value-mode", "raw", "-C", "--weight", "2", "--stats-json", "stats.json", "--export-cells", "cells.csv", "map.png"],
+        FIXTURE,
+    );
+    // Both exit with the status for rejected lines
+    assert_eq!(rendered.status.code(), Some(3), "{}", String::from_utf8_lossy(&rendered.stderr));
+    let summarized = run_in(&dir, &["summarize", "--weight", "2", "--json"], FIXTURE);
+    assert_eq!(summarized.status.code(), Some(3), "{}", String::from_utf8_lossy(&summarized.stderr));
+    let summary = String::from_utf8(summarized.stdout).unwrap();
+    let stats = std::fs::read_to_string(dir.join("stats.json")).unwrap();
+    for key in ["lines", "records", "rejected", "weighted_total", "ipv6_skipped", "cidr_host_bits"] {
+        assert_eq!(number(&summary, key), number(&stats, key), "{}", key);
+    }
+    // At -z 24 every /8 is one cell, so the exported cells are the per-/8 totals
+    let cells = std::fs::read_to_string(dir.join("cells.csv")).unwrap();
+    assert_eq!(cells.lines().count(), 4, "{}", cells);
+    assert!(summary.contains(r#""slash8":[{"octet":10,"records":3,"value":18},{"octet":11,"records":1,"value":4},"#), "{}", summary);
+    for row in cells.lines().skip(1) {
+        let fields: Vec<&str> = row.split(',').collect();
+        let octet = fields[0].split('.').next().unwrap();
+        let entry = summary.split(&format!(r#"{{"octet":{},"#, octet)).nth(1).unwrap_or_else(|| panic!("{} in {}", row, summary));
+        assert_eq!(number(entry, "value"), fields[1].parse::<i64>().unwrap(), "{}", row);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_text_report_and_rejects_file() {
+    let dir = scratch_dir("text");
+    let result = run_in(&dir, &["summarize", "--ascii", "--rejects", "rejects.tsv"], FIXTURE);
+    assert_eq!(result.status.code(), Some(3), "{}", String::from_utf8_lossy(&result.stderr));
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    assert!(stdout.contains("| 10.0.0.0/8  |       3 |     9 |\n"), "{}", stdout);
+    assert!(stdout.contains("| 192.0.0.0/8 |       1 |     7 |\n"), "{}", stdout);
+    assert!(stdout.contains("| rejected             |     2 |\n"), "{}", stdout);
+    assert!(stdout.contains("|   IPv6 address       |     1 |\n"), "{}", stdout);
+    let rejects = std::fs::read_to_string(dir.join("rejects.tsv")).unwrap();
+    assert!(rejects.starts_with("3\tinvalid IP address\t"), "{}", rejects);
+    // Nothing but the report is written
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_reads_files_in_other_formats() {
+    let dir = scratch_dir("formats");
+    std::fs::write(dir.join("input.txt"), FIXTURE).unwrap();
+    let converted = run_in(&dir, &["convert", "input.txt", "input.u32v", "--to", "raw-u32v"], "");
+    assert_eq!(converted.status.code(), Some(3), "{}", String::from_utf8_lossy(&converted.stderr));
+    let text = run_in(&dir, &["summarize", "--json", "input.txt"], "");
+    let binary = run_in(&dir, &["summarize", "--json", "--format", "raw-u32v", "input.u32v"], "");
+    assert_eq!(binary.status.code(), Some(0), "{}", String::from_utf8_lossy(&binary.stderr));
+    let (text, binary) = (String::from_utf8(text.stdout).unwrap(), String::from_utf8(binary.stdout).unwrap());
+    assert_eq!(number(&text, "weighted_total"), number(&binary, "weighted_total"));
+    assert_eq!(number(&text, "records"), number(&binary, "records"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
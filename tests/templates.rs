@@ -0,0 +1,83 @@
+//! Output names expand `{date}`, `{bpp}` and the other variables, unless
+//! `--no-template` takes them literally.
+
+mod common;
+
+use common::{entries, scratch_dir};
+use std::process::Output;
+
+const INPUT: &str = "10.0.0.1 5\n10.1.0.0/16 2\n";
+
+fn run(args: &[&str]) -> Output {
+    common::run(args, INPUT)
+}
+
+/// Whether `date` looks like `2024-06-01`.
+fn is_date(date: &str) -> bool {
+    date.len() == 10 && date.char_indices().all(|(i, c)| if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() })
+}
+
+#[test]
+fn test_every_output_is_expanded() {
+    let dir = scratch_dir("expanded");
+    let input = dir.join("scans.txt");
+    std::fs::write(&input, INPUT).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let output = run(&[
+        "-z",
+        "16",
+        "--curve",
+        "log",
+        "--value-mode",
+        "raw",
+        "--input",
+        input.to_str().unwrap(),
+        "-o",
+        &path("map-{date}-z{bpp}-{curve}.png"),
+        "--render",
+        &path("{palette}-{curve}.png:curve=linear,palette=viridis"),
+        "--thumbnail",
+        &path("thumb-{palette}.png:16"),
+        "--stats-json",
+        &path("{input-stem}-{datetime}.json"),
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+
+    let names = entries(&dir);
+    assert_eq!(names.len(), 5, "{:?}", names);
+    let map = names.iter().find(|name| name.starts_with("map-")).unwrap();
+    let date = &map["map-".len()..map.len() - "-z16-log.png".len()];
+    assert!(is_date(date) && map.ends_with("-z16-log.png"), "{}", map);
+    assert!(names.contains(&"viridis-linear.png".to_string()), "{:?}", names);
+    assert!(names.contains(&"thumb-magma.png".to_string()), "{:?}", names);
+    let stats = names.iter().find(|name| name.starts_with("scans-")).unwrap();
+    // The date and time the run started, e.g. scans-2024-06-01T070809Z.json
+    assert!(is_date(&stats[6..16]) && stats[16..].len() == "T070809Z.json".len(), "{}", stats);
+    let outputs = format!("output={},{},{}", path(map), path("viridis-linear.png"), path("thumb-magma.png"));
+    assert!(stderr.trim_end().ends_with(&outputs), "{}", stderr);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_literal_braces_and_errors() {
+    let dir = scratch_dir("literal");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let args = ["-z", "16", "--value-mode", "raw"];
+
+    let unknown = run(&[&args[..], &[&path("map-{day}.png")]].concat());
+    let stderr = String::from_utf8_lossy(&unknown.stderr);
+    assert_eq!(unknown.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("Unknown variable {day}") && stderr.contains("--no-template"), "{}", stderr);
+
+    let escaped = run(&[&args[..], &[&path("{{bpp}}-{bpp}.png")]].concat());
+    assert!(escaped.status.success(), "{}", String::from_utf8_lossy(&escaped.stderr));
+    let literal = run(&[&args[..], &["--no-template", &path("{day}.png")]].concat());
+    assert!(literal.status.success(), "{}", String::from_utf8_lossy(&literal.stderr));
+    assert_eq!(entries(&dir), ["{bpp}-16.png", "{day}.png"]);
+
+    let resolutions = run(&["-z", "16", "-z", "12", "--value-mode", "raw", &path("map-{bpp}.png")]);
+    assert_eq!(resolutions.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&resolutions.stderr).contains("needs a single -z"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
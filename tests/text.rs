@@ -0,0 +1,44 @@
+//! `--text` replaces the fixed text drawn into an image, such as the pixel caption,
+//! without moving anything else.
+
+mod common;
+
+use common::{run_in, scratch_dir};
+use std::path::Path;
+use std::process::Output;
+
+const INPUT: &str = "10.0.0.1 5\n192.168.0.0/16 3\n";
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    run_in(dir, &[&["-z", "16", "-u", "hosts"], args].concat(), INPUT)
+}
+
+fn pixels(dir: &Path, name: &str) -> image::RgbaImage {
+    image::open(dir.join(name)).unwrap().to_rgba8()
+}
+
+#[test]
+fn test_override_changes_the_caption() {
+    let dir = scratch_dir("caption");
+    let default = run(&dir, &["default.png"]);
+    assert!(default.status.success(), "{}", String::from_utf8_lossy(&default.stderr));
+    let translated = run(&dir, &["--text", "caption.pixel=1 Pixel = /{prefix} ({addresses} Adressen)", "translated.png"]);
+    assert!(translated.status.success(), "{}", String::from_utf8_lossy(&translated.stderr));
+    let (default, translated) = (pixels(&dir, "default.png"), pixels(&dir, "translated.png"));
+    assert_eq!(default.dimensions(), translated.dimensions());
+    assert_ne!(default, translated);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_unknown_key_is_ignored_with_a_warning() {
+    let dir = scratch_dir("unknown");
+    assert!(run(&dir, &["default.png"]).status.success());
+    let result = run(&dir, &["--text", "legend.heading=Titel", "--text", "caption.pixel", "unknown.png"]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert_eq!(stderr.matches("Ignoring --text").count(), 2, "{}", stderr);
+    assert!(stderr.contains("Unknown text key: legend.heading. Use one of curve.linear"), "{}", stderr);
+    assert_eq!(pixels(&dir, "default.png"), pixels(&dir, "unknown.png"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,71 @@
+//! `--threads` paints the same cells and counts the same lines as a serial run.
+
+mod common;
+
+use common::{run, scratch_dir};
+
+#[test]
+fn test_threads_match_serial() {
+    let dir = scratch_dir("match");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let mut input = String::new();
+    for line in 0u32..40_000 {
+        let address = std::net::Ipv4Addr::from(line.wrapping_mul(2_654_435_761));
+        match line % 1000 {
+            0 => input.push_str(&format!("{}/12 2\n", address)),
+            1 => input.push_str("bad line\n"),
+            _ => input.push_str(&format!("{} {}\n", address, line % 9)),
+        }
+    }
+    std::fs::write(path("input.txt"), &input).unwrap();
+    let common = ["-z", "16", "--value-mode", "raw"];
+
+    let mut states = Vec::new();
+    for (name, threads, from_file) in [("serial", "1", false), ("stdin", "4", false), ("file", "3", true)] {
+        let (state, stats, output) = (path(&format!("{}.state", name)), path(&format!("{}.json", name)), path(&format!("{}.png", name)));
+        let mut args = common.to_vec();
+        args.extend(["--threads", threads, "--save-state", &state, "--stats-json", &stats]);
+        let input_path = path("input.txt");
+        if from_file {
+            args.extend(["--input", &input_path]);
+        }
+        args.push(&output);
+        let result = run(&args, if from_file { "" } else { &input });
+        // Rejected lines exit with 3
+        assert_eq!(result.status.code(), Some(3), "{}", String::from_utf8_lossy(&result.stderr));
+        let stats = std::fs::read_to_string(&stats).unwrap();
+        assert!(stats.contains(r#""lines":40000"#), "{}", stats);
+        assert!(stats.contains(r#""rejected":40"#), "{}", stats);
+        states.push(std::fs::read(&state).unwrap());
+    }
+    assert_eq!(states[1], states[0]);
+    assert_eq!(states[2], states[0]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dedup_window_reads_in_order() {
+    let dir = scratch_dir("dedup");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    // Bursts of repeated lines between unique ones
+    let mut input = String::new();
+    for line in 0u32..20_000 {
+        let address = std::net::Ipv4Addr::from((line / 10).wrapping_mul(2_654_435_761));
+        input.push_str(&format!("{} {}\n", address, line / 10 % 7));
+    }
+    let mut states = Vec::new();
+    for threads in ["1", "4"] {
+        let (state, stats, output) = (path(&format!("{}.state", threads)), path(&format!("{}.json", threads)), path(&format!("{}.png", threads)));
+        let args = [
+            "-z", "16", "--value-mode", "raw", "--dedup-window", "4", "--threads", threads, "--save-state", &state,
+            "--stats-json", &stats, &output,
+        ];
+        let result = run(&args, &input);
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        let stats = std::fs::read_to_string(&stats).unwrap();
+        assert!(stats.contains(r#""deduplicated":18000"#), "{}", stats);
+        states.push(std::fs::read(&state).unwrap());
+    }
+    assert_eq!(states[1], states[0]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,91 @@
+//! Warnings stay bounded for floods of malformed lines, name data outside the view and
+//! flag blocks that `--sanity-check` finds far off the rest.
+
+mod common;
+
+use common::run;
+
+#[test]
+fn test_pathological_input_logs_bounded_warnings() {
+    // 50,000 bad addresses and 5,000 bad prefixes among a few good lines
+    let mut input = String::from("10.0.0.1 5\n");
+    for line in 0..55_000 {
+        match line % 11 {
+            0 => input.push_str(&format!("10.0.0.0/{} 1\n", 40 + line % 7)),
+            _ => input.push_str(&format!("host-{}.example 1\n", line)),
+        }
+    }
+    input.push_str("10.0.0.2 5\n");
+    let output = std::env::temp_dir().join(format!("ip-heatmap-warnings-{}.png", std::process::id()));
+    let stats_path = std::env::temp_dir().join(format!("ip-heatmap-warnings-{}.json", std::process::id()));
+    let result = run(
+        &["-z", "16", "--value-mode", "raw", "--stats-json", stats_path.to_str().unwrap(), output.to_str().unwrap()],
+        &input,
+    );
+    assert_eq!(result.status.code(), Some(3));
+
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    let verbatim = |reason: &str| lines.iter().filter(|line| line.contains(&format!(": {} - ", reason))).count();
+    assert_eq!(verbatim("invalid IP address"), 10, "{}", stderr);
+    assert_eq!(verbatim("invalid CIDR"), 10, "{}", stderr);
+    assert!(stderr.contains("Further invalid IP address warnings are counted without being logged"), "{}", stderr);
+    assert!(stderr.contains("…suppressed 54,980 similar warnings"), "{}", stderr);
+    assert!(lines.len() < 40, "{}", stderr);
+
+    let stats = std::fs::read_to_string(&stats_path).unwrap();
+    assert!(stats.contains(r#""rejected":55000"#), "{}", stats);
+    assert!(stats.contains(r#""suppressed_warnings":54980"#), "{}", stats);
+    let _ = std::fs::remove_file(&output);
+    let _ = std::fs::remove_file(&stats_path);
+}
+
+#[test]
+fn test_data_outside_the_crop_is_reported() {
+    // Half the records are outside the crop
+    let input = "10.0.0.1 5\n10.1.0.1 5\n192.168.0.1 5\n192.168.1.1 5\n";
+    let output = std::env::temp_dir().join(format!("ip-heatmap-clipped-{}.png", std::process::id()));
+    let stats_path = std::env::temp_dir().join(format!("ip-heatmap-clipped-{}.json", std::process::id()));
+    let args = |clip_warning: &'static str| {
+        vec![
+            "-z", "16", "--value-mode", "raw", "--crop", "10.0.0.0/8", "-t", "Scan", "--clip-warning", clip_warning,
+            "--stats-json", stats_path.to_str().unwrap(), output.to_str().unwrap(),
+        ]
+    };
+    let result = run(&args("25"), input);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("50% of data outside view: 2 of 4 records and 10 of 20 value fell outside --crop 10.0.0.0/8"),
+        "{}",
+        stderr
+    );
+    let stats = std::fs::read_to_string(&stats_path).unwrap();
+    assert!(stats.contains(r#""records":4,"clipped_records":2,"clipped_value":10"#), "{}", stats);
+
+    // At or below the threshold the counts are still reported, without a warning
+    let result = run(&args("50"), input);
+    assert!(result.status.success());
+    assert!(!String::from_utf8_lossy(&result.stderr).contains("outside view"));
+    assert!(std::fs::read_to_string(&stats_path).unwrap().contains(r#""clipped_records":2"#));
+    let _ = std::fs::remove_file(&output);
+    let _ = std::fs::remove_file(&stats_path);
+}
+
+#[test]
+fn test_sanity_check_flags_an_empty_block() {
+    // A /8 in every unicast /4 except 128.0.0.0/4, as if a script had dropped it
+    let input: String =
+        (0..14).filter(|&block| block != 8).map(|block| format!("{}.0.0.0/8 3\n", block * 16)).collect();
+    let output = std::env::temp_dir().join(format!("ip-heatmap-sanity-{}.png", std::process::id()));
+    let result = run(&["-z", "16", "--value-mode", "raw", "--sanity-check", output.to_str().unwrap()], &input);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.contains("| 128.0.0.0/4 |       0 | 0.00% |     0 |                           |\n"), "{}", stderr);
+    assert!(stderr.contains("Sanity check: 128.0.0.0/4 has 0.00% of its pixels painted against 5.80% overall"), "{}", stderr);
+    assert_eq!(stderr.matches("Sanity check:").count(), 1, "{}", stderr);
+    std::fs::remove_file(&output).unwrap();
+
+    let unchecked = run(&["-z", "16", "--value-mode", "raw", "--sanity-factor", "2", output.to_str().unwrap()], &input);
+    assert_eq!(unchecked.status.code(), Some(2), "--sanity-factor needs --sanity-check");
+}
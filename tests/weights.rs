@@ -0,0 +1,62 @@
+//! Per-file weights give the same cells as input with the values already multiplied.
+
+mod common;
+
+use common::{run, scratch_dir};
+
+#[test]
+fn test_weighted_files_match_premultiplied_input() {
+    let dir = scratch_dir("files");
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    std::fs::write(path("a.txt"), "10.0.0.1 3\n10.1.0.0/16 2\n").unwrap();
+    std::fs::write(path("b.txt"), "10.0.0.1 1\n192.168.0.0/16\n").unwrap();
+    let common = ["-z", "16", "-C", "--value-mode", "raw"];
+
+    let a = format!("{}:1", path("a.txt"));
+    let b = format!("{}:1000", path("b.txt"));
+    let weighted_state = path("weighted.state");
+    let stats = path("stats.json");
+    let mut args = common.to_vec();
+    let output = path("weighted.png");
+    args.extend(["--input", &a, "--input", &b, "--save-state", &weighted_state, "--stats-json", &stats, &output]);
+    let result = run(&args, "");
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+
+    let premultiplied = "10.0.0.1 3\n10.1.0.0/16 2\n10.0.0.1 1000\n192.168.0.0/16 1000\n";
+    let plain_state = path("plain.state");
+    let output = path("plain.png");
+    let mut args = common.to_vec();
+    args.extend(["--save-state", &plain_state, &output]);
+    let result = run(&args, premultiplied);
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+
+    assert_eq!(std::fs::read(&weighted_state).unwrap(), std::fs::read(&plain_state).unwrap());
+    let stats = std::fs::read_to_string(&stats).unwrap();
+    assert!(stats.contains(r#""weighted_total":2005"#), "{}", stats);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_global_weight_composes_with_file_weights() {
+    let dir = scratch_dir("global");
+    let input = dir.join("a.txt");
+    std::fs::write(&input, "10.0.0.1 3\n").unwrap();
+    let input = format!("{}:2", input.to_str().unwrap());
+    let stats = dir.join("stats.json");
+    let output = dir.join("map.png");
+    let result = run(
+        &[
+            "-z", "16", "--value-mode", "raw", "--weight", "1.5",
+            "--input", &input,
+            "--stats-json", stats.to_str().unwrap(),
+            output.to_str().unwrap(),
+        ],
+        "",
+    );
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    assert!(std::fs::read_to_string(&stats).unwrap().contains(r#""weighted_total":9"#));
+
+    let result = run(&["--value-mode", "categorical", "--weight", "2", output.to_str().unwrap()], "");
+    assert!(!result.status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}